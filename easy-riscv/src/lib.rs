@@ -0,0 +1,32 @@
+//! The assembler, emulator and debugger of easy-riscv behind one dependency.
+//!
+//! [`asm`] and [`emu`] are the `riscv-asm` and `riscv-emu` crates as they are, [`Program`]
+//! connects them: it assembles a source and loads the result, symbols and source lines
+//! included, into a [`Machine`](emu::machine::Machine) or a
+//! [`Debugger`](emu::debugger::Debugger). Most programs only need the [`prelude`]:
+//!
+//! ```
+//! use easy_riscv::prelude::*;
+//!
+//! let program = Program::assemble("li a0, 42\necall", &AssemblerOptions::default())?;
+//! let mut machine = program.machine(0x1000, &RunOptions::default())?;
+//! let outcome = machine.run(&RunLimits::default());
+//! assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall);
+//! assert_eq!(machine.cpu.regs[10], 42);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The crate follows semver on its own: everything reachable from the crate root, the
+//! re-exported crates included, is its public surface, and [`VERSION`] says which one a
+//! build has.
+
+pub mod prelude;
+pub mod program;
+
+pub use riscv_asm as asm;
+pub use riscv_emu as emu;
+
+pub use program::Program;
+
+/// Version of this crate, e.g. for `--version` output of tools built on it
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,16 @@
+//! The types most programs assembling and running code use, `use easy_riscv::prelude::*`
+
+pub use crate::program::Program;
+pub use riscv_asm::{
+    AssembledProgram, AssemblerOptions, SourceInput, assemble, assemble_program,
+    error::{AssemblerError, SourceLocation},
+};
+pub use riscv_emu::{
+    debugger::Debugger,
+    error::EmuError,
+    image::{Image, ImageFormat},
+    line_map::LineMap,
+    machine::{ExitReason, Machine, RunLimits, RunOptions, RunOutcome},
+    symbols::Symbols,
+    syscalls::SyscallMode,
+};
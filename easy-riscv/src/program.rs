@@ -0,0 +1,142 @@
+//! An assembled program ready to load, the glue between the assembler and the emulator
+
+use riscv_asm::{AssembledProgram, AssemblerOptions, SourceInput, assemble_program};
+use riscv_emu::{
+    debugger::Debugger,
+    error::EmuError,
+    image::{Image, ImageError},
+    line_map::LineMap,
+    machine::{Machine, RunOptions},
+    symbols::Symbols,
+};
+
+/// File name source lines of a program assembled from unnamed text are listed under
+const UNNAMED_SOURCE: &str = "<source>";
+
+/// A program's image with the symbols and source lines the emulator reports and debugs
+/// with, all at the addresses the program is loaded at
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub image: Image,
+    pub symbols: Symbols,
+    pub line_map: LineMap,
+}
+
+impl Program {
+    /// Assembles `source` to run from address 0
+    pub fn assemble<'a>(
+        source: impl Into<SourceInput<'a>>,
+        options: &AssemblerOptions,
+    ) -> anyhow::Result<Self> {
+        Self::assemble_at(source, options, 0)
+    }
+
+    /// Assembles `source` and moves it to `load_addr`
+    pub fn assemble_at<'a>(
+        source: impl Into<SourceInput<'a>>,
+        options: &AssemblerOptions,
+        load_addr: u32,
+    ) -> anyhow::Result<Self> {
+        let source = source.into();
+        let name = source.name().unwrap_or(UNNAMED_SOURCE).to_string();
+        let program = assemble_program(source, options)?;
+        Ok(Self::from_assembled(&program, &name, load_addr)?)
+    }
+
+    /// Places the sections of `program` at `load_addr` on, lines of the source itself are
+    /// listed under `file`, those of files it included under their own names
+    pub fn from_assembled(
+        program: &AssembledProgram,
+        file: &str,
+        load_addr: u32,
+    ) -> Result<Self, ImageError> {
+        let mut image = Image::new();
+        for extent in &program.sections {
+            let start = (extent.address - program.origin) as usize;
+            let data = program.bytes[start..][..extent.size as usize].to_vec();
+            image.add_segment(extent.address.wrapping_add(load_addr), data)?;
+        }
+        // Absolute symbols (.equ constants) stay where they are
+        let mut symbols = Symbols::new();
+        for (name, address) in program.symbols.sorted_by_address() {
+            match program.symbols.get(name) {
+                Some(symbol) if symbol.absolute => symbols.insert_with_kind(name, address, 'A'),
+                _ => symbols.insert(name, address.wrapping_add(load_addr)),
+            }
+        }
+        let mut line_map = LineMap::new();
+        for (address, location) in &program.line_map {
+            let file = location.file.as_deref().unwrap_or(file);
+            line_map.insert(address.wrapping_add(load_addr), file, location.line);
+        }
+        Ok(Self {
+            image,
+            symbols,
+            line_map,
+        })
+    }
+
+    /// Where execution starts, the image's entry point or else its lowest address
+    pub fn entry(&self) -> u32 {
+        self.image.entry.or(self.image.start()).unwrap_or(0)
+    }
+
+    /// A `memory_size` byte machine with the program loaded, its symbols added and the
+    /// stack pointer at the top of memory
+    pub fn machine(&self, memory_size: usize, options: &RunOptions) -> Result<Machine, EmuError> {
+        let mut machine = Machine::try_from_shared(Vec::new().into(), memory_size, options)?;
+        machine.load_image(&self.image)?;
+        machine.set_entry(self.entry());
+        // 4GiB of memory wraps to 0, where a push lands at the top of memory
+        machine.set_stack_pointer(memory_size as u32);
+        machine.symbols = self.symbols.clone();
+        machine.layout().validate()?;
+        Ok(machine)
+    }
+
+    /// Like [`Program::machine`], in a debugger that knows the program's source lines
+    pub fn debugger(&self, memory_size: usize, options: &RunOptions) -> Result<Debugger, EmuError> {
+        let mut debugger = Debugger::new(self.machine(memory_size, options)?);
+        debugger.line_map = self.line_map.clone();
+        Ok(debugger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use riscv_emu::machine::{ExitReason, RunLimits};
+
+    use super::*;
+
+    #[test]
+    fn test_assemble_and_run() {
+        let source = "
+            .equ ANSWER, 42
+            .text
+        main:
+            li a0, 42
+            la a1, value
+            lw a1, 0(a1)
+            ecall
+            .data
+        value:
+            .word 7
+        ";
+        let program = Program::assemble_at(source, &AssemblerOptions::default(), 0x100).unwrap();
+        assert_eq!(program.entry(), 0x100);
+        assert_eq!(program.symbols.address("main"), Some(0x100));
+        assert_eq!(program.symbols.address("ANSWER"), Some(42));
+        let line = program.line_map.line_at(0x100).unwrap();
+        assert_eq!((line.file.as_str(), line.line), (UNNAMED_SOURCE, 5));
+
+        let mut machine = program.machine(0x1000, &RunOptions::default()).unwrap();
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall);
+        assert_eq!((machine.cpu.regs[10], machine.cpu.regs[11]), (42, 7));
+        assert_eq!(machine.cpu.regs[2], 0x1000);
+
+        let debugger = program.debugger(0x1000, &RunOptions::default()).unwrap();
+        assert_eq!(debugger.line_map.len(), program.line_map.len());
+        assert!(program.machine(0x80, &RunOptions::default()).is_err());
+    }
+}
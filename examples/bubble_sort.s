@@ -0,0 +1,79 @@
+# Sorts an array of signed words in place with bubble sort, printing it before and after.
+#
+#     rv run --syscalls bare --stack-size 0x1000 examples/bubble_sort.s
+#
+# Exits with the number of swaps it made. The program image is read-only, so the array is
+# copied onto the stack to be sorted there. `lw rd, =label` loads the address of a label
+# from a literal pool.
+
+main:
+    lw t0, =length
+    lw s1, 0(t0)            # elements in the array
+    slli t1, s1, 2
+    sub sp, sp, t1
+    mv s0, sp               # the copy being sorted
+    lw t0, =array
+    mv t2, s0
+copy:
+    beq t1, zero, copied
+    lw t3, 0(t0)
+    sw t3, 0(t2)
+    addi t0, t0, 4
+    addi t2, t2, 4
+    addi t1, t1, -4
+    jal zero, copy
+copied:
+    jal ra, print
+    li s3, 0                # swaps made
+
+outer:
+    li s2, 0                # whether this pass swapped anything
+    mv t0, s0               # the pair compared
+    addi t1, s1, -1         # pairs left in this pass
+inner:
+    beq t1, zero, passed
+    lw t2, 0(t0)
+    lw t3, 4(t0)
+    bge t3, t2, in_order
+    sw t3, 0(t0)
+    sw t2, 4(t0)
+    li s2, 1
+    inc s3
+in_order:
+    addi t0, t0, 4
+    dec t1
+    jal zero, inner
+passed:
+    bne s2, zero, outer
+
+    jal ra, print
+    mv a0, s3
+    li a7, 93               # exit with the code in a0
+    ecall
+
+# Prints the array on one line
+print:
+    mv t0, s0
+    mv t1, s1
+print_next:
+    beq t1, zero, print_end
+    lw a0, 0(t0)
+    li a7, 1                # print_int
+    ecall
+    li a0, 32               # ' '
+    li a7, 11               # print_char
+    ecall
+    addi t0, t0, 4
+    dec t1
+    jal zero, print_next
+print_end:
+    li a0, 10               # '\n'
+    li a7, 11
+    ecall
+    jalr zero, 0(ra)
+
+    .data
+length:
+    .word 8
+array:
+    .word 5, -3, 12, 0, 7, 7, -20, 1
@@ -0,0 +1,32 @@
+# Prints the first ten Fibonacci numbers, separated by spaces, and exits with the last one.
+#
+#     rv run --syscalls bare examples/fibonacci.s
+#
+# Uses the RARS-style system calls of `--syscalls bare`: a7 selects the call, a0 carries
+# its argument.
+
+main:
+    li s0, 10               # numbers left to print
+    li s1, 0                # fib(n)
+    li s2, 1                # fib(n + 1)
+
+loop:
+    mv a0, s1
+    li a7, 1                # print_int
+    ecall
+    li a0, 32               # ' '
+    li a7, 11               # print_char
+    ecall
+
+    add t0, s1, s2          # step to fib(n + 1), fib(n + 2)
+    mv s1, s2
+    mv s2, t0
+    dec s0
+    bne s0, zero, loop
+
+    li a0, 10               # '\n'
+    li a7, 11
+    ecall
+    sub a0, s2, s1          # undo the last step: fib(9) = fib(11) - fib(10)
+    li a7, 93               # exit with the code in a0
+    ecall
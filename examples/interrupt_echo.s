@@ -0,0 +1,65 @@
+# Echoes console input in upper case from the UART's receive interrupt, until it has
+# echoed a line, and exits with the length of the line.
+#
+#     echo hello | rv run --uart --plic --syscalls bare examples/interrupt_echo.s
+#
+# The UART of QEMU's virt machine is at 0x10000000 and interrupts through source 10 of
+# the PLIC at 0x0c000000. The main program only sleeps; the trap handler claims the
+# interrupt, echoes the received byte and completes the claim. The exit is a system call,
+# as a plain `ecall` would trap to the handler too.
+
+main:
+    li s2, 0                # bytes echoed
+    li s3, 0                # set by the handler at the end of the line
+    lui s0, 0x10000         # the UART
+    lui s1, 0x0c000         # the PLIC
+    li t0, 1
+    sw t0, 40(s1)           # priority 1 for source 10
+    lui t1, 0x0c002
+    li t0, 0x400
+    sw t0, 0(t1)            # enable source 10
+    li t0, 1
+    sb t0, 1(s0)            # IER: interrupt when a byte was received
+
+    lw t0, =handler
+    csrrw zero, mtvec, t0
+    lui t0, 1
+    srli t0, t0, 1          # MEIE, machine external interrupts
+    csrrs zero, mie, t0
+    csrrsi zero, mstatus, 8 # MIE, interrupts on
+
+wait:
+    csrrci zero, mstatus, 8 # interrupts off, so none is taken between the check and wfi
+    bne s3, zero, done
+    wfi                     # sleep until the next byte is pending
+    csrrsi zero, mstatus, 8 # and take it
+    j wait
+
+done:
+
+    mv a0, s2
+    li a7, 93               # exit with the code in a0
+    ecall
+
+handler:
+    lui t1, 0x0c200
+    lw t2, 4(t1)            # claim the interrupt
+    lbu t3, 0(s0)           # the received byte
+    li t4, 10
+    beq t3, t4, newline
+    li t4, 97               # 'a'
+    blt t3, t4, echo
+    li t4, 123              # past 'z'
+    bge t3, t4, echo
+    addi t3, t3, -32        # to upper case
+echo:
+    sb t3, 0(s0)
+    inc s2
+    jal zero, complete
+newline:
+    sb t3, 0(s0)
+    sb zero, 1(s0)          # IER: no more interrupts, the rest of the input stays unread
+    li s3, 1
+complete:
+    sw t2, 4(t1)            # complete it
+    mret
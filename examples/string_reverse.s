@@ -0,0 +1,39 @@
+# Reads a line from the console and prints it reversed, exiting with its length.
+#
+#     echo hello | rv run --syscalls bare --stack-size 0x1000 examples/string_reverse.s
+#
+# The characters are pushed on the stack as they are read and popped in reverse, one word
+# each to keep the stack pointer aligned.
+
+main:
+    li s0, 0                # length of the line
+    li s1, 10               # '\n' ends the line
+
+read:
+    li a7, 12               # read_char, -1 at the end of the input
+    ecall
+    blt a0, zero, print
+    beq a0, s1, print
+    addi sp, sp, -4
+    sw a0, 0(sp)
+    inc s0
+    jal zero, read
+
+print:
+    mv s2, s0
+pop:
+    beq s2, zero, done
+    lw a0, 0(sp)
+    addi sp, sp, 4
+    li a7, 11               # print_char
+    ecall
+    dec s2
+    jal zero, pop
+
+done:
+    mv a0, s1
+    li a7, 11
+    ecall
+    mv a0, s0
+    li a7, 93               # exit with the code in a0
+    ecall
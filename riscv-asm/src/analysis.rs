@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    assembler::MemoryMap,
+    error::{AssemblerError, SourceLocation},
+    parser::{Instruction, LITERAL_LABEL_PREFIX, Operand, ParsedItem, local_label_instance},
+    section::Section,
+    symbol_table::SymbolTable,
+    xref::{CrossReferences, ReferenceKind},
+};
+
+/// What a static analysis finding is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FindingKind {
+    /// A label nothing refers to
+    DeadLabel,
+    /// Instructions no path from the entry point or an address-taken label reaches
+    UnreachableCode,
+    /// A branch or jump to the instruction after it, which does nothing either way
+    BranchToNext,
+    /// `li` of a value `addi` takes, for code meant to show the base instructions
+    LiFitsAddi,
+    /// Labelled data between the instructions of `.text`
+    DataInText,
+}
+
+impl FindingKind {
+    pub const ALL: [FindingKind; 5] = [
+        FindingKind::DeadLabel,
+        FindingKind::UnreachableCode,
+        FindingKind::BranchToNext,
+        FindingKind::LiFitsAddi,
+        FindingKind::DataInText,
+    ];
+
+    /// Name of the kind in `-W` options, e.g. `dead-label`
+    pub fn name(self) -> &'static str {
+        match self {
+            FindingKind::DeadLabel => "dead-label",
+            FindingKind::UnreachableCode => "unreachable-code",
+            FindingKind::BranchToNext => "branch-to-next",
+            FindingKind::LiFitsAddi => "li-fits-addi",
+            FindingKind::DataInText => "data-in-text",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Level of the kind unless the options set one, only `li-fits-addi` is off
+    pub fn default_level(self) -> WarningLevel {
+        match self {
+            FindingKind::LiFitsAddi => WarningLevel::Allow,
+            _ => WarningLevel::Warn,
+        }
+    }
+}
+
+/// How findings of a kind are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum WarningLevel {
+    /// Not at all
+    Allow,
+    /// In [`AssembledProgram::findings`](crate::AssembledProgram::findings)
+    Warn,
+    /// As errors, the program doesn't assemble
+    Deny,
+}
+
+/// Levels of the kinds of findings, set the way `-W` options of C compilers do
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Warnings {
+    /// Levels differing from the kinds' [`FindingKind::default_level`]
+    pub levels: BTreeMap<FindingKind, WarningLevel>,
+    /// Report every finding that would be a warning as an error, `-Werror`
+    pub errors: bool,
+}
+
+impl Warnings {
+    /// Every finding a warning, and a failure
+    pub fn errors() -> Self {
+        Self {
+            errors: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn level(&self, kind: FindingKind) -> WarningLevel {
+        match self.levels.get(&kind).copied() {
+            Some(level) => level,
+            None if self.errors && kind.default_level() == WarningLevel::Warn => WarningLevel::Deny,
+            None => kind.default_level(),
+        }
+    }
+
+    /// Applies a `-W` option: `NAME` turns a kind on, `no-NAME` off, `error=NAME` makes it
+    /// an error, `error` makes every warning one and `all` turns every kind on
+    pub fn apply(&mut self, option: &str) -> Result<(), String> {
+        let kind = |name: &str| {
+            FindingKind::from_name(name).ok_or_else(|| {
+                let names: Vec<&str> = FindingKind::ALL.iter().map(|kind| kind.name()).collect();
+                format!(
+                    "unknown warning '{}', expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+        };
+        let warn = |errors: bool| match errors {
+            true => WarningLevel::Deny,
+            false => WarningLevel::Warn,
+        };
+        match option {
+            "error" => {
+                self.errors = true;
+                for level in self.levels.values_mut() {
+                    if *level == WarningLevel::Warn {
+                        *level = WarningLevel::Deny;
+                    }
+                }
+            }
+            "no-error" => self.errors = false,
+            "all" => {
+                for kind in FindingKind::ALL {
+                    self.levels.insert(kind, warn(self.errors));
+                }
+            }
+            _ => match option.strip_prefix("error=") {
+                Some(name) => {
+                    self.levels.insert(kind(name)?, WarningLevel::Deny);
+                }
+                None => match option.strip_prefix("no-") {
+                    Some(name) => {
+                        self.levels.insert(kind(name)?, WarningLevel::Allow);
+                    }
+                    None => {
+                        self.levels.insert(kind(option)?, warn(self.errors));
+                    }
+                },
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Something suspicious about an assembled program, reported as a warning or an error as
+/// [`AssemblerOptions::warnings`](crate::AssemblerOptions::warnings) say
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub message: String,
+    pub location: SourceLocation,
+}
+
+impl Finding {
+    pub fn to_error(&self) -> AssemblerError {
+        AssemblerError::AnalysisError {
+            message: self.message.clone(),
+            location: self.location.clone(),
+        }
+    }
+}
+
+/// Directives placing data, the ones out of place in `.text`
+const DATA_DIRECTIVES: [&str; 6] = [".word", ".half", ".byte", ".ascii", ".asciz", ".string"];
+
+/// Flags dead labels, unreachable blocks, branches to the next instruction and data in
+/// `.text`, in source order.
+///
+/// Execution starts at address 0. Control flow is followed through fallthrough, branches and
+/// `jal`; calls (jumps that link) are assumed to return, `jalr` without a link ends a path.
+/// Labels used other than as a branch or jump target (address loads, `.globl`) might be
+/// jumped to indirectly, so they count as entry points too.
+pub(crate) fn analyze(
+    symbol_table: &SymbolTable,
+    memory_map: &MemoryMap,
+    parsed_items: &[ParsedItem],
+    xrefs: &CrossReferences,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut roots = vec![0];
+    for (name, symbol) in symbol_table.iter() {
+        let (Some(address), Some(definition)) = (symbol.address, &symbol.definition) else {
+            continue;
+        };
+        let control_flow_uses = xrefs.references_to(name).map_or(0, |references| {
+            references
+                .iter()
+                .filter(|reference| reference.kind != ReferenceKind::Address)
+                .count()
+        });
+        if symbol.references.len() > control_flow_uses {
+            roots.push(address);
+        }
+        // Constants defined with `.equ` are often only there for documentation
+        if symbol.references.is_empty() && address != 0 && !symbol.absolute {
+            let name = local_label_instance(name).map_or(name, |(number, _)| number);
+            findings.push(Finding {
+                kind: FindingKind::DeadLabel,
+                message: format!("Label '{}' is never referenced", name),
+                location: definition.clone(),
+            });
+        }
+    }
+
+    let instructions: BTreeMap<u32, &Instruction> = parsed_items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| match item {
+            ParsedItem::Instruction(instruction) => {
+                Some((memory_map.address_of(index), instruction))
+            }
+            _ => None,
+        })
+        .collect();
+    // Traps enter a vector table at any of its entries, right after the table's label
+    for (index, item) in parsed_items.iter().enumerate() {
+        if let ParsedItem::Directive { name, args, .. } = item
+            && name == ".vector_table"
+            && let [Operand::Immediate(entries)] = args[..]
+        {
+            let base = memory_map.address_of(index + 1);
+            roots.extend((0..entries as u32).map(|entry| base + entry * 4));
+        }
+    }
+    for (&address, instruction) in &instructions {
+        let next = address.wrapping_add(instruction.size());
+        if is_jump(instruction) && jump_target(address, instruction, symbol_table) == Some(next) {
+            findings.push(Finding {
+                kind: FindingKind::BranchToNext,
+                message: format!(
+                    "'{}' to the next instruction does nothing",
+                    instruction.mnemonic
+                ),
+                location: instruction.location.clone(),
+            });
+        }
+    }
+    // Runs of data directives after a label count once, the literals of a pool not at all.
+    // Unlabelled words are likely instructions encoded by hand.
+    let mut in_data = false;
+    for (index, item) in parsed_items.iter().enumerate() {
+        match item {
+            ParsedItem::Directive { name, location, .. }
+                if DATA_DIRECTIVES.contains(&name.as_str())
+                    && memory_map.section_of(index) == Section::Text =>
+            {
+                let labelled = index > 0
+                    && matches!(&parsed_items[index - 1], ParsedItem::Label { name, .. }
+                        if !name.starts_with(LITERAL_LABEL_PREFIX));
+                if !in_data && labelled {
+                    findings.push(Finding {
+                        kind: FindingKind::DataInText,
+                        message: format!(
+                            "'{}' places data in .text, between instructions; .rodata or .data keep it apart",
+                            name
+                        ),
+                        location: location.clone(),
+                    });
+                }
+                in_data = true;
+            }
+            ParsedItem::Label { .. } if in_data => {}
+            _ => in_data = false,
+        }
+    }
+
+    let mut reached = BTreeSet::new();
+    while let Some(address) = roots.pop() {
+        let Some(instruction) = instructions.get(&address) else {
+            continue;
+        };
+        if reached.insert(address) {
+            roots.extend(successors(address, instruction, symbol_table));
+        }
+    }
+
+    // Group unreached instructions into runs of consecutive addresses
+    // (start, address after the last instruction, instruction count, first instruction)
+    let mut block: Option<(u32, u32, usize, &Instruction)> = None;
+    let mut push_block = |block: Option<(u32, u32, usize, &Instruction)>| {
+        if let Some((start, _, count, first)) = block {
+            findings.push(Finding {
+                kind: FindingKind::UnreachableCode,
+                message: format!(
+                    "Unreachable code, {} instruction{} at {:#x}",
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    start
+                ),
+                location: first.location.clone(),
+            });
+        }
+    };
+    for (&address, instruction) in &instructions {
+        if reached.contains(&address) {
+            push_block(block.take());
+            continue;
+        }
+        block = match block {
+            Some((start, end, count, first)) if end == address => {
+                Some((start, address + instruction.size(), count + 1, first))
+            }
+            previous => {
+                push_block(previous);
+                Some((address, address + instruction.size(), 1, instruction))
+            }
+        };
+    }
+    push_block(block);
+
+    findings.sort_by_key(|finding| (finding.location.line, finding.location.col));
+    findings
+}
+
+/// Whether `instruction` is a branch or a jump that doesn't link, whose only effect is
+/// where execution continues
+fn is_jump(instruction: &Instruction) -> bool {
+    match instruction.mnemonic.as_str() {
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => true,
+        "jal" => matches!(instruction.operands.first(), Some(Operand::Register(0))),
+        _ => false,
+    }
+}
+
+/// Address the branch or `jal` at `address` goes to when taken
+fn jump_target(address: u32, instruction: &Instruction, symbol_table: &SymbolTable) -> Option<u32> {
+    let operand = match instruction.mnemonic.as_str() {
+        "jal" => instruction.operands.get(1),
+        _ => instruction.operands.get(2),
+    };
+    match operand? {
+        Operand::Symbol(name) => symbol_table.address(name),
+        Operand::Immediate(offset) => Some(address.wrapping_add(*offset as u32)),
+        _ => None,
+    }
+}
+
+/// Addresses execution can continue at after the instruction at `address`
+fn successors(address: u32, instruction: &Instruction, symbol_table: &SymbolTable) -> Vec<u32> {
+    let next = address.wrapping_add(instruction.size());
+    let links = !matches!(instruction.operands.first(), Some(Operand::Register(0)));
+    match instruction.mnemonic.as_str() {
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+            let mut successors = vec![next];
+            successors.extend(jump_target(address, instruction, symbol_table));
+            successors
+        }
+        "jal" => {
+            let mut successors: Vec<u32> = jump_target(address, instruction, symbol_table)
+                .into_iter()
+                .collect();
+            if links {
+                successors.push(next);
+            }
+            successors
+        }
+        "jalr" if links => vec![next],
+        "jalr" => Vec::new(),
+        _ => vec![next],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssemblerOptions, assemble_program};
+
+    use super::*;
+
+    fn findings(source: &str) -> Vec<(FindingKind, String, u64)> {
+        assemble_program(source, &AssemblerOptions::default())
+            .unwrap()
+            .findings
+            .into_iter()
+            .map(|finding| (finding.kind, finding.message, finding.location.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_program() {
+        let source = "main:\n jal ra, f\n jal zero, done\nf:\n beq a0, zero, out\n dec a0\nout:\n jalr zero, 0(ra)\ndone:\n";
+        // `done` is only past the end of the code, still a referenced label
+        assert_eq!(findings(source), []);
+    }
+
+    #[test]
+    fn test_dead_label_and_unreachable_block() {
+        let source = "main:\n jal zero, done\nunused:\n nop\n nop\ndone:\n nop\n";
+        assert_eq!(
+            findings(source),
+            [
+                (
+                    FindingKind::DeadLabel,
+                    "Label 'unused' is never referenced".to_string(),
+                    3
+                ),
+                (
+                    FindingKind::UnreachableCode,
+                    "Unreachable code, 2 instructions at 0x4".to_string(),
+                    4
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_after_return_is_unreachable() {
+        let source = ".globl helper\nhelper:\n jalr zero, 0(ra)\n nop\n";
+        assert_eq!(
+            findings(source),
+            [(
+                FindingKind::UnreachableCode,
+                "Unreachable code, 1 instruction at 0x4".to_string(),
+                4
+            )]
+        );
+    }
+
+    #[test]
+    fn test_branches_to_next_and_data_in_text() {
+        let source = "main:\n beq a0, a1, next\nnext:\n jal zero, 1f\n1:\n lw a0, =7\n la a1, table\n jal zero, main\ntable:\n .word 1\n .byte 2\n";
+        assert_eq!(
+            findings(source),
+            [
+                (
+                    FindingKind::BranchToNext,
+                    "'beq' to the next instruction does nothing".to_string(),
+                    2
+                ),
+                (
+                    FindingKind::BranchToNext,
+                    "'jal' to the next instruction does nothing".to_string(),
+                    4
+                ),
+                (
+                    FindingKind::DataInText,
+                    "'.word' places data in .text, between instructions; .rodata or .data keep it apart"
+                        .to_string(),
+                    10
+                ),
+            ]
+        );
+        // Calls to the next instruction load the address, data in .data is fine
+        assert_eq!(
+            findings("main:\n jal ra, 1f\n1:\n ecall\n.data\n.word 1\n"),
+            []
+        );
+        // So are instructions encoded by hand
+        assert_eq!(findings("main:\n nop\n .word 0x30200073\n"), []);
+    }
+
+    #[test]
+    fn test_warning_levels() {
+        let source = "main:\n li a0, 5\n nop\nunused:\n nop\n";
+        let assemble = |options: &[&str]| {
+            let mut warnings = Warnings::default();
+            for option in options {
+                warnings.apply(option).unwrap();
+            }
+            let options = AssemblerOptions {
+                warnings,
+                ..AssemblerOptions::default()
+            };
+            assemble_program(source, &options).map(|program| {
+                program
+                    .findings
+                    .into_iter()
+                    .map(|finding| finding.kind)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(assemble(&[]).unwrap(), [FindingKind::DeadLabel]);
+        assert_eq!(
+            assemble(&["li-fits-addi"]).unwrap(),
+            [FindingKind::LiFitsAddi, FindingKind::DeadLabel]
+        );
+        assert_eq!(
+            assemble(&["all", "no-dead-label"]).unwrap(),
+            [FindingKind::LiFitsAddi]
+        );
+        assert_eq!(assemble(&["no-dead-label", "error"]).unwrap(), []);
+        let error = assemble(&["error"]).unwrap_err().to_string();
+        assert!(error.contains("Label 'unused' is never referenced"));
+        assert!(assemble(&["error=li-fits-addi"]).is_err());
+        assert!(assemble(&["error", "no-error"]).is_ok());
+
+        let mut warnings = Warnings::default();
+        assert!(
+            warnings
+                .apply("frobnicate")
+                .unwrap_err()
+                .contains("dead-label")
+        );
+        assert!(warnings.apply("no-frobnicate").is_err());
+        assert_eq!(warnings, Warnings::default());
+    }
+}
@@ -1,51 +1,2286 @@
+use anyhow::Context;
+use tracing::{debug, info};
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
 use crate::{
+    analysis::{Finding, WarningLevel, Warnings, analyze},
+    elf,
+    encoder::{Xlen, encode},
     error::{AssemblerError, SourceLocation},
-    tokenizer::tokenize,
+    isa::is_known,
+    layout::{self, lay_out},
+    listing::Listing,
+    parser::{Operand, ParsedItem, Parser, SpannedItem, local_label_instance},
+    plugin::Plugins,
+    register::RegisterSet,
+    section::{LAYOUT_ORDER, Section, SectionExtent},
+    source::SourceInput,
+    symbol_table::SymbolTable,
+    tokenizer::tokenize_file,
+    xref::CrossReferences,
 };
 
+/// Settings that change how a source file is assembled
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AssemblerOptions {
+    /// Registers the target provides, `RegisterSet::Embedded` rejects x16-x31
+    pub register_set: RegisterSet,
+    /// Width of the target's registers, RV64-only instructions are rejected on RV32
+    pub xlen: Xlen,
+    /// Register aliases available from the first line, as if declared with `.register`
+    pub register_aliases: BTreeMap<String, u8>,
+    /// Which analysis findings (dead labels, unreachable code and the like) are reported,
+    /// and which fail the assembly
+    pub warnings: Warnings,
+    /// Replace lines that fail to assemble with an EBREAK and carry on, so the rest of the
+    /// program can run. The replaced lines end up in [`AssembledProgram::skipped_lines`].
+    pub permissive: bool,
+    /// Handlers of custom directives and instructions
+    pub plugins: Plugins,
+    /// Addresses to place sections at, the others follow the section before them in
+    /// [`LAYOUT_ORDER`] (`.text`, `.rodata`, `.data`, `.bss`) and `.text` starts at 0
+    pub section_bases: BTreeMap<Section, u32>,
+    /// Directories to look for `.include`d files in, after the directory of the file
+    /// including them, see [`include`](crate::include)
+    pub include_paths: Vec<PathBuf>,
+    /// Symbols defined before the first line as if with `.equ`, for `.if` and `.ifdef` to
+    /// pick a configuration
+    pub defines: BTreeMap<String, u32>,
+    /// Turn conditional branches to labels beyond their ±4 KiB reach into a branch on the
+    /// opposite condition over a `jal`, rather than failing
+    pub relax_branches: bool,
+}
+
+impl AssemblerOptions {
+    /// Options for an RV32E target
+    pub fn rv32e() -> Self {
+        Self {
+            register_set: RegisterSet::Embedded,
+            ..Self::default()
+        }
+    }
+
+    /// Options for an RV64I target
+    pub fn rv64i() -> Self {
+        Self {
+            xlen: Xlen::Rv64,
+            ..Self::default()
+        }
+    }
+}
+
+/// Alignment of `.vector_table`, a base address `mtvec` accepts on common cores
+const VECTOR_TABLE_ALIGNMENT: u32 = 64;
+
+/// Sections start at least this aligned, for the words in them
+const SECTION_ALIGNMENT: u32 = 4;
+
+/// Most bytes a program can span from its lowest address to its highest, 256 MiB. Far more
+/// than the memories it runs in, and keeps `.space`, `.org` and `.align` from asking for
+/// more memory than there is.
+pub(crate) const MAX_IMAGE_SIZE: u32 = 1 << 28;
+
+/// Addresses assigned to the parsed program
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+    /// Address of every parsed item, indexed like the item list
+    item_addresses: Vec<u32>,
+    /// Section of every parsed item
+    item_sections: Vec<Section>,
+    /// Section the next item goes to
+    section: Section,
+    /// Next free offset from the start of each section used so far
+    location_counters: BTreeMap<Section, u32>,
+    /// Alignment a section's start needs beyond [`SECTION_ALIGNMENT`], for the `.align` or
+    /// `.vector_table` directives in it
+    alignments: BTreeMap<Section, u32>,
+    /// Addresses sections were given in the options, the others follow the section before
+    /// them in [`LAYOUT_ORDER`]
+    section_bases: BTreeMap<Section, u32>,
+    /// Where the sections ended up, in address order
+    sections: Vec<SectionExtent>,
+    /// Bytes placed by plugin directives, by item index
+    plugin_data: BTreeMap<usize, Vec<u8>>,
+    /// Bytes placed by the data directives other than `.word`, by item index
+    data: BTreeMap<usize, Vec<u8>>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A map placing the sections in `section_bases` at their addresses
+    pub fn with_section_bases(section_bases: BTreeMap<Section, u32>) -> Self {
+        Self {
+            section_bases,
+            ..Self::default()
+        }
+    }
+
+    pub fn address_of(&self, item_index: usize) -> u32 {
+        self.item_addresses[item_index]
+    }
+
+    pub fn section_of(&self, item_index: usize) -> Section {
+        self.item_sections[item_index]
+    }
+
+    /// Where the sections ended up, in address order
+    pub fn sections(&self) -> &[SectionExtent] {
+        &self.sections
+    }
+
+    /// Address the program image starts at, that of its lowest section
+    pub fn origin(&self) -> u32 {
+        self.sections
+            .iter()
+            .find(|extent| extent.size > 0)
+            .or(self.sections.first())
+            .map_or(0, |extent| extent.address)
+    }
+
+    /// Total size of the program image in bytes, from [`MemoryMap::origin`] to the end of
+    /// the highest section
+    pub fn size(&self) -> u32 {
+        let end = self
+            .sections
+            .iter()
+            .map(|extent| extent.address + extent.size)
+            .max();
+        end.map_or(0, |end| end - self.origin())
+    }
+
+    /// Alignment the start of `section` needs
+    pub(crate) fn alignment(&self, section: Section) -> u32 {
+        self.alignments
+            .get(&section)
+            .map_or(SECTION_ALIGNMENT, |&alignment| {
+                alignment.max(SECTION_ALIGNMENT)
+            })
+    }
+
+    fn align_section(&mut self, section: Section, alignment: u32) {
+        let current = self.alignments.entry(section).or_insert(alignment);
+        *current = (*current).max(alignment);
+    }
+
+    /// Gives every section used an address and turns the items' offsets into addresses.
+    /// Sections without a base in the options follow the one before them in
+    /// [`LAYOUT_ORDER`], and no two may overlap.
+    fn place_sections(&mut self, parsed_items: &[ParsedItem]) -> anyhow::Result<()> {
+        let first_item = |section: Section| {
+            let index = self
+                .item_sections
+                .iter()
+                .position(|&item_section| item_section == section);
+            // SAFETY: only sections items were placed in have a location counter
+            parsed_items[index.unwrap()].location().clone()
+        };
+        let mut next = 0u32;
+        let mut bases = BTreeMap::new();
+        for section in LAYOUT_ORDER {
+            let Some(&size) = self.location_counters.get(&section) else {
+                continue;
+            };
+            let alignment = self.alignment(section);
+            let address = match self.section_bases.get(&section) {
+                Some(&base) if base % alignment != 0 => {
+                    return Err(AssemblerError::ParserError {
+                        message: format!(
+                            "{} at {:#x} isn't aligned to the {} bytes it needs",
+                            section.name(),
+                            base,
+                            alignment
+                        ),
+                        location: first_item(section),
+                    }
+                    .into());
+                }
+                Some(&base) => Some(base),
+                None => next.checked_next_multiple_of(alignment),
+            };
+            let Some(end) = address.and_then(|address| address.checked_add(size)) else {
+                return Err(AssemblerError::ParserError {
+                    message: format!("{} does not fit below 4 GiB", section.name()),
+                    location: first_item(section),
+                }
+                .into());
+            };
+            next = end;
+            bases.insert(section, end - size);
+            self.sections.push(SectionExtent {
+                section,
+                address: end - size,
+                size,
+            });
+        }
+        self.sections.sort_by_key(|extent| extent.address);
+        let placed: Vec<&SectionExtent> = self
+            .sections
+            .iter()
+            .filter(|extent| extent.size > 0)
+            .collect();
+        for pair in placed.windows(2) {
+            let (lower, upper) = (pair[0], pair[1]);
+            if lower.address + lower.size > upper.address {
+                return Err(AssemblerError::ParserError {
+                    message: format!(
+                        "{} ({:#x}-{:#x}) overlaps {} ({:#x}-{:#x})",
+                        upper.section.name(),
+                        upper.address,
+                        upper.address + upper.size,
+                        lower.section.name(),
+                        lower.address,
+                        lower.address + lower.size
+                    ),
+                    location: first_item(upper.section),
+                }
+                .into());
+            }
+        }
+        let highest = self
+            .sections
+            .iter()
+            .max_by_key(|extent| extent.address + extent.size);
+        if let Some(highest) = highest
+            && self.size() > MAX_IMAGE_SIZE
+        {
+            return Err(AssemblerError::ParserError {
+                message: format!(
+                    "{} at {:#x} is more than the {} MiB a program can span above {:#x}",
+                    highest.section.name(),
+                    highest.address,
+                    MAX_IMAGE_SIZE >> 20,
+                    self.origin()
+                ),
+                location: first_item(highest.section),
+            }
+            .into());
+        }
+        for (address, section) in self.item_addresses.iter_mut().zip(&self.item_sections) {
+            *address += bases[section];
+        }
+        Ok(())
+    }
+
+    /// Bytes placed by the item at `item_index` that are known before labels are, those of
+    /// data directives like `.byte` and of plugin directives
+    pub(crate) fn fixed_data(&self, item_index: usize) -> Option<&[u8]> {
+        self.data
+            .get(&item_index)
+            .or_else(|| self.plugin_data.get(&item_index))
+            .map(Vec::as_slice)
+    }
+}
+
+/// Assembles `source`: a `&str` or `String`, a file's `&Path`, or any [`SourceInput`].
+///
 /// Supported instructions:
-/// LUI rd, imm #imm 0x00000 to 0xFFFFF
-/// ADDI rd, rs1, imm #imm -0x800 to +0x7FF
-/// ADD rd, rs1, rs2
-/// SUB rd, rs1, rs2
+/// The RV32I base integer instruction set:
+/// LUI, AUIPC, JAL, JALR, BEQ, BNE, BLT, BGE, BLTU, BGEU,
+/// LB, LH, LW, LBU, LHU, SB, SH, SW,
+/// ADDI, SLTI, SLTIU, XORI, ORI, ANDI, SLLI, SRLI, SRAI,
+/// ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND, ECALL, EBREAK
+/// The M extension:
+/// MUL, MULH, MULHSU, MULHU, DIV, DIVU, REM, REMU
+/// The A extension, addressing memory as (rs1) and each with optional .AQ, .RL or .AQRL
+/// ordering:
+/// LR.W, SC.W, AMOSWAP.W, AMOADD.W, AMOXOR.W, AMOAND.W, AMOOR.W, AMOMIN.W, AMOMAX.W,
+/// AMOMINU.W, AMOMAXU.W
+/// The F and D extensions, f0-f31 with an optional rounding mode operand (rne, rtz, rdn,
+/// rup, rmm, dyn) on operations that round:
+/// FLW, FSW, FLD, FSD, FMADD, FMSUB, FNMSUB, FNMADD, FADD, FSUB, FMUL, FDIV, FSQRT,
+/// FSGNJ, FSGNJN, FSGNJX, FMIN, FMAX, FEQ, FLT, FLE, FCLASS (each .S and .D),
+/// FCVT.W.S, FCVT.WU.S, FCVT.S.W, FCVT.S.WU, FCVT.W.D, FCVT.WU.D, FCVT.D.W, FCVT.D.WU,
+/// FCVT.S.D, FCVT.D.S, FMV.X.W, FMV.W.X
+/// The C extension's integer instructions (C.ADDI, C.LW, C.J, ...), written explicitly or
+/// picked automatically after `.option rvc`
+/// The Zicsr extension, naming the CSR (mstatus, mtvec, mepc, fcsr, ...):
+/// CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI
+/// Relocation operators, for the immediates and memory offsets of instructions:
+/// %hi(label) and %lo(label) -> the parts of a label's address, as in
+/// LUI rd, %hi(label) + ADDI rd, rd, %lo(label) or LW rd, %lo(label)(rd)
+/// %pcrel_hi(label) and %pcrel_lo(auipc) -> the parts of a PC relative offset, as in
+/// here: AUIPC rd, %pcrel_hi(label) + ADDI rd, rd, %pcrel_lo(here), the %pcrel_lo naming
+/// the AUIPC right before it
+/// Constant expressions wherever a number or label goes, with C's operators and precedence:
+/// `addi a0, a0, (BUF_SIZE * 4) + 8`, `beq a0, a1, end - 4`, `.word end - start`
+/// Macros with parameters, `.macro NAME PARAMETERS` ... `.endm`, see [`macros`](crate::macros)
+/// Numeric local labels, defined any number of times as `1:` and referred to as `1b` (the
+/// closest definition before) or `1f` (the closest after)
+/// Conditional assembly, `.if expr`, `.ifdef NAME` or `.ifndef NAME` ... `.elseif expr` ...
+/// `.else` ... `.endif`, on `.equ` symbols and [`AssemblerOptions::defines`], see
+/// [`conditional`](crate::conditional)
 /// Supported pseudoinstructions:
 /// INC rd -> ADDI rd, rd, 1
 /// DEC rd -> ADDI rd, rd, -1
 /// MV rd, rs1 -> ADDI rd, rs1, 0
 /// NOP -> ADDI x0, x0, 0
-/// NEG rd -> SUB rd, x0, rd
-/// LI rd, imm -> DEPENDS ON imm SIZE (1-3 instructions)
-pub struct Assembler {
-    bytes: Vec<u8>,
+/// NEG rd -> SUB rd, x0, rd (NEG rd, rs -> SUB rd, x0, rs)
+/// NOT rd, rs -> XORI rd, rs, -1
+/// SEQZ, SNEZ, SLTZ, SGTZ rd, rs -> SLTIU rd, rs, 1, SLTU rd, x0, rs, SLT rd, rs, x0 and
+/// SLT rd, x0, rs
+/// NEGW rd, rs -> SUBW rd, x0, rs and SEXT.W rd, rs -> ADDIW rd, rs, 0 (RV64)
+/// FMV, FABS, FNEG fd, fs (each .S and .D) -> FSGNJ, FSGNJX, FSGNJN fd, fs, fs
+/// BEQZ, BNEZ, BLEZ, BGEZ, BLTZ, BGTZ rs, label -> the branch comparing rs with x0
+/// BGT, BLE, BGTU, BLEU rs, rt, label -> BLT, BGE, BLTU, BGEU rt, rs, label
+/// J label -> JAL x0, label and JAL label -> JAL ra, label
+/// JR rs -> JALR x0, 0(rs), JALR rs -> JALR ra, 0(rs) and RET -> JALR x0, 0(ra)
+/// CALL label -> AUIPC ra + JALR ra (CALL rd, label through rd), TAIL label -> AUIPC t1 +
+/// JALR x0, each reaching anywhere
+/// LA rd, label and LLA rd, label -> AUIPC rd + ADDI rd, rd, the PC relative address
+/// LB, LH, LW, LBU, LHU (LD, LWU) rd, label -> AUIPC rd + the load from rd
+/// SB, SH, SW (SD), FLW, FLD, FSW, FSD reg, label, rt -> AUIPC rt + the access through rt
+/// LI rd, imm -> ADDI rd, x0, imm or LUI rd, %hi(imm) + ADDI rd, rd, %lo(imm) (ADDIW on
+/// RV64), larger RV64 values add SLLI and ADDI steps. RV32 takes any i32 or u32 value.
+/// LW rd, =value -> AUIPC rd + LW rd reading the value (a number or a label's address) from
+/// a literal pool. Pools are placed at `.ltorg`, which has to be somewhere execution doesn't
+/// fall into, and after the end of the program.
+pub fn assemble<'a>(source: impl Into<SourceInput<'a>>) -> anyhow::Result<Vec<u8>> {
+    assemble_with_options(source, &AssemblerOptions::default())
+}
+
+pub fn assemble_with_options<'a>(
+    source: impl Into<SourceInput<'a>>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<u8>> {
+    assemble_program(source, options).map(|program| program.bytes)
+}
+
+/// Machine code together with the final symbol table, for symbol files and debuggers
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AssembledProgram {
+    /// The program image, starting at [`AssembledProgram::origin`]
+    pub bytes: Vec<u8>,
+    /// Address of the first byte, 0 unless every section was placed higher
+    pub origin: u32,
+    /// Where each section used ended up, in address order
+    pub sections: Vec<SectionExtent>,
+    pub symbols: SymbolTable,
+    /// Source location of every instruction by address, pseudoinstructions expanding to
+    /// several instructions map each of them to the same line
+    pub line_map: Vec<(u32, SourceLocation)>,
+    /// Instructions referring to each label
+    pub xrefs: CrossReferences,
+    /// Dead labels, unreachable code and the like, the kinds the options have warned about,
+    /// in source order
+    pub findings: Vec<Finding>,
+    /// Labelled data in `.rodata`, `.data` and `.bss`, in address order
+    pub data_objects: Vec<DataObject>,
+    /// Lines replaced by an EBREAK in permissive mode, in source order
+    pub skipped_lines: Vec<SkippedLine>,
+    /// Directives handled by plugins, in source order, e.g. for metadata they carry
+    pub plugin_directives: Vec<PluginDirective>,
+    /// Addresses and symbol values of every source line, see [`Listing::render`]
+    pub listing: Listing,
+}
+
+/// A directive a plugin handled, placed at `address`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PluginDirective {
+    pub name: String,
+    pub args: Vec<Operand>,
+    pub address: u32,
+    pub location: SourceLocation,
+}
+
+/// A line that failed to assemble in permissive mode, the EBREAK in its place traps at
+/// `address`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SkippedLine {
+    pub address: u32,
+    pub location: SourceLocation,
+    /// Why the line failed to assemble
+    pub message: String,
+}
+
+/// A label in the `.rodata`, `.data` or `.bss` section and the data placed after it, e.g. a `.word`
+/// array
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataObject {
+    pub name: String,
+    pub address: u32,
+    /// Bytes up to the next label in the section or the section's end
+    pub size: u32,
+}
+
+/// Like [`assemble_with_options`], but keeps the symbol table. Errors in a named source
+/// (a file, say) say which one they are in.
+pub fn assemble_program<'a>(
+    source: impl Into<SourceInput<'a>>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<AssembledProgram> {
+    with_source_text(source.into(), |text, path| {
+        assemble_text(text, path, options)
+    })
+}
+
+/// Assembles `source` into a relocatable ELF object file for `ld` or `lld` to link, see
+/// [`elf`](crate::elf). Symbols the source doesn't define are left for the linker to
+/// resolve instead of being errors.
+pub fn assemble_object<'a>(
+    source: impl Into<SourceInput<'a>>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<u8>> {
+    with_source_text(source.into(), |text, path| {
+        let tokens = tokenize_file(text, path, options)?;
+        let mut symbol_table = SymbolTable::new();
+        let parsed_items = Parser::with_options(tokens, options).parse_all(&mut symbol_table)?;
+        let parsed_items = layout::worst_case(&parsed_items, &symbol_table, options.xlen)?;
+        elf::write_object(&parsed_items, &mut symbol_table, options)
+    })
+}
+
+/// Parses `source` without assembling it, for tools that work on the program's structure,
+/// like formatters and linters. Symbols don't have to be defined.
+pub fn parse<'a>(
+    source: impl Into<SourceInput<'a>>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<SpannedItem>> {
+    with_source_text(source.into(), |text, path| {
+        let tokens = tokenize_file(text, path, options)?;
+        Parser::with_options(tokens, options).parse_spanned(&mut SymbolTable::new())
+    })
 }
-impl Assembler {}
 
-pub fn assemble(source: &str) -> anyhow::Result<Vec<u8>> {
-    let tokens = tokenize(source)?;
+/// Reads `source` and runs `assemble` on its text and the path of its file, naming the
+/// source in errors if it has a name
+fn with_source_text<T>(
+    source: SourceInput<'_>,
+    assemble: impl FnOnce(&str, Option<&Path>) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let path = source.path().map(Path::to_path_buf);
+    let Some(name) = source.name().map(str::to_string) else {
+        let text = source.read().context("reading the source")?;
+        return assemble(&text, path.as_deref());
+    };
+    let text = source.read().with_context(|| format!("reading {}", name))?;
+    assemble(&text, path.as_deref()).with_context(|| format!("assembling {}", name))
+}
+
+fn assemble_text(
+    source: &str,
+    path: Option<&Path>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<AssembledProgram> {
+    if options.permissive {
+        assemble_permissive(source, path, options)
+    } else {
+        assemble_lines(source, path, options)
+    }
+}
+
+/// Assembles `source`, replacing every line an error points at with an EBREAK until the
+/// rest assembles. Labels in front of a bad line are kept unless they are the problem, so
+/// references to them still resolve. Findings that are errors and errors without a
+/// line still fail, as do errors in included files, which aren't replaced.
+fn assemble_permissive(
+    source: &str,
+    path: Option<&Path>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<AssembledProgram> {
+    // Split like the tokenizer does, so line numbers agree
+    let mut lines: Vec<Cow<str>> = source.split('\n').map(Cow::Borrowed).collect();
+    // Index of every replaced line, and whether its placeholder kept labels that can still
+    // be dropped
+    let mut replaced: BTreeMap<usize, bool> = BTreeMap::new();
+    let mut skipped: Vec<(SourceLocation, String)> = Vec::new();
+    loop {
+        let error = match assemble_lines(&lines.join("\n"), path, options) {
+            Ok(mut program) => {
+                skipped.sort_by_key(|(location, _)| location.line);
+                program.skipped_lines = skipped
+                    .into_iter()
+                    .filter_map(|(location, message)| {
+                        let address = program
+                            .line_map
+                            .iter()
+                            .find(|(_, instruction)| {
+                                instruction.file.is_none() && instruction.line == location.line
+                            })?
+                            .0;
+                        Some(SkippedLine {
+                            address,
+                            location,
+                            message,
+                        })
+                    })
+                    .collect();
+                return Ok(program);
+            }
+            Err(error) => error,
+        };
+        let Some(assembler_error) = error.downcast_ref::<AssemblerError>() else {
+            return Err(error);
+        };
+        let errors = match assembler_error {
+            AssemblerError::MultipleErrors(errors) => errors.as_slice(),
+            error => std::slice::from_ref(error),
+        };
+        let mut progress = false;
+        let diagnostics = errors
+            .iter()
+            .filter(|error| !matches!(error, AssemblerError::AnalysisError { .. }))
+            .flat_map(AssemblerError::diagnostics);
+        for (message, location) in diagnostics.filter(|(_, location)| location.file.is_none()) {
+            let index = (location.line as usize).wrapping_sub(1);
+            let Some(line) = lines.get_mut(index) else {
+                continue;
+            };
+            let placeholder = match replaced.get(&index) {
+                None => with_labels_kept(line, "ebreak"),
+                // The labels themselves are wrong, e.g. defined twice
+                Some(true) => "ebreak".to_string(),
+                Some(false) => continue,
+            };
+            debug!(line = location.line, message, "replacing line with ebreak");
+            replaced.insert(index, placeholder != "ebreak");
+            *line = Cow::Owned(placeholder);
+            skipped.retain(|(skipped, _)| skipped.line != location.line);
+            skipped.push((location.clone(), message.to_string()));
+            progress = true;
+        }
+        if !progress {
+            return Err(error);
+        }
+    }
+}
+
+/// `replacement` in place of `line`, after the labels `line` starts with
+fn with_labels_kept(line: &str, replacement: &str) -> String {
+    let mut rest = line;
+    while let Some((label, after)) = rest.split_once(':')
+        && is_label_name(label.trim())
+    {
+        rest = after;
+    }
+    let labels = &line[..line.len() - rest.len()];
+    if labels.is_empty() {
+        replacement.to_string()
+    } else {
+        format!("{} {}", labels, replacement)
+    }
+}
+
+/// Whether `name` is a label's, numeric local labels included
+fn is_label_name(name: &str) -> bool {
+    let is_numeric = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+    is_numeric
+        || name.starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '_' | '.' | '$'))
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+}
+
+fn assemble_lines(
+    source: &str,
+    path: Option<&Path>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<AssembledProgram> {
+    let tokens = tokenize_file(source, path, options)?;
 
     let mut symbol_table = SymbolTable::new();
-    let mut parser = Parser::new();
+    let mut parser = Parser::with_options(tokens, options);
     let parsed_items = parser.parse_all(&mut symbol_table)?;
+    let source_findings = parser.take_findings();
 
     let unresolved = symbol_table.check_for_unresolved();
     if !unresolved.is_empty() {
+        debug!(count = unresolved.len(), "unresolved symbols");
         let mut errors = Vec::new();
-        for (name, line) in unresolved {
-            errors.push(AssemblerError::SymbolError {
-                message: format!("Undefined symbol: {}", name),
-                location: SourceLocation { line, col: 0 },
-            })
+        for (name, location) in unresolved {
+            let message = match local_label_instance(&name) {
+                Some((number, 0)) => {
+                    format!("No label '{}:' before the reference to {}b", number, number)
+                }
+                Some((number, _)) => {
+                    format!("No label '{}:' after the reference to {}f", number, number)
+                }
+                None => format!("Undefined symbol: {}", name),
+            };
+            errors.push(AssemblerError::SymbolError { message, location })
         }
         if errors.len() == 1 {
-            return anyhow::Err(errors.remove(0));
+            return Err(errors.remove(0).into());
         } else {
-            return anyhow::Err(AssemblerError::MultipleErrors(errors));
+            return Err(AssemblerError::MultipleErrors(errors).into());
         }
     }
 
-    let mut memory_map = MemoryMap::new();
-    allocate_memory(&mut memory_map, &parsed_items)?;
+    let (parsed_items, memory_map) = lay_out(&parsed_items, &mut symbol_table, options)?;
 
-    let output = generate_machine_code(&memory_map, &symbol_table, &parsed_items)?;
+    let output = generate_machine_code(
+        &memory_map,
+        &symbol_table,
+        &parsed_items,
+        options.xlen,
+        &options.plugins,
+    )?;
+    let xrefs = CrossReferences::build(&symbol_table, &memory_map, &parsed_items);
+    let mut findings = analyze(&symbol_table, &memory_map, &parsed_items, &xrefs);
+    findings.extend(source_findings);
+    findings.sort_by_key(|finding| (finding.location.line, finding.location.col));
+    findings.retain(|finding| options.warnings.level(finding.kind) != WarningLevel::Allow);
+    let mut errors: Vec<AssemblerError> = findings
+        .iter()
+        .filter(|finding| options.warnings.level(finding.kind) == WarningLevel::Deny)
+        .map(Finding::to_error)
+        .collect();
+    if !errors.is_empty() {
+        if errors.len() == 1 {
+            return Err(errors.remove(0).into());
+        }
+        return Err(AssemblerError::MultipleErrors(errors).into());
+    }
+    info!(
+        bytes = output.len(),
+        symbols = symbol_table.len(),
+        findings = findings.len(),
+        "assembled program"
+    );
+    Ok(AssembledProgram {
+        bytes: output,
+        origin: memory_map.origin(),
+        sections: memory_map.sections().to_vec(),
+        line_map: line_map(&memory_map, &parsed_items),
+        xrefs,
+        findings,
+        data_objects: data_objects(&memory_map, &parsed_items),
+        skipped_lines: Vec::new(),
+        plugin_directives: plugin_directives(&memory_map, &parsed_items),
+        listing: Listing::build(&symbol_table, &memory_map, &parsed_items),
+        symbols: symbol_table,
+    })
+}
+
+/// Assigns an address to every item and resolves label addresses. Items are placed in
+/// their section first, then the sections in memory, see [`MemoryMap::place_sections`].
+pub(crate) fn allocate_memory(
+    memory_map: &mut MemoryMap,
+    symbol_table: &mut SymbolTable,
+    parsed_items: &[ParsedItem],
+    plugins: &Plugins,
+) -> anyhow::Result<()> {
+    // Items of a literal pool left to place, and the section to return to after them
+    let mut pool: Option<(usize, Section)> = None;
+    for (index, item) in parsed_items.iter().enumerate() {
+        if let ParsedItem::Directive { name, args, .. } = item {
+            if let Some(section) = Section::from_directive(name) {
+                memory_map.section = section;
+            } else if name == ".ltorg"
+                && memory_map.section == Section::Bss
+                && let [Operand::Immediate(count @ 1..)] = args[..]
+            {
+                // `.bss` can't hold the literals, so they go to `.text`
+                pool = Some((2 * count as usize + 1, Section::Bss));
+                memory_map.section = Section::Text;
+            }
+        }
+        let section = memory_map.section;
+        let mut counter = memory_map
+            .location_counters
+            .get(&section)
+            .copied()
+            .unwrap_or(0);
+        memory_map.item_addresses.push(counter);
+        memory_map.item_sections.push(section);
+        match item {
+            ParsedItem::Label { .. } => {}
+            ParsedItem::Instruction(instruction) => {
+                counter += instruction.size();
+            }
+            ParsedItem::Directive {
+                name,
+                args,
+                location,
+            } => match name.as_str() {
+                ".text" | ".data" | ".bss" | ".rodata" | ".globl" | ".global" => {}
+                ".word" => {
+                    if args.is_empty() {
+                        return Err(AssemblerError::ParserError {
+                            message: "'.word' expects at least one value".to_string(),
+                            location: location.clone(),
+                        }
+                        .into());
+                    }
+                    counter += 4 * args.len() as u32;
+                }
+                ".half" | ".byte" | ".ascii" | ".asciz" | ".string" | ".space" | ".zero" => {
+                    let error = |message: String| AssemblerError::ParserError {
+                        message,
+                        location: location.clone(),
+                    };
+                    let bytes = data_bytes(name, args).map_err(error)?;
+                    counter = grow(counter, bytes.len() as u32, name).map_err(error)?;
+                    memory_map.data.insert(index, bytes);
+                }
+                ".align" | ".p2align" => {
+                    let error = |message: String| AssemblerError::ParserError {
+                        message,
+                        location: location.clone(),
+                    };
+                    let (alignment, padding) =
+                        alignment_padding(name, args, counter, section).map_err(error)?;
+                    counter = grow(counter, padding.len() as u32, name).map_err(error)?;
+                    memory_map.align_section(section, alignment);
+                    memory_map.data.insert(index, padding);
+                }
+                ".org" => {
+                    let address = match args.as_slice() {
+                        [Operand::Immediate(address)] => u32::try_from(*address).ok(),
+                        _ => None,
+                    };
+                    let Some(address) = address else {
+                        return Err(AssemblerError::ParserError {
+                            message: "'.org' expects an address".to_string(),
+                            location: location.clone(),
+                        }
+                        .into());
+                    };
+                    if address < counter {
+                        return Err(AssemblerError::ParserError {
+                            message: format!(
+                                "'.org {:#x}' would move the location counter back from {:#x}",
+                                address, counter
+                            ),
+                            location: location.clone(),
+                        }
+                        .into());
+                    }
+                    counter = grow(counter, address - counter, ".org").map_err(|message| {
+                        AssemblerError::ParserError {
+                            message,
+                            location: location.clone(),
+                        }
+                    })?;
+                }
+                // Literal pools are word aligned, the parser places the literals after it
+                ".ltorg" => {
+                    let Some(address) = counter.checked_next_multiple_of(4) else {
+                        return Err(AssemblerError::ParserError {
+                            message: "The literal pool does not fit below 4 GiB".to_string(),
+                            location: location.clone(),
+                        }
+                        .into());
+                    };
+                    counter = address;
+                }
+                ".vector_table" => {
+                    let Some(address) = counter.checked_next_multiple_of(VECTOR_TABLE_ALIGNMENT)
+                    else {
+                        return Err(AssemblerError::ParserError {
+                            message: "'.vector_table' does not fit below 4 GiB".to_string(),
+                            location: location.clone(),
+                        }
+                        .into());
+                    };
+                    counter = address;
+                    memory_map.align_section(section, VECTOR_TABLE_ALIGNMENT);
+                }
+                _ => {
+                    let Some(plugin) = plugins.directive(name) else {
+                        return Err(AssemblerError::ParserError {
+                            message: format!("Unsupported directive '{}'", name),
+                            location: location.clone(),
+                        }
+                        .into());
+                    };
+                    let bytes = plugin.directive(name, args).map_err(|message| {
+                        AssemblerError::ParserError {
+                            message,
+                            location: location.clone(),
+                        }
+                    })?;
+                    counter += bytes.len() as u32;
+                    memory_map.plugin_data.insert(index, bytes);
+                }
+            },
+        }
+        memory_map.location_counters.insert(section, counter);
+        if let Some((left, previous)) = &mut pool {
+            *left -= 1;
+            if *left == 0 {
+                memory_map.section = *previous;
+                pool = None;
+            }
+        }
+    }
+
+    memory_map.place_sections(parsed_items)?;
+    for (index, item) in parsed_items.iter().enumerate() {
+        if let ParsedItem::Label { name, .. } = item {
+            symbol_table.set_address(name, memory_map.address_of(index));
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the program into a little-endian image starting at the memory map's origin,
+/// gaps between and inside the sections are zero filled
+fn generate_machine_code(
+    memory_map: &MemoryMap,
+    symbol_table: &SymbolTable,
+    parsed_items: &[ParsedItem],
+    xlen: Xlen,
+    plugins: &Plugins,
+) -> anyhow::Result<Vec<u8>> {
+    let mut output = vec![0; memory_map.size() as usize];
+    let origin = memory_map.origin();
+    let mut place = |address: u32, bytes: &[u8]| {
+        if bytes.is_empty() {
+            return;
+        }
+        let start = (address - origin) as usize;
+        output[start..start + bytes.len()].copy_from_slice(bytes);
+    };
+    for (index, item) in parsed_items.iter().enumerate() {
+        let address = memory_map.address_of(index);
+        let in_bss = memory_map.section_of(index) == Section::Bss;
+        match item {
+            ParsedItem::Instruction(instruction) => {
+                if in_bss {
+                    return Err(bss_error(&instruction.location));
+                }
+                let plugin = plugins
+                    .instruction(&instruction.mnemonic)
+                    .filter(|_| !is_known(&instruction.mnemonic));
+                let word =
+                    match plugin {
+                        Some(plugin) => plugin.encode(instruction, address, symbol_table).map_err(
+                            |message| AssemblerError::EncoderError {
+                                message,
+                                location: instruction.location.clone(),
+                            },
+                        )?,
+                        None => encode(instruction, address, symbol_table, xlen)?,
+                    };
+                place(address, &word.to_le_bytes()[..instruction.size() as usize]);
+            }
+            ParsedItem::Directive {
+                name,
+                args,
+                location,
+            } if name == ".word" => {
+                for (offset, arg) in args.iter().enumerate() {
+                    let value = word_value(arg, symbol_table).map_err(|message| {
+                        AssemblerError::ParserError {
+                            message,
+                            location: location.clone(),
+                        }
+                    })?;
+                    if in_bss && value != 0 {
+                        return Err(bss_error(location));
+                    }
+                    place(address + 4 * offset as u32, &value.to_le_bytes());
+                }
+            }
+            _ => {
+                if let Some(bytes) = memory_map.fixed_data(index) {
+                    if in_bss
+                        && bytes.iter().any(|&byte| byte != 0)
+                        && let ParsedItem::Directive { location, .. } = item
+                    {
+                        return Err(bss_error(location));
+                    }
+                    place(address, bytes);
+                }
+            }
+        }
+    }
     Ok(output)
 }
+
+/// `.bss` only reserves space, every output format rejects anything but zeros in it
+pub(crate) fn bss_error(location: &SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: "'.bss' can only reserve zeroed space".to_string(),
+        location: location.clone(),
+    }
+    .into()
+}
+
+/// Alignment `.align n[, fill[, max]]` or its synonym `.p2align` asks for, 2 to the power of
+/// `n` like GNU `as` on RISC-V, and the padding from `counter` up to it. Without a fill byte
+/// `.text` is padded with NOPs, a `c.nop` first if needed, and the other sections with zeros.
+/// When more than `max` bytes would be needed nothing is padded.
+fn alignment_padding(
+    name: &str,
+    args: &[Operand],
+    counter: u32,
+    section: Section,
+) -> Result<(u32, Vec<u8>), String> {
+    let (exponent, fill, max) = match args {
+        [Operand::Immediate(exponent)] => (*exponent, None, None),
+        [Operand::Immediate(exponent), Operand::Immediate(fill)] => (*exponent, Some(*fill), None),
+        [
+            Operand::Immediate(exponent),
+            Operand::Immediate(fill),
+            Operand::Immediate(max),
+        ] => (*exponent, Some(*fill), Some(*max)),
+        _ => {
+            return Err(format!(
+                "'{}' expects a power of two exponent, an optional fill byte and an optional \
+                 maximum padding",
+                name
+            ));
+        }
+    };
+    let alignment = u32::try_from(exponent)
+        .ok()
+        .and_then(|exponent| 1u32.checked_shl(exponent))
+        .ok_or_else(|| format!("Invalid '{}' exponent {}", name, exponent))?;
+    let fill = fill
+        .map(|fill| {
+            u8::try_from(fill)
+                .or_else(|_| i8::try_from(fill).map(|fill| fill as u8))
+                .map_err(|_| format!("'{}' fill value {} is not a byte", name, fill))
+        })
+        .transpose()?;
+    let end = counter
+        .checked_next_multiple_of(alignment)
+        .ok_or_else(|| format!("'{}' does not fit below 4 GiB", name))?;
+    let len = end - counter;
+    if max.is_some_and(|max| i64::from(len) > max) {
+        return Ok((1, Vec::new()));
+    }
+    grow(counter, len, name)?;
+    let mut padding = Vec::new();
+    padding
+        .try_reserve_exact(len as usize)
+        .map_err(|_| format!("Out of memory for the {} bytes of '{}' padding", len, name))?;
+    match fill {
+        Some(fill) => padding.resize(len as usize, fill),
+        None if section == Section::Text => {
+            let mut address = counter;
+            while address < end {
+                let step: &[u8] = if !address.is_multiple_of(2) || end - address < 2 {
+                    &[0]
+                } else if !address.is_multiple_of(4) || end - address < 4 {
+                    &0x0001u16.to_le_bytes() // c.nop
+                } else {
+                    &0x00000013u32.to_le_bytes() // addi zero, zero, 0
+                };
+                padding.extend_from_slice(step);
+                address += step.len() as u32;
+            }
+        }
+        None => padding.resize(len as usize, 0),
+    }
+    Ok((alignment, padding))
+}
+
+/// The location counter after `size` bytes of `directive`, if it stays within
+/// [`MAX_IMAGE_SIZE`]
+fn grow(counter: u32, size: u32, directive: &str) -> Result<u32, String> {
+    counter
+        .checked_add(size)
+        .filter(|&end| end <= MAX_IMAGE_SIZE)
+        .ok_or_else(|| {
+            format!(
+                "'{}' grows the section past the {} MiB a program can span",
+                directive,
+                MAX_IMAGE_SIZE >> 20
+            )
+        })
+}
+
+/// Bytes of a `.half`, `.byte`, `.ascii`, `.asciz` (or `.string`), `.space` or `.zero`
+/// directive. Halves and bytes take numbers only, signed or unsigned.
+fn data_bytes(name: &str, args: &[Operand]) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    match name {
+        ".half" | ".byte" => {
+            if args.is_empty() {
+                return Err(format!("'{}' expects at least one value", name));
+            }
+            let size = if name == ".half" { 2 } else { 1 };
+            let bits = 8 * size as u32;
+            for arg in args {
+                let value = match arg {
+                    Operand::Immediate(value)
+                        if *value >= -(1 << (bits - 1)) && *value < 1 << bits =>
+                    {
+                        *value
+                    }
+                    _ => return Err(format!("'{}' values must be {} bit numbers", name, bits)),
+                };
+                bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+            }
+        }
+        ".ascii" | ".asciz" | ".string" => {
+            if args.is_empty() {
+                return Err(format!("'{}' expects at least one string", name));
+            }
+            for arg in args {
+                let Operand::String(text) = arg else {
+                    return Err(format!("'{}' expects strings", name));
+                };
+                bytes.extend_from_slice(text);
+                if name != ".ascii" {
+                    bytes.push(0);
+                }
+            }
+        }
+        _ => {
+            let (count, fill) = match args {
+                [Operand::Immediate(count)] => (*count, 0),
+                [Operand::Immediate(count), Operand::Immediate(fill)] if name == ".space" => {
+                    (*count, *fill)
+                }
+                _ if name == ".space" => {
+                    return Err("'.space' expects a size and an optional fill byte".to_string());
+                }
+                _ => return Err("'.zero' expects a size".to_string()),
+            };
+            let count =
+                u32::try_from(count).map_err(|_| format!("Invalid '{}' size {}", name, count))?;
+            let fill = u8::try_from(fill)
+                .or_else(|_| i8::try_from(fill).map(|fill| fill as u8))
+                .map_err(|_| format!("'{}' fill value {} is not a byte", name, fill))?;
+            grow(0, count, name)?;
+            bytes
+                .try_reserve_exact(count as usize)
+                .map_err(|_| format!("Out of memory for the {} bytes of '{}'", count, name))?;
+            bytes.resize(count as usize, fill);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Value of one `.word` operand, numbers may be given signed or unsigned
+pub(crate) fn word_value(operand: &Operand, symbol_table: &SymbolTable) -> Result<u32, String> {
+    let invalid = || "'.word' values must be 32 bit numbers, labels or expressions".to_string();
+    let value = match operand {
+        Operand::Immediate(value) => *value,
+        Operand::Symbol(name) => return symbol_table.address(name).ok_or_else(invalid),
+        Operand::Expression(expression) => {
+            expression.evaluate(&|name| symbol_table.address(name).map(i64::from))?
+        }
+        _ => return Err(invalid()),
+    };
+    u32::try_from(value)
+        .ok()
+        .or_else(|| i32::try_from(value).ok().map(|value| value as u32))
+        .ok_or_else(|| format!("'.word' value {} is out of the 32 bit range", value))
+}
+
+/// Extents of the labels in `.rodata`, `.data` and `.bss`, each running up to the next
+/// label in its section or the section's end
+fn data_objects(memory_map: &MemoryMap, parsed_items: &[ParsedItem]) -> Vec<DataObject> {
+    let mut objects = Vec::new();
+    for extent in memory_map.sections() {
+        if extent.section == Section::Text {
+            continue;
+        }
+        let mut labels: Vec<(&str, u32)> = parsed_items
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| memory_map.section_of(*index) == extent.section)
+            .filter_map(|(index, item)| match item {
+                ParsedItem::Label { name, .. } => {
+                    Some((name.as_str(), memory_map.address_of(index)))
+                }
+                _ => None,
+            })
+            .collect();
+        labels.sort_by_key(|(_, address)| *address);
+        let ends = labels
+            .iter()
+            .skip(1)
+            .map(|(_, address)| *address)
+            .chain([extent.address + extent.size]);
+        for ((name, address), end) in labels.iter().zip(ends) {
+            if end > *address {
+                objects.push(DataObject {
+                    name: name.to_string(),
+                    address: *address,
+                    size: end - address,
+                });
+            }
+        }
+    }
+    objects
+}
+
+fn plugin_directives(memory_map: &MemoryMap, parsed_items: &[ParsedItem]) -> Vec<PluginDirective> {
+    parsed_items
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| memory_map.plugin_data.contains_key(index))
+        .filter_map(|(index, item)| match item {
+            ParsedItem::Directive {
+                name,
+                args,
+                location,
+            } => Some(PluginDirective {
+                name: name.clone(),
+                args: args.clone(),
+                address: memory_map.address_of(index),
+                location: location.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Address and source location of every instruction, in address order
+fn line_map(memory_map: &MemoryMap, parsed_items: &[ParsedItem]) -> Vec<(u32, SourceLocation)> {
+    parsed_items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| match item {
+            ParsedItem::Instruction(instruction) => {
+                Some((memory_map.address_of(index), instruction.location.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::xorshift64;
+
+    const PROGRAM: &str = "
+        .text
+        .globl main
+        main:
+            li a0, 10
+            li a1, 0
+        loop:
+            beq a0, zero, done
+            add a1, a1, a0
+            dec a0
+            jal zero, loop
+        done:
+            mv a0, a1
+            ecall
+    ";
+
+    #[test]
+    fn test_output_is_reproducible() {
+        let first = assemble(PROGRAM).unwrap();
+        for _ in 0..10 {
+            assert_eq!(assemble(PROGRAM).unwrap(), first);
+        }
+        assert_eq!(first.len(), 8 * 4);
+    }
+
+    #[test]
+    fn test_symbol_file() {
+        let program = assemble_program(PROGRAM, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            program.symbols.to_symbol_file(),
+            "00000000 T main\n00000008 T loop\n00000018 T done\n"
+        );
+    }
+
+    #[test]
+    fn test_line_map() {
+        let program =
+            assemble_program("nop\n\nli a0, 0x12345\n", &AssemblerOptions::default()).unwrap();
+        let lines: Vec<(u32, u64)> = program
+            .line_map
+            .iter()
+            .map(|(address, location)| (*address, location.line))
+            .collect();
+        assert_eq!(lines, [(0, 1), (4, 3), (8, 3)]);
+    }
+
+    #[test]
+    fn test_org_and_absolute_symbols() {
+        let source = "
+            .equ HANDLER, 0x40
+            .set NEGATIVE, -4
+            jal zero, reset
+            .org 0x10
+        reset:
+            jal ra, HANDLER
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.bytes.len(), 0x14);
+        assert_eq!(&program.bytes[4..0x10], &[0; 12]);
+        assert_eq!(program.symbols.address("reset"), Some(0x10));
+        assert_eq!(program.symbols.address("NEGATIVE"), Some(0xffff_fffc));
+        assert!(program.symbols.get("HANDLER").unwrap().absolute);
+        // jal ra, +0x30
+        assert_eq!(&program.bytes[0x10..], &0x030000efu32.to_le_bytes());
+
+        let error = assemble("nop\nnop\n.org 4\nnop").unwrap_err().to_string();
+        assert!(
+            error.contains("move the location counter back"),
+            "{}",
+            error
+        );
+        assert!(assemble(".org start\nstart:").is_err());
+    }
+
+    #[test]
+    fn test_huge_sizes_are_errors() {
+        for directive in [
+            ".space 0xffffffff",
+            ".zero 0x7fffffff",
+            ".org 0xfffffff0",
+            ".p2align 31",
+            ".space 0x8000000\n.space 0x8000000\n.byte 1",
+        ] {
+            let source = format!("nop\n{}", directive);
+            let error = assemble_program(&source, &AssemblerOptions::default()).unwrap_err();
+            let error = error.downcast_ref::<AssemblerError>().unwrap();
+            let [(message, location)] = error.diagnostics()[..] else {
+                panic!("{}", error);
+            };
+            assert!(message.contains("256 MiB"), "{}", message);
+            assert!(location.line >= 2, "{}", location);
+        }
+        let far_data = AssemblerOptions {
+            section_bases: BTreeMap::from([(Section::Data, 0x8000_0000)]),
+            ..AssemblerOptions::default()
+        };
+        assert!(assemble_program("nop\n.data\n.word 1", &far_data).is_err());
+        assert!(assemble(".space 0x1000\n.org 0x2000\n.p2align 12").is_ok());
+    }
+
+    #[test]
+    fn test_vector_table() {
+        let source = "
+            jal zero, main
+        trap:
+            jal zero, trap
+        timer:
+            jal zero, timer
+            .vector_table vectors, trap, timer
+        main:
+            nop
+        ";
+        let options = AssemblerOptions {
+            warnings: Warnings::errors(),
+            ..AssemblerOptions::default()
+        };
+        let program = assemble_program(source, &options).unwrap();
+        assert_eq!(program.symbols.address("vectors"), Some(0x40));
+        assert_eq!(program.symbols.address("main"), Some(0x48));
+        assert_eq!(&program.bytes[0xc..0x40], &[0; 0x34]);
+        // jal zero, -0x3c; jal zero, -0x3c
+        assert_eq!(&program.bytes[0x40..0x44], &0xfc5ff06fu32.to_le_bytes());
+        assert_eq!(&program.bytes[0x44..0x48], &0xfc5ff06fu32.to_le_bytes());
+
+        assert!(assemble(".vector_table vectors").is_err());
+        assert!(assemble(".vector_table vectors, 4").is_err());
+    }
+
+    #[test]
+    fn test_literal_pools() {
+        let source = "
+        start:
+            lw a0, =0x12345678
+            lw a1, =start
+            jal zero, done
+            .ltorg
+        done:
+            lw a2, =0x12345678
+            lw a3, =0x12345678
+            jal zero, end
+            .org 0x1000
+        end:
+            ecall
+        ";
+        let options = AssemblerOptions {
+            warnings: Warnings::errors(),
+            ..AssemblerOptions::default()
+        };
+        let program = assemble_program(source, &options).unwrap();
+        let word = |address: usize| {
+            u32::from_le_bytes(program.bytes[address..address + 4].try_into().unwrap())
+        };
+        // auipc a0, 0; lw a0, 20(a0); auipc a1, 0; lw a1, 16(a1)
+        assert_eq!(word(0x0), 0x00000517);
+        assert_eq!(word(0x4), 0x01452503);
+        assert_eq!(word(0x8), 0x00000597);
+        assert_eq!(word(0xc), 0x0105a583);
+        assert_eq!(word(0x14), 0x12345678);
+        assert_eq!(word(0x18), 0);
+        // The second pool, after the program, holds the value once for both loads:
+        // auipc a2, 1; lw a2, -24(a2); auipc a3, 1; lw a3, -32(a3)
+        assert_eq!(word(0x1c), 0x00001617);
+        assert_eq!(word(0x20), 0xfe862603);
+        assert_eq!(word(0x24), 0x00001697);
+        assert_eq!(word(0x28), 0xfe06a683);
+        assert_eq!(word(0x1004), 0x12345678);
+        assert_eq!(program.bytes.len(), 0x1008);
+
+        // Pools are word aligned
+        let program = assemble_program(
+            ".option rvc\nnop\nlw a0, =-1\n",
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(program.symbols.address(".Lliteral0"), Some(12));
+        assert_eq!(&program.bytes[10..], &[0, 0, 0xff, 0xff, 0xff, 0xff]);
+
+        assert!(assemble("lw zero, =1").is_err());
+        assert!(assemble("lw a0, =0x100000000").is_err());
+        assert!(assemble("lw a0, =undefined").is_err());
+        assert!(assemble("lw a0, =1, 2").is_err());
+        assert!(assemble_with_options("lw a0, =0xffffffff", &AssemblerOptions::rv64i()).is_err());
+    }
+
+    #[test]
+    fn test_word_data_and_data_objects() {
+        let source = "
+            .text
+            main:
+                jal zero, main
+            .data
+            table:
+                .word 1, -1, 0xffffffff
+                .word main
+            flag:
+                .word 7
+            .text
+            after:
+                nop
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        // .data follows both parts of .text
+        assert_eq!(
+            program.bytes[8..28],
+            [
+                1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 7, 0, 0, 0
+            ]
+        );
+        let object = |name: &str, address, size| DataObject {
+            name: name.to_string(),
+            address,
+            size,
+        };
+        assert_eq!(
+            program.data_objects,
+            [object("table", 8, 16), object("flag", 24, 4)]
+        );
+        assert!(assemble(".word").is_err());
+        assert!(assemble(".word 0x100000000").is_err());
+        assert!(assemble(".word a0").is_err());
+    }
+
+    #[test]
+    fn test_sections() {
+        let source = r#"
+            .data
+            counter:
+                .word 1
+            .text
+            main:
+                lw a0, =counter
+            .section .rodata.str, "a"
+            message:
+                .asciz "hi"
+            .section .text.exit
+                lw a1, =0x12345678
+                ecall
+            .bss
+            buffer:
+                .space 8
+        "#;
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let extent = |section, address, size| SectionExtent {
+            section,
+            address,
+            size,
+        };
+        // The literal pool of .bss went to .text, after the code before it
+        assert_eq!(
+            program.sections,
+            [
+                extent(Section::Text, 0, 0x1c),
+                extent(Section::Rodata, 0x1c, 3),
+                extent(Section::Data, 0x20, 4),
+                extent(Section::Bss, 0x24, 8),
+            ]
+        );
+        assert_eq!(program.symbols.address("main"), Some(0));
+        assert_eq!(program.symbols.address("message"), Some(0x1c));
+        assert_eq!(program.symbols.address("counter"), Some(0x20));
+        assert_eq!(program.symbols.address("buffer"), Some(0x24));
+        // ecall
+        assert_eq!(program.bytes[0x10..0x14], 0x00000073u32.to_le_bytes());
+        assert_eq!(program.bytes[0x14..0x18], 0x20u32.to_le_bytes());
+        assert_eq!(program.bytes[0x18..0x1c], 0x12345678u32.to_le_bytes());
+        assert_eq!(program.bytes[0x1c..0x20], [b'h', b'i', 0, 0]);
+        assert_eq!(program.bytes.len(), 0x2c);
+
+        let options = AssemblerOptions {
+            section_bases: BTreeMap::from([(Section::Text, 0x100), (Section::Data, 0x80)]),
+            ..AssemblerOptions::default()
+        };
+        let program = assemble_program(source, &options).unwrap();
+        assert_eq!(program.origin, 0x80);
+        assert_eq!(program.symbols.address("main"), Some(0x100));
+        assert_eq!(program.symbols.address("message"), Some(0x11c));
+        assert_eq!(program.symbols.address("buffer"), Some(0x84));
+        assert_eq!(program.bytes[..4], 1u32.to_le_bytes());
+        assert_eq!(program.bytes.len(), 0x11f - 0x80);
+
+        let options = AssemblerOptions {
+            section_bases: BTreeMap::from([(Section::Data, 0x10)]),
+            ..AssemblerOptions::default()
+        };
+        let error = assemble_program(source, &options).unwrap_err().to_string();
+        assert!(
+            error.contains(".data (0x10-0x14) overlaps .text (0x0-0x1c)"),
+            "{}",
+            error
+        );
+        assert!(assemble(".section .init_array").is_err());
+    }
+
+    #[test]
+    fn test_bss_only_reserves_zeroed_space() {
+        let program = assemble(
+            ".bss
+.word 0
+.byte 0
+.align 2
+.space 4",
+        )
+        .unwrap();
+        assert_eq!(program, [0; 12]);
+        for source in [
+            ".bss
+.word 5",
+            ".bss
+nop",
+            ".bss
+.byte 1",
+            ".bss
+.space 2, 7",
+        ] {
+            let error = assemble(source).unwrap_err().to_string();
+            assert!(
+                error.contains("'.bss' can only reserve zeroed space at line 2"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let source = r#"
+            .data
+            greeting:
+                .asciz "hi", "!"
+            tag:
+                .ascii "ok"
+            halves:
+                .half 0x1234, -1
+            bytes:
+                .byte 1, 255, -128
+            buffer:
+                .space 3, 0xaa
+                .zero 2
+            end:
+                .word greeting
+        "#;
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            program.bytes,
+            [
+                b'h', b'i', 0, b'!', 0, b'o', b'k', 0x34, 0x12, 0xff, 0xff, 1, 255, 0x80, 0xaa,
+                0xaa, 0xaa, 0, 0, 0, 0, 0, 0
+            ]
+        );
+        assert_eq!(program.symbols.address("halves"), Some(7));
+        assert_eq!(program.symbols.address("buffer"), Some(14));
+        assert_eq!(program.symbols.address("end"), Some(19));
+        assert_eq!(program.data_objects[0].size, 5);
+
+        assert!(assemble(".byte 256").is_err());
+        assert!(assemble(".half -32769").is_err());
+        assert!(assemble(".byte greeting").is_err());
+        assert!(assemble(".ascii 1").is_err());
+        assert!(assemble(".space -1").is_err());
+        assert!(assemble(".zero 2, 1").is_err());
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let source = r#"
+            .data
+                .ascii "a\tb\n\"q\"\\"
+                .asciz "\x41\x7", "\101\0"
+                .ascii "\xff"
+        "#;
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            program.bytes,
+            [
+                b'a', b'\t', b'b', b'\n', b'"', b'q', b'"', b'\\', b'A', 7, 0, b'A', 0, 0, 0xff
+            ]
+        );
+
+        // Errors point at the escape
+        let error = assemble(".data\n.ascii \"ok\\q\"").unwrap_err();
+        let error = error.downcast_ref::<AssemblerError>().unwrap();
+        let (message, location) = error.diagnostics()[0];
+        assert!(message.contains("Unknown escape '\\q'"), "{}", message);
+        assert_eq!((location.line, location.col), (2, 11));
+        assert!(assemble(".ascii \"\\xg\"").is_err());
+        assert!(assemble(".ascii \"\\777\"").is_err());
+    }
+
+    #[test]
+    fn test_alignment_directives() {
+        let source = "
+            c.nop
+            .align 3
+        aligned:
+            nop
+            .data
+            .byte 1
+            .p2align 2
+        word:
+            .word 2
+            .byte 3
+            .p2align 4, 0xff, 4
+        unpadded:
+            .byte 4
+            .p2align 3, 0xee
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.symbols.address("aligned"), Some(8));
+        // c.nop, then a c.nop and a nop to the 8 byte boundary
+        assert_eq!(
+            &program.bytes[..12],
+            &[0x01, 0, 0x01, 0, 0x13, 0, 0, 0, 0x13, 0, 0, 0]
+        );
+        // .data starts on the next 8 byte boundary, the largest alignment in it
+        assert_eq!(program.symbols.address("word"), Some(20));
+        assert_eq!(&program.bytes[16..26], &[1, 0, 0, 0, 2, 0, 0, 0, 3, 4]);
+        assert_eq!(program.symbols.address("unpadded"), Some(25));
+        assert_eq!(&program.bytes[26..], &[0xee; 6]);
+
+        assert!(assemble(".align").is_err());
+        assert!(assemble(".align 32").is_err());
+        assert!(assemble(".p2align 2, 256").is_err());
+        assert!(assemble(".p2align main").is_err());
+    }
+
+    #[test]
+    fn test_compressed_instructions() {
+        let source = "
+            .option rvc
+            addi a0, zero, 5
+            .option push
+            .option norvc
+            addi a0, a0, 1
+            .option pop
+            c.addi a0, 1
+        loop:
+            c.j loop
+            addi a1, a1, 1
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.symbols.address("loop"), Some(8));
+        let halfwords: Vec<u16> = program
+            .bytes
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        // c.li a0, 5; addi a0, a0, 1; c.addi a0, 1; c.j loop; c.addi a1, 1
+        assert_eq!(halfwords, [0x4515, 0x0513, 0x0015, 0x0505, 0xa001, 0x0585]);
+
+        assert!(assemble("c.addi a0, 100").is_err());
+        assert!(assemble("c.lw a0, 0(t0)").is_err());
+        assert!(assemble(".option pop").is_err());
+        assert!(assemble(".option rvc, 1").is_err());
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let source = "
+            csrrw a0, mtvec, t0
+            csrrs a1, mtvec, zero
+            csrrsi zero, mstatus, 8
+            csrrci a2, mstatus, 8
+            csrrwi zero, frm, 1
+            csrrc s0, mie, t0
+            csrrwi a6, mscratch, 17
+        mie:
+            jal zero, mie
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x30529573, 0x305025f3, 0x30046073, 0x30047673, 0x0020d073, 0x3042b473, 0x3408d873,
+                0x0000006f
+            ]
+        );
+
+        assert!(assemble("csrrw a0, nonsense, t0").is_err());
+        assert!(assemble("csrrwi a0, mstatus, 32").is_err());
+        assert!(assemble("csrrw a0, t0, mstatus").is_err());
+    }
+
+    #[test]
+    fn test_fences_and_privileged_instructions() {
+        let source = "
+            fence
+            fence rw, w
+            fence r, 0
+            fence.i
+            fence.tso
+            ebreak
+            sret
+            mret
+            wfi
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x0ff0000f, 0x0310000f, 0x0200000f, 0x0000100f, 0x8330000f, 0x00100073, 0x10200073,
+                0x30200073, 0x10500073
+            ]
+        );
+
+        let error = assemble("fence rx, w").unwrap_err();
+        assert!(format!("{:#}", error).contains("'iorw'"), "{:#}", error);
+        assert!(assemble("fence rr, w").is_err());
+        assert!(assemble("fence rw").is_err());
+        assert!(assemble("fence.i a0").is_err());
+        assert!(assemble("mret 1").is_err());
+    }
+
+    #[test]
+    fn test_csr_numbers_and_pseudoinstructions() {
+        let source = "
+            csrr t0, mstatus
+            csrrwi zero, 0x305, 0
+            csrw mtvec, a0
+            csrs mie, t0
+            csrci mstatus, 8
+            csrr a0, cycle
+            csrr a0, 0xc00
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x300022f3, 0x30505073, 0x30551073, 0x3042a073, 0x30047073, 0xc0002573, 0xc0002573
+            ]
+        );
+
+        let error = assemble("csrr a0, 0x1000").unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("more than 12 bits"),
+            "{:#}",
+            error
+        );
+        assert!(assemble("csrr a0, -1").is_err());
+        assert!(assemble("csrw a0, mstatus").is_err());
+        assert!(assemble("csrwi mstatus, a0").is_err());
+    }
+
+    #[test]
+    fn test_pseudoinstructions() {
+        let source = "
+        start:
+            not a0, a1
+            seqz a0, a1
+            sgtz a0, a1
+            fneg.d f1, f2
+            bnez a0, start
+            bgt a0, a1, start
+            bleu a0, a1, start
+            j start
+            jal start
+            jr a0
+            ret
+            call start
+            tail start
+            la a0, data
+            lw a0, data
+            sw a0, data, t0
+            fsd f1, data, t0
+        data:
+            .word 0
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        // The encodings GNU as and llvm-mc produce, with the calls as a linker relaxes them
+        assert_eq!(
+            words,
+            [
+                0xfff5c513, 0x0015b513, 0x00b02533, 0x222110d3, 0xfe0518e3, 0xfea5c6e3, 0xfea5f4e3,
+                0xfe5ff06f, 0xfe1ff0ef, 0x00050067, 0x00008067, 0xfd5ff0ef, 0xfd1ff06f, 0x00000517,
+                0x02050513, 0x00000517, 0x01852503, 0x00000297, 0x00a2a823, 0x00000297, 0x0012b427,
+                0x00000000
+            ]
+        );
+
+        assert!(
+            assemble(
+                "la zero, start
+start:"
+            )
+            .is_err()
+        );
+        assert!(
+            assemble(
+                "call zero, start
+start:"
+            )
+            .is_err()
+        );
+        assert!(
+            assemble(
+                "sw a0, start, zero
+start:"
+            )
+            .is_err()
+        );
+        assert!(assemble("sext.w a0, a1").is_err());
+        assert!(assemble_with_options("sext.w a0, a1", &AssemblerOptions::rv64i()).is_ok());
+    }
+
+    #[test]
+    fn test_relocation_operators() {
+        let source = "
+            .equ UART, 0x10000000
+            lui a0, %hi(data)
+            addi a0, a0, %lo(data)
+            lui t0, %hi(UART)
+            lw a1, %lo(data)(t0)
+            sw a1, %lo(UART)(t0)
+        here:
+            auipc a2, %pcrel_hi(data)
+            addi a2, a2, %pcrel_lo(here)
+        there:
+            auipc a3, %pcrel_hi(data)
+            sh a1, %pcrel_lo(there)(a3)
+            .org 0x1268
+        data:
+            .word 0
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program.bytes[..36]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        // The encodings GNU as and llvm-mc produce
+        assert_eq!(
+            words,
+            [
+                0x00001537, 0x26850513, 0x100002b7, 0x2682a583, 0x00b2a023, 0x00001617, 0x25460613,
+                0x00001697, 0x24b69623
+            ]
+        );
+
+        // %pcrel_lo names the AUIPC, which has to come right before it
+        assert!(
+            assemble(
+                "here: auipc a0, %pcrel_hi(data)
+                nop
+                addi a0, a0, %pcrel_lo(here)
+            data:"
+            )
+            .is_err()
+        );
+        assert!(assemble("data: addi a0, a0, %pcrel_lo(data)").is_err());
+        assert!(assemble("data: lui a0, %hi(data)(a0)").is_err());
+        assert!(assemble("data: lui a0, %lo(data)").is_err());
+        assert!(assemble("data: lui a0, %high(data)").is_err());
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "
+            .equ BUF_SIZE, 16
+            .equ TOTAL, BUF_SIZE*4 + 8
+        start:
+            addi a0, a0, (BUF_SIZE*4)+8
+            addi a1, a1, -(BUF_SIZE << 2) | 1
+            li a2, TOTAL % 5 - ~3
+            lw a3, (BUF_SIZE/2)(sp)
+            lw a4, BUF_SIZE+4(sp)
+            addi a5, a5, end - start
+            beq a0, a1, end - 4
+            jal zero, start+4
+            andi a6, a6, 0xff ^ 0x0f & 0x3c
+        end:
+            .word end - start, start + 8, (end-start)/4, -1
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x04850513, 0xfc158593, 0x00600613, 0x00812683, 0x01412703, 0x02478793, 0x00b50463,
+                0xfe9ff06f, 0x0f387813, 0x24, 8, 9, 0xffffffff
+            ]
+        );
+
+        assert!(assemble("addi a0, a0, 1/0").is_err());
+        assert!(assemble("addi a0, a0, (1 << 11)").is_err());
+        assert!(assemble("lw a0, end-start(sp)\nstart:\nend:").is_err());
+        assert!(assemble(".word 1 << 40").is_err());
+        assert!(assemble("addi a0, a0, (1 + 2").is_err());
+    }
+
+    #[test]
+    fn test_bare_constants() {
+        let source = "
+            .equ VAL, 1
+            .set OFF, 8
+            .equ BIG, 0x12345
+            addi a0, zero, VAL
+            slli a1, a1, OFF
+            li a2, VAL
+            li a3, BIG
+            lw a4, OFF(sp)
+            sw a4, VAL+3(sp)
+        ";
+        let expected = "
+            addi a0, zero, 1
+            slli a1, a1, 8
+            addi a2, zero, 1
+            lui a3, 0x12
+            addi a3, a3, 0x345
+            lw a4, 8(sp)
+            sw a4, 4(sp)
+        ";
+        assert_eq!(assemble(source).unwrap(), assemble(expected).unwrap());
+
+        // Still an address where a label goes
+        let jump = assemble(".equ TARGET, 8\nj TARGET\nnop\nnop").unwrap();
+        assert_eq!(jump, assemble("j target\nnop\ntarget: nop").unwrap());
+        assert!(assemble("lw a0, LABEL(sp)\nLABEL:").is_err());
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+            .macro push reg
+                addi sp, sp, -4
+                sw \\reg, 0(sp)
+            .endm
+            .macro countdown reg, from=3
+                li \\reg, \\from
+            loop\\@:
+                addi \\reg, \\reg, -1
+                bnez \\reg, loop\\@
+            .endm
+        main:
+            push ra
+            countdown t0
+            countdown t1, 10
+        ";
+        let expanded = "
+        main:
+            addi sp, sp, -4
+            sw ra, 0(sp)
+            li t0, 3
+        loop1:
+            addi t0, t0, -1
+            bnez t0, loop1
+            li t1, 10
+        loop2:
+            addi t1, t1, -1
+            bnez t1, loop2
+        ";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.bytes, assemble(expanded).unwrap());
+        // What a macro expands to belongs to the line using it
+        let lines: Vec<u64> = program
+            .line_map
+            .iter()
+            .map(|(_, location)| location.line)
+            .collect();
+        assert_eq!(lines, [13, 13, 14, 14, 14, 15, 15, 15]);
+        assert_eq!(program.symbols.address("loop1"), Some(0xc));
+
+        assert!(assemble(".macro push reg\nsw \\reg, 0(sp)\n.endm\npush").is_err());
+        assert!(assemble("addi a0, a0, \\x").is_err());
+    }
+
+    #[test]
+    fn test_numeric_local_labels() {
+        let source = "
+        1:  addi a0, a0, -1
+            bnez a0, 1b
+            j 1f
+        1:  beqz a1, 2f
+            j 1b
+        2:
+        1:  .word 1b, 1b - 2b";
+        let expected = "
+            addi a0, a0, -1
+            bne a0, zero, -4
+            jal zero, 4
+            beq a1, zero, 8
+            jal zero, -4
+            .word 20, 0";
+        assert_eq!(assemble(source).unwrap(), assemble(expected).unwrap());
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("j 1b\n1:"),
+            "Symbol error: No label '1:' before the reference to 1b at line 1, column 3"
+        );
+        assert_eq!(
+            message("1:\nj 1f"),
+            "Symbol error: No label '1:' after the reference to 1f at line 2, column 3"
+        );
+        // Like any other label, a numeric one can start a line using a macro
+        let source = ".macro twice insn\n\\insn\n\\insn\n.endm\n1: twice nop\nj 1b";
+        assert_eq!(
+            assemble(source).unwrap(),
+            assemble("nop\nnop\nj -8").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let directory = std::env::temp_dir().join(format!("rv-includes-{}", std::process::id()));
+        let library = directory.join("include");
+        std::fs::create_dir_all(&library).unwrap();
+        std::fs::write(
+            library.join("defs.s"),
+            ".equ EXIT, 93\n.macro exit code\n    li a0, \\code\n    ecall\n.endm",
+        )
+        .unwrap();
+        std::fs::write(directory.join("bad.s"), "nop\nfrobnicate a0").unwrap();
+        let main = directory.join("main.s");
+        std::fs::write(
+            &main,
+            ".include \"defs.s\"\nmain:\n    exit 0\n    .word EXIT",
+        )
+        .unwrap();
+
+        let options = AssemblerOptions {
+            include_paths: vec![library],
+            ..AssemblerOptions::default()
+        };
+        let program = assemble_program(&main, &options).unwrap();
+        assert_eq!(
+            program.bytes,
+            assemble("li a0, 0\necall\n.word 93").unwrap()
+        );
+        assert_eq!(program.line_map[0].1, SourceLocation::new(3, 5));
+        // Without the include path, defs.s isn't found
+        assert!(assemble(&main).is_err());
+
+        std::fs::write(&main, "nop\n.include \"bad.s\"").unwrap();
+        let error = assemble(&main).unwrap_err();
+        let error = error.downcast_ref::<AssemblerError>().unwrap();
+        let (_, location) = error.diagnostics()[0];
+        let bad = directory.join("bad.s").display().to_string();
+        assert_eq!(location.file.as_deref(), Some(bad.as_str()));
+        assert_eq!(location.line, 2);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_rv64_instructions() {
+        let source = "
+            ld a0, 8(sp)
+            lwu a1, -4(a0)
+            sd a2, 16(sp)
+            addiw a0, a0, -1
+            slliw a0, a1, 31
+            sraiw a0, a1, 3
+            addw a0, a1, a2
+            subw a0, a1, a2
+            mulw a0, a1, a2
+            remuw a0, a1, a2
+            slli a0, a1, 63
+            srai a0, a1, 40
+        ";
+        let program = assemble_program(source, &AssemblerOptions::rv64i()).unwrap();
+        let words: Vec<u32> = program
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x00813503, 0xffc56583, 0x00c13823, 0xfff5051b, 0x01f5951b, 0x4035d51b, 0x00c5853b,
+                0x40c5853b, 0x02c5853b, 0x02c5f53b, 0x03f59513, 0x4285d513
+            ]
+        );
+
+        // RV64-only instructions and shift amounts on RV32, and RV32C encodings on RV64
+        assert!(assemble("ld a0, 0(sp)").is_err());
+        assert!(assemble("addw a0, a1, a2").is_err());
+        assert!(assemble("slli a0, a1, 32").is_err());
+        assert!(assemble_with_options("slliw a0, a1, 32", &AssemblerOptions::rv64i()).is_err());
+        assert!(assemble_with_options("c.addi a0, 1", &AssemblerOptions::rv64i()).is_err());
+    }
+
+    #[test]
+    fn test_backward_and_forward_references() {
+        let output = assemble(PROGRAM).unwrap();
+        let word =
+            |index: usize| u32::from_le_bytes(output[index * 4..index * 4 + 4].try_into().unwrap());
+        // beq a0, zero, done (+16)
+        assert_eq!(word(2), 0x00050863);
+        // jal zero, loop (-12)
+        assert_eq!(word(5), 0xff5ff06f);
+    }
+
+    #[test]
+    fn test_unresolved_errors_are_ordered() {
+        let source = "jal ra, zulu\njal ra, alpha\njal ra, mike";
+        let first = assemble(source).unwrap_err().to_string();
+        for _ in 0..10 {
+            assert_eq!(assemble(source).unwrap_err().to_string(), first);
+        }
+        let zulu = first.find("zulu").unwrap();
+        let alpha = first.find("alpha").unwrap();
+        let mike = first.find("mike").unwrap();
+        assert!(zulu < alpha && alpha < mike);
+    }
+
+    #[test]
+    fn test_branch_relaxation() {
+        let source = "beq a0, a1, far\n.space 5000\nfar: nop";
+        let error = assemble(source).unwrap_err().to_string();
+        assert!(
+            error.contains("'far' is 5004 bytes away, out of the -4096..4094 a branch reaches"),
+            "{}",
+            error
+        );
+        let error = assemble("1: .space 5000\nbnez a0, 1b")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'1' is -5000 bytes away"), "{}", error);
+
+        let options = AssemblerOptions {
+            relax_branches: true,
+            ..AssemblerOptions::default()
+        };
+        let program = assemble_program(source, &options).unwrap();
+        let word = |address: usize| {
+            u32::from_le_bytes(program.bytes[address..address + 4].try_into().unwrap())
+        };
+        // bne a0, a1, 8; jal zero, far (+5004)
+        assert_eq!(word(0), 0x00b51463);
+        assert_eq!(word(4), 0x38c0106f);
+        assert_eq!(program.symbols.address("far"), Some(5008));
+        // Branches in reach stay as they are
+        assert_eq!(
+            assemble_with_options("beq a0, a1, near\nnear: nop", &options).unwrap(),
+            assemble("beq a0, a1, near\nnear: nop").unwrap()
+        );
+
+        // Relaxing the first branch moves the second one out of reach
+        let source = "top: beq a0, a1, far\n.space 4092\nbne a0, a1, top\n.space 4000\nfar: nop";
+        let program = assemble_program(source, &options).unwrap();
+        assert_eq!(program.symbols.address("far"), Some(8108));
+        // beq a0, a1, 8; jal zero, top (-4104)
+        let word = |address: usize| {
+            u32::from_le_bytes(program.bytes[address..address + 4].try_into().unwrap())
+        };
+        assert_eq!(word(4100), 0x00b50463);
+        assert_eq!(word(4104), 0xff9fe06f);
+    }
+
+    #[test]
+    fn test_misspellings_get_suggestions() {
+        let error = assemble("adi a0, a0, 1").unwrap_err().to_string();
+        assert!(
+            error.contains("Unknown instruction 'adi', did you mean 'addi', 'add' or 'andi'?"),
+            "{}",
+            error
+        );
+        let error = assemble("add a0, a8, a1").unwrap_err().to_string();
+        assert!(
+            error.contains("did you mean 'a0', 'a1' or 'a2'?"),
+            "{}",
+            error
+        );
+        // Base registers of memory operands too
+        let error = assemble("lw a0, 4(a9)").unwrap_err().to_string();
+        assert!(
+            error.contains("Unknown register 'a9', did you mean 'a0', 'a1' or 'a2'?"),
+            "{}",
+            error
+        );
+        let error = assemble("frobnicate a0").unwrap_err().to_string();
+        assert!(!error.contains("did you mean"), "{}", error);
+    }
+
+    #[test]
+    fn test_source_inputs() {
+        let expected = assemble(PROGRAM).unwrap();
+        assert_eq!(
+            assemble(SourceInput::reader(PROGRAM.as_bytes())).unwrap(),
+            expected
+        );
+        assert_eq!(assemble(PROGRAM.to_string()).unwrap(), expected);
+        // Named sources say where the error is
+        let error = assemble(SourceInput::text("frobnicate a0").with_name("main.s")).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message.starts_with("assembling main.s: ") && message.contains("frobnicate"),
+            "{}",
+            message
+        );
+        let error = assemble(std::path::Path::new("/nonexistent/main.s")).unwrap_err();
+        assert!(error.to_string().contains("reading /nonexistent/main.s"));
+    }
+
+    #[test]
+    fn test_permissive_mode_replaces_bad_lines() {
+        let source = "start: li a0, 1
+bad: frobnicate a0
+addi a1, a0, 99999
+jal ra, nowhere
+jal zero, bad
+start: addi a0, a0, 1
+ecall";
+        let options = AssemblerOptions {
+            permissive: true,
+            ..AssemblerOptions::default()
+        };
+        assert!(assemble(source).is_err());
+        let program = assemble_program(source, &options).unwrap();
+        let skipped: Vec<(u32, u64)> = program
+            .skipped_lines
+            .iter()
+            .map(|skipped| (skipped.address, skipped.location.line))
+            .collect();
+        assert_eq!(skipped, [(4, 2), (8, 3), (12, 4), (20, 6)]);
+        assert!(program.skipped_lines[0].message.contains("frobnicate"));
+        let word = |address: usize| {
+            u32::from_le_bytes(program.bytes[address..address + 4].try_into().unwrap())
+        };
+        for address in [4, 8, 12, 20] {
+            assert_eq!(word(address), 0x00100073);
+        }
+        // The label on the bad line survived, the duplicate one didn't
+        assert_eq!(program.symbols.get("bad").unwrap().address, Some(4));
+        assert_eq!(program.symbols.get("start").unwrap().address, Some(0));
+        assert_eq!(word(16), 0xff5ff06f);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        use crate::tokenizer::{Token, tokenize};
+
+        let tokens = tokenize(PROGRAM).unwrap();
+        let json = serde_json::to_string(&tokens).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Token>>(&json).unwrap(), tokens);
+
+        let mut symbol_table = SymbolTable::new();
+        let items = Parser::new(tokens).parse_all(&mut symbol_table).unwrap();
+        let json = serde_json::to_string(&items).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<ParsedItem>>(&json).unwrap(),
+            items
+        );
+
+        let program = assemble_program(PROGRAM, &AssemblerOptions::default()).unwrap();
+        let json = serde_json::to_value(&program).unwrap();
+        assert_eq!(json["symbols"]["symbols"]["loop"]["address"], 8);
+        assert_eq!(
+            json["line_map"][0],
+            serde_json::json!([0, {"line": 5, "col": 13}])
+        );
+        assert_eq!(json["xrefs"]["labels"]["done"][1][0]["kind"], "branch");
+        let program: AssembledProgram = serde_json::from_value(json).unwrap();
+        assert_eq!(program.symbols.get("done").unwrap().address, Some(24));
+    }
+
+    #[test]
+    fn test_arbitrary_input_never_panics() {
+        const PIECES: &[&str] = &[
+            "addi",
+            "lui",
+            "jal",
+            "beq",
+            "lw",
+            "sw",
+            "li",
+            "mv",
+            "slli",
+            "ecall",
+            "a0",
+            "x31",
+            "zero",
+            "t9",
+            "loop",
+            "loop:",
+            ".text",
+            ".register",
+            ".word",
+            ".bogus",
+            ",",
+            "(",
+            ")",
+            ":",
+            "-",
+            "0x",
+            "0xffffffffffffffff",
+            "-9223372036854775808",
+            "2048",
+            "-2049",
+            "4096",
+            "\"",
+            "\\",
+            "\n",
+            " ",
+            "é",
+            "#",
+            "\t",
+        ];
+        let mut next = xorshift64(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let len = next() % 24;
+            let source: String = (0..len)
+                .map(|_| PIECES[(next() % PIECES.len() as u64) as usize])
+                .collect::<Vec<_>>()
+                .join(if next().is_multiple_of(2) { " " } else { "" });
+            let _ = assemble(&source);
+            let _ = assemble_with_options(&source, &AssemblerOptions::rv32e());
+        }
+    }
+}
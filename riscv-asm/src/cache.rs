@@ -0,0 +1,335 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    assembler::{AssemblerOptions, assemble_with_options},
+    preprocess::preprocess,
+    source::SourceInput,
+};
+
+/// Default cache location, next to cargo's own build artifacts
+pub const DEFAULT_CACHE_DIR: &str = "target/riscv-asm-cache";
+
+/// On-disk cache of assembled artifacts keyed by a hash of the source and options.
+///
+/// Builds that assemble the same sources over and over, like `riscv-asm --cache` run from a
+/// Makefile, only re-assemble those whose contents, included files or assembler options
+/// changed.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    dir: PathBuf,
+}
+
+impl BuildCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Cache key for `source` assembled with `options`.
+    ///
+    /// The crate version is part of the key so artifacts from an older assembler are never reused.
+    pub fn key(source: &str, options: &str) -> String {
+        Self::key_of([options, source])
+    }
+
+    /// Cache key for `source` assembled with `options` and the files it `.include`s, by the
+    /// lines they come to with conditionals and macros applied. `path` is the file `source`
+    /// was read from, whose directory includes are looked for in, the working directory
+    /// without one. `None` if an included file can't be read, which the assembler reports
+    /// better, or with plugins, whose configuration the key can't see.
+    fn key_with_includes(
+        source: &str,
+        path: Option<&Path>,
+        options: &AssemblerOptions,
+    ) -> Option<String> {
+        if !options.plugins.is_empty() {
+            return None;
+        }
+        let key_options = format!("{:?}", options);
+        // Located, as line numbers end up in the debug line map
+        let lines: Vec<String> = preprocess(source, path, options)
+            .ok()?
+            .iter()
+            .map(|line| format!("{}\n{}", line.location, line.text))
+            .collect();
+        Some(Self::key_of(
+            [key_options.as_str(), source]
+                .into_iter()
+                .chain(lines.iter().map(String::as_str)),
+        ))
+    }
+
+    fn key_of<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+        let mut hash = Fnv1a::new();
+        for part in std::iter::once(env!("CARGO_PKG_VERSION")).chain(parts) {
+            hash.write(&(part.len() as u64).to_le_bytes());
+            hash.write(part.as_bytes());
+        }
+        format!("{:016x}", hash.finish())
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.artifact_path(key)).ok()
+    }
+
+    pub fn put(&self, key: &str, artifact: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Write then rename so a concurrent reader never sees a partial artifact
+        let temporary = self.dir.join(format!("{}.tmp{}", key, std::process::id()));
+        fs::write(&temporary, artifact)?;
+        fs::rename(&temporary, self.artifact_path(key))
+    }
+
+    /// Returns the cached artifact for `source` and `options`, running `build` only on a miss
+    pub fn get_or_build(
+        &self,
+        source: &str,
+        options: &str,
+        build: impl FnOnce(&str) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.cached(&Self::key(source, options), || build(source))
+    }
+
+    /// Cached equivalent of [`assemble`](crate::assemble)
+    pub fn assemble(&self, source: &str) -> anyhow::Result<Vec<u8>> {
+        self.assemble_with_options(source, &AssemblerOptions::default())
+    }
+
+    /// Cached equivalent of [`assemble_with_options`], rebuilding when `source` or a file
+    /// it includes changes
+    pub fn assemble_with_options(
+        &self,
+        source: &str,
+        options: &AssemblerOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        let build = || assemble_with_options(source, options);
+        match Self::key_with_includes(source, None, options) {
+            Some(key) => self.cached(&key, build),
+            None => build(),
+        }
+    }
+
+    /// Cached equivalent of assembling [`SourceInput::file`], whose includes are looked for
+    /// next to it
+    pub fn assemble_file(
+        &self,
+        path: &Path,
+        options: &AssemblerOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        let build = || assemble_with_options(SourceInput::file(path), options);
+        let Ok(source) = fs::read_to_string(path) else {
+            return build();
+        };
+        match Self::key_with_includes(&source, Some(path), options) {
+            Some(key) => self.cached(&key, build),
+            None => build(),
+        }
+    }
+
+    fn cached(
+        &self,
+        key: &str,
+        build: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        if let Some(artifact) = self.get(key) {
+            return Ok(artifact);
+        }
+        let artifact = build()?;
+        self.put(key, &artifact)?;
+        Ok(artifact)
+    }
+
+    /// Removes every cached artifact
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn artifact_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", key))
+    }
+}
+
+impl Default for BuildCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_DIR)
+    }
+}
+
+/// 64 bit FNV-1a, used instead of `DefaultHasher` because its output must not change between
+/// Rust releases
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        parser::Operand,
+        plugin::{AssemblerPlugin, Plugins},
+    };
+
+    fn test_cache(name: &str) -> BuildCache {
+        let cache = BuildCache::new(std::env::temp_dir().join(format!(
+            "riscv-asm-cache-{}-{}",
+            name,
+            std::process::id()
+        )));
+        cache.clear().unwrap();
+        cache
+    }
+
+    #[test]
+    fn test_unchanged_source_is_not_reassembled() {
+        let cache = test_cache("unchanged");
+        let builds = Cell::new(0);
+        let build = |source: &str| {
+            builds.set(builds.get() + 1);
+            assemble(source)
+        };
+
+        let first = cache.get_or_build("addi a0, zero, 1", "", build).unwrap();
+        let second = cache.get_or_build("addi a0, zero, 1", "", build).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(builds.get(), 1);
+
+        cache.get_or_build("addi a0, zero, 2", "", build).unwrap();
+        assert_eq!(builds.get(), 2);
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn test_options_are_part_of_the_key() {
+        assert_ne!(
+            BuildCache::key("nop", ""),
+            BuildCache::key("nop", "-march=rv32e")
+        );
+        // Length prefixes keep the boundary between options and source unambiguous
+        assert_ne!(BuildCache::key("bc", "a"), BuildCache::key("c", "ab"));
+        assert_eq!(BuildCache::key("nop", ""), BuildCache::key("nop", ""));
+    }
+
+    #[test]
+    fn test_failed_builds_are_not_cached() {
+        let cache = test_cache("failed");
+        assert!(cache.assemble("frobnicate a0").is_err());
+        assert!(!cache.dir().exists());
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn test_included_files_are_part_of_the_key() {
+        let cache = test_cache("include");
+        let directory = cache.dir().with_extension("src");
+        fs::create_dir_all(&directory).unwrap();
+        let options = AssemblerOptions {
+            include_paths: vec![directory.clone()],
+            ..AssemblerOptions::default()
+        };
+        let source = ".include \"inc.s\"";
+
+        fs::write(directory.join("inc.s"), ".word 1").unwrap();
+        assert_eq!(
+            cache.assemble_with_options(source, &options).unwrap(),
+            [1, 0, 0, 0]
+        );
+        fs::write(directory.join("inc.s"), ".word 2").unwrap();
+        assert_eq!(
+            cache.assemble_with_options(source, &options).unwrap(),
+            [2, 0, 0, 0]
+        );
+        // Next to the file, whatever the working directory
+        fs::write(directory.join("main.s"), ".word 3\n.include \"inc.s\"").unwrap();
+        let main = directory.join("main.s");
+        assert_eq!(
+            cache
+                .assemble_file(&main, &AssemblerOptions::default())
+                .unwrap(),
+            [3, 0, 0, 0, 2, 0, 0, 0]
+        );
+        fs::write(directory.join("inc.s"), ".word 4").unwrap();
+        assert_eq!(
+            cache
+                .assemble_file(&main, &AssemblerOptions::default())
+                .unwrap(),
+            [3, 0, 0, 0, 4, 0, 0, 0]
+        );
+        // Macros the included file defines, used in the source
+        let source = ".include \"inc.s\"\nvalue";
+        fs::write(directory.join("inc.s"), ".macro value\n.word 5\n.endm").unwrap();
+        assert_eq!(
+            cache.assemble_with_options(source, &options).unwrap(),
+            [5, 0, 0, 0]
+        );
+        fs::write(directory.join("inc.s"), ".macro value\n.word 6\n.endm").unwrap();
+        assert_eq!(
+            cache.assemble_with_options(source, &options).unwrap(),
+            [6, 0, 0, 0]
+        );
+        fs::remove_file(directory.join("inc.s")).unwrap();
+        assert!(cache.assemble_with_options(source, &options).is_err());
+        fs::remove_dir_all(directory).unwrap();
+        cache.clear().unwrap();
+    }
+
+    /// `.magic` placing its configured word
+    struct Magic(u32);
+
+    impl AssemblerPlugin for Magic {
+        fn name(&self) -> &str {
+            "magic"
+        }
+
+        fn handles_directive(&self, name: &str) -> bool {
+            name == ".magic"
+        }
+
+        fn directive(&self, _name: &str, _args: &[Operand]) -> Result<Vec<u8>, String> {
+            Ok(self.0.to_le_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_builds_with_plugins_are_not_cached() {
+        let cache = test_cache("plugins");
+        for magic in [1, 2] {
+            let options = AssemblerOptions {
+                plugins: Plugins::new().with(Magic(magic)),
+                ..AssemblerOptions::default()
+            };
+            assert_eq!(
+                cache.assemble_with_options(".magic", &options).unwrap(),
+                [magic as u8, 0, 0, 0]
+            );
+        }
+        assert!(!cache.dir().exists());
+        cache.clear().unwrap();
+    }
+}
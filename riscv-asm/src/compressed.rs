@@ -0,0 +1,570 @@
+//! The C extension's 16 bit encodings.
+//!
+//! Every compressed instruction is shorthand for a base instruction, so `c.*` mnemonics are
+//! parsed into the base instruction they stand for and only the final encoding step differs:
+//! the 32 bit word is squeezed into one of the 16 bit forms that can express it.
+
+use crate::{
+    encoder::{Xlen, encode_b, encode_i, encode_j, encode_r, encode_s},
+    error::SourceLocation,
+    isa::{
+        OPCODE_BRANCH, OPCODE_JAL, OPCODE_JALR, OPCODE_LOAD, OPCODE_LUI, OPCODE_OP, OPCODE_OP_IMM,
+        OPCODE_STORE,
+    },
+    parser::{Instruction, Operand, parser_error},
+};
+
+/// Mnemonics of the compressed instructions the assembler accepts
+pub const MNEMONICS: &[&str] = &[
+    "c.nop",
+    "c.addi",
+    "c.li",
+    "c.addi16sp",
+    "c.addi4spn",
+    "c.lui",
+    "c.mv",
+    "c.add",
+    "c.sub",
+    "c.xor",
+    "c.or",
+    "c.and",
+    "c.andi",
+    "c.slli",
+    "c.srli",
+    "c.srai",
+    "c.lw",
+    "c.sw",
+    "c.lwsp",
+    "c.swsp",
+    "c.j",
+    "c.jal",
+    "c.jr",
+    "c.jalr",
+    "c.beqz",
+    "c.bnez",
+];
+
+/// Rewrites a `c.*` instruction into the base instruction it stands for, marked as
+/// compressed so it is encoded in the requested 16 bit form
+pub(crate) fn expand(
+    mnemonic: &str,
+    operands: Vec<Operand>,
+    location: &SourceLocation,
+) -> anyhow::Result<Instruction> {
+    use Operand::{Register, Symbol};
+
+    let mnemonic = mnemonic.to_lowercase();
+    let wrong_operands = |expected: &str| {
+        parser_error(
+            &format!("'{}' expects {}", mnemonic, expected),
+            location.clone(),
+        )
+    };
+    let (base, operands) = match (mnemonic.as_str(), operands.as_slice()) {
+        ("c.nop", []) => (
+            "addi",
+            vec![Register(0), Register(0), Operand::Immediate(0)],
+        ),
+        ("c.nop", _) => return Err(wrong_operands("no operands")),
+        ("c.addi", [Register(rd), imm @ Operand::Immediate(_)]) => {
+            ("addi", vec![Register(*rd), Register(*rd), imm.clone()])
+        }
+        ("c.li", [Register(rd), imm @ Operand::Immediate(_)]) => {
+            ("addi", vec![Register(*rd), Register(0), imm.clone()])
+        }
+        ("c.addi16sp", [Register(2), imm @ Operand::Immediate(_)]) => {
+            ("addi", vec![Register(2), Register(2), imm.clone()])
+        }
+        ("c.addi16sp", _) => return Err(wrong_operands("sp, imm")),
+        ("c.addi4spn", [Register(rd), Register(2), imm @ Operand::Immediate(_)]) => {
+            ("addi", vec![Register(*rd), Register(2), imm.clone()])
+        }
+        ("c.addi4spn", _) => return Err(wrong_operands("rd, sp, imm")),
+        ("c.andi", [Register(rd), imm @ Operand::Immediate(_)]) => {
+            ("andi", vec![Register(*rd), Register(*rd), imm.clone()])
+        }
+        ("c.slli" | "c.srli" | "c.srai", [Register(rd), shamt @ Operand::Immediate(_)]) => (
+            &mnemonic[2..],
+            vec![Register(*rd), Register(*rd), shamt.clone()],
+        ),
+        ("c.addi" | "c.li" | "c.andi" | "c.slli" | "c.srli" | "c.srai", _) => {
+            return Err(wrong_operands("rd, imm"));
+        }
+        ("c.lui", [Register(rd), imm @ Operand::Immediate(_)]) => {
+            ("lui", vec![Register(*rd), imm.clone()])
+        }
+        ("c.lui", _) => return Err(wrong_operands("rd, imm")),
+        ("c.mv", [Register(rd), Register(rs2)]) => {
+            ("add", vec![Register(*rd), Register(0), Register(*rs2)])
+        }
+        ("c.add" | "c.sub" | "c.xor" | "c.or" | "c.and", [Register(rd), Register(rs2)]) => (
+            &mnemonic[2..],
+            vec![Register(*rd), Register(*rd), Register(*rs2)],
+        ),
+        ("c.mv" | "c.add" | "c.sub" | "c.xor" | "c.or" | "c.and", _) => {
+            return Err(wrong_operands("rd, rs2"));
+        }
+        ("c.lw" | "c.lwsp", [Register(_), Operand::Memory { .. }]) => ("lw", operands),
+        ("c.lw" | "c.lwsp", _) => return Err(wrong_operands("rd, offset(rs1)")),
+        ("c.sw" | "c.swsp", [Register(_), Operand::Memory { .. }]) => ("sw", operands),
+        ("c.sw" | "c.swsp", _) => return Err(wrong_operands("rs2, offset(rs1)")),
+        ("c.j", [target @ (Symbol(_) | Operand::Immediate(_))]) => {
+            ("jal", vec![Register(0), target.clone()])
+        }
+        ("c.jal", [target @ (Symbol(_) | Operand::Immediate(_))]) => {
+            ("jal", vec![Register(1), target.clone()])
+        }
+        ("c.j" | "c.jal", _) => return Err(wrong_operands("label")),
+        ("c.jr", [Register(rs1)]) => (
+            "jalr",
+            vec![Register(0), Register(*rs1), Operand::Immediate(0)],
+        ),
+        ("c.jalr", [Register(rs1)]) => (
+            "jalr",
+            vec![Register(1), Register(*rs1), Operand::Immediate(0)],
+        ),
+        ("c.jr" | "c.jalr", _) => return Err(wrong_operands("rs1")),
+        ("c.beqz", [Register(rs1), target @ (Symbol(_) | Operand::Immediate(_))]) => {
+            ("beq", vec![Register(*rs1), Register(0), target.clone()])
+        }
+        ("c.bnez", [Register(rs1), target @ (Symbol(_) | Operand::Immediate(_))]) => {
+            ("bne", vec![Register(*rs1), Register(0), target.clone()])
+        }
+        ("c.beqz" | "c.bnez", _) => return Err(wrong_operands("rs1, label")),
+        _ => {
+            return Err(parser_error(
+                &format!("Unknown compressed instruction '{}'", mnemonic),
+                location.clone(),
+            ));
+        }
+    };
+    Ok(Instruction {
+        mnemonic: base.to_string(),
+        operands,
+        location: location.clone(),
+        operand_locations: Vec::new(),
+        compressed: Some(mnemonic.clone()),
+    })
+}
+
+/// Every 16 bit form that can express the base instruction `word`, the one assemblers pick first
+pub(crate) fn compress(word: u32) -> Vec<(&'static str, u16)> {
+    let opcode = word & 0x7f;
+    let rd = (word >> 7) & 0x1f;
+    let funct3 = (word >> 12) & 0x7;
+    let rs1 = (word >> 15) & 0x1f;
+    let rs2 = (word >> 20) & 0x1f;
+    let funct7 = word >> 25;
+    let imm_i = word as i32 >> 20;
+    let imm_s = ((word as i32 >> 25) << 5) | ((word >> 7) & 0x1f) as i32;
+    let imm_b = ((word & 0x8000_0000) as i32 >> 19)
+        | ((word << 4) & 0x800) as i32
+        | ((word >> 20) & 0x7e0) as i32
+        | ((word >> 7) & 0x1e) as i32;
+    let imm_j = ((word & 0x8000_0000) as i32 >> 11)
+        | (word & 0x000f_f000) as i32
+        | ((word >> 9) & 0x800) as i32
+        | ((word >> 20) & 0x7fe) as i32;
+    // x8-x15, the registers the 3 bit register fields can name
+    let small = |register: u32| (8..16).contains(&register);
+    let fits = |value: i32, bits: u32| (-(1 << (bits - 1))..(1 << (bits - 1))).contains(&value);
+    let bit = |value: i32, from: u32, to: u32| (((value as u32) >> from) & 1) << to;
+    // Quadrant, funct3 and the low 3 bits of a register number in the usual places
+    let ci = |quadrant: u32, funct3: u32, rd: u32, imm: i32| {
+        quadrant | (imm as u32 & 0x1f) << 2 | rd << 7 | bit(imm, 5, 12) | funct3 << 13
+    };
+
+    let mut forms = Vec::new();
+    let mut push = |name: &'static str, encoding: u32| forms.push((name, encoding as u16));
+    match (opcode, funct3) {
+        // ADDI
+        (0b0010011, 0x0) => {
+            if rd == 0 && rs1 == 0 && imm_i == 0 {
+                push("c.nop", 0x0001);
+            }
+            if rd != 0 && rd == rs1 && imm_i != 0 && fits(imm_i, 6) {
+                push("c.addi", ci(0b01, 0b000, rd, imm_i));
+            }
+            if rd != 0 && rs1 == 0 && fits(imm_i, 6) {
+                push("c.li", ci(0b01, 0b010, rd, imm_i));
+            }
+            if rd == 2 && rs1 == 2 && imm_i != 0 && imm_i % 16 == 0 && fits(imm_i, 10) {
+                let imm = bit(imm_i, 9, 12)
+                    | bit(imm_i, 4, 6)
+                    | bit(imm_i, 6, 5)
+                    | bit(imm_i, 7, 3)
+                    | bit(imm_i, 8, 4)
+                    | bit(imm_i, 5, 2);
+                push("c.addi16sp", 0b01 | 2 << 7 | imm | 0b011 << 13);
+            }
+            if small(rd) && rs1 == 2 && imm_i > 0 && imm_i % 4 == 0 && imm_i < 1024 {
+                let imm = bit(imm_i, 4, 11)
+                    | bit(imm_i, 5, 12)
+                    | ((imm_i as u32 >> 6) & 0xf) << 7
+                    | bit(imm_i, 2, 6)
+                    | bit(imm_i, 3, 5);
+                push("c.addi4spn", (rd - 8) << 2 | imm);
+            }
+            if rd != 0 && rs1 != 0 && imm_i == 0 {
+                push("c.mv", 0b10 | rs1 << 2 | rd << 7 | 0b100 << 13);
+            }
+        }
+        // ANDI
+        (0b0010011, 0x7) if small(rd) && rd == rs1 && fits(imm_i, 6) => {
+            push("c.andi", ci(0b01, 0b100, rd - 8, imm_i) | 0b10 << 10);
+        }
+        // SLLI
+        (0b0010011, 0x1) if rd != 0 && rd == rs1 && rs2 != 0 && funct7 == 0 => {
+            push("c.slli", 0b10 | rs2 << 2 | rd << 7);
+        }
+        // SRLI, SRAI
+        (0b0010011, 0x5) if small(rd) && rd == rs1 && rs2 != 0 => {
+            let (name, funct2) = match funct7 {
+                0x00 => ("c.srli", 0b00),
+                0x20 => ("c.srai", 0b01),
+                _ => return forms,
+            };
+            push(
+                name,
+                0b01 | rs2 << 2 | (rd - 8) << 7 | funct2 << 10 | 0b100 << 13,
+            );
+        }
+        // ADD
+        (0b0110011, 0x0) if funct7 == 0 && rd != 0 && rs2 != 0 => {
+            if rs1 == 0 {
+                push("c.mv", 0b10 | rs2 << 2 | rd << 7 | 0b100 << 13);
+            } else if rs1 == rd {
+                push("c.add", 0b10 | rs2 << 2 | rd << 7 | 1 << 12 | 0b100 << 13);
+            } else if rs2 == rd {
+                push("c.add", 0b10 | rs1 << 2 | rd << 7 | 1 << 12 | 0b100 << 13);
+            }
+        }
+        // SUB, XOR, OR, AND
+        (0b0110011, _) if small(rd) && rd == rs1 && small(rs2) => {
+            let (name, funct2) = match (funct3, funct7) {
+                (0x0, 0x20) => ("c.sub", 0b00),
+                (0x4, 0x00) => ("c.xor", 0b01),
+                (0x6, 0x00) => ("c.or", 0b10),
+                (0x7, 0x00) => ("c.and", 0b11),
+                _ => return forms,
+            };
+            push(
+                name,
+                0b01 | (rs2 - 8) << 2 | funct2 << 5 | (rd - 8) << 7 | 0b11 << 10 | 0b100 << 13,
+            );
+        }
+        // LUI, the immediate is sign extended from 6 bits
+        (0b0110111, _) if rd != 0 && rd != 2 => {
+            let imm = word as i32 >> 12;
+            if imm != 0 && fits(imm, 6) {
+                push("c.lui", ci(0b01, 0b011, rd, imm));
+            }
+        }
+        // LW
+        (0b0000011, 0x2) if imm_i >= 0 && imm_i % 4 == 0 => {
+            if rs1 == 2 && rd != 0 && imm_i < 256 {
+                let imm = bit(imm_i, 5, 12)
+                    | ((imm_i as u32 >> 2) & 0x7) << 4
+                    | ((imm_i as u32 >> 6) & 0x3) << 2;
+                push("c.lwsp", 0b10 | imm | rd << 7 | 0b010 << 13);
+            }
+            if small(rd) && small(rs1) && imm_i < 128 {
+                let imm = ((imm_i as u32 >> 3) & 0x7) << 10 | bit(imm_i, 2, 6) | bit(imm_i, 6, 5);
+                push("c.lw", (rd - 8) << 2 | imm | (rs1 - 8) << 7 | 0b010 << 13);
+            }
+        }
+        // SW
+        (0b0100011, 0x2) if imm_s >= 0 && imm_s % 4 == 0 => {
+            if rs1 == 2 && imm_s < 256 {
+                let imm = ((imm_s as u32 >> 2) & 0xf) << 9 | ((imm_s as u32 >> 6) & 0x3) << 7;
+                push("c.swsp", 0b10 | rs2 << 2 | imm | 0b110 << 13);
+            }
+            if small(rs2) && small(rs1) && imm_s < 128 {
+                let imm = ((imm_s as u32 >> 3) & 0x7) << 10 | bit(imm_s, 2, 6) | bit(imm_s, 6, 5);
+                push("c.sw", (rs2 - 8) << 2 | imm | (rs1 - 8) << 7 | 0b110 << 13);
+            }
+        }
+        // JAL
+        (0b1101111, _) if (rd == 0 || rd == 1) && fits(imm_j, 12) => {
+            let imm = bit(imm_j, 11, 12)
+                | bit(imm_j, 4, 11)
+                | ((imm_j as u32 >> 8) & 0x3) << 9
+                | bit(imm_j, 10, 8)
+                | bit(imm_j, 6, 7)
+                | bit(imm_j, 7, 6)
+                | ((imm_j as u32 >> 1) & 0x7) << 3
+                | bit(imm_j, 5, 2);
+            let (name, funct3) = if rd == 0 {
+                ("c.j", 0b101)
+            } else {
+                ("c.jal", 0b001)
+            };
+            push(name, 0b01 | imm | funct3 << 13);
+        }
+        // JALR
+        (0b1100111, 0x0) if rs1 != 0 && imm_i == 0 && (rd == 0 || rd == 1) => {
+            let (name, link) = if rd == 0 { ("c.jr", 0) } else { ("c.jalr", 1) };
+            push(name, 0b10 | rs1 << 7 | link << 12 | 0b100 << 13);
+        }
+        // BEQ, BNE against zero
+        (0b1100011, 0x0 | 0x1) if small(rs1) && rs2 == 0 && fits(imm_b, 9) => {
+            let imm = bit(imm_b, 8, 12)
+                | ((imm_b as u32 >> 3) & 0x3) << 10
+                | ((imm_b as u32 >> 6) & 0x3) << 5
+                | ((imm_b as u32 >> 1) & 0x3) << 3
+                | bit(imm_b, 5, 2);
+            let (name, funct3) = if funct3 == 0 {
+                ("c.beqz", 0b110)
+            } else {
+                ("c.bnez", 0b111)
+            };
+            push(name, 0b01 | imm | (rs1 - 8) << 7 | funct3 << 13);
+        }
+        _ => {}
+    }
+    forms
+}
+
+/// The mnemonic of a 16 bit encoding and the base instruction it stands for, the reverse of
+/// [`compress`]. Reserved encodings, hints and forms the assembler doesn't accept are `None`.
+pub(crate) fn decompress(parcel: u16, xlen: Xlen) -> Option<(&'static str, u32)> {
+    let parcel = parcel as u32;
+    let bits = |from: u32, count: u32| (parcel >> from) & ((1 << count) - 1);
+    // Sign extends the low `width` bits
+    let signed = |value: u32, width: u32| ((value << (32 - width)) as i32 >> (32 - width)) as u32;
+    let funct3 = bits(13, 3);
+    // The 5 bit register fields, and the 3 bit ones naming x8-x15
+    let rd = bits(7, 5) as u8;
+    let rs2 = bits(2, 5) as u8;
+    let rd_small = bits(7, 3) as u8 + 8;
+    let rs2_small = bits(2, 3) as u8 + 8;
+    let imm = signed(bits(12, 1) << 5 | bits(2, 5), 6);
+    let shamt = bits(12, 1) << 5 | bits(2, 5);
+    let shamt_fits = shamt != 0 && (xlen == Xlen::Rv64 || shamt < 32);
+
+    let form = match (bits(0, 2), funct3) {
+        (0b00, 0b000) => {
+            let imm = bits(11, 2) << 4 | bits(7, 4) << 6 | bits(6, 1) << 2 | bits(5, 1) << 3;
+            if imm == 0 {
+                return None;
+            }
+            (
+                "c.addi4spn",
+                encode_i(OPCODE_OP_IMM, rs2_small, 0x0, 2, imm),
+            )
+        }
+        (0b00, 0b010 | 0b110) => {
+            let offset = bits(10, 3) << 3 | bits(6, 1) << 2 | bits(5, 1) << 6;
+            match funct3 {
+                0b010 => (
+                    "c.lw",
+                    encode_i(OPCODE_LOAD, rs2_small, 0x2, rd_small, offset),
+                ),
+                _ => (
+                    "c.sw",
+                    encode_s(OPCODE_STORE, 0x2, rd_small, rs2_small, offset),
+                ),
+            }
+        }
+        (0b01, 0b000) => match (rd, imm) {
+            (0, 0) => ("c.nop", encode_i(OPCODE_OP_IMM, 0, 0x0, 0, 0)),
+            (0, _) | (_, 0) => return None,
+            _ => ("c.addi", encode_i(OPCODE_OP_IMM, rd, 0x0, rd, imm)),
+        },
+        (0b01, 0b001 | 0b101) => {
+            // On RV64 the first is C.ADDIW instead
+            if funct3 == 0b001 && xlen == Xlen::Rv64 {
+                return None;
+            }
+            let offset = signed(
+                bits(12, 1) << 11
+                    | bits(11, 1) << 4
+                    | bits(9, 2) << 8
+                    | bits(8, 1) << 10
+                    | bits(7, 1) << 6
+                    | bits(6, 1) << 7
+                    | bits(3, 3) << 1
+                    | bits(2, 1) << 5,
+                12,
+            );
+            match funct3 {
+                0b001 => ("c.jal", encode_j(OPCODE_JAL, 1, offset)),
+                _ => ("c.j", encode_j(OPCODE_JAL, 0, offset)),
+            }
+        }
+        (0b01, 0b010) if rd != 0 => ("c.li", encode_i(OPCODE_OP_IMM, rd, 0x0, 0, imm)),
+        (0b01, 0b011) if rd == 2 => {
+            let imm = signed(
+                bits(12, 1) << 9
+                    | bits(6, 1) << 4
+                    | bits(5, 1) << 6
+                    | bits(3, 2) << 7
+                    | bits(2, 1) << 5,
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            ("c.addi16sp", encode_i(OPCODE_OP_IMM, 2, 0x0, 2, imm))
+        }
+        (0b01, 0b011) if rd != 0 && imm != 0 => (
+            "c.lui",
+            OPCODE_LUI | (rd as u32) << 7 | (imm & 0xfffff) << 12,
+        ),
+        (0b01, 0b100) => match bits(10, 2) {
+            0b00 if shamt_fits => (
+                "c.srli",
+                encode_i(OPCODE_OP_IMM, rd_small, 0x5, rd_small, shamt),
+            ),
+            0b01 if shamt_fits => (
+                "c.srai",
+                encode_i(OPCODE_OP_IMM, rd_small, 0x5, rd_small, 0x20 << 5 | shamt),
+            ),
+            0b10 => (
+                "c.andi",
+                encode_i(OPCODE_OP_IMM, rd_small, 0x7, rd_small, imm),
+            ),
+            // The RV64 forms, C.SUBW and C.ADDW, have bit 12 set
+            0b11 if bits(12, 1) == 0 => {
+                let (name, funct3, funct7) = match bits(5, 2) {
+                    0b00 => ("c.sub", 0x0, 0x20),
+                    0b01 => ("c.xor", 0x4, 0x00),
+                    0b10 => ("c.or", 0x6, 0x00),
+                    _ => ("c.and", 0x7, 0x00),
+                };
+                (
+                    name,
+                    encode_r(OPCODE_OP, rd_small, funct3, rd_small, rs2_small, funct7),
+                )
+            }
+            _ => return None,
+        },
+        (0b01, 0b110 | 0b111) => {
+            let offset = signed(
+                bits(12, 1) << 8
+                    | bits(10, 2) << 3
+                    | bits(5, 2) << 6
+                    | bits(3, 2) << 1
+                    | bits(2, 1) << 5,
+                9,
+            );
+            let name = if funct3 == 0b110 { "c.beqz" } else { "c.bnez" };
+            (
+                name,
+                encode_b(OPCODE_BRANCH, funct3 & 1, rd_small, 0, offset),
+            )
+        }
+        (0b10, 0b000) if rd != 0 && shamt_fits => {
+            ("c.slli", encode_i(OPCODE_OP_IMM, rd, 0x1, rd, shamt))
+        }
+        (0b10, 0b010) if rd != 0 => {
+            let offset = bits(12, 1) << 5 | bits(4, 3) << 2 | bits(2, 2) << 6;
+            ("c.lwsp", encode_i(OPCODE_LOAD, rd, 0x2, 2, offset))
+        }
+        (0b10, 0b100) => match (bits(12, 1), rd, rs2) {
+            // C.EBREAK and hints
+            (_, 0, _) => return None,
+            (0, _, 0) => ("c.jr", encode_i(OPCODE_JALR, 0, 0x0, rd, 0)),
+            (0, _, _) => ("c.mv", encode_r(OPCODE_OP, rd, 0x0, 0, rs2, 0x00)),
+            (_, _, 0) => ("c.jalr", encode_i(OPCODE_JALR, 1, 0x0, rd, 0)),
+            _ => ("c.add", encode_r(OPCODE_OP, rd, 0x0, rd, rs2, 0x00)),
+        },
+        (0b10, 0b110) => {
+            let offset = bits(9, 4) << 2 | bits(7, 2) << 6;
+            ("c.swsp", encode_s(OPCODE_STORE, 0x2, 2, rs2, offset))
+        }
+        _ => return None,
+    };
+    Some(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(word: u32, name: &str) -> Option<u16> {
+        compress(word)
+            .into_iter()
+            .find(|(form, _)| *form == name)
+            .map(|(_, encoding)| encoding)
+    }
+
+    // Expected encodings taken from the LLVM assembler
+    #[test]
+    fn test_compress() {
+        // addi sp, sp, -16
+        assert_eq!(compress(0xff010113)[0], ("c.addi", 0x1141));
+        assert_eq!(form(0xff010113, "c.addi16sp"), Some(0x717d));
+        // addi sp, sp, -48
+        assert_eq!(compress(0xfd010113)[0], ("c.addi16sp", 0x7179));
+        // addi a0, zero, 5
+        assert_eq!(compress(0x00500513)[0], ("c.li", 0x4515));
+        // addi a0, sp, 8
+        assert_eq!(compress(0x00810513)[0], ("c.addi4spn", 0x0028));
+        // addi a0, a1, 0
+        assert_eq!(compress(0x00058513)[0], ("c.mv", 0x852e));
+        // add a0, a0, a1
+        assert_eq!(compress(0x00b50533)[0], ("c.add", 0x952e));
+        // sub s0, s0, s1
+        assert_eq!(compress(0x40940433)[0], ("c.sub", 0x8c05));
+        // lui a0, 0xfffff
+        assert_eq!(compress(0xfffff537)[0], ("c.lui", 0x757d));
+        // lw ra, 12(sp); sw ra, 12(sp)
+        assert_eq!(compress(0x00c12083)[0], ("c.lwsp", 0x40b2));
+        assert_eq!(compress(0x00112623)[0], ("c.swsp", 0xc606));
+        // lw a0, 4(a1); sw a0, 4(a1)
+        assert_eq!(compress(0x0045a503)[0], ("c.lw", 0x41c8));
+        assert_eq!(compress(0x00a5a223)[0], ("c.sw", 0xc1c8));
+        // srai a0, a0, 3; andi a0, a0, -1; slli a0, a0, 2
+        assert_eq!(compress(0x40355513)[0], ("c.srai", 0x850d));
+        assert_eq!(compress(0xfff57513)[0], ("c.andi", 0x997d));
+        assert_eq!(compress(0x00251513)[0], ("c.slli", 0x050a));
+        // jal zero, -8; jal ra, +0x100
+        assert_eq!(compress(0xff9ff06f)[0], ("c.j", 0xbfe5));
+        assert_eq!(compress(0x100000ef)[0], ("c.jal", 0x2201));
+        // jalr zero, 0(ra); jalr ra, 0(a0)
+        assert_eq!(compress(0x00008067)[0], ("c.jr", 0x8082));
+        assert_eq!(compress(0x000500e7)[0], ("c.jalr", 0x9502));
+        // beq a0, zero, +8; bne a0, zero, -4
+        assert_eq!(compress(0x00050463)[0], ("c.beqz", 0xc501));
+        assert_eq!(compress(0xfe051ee3)[0], ("c.bnez", 0xfd75));
+    }
+
+    #[test]
+    fn test_incompressible() {
+        // addi a0, a1, 1: rd differs from rs1
+        assert_eq!(compress(0x00158513), []);
+        // add a0, a0, a1 with t6 (x31) is fine, sub isn't: t6 isn't one of x8-x15
+        assert_eq!(compress(0x41ff0f33), []);
+        // lw a0, 128(a1): offset out of range
+        assert_eq!(compress(0x0805a503), []);
+        // jal a0, 0: links to a register other than ra
+        assert_eq!(compress(0x0000056f), []);
+    }
+
+    #[test]
+    fn test_decompress() {
+        // Every form of the base instructions compress() turns into them, c.mv standing for
+        // an ADD
+        for word in [
+            0xff010113, 0xfd010113, 0x00500513, 0x00810513, 0x00b00533, 0x00b50533, 0x40940433,
+            0xfffff537, 0x00c12083, 0x00112623, 0x0045a503, 0x00a5a223, 0x40355513, 0xfff57513,
+            0x00251513, 0xff9ff06f, 0x100000ef, 0x00008067, 0x000500e7, 0x00050463, 0xfe051ee3,
+            0x00000013,
+        ] {
+            for (name, encoding) in compress(word) {
+                assert_eq!(
+                    decompress(encoding, Xlen::Rv32),
+                    Some((name, word)),
+                    "{}",
+                    name
+                );
+            }
+        }
+        // C.JAL is C.ADDIW on RV64
+        assert_eq!(decompress(0x2201, Xlen::Rv64), None);
+        // All zeros is illegal, C.EBREAK isn't written by the assembler
+        assert_eq!(decompress(0x0000, Xlen::Rv32), None);
+        assert_eq!(decompress(0x9002, Xlen::Rv32), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! Conditional assembly, keeping or dropping lines before they're tokenized so a condition
+//! can guard includes and macro definitions too:
+//!
+//! ```text
+//! .ifdef FAST
+//!     .include "fast.s"
+//! .elseif XLEN == 64
+//!     .macro load reg, address
+//!         ld \reg, \address
+//!     .endm
+//! .else
+//!     .macro load reg, address
+//!         lw \reg, \address
+//!     .endm
+//! .endif
+//! ```
+//!
+//! `.if expr`, `.ifdef NAME` and `.ifndef NAME` start a conditional, `.elseif expr` and
+//! `.else` the branches after the first and `.endif` ends it. Only the first branch whose
+//! condition holds is assembled. Conditions use numbers, earlier `.equ` symbols and
+//! [`AssemblerOptions::defines`](crate::AssemblerOptions::defines), `.ifdef` asks whether
+//! a label or `.equ` symbol was defined on an earlier line. Conditionals in the body of a
+//! macro are evaluated where the macro is used.
+
+use std::borrow::Cow;
+
+use crate::{
+    assembler::AssemblerOptions,
+    error::{AssemblerError, SourceLocation},
+    include::Line,
+    macros::{split_labels, split_statement, strip_comment},
+    parser::Parser,
+    symbol_table::SymbolTable,
+    tokenizer::{TokenKind, tokenize_lines},
+};
+
+/// Directives of conditional assembly
+const CONDITIONAL_DIRECTIVES: [&str; 6] =
+    [".if", ".ifdef", ".ifndef", ".elseif", ".else", ".endif"];
+
+/// An `.if`, `.ifdef` or `.ifndef` whose `.endif` hasn't come yet
+#[derive(Debug)]
+struct Conditional {
+    location: SourceLocation,
+    /// Whether one of its branches was assembled, the ones after it are skipped
+    taken: bool,
+    /// Whether its `.else` came
+    in_else: bool,
+}
+
+/// The conditionals the lines so far are in, and the symbols their conditions can use
+#[derive(Debug)]
+pub(crate) struct Conditionals<'o> {
+    options: &'o AssemblerOptions,
+    /// Innermost last
+    open: Vec<Conditional>,
+    /// Whether lines are skipped, up to the next branch of the innermost conditional
+    skipping: bool,
+    /// Conditionals started in skipped lines, which are skipped whole
+    skipped: usize,
+    /// Labels and `.equ` symbols of the lines so far, with the defines
+    symbol_table: SymbolTable,
+}
+
+impl<'o> Conditionals<'o> {
+    pub fn new(options: &'o AssemblerOptions) -> anyhow::Result<Self> {
+        let mut symbol_table = SymbolTable::new();
+        for (name, value) in &options.defines {
+            symbol_table.define_absolute(name, *value, SourceLocation::new(0, 0))?;
+        }
+        Ok(Self {
+            options,
+            open: Vec::new(),
+            skipping: false,
+            skipped: 0,
+            symbol_table,
+        })
+    }
+
+    /// `line` if it's assembled, `None` if it's skipped or a conditional directive, which is
+    /// applied. The labels in front of a directive are kept.
+    pub fn filter<'a>(&mut self, line: Line<'a>) -> anyhow::Result<Option<Line<'a>>> {
+        let (labels, statement) = split_labels(&line.text);
+        let directive = split_statement(strip_comment(statement))
+            .0
+            .to_ascii_lowercase();
+        if !CONDITIONAL_DIRECTIVES.contains(&directive.as_str()) {
+            return Ok((!self.skipping).then_some(line));
+        }
+        if self.skipping {
+            match directive.as_str() {
+                ".if" | ".ifdef" | ".ifndef" => self.skipped += 1,
+                ".endif" if self.skipped > 0 => self.skipped -= 1,
+                _ if self.skipped > 0 => {}
+                _ => self.skipping = !self.branch(&directive, &line)?,
+            }
+            return Ok(None);
+        }
+        let labels = (!labels.trim().is_empty()).then(|| {
+            let span = match line.expanded {
+                true => line.span.clone(),
+                false => line.span.start..line.span.start + labels.len(),
+            };
+            Line {
+                text: Cow::Owned(labels.to_string()),
+                span,
+                ..line.clone()
+            }
+        });
+        self.skipping = !self.branch(&directive, &line)?;
+        Ok(labels)
+    }
+
+    /// Applies the conditional directive on `line`, returning whether the lines after it are
+    /// assembled
+    fn branch(&mut self, directive: &str, line: &Line) -> anyhow::Result<bool> {
+        let tokens = tokenize_lines(vec![line.clone()], line.span.end)?;
+        let location = tokens
+            .iter()
+            .find(|token| token.kind == TokenKind::Directive)
+            .map_or_else(|| line.location.clone(), |token| token.location.clone());
+        let mut parser = Parser::with_options(tokens, self.options);
+        if matches!(directive, ".if" | ".ifdef" | ".ifndef") {
+            let taken = parser
+                .parse_conditional(&mut self.symbol_table)?
+                .unwrap_or_default();
+            self.open.push(Conditional {
+                location,
+                taken,
+                in_else: false,
+            });
+            return Ok(taken);
+        }
+
+        let Some(conditional) = self.open.last_mut() else {
+            return Err(conditional_error(
+                &format!("'{}' without an '.if'", directive),
+                location,
+            ));
+        };
+        if conditional.in_else && directive != ".endif" {
+            return Err(conditional_error(
+                &format!(
+                    "'{}' after the '.else' of the '.if' at {}",
+                    directive, conditional.location
+                ),
+                location,
+            ));
+        }
+        let assemble = match directive {
+            ".endif" => {
+                parser.parse_conditional(&mut self.symbol_table)?;
+                self.open.pop();
+                return Ok(true);
+            }
+            ".else" => {
+                parser.parse_conditional(&mut self.symbol_table)?;
+                !conditional.taken
+            }
+            // The condition isn't even parsed once a branch was taken
+            _ if conditional.taken => false,
+            _ => parser
+                .parse_conditional(&mut self.symbol_table)?
+                .unwrap_or_default(),
+        };
+        conditional.taken |= assemble;
+        conditional.in_else |= directive == ".else";
+        Ok(assemble)
+    }
+
+    /// Defines the labels and `.equ` symbol of the assembled `line`, for the conditions after
+    /// it. Redefined labels are left to the parser to report.
+    pub fn record(&mut self, line: &Line) -> anyhow::Result<()> {
+        let (labels, statement) = split_labels(&line.text);
+        for label in labels.split(':').map(str::trim) {
+            if !label.is_empty() && label.parse::<u64>().is_err() {
+                let _ = self.symbol_table.define(label, line.location.clone());
+            }
+        }
+        let directive = split_statement(statement).0;
+        if directive.eq_ignore_ascii_case(".equ") || directive.eq_ignore_ascii_case(".set") {
+            let tokens = tokenize_lines(vec![line.clone()], line.span.end)?;
+            Parser::with_options(tokens, self.options).parse_constant(&mut self.symbol_table)?;
+        }
+        Ok(())
+    }
+
+    /// Fails if a conditional has no `.endif`
+    pub fn finish(&self) -> anyhow::Result<()> {
+        match self.open.last() {
+            Some(conditional) => Err(conditional_error(
+                "'.if' without an '.endif'",
+                conditional.location.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn conditional_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
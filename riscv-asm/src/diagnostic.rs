@@ -0,0 +1,385 @@
+//! Errors and warnings rendered for people: the message, the line it is about with the
+//! offending token underlined, and a suggestion where there is an obvious fix:
+//!
+//! ```text
+//! error: Immediate 5000 does not fit in 12 bits (-2048..2047)
+//!  --> main.s:3:5
+//!   |
+//! 3 |     addi a0, zero, 5000
+//!   |     ^^^^
+//!   = help: `li a0, 5000` loads a constant of any size
+//! ```
+//!
+//! Locations only have a column, the underline covers the word (a mnemonic, register, number
+//! or label) or string starting there. Lines of included files are read from the files the
+//! locations name.
+
+use std::{borrow::Cow, fmt::Write, fs};
+
+use crate::error::{AssemblerError, SourceLocation};
+
+/// SGR parameters of the parts of a rendered diagnostic, the colors `rv` uses too
+const ERROR_COLOR: &str = "1;31";
+const WARNING_COLOR: &str = "1;33";
+const GUTTER_COLOR: &str = "1;34";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => ERROR_COLOR,
+            Severity::Warning => WARNING_COLOR,
+        }
+    }
+}
+
+/// A message about a place in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: SourceLocation,
+    /// What would likely fix it
+    pub help: Option<String>,
+    /// Another place the message is about, like where a redefined symbol was first defined
+    pub note: Option<String>,
+}
+
+/// The text of a source, for the lines diagnostics show
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFiles<'a> {
+    /// How locations in the source itself are named, e.g. its path
+    pub name: &'a str,
+    pub text: &'a str,
+}
+
+impl<'a> SourceFiles<'a> {
+    pub fn new(name: &'a str, text: &'a str) -> Self {
+        Self { name, text }
+    }
+
+    /// `location` as `file:line:column`, the source itself by its name
+    pub fn place(&self, location: &SourceLocation) -> String {
+        let file = location.file.as_deref().unwrap_or(self.name);
+        format!("{}:{}:{}", file, location.line, location.col)
+    }
+
+    /// Line `line` (1-based) of the source, or of the included `file`
+    pub fn line(&self, file: Option<&str>, line: u64) -> Option<Cow<'a, str>> {
+        let index = usize::try_from(line).ok()?.checked_sub(1)?;
+        match file {
+            None => self.text.split('\n').nth(index).map(Cow::Borrowed),
+            Some(file) => {
+                let text = fs::read_to_string(file).ok()?;
+                text.split('\n')
+                    .nth(index)
+                    .map(|line| Cow::Owned(line.to_string()))
+            }
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, location: SourceLocation) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            location,
+            help: None,
+            note: None,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, location: SourceLocation) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message, location)
+        }
+    }
+
+    /// One diagnostic per error `error` stands for, with suggestions for the ones that have
+    /// an obvious fix
+    pub fn from_error(error: &AssemblerError, sources: &SourceFiles) -> Vec<Self> {
+        error
+            .errors()
+            .into_iter()
+            .flat_map(|error| {
+                let note = match error {
+                    AssemblerError::Redefinition { first, .. } => {
+                        Some(format!("first defined at {}", sources.place(first)))
+                    }
+                    _ => None,
+                };
+                error
+                    .diagnostics()
+                    .into_iter()
+                    .map(move |(message, location)| (message, location, note.clone()))
+            })
+            .map(|(message, location, note)| {
+                let line = sources.line(location.file.as_deref(), location.line);
+                Self {
+                    help: line.and_then(|line| suggestion(message, &line)),
+                    note,
+                    ..Self::error(message, location.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// The diagnostic as lines of text ending in a newline, with ANSI colors if `color`
+    pub fn render(&self, sources: &SourceFiles, color: bool) -> String {
+        let paint = |code: &str, text: &str| match color {
+            true => format!("\x1b[{}m{}\x1b[0m", code, text),
+            false => text.to_string(),
+        };
+        let location = &self.location;
+        let mut rendered = String::new();
+        let _ = writeln!(
+            rendered,
+            "{}: {}",
+            paint(self.severity.color(), self.severity.label()),
+            self.message
+        );
+        let line = sources.line(location.file.as_deref(), location.line);
+        let number = location.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let _ = writeln!(
+            rendered,
+            "{}{} {}",
+            gutter,
+            paint(GUTTER_COLOR, "-->"),
+            sources.place(location)
+        );
+        if let Some(line) = line {
+            let bar = paint(GUTTER_COLOR, "|");
+            let line = line.trim_end_matches('\r');
+            let start = usize::try_from(location.col.saturating_sub(1)).unwrap_or(usize::MAX);
+            // Tabs before the token stay tabs, so the carets line up under it
+            let indent: String = line
+                .chars()
+                .take(start)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let carets = "^".repeat(token_width(line, start));
+            let _ = writeln!(rendered, "{} {}", gutter, bar);
+            let _ = writeln!(
+                rendered,
+                "{} {} {}",
+                paint(GUTTER_COLOR, &number),
+                bar,
+                line
+            );
+            let _ = writeln!(
+                rendered,
+                "{} {} {}{}",
+                gutter,
+                bar,
+                indent,
+                paint(self.severity.color(), &carets)
+            );
+        }
+        if let Some(note) = &self.note {
+            let _ = writeln!(rendered, "{} = note: {}", gutter, note);
+        }
+        if let Some(help) = &self.help {
+            let _ = writeln!(rendered, "{} = help: {}", gutter, help);
+        }
+        rendered
+    }
+}
+
+/// Characters the token starting at character `start` of `line` takes up, at least 1
+fn token_width(line: &str, start: usize) -> usize {
+    let mut chars = line.chars().skip(start).peekable();
+    let width = match chars.peek() {
+        Some('"') => {
+            let mut escaped = false;
+            let mut width = 0;
+            for c in chars {
+                width += 1;
+                if c == '"' && !escaped && width > 1 {
+                    break;
+                }
+                escaped = c == '\\' && !escaped;
+            }
+            width
+        }
+        // A negative number
+        Some('-') => {
+            chars.next();
+            1 + chars.take_while(char::is_ascii_alphanumeric).count()
+        }
+        _ => chars
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | '%'))
+            .count(),
+    };
+    width.max(1)
+}
+
+/// A likely fix for the error `message` about the source line `line`
+fn suggestion(message: &str, line: &str) -> Option<String> {
+    let statement = statement(line);
+    let (mnemonic, operands) = statement
+        .split_once([' ', '\t'])
+        .map_or((statement, ""), |(mnemonic, operands)| {
+            (mnemonic, operands.trim())
+        });
+    let mnemonic = mnemonic.to_lowercase();
+    let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+
+    if let Some(name) = message.strip_prefix("Undefined symbol: ") {
+        return Some(format!(
+            "define it as a label, `{}:`, or as a constant with `.equ {}, value`",
+            name, name
+        ));
+    }
+    if message.ends_with("is only available on RV64") {
+        return Some("assemble for RV64 to use it".to_string());
+    }
+    if message.starts_with("'.if' without an '.endif'") {
+        return Some("close the block with `.endif`".to_string());
+    }
+    if message.starts_with("Immediate ") && message.contains("out of range 0x00000..0xFFFFF") {
+        return Some("`li` loads a full 32 bit constant".to_string());
+    }
+    if message.ends_with("a branch reaches") {
+        return Some(
+            "branch on the opposite condition around a `j`, which reaches 1MiB, or assemble with \
+             branch relaxation to have that done"
+                .to_string(),
+        );
+    }
+    if !(message.starts_with("Immediate ") && message.contains(" does not fit in ")) {
+        return None;
+    }
+    let register_form = match mnemonic.as_str() {
+        "addi" => "add",
+        "andi" => "and",
+        "ori" => "or",
+        "xori" => "xor",
+        "slti" => "slt",
+        "sltiu" => "sltu",
+        _ => return None,
+    };
+    match operands.as_slice() {
+        [rd, "zero" | "x0", immediate] if mnemonic == "addi" => Some(format!(
+            "`li {}, {}` loads a constant of any size",
+            rd, immediate
+        )),
+        [rd, rs1, immediate] => Some(format!(
+            "load the constant into a free register with `li`, e.g. `li t0, {}`, and use `{} {}, {}, t0`",
+            immediate, register_form, rd, rs1
+        )),
+        _ => None,
+    }
+}
+
+/// The instruction or directive on `line`, without labels and comment
+fn statement(line: &str) -> &str {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut rest = line;
+    while let Some((label, after)) = rest.split_once(':')
+        && !label.trim().is_empty()
+        && label
+            .trim()
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+    {
+        rest = after;
+    }
+    rest.trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    fn render(source: &str) -> String {
+        let error = assemble(source).unwrap_err();
+        let error = error.downcast_ref::<AssemblerError>().unwrap();
+        let sources = SourceFiles::new("main.s", source);
+        Diagnostic::from_error(error, &sources)
+            .iter()
+            .map(|diagnostic| diagnostic.render(&sources, false))
+            .collect()
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("main:\n    addi a0, zero, 5000  # too big"),
+            "error: Immediate 5000 does not fit in 12 bits (-2048..2047)
+ --> main.s:2:20
+  |
+2 |     addi a0, zero, 5000  # too big
+  |                    ^^^^
+  = help: `li a0, 5000` loads a constant of any size
+"
+        );
+        // Values out of range are pointed at, not the instruction
+        assert!(render("slli a0, a0, 32").ends_with(
+            "1 | slli a0, a0, 32
+  |              ^^
+"
+        ));
+        assert!(render("lui a0, 0x100000").contains(
+            "1 | lui a0, 0x100000
+  |         ^^^^^^^^
+"
+        ));
+        assert!(render("lw a0, -5000(a1)").ends_with(
+            "1 | lw a0, -5000(a1)
+  |        ^^^^^
+"
+        ));
+        assert!(
+            render("andi a0, a1, 0x1000")
+                .contains("help: load the constant into a free register with `li`, e.g. `li t0, 0x1000`, and use `and a0, a1, t0`")
+        );
+        assert!(
+            render("beq a0, a1, far\n.space 8000\nfar:")
+                .contains("help: branch on the opposite condition around a `j`")
+        );
+        // Carets line up under tabs, and cover the whole token
+        assert!(render("\tj\tnowhere").ends_with(
+            "1 | \tj\tnowhere
+  | \t \t^^^^^^^
+  = help: define it as a label, `nowhere:`, or as a constant with `.equ nowhere, value`
+"
+        ));
+        assert!(
+            render("loop:\nnop\nloop:")
+                .ends_with("3 | loop:\n  | ^^^^\n  = note: first defined at main.s:1:1\n")
+        );
+        assert!(
+            render(".macro m\n.endm\nnop\n.macro m\n.endm")
+                .ends_with("4 | .macro m\n  | ^^^^^^\n  = note: first defined at main.s:1:1\n")
+        );
+        let rendered = render("nop\nfrobnicate a0");
+        assert!(rendered.contains("2 | frobnicate a0\n  | ^^^^^^^^^^\n"));
+        assert!(!rendered.contains("help"));
+
+        let warning =
+            Diagnostic::warning("Label 'x' is never referenced", SourceLocation::new(9, 1));
+        assert_eq!(
+            warning.render(&SourceFiles::new("main.s", "x:"), true),
+            "\x1b[1;33mwarning\x1b[0m: Label 'x' is never referenced\n \x1b[1;34m-->\x1b[0m main.s:9:1\n"
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Machine code back to assembly, for dumping what the assembler made and for the
+//! emulator's trace and debugger views.
+//!
+//! Instructions come out in the syntax the assembler reads, with ABI register names and no
+//! pseudoinstructions, so each assembles back to its encoding. Branches and jumps show the
+//! address they reach rather than their offset, followed by the symbol there when one is
+//! known, `jal ra, 0x10 <main>`. 16 bit parcels decode to the C extension's `c.*` forms.
+//! Anything else comes out as the `.word` or `.half` that places its bits.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    compressed,
+    encoder::{Xlen, csr_name, rounding_mode_name},
+    isa::{self, Definition, Format, OPCODE_OP_IMM, RegisterFile},
+    register::{ABI_NAMES, FLOAT_ABI_NAMES},
+    symbol_table::SymbolTable,
+};
+
+/// An instruction decoded back to assembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembled {
+    /// `addi a0, zero, 5`, branch targets followed by their symbol if one is known
+    pub text: String,
+    /// Bytes the instruction takes, 2 for compressed ones
+    pub size: u32,
+    /// Address a branch or jump goes to
+    pub target: Option<u32>,
+}
+
+/// Decodes instructions of an `xlen` bit target, naming the addresses branches go to
+pub struct Disassembler<'a> {
+    xlen: Xlen,
+    /// Name of the symbol at exactly an address
+    symbol_at: Box<dyn Fn(u32) -> Option<String> + 'a>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(xlen: Xlen) -> Self {
+        Self {
+            xlen,
+            symbol_at: Box::new(|_| None),
+        }
+    }
+
+    /// Names addresses with `symbol_at`, which gives the symbol at exactly an address
+    pub fn with_symbols(self, symbol_at: impl Fn(u32) -> Option<String> + 'a) -> Self {
+        Self {
+            symbol_at: Box::new(symbol_at),
+            ..self
+        }
+    }
+
+    /// Names addresses with an assembled program's labels, leaving out `.equ` constants and
+    /// the labels the assembler made up. The first by name wins where several share one.
+    pub fn with_symbol_table(self, symbols: &SymbolTable) -> Self {
+        let mut labels: BTreeMap<u32, String> = BTreeMap::new();
+        for (name, address) in symbols.sorted_by_address() {
+            let absolute = symbols.get(name).is_some_and(|symbol| symbol.absolute);
+            if !absolute && !name.starts_with(".L") {
+                labels.entry(address).or_insert_with(|| name.to_string());
+            }
+        }
+        self.with_symbols(move |address| labels.get(&address).cloned())
+    }
+
+    /// The instruction at `address` whose 16 or 32 bits are the low ones of `parcel`
+    pub fn instruction(&self, parcel: u32, address: u32) -> Disassembled {
+        let mut disassembled = decode(parcel, address, self.xlen);
+        if let Some(target) = disassembled.target
+            && let Some(name) = (self.symbol_at)(target)
+        {
+            let _ = write!(disassembled.text, " <{}>", name);
+        }
+        disassembled
+    }
+
+    /// Every instruction of `bytes` placed at `origin`, with its address. Bytes at the end
+    /// too few for the instruction they start come out as `.half` or `.byte`.
+    pub fn instructions(&self, bytes: &[u8], origin: u32) -> Vec<(u32, Disassembled)> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let address = origin.wrapping_add(offset as u32);
+            let rest = &bytes[offset..];
+            let disassembled = match rest {
+                [byte] => Disassembled {
+                    text: format!(".byte {:#04x}", byte),
+                    size: 1,
+                    target: None,
+                },
+                [low, high, ..] if rest.len() < 4 && low & 0b11 == 0b11 => Disassembled {
+                    text: format!(".half {:#06x}", u16::from_le_bytes([*low, *high])),
+                    size: 2,
+                    target: None,
+                },
+                _ => {
+                    let mut word = [0; 4];
+                    let len = rest.len().min(4);
+                    word[..len].copy_from_slice(&rest[..len]);
+                    self.instruction(u32::from_le_bytes(word), address)
+                }
+            };
+            offset += disassembled.size as usize;
+            instructions.push((address, disassembled));
+        }
+        instructions
+    }
+
+    /// objdump style listing of `bytes` placed at `origin`: each instruction's address,
+    /// encoding and text, under a `<label>:` line where a symbol starts
+    pub fn dump(&self, bytes: &[u8], origin: u32) -> String {
+        let mut dump = String::new();
+        for (address, disassembled) in self.instructions(bytes, origin) {
+            if let Some(name) = (self.symbol_at)(address) {
+                if !dump.is_empty() {
+                    dump.push('\n');
+                }
+                let _ = writeln!(dump, "{:08x} <{}>:", address, name);
+            }
+            let start = (address - origin) as usize;
+            let encoding: String = bytes[start..start + disassembled.size as usize]
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            let _ = writeln!(
+                dump,
+                "{:>8x}:  {:<8}  {}",
+                address, encoding, disassembled.text
+            );
+        }
+        dump
+    }
+}
+
+/// The instruction at `address` whose 16 or 32 bits are the low ones of `parcel`, without
+/// symbols
+pub fn decode(parcel: u32, address: u32, xlen: Xlen) -> Disassembled {
+    if parcel & 0b11 != 0b11 {
+        let half = parcel as u16;
+        let (text, target) = match compressed::decompress(half, xlen) {
+            Some((name, word)) => decode_compressed(name, word, address),
+            None => (format!(".half {:#06x}", half), None),
+        };
+        return Disassembled {
+            text,
+            size: 2,
+            target,
+        };
+    }
+    let (text, target) = decode_word(parcel, address, xlen)
+        .unwrap_or_else(|| (format!(".word {:#010x}", parcel), None));
+    Disassembled {
+        text,
+        size: 4,
+        target,
+    }
+}
+
+/// Fields of a 32 bit instruction, every immediate sign extended
+struct Fields {
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    funct3: u32,
+    funct7: u32,
+    imm_i: i32,
+    imm_s: i32,
+    imm_b: i32,
+    imm_j: i32,
+}
+
+impl Fields {
+    fn new(word: u32) -> Self {
+        Self {
+            rd: ((word >> 7) & 0x1f) as usize,
+            rs1: ((word >> 15) & 0x1f) as usize,
+            rs2: ((word >> 20) & 0x1f) as usize,
+            funct3: (word >> 12) & 0x7,
+            funct7: word >> 25,
+            imm_i: word as i32 >> 20,
+            imm_s: ((word as i32 >> 25) << 5) | ((word >> 7) & 0x1f) as i32,
+            imm_b: ((word & 0x8000_0000) as i32 >> 19)
+                | ((word << 4) & 0x800) as i32
+                | ((word >> 20) & 0x7e0) as i32
+                | ((word >> 7) & 0x1e) as i32,
+            imm_j: ((word & 0x8000_0000) as i32 >> 11)
+                | (word & 0x000f_f000) as i32
+                | ((word >> 9) & 0x800) as i32
+                | ((word >> 20) & 0x7fe) as i32,
+        }
+    }
+}
+
+/// Text of a 32 bit instruction and where it branches to, `None` if it isn't one
+fn decode_word(word: u32, address: u32, xlen: Xlen) -> Option<(String, Option<u32>)> {
+    let Definition {
+        mnemonic,
+        opcode,
+        format,
+        ..
+    } = isa::decode(word, xlen)?;
+    let Fields {
+        rd,
+        rs1,
+        rs2,
+        funct3,
+        funct7,
+        imm_i,
+        imm_s,
+        imm_b,
+        imm_j,
+    } = Fields::new(word);
+    let x = |register: usize| ABI_NAMES[register];
+    let f = |register: usize| FLOAT_ABI_NAMES[register];
+    let in_file = |file: RegisterFile, register: usize| match file {
+        RegisterFile::Integer => x(register),
+        RegisterFile::Float => f(register),
+    };
+    let csr = || {
+        csr_name((word >> 20) as u16).map_or_else(|| format!("{:#x}", word >> 20), str::to_string)
+    };
+
+    let mut target = None;
+    let operands = match format {
+        Format::R(..) => format!("{}, {}, {}", x(rd), x(rs1), x(rs2)),
+        Format::I(_) => format!("{}, {}, {}", x(rd), x(rs1), imm_i),
+        Format::Shift(..) => {
+            // RV64 shifts of whole registers take 6 bits of shift amount from the funct7
+            let shamt = match (xlen, opcode) {
+                (Xlen::Rv64, OPCODE_OP_IMM) => (word >> 20) & 0x3f,
+                _ => rs2 as u32,
+            };
+            format!("{}, {}, {}", x(rd), x(rs1), shamt)
+        }
+        Format::Load(_) => format!("{}, {}({})", x(rd), imm_i, x(rs1)),
+        Format::Store(_) => format!("{}, {}({})", x(rs2), imm_s, x(rs1)),
+        Format::Branch(_) => {
+            let reached = address.wrapping_add(imm_b as u32);
+            target = Some(reached);
+            format!("{}, {}, {:#x}", x(rs1), x(rs2), reached)
+        }
+        Format::Upper => format!("{}, {:#x}", x(rd), word >> 12),
+        Format::Jal => {
+            let reached = address.wrapping_add(imm_j as u32);
+            target = Some(reached);
+            format!("{}, {:#x}", x(rd), reached)
+        }
+        Format::Jalr => format!("{}, {}({})", x(rd), imm_i, x(rs1)),
+        Format::System(_) | Format::FixedFence(..) => return Some((mnemonic.to_string(), None)),
+        Format::Fence => match (word >> 20) & 0xff {
+            0xff => return Some((mnemonic.to_string(), None)),
+            sets => format!("{}, {}", fence_set(sets >> 4), fence_set(sets & 0xf)),
+        },
+        Format::Csr(_) => format!("{}, {}, {}", x(rd), csr(), x(rs1)),
+        Format::CsrImmediate(_) => format!("{}, {}, {}", x(rd), csr(), rs1),
+        Format::Fp {
+            rs2: fixed,
+            rd: rd_file,
+            rs1: rs1_file,
+            ..
+        } => match fixed {
+            Some(_) => format!("{}, {}", in_file(rd_file, rd), in_file(rs1_file, rs1)),
+            None => format!(
+                "{}, {}, {}",
+                in_file(rd_file, rd),
+                in_file(rs1_file, rs1),
+                f(rs2)
+            ),
+        },
+        Format::FpFused(_) => {
+            let rs3 = (word >> 27) as usize;
+            format!("{}, {}, {}, {}", f(rd), f(rs1), f(rs2), f(rs3))
+        }
+        Format::FpLoad(_) => format!("{}, {}({})", f(rd), imm_i, x(rs1)),
+        Format::FpStore(_) => format!("{}, {}({})", f(rs2), imm_s, x(rs1)),
+        Format::LoadReserved(_) => format!("{}, ({})", x(rd), x(rs1)),
+        Format::Atomic(_) => format!("{}, {}, ({})", x(rd), x(rs2), x(rs1)),
+    };
+    let ordering = match format {
+        Format::LoadReserved(_) | Format::Atomic(_) => match funct7 & 0b11 {
+            0b00 => "",
+            0b10 => ".aq",
+            0b01 => ".rl",
+            _ => ".aqrl",
+        },
+        _ => "",
+    };
+    let text = format!("{}{} {}", mnemonic, ordering, operands);
+    let text = match isa::default_rounding_mode(format) {
+        Some(default) => with_rounding_mode(text, default, funct3)?,
+        None => text,
+    };
+    Some((text, target))
+}
+
+/// `text` followed by the rounding mode `funct3` holds, unless it is the `default` the
+/// instruction is encoded with when the mode is left out. Reserved modes aren't an
+/// instruction.
+fn with_rounding_mode(text: String, default: u32, funct3: u32) -> Option<String> {
+    let name = rounding_mode_name(funct3 as u8)?;
+    match funct3 == default {
+        true => Some(text),
+        false => Some(format!("{}, {}", text, name)),
+    }
+}
+
+/// `iorw` letters of the device input, device output, memory read and memory write bits a
+/// FENCE orders
+fn fence_set(bits: u32) -> String {
+    let set: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(index, _)| bits & (0b1000 >> index) != 0)
+        .map(|(_, letter)| letter)
+        .collect();
+    match set.is_empty() {
+        true => "0".to_string(),
+        false => set,
+    }
+}
+
+/// Text of the compressed instruction `name`, which stands for the base instruction `word`,
+/// and where it branches to
+fn decode_compressed(name: &str, word: u32, address: u32) -> (String, Option<u32>) {
+    let Fields {
+        rd,
+        rs1,
+        rs2,
+        imm_i,
+        imm_s,
+        imm_b,
+        imm_j,
+        ..
+    } = Fields::new(word);
+    let x = |register: usize| ABI_NAMES[register];
+    let operands = match name {
+        "c.nop" => String::new(),
+        "c.addi" | "c.li" | "c.andi" => format!("{}, {}", x(rd), imm_i),
+        "c.slli" | "c.srli" | "c.srai" => format!("{}, {}", x(rd), (word >> 20) & 0x3f),
+        "c.addi16sp" => format!("sp, {}", imm_i),
+        "c.addi4spn" => format!("{}, sp, {}", x(rd), imm_i),
+        "c.lui" => format!("{}, {:#x}", x(rd), word >> 12),
+        "c.mv" | "c.add" | "c.sub" | "c.xor" | "c.or" | "c.and" => {
+            format!("{}, {}", x(rd), x(rs2))
+        }
+        "c.lw" | "c.lwsp" => format!("{}, {}({})", x(rd), imm_i, x(rs1)),
+        "c.sw" | "c.swsp" => format!("{}, {}({})", x(rs2), imm_s, x(rs1)),
+        "c.jr" | "c.jalr" => x(rs1).to_string(),
+        "c.j" | "c.jal" => {
+            let target = address.wrapping_add(imm_j as u32);
+            return (format!("{} {:#x}", name, target), Some(target));
+        }
+        // c.beqz, c.bnez
+        _ => {
+            let target = address.wrapping_add(imm_b as u32);
+            return (format!("{} {}, {:#x}", name, x(rs1), target), Some(target));
+        }
+    };
+    match operands.is_empty() {
+        true => (name.to_string(), None),
+        false => (format!("{} {}", name, operands), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::test_support::xorshift32;
+    use crate::{
+        AssemblerOptions, assemble_program, assemble_with_options,
+        isa::{
+            OPCODE_AMO, OPCODE_AUIPC, OPCODE_BRANCH, OPCODE_FMADD, OPCODE_FMSUB, OPCODE_FNMADD,
+            OPCODE_FNMSUB, OPCODE_JAL, OPCODE_JALR, OPCODE_LOAD, OPCODE_LOAD_FP, OPCODE_LUI,
+            OPCODE_OP, OPCODE_OP_32, OPCODE_OP_FP, OPCODE_OP_IMM_32, OPCODE_STORE, OPCODE_STORE_FP,
+            OPCODE_SYSTEM,
+        },
+    };
+
+    /// The text of each instruction the source assembles to, as placed at 0
+    fn round_trip(source: &str, options: &AssemblerOptions) -> Vec<String> {
+        let program = assemble_with_options(source, options).unwrap();
+        Disassembler::new(options.xlen)
+            .instructions(&program, 0)
+            .into_iter()
+            .map(|(_, disassembled)| disassembled.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let source = "\
+            lui a0, 0x12345
+            auipc t0, 0x1
+            addi a0, zero, -1
+            slti a1, a2, 5
+            srai s0, s1, 31
+            add a0, a1, a2
+            sub t0, t1, t2
+            mulhsu a0, a1, a2
+            remu a0, a1, a2
+            lb a0, -8(sp)
+            lhu a1, 2(a0)
+            sw ra, 12(sp)
+            jalr ra, 0(a0)
+            ecall
+            ebreak
+            csrrw zero, mstatus, a0
+            csrrsi a0, mie, 8
+            flw fa0, 4(a0)
+            fsd fs0, -16(sp)
+            fadd.s fa0, fa1, fa2
+            fadd.d fa0, fa1, fa2, rtz
+            fmadd.s ft0, ft1, ft2, ft3
+            fsqrt.d fs1, fs2
+            fsgnjx.s fa0, fa1, fa1
+            feq.d a0, fa0, fa1
+            fcvt.w.s a0, fa0, rtz
+            fcvt.d.w fa0, a0
+            fcvt.d.wu fa0, a0, rup
+            fcvt.s.d fa0, fa1
+            fmv.x.w a0, fa0
+            fclass.d a0, fa0
+            lr.w.aq a0, (a1)
+            sc.w.rl a2, a3, (a1)
+            amoadd.w.aqrl a0, a1, (a2)
+            amomaxu.w a0, a1, (a2)
+        ";
+        let expected: Vec<String> = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+        assert_eq!(round_trip(source, &AssemblerOptions::default()), expected);
+
+        let source = "\
+            ld a0, 8(sp)
+            sd a0, 8(sp)
+            lwu a0, 0(a1)
+            slli a0, a0, 40
+            srai a0, a0, 63
+            addiw a0, a0, -1
+            sraiw a0, a0, 31
+            subw a0, a1, a2
+            remuw a0, a1, a2
+        ";
+        let expected: Vec<String> = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+        assert_eq!(round_trip(source, &AssemblerOptions::rv64i()), expected);
+        // RV64 instructions aren't RV32 ones
+        assert_eq!(decode(0x00813503, 0, Xlen::Rv32).text, ".word 0x00813503");
+
+        assert_eq!(decode(0x0ff0000f, 0, Xlen::Rv32).text, "fence");
+        assert_eq!(decode(0x0230000f, 0, Xlen::Rv32).text, "fence r, rw");
+        assert_eq!(decode(0x0000100f, 0, Xlen::Rv32).text, "fence.i");
+        assert_eq!(decode(0x30200073, 0, Xlen::Rv32).text, "mret");
+        assert_eq!(decode(0x10500073, 0, Xlen::Rv32).text, "wfi");
+        // CSRs without a name are shown by number
+        assert_eq!(
+            decode(0x7c002573, 0, Xlen::Rv32).text,
+            "csrrs a0, 0x7c0, zero"
+        );
+        assert_eq!(decode(0xffffffff, 0, Xlen::Rv32).text, ".word 0xffffffff");
+        assert_eq!(decode(0x0000, 0, Xlen::Rv32).text, ".half 0x0000");
+    }
+
+    #[test]
+    fn test_compressed() {
+        let source = "\
+            c.nop
+            c.addi a0, -3
+            c.li a1, 5
+            c.addi16sp sp, -64
+            c.addi4spn a0, sp, 8
+            c.lui a0, 0xfffff
+            c.mv a0, a1
+            c.add a0, a1
+            c.and s0, s1
+            c.andi a0, -1
+            c.slli a0, 2
+            c.srai a0, 3
+            c.lw a0, 4(a1)
+            c.sw a0, 4(a1)
+            c.lwsp ra, 12(sp)
+            c.swsp ra, 12(sp)
+            c.jr ra
+            c.jalr a0
+        ";
+        let expected: Vec<String> = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+        assert_eq!(round_trip(source, &AssemblerOptions::default()), expected);
+        assert_eq!(
+            round_trip(
+                "c.j 2\nc.beqz a0, -2\nc.jal 0",
+                &AssemblerOptions::default()
+            ),
+            ["c.j 0x2", "c.beqz a0, 0x0", "c.jal 0x4"]
+        );
+    }
+
+    #[test]
+    fn test_symbolized_targets() {
+        let program = assemble_program(
+            "main:\n    jal ra, f\nloop:\n    beq a0, zero, loop\n    j 1f\n1:\nf:\n    ret\n.equ f_too, 12",
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        let disassembler = Disassembler::new(Xlen::Rv32).with_symbol_table(&program.symbols);
+        let instruction = disassembler.instruction(0x00c000ef, 0);
+        assert_eq!(instruction.text, "jal ra, 0xc <f>");
+        assert_eq!(instruction.target, Some(12));
+        assert_eq!(
+            disassembler.dump(&program.bytes, 0),
+            "\
+00000000 <main>:
+       0:  00c000ef  jal ra, 0xc <f>
+
+00000004 <loop>:
+       4:  00050063  beq a0, zero, 0x4 <loop>
+       8:  0040006f  jal zero, 0xc <f>
+
+0000000c <f>:
+       c:  00008067  jalr zero, 0(ra)
+"
+        );
+        // Bytes too few for an instruction
+        let instructions = Disassembler::new(Xlen::Rv32).instructions(&[0x13, 0x05, 0x50], 0x100);
+        assert_eq!(
+            instructions[0],
+            (
+                0x100,
+                Disassembled {
+                    text: ".half 0x0513".to_string(),
+                    size: 2,
+                    target: None,
+                }
+            )
+        );
+        assert_eq!(instructions[1].1.text, ".byte 0x50");
+    }
+
+    #[test]
+    fn test_random_round_trips() {
+        // The major opcodes and the funct7 values that tell operations apart within them,
+        // most words made of them are instructions, and most operations come up
+        const OPCODES: [u32; 20] = [
+            OPCODE_LUI,
+            OPCODE_AUIPC,
+            OPCODE_JAL,
+            OPCODE_JALR,
+            OPCODE_BRANCH,
+            OPCODE_LOAD,
+            OPCODE_STORE,
+            OPCODE_OP_IMM,
+            OPCODE_OP,
+            OPCODE_OP_IMM_32,
+            OPCODE_OP_32,
+            OPCODE_SYSTEM,
+            OPCODE_LOAD_FP,
+            OPCODE_STORE_FP,
+            OPCODE_FMADD,
+            OPCODE_FMSUB,
+            OPCODE_FNMSUB,
+            OPCODE_FNMADD,
+            OPCODE_OP_FP,
+            OPCODE_AMO,
+        ];
+        let fp_funct5s = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x08, 0x0b, 0x14, 0x18, 0x1a, 0x1c, 0x1e,
+        ];
+        let atomic_funct5s = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x08, 0x0c, 0x10, 0x14, 0x18, 0x1c,
+        ];
+        // With the fmt and aq/rl bits below the funct5s
+        let funct7s: Vec<u32> = [0x00, 0x01, 0x20]
+            .into_iter()
+            .chain(
+                fp_funct5s
+                    .iter()
+                    .flat_map(|funct5| [funct5 << 2, funct5 << 2 | 1]),
+            )
+            .chain(
+                atomic_funct5s
+                    .iter()
+                    .flat_map(|funct5| (0..4).map(move |ordering| funct5 << 2 | ordering)),
+            )
+            .collect();
+        let mut next = xorshift32(0x2545_f491);
+        for (xlen, options, operations) in [
+            (Xlen::Rv32, AssemblerOptions::default(), 130),
+            (Xlen::Rv64, AssemblerOptions::rv64i(), 125),
+        ] {
+            let mut mnemonics = BTreeSet::new();
+            let mut source = String::new();
+            let mut expected: Vec<(String, Vec<u8>)> = Vec::new();
+            for _ in 0..40_000 {
+                let mut parcel = next() & !0x7f | OPCODES[next() as usize % OPCODES.len()];
+                if next().is_multiple_of(2) {
+                    let funct7 = funct7s[next() as usize % funct7s.len()];
+                    parcel = parcel & 0x01ff_ffff | funct7 << 25;
+                }
+                // rs2 selects among the conversions
+                if next().is_multiple_of(2) {
+                    parcel &= !(0xf << 21);
+                }
+                // The assembler only writes compressed instructions for RV32
+                if xlen == Xlen::Rv32 && next().is_multiple_of(4) {
+                    parcel = next() & 0xffff;
+                }
+                let disassembled = decode(parcel, 0, xlen);
+                if disassembled.text.starts_with('.') {
+                    continue;
+                }
+                // Decoded at 0 a target is the offset, which the assembler takes signed
+                let text = match disassembled.target {
+                    Some(target) => {
+                        let (operands, _) = disassembled.text.rsplit_once(' ').unwrap();
+                        format!("{} {}", operands, target as i32)
+                    }
+                    None => disassembled.text.clone(),
+                };
+                let mnemonic = text.split(' ').next().unwrap();
+                let operation = [".aqrl", ".aq", ".rl"]
+                    .iter()
+                    .find_map(|ordering| mnemonic.strip_suffix(ordering))
+                    .unwrap_or(mnemonic);
+                mnemonics.insert(operation.to_string());
+                let bytes = parcel.to_le_bytes()[..disassembled.size as usize].to_vec();
+                source.push_str(&text);
+                source.push('\n');
+                expected.push((text, bytes));
+            }
+            // One program of them all, each instruction's bytes follow the ones before
+            let program = assemble_with_options(source.as_str(), &options)
+                .unwrap_or_else(|error| panic!("{:#}", error));
+            let mut offset = 0;
+            for (text, bytes) in &expected {
+                assert_eq!(&program[offset..offset + bytes.len()], bytes, "{}", text);
+                offset += bytes.len();
+            }
+            assert!(
+                mnemonics.len() >= operations,
+                "{:?} came up, {:?}",
+                xlen,
+                mnemonics
+            );
+        }
+    }
+}
@@ -0,0 +1,942 @@
+//! Relocatable ELF object files, for linking assembled code with GNU `ld` or `lld`.
+//!
+//! Unlike the flat image, every section starts at offset 0 and is left for the linker to
+//! place, the section bases of the options don't apply. `.org` moves the current section's
+//! location counter.
+//! Branches, jumps and `%pcrel_hi`/`%pcrel_lo` pairs within a section are resolved right
+//! away, references to other sections, to `.equ` symbols and to symbols the source doesn't
+//! define get RISC-V relocations with the fields left zero, as do the `%hi` and `%lo`
+//! parts of label addresses. `.globl` symbols and undefined ones are global, every other label
+//! local.
+//!
+//! `.bss` only reserves space, anything but zero words in it is an error, and literal pools
+//! that would end up there go to `.text` instead. Instructions of plugins are encoded with
+//! section offsets as label addresses, so they can only refer to labels in their own
+//! section.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    assembler::{AssemblerOptions, MemoryMap, allocate_memory, bss_error, word_value},
+    encoder::{Xlen, encode},
+    error::{AssemblerError, SourceLocation},
+    expression::Expression,
+    isa::is_known,
+    parser::{Instruction, Operand, ParsedItem},
+    section::Section,
+    symbol_table::SymbolTable,
+};
+
+const ET_REL: u16 = 1;
+const EM_RISCV: u16 = 243;
+/// Set in e_flags if the object uses compressed instructions
+const EF_RISCV_RVC: u32 = 0x1;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+const R_RISCV_32: u32 = 1;
+const R_RISCV_BRANCH: u32 = 16;
+const R_RISCV_JAL: u32 = 17;
+const R_RISCV_PCREL_HI20: u32 = 23;
+const R_RISCV_PCREL_LO12_I: u32 = 24;
+const R_RISCV_PCREL_LO12_S: u32 = 25;
+const R_RISCV_HI20: u32 = 26;
+const R_RISCV_LO12_I: u32 = 27;
+const R_RISCV_LO12_S: u32 = 28;
+const R_RISCV_RVC_BRANCH: u32 = 44;
+const R_RISCV_RVC_JUMP: u32 = 45;
+
+/// The sections of an object file, in section header order
+const SECTIONS: [Section; 4] = [Section::Text, Section::Data, Section::Bss, Section::Rodata];
+
+/// Section header index of `section`, after the null section
+fn section_index(section: Section) -> u16 {
+    section as u16 + 1
+}
+
+/// What a relocation refers to
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    /// A section's symbol, for local labels, with their offset as the addend
+    Section(Section),
+    Symbol(String),
+}
+
+#[derive(Debug)]
+struct Relocation {
+    offset: u32,
+    kind: u32,
+    target: Target,
+    addend: i64,
+}
+
+/// What an expression of labels comes to in an object file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExpressionValue {
+    Constant(i64),
+    /// The address of a symbol plus an addend
+    Relocated {
+        name: String,
+        addend: i64,
+    },
+}
+
+/// The contents of one section, with the relocations applying to it
+#[derive(Debug, Default)]
+struct SectionData {
+    bytes: Vec<u8>,
+    /// Size of `.bss`, which has no bytes
+    size: u32,
+    relocations: Vec<Relocation>,
+    alignment: u64,
+}
+
+/// Lays out the parsed program in sections and writes it as an ELF object, 64 bit for RV64
+pub(crate) fn write_object(
+    parsed_items: &[ParsedItem],
+    symbol_table: &mut SymbolTable,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut items: BTreeMap<Section, Vec<ParsedItem>> = BTreeMap::new();
+    let mut label_sections: BTreeMap<String, Section> = BTreeMap::new();
+    let mut globals: BTreeSet<String> = BTreeSet::new();
+    let mut current = Section::Text;
+    // Items left of a literal pool, and the section they go to
+    let mut pool: Option<(usize, Section)> = None;
+    for item in parsed_items {
+        if let Some((left, section)) = &mut pool {
+            items.entry(*section).or_default().push(item.clone());
+            if let ParsedItem::Label { name, .. } = item {
+                label_sections.insert(name.clone(), *section);
+            }
+            *left -= 1;
+            if *left == 0 {
+                pool = None;
+            }
+            continue;
+        }
+        match item {
+            ParsedItem::Directive { name, args, .. } if name == ".ltorg" => {
+                let section = match current {
+                    Section::Bss => Section::Text,
+                    section => section,
+                };
+                if let [Operand::Immediate(count @ 1..)] = args[..] {
+                    // A label and a word for each literal
+                    pool = Some((2 * count as usize, section));
+                }
+                items.entry(section).or_default().push(item.clone());
+                continue;
+            }
+            ParsedItem::Directive { name, .. } if Section::from_directive(name).is_some() => {
+                current = Section::from_directive(name).unwrap();
+                continue;
+            }
+            ParsedItem::Directive { name, args, .. } if name == ".globl" || name == ".global" => {
+                globals.extend(args.iter().filter_map(Operand::symbol).map(str::to_string));
+                continue;
+            }
+            ParsedItem::Label { name, .. } => {
+                label_sections.insert(name.clone(), current);
+            }
+            _ => {}
+        }
+        items.entry(current).or_default().push(item.clone());
+    }
+
+    // Labels get their offsets in their own section
+    let mut memory_maps = BTreeMap::new();
+    for (&section, items) in &items {
+        let mut memory_map = MemoryMap::new();
+        allocate_memory(&mut memory_map, symbol_table, items, &options.plugins)?;
+        memory_maps.insert(section, memory_map);
+    }
+
+    let mut writer = ObjectWriter {
+        symbol_table,
+        label_sections: &label_sections,
+        globals: &globals,
+        pcrel_labels: Vec::new(),
+        compressed: false,
+    };
+    let mut sections = BTreeMap::new();
+    for section in SECTIONS {
+        let data = match (items.get(&section), memory_maps.get(&section)) {
+            (Some(items), Some(memory_map)) => {
+                writer.section(section, items, memory_map, options)?
+            }
+            _ => SectionData {
+                alignment: 4,
+                ..SectionData::default()
+            },
+        };
+        sections.insert(section, data);
+    }
+    let symbols = writer.symbols();
+    Ok(Layout {
+        is_64: options.xlen == Xlen::Rv64,
+        flags: if writer.compressed { EF_RISCV_RVC } else { 0 },
+        sections,
+        symbols,
+    }
+    .write())
+}
+
+/// Encodes the sections, collecting relocations and the labels they need
+struct ObjectWriter<'a> {
+    symbol_table: &'a SymbolTable,
+    label_sections: &'a BTreeMap<String, Section>,
+    /// Symbols named by `.globl`
+    globals: &'a BTreeSet<String>,
+    /// Labels at AUIPCs whose `%pcrel_lo` needs a relocation, which has to refer to them
+    pcrel_labels: Vec<(String, Section, u32)>,
+    compressed: bool,
+}
+
+impl ObjectWriter<'_> {
+    fn section(
+        &mut self,
+        section: Section,
+        items: &[ParsedItem],
+        memory_map: &MemoryMap,
+        options: &AssemblerOptions,
+    ) -> anyhow::Result<SectionData> {
+        let mut data = SectionData {
+            alignment: 4,
+            ..SectionData::default()
+        };
+        for (index, item) in items.iter().enumerate() {
+            let address = memory_map.address_of(index);
+            match item {
+                ParsedItem::Instruction(instruction) => {
+                    if section == Section::Bss {
+                        return Err(bss_error(&instruction.location));
+                    }
+                    let word =
+                        self.instruction(section, instruction, address, &mut data, options)?;
+                    self.compressed |= instruction.compressed.is_some();
+                    data.bytes.resize(address as usize, 0);
+                    data.bytes
+                        .extend_from_slice(&word.to_le_bytes()[..instruction.size() as usize]);
+                }
+                ParsedItem::Directive {
+                    name,
+                    args,
+                    location,
+                } if name == ".word" => {
+                    data.bytes.resize(address as usize, 0);
+                    for arg in args {
+                        let offset = data.bytes.len() as u32;
+                        let value = match arg {
+                            Operand::Symbol(_) if section == Section::Bss => {
+                                return Err(bss_error(location));
+                            }
+                            Operand::Symbol(name) if self.is_relocatable(name) => {
+                                let (target, addend) = self.target(name);
+                                data.relocations.push(Relocation {
+                                    offset,
+                                    kind: R_RISCV_32,
+                                    target,
+                                    addend,
+                                });
+                                0
+                            }
+                            Operand::Expression(expression) => match self
+                                .expression_value(expression)
+                                .map_err(|message| parser_error(message, location))?
+                            {
+                                ExpressionValue::Constant(value) => {
+                                    word_value(&Operand::Immediate(value), self.symbol_table)
+                                        .map_err(|message| parser_error(message, location))?
+                                }
+                                ExpressionValue::Relocated { name, addend } => {
+                                    if section == Section::Bss {
+                                        return Err(bss_error(location));
+                                    }
+                                    let (target, offset_addend) = self.target(&name);
+                                    data.relocations.push(Relocation {
+                                        offset,
+                                        kind: R_RISCV_32,
+                                        target,
+                                        addend: offset_addend + addend,
+                                    });
+                                    0
+                                }
+                            },
+                            arg => word_value(arg, self.symbol_table)
+                                .map_err(|message| parser_error(message, location))?,
+                        };
+                        if section == Section::Bss && value != 0 {
+                            return Err(bss_error(location));
+                        }
+                        data.bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Data directives' and plugins' bytes, which the flat image writes in the same pass
+        for (index, bytes) in
+            (0..items.len()).filter_map(|index| Some((index, memory_map.fixed_data(index)?)))
+        {
+            let start = memory_map.address_of(index) as usize;
+            data.bytes.resize(start, 0);
+            data.bytes.extend_from_slice(bytes);
+            if section == Section::Bss
+                && bytes.iter().any(|&byte| byte != 0)
+                && let ParsedItem::Directive { location, .. } = &items[index]
+            {
+                return Err(bss_error(location));
+            }
+        }
+        data.size = memory_map.size();
+        // `.align` and `.vector_table` need the section to keep their alignment once linked
+        data.alignment = memory_map.alignment(section).into();
+        if section == Section::Bss {
+            data.bytes.clear();
+        } else {
+            data.bytes.resize(data.size as usize, 0);
+        }
+        Ok(data)
+    }
+
+    /// Encodes `instruction`, with a relocation in place of a label outside its section
+    fn instruction(
+        &mut self,
+        section: Section,
+        instruction: &Instruction,
+        address: u32,
+        data: &mut SectionData,
+        options: &AssemblerOptions,
+    ) -> anyhow::Result<u32> {
+        let plugin = options
+            .plugins
+            .instruction(&instruction.mnemonic)
+            .filter(|_| !is_known(&instruction.mnemonic));
+        if let Some(plugin) = plugin {
+            return plugin
+                .encode(instruction, address, self.symbol_table)
+                .map_err(|message| {
+                    AssemblerError::EncoderError {
+                        message,
+                        location: instruction.location.clone(),
+                    }
+                    .into()
+                });
+        }
+
+        // Expressions are encoded with section offsets for addresses, which only works out if
+        // the labels cancel out, or for a branch within the section
+        let is_jump = matches!(
+            instruction.mnemonic.as_str(),
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "jal"
+        );
+        for operand in &instruction.operands {
+            let Operand::Expression(expression) = operand else {
+                continue;
+            };
+            let value = self
+                .expression_value(expression)
+                .map_err(|message| parser_error(message, &instruction.location))?;
+            let resolved = match value {
+                ExpressionValue::Constant(_) => !is_jump,
+                ExpressionValue::Relocated { name, .. } => {
+                    is_jump && self.label_sections.get(&name) == Some(&section)
+                }
+            };
+            if !resolved {
+                return Err(parser_error(
+                    format!(
+                        "'{}' depends on where the linker places labels, which an object \
+                         file can't express here",
+                        expression
+                    ),
+                    &instruction.location,
+                ));
+            }
+        }
+
+        let reference = instruction
+            .operands
+            .iter()
+            .enumerate()
+            .find_map(|(index, operand)| Some((index, operand, operand.symbol()?)));
+        // Absolute addresses are only known once the linker placed the sections
+        let Some((operand_index, operand, name)) =
+            reference.filter(|(_, operand, name)| match operand {
+                Operand::Hi(_) | Operand::Lo { .. } => self.is_relocatable(name),
+                _ => self.label_sections.get(*name) != Some(&section),
+            })
+        else {
+            return encode(instruction, address, self.symbol_table, options.xlen);
+        };
+
+        let is_store = matches!(
+            instruction.mnemonic.as_str(),
+            "sb" | "sh" | "sw" | "sd" | "fsw" | "fsd"
+        );
+        // Low parts become a zero offset or immediate
+        let zeroed_low = |base: &Option<u8>| match *base {
+            Some(base) => Operand::Memory { offset: 0, base },
+            None => Operand::Immediate(0),
+        };
+        let (kind, zeroed) = match operand {
+            Operand::Hi(_) => (R_RISCV_HI20, Operand::Immediate(0)),
+            Operand::Lo { base, .. } => (
+                if is_store {
+                    R_RISCV_LO12_S
+                } else {
+                    R_RISCV_LO12_I
+                },
+                zeroed_low(base),
+            ),
+            Operand::PcrelHi(_) => (R_RISCV_PCREL_HI20, Operand::Immediate(0)),
+            Operand::PcrelLo { base, .. } => (
+                if is_store {
+                    R_RISCV_PCREL_LO12_S
+                } else {
+                    R_RISCV_PCREL_LO12_I
+                },
+                zeroed_low(base),
+            ),
+            _ => {
+                let kind = match instruction.compressed.as_deref() {
+                    Some("c.j" | "c.jal") => R_RISCV_RVC_JUMP,
+                    Some(_) => R_RISCV_RVC_BRANCH,
+                    None if instruction.mnemonic == "jal" => R_RISCV_JAL,
+                    None => R_RISCV_BRANCH,
+                };
+                (kind, Operand::Immediate(0))
+            }
+        };
+        let (target, addend) = if matches!(kind, R_RISCV_PCREL_LO12_I | R_RISCV_PCREL_LO12_S) {
+            // The low part refers to the AUIPC, where the linker finds the high part
+            let label = format!(".Lpcrel_hi{}", self.pcrel_labels.len());
+            self.pcrel_labels
+                .push((label.clone(), section, address.wrapping_sub(4)));
+            (Target::Symbol(label), 0)
+        } else {
+            self.target(name)
+        };
+        data.relocations.push(Relocation {
+            offset: address,
+            kind,
+            target,
+            addend,
+        });
+        let mut instruction = instruction.clone();
+        instruction.operands[operand_index] = zeroed;
+        encode(&instruction, address, self.symbol_table, options.xlen)
+    }
+
+    /// Whether a `.word` holding the address of `name`, or a `%hi` or `%lo` of it, needs a
+    /// relocation, anything but a `.equ` constant does
+    fn is_relocatable(&self, name: &str) -> bool {
+        !self
+            .symbol_table
+            .get(name)
+            .is_some_and(|symbol| symbol.absolute)
+    }
+
+    /// The value of `expression`, addresses being section offsets: a constant if its labels
+    /// cancel out, like `end - start` of one section, or a symbol plus a constant. Anything
+    /// else would take several relocations.
+    fn expression_value(&self, expression: &Expression) -> Result<ExpressionValue, String> {
+        const SHIFT: i64 = 0x1000;
+        let relocatable: BTreeSet<&str> = expression
+            .symbols()
+            .into_iter()
+            .filter(|name| self.is_relocatable(name))
+            .collect();
+        let address = |name: &str| self.symbol_table.address(name).map(i64::from);
+        // Moving all the labels shows how the value depends on where they are
+        let value_at = |shift: i64| {
+            expression.evaluate(&|name| match relocatable.contains(name) {
+                true => Some(address(name).unwrap_or(0) + shift),
+                false => address(name),
+            })
+        };
+        let value = value_at(0)?;
+        let sections: BTreeSet<Option<&Section>> = relocatable
+            .iter()
+            .map(|name| self.label_sections.get(*name))
+            .collect();
+        let moves_together =
+            relocatable.len() <= 1 || sections.len() == 1 && !sections.contains(&None);
+        match (value_at(SHIFT)? - value, relocatable.first()) {
+            (0, _) if moves_together => Ok(ExpressionValue::Constant(value)),
+            (SHIFT, Some(name)) if relocatable.len() == 1 => Ok(ExpressionValue::Relocated {
+                name: name.to_string(),
+                addend: value - address(name).unwrap_or(0),
+            }),
+            _ => Err(format!(
+                "'{}' can't be expressed as a symbol plus a constant in an object file",
+                expression
+            )),
+        }
+    }
+
+    /// What a relocation against `name` refers to: local labels through their section
+    fn target(&self, name: &str) -> (Target, i64) {
+        match self.label_sections.get(name) {
+            Some(&section) if !self.is_global(name) => (
+                Target::Section(section),
+                self.symbol_table.address(name).unwrap_or(0) as i64,
+            ),
+            _ => (Target::Symbol(name.to_string()), 0),
+        }
+    }
+
+    /// Labels become global through `.globl`, symbols the source doesn't define are
+    /// always global
+    fn is_global(&self, name: &str) -> bool {
+        self.globals.contains(name)
+            || self
+                .symbol_table
+                .get(name)
+                .is_none_or(|symbol| symbol.definition.is_none())
+    }
+
+    /// The symbol table: section symbols, local labels and constants, then the globals
+    fn symbols(&self) -> Vec<ElfSymbol> {
+        let mut locals: Vec<ElfSymbol> = SECTIONS
+            .iter()
+            .map(|&section| ElfSymbol {
+                key: Target::Section(section),
+                value: 0,
+                binding: STB_LOCAL,
+                kind: STT_SECTION,
+                section: section_index(section),
+            })
+            .collect();
+        let mut global_symbols = Vec::new();
+        for (name, symbol) in self.symbol_table.iter() {
+            let global = self.is_global(name);
+            let section = match self.label_sections.get(name) {
+                Some(section) => section_index(*section),
+                None if symbol.absolute => SHN_ABS,
+                None => SHN_UNDEF,
+            };
+            let elf_symbol = ElfSymbol {
+                key: Target::Symbol(name.to_string()),
+                value: if section == SHN_UNDEF {
+                    0
+                } else {
+                    symbol.address.unwrap_or(0)
+                },
+                binding: if global { STB_GLOBAL } else { STB_LOCAL },
+                kind: STT_NOTYPE,
+                section,
+            };
+            if global {
+                global_symbols.push(elf_symbol);
+            } else {
+                locals.push(elf_symbol);
+            }
+        }
+        locals.extend(
+            self.pcrel_labels
+                .iter()
+                .map(|(name, section, address)| ElfSymbol {
+                    key: Target::Symbol(name.clone()),
+                    value: *address,
+                    binding: STB_LOCAL,
+                    kind: STT_NOTYPE,
+                    section: section_index(*section),
+                }),
+        );
+        locals.extend(global_symbols);
+        locals
+    }
+}
+
+fn parser_error(message: impl Into<String>, location: &SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.into(),
+        location: location.clone(),
+    }
+    .into()
+}
+
+struct ElfSymbol {
+    /// The symbol's name, or the section of a section symbol
+    key: Target,
+    value: u32,
+    binding: u8,
+    kind: u8,
+    section: u16,
+}
+
+/// Everything that goes into the file, written in the order the section headers list it
+struct Layout {
+    is_64: bool,
+    flags: u32,
+    sections: BTreeMap<Section, SectionData>,
+    /// Locals first, as ELF requires
+    symbols: Vec<ElfSymbol>,
+}
+
+/// What of a section is in the file
+enum Contents {
+    Bytes(Vec<u8>),
+    /// Zero-filled space of a NOBITS section, only its size is in the file
+    Reserved(u64),
+}
+
+/// A section header, with the section's contents
+struct SectionHeader {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    contents: Contents,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: u64,
+}
+
+impl Layout {
+    fn write(self) -> Vec<u8> {
+        let word = if self.is_64 { 8 } else { 4 };
+        let symbol_size = if self.is_64 { 24 } else { 16 };
+        let rela_size = if self.is_64 { 24 } else { 12 };
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; symbol_size];
+        let mut symbol_indices = BTreeMap::new();
+        for (index, symbol) in self.symbols.iter().enumerate() {
+            let name = match &symbol.key {
+                Target::Section(_) => 0,
+                Target::Symbol(name) => {
+                    let offset = strtab.len() as u32;
+                    strtab.extend_from_slice(name.as_bytes());
+                    strtab.push(0);
+                    offset
+                }
+            };
+            let info = symbol.binding << 4 | symbol.kind;
+            symtab.extend_from_slice(&name.to_le_bytes());
+            if self.is_64 {
+                symtab.extend_from_slice(&[info, 0]);
+                symtab.extend_from_slice(&symbol.section.to_le_bytes());
+                symtab.extend_from_slice(&(symbol.value as u64).to_le_bytes());
+                symtab.extend_from_slice(&0u64.to_le_bytes());
+            } else {
+                symtab.extend_from_slice(&symbol.value.to_le_bytes());
+                symtab.extend_from_slice(&0u32.to_le_bytes());
+                symtab.extend_from_slice(&[info, 0]);
+                symtab.extend_from_slice(&symbol.section.to_le_bytes());
+            }
+            symbol_indices.insert(&symbol.key, index as u64 + 1);
+        }
+        let first_global = self
+            .symbols
+            .iter()
+            .position(|symbol| symbol.binding != STB_LOCAL)
+            .unwrap_or(self.symbols.len())
+            + 1;
+
+        let mut headers: Vec<SectionHeader> = Vec::new();
+        for (&section, data) in &self.sections {
+            let (kind, flags) = match section {
+                Section::Text => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+                Section::Data => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+                Section::Bss => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+                Section::Rodata => (SHT_PROGBITS, SHF_ALLOC),
+            };
+            headers.push(SectionHeader {
+                name: section.name(),
+                kind,
+                flags,
+                contents: if kind == SHT_NOBITS {
+                    Contents::Reserved(data.size as u64)
+                } else {
+                    Contents::Bytes(data.bytes.clone())
+                },
+                link: 0,
+                info: 0,
+                alignment: data.alignment,
+                entry_size: 0,
+            });
+        }
+        // .symtab comes after the sections and their relocations
+        let relocated: Vec<(Section, &SectionData)> = self
+            .sections
+            .iter()
+            .filter(|(_, data)| !data.relocations.is_empty())
+            .map(|(&section, data)| (section, data))
+            .collect();
+        let symtab_index = (SECTIONS.len() + relocated.len() + 1) as u32;
+        for (section, data) in relocated {
+            let mut rela = Vec::with_capacity(data.relocations.len() * rela_size);
+            for relocation in &data.relocations {
+                let symbol = symbol_indices[&relocation.target];
+                if self.is_64 {
+                    rela.extend_from_slice(&(relocation.offset as u64).to_le_bytes());
+                    rela.extend_from_slice(&(symbol << 32 | relocation.kind as u64).to_le_bytes());
+                    rela.extend_from_slice(&relocation.addend.to_le_bytes());
+                } else {
+                    rela.extend_from_slice(&relocation.offset.to_le_bytes());
+                    rela.extend_from_slice(&((symbol as u32) << 8 | relocation.kind).to_le_bytes());
+                    rela.extend_from_slice(&(relocation.addend as i32).to_le_bytes());
+                }
+            }
+            headers.push(SectionHeader {
+                name: match section {
+                    Section::Text => ".rela.text",
+                    Section::Data => ".rela.data",
+                    Section::Bss => ".rela.bss",
+                    Section::Rodata => ".rela.rodata",
+                },
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                contents: Contents::Bytes(rela),
+                link: symtab_index,
+                info: section_index(section) as u32,
+                alignment: word,
+                entry_size: rela_size as u64,
+            });
+        }
+        headers.push(SectionHeader {
+            name: ".symtab",
+            kind: SHT_SYMTAB,
+            flags: 0,
+            contents: Contents::Bytes(symtab),
+            link: symtab_index + 1,
+            info: first_global as u32,
+            alignment: word,
+            entry_size: symbol_size as u64,
+        });
+        headers.push(SectionHeader {
+            name: ".strtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            contents: Contents::Bytes(strtab),
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        });
+        let mut shstrtab = vec![0];
+        let mut header_names = Vec::new();
+        for name in headers
+            .iter()
+            .map(|header| header.name)
+            .chain([".shstrtab"])
+        {
+            header_names.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        headers.push(SectionHeader {
+            name: ".shstrtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            contents: Contents::Bytes(shstrtab),
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        });
+
+        let header_size = if self.is_64 { 64 } else { 52 };
+        let section_header_size = if self.is_64 { 64 } else { 40 };
+        let mut output = vec![0; header_size];
+        let mut offsets = Vec::with_capacity(headers.len());
+        for header in &headers {
+            match &header.contents {
+                Contents::Bytes(bytes) => {
+                    output.resize(output.len().next_multiple_of(header.alignment as usize), 0);
+                    offsets.push(output.len() as u64);
+                    output.extend_from_slice(bytes);
+                }
+                Contents::Reserved(_) => offsets.push(output.len() as u64),
+            }
+        }
+        output.resize(output.len().next_multiple_of(word as usize), 0);
+        let section_headers = output.len() as u64;
+
+        // The null section, then the headers of the rest
+        output.resize(output.len() + section_header_size, 0);
+        for ((header, offset), name) in headers.iter().zip(offsets).zip(header_names) {
+            let size = match &header.contents {
+                Contents::Bytes(bytes) => bytes.len() as u64,
+                Contents::Reserved(size) => *size,
+            };
+            let put_word = |output: &mut Vec<u8>, value: u64| {
+                if self.is_64 {
+                    output.extend_from_slice(&value.to_le_bytes());
+                } else {
+                    output.extend_from_slice(&(value as u32).to_le_bytes());
+                }
+            };
+            output.extend_from_slice(&name.to_le_bytes());
+            output.extend_from_slice(&header.kind.to_le_bytes());
+            put_word(&mut output, header.flags);
+            put_word(&mut output, 0); // sh_addr
+            put_word(&mut output, offset);
+            put_word(&mut output, size);
+            output.extend_from_slice(&header.link.to_le_bytes());
+            output.extend_from_slice(&header.info.to_le_bytes());
+            put_word(&mut output, header.alignment);
+            put_word(&mut output, header.entry_size);
+        }
+
+        // e_ident: ELF, class, little-endian, version 1, System V ABI
+        let class = if self.is_64 { 2 } else { 1 };
+        let mut elf_header = vec![
+            0x7f, b'E', b'L', b'F', class, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        elf_header.extend_from_slice(&ET_REL.to_le_bytes());
+        elf_header.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf_header.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        if self.is_64 {
+            elf_header.extend_from_slice(&[0; 16]); // e_entry, e_phoff
+            elf_header.extend_from_slice(&section_headers.to_le_bytes());
+        } else {
+            elf_header.extend_from_slice(&[0; 8]);
+            elf_header.extend_from_slice(&(section_headers as u32).to_le_bytes());
+        }
+        elf_header.extend_from_slice(&self.flags.to_le_bytes());
+        elf_header.extend_from_slice(&(header_size as u16).to_le_bytes());
+        elf_header.extend_from_slice(&[0; 4]); // e_phentsize, e_phnum
+        elf_header.extend_from_slice(&(section_header_size as u16).to_le_bytes());
+        elf_header.extend_from_slice(&(headers.len() as u16 + 1).to_le_bytes());
+        elf_header.extend_from_slice(&(headers.len() as u16).to_le_bytes()); // e_shstrndx
+        output[..header_size].copy_from_slice(&elf_header);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_object;
+
+    /// Reads little-endian fields of a 32 bit object
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    /// Name, type and contents of every section
+    fn sections(object: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let shoff = u32_at(object, 32) as usize;
+        let count = u16_at(object, 48) as usize;
+        let shstrtab = shoff + 40 * u16_at(object, 50) as usize;
+        let names = &object[u32_at(object, shstrtab + 16) as usize..];
+        (1..count)
+            .map(|index| {
+                let header = shoff + 40 * index;
+                let name = &names[u32_at(object, header) as usize..];
+                let name = name.split(|&byte| byte == 0).next().unwrap();
+                let kind = u32_at(object, header + 4);
+                let offset = u32_at(object, header + 16) as usize;
+                let size = u32_at(object, header + 20) as usize;
+                let contents = if kind == SHT_NOBITS {
+                    Vec::new()
+                } else {
+                    object[offset..offset + size].to_vec()
+                };
+                (String::from_utf8(name.to_vec()).unwrap(), kind, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_object_sections_and_relocations() {
+        let source = "
+            .text
+            .globl main
+        main:
+            beq a0, zero, done
+            jal ra, helper
+            lw a1, =counter
+        done:
+            ecall
+            .data
+        counter:
+            .word 7, main
+            .bss
+            .word 0
+        ";
+        let object = assemble_object(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(&object[..6], &[0x7f, b'E', b'L', b'F', 1, 1]);
+        assert_eq!(u16_at(&object, 16), ET_REL);
+
+        let sections = sections(&object);
+        let names: Vec<&str> = sections.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                ".text",
+                ".data",
+                ".bss",
+                ".rodata",
+                ".rela.text",
+                ".rela.data",
+                ".symtab",
+                ".strtab",
+                ".shstrtab"
+            ]
+        );
+        let text = &sections[0].2;
+        // beq a0, zero, +16 resolved in place, jal ra, 0 and the literal load left to the
+        // linker, the literal pool after the code
+        assert_eq!(u32_at(text, 0), 0x00050863);
+        assert_eq!(u32_at(text, 4), 0x000000ef);
+        assert_eq!(text.len(), 24);
+        assert_eq!(&sections[1].2[..4], &7u32.to_le_bytes());
+
+        // Relocations: offset, symbol and type
+        let relocations = |rela: &[u8]| -> Vec<(u32, u32, u32)> {
+            rela.chunks(12)
+                .map(|entry| {
+                    let info = u32_at(entry, 4);
+                    (u32_at(entry, 0), info >> 8, info & 0xff)
+                })
+                .collect()
+        };
+        let symtab = &sections[6].2;
+        let strtab = &sections[7].2;
+        let symbol_name = |index: u32| {
+            let name = &strtab[u32_at(symtab, 16 * index as usize) as usize..];
+            String::from_utf8(name.split(|&byte| byte == 0).next().unwrap().to_vec()).unwrap()
+        };
+        let text_relocations: Vec<(u32, String, u32)> = relocations(&sections[4].2)
+            .into_iter()
+            .map(|(offset, symbol, kind)| (offset, symbol_name(symbol), kind))
+            .collect();
+        assert_eq!(
+            text_relocations,
+            [
+                (4, "helper".to_string(), R_RISCV_JAL),
+                (20, "".to_string(), R_RISCV_32)
+            ]
+        );
+        let data_relocations = relocations(&sections[5].2);
+        assert_eq!(data_relocations.len(), 1);
+        assert_eq!(symbol_name(data_relocations[0].1), "main");
+
+        assert!(assemble_object(".bss\n.word 1", &AssemblerOptions::default()).is_err());
+        assert!(assemble_object(".bss\nnop", &AssemblerOptions::default()).is_err());
+    }
+}
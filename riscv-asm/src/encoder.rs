@@ -0,0 +1,1140 @@
+use tracing::trace;
+
+use crate::{
+    compressed,
+    error::{AssemblerError, SourceLocation},
+    expression::Expression,
+    isa::{
+        self, DYNAMIC_ROUNDING, Definition, Format, Funct3, OPCODE_AUIPC, OPCODE_LUI,
+        OPCODE_OP_IMM, RegisterFile,
+    },
+    parser::{Instruction, Operand, local_label_instance},
+    suggest::{did_you_mean, float_registers_like, registers_like},
+    symbol_table::SymbolTable,
+};
+
+/// Width of the target's integer registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Xlen {
+    #[default]
+    Rv32,
+    /// Adds the doubleword loads and stores and the 32 bit (`*w`) operations, and widens
+    /// shift amounts to 0..63
+    Rv64,
+}
+
+/// Offsets a conditional branch reaches, 13 bits in steps of 2
+pub(crate) const BRANCH_RANGE: std::ops::RangeInclusive<i64> = -4096..=4094;
+
+/// Rounding modes by name, as operands of floating point instructions
+const ROUNDING_MODES: &[(&str, u8)] = &[
+    ("rne", 0b000),
+    ("rtz", 0b001),
+    ("rdn", 0b010),
+    ("rup", 0b011),
+    ("rmm", 0b100),
+    ("dyn", 0b111),
+];
+
+/// CSRs operands can name, the standard unprivileged, supervisor and machine level ones.
+/// Others are given by number.
+const CSR_NAMES: &[(&str, u16)] = &[
+    ("fflags", 0x001),
+    ("frm", 0x002),
+    ("fcsr", 0x003),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("instret", 0xc02),
+    ("cycleh", 0xc80),
+    ("timeh", 0xc81),
+    ("instreth", 0xc82),
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("scounteren", 0x106),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mcounteren", 0x306),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("mcycle", 0xb00),
+    ("minstret", 0xb02),
+    ("mcycleh", 0xb80),
+    ("minstreth", 0xb82),
+    ("mvendorid", 0xf11),
+    ("marchid", 0xf12),
+    ("mimpid", 0xf13),
+    ("mhartid", 0xf14),
+];
+
+/// CSR numbers are 12 bits
+pub(crate) const CSR_RANGE: std::ops::RangeInclusive<i64> = 0..=0xfff;
+
+/// Number of a rounding mode operand's name
+pub(crate) fn rounding_mode(name: &str) -> Option<u8> {
+    ROUNDING_MODES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, mode)| *mode)
+}
+
+/// Name of a rounding mode, `None` for the reserved ones
+pub(crate) fn rounding_mode_name(mode: u8) -> Option<&'static str> {
+    ROUNDING_MODES
+        .iter()
+        .find(|(_, known)| *known == mode)
+        .map(|(name, _)| *name)
+}
+
+/// Number of a CSR operand's name
+pub(crate) fn csr_number(name: &str) -> Option<u16> {
+    CSR_NAMES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, number)| *number)
+}
+
+/// Name of a CSR by number, for the CSRs operands can name
+pub(crate) fn csr_name(number: u16) -> Option<&'static str> {
+    CSR_NAMES
+        .iter()
+        .find(|(_, known)| *known == number)
+        .map(|(name, _)| *name)
+}
+
+/// Encodes one instruction placed at `address` into its machine word for an `xlen` bit
+/// target, 16 bit compressed encodings are returned in the low half
+pub fn encode(
+    instruction: &Instruction,
+    address: u32,
+    symbol_table: &SymbolTable,
+    xlen: Xlen,
+) -> anyhow::Result<u32> {
+    let location = &instruction.location;
+    // Errors about an operand's value point at it, when the parser saw where it is
+    let at = |operand: usize| {
+        instruction
+            .operand_locations
+            .get(operand)
+            .unwrap_or(location)
+    };
+    let mnemonic = instruction.mnemonic.as_str();
+    let Definition { opcode, format, .. } = isa::lookup(mnemonic)
+        .filter(|definition| xlen == Xlen::Rv64 || !definition.rv64)
+        .ok_or_else(|| {
+            encoder_error(&format!("Unsupported instruction '{}'", mnemonic), location)
+        })?;
+    // Labels' addresses are known by now, branch targets take them as addresses rather than
+    // offsets though
+    let resolved;
+    let operands = match format {
+        Format::Branch(_) | Format::Jal => instruction.operands.as_slice(),
+        _ => {
+            resolved = resolve_expressions(&instruction.operands, symbol_table, at)?;
+            resolved.as_slice()
+        }
+    };
+    let wrong_operands = || {
+        encoder_error(
+            &format!("'{}' expects {}", mnemonic, syntax(format)),
+            location,
+        )
+    };
+
+    let word = match format {
+        Format::R(funct3, funct7) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Register(rs1),
+                Operand::Register(rs2),
+            ] => encode_r(opcode, *rd, funct3, *rs1, *rs2, funct7),
+            _ => return Err(wrong_operands()),
+        },
+        Format::I(funct3) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Register(rs1),
+                Operand::Immediate(imm),
+            ] => {
+                let imm = check_signed(*imm, 12, at(2))?;
+                encode_i(opcode, *rd, funct3, *rs1, imm)
+            }
+            [
+                Operand::Register(rd),
+                Operand::Register(rs1),
+                low @ (Operand::Lo { base: None, .. } | Operand::PcrelLo { base: None, .. }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(2))?;
+                encode_i(opcode, *rd, funct3, *rs1, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Shift(funct3, funct7) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Register(rs1),
+                Operand::Immediate(shamt),
+            ] => {
+                let limit = match (xlen, opcode) {
+                    (Xlen::Rv64, OPCODE_OP_IMM) => 64,
+                    _ => 32,
+                };
+                if !(0..limit).contains(shamt) {
+                    return Err(encoder_error(
+                        &format!("Shift amount {} is out of range 0..{}", shamt, limit - 1),
+                        at(2),
+                    ));
+                }
+                encode_i(opcode, *rd, funct3, *rs1, (funct7 << 5) | *shamt as u32)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Load(funct3) => match operands {
+            [Operand::Register(rd), Operand::Memory { offset, base }] => {
+                let imm = check_signed(*offset, 12, at(1))?;
+                encode_i(opcode, *rd, funct3, *base, imm)
+            }
+            [
+                Operand::Register(rd),
+                low @ (Operand::Lo {
+                    base: Some(base), ..
+                }
+                | Operand::PcrelLo {
+                    base: Some(base), ..
+                }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(1))?;
+                encode_i(opcode, *rd, funct3, *base, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Store(funct3) => match operands {
+            [Operand::Register(rs2), Operand::Memory { offset, base }] => {
+                let imm = check_signed(*offset, 12, at(1))?;
+                encode_s(opcode, funct3, *base, *rs2, imm)
+            }
+            [
+                Operand::Register(rs2),
+                low @ (Operand::Lo {
+                    base: Some(base), ..
+                }
+                | Operand::PcrelLo {
+                    base: Some(base), ..
+                }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(1))?;
+                encode_s(opcode, funct3, *base, *rs2, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Branch(funct3) => match operands {
+            [Operand::Register(rs1), Operand::Register(rs2), target] => {
+                let offset = branch_offset(target, address, symbol_table, at(2))?;
+                if !BRANCH_RANGE.contains(&offset) {
+                    let target = match target {
+                        Operand::Symbol(name) => {
+                            let name = local_label_instance(name)
+                                .map_or(name.as_str(), |(number, _)| number);
+                            format!("'{}'", name)
+                        }
+                        _ => "The branch target".to_string(),
+                    };
+                    return Err(encoder_error(
+                        &format!(
+                            "{} is {} bytes away, out of the {}..{} a branch reaches",
+                            target,
+                            offset,
+                            BRANCH_RANGE.start(),
+                            BRANCH_RANGE.end()
+                        ),
+                        at(2),
+                    ));
+                }
+                let imm = check_signed(offset, 13, at(2))?;
+                encode_b(opcode, funct3, *rs1, *rs2, imm)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Upper => match operands {
+            [Operand::Register(rd), Operand::Immediate(imm)] => {
+                if !(0..=0xFFFFF).contains(imm) {
+                    return Err(encoder_error(
+                        &format!("Immediate {} is out of range 0x00000..0xFFFFF", imm),
+                        at(1),
+                    ));
+                }
+                opcode | (*rd as u32) << 7 | (*imm as u32) << 12
+            }
+            [Operand::Register(rd), Operand::PcrelHi(symbol)] if opcode == OPCODE_AUIPC => {
+                let (high, _) = pcrel_parts(symbol, address, symbol_table, at(1))?;
+                opcode | (*rd as u32) << 7 | high << 12
+            }
+            [Operand::Register(rd), Operand::Hi(symbol)] if opcode == OPCODE_LUI => {
+                let (high, _) = pcrel_parts(symbol, 0, symbol_table, at(1))?;
+                opcode | (*rd as u32) << 7 | high << 12
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Jal => match operands {
+            [Operand::Register(rd), target] => {
+                let offset = branch_offset(target, address, symbol_table, at(1))?;
+                let imm = check_signed(offset, 21, at(1))?;
+                encode_j(opcode, *rd, imm)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Jalr => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Register(rs1),
+                Operand::Immediate(imm),
+            ]
+            | [
+                Operand::Register(rd),
+                Operand::Memory {
+                    offset: imm,
+                    base: rs1,
+                },
+            ] => {
+                let imm = check_signed(*imm, 12, at(operands.len() - 1))?;
+                encode_i(opcode, *rd, 0x0, *rs1, imm)
+            }
+            [
+                Operand::Register(rd),
+                Operand::Register(base),
+                low @ (Operand::Lo { base: None, .. } | Operand::PcrelLo { base: None, .. }),
+            ]
+            | [
+                Operand::Register(rd),
+                low @ (Operand::Lo {
+                    base: Some(base), ..
+                }
+                | Operand::PcrelLo {
+                    base: Some(base), ..
+                }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(operands.len() - 1))?;
+                encode_i(opcode, *rd, 0x0, *base, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::System(imm) => match operands {
+            [] => encode_i(opcode, 0, 0x0, 0, imm),
+            _ => return Err(wrong_operands()),
+        },
+        // Without sets a fence orders all accesses
+        Format::Fence => match operands {
+            [] => encode_i(opcode, 0, 0x0, 0, 0x0ff),
+            [Operand::Immediate(pred), Operand::Immediate(succ)]
+                if (0..16).contains(pred) && (0..16).contains(succ) =>
+            {
+                encode_i(opcode, 0, 0x0, 0, (*pred << 4 | *succ) as u32)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::FixedFence(funct3, imm) => match operands {
+            [] => encode_i(opcode, 0, funct3, 0, imm),
+            _ => return Err(wrong_operands()),
+        },
+        Format::Csr(funct3) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Csr(csr),
+                Operand::Register(rs1),
+            ] => encode_i(opcode, *rd, funct3, *rs1, *csr as u32),
+            _ => return Err(wrong_operands()),
+        },
+        Format::CsrImmediate(funct3) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Csr(csr),
+                Operand::Immediate(imm),
+            ] => {
+                if !(0..32).contains(imm) {
+                    return Err(encoder_error(
+                        &format!("Immediate {} is out of range 0..31", imm),
+                        at(2),
+                    ));
+                }
+                encode_i(opcode, *rd, funct3, *imm as u8, *csr as u32)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Fp {
+            funct7,
+            funct3,
+            rs2: fixed_rs2,
+            rd: rd_file,
+            rs1: rs1_file,
+        } => {
+            let (registers, rounding) = split_rounding_mode(operands);
+            let funct3 = match (funct3, rounding) {
+                (Funct3::Fixed(funct3), None) => funct3,
+                (Funct3::Rounding(default), rounding) => rounding.map_or(default, u32::from),
+                (Funct3::Fixed(_), Some(_)) => return Err(wrong_operands()),
+            };
+            let registers = match (registers, fixed_rs2) {
+                ([rd, rs1], Some(rs2)) => register_in(rd, rd_file)
+                    .zip(register_in(rs1, rs1_file))
+                    .map(|(rd, rs1)| (rd, rs1, rs2 as u8)),
+                ([rd, rs1, rs2], None) => register_in(rd, rd_file)
+                    .zip(register_in(rs1, rs1_file))
+                    .zip(register_in(rs2, RegisterFile::Float))
+                    .map(|((rd, rs1), rs2)| (rd, rs1, rs2)),
+                _ => None,
+            };
+            let (rd, rs1, rs2) = registers.ok_or_else(wrong_operands)?;
+            encode_r(opcode, rd, funct3, rs1, rs2, funct7)
+        }
+        Format::FpFused(fmt) => match split_rounding_mode(operands) {
+            (
+                [
+                    Operand::FloatRegister(rd),
+                    Operand::FloatRegister(rs1),
+                    Operand::FloatRegister(rs2),
+                    Operand::FloatRegister(rs3),
+                ],
+                rounding,
+            ) => {
+                let rm = rounding.map_or(DYNAMIC_ROUNDING, u32::from);
+                encode_r(opcode, *rd, rm, *rs1, *rs2, (*rs3 as u32) << 2 | fmt)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::FpLoad(funct3) => match operands {
+            [Operand::FloatRegister(rd), Operand::Memory { offset, base }] => {
+                let imm = check_signed(*offset, 12, at(1))?;
+                encode_i(opcode, *rd, funct3, *base, imm)
+            }
+            [
+                Operand::FloatRegister(rd),
+                low @ (Operand::Lo {
+                    base: Some(base), ..
+                }
+                | Operand::PcrelLo {
+                    base: Some(base), ..
+                }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(1))?;
+                encode_i(opcode, *rd, funct3, *base, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::FpStore(funct3) => match operands {
+            [
+                Operand::FloatRegister(rs2),
+                Operand::Memory { offset, base },
+            ] => {
+                let imm = check_signed(*offset, 12, at(1))?;
+                encode_s(opcode, funct3, *base, *rs2, imm)
+            }
+            [
+                Operand::FloatRegister(rs2),
+                low @ (Operand::Lo {
+                    base: Some(base), ..
+                }
+                | Operand::PcrelLo {
+                    base: Some(base), ..
+                }),
+            ] => {
+                let low = low_part(low, address, symbol_table, at(1))?;
+                encode_s(opcode, funct3, *base, *rs2, low)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::LoadReserved(funct7) => match operands {
+            [Operand::Register(rd), Operand::Memory { offset: 0, base }] => {
+                encode_r(opcode, *rd, 0x2, *base, 0, funct7)
+            }
+            _ => return Err(wrong_operands()),
+        },
+        Format::Atomic(funct7) => match operands {
+            [
+                Operand::Register(rd),
+                Operand::Register(rs2),
+                Operand::Memory { offset: 0, base },
+            ] => encode_r(opcode, *rd, 0x2, *base, *rs2, funct7),
+            _ => return Err(wrong_operands()),
+        },
+    };
+
+    let word = match &instruction.compressed {
+        Some(form) => compressed::compress(word)
+            .into_iter()
+            .find(|(name, _)| name == form)
+            .map(|(_, encoding)| u32::from(encoding))
+            .ok_or_else(|| {
+                encoder_error(
+                    &format!("Operands don't fit the compressed encoding of '{}'", form),
+                    location,
+                )
+            })?,
+        None => word,
+    };
+    trace!(
+        address = format_args!("{:#010x}", address),
+        mnemonic,
+        word = format_args!("{:#010x}", word),
+        "encoded"
+    );
+    Ok(word)
+}
+
+/// The operands an instruction of `format` is written with, e.g. "rd, offset(rs1)".
+/// Alternatives are separated by " or ", optional operands are in brackets.
+fn syntax(format: Format) -> String {
+    let syntax = match format {
+        Format::R(..) => "rd, rs1, rs2",
+        Format::I(_) => "rd, rs1, imm",
+        Format::Shift(..) => "rd, rs1, shamt",
+        Format::Load(_) => "rd, offset(rs1)",
+        Format::Store(_) => "rs2, offset(rs1)",
+        Format::Branch(_) => "rs1, rs2, label",
+        Format::Upper => "rd, imm",
+        Format::Jal => "rd, label",
+        Format::Jalr => "rd, rs1, imm or rd, offset(rs1)",
+        Format::System(_) | Format::FixedFence(..) => "no operands",
+        Format::Fence => "no operands or pred, succ",
+        Format::Fp {
+            funct3,
+            rs2,
+            rd,
+            rs1,
+            ..
+        } => {
+            let rounds = matches!(funct3, Funct3::Rounding(_));
+            return fp_operand_names(rd, rs1, rs2.is_none(), rounds);
+        }
+        Format::FpFused(_) => "fd, fs1, fs2, fs3[, rm]",
+        Format::FpLoad(_) => "fd, offset(rs1)",
+        Format::FpStore(_) => "fs2, offset(rs1)",
+        Format::LoadReserved(_) => "rd, (rs1)",
+        Format::Atomic(_) => "rd, rs2, (rs1)",
+        Format::Csr(_) => "rd, csr, rs1",
+        Format::CsrImmediate(_) => "rd, csr, uimm",
+    };
+    syntax.to_string()
+}
+
+/// Operands that don't fit an instruction's syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OperandMismatch {
+    pub message: String,
+    /// Index of the offending operand, `None` when operands are missing
+    pub operand: Option<usize>,
+}
+
+/// Checks the number and kinds of the operands of `mnemonic` against its syntax, before
+/// any of their values are looked at. Unknown mnemonics are left to [`encode`].
+pub(crate) fn check_operands(mnemonic: &str, operands: &[Operand]) -> Result<(), OperandMismatch> {
+    let Some(Definition { format, .. }) = isa::lookup(mnemonic) else {
+        return Ok(());
+    };
+    let syntax = syntax(format);
+    let mut mismatches = Vec::new();
+    for alternative in syntax.split(" or ") {
+        let (required, optional) = match alternative.split_once("[, ") {
+            Some((required, optional)) => (required, optional.trim_end_matches(']')),
+            None => (alternative, ""),
+        };
+        let slots: Vec<&str> = match required {
+            "no operands" => Vec::new(),
+            _ => required.split(", ").collect(),
+        };
+        let most = slots.len() + usize::from(!optional.is_empty());
+        if operands.len() < slots.len() || operands.len() > most {
+            let count = match operands.len() {
+                1 => "1 operand".to_string(),
+                count => format!("{} operands", count),
+            };
+            mismatches.push(OperandMismatch {
+                message: format!("'{}' expects {}; found {}", mnemonic, syntax, count),
+                operand: (operands.len() > most).then_some(most),
+            });
+            continue;
+        }
+        let wrong = slots
+            .iter()
+            .chain((!optional.is_empty()).then_some(&optional))
+            .zip(operands)
+            .position(|(slot, operand)| !fits(slot, operand));
+        match wrong {
+            None => return Ok(()),
+            // A wrong kind says more than a wrong count, whichever alternative it's from
+            Some(index) => {
+                let slot = if index < slots.len() {
+                    slots[index]
+                } else {
+                    optional
+                };
+                // A label where a register goes is likely a misspelled register
+                let suggestions = match (&operands[index], slot) {
+                    (Operand::Symbol(name), "rd" | "rs1" | "rs2") => registers_like(name),
+                    (Operand::Symbol(name), "fd" | "fs1" | "fs2" | "fs3") => {
+                        float_registers_like(name)
+                    }
+                    _ => Vec::new(),
+                };
+                mismatches.insert(
+                    0,
+                    OperandMismatch {
+                        message: format!(
+                            "'{}' expects {}; found {} for {}{}",
+                            mnemonic,
+                            syntax,
+                            describe_operand(&operands[index]),
+                            slot,
+                            did_you_mean(&suggestions)
+                        ),
+                        operand: Some(index),
+                    },
+                )
+            }
+        }
+    }
+    Err(mismatches.swap_remove(0))
+}
+
+/// Whether `operand` can stand in the place of `slot` of an instruction's syntax
+fn fits(slot: &str, operand: &Operand) -> bool {
+    match slot {
+        "rd" | "rs1" | "rs2" => matches!(operand, Operand::Register(_)),
+        "fd" | "fs1" | "fs2" | "fs3" => matches!(operand, Operand::FloatRegister(_)),
+        "imm" => matches!(
+            operand,
+            Operand::Immediate(_)
+                | Operand::Expression(_)
+                | Operand::Hi(_)
+                | Operand::Lo { base: None, .. }
+                | Operand::PcrelHi(_)
+                | Operand::PcrelLo { base: None, .. }
+        ),
+        "shamt" | "uimm" => matches!(operand, Operand::Immediate(_)),
+        "label" => matches!(
+            operand,
+            Operand::Symbol(_) | Operand::Immediate(_) | Operand::Expression(_)
+        ),
+        "offset(rs1)" => matches!(
+            operand,
+            Operand::Memory { .. }
+                | Operand::Lo { base: Some(_), .. }
+                | Operand::PcrelLo { base: Some(_), .. }
+        ),
+        "(rs1)" => matches!(operand, Operand::Memory { offset: 0, .. }),
+        "csr" => matches!(operand, Operand::Csr(_)),
+        "pred" | "succ" => matches!(operand, Operand::Immediate(_)),
+        "rm" => matches!(operand, Operand::RoundingMode(_)),
+        _ => false,
+    }
+}
+
+fn describe_operand(operand: &Operand) -> String {
+    match operand {
+        Operand::Register(number) => format!("register x{}", number),
+        Operand::FloatRegister(number) => format!("register f{}", number),
+        Operand::RoundingMode(_) => "a rounding mode".to_string(),
+        Operand::Csr(number) => format!("CSR {:#05x}", number),
+        Operand::Immediate(value) => format!("immediate {}", value),
+        Operand::Symbol(name) => format!("label '{}'", name),
+        Operand::Memory { offset: 0, base } => format!("memory operand (x{})", base),
+        Operand::Memory { offset, base } => format!("memory operand {}(x{})", offset, base),
+        Operand::Hi(name) => format!("%hi({})", name),
+        Operand::Lo { symbol, base } => match base {
+            Some(base) => format!("%lo({})(x{})", symbol, base),
+            None => format!("%lo({})", symbol),
+        },
+        Operand::PcrelHi(name) => format!("%pcrel_hi({})", name),
+        Operand::PcrelLo { symbol, base } => match base {
+            Some(base) => format!("%pcrel_lo({})(x{})", symbol, base),
+            None => format!("%pcrel_lo({})", symbol),
+        },
+        Operand::String(bytes) => format!("string \"{}\"", bytes.escape_ascii()),
+        Operand::Expression(expression) => format!("expression {}", expression),
+    }
+}
+
+/// Splits off a trailing rounding mode operand
+fn split_rounding_mode(operands: &[Operand]) -> (&[Operand], Option<u8>) {
+    match operands {
+        [registers @ .., Operand::RoundingMode(mode)] => (registers, Some(*mode)),
+        registers => (registers, None),
+    }
+}
+
+fn register_in(operand: &Operand, file: RegisterFile) -> Option<u8> {
+    match (operand, file) {
+        (Operand::Register(number), RegisterFile::Integer)
+        | (Operand::FloatRegister(number), RegisterFile::Float) => Some(*number),
+        _ => None,
+    }
+}
+
+/// Operand list of a floating point operation for error messages, e.g. "rd, fs1, fs2"
+fn fp_operand_names(rd: RegisterFile, rs1: RegisterFile, rs2: bool, rounding: bool) -> String {
+    let prefix = |file| match file {
+        RegisterFile::Integer => "r",
+        RegisterFile::Float => "f",
+    };
+    format!(
+        "{}d, {}s1{}{}",
+        prefix(rd),
+        prefix(rs1),
+        if rs2 { ", fs2" } else { "" },
+        if rounding { "[, rm]" } else { "" }
+    )
+}
+
+/// The 20 bit upper part of the offset from `address` to label `name` and the sign extended
+/// 12 bit rest, as an AUIPC and the instruction after it add them up
+fn pcrel_parts(
+    name: &str,
+    address: u32,
+    symbol_table: &SymbolTable,
+    location: &SourceLocation,
+) -> anyhow::Result<(u32, i64)> {
+    let target = symbol_table
+        .address(name)
+        .ok_or_else(|| encoder_error(&format!("Undefined symbol: {}", name), location))?;
+    // Addresses wrap around, so every label is in reach
+    let offset = target.wrapping_sub(address) as i32 as i64;
+    let high = (offset + 0x800) >> 12;
+    Ok((high as u32 & 0xFFFFF, offset - (high << 12)))
+}
+
+/// The 12 bit value of a `%lo` operand, or of a `%pcrel_lo` one of the instruction at
+/// `address` right after the AUIPC adding the upper part
+fn low_part(
+    operand: &Operand,
+    address: u32,
+    symbol_table: &SymbolTable,
+    location: &SourceLocation,
+) -> anyhow::Result<u32> {
+    let (_, low) = match operand {
+        // The parts of an address are those of its offset from 0
+        Operand::Lo { symbol, .. } => pcrel_parts(symbol, 0, symbol_table, location)?,
+        Operand::PcrelLo { symbol, .. } => {
+            pcrel_parts(symbol, address.wrapping_sub(4), symbol_table, location)?
+        }
+        _ => return Err(encoder_error("Expected %lo or %pcrel_lo", location)),
+    };
+    Ok(low as u32 & 0xFFF)
+}
+
+/// The value of an expression of labels
+fn evaluate(
+    expression: &Expression,
+    symbol_table: &SymbolTable,
+    location: &SourceLocation,
+) -> anyhow::Result<i64> {
+    expression
+        .evaluate(&|name| symbol_table.address(name).map(i64::from))
+        .map_err(|message| encoder_error(&message, location))
+}
+
+/// `operands` with their expressions replaced by their values
+fn resolve_expressions<'a>(
+    operands: &[Operand],
+    symbol_table: &SymbolTable,
+    at: impl Fn(usize) -> &'a SourceLocation,
+) -> anyhow::Result<Vec<Operand>> {
+    operands
+        .iter()
+        .enumerate()
+        .map(|(index, operand)| match operand {
+            Operand::Expression(expression) => Ok(Operand::Immediate(evaluate(
+                expression,
+                symbol_table,
+                at(index),
+            )?)),
+            operand => Ok(operand.clone()),
+        })
+        .collect()
+}
+
+/// PC relative offset of a branch or jump target
+fn branch_offset(
+    target: &Operand,
+    address: u32,
+    symbol_table: &SymbolTable,
+    location: &SourceLocation,
+) -> anyhow::Result<i64> {
+    let offset = match target {
+        Operand::Immediate(offset) => *offset,
+        Operand::Symbol(name) => {
+            let target = symbol_table
+                .address(name)
+                .ok_or_else(|| encoder_error(&format!("Undefined symbol: {}", name), location))?;
+            target as i64 - address as i64
+        }
+        Operand::Expression(expression) => {
+            let target = evaluate(expression, symbol_table, location)?;
+            target - address as i64
+        }
+        _ => return Err(encoder_error("Expected a label or offset", location)),
+    };
+    if offset % 2 != 0 {
+        return Err(encoder_error(
+            &format!("Branch offset {} is not a multiple of 2", offset),
+            location,
+        ));
+    }
+    Ok(offset)
+}
+
+/// Checks that `value` fits in a `bits` wide two's complement field and returns its raw bits
+fn check_signed(value: i64, bits: u32, location: &SourceLocation) -> anyhow::Result<u32> {
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << (bits - 1)) - 1;
+    if !(min..=max).contains(&value) {
+        return Err(encoder_error(
+            &format!(
+                "Immediate {} does not fit in {} bits ({}..{})",
+                value, bits, min, max
+            ),
+            location,
+        ));
+    }
+    Ok(value as u32 & ((1u32 << bits) - 1))
+}
+
+pub(crate) fn encode_r(opcode: u32, rd: u8, funct3: u32, rs1: u8, rs2: u8, funct7: u32) -> u32 {
+    opcode
+        | (rd as u32) << 7
+        | funct3 << 12
+        | (rs1 as u32) << 15
+        | (rs2 as u32) << 20
+        | funct7 << 25
+}
+
+pub(crate) fn encode_i(opcode: u32, rd: u8, funct3: u32, rs1: u8, imm: u32) -> u32 {
+    opcode | (rd as u32) << 7 | funct3 << 12 | (rs1 as u32) << 15 | (imm & 0xFFF) << 20
+}
+
+pub(crate) fn encode_s(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: u32) -> u32 {
+    opcode
+        | (imm & 0x1F) << 7
+        | funct3 << 12
+        | (rs1 as u32) << 15
+        | (rs2 as u32) << 20
+        | ((imm >> 5) & 0x7F) << 25
+}
+
+pub(crate) fn encode_b(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: u32) -> u32 {
+    opcode
+        | ((imm >> 11) & 0x1) << 7
+        | ((imm >> 1) & 0xF) << 8
+        | funct3 << 12
+        | (rs1 as u32) << 15
+        | (rs2 as u32) << 20
+        | ((imm >> 5) & 0x3F) << 25
+        | ((imm >> 12) & 0x1) << 31
+}
+
+pub(crate) fn encode_j(opcode: u32, rd: u8, imm: u32) -> u32 {
+    opcode
+        | (rd as u32) << 7
+        | ((imm >> 12) & 0xFF) << 12
+        | ((imm >> 11) & 0x1) << 20
+        | ((imm >> 1) & 0x3FF) << 21
+        | ((imm >> 20) & 0x1) << 31
+}
+
+fn encoder_error(message: &str, location: &SourceLocation) -> anyhow::Error {
+    AssemblerError::EncoderError {
+        message: message.to_string(),
+        location: location.clone(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_at(mnemonic: &str, operands: Vec<Operand>, address: u32) -> u32 {
+        let mut symbol_table = SymbolTable::new();
+        symbol_table.set_address("target", 0x100);
+        let instruction = Instruction {
+            mnemonic: mnemonic.to_string(),
+            operands,
+            location: SourceLocation::new(1, 1),
+            operand_locations: Vec::new(),
+            compressed: None,
+        };
+        encode(&instruction, address, &symbol_table, Xlen::Rv32).unwrap()
+    }
+
+    use Operand::{Immediate, Memory, Register, Symbol};
+
+    // Expected words taken from the GNU assembler
+    #[test]
+    fn test_encodings() {
+        assert_eq!(
+            encode_at("add", vec![Register(10), Register(11), Register(12)], 0),
+            0x00c58533
+        );
+        assert_eq!(
+            encode_at("sub", vec![Register(5), Register(6), Register(7)], 0),
+            0x407302b3
+        );
+        assert_eq!(
+            encode_at("addi", vec![Register(1), Register(0), Immediate(5)], 0),
+            0x00500093
+        );
+        assert_eq!(
+            encode_at("addi", vec![Register(1), Register(1), Immediate(-1)], 0),
+            0xfff08093
+        );
+        assert_eq!(
+            encode_at("srai", vec![Register(10), Register(10), Immediate(3)], 0),
+            0x40355513
+        );
+        assert_eq!(
+            encode_at("lw", vec![Register(10), Memory { offset: 8, base: 2 }], 0),
+            0x00812503
+        );
+        assert_eq!(
+            encode_at(
+                "sw",
+                vec![
+                    Register(10),
+                    Memory {
+                        offset: -4,
+                        base: 2
+                    }
+                ],
+                0
+            ),
+            0xfea12e23
+        );
+        assert_eq!(
+            encode_at("lui", vec![Register(10), Immediate(0x12345)], 0),
+            0x12345537
+        );
+        assert_eq!(
+            encode_at("jalr", vec![Register(0), Register(1), Immediate(0)], 0),
+            0x00008067
+        );
+        assert_eq!(encode_at("ecall", vec![], 0), 0x00000073);
+        assert_eq!(encode_at("ebreak", vec![], 0), 0x00100073);
+        assert_eq!(
+            encode_at("mul", vec![Register(10), Register(11), Register(12)], 0),
+            0x02c58533
+        );
+        assert_eq!(
+            encode_at("mulhsu", vec![Register(10), Register(11), Register(12)], 0),
+            0x02c5a533
+        );
+        assert_eq!(
+            encode_at("remu", vec![Register(5), Register(6), Register(7)], 0),
+            0x027372b3
+        );
+    }
+
+    #[test]
+    fn test_float_encodings() {
+        use Operand::{FloatRegister as F, RoundingMode};
+        assert_eq!(encode_at("fadd.s", vec![F(1), F(2), F(3)], 0), 0x003170d3);
+        assert_eq!(
+            encode_at("fadd.s", vec![F(1), F(2), F(3), RoundingMode(1)], 0),
+            0x003110d3
+        );
+        assert_eq!(encode_at("fdiv.d", vec![F(8), F(9), F(18)], 0), 0x1b24f453);
+        assert_eq!(encode_at("fsqrt.d", vec![F(10), F(11)], 0), 0x5a05f553);
+        assert_eq!(
+            encode_at("fmadd.d", vec![F(1), F(2), F(3), F(4), RoundingMode(0)], 0),
+            0x223100c3
+        );
+        assert_eq!(
+            encode_at("fnmsub.s", vec![F(1), F(2), F(3), F(31)], 0),
+            0xf83170cb
+        );
+        assert_eq!(encode_at("fsgnjn.d", vec![F(1), F(2), F(3)], 0), 0x223110d3);
+        assert_eq!(
+            encode_at("fle.s", vec![Register(5), F(1), F(2)], 0),
+            0xa02082d3
+        );
+        assert_eq!(
+            encode_at("fclass.d", vec![Register(10), F(1)], 0),
+            0xe2009553
+        );
+        assert_eq!(
+            encode_at("fmv.w.x", vec![F(1), Register(10)], 0),
+            0xf00500d3
+        );
+        assert_eq!(
+            encode_at("fcvt.d.w", vec![F(1), Register(10)], 0),
+            0xd20500d3
+        );
+        assert_eq!(encode_at("fcvt.s.d", vec![F(1), F(2)], 0), 0x401170d3);
+        assert_eq!(
+            encode_at("fcvt.wu.d", vec![Register(10), F(1), RoundingMode(1)], 0),
+            0xc2109553
+        );
+        assert_eq!(
+            encode_at(
+                "fld",
+                vec![
+                    F(31),
+                    Memory {
+                        offset: -2048,
+                        base: 10
+                    }
+                ],
+                0
+            ),
+            0x80053f87
+        );
+        assert_eq!(
+            encode_at(
+                "fsw",
+                vec![
+                    F(1),
+                    Memory {
+                        offset: 2047,
+                        base: 2
+                    }
+                ],
+                0
+            ),
+            0x7e112fa7
+        );
+    }
+
+    #[test]
+    fn test_atomic_encodings() {
+        let address = |base| Memory { offset: 0, base };
+        assert_eq!(
+            encode_at("lr.w", vec![Register(10), address(11)], 0),
+            0x1005a52f
+        );
+        assert_eq!(
+            encode_at("lr.w.aq", vec![Register(10), address(11)], 0),
+            0x1405a52f
+        );
+        assert_eq!(
+            encode_at("sc.w.rl", vec![Register(10), Register(12), address(11)], 0),
+            0x1ac5a52f
+        );
+        assert_eq!(
+            encode_at(
+                "amoswap.w.aqrl",
+                vec![Register(5), Register(6), address(7)],
+                0
+            ),
+            0x0e63a2af
+        );
+        assert_eq!(
+            encode_at("amoadd.w", vec![Register(10), Register(12), address(11)], 0),
+            0x00c5a52f
+        );
+        assert_eq!(
+            encode_at(
+                "amomaxu.w",
+                vec![Register(10), Register(12), address(11)],
+                0
+            ),
+            0xe0c5a52f
+        );
+        let symbol_table = SymbolTable::new();
+        let encode = |mnemonic: &str, operands: Vec<Operand>| {
+            let instruction = Instruction {
+                mnemonic: mnemonic.to_string(),
+                operands,
+                location: SourceLocation::new(1, 1),
+                operand_locations: Vec::new(),
+                compressed: None,
+            };
+            encode(&instruction, 0, &symbol_table, Xlen::Rv32)
+        };
+        // Atomics address memory without an offset
+        let offset = Memory {
+            offset: 4,
+            base: 11,
+        };
+        assert!(encode("lr.w", vec![Register(10), offset]).is_err());
+        assert!(encode("sc.w", vec![Register(10), address(11)]).is_err());
+        assert!(encode("amoor.w.rel", vec![Register(10), Register(12), address(11)]).is_err());
+    }
+
+    #[test]
+    fn test_float_operand_checks() {
+        use Operand::{FloatRegister as F, RoundingMode};
+        let symbol_table = SymbolTable::new();
+        let encode = |mnemonic: &str, operands: Vec<Operand>| {
+            let instruction = Instruction {
+                mnemonic: mnemonic.to_string(),
+                operands,
+                location: SourceLocation::new(1, 1),
+                operand_locations: Vec::new(),
+                compressed: None,
+            };
+            encode(&instruction, 0, &symbol_table, Xlen::Rv32)
+        };
+        // Integer registers where float registers belong, and the other way around
+        assert!(encode("fadd.s", vec![Register(1), F(2), F(3)]).is_err());
+        assert!(encode("fmv.x.w", vec![F(1), F(2)]).is_err());
+        assert!(encode("flw", vec![Register(1), Memory { offset: 0, base: 2 }]).is_err());
+        // Only operations that round take a rounding mode
+        assert!(encode("fsgnj.s", vec![F(1), F(2), F(3), RoundingMode(0)]).is_err());
+        assert!(encode("fsqrt.s", vec![F(1), F(2), F(3)]).is_err());
+    }
+
+    #[test]
+    fn test_pc_relative_targets() {
+        // beq a0, zero, +8
+        assert_eq!(
+            encode_at(
+                "beq",
+                vec![Register(10), Register(0), Symbol("target".into())],
+                0xF8
+            ),
+            0x00050463
+        );
+        // bne a0, a1, -4
+        assert_eq!(
+            encode_at(
+                "bne",
+                vec![Register(10), Register(11), Symbol("target".into())],
+                0x104
+            ),
+            0xfeb51ee3
+        );
+        // jal ra, +0x100
+        assert_eq!(
+            encode_at("jal", vec![Register(1), Symbol("target".into())], 0),
+            0x100000ef
+        );
+        // jal zero, -8
+        assert_eq!(
+            encode_at("jal", vec![Register(0), Symbol("target".into())], 0x108),
+            0xff9ff06f
+        );
+    }
+
+    #[test]
+    fn test_immediate_range() {
+        let symbol_table = SymbolTable::new();
+        let instruction = Instruction {
+            mnemonic: "addi".to_string(),
+            operands: vec![Register(1), Register(0), Immediate(2048)],
+            location: SourceLocation::new(1, 1),
+            operand_locations: Vec::new(),
+            compressed: None,
+        };
+        assert!(encode(&instruction, 0, &symbol_table, Xlen::Rv32).is_err());
+    }
+}
@@ -1,12 +1,52 @@
+use std::{fmt, sync::Arc};
+
 use thiserror::Error;
 
-#[derive(Error, Debug, Clone, PartialEq, Eq)]
-#[error("line {line}, column {col}")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SourceLocation {
+    /// File the line is in when it's one the source included, `None` for the source itself
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub file: Option<Arc<str>>,
     pub line: u64,
     pub col: u64,
 }
 
+impl SourceLocation {
+    /// Line `line`, column `col` of the source itself
+    pub fn new(line: u64, col: u64) -> Self {
+        Self {
+            file: None,
+            line,
+            col,
+        }
+    }
+}
+
+/// The source a statement covers, from the start of its first token to the end of its last
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: SourceLocation,
+    /// Just past the last character
+    pub end: SourceLocation,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)?;
+        if let Some(file) = &self.file {
+            write!(f, " of {}", file)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SourceLocation {}
+
 #[derive(Error, Debug, Clone)]
 pub enum AssemblerError {
     #[error("Tokenizer error: {message} at {location}")]
@@ -19,7 +59,78 @@ pub enum AssemblerError {
         message: String,
         location: SourceLocation,
     },
-    // FIX:
-    #[error("Multiple errors")]
+    #[error("Symbol error: {message} at {location}")]
+    SymbolError {
+        message: String,
+        location: SourceLocation,
+    },
+    #[error("Encoder error: {message} at {location}")]
+    EncoderError {
+        message: String,
+        location: SourceLocation,
+    },
+    #[error("Analysis error: {message} at {location}")]
+    AnalysisError {
+        message: String,
+        location: SourceLocation,
+    },
+    /// A symbol or macro defined again, `first` being where it was defined before
+    #[error("{what} error: {message} at {location}, first defined at {first}")]
+    Redefinition {
+        what: Redefined,
+        message: String,
+        location: SourceLocation,
+        first: SourceLocation,
+    },
+    #[error("{}", join_errors(.0))]
     MultipleErrors(Vec<AssemblerError>),
 }
+
+impl AssemblerError {
+    /// Message and location of every error this stands for, one unless it is `MultipleErrors`
+    pub fn diagnostics(&self) -> Vec<(&str, &SourceLocation)> {
+        match self {
+            Self::TokenizerError { message, location }
+            | Self::ParserError { message, location }
+            | Self::SymbolError { message, location }
+            | Self::EncoderError { message, location }
+            | Self::AnalysisError { message, location }
+            | Self::Redefinition {
+                message, location, ..
+            } => vec![(message, location)],
+            Self::MultipleErrors(errors) => errors.iter().flat_map(Self::diagnostics).collect(),
+        }
+    }
+
+    /// Every error this stands for, itself unless it is `MultipleErrors`
+    pub fn errors(&self) -> Vec<&AssemblerError> {
+        match self {
+            Self::MultipleErrors(errors) => errors.iter().flat_map(Self::errors).collect(),
+            error => vec![error],
+        }
+    }
+}
+
+/// What an [`AssemblerError::Redefinition`] defines a second time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redefined {
+    Symbol,
+    Macro,
+}
+
+impl fmt::Display for Redefined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redefined::Symbol => write!(f, "Symbol"),
+            Redefined::Macro => write!(f, "Macro"),
+        }
+    }
+}
+
+fn join_errors(errors: &[AssemblerError]) -> String {
+    errors
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,303 @@
+//! Constant expressions in operands, like `(BUF_SIZE * 4) + 8` or `end - start`.
+//!
+//! The operators are C's, with C's precedence: unary `-`, `+`, `~` and `!` bind tightest,
+//! then `*`, `/` and `%`, then `+` and `-`, then `<<` and `>>`, then `<`, `<=`, `>` and `>=`,
+//! then `==` and `!=`, then `&`, `^`, `|`, `&&` and `||`, in that order. Comparisons and the
+//! logical operators are 1 when true and 0 when false, `&&` and `||` only evaluate their
+//! right side when the left one doesn't decide. Arithmetic is on 64 bit signed numbers and
+//! fails on overflow and division by zero instead of wrapping around. Labels stand for their
+//! addresses and `.equ` symbols for their values.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    /// `-value`
+    Negate(Box<Expression>),
+    /// `~value`, the bitwise complement
+    Not(Box<Expression>),
+    /// `!value`, 1 for 0 and 0 for anything else
+    LogicalNot(Box<Expression>),
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BinaryOperator {
+    Multiply,
+    Divide,
+    Remainder,
+    Add,
+    Subtract,
+    ShiftLeft,
+    /// Arithmetic shift, keeping the sign
+    ShiftRight,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    And,
+    Xor,
+    Or,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOperator {
+    pub fn from_text(text: &str) -> Option<Self> {
+        match text {
+            "*" => Some(BinaryOperator::Multiply),
+            "/" => Some(BinaryOperator::Divide),
+            "%" => Some(BinaryOperator::Remainder),
+            "+" => Some(BinaryOperator::Add),
+            "-" => Some(BinaryOperator::Subtract),
+            "<<" => Some(BinaryOperator::ShiftLeft),
+            ">>" => Some(BinaryOperator::ShiftRight),
+            "<" => Some(BinaryOperator::Less),
+            "<=" => Some(BinaryOperator::LessOrEqual),
+            ">" => Some(BinaryOperator::Greater),
+            ">=" => Some(BinaryOperator::GreaterOrEqual),
+            "==" => Some(BinaryOperator::Equal),
+            "!=" => Some(BinaryOperator::NotEqual),
+            "&" => Some(BinaryOperator::And),
+            "^" => Some(BinaryOperator::Xor),
+            "|" => Some(BinaryOperator::Or),
+            "&&" => Some(BinaryOperator::LogicalAnd),
+            "||" => Some(BinaryOperator::LogicalOr),
+            _ => None,
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Remainder => "%",
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::ShiftLeft => "<<",
+            BinaryOperator::ShiftRight => ">>",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessOrEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterOrEqual => ">=",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::And => "&",
+            BinaryOperator::Xor => "^",
+            BinaryOperator::Or => "|",
+            BinaryOperator::LogicalAnd => "&&",
+            BinaryOperator::LogicalOr => "||",
+        }
+    }
+
+    /// How tightly the operator binds, operators of higher precedence are applied first
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Remainder => 10,
+            BinaryOperator::Add | BinaryOperator::Subtract => 9,
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => 8,
+            BinaryOperator::Less
+            | BinaryOperator::LessOrEqual
+            | BinaryOperator::Greater
+            | BinaryOperator::GreaterOrEqual => 7,
+            BinaryOperator::Equal | BinaryOperator::NotEqual => 6,
+            BinaryOperator::And => 5,
+            BinaryOperator::Xor => 4,
+            BinaryOperator::Or => 3,
+            BinaryOperator::LogicalAnd => 2,
+            BinaryOperator::LogicalOr => 1,
+        }
+    }
+
+    fn apply(self, left: i64, right: i64) -> Result<i64, String> {
+        let overflow = || format!("Overflow in {} {} {}", left, self.text(), right);
+        match self {
+            BinaryOperator::Multiply => left.checked_mul(right).ok_or_else(overflow),
+            BinaryOperator::Divide | BinaryOperator::Remainder if right == 0 => {
+                Err(format!("Division by zero in {} {} 0", left, self.text()))
+            }
+            BinaryOperator::Divide => left.checked_div(right).ok_or_else(overflow),
+            BinaryOperator::Remainder => left.checked_rem(right).ok_or_else(overflow),
+            BinaryOperator::Add => left.checked_add(right).ok_or_else(overflow),
+            BinaryOperator::Subtract => left.checked_sub(right).ok_or_else(overflow),
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight if !(0..64).contains(&right) => {
+                Err(format!("Shift amount {} is out of range 0..63", right))
+            }
+            BinaryOperator::ShiftLeft => Some(left << right)
+                .filter(|shifted| shifted >> right == left)
+                .ok_or_else(overflow),
+            BinaryOperator::ShiftRight => Ok(left >> right),
+            BinaryOperator::Less => Ok((left < right).into()),
+            BinaryOperator::LessOrEqual => Ok((left <= right).into()),
+            BinaryOperator::Greater => Ok((left > right).into()),
+            BinaryOperator::GreaterOrEqual => Ok((left >= right).into()),
+            BinaryOperator::Equal => Ok((left == right).into()),
+            BinaryOperator::NotEqual => Ok((left != right).into()),
+            BinaryOperator::And => Ok(left & right),
+            BinaryOperator::Xor => Ok(left ^ right),
+            BinaryOperator::Or => Ok(left | right),
+            BinaryOperator::LogicalAnd => Ok((left != 0 && right != 0).into()),
+            BinaryOperator::LogicalOr => Ok((left != 0 || right != 0).into()),
+        }
+    }
+}
+
+impl Expression {
+    /// The value of the expression, taking the values of symbols from `value_of`
+    pub fn evaluate(&self, value_of: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Symbol(name) => {
+                value_of(name).ok_or_else(|| format!("Undefined symbol: {}", name))
+            }
+            Expression::Negate(value) => {
+                let value = value.evaluate(value_of)?;
+                value
+                    .checked_neg()
+                    .ok_or_else(|| format!("Overflow in -({})", value))
+            }
+            Expression::Not(value) => Ok(!value.evaluate(value_of)?),
+            Expression::LogicalNot(value) => Ok((value.evaluate(value_of)? == 0).into()),
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = left.evaluate(value_of)?;
+                match operator {
+                    BinaryOperator::LogicalAnd if left == 0 => Ok(0),
+                    BinaryOperator::LogicalOr if left != 0 => Ok(1),
+                    _ => operator.apply(left, right.evaluate(value_of)?),
+                }
+            }
+        }
+    }
+
+    /// Every symbol the expression uses, in order of appearance
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => Vec::new(),
+            Expression::Symbol(name) => vec![name],
+            Expression::Negate(value) | Expression::Not(value) | Expression::LogicalNot(value) => {
+                value.symbols()
+            }
+            Expression::Binary { left, right, .. } => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Number(value) => write!(f, "{}", value),
+            Expression::Symbol(name) => write!(f, "{}", name),
+            Expression::Negate(value) => write!(f, "-{}", Operand(value)),
+            Expression::Not(value) => write!(f, "~{}", Operand(value)),
+            Expression::LogicalNot(value) => write!(f, "!{}", Operand(value)),
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => write!(
+                f,
+                "{} {} {}",
+                Operand(left),
+                operator.text(),
+                Operand(right)
+            ),
+        }
+    }
+}
+
+/// An operand of an operator, binary operations and negative numbers in parentheses
+struct Operand<'a>(&'a Expression);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expression::Number(value) if *value < 0 => write!(f, "({})", value),
+            Expression::Binary { .. } => write!(f, "({})", self.0),
+            expression => write!(f, "{}", expression),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let symbol = |name: &str| Expression::Symbol(name.to_string());
+        let binary = |operator, left, right| Expression::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        };
+        let values = |name: &str| match name {
+            "start" => Some(0x100),
+            "end" => Some(0x140),
+            _ => None,
+        };
+        let size = binary(BinaryOperator::Subtract, symbol("end"), symbol("start"));
+        assert_eq!(size.evaluate(&values), Ok(0x40));
+        assert_eq!(size.symbols(), ["end", "start"]);
+        let words = binary(
+            BinaryOperator::ShiftRight,
+            Expression::Not(Box::new(size.clone())),
+            Expression::Number(2),
+        );
+        assert_eq!(words.evaluate(&values), Ok(!0x40 >> 2));
+        assert_eq!(words.to_string(), "~(end - start) >> 2");
+
+        assert!(symbol("missing").evaluate(&values).is_err());
+        let overflow = binary(
+            BinaryOperator::Multiply,
+            Expression::Number(i64::MAX),
+            Expression::Number(2),
+        );
+        assert!(overflow.evaluate(&values).is_err());
+        let division = binary(BinaryOperator::Remainder, size, Expression::Number(0));
+        assert!(division.evaluate(&values).is_err());
+        assert!(
+            Expression::Negate(Box::new(Expression::Number(i64::MIN)))
+                .evaluate(&values)
+                .is_err()
+        );
+
+        let compare = |operator| binary(operator, symbol("end"), symbol("start"));
+        assert_eq!(compare(BinaryOperator::Greater).evaluate(&values), Ok(1));
+        assert_eq!(
+            compare(BinaryOperator::LessOrEqual).evaluate(&values),
+            Ok(0)
+        );
+        assert_eq!(compare(BinaryOperator::NotEqual).evaluate(&values), Ok(1));
+        let not = Expression::LogicalNot(Box::new(compare(BinaryOperator::Equal)));
+        assert_eq!(not.evaluate(&values), Ok(1));
+        assert_eq!(not.to_string(), "!(end == start)");
+        // The right side isn't evaluated when the left one decides
+        let missing = || symbol("missing");
+        let and = binary(BinaryOperator::LogicalAnd, Expression::Number(0), missing());
+        assert_eq!(and.evaluate(&values), Ok(0));
+        let or = binary(BinaryOperator::LogicalOr, symbol("end"), missing());
+        assert_eq!(or.evaluate(&values), Ok(1));
+        let or = binary(BinaryOperator::LogicalOr, Expression::Number(0), missing());
+        assert!(or.evaluate(&values).is_err());
+    }
+}
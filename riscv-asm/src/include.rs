@@ -0,0 +1,275 @@
+//! `.include "file"`, replacing the line with the lines of another source file, so a project
+//! can keep its constants and macros in files of their own:
+//!
+//! ```text
+//! .include "defs.s"       # .equ UART_BASE, 0x10000000 and friends
+//!
+//!     li a0, UART_BASE
+//! ```
+//!
+//! A relative name is looked for in the directory of the file including it first (the
+//! working directory for source that isn't a file), then in each of
+//! [`AssemblerOptions::include_paths`](crate::AssemblerOptions::include_paths) in order.
+//! Included files can include others, a file including itself, directly or not, is an
+//! error. Lines of included files are located by their file, so errors in them name it.
+
+use std::{
+    borrow::Cow,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::debug;
+
+use crate::error::{AssemblerError, SourceLocation};
+
+/// A line of the program and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line<'a> {
+    /// Borrowed from the source for its own lines
+    pub text: Cow<'a, str>,
+    pub location: SourceLocation,
+    /// Bytes of its file the line takes up, without the line break
+    pub span: Range<usize>,
+    /// Whether a macro expanded to the line, whose text then isn't in any file and whose
+    /// span is the macro's use
+    pub expanded: bool,
+}
+
+/// The lines of `source`, `file` naming it in their locations
+pub(crate) fn source_lines(source: Cow<'_, str>, file: Option<Arc<str>>) -> Vec<Line<'_>> {
+    let mut start = 0;
+    let mut lines = Vec::new();
+    for (text, line) in source.split('\n').zip(1..) {
+        let span = start..start + text.len();
+        start = span.end + 1;
+        let text = match &source {
+            Cow::Borrowed(source) => Cow::Borrowed(&source[span.clone()]),
+            Cow::Owned(_) => Cow::Owned(text.to_string()),
+        };
+        lines.push(Line {
+            text,
+            location: SourceLocation {
+                file: file.clone(),
+                line,
+                col: 1,
+            },
+            span,
+            expanded: false,
+        });
+    }
+    lines
+}
+
+/// The files being included, for finding the ones `.include`s name and catching files that
+/// include themselves
+#[derive(Debug)]
+pub(crate) struct Includes<'p> {
+    /// Directory of the source, for its own includes
+    directory: PathBuf,
+    include_paths: &'p [PathBuf],
+    /// Files being included, outermost first
+    including: Vec<PathBuf>,
+}
+
+impl<'p> Includes<'p> {
+    /// Includes of the source read from the file at `path`, if any
+    pub fn new(path: Option<&Path>, include_paths: &'p [PathBuf]) -> Self {
+        Self {
+            directory: path
+                .and_then(Path::parent)
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            include_paths,
+            including: path
+                .map(|path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// The lines of the file `line` includes, `None` if it isn't an `.include`. The file
+    /// counts as being included until [`Includes::end`].
+    pub fn start(&mut self, line: &Line) -> anyhow::Result<Option<Vec<Line<'static>>>> {
+        let location = SourceLocation {
+            col: 1,
+            ..line.location.clone()
+        };
+        let Some(name) = included_file(&line.text, &location)? else {
+            return Ok(None);
+        };
+        // Next to the file the line is in, `main.s` has an empty parent, the working directory
+        let directory = match &line.location.file {
+            Some(file) => Path::new(file.as_ref()).parent(),
+            None => Some(self.directory.as_path()),
+        };
+        let directory = directory
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let Some(path) = find(name, directory, self.include_paths) else {
+            let searched: Vec<String> = std::iter::once(directory)
+                .chain(self.include_paths.iter().map(PathBuf::as_path))
+                .map(|directory| directory.display().to_string())
+                .collect();
+            return Err(include_error(
+                &format!(
+                    "can't find included file '{}' in {}",
+                    name,
+                    searched.join(", ")
+                ),
+                location,
+            ));
+        };
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.including.contains(&canonical) {
+            return Err(include_error(
+                &format!("'{}' includes itself", path.display()),
+                location,
+            ));
+        }
+        let text = fs::read_to_string(&path).map_err(|error| {
+            include_error(
+                &format!("can't read '{}': {}", path.display(), error),
+                location.clone(),
+            )
+        })?;
+        debug!(file = %path.display(), "including file");
+        self.including.push(canonical);
+        Ok(Some(source_lines(
+            Cow::Owned(text),
+            Some(path.display().to_string().into()),
+        )))
+    }
+
+    /// Ends the innermost file being included
+    pub fn end(&mut self) {
+        self.including.pop();
+    }
+}
+
+/// The name of the file `text` includes, if it's an `.include` line
+fn included_file<'a>(text: &'a str, location: &SourceLocation) -> anyhow::Result<Option<&'a str>> {
+    let statement = text.split('#').next().unwrap_or_default().trim();
+    let (directive, rest) = statement.split_once([' ', '\t']).unwrap_or((statement, ""));
+    if !directive.eq_ignore_ascii_case(".include") {
+        return Ok(None);
+    }
+    match rest
+        .trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    {
+        Some(name) if !name.is_empty() && !name.contains('"') => Ok(Some(name)),
+        _ => Err(include_error(
+            "'.include' expects a file name in double quotes",
+            location.clone(),
+        )),
+    }
+}
+
+/// Where `name` is, looking in `directory` and then the include paths
+fn find(name: &str, directory: &Path, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    let name = Path::new(name);
+    if name.is_absolute() {
+        return name.is_file().then(|| name.to_path_buf());
+    }
+    std::iter::once(directory)
+        .chain(include_paths.iter().map(PathBuf::as_path))
+        .map(|directory| directory.join(name))
+        .find(|path| path.is_file())
+}
+
+fn include_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssemblerOptions, preprocess::preprocess};
+
+    fn resolve_includes<'a>(
+        source: &'a str,
+        path: Option<&Path>,
+        include_paths: &[PathBuf],
+    ) -> anyhow::Result<Vec<Line<'a>>> {
+        let options = AssemblerOptions {
+            include_paths: include_paths.to_vec(),
+            ..AssemblerOptions::default()
+        };
+        preprocess(source, path, &options)
+    }
+
+    #[test]
+    fn test_resolve_includes() {
+        let directory = std::env::temp_dir().join(format!("rv-include-{}", std::process::id()));
+        let library = directory.join("lib");
+        fs::create_dir_all(&library).unwrap();
+        fs::write(directory.join("defs.s"), ".include \"more.s\"\n.equ A, 1").unwrap();
+        fs::write(library.join("more.s"), ".equ B, 2").unwrap();
+        fs::write(directory.join("loop.s"), ".include \"loop.s\"").unwrap();
+        let main = directory.join("main.s");
+
+        let lines = resolve_includes(
+            "  .INCLUDE \"defs.s\"  # constants\nnop",
+            Some(&main),
+            std::slice::from_ref(&library),
+        )
+        .unwrap();
+        let lines: Vec<(&str, Option<&str>, u64)> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line.text.as_ref(),
+                    line.location.file.as_deref(),
+                    line.location.line,
+                )
+            })
+            .collect();
+        let more = library.join("more.s").display().to_string();
+        let defs = directory.join("defs.s").display().to_string();
+        assert_eq!(
+            lines,
+            [
+                (".equ B, 2", Some(more.as_str()), 1),
+                (".equ A, 1", Some(defs.as_str()), 2),
+                ("nop", None, 2),
+            ]
+        );
+
+        let error = resolve_includes(".include \"missing.s\"", Some(&main), &[]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("can't find included file 'missing.s'")
+        );
+        let error = resolve_includes(
+            ".include \"missing.s\"",
+            Some(Path::new("main.s")),
+            std::slice::from_ref(&library),
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains(&format!(
+                "can't find included file 'missing.s' in ., {}",
+                library.display()
+            )),
+            "{}",
+            error
+        );
+        let error = resolve_includes("\n.include \"loop.s\"", Some(&main), &[]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("includes itself at line 1, column 1 of")
+        );
+        assert!(resolve_includes(".include defs.s", Some(&main), &[]).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,568 @@
+//! The instructions the assembler knows, defined once.
+//!
+//! Each entry of [`INSTRUCTIONS`] gives a mnemonic's format, opcode and the funct fields
+//! that tell it apart from the others sharing the opcode. The tokenizer classifies
+//! mnemonics by the table, the parser and encoder take operands in the order the format
+//! gives and place them, and the disassembler matches words against the bits an entry
+//! fixes. Adding an instruction is adding its entry.
+
+use crate::encoder::Xlen;
+
+/// Register file an operand is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RegisterFile {
+    Integer,
+    Float,
+}
+
+/// What the funct3 field of a floating point operation holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Funct3 {
+    Fixed(u32),
+    /// A rounding mode operand, with the mode used when it's left out
+    Rounding(u32),
+}
+
+/// Instruction formats of the RV32I base ISA, which the M extension shares, and the F
+/// and D extensions' formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// rd, rs1, rs2 (funct3, funct7)
+    R(u32, u32),
+    /// rd, rs1, imm (funct3)
+    I(u32),
+    /// rd, rs1, shamt (funct3, funct7)
+    Shift(u32, u32),
+    /// rd, offset(rs1) (funct3)
+    Load(u32),
+    /// rs2, offset(rs1) (funct3)
+    Store(u32),
+    /// rs1, rs2, label (funct3)
+    Branch(u32),
+    /// rd, imm
+    Upper,
+    /// rd, label
+    Jal,
+    /// rd, rs1, imm or rd, offset(rs1)
+    Jalr,
+    /// No operands (imm)
+    System(u32),
+    /// pred, succ, sets of the `iorw` accesses to order, or no operands for all of them
+    Fence,
+    /// No operands, a fence of fixed kind (funct3, imm)
+    FixedFence(u32, u32),
+    /// Floating point operation: rd, rs1 and unless `rs2` is fixed (single-operand
+    /// operations) fs2, optionally followed by a rounding mode
+    Fp {
+        funct7: u32,
+        funct3: Funct3,
+        rs2: Option<u32>,
+        rd: RegisterFile,
+        rs1: RegisterFile,
+    },
+    /// fd, fs1, fs2, fs3[, rm] (fmt)
+    FpFused(u32),
+    /// fd, offset(rs1) (funct3)
+    FpLoad(u32),
+    /// fs2, offset(rs1) (funct3)
+    FpStore(u32),
+    /// rd, (rs1) (funct7 including the aq and rl bits)
+    LoadReserved(u32),
+    /// rd, rs2, (rs1) (funct7 including the aq and rl bits)
+    Atomic(u32),
+    /// rd, csr, rs1 (funct3)
+    Csr(u32),
+    /// rd, csr, 5 bit unsigned immediate (funct3)
+    CsrImmediate(u32),
+}
+
+/// An instruction the assembler knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Definition {
+    pub mnemonic: &'static str,
+    pub opcode: u32,
+    pub format: Format,
+    /// Only RV64 has the instruction
+    pub rv64: bool,
+}
+
+pub(crate) const OPCODE_LUI: u32 = 0b0110111;
+pub(crate) const OPCODE_AUIPC: u32 = 0b0010111;
+pub(crate) const OPCODE_JAL: u32 = 0b1101111;
+pub(crate) const OPCODE_JALR: u32 = 0b1100111;
+pub(crate) const OPCODE_BRANCH: u32 = 0b1100011;
+pub(crate) const OPCODE_LOAD: u32 = 0b0000011;
+pub(crate) const OPCODE_STORE: u32 = 0b0100011;
+pub(crate) const OPCODE_OP_IMM: u32 = 0b0010011;
+pub(crate) const OPCODE_OP: u32 = 0b0110011;
+pub(crate) const OPCODE_OP_IMM_32: u32 = 0b0011011;
+pub(crate) const OPCODE_OP_32: u32 = 0b0111011;
+pub(crate) const OPCODE_SYSTEM: u32 = 0b1110011;
+pub(crate) const OPCODE_MISC_MEM: u32 = 0b0001111;
+pub(crate) const OPCODE_LOAD_FP: u32 = 0b0000111;
+pub(crate) const OPCODE_STORE_FP: u32 = 0b0100111;
+pub(crate) const OPCODE_FMADD: u32 = 0b1000011;
+pub(crate) const OPCODE_FMSUB: u32 = 0b1000111;
+pub(crate) const OPCODE_FNMSUB: u32 = 0b1001011;
+pub(crate) const OPCODE_FNMADD: u32 = 0b1001111;
+pub(crate) const OPCODE_OP_FP: u32 = 0b1010011;
+pub(crate) const OPCODE_AMO: u32 = 0b0101111;
+
+/// `fmt` field of single and double precision operations
+const FMT_S: u32 = 0b00;
+const FMT_D: u32 = 0b01;
+
+/// Rounding mode taken from fcsr at run time
+pub(crate) const DYNAMIC_ROUNDING: u32 = 0b111;
+
+const DYNAMIC: Funct3 = Funct3::Rounding(DYNAMIC_ROUNDING);
+/// Conversions that are always exact default to round to nearest, like GNU as does
+const EXACT: Funct3 = Funct3::Rounding(0b000);
+
+/// The aq and rl bits at the bottom of an atomic instruction's funct7
+const ORDERING: u32 = 0b11;
+
+const fn base(mnemonic: &'static str, opcode: u32, format: Format) -> Definition {
+    Definition {
+        mnemonic,
+        opcode,
+        format,
+        rv64: false,
+    }
+}
+
+const fn rv64(mnemonic: &'static str, opcode: u32, format: Format) -> Definition {
+    Definition {
+        rv64: true,
+        ..base(mnemonic, opcode, format)
+    }
+}
+
+/// A floating point operation of the OP-FP opcode. `fmt` is the destination's precision
+/// for conversions from integers and between precisions, the source's for conversions to
+/// integers.
+const fn fp(
+    mnemonic: &'static str,
+    funct5: u32,
+    fmt: u32,
+    funct3: Funct3,
+    rs2: Option<u32>,
+    rd: RegisterFile,
+    rs1: RegisterFile,
+) -> Definition {
+    let format = Format::Fp {
+        funct7: funct5 << 2 | fmt,
+        funct3,
+        rs2,
+        rd,
+        rs1,
+    };
+    base(mnemonic, OPCODE_OP_FP, format)
+}
+
+/// An atomic memory operation of the A extension, written without an ordering suffix
+const fn amo(mnemonic: &'static str, funct5: u32) -> Definition {
+    base(mnemonic, OPCODE_AMO, Format::Atomic(funct5 << 2))
+}
+
+use Format::*;
+use RegisterFile::{Float, Integer};
+
+/// Every instruction the assembler knows. The A extension's may also be written with an
+/// ".aq", ".rl" or ".aqrl" ordering suffix, see [`lookup`].
+pub(crate) const INSTRUCTIONS: &[Definition] = &[
+    // RV32I
+    base("lui", OPCODE_LUI, Upper),
+    base("auipc", OPCODE_AUIPC, Upper),
+    base("jal", OPCODE_JAL, Jal),
+    base("jalr", OPCODE_JALR, Jalr),
+    base("beq", OPCODE_BRANCH, Branch(0x0)),
+    base("bne", OPCODE_BRANCH, Branch(0x1)),
+    base("blt", OPCODE_BRANCH, Branch(0x4)),
+    base("bge", OPCODE_BRANCH, Branch(0x5)),
+    base("bltu", OPCODE_BRANCH, Branch(0x6)),
+    base("bgeu", OPCODE_BRANCH, Branch(0x7)),
+    base("lb", OPCODE_LOAD, Load(0x0)),
+    base("lh", OPCODE_LOAD, Load(0x1)),
+    base("lw", OPCODE_LOAD, Load(0x2)),
+    base("lbu", OPCODE_LOAD, Load(0x4)),
+    base("lhu", OPCODE_LOAD, Load(0x5)),
+    base("sb", OPCODE_STORE, Store(0x0)),
+    base("sh", OPCODE_STORE, Store(0x1)),
+    base("sw", OPCODE_STORE, Store(0x2)),
+    base("addi", OPCODE_OP_IMM, I(0x0)),
+    base("slti", OPCODE_OP_IMM, I(0x2)),
+    base("sltiu", OPCODE_OP_IMM, I(0x3)),
+    base("xori", OPCODE_OP_IMM, I(0x4)),
+    base("ori", OPCODE_OP_IMM, I(0x6)),
+    base("andi", OPCODE_OP_IMM, I(0x7)),
+    base("slli", OPCODE_OP_IMM, Shift(0x1, 0x00)),
+    base("srli", OPCODE_OP_IMM, Shift(0x5, 0x00)),
+    base("srai", OPCODE_OP_IMM, Shift(0x5, 0x20)),
+    base("add", OPCODE_OP, R(0x0, 0x00)),
+    base("sub", OPCODE_OP, R(0x0, 0x20)),
+    base("sll", OPCODE_OP, R(0x1, 0x00)),
+    base("slt", OPCODE_OP, R(0x2, 0x00)),
+    base("sltu", OPCODE_OP, R(0x3, 0x00)),
+    base("xor", OPCODE_OP, R(0x4, 0x00)),
+    base("srl", OPCODE_OP, R(0x5, 0x00)),
+    base("sra", OPCODE_OP, R(0x5, 0x20)),
+    base("or", OPCODE_OP, R(0x6, 0x00)),
+    base("and", OPCODE_OP, R(0x7, 0x00)),
+    base("ecall", OPCODE_SYSTEM, System(0)),
+    base("ebreak", OPCODE_SYSTEM, System(1)),
+    base("fence", OPCODE_MISC_MEM, Fence),
+    base("fence.tso", OPCODE_MISC_MEM, FixedFence(0x0, 0x833)),
+    // Zifencei
+    base("fence.i", OPCODE_MISC_MEM, FixedFence(0x1, 0x000)),
+    // Privileged
+    base("sret", OPCODE_SYSTEM, System(0x102)),
+    base("mret", OPCODE_SYSTEM, System(0x302)),
+    base("wfi", OPCODE_SYSTEM, System(0x105)),
+    // Zicsr
+    base("csrrw", OPCODE_SYSTEM, Csr(0x1)),
+    base("csrrs", OPCODE_SYSTEM, Csr(0x2)),
+    base("csrrc", OPCODE_SYSTEM, Csr(0x3)),
+    base("csrrwi", OPCODE_SYSTEM, CsrImmediate(0x5)),
+    base("csrrsi", OPCODE_SYSTEM, CsrImmediate(0x6)),
+    base("csrrci", OPCODE_SYSTEM, CsrImmediate(0x7)),
+    // M extension
+    base("mul", OPCODE_OP, R(0x0, 0x01)),
+    base("mulh", OPCODE_OP, R(0x1, 0x01)),
+    base("mulhsu", OPCODE_OP, R(0x2, 0x01)),
+    base("mulhu", OPCODE_OP, R(0x3, 0x01)),
+    base("div", OPCODE_OP, R(0x4, 0x01)),
+    base("divu", OPCODE_OP, R(0x5, 0x01)),
+    base("rem", OPCODE_OP, R(0x6, 0x01)),
+    base("remu", OPCODE_OP, R(0x7, 0x01)),
+    // RV64I and RV64M
+    rv64("ld", OPCODE_LOAD, Load(0x3)),
+    rv64("lwu", OPCODE_LOAD, Load(0x6)),
+    rv64("sd", OPCODE_STORE, Store(0x3)),
+    rv64("addiw", OPCODE_OP_IMM_32, I(0x0)),
+    rv64("slliw", OPCODE_OP_IMM_32, Shift(0x1, 0x00)),
+    rv64("srliw", OPCODE_OP_IMM_32, Shift(0x5, 0x00)),
+    rv64("sraiw", OPCODE_OP_IMM_32, Shift(0x5, 0x20)),
+    rv64("addw", OPCODE_OP_32, R(0x0, 0x00)),
+    rv64("subw", OPCODE_OP_32, R(0x0, 0x20)),
+    rv64("sllw", OPCODE_OP_32, R(0x1, 0x00)),
+    rv64("srlw", OPCODE_OP_32, R(0x5, 0x00)),
+    rv64("sraw", OPCODE_OP_32, R(0x5, 0x20)),
+    rv64("mulw", OPCODE_OP_32, R(0x0, 0x01)),
+    rv64("divw", OPCODE_OP_32, R(0x4, 0x01)),
+    rv64("divuw", OPCODE_OP_32, R(0x5, 0x01)),
+    rv64("remw", OPCODE_OP_32, R(0x6, 0x01)),
+    rv64("remuw", OPCODE_OP_32, R(0x7, 0x01)),
+    // A extension
+    base("lr.w", OPCODE_AMO, LoadReserved(0x02 << 2)),
+    amo("sc.w", 0x03),
+    amo("amoswap.w", 0x01),
+    amo("amoadd.w", 0x00),
+    amo("amoxor.w", 0x04),
+    amo("amoand.w", 0x0c),
+    amo("amoor.w", 0x08),
+    amo("amomin.w", 0x10),
+    amo("amomax.w", 0x14),
+    amo("amominu.w", 0x18),
+    amo("amomaxu.w", 0x1c),
+    // F extension
+    base("flw", OPCODE_LOAD_FP, FpLoad(0x2)),
+    base("fsw", OPCODE_STORE_FP, FpStore(0x2)),
+    base("fmadd.s", OPCODE_FMADD, FpFused(FMT_S)),
+    base("fmsub.s", OPCODE_FMSUB, FpFused(FMT_S)),
+    base("fnmsub.s", OPCODE_FNMSUB, FpFused(FMT_S)),
+    base("fnmadd.s", OPCODE_FNMADD, FpFused(FMT_S)),
+    fp("fadd.s", 0x00, FMT_S, DYNAMIC, None, Float, Float),
+    fp("fsub.s", 0x01, FMT_S, DYNAMIC, None, Float, Float),
+    fp("fmul.s", 0x02, FMT_S, DYNAMIC, None, Float, Float),
+    fp("fdiv.s", 0x03, FMT_S, DYNAMIC, None, Float, Float),
+    fp("fsqrt.s", 0x0b, FMT_S, DYNAMIC, Some(0), Float, Float),
+    fp("fsgnj.s", 0x04, FMT_S, Funct3::Fixed(0), None, Float, Float),
+    fp(
+        "fsgnjn.s",
+        0x04,
+        FMT_S,
+        Funct3::Fixed(1),
+        None,
+        Float,
+        Float,
+    ),
+    fp(
+        "fsgnjx.s",
+        0x04,
+        FMT_S,
+        Funct3::Fixed(2),
+        None,
+        Float,
+        Float,
+    ),
+    fp("fmin.s", 0x05, FMT_S, Funct3::Fixed(0), None, Float, Float),
+    fp("fmax.s", 0x05, FMT_S, Funct3::Fixed(1), None, Float, Float),
+    fp("fcvt.w.s", 0x18, FMT_S, DYNAMIC, Some(0), Integer, Float),
+    fp("fcvt.wu.s", 0x18, FMT_S, DYNAMIC, Some(1), Integer, Float),
+    fp(
+        "fmv.x.w",
+        0x1c,
+        FMT_S,
+        Funct3::Fixed(0),
+        Some(0),
+        Integer,
+        Float,
+    ),
+    fp("feq.s", 0x14, FMT_S, Funct3::Fixed(2), None, Integer, Float),
+    fp("flt.s", 0x14, FMT_S, Funct3::Fixed(1), None, Integer, Float),
+    fp("fle.s", 0x14, FMT_S, Funct3::Fixed(0), None, Integer, Float),
+    fp(
+        "fclass.s",
+        0x1c,
+        FMT_S,
+        Funct3::Fixed(1),
+        Some(0),
+        Integer,
+        Float,
+    ),
+    fp("fcvt.s.w", 0x1a, FMT_S, DYNAMIC, Some(0), Float, Integer),
+    fp("fcvt.s.wu", 0x1a, FMT_S, DYNAMIC, Some(1), Float, Integer),
+    fp(
+        "fmv.w.x",
+        0x1e,
+        FMT_S,
+        Funct3::Fixed(0),
+        Some(0),
+        Float,
+        Integer,
+    ),
+    // D extension
+    base("fld", OPCODE_LOAD_FP, FpLoad(0x3)),
+    base("fsd", OPCODE_STORE_FP, FpStore(0x3)),
+    base("fmadd.d", OPCODE_FMADD, FpFused(FMT_D)),
+    base("fmsub.d", OPCODE_FMSUB, FpFused(FMT_D)),
+    base("fnmsub.d", OPCODE_FNMSUB, FpFused(FMT_D)),
+    base("fnmadd.d", OPCODE_FNMADD, FpFused(FMT_D)),
+    fp("fadd.d", 0x00, FMT_D, DYNAMIC, None, Float, Float),
+    fp("fsub.d", 0x01, FMT_D, DYNAMIC, None, Float, Float),
+    fp("fmul.d", 0x02, FMT_D, DYNAMIC, None, Float, Float),
+    fp("fdiv.d", 0x03, FMT_D, DYNAMIC, None, Float, Float),
+    fp("fsqrt.d", 0x0b, FMT_D, DYNAMIC, Some(0), Float, Float),
+    fp("fsgnj.d", 0x04, FMT_D, Funct3::Fixed(0), None, Float, Float),
+    fp(
+        "fsgnjn.d",
+        0x04,
+        FMT_D,
+        Funct3::Fixed(1),
+        None,
+        Float,
+        Float,
+    ),
+    fp(
+        "fsgnjx.d",
+        0x04,
+        FMT_D,
+        Funct3::Fixed(2),
+        None,
+        Float,
+        Float,
+    ),
+    fp("fmin.d", 0x05, FMT_D, Funct3::Fixed(0), None, Float, Float),
+    fp("fmax.d", 0x05, FMT_D, Funct3::Fixed(1), None, Float, Float),
+    fp("fcvt.s.d", 0x08, FMT_S, DYNAMIC, Some(1), Float, Float),
+    fp("fcvt.d.s", 0x08, FMT_D, EXACT, Some(0), Float, Float),
+    fp("feq.d", 0x14, FMT_D, Funct3::Fixed(2), None, Integer, Float),
+    fp("flt.d", 0x14, FMT_D, Funct3::Fixed(1), None, Integer, Float),
+    fp("fle.d", 0x14, FMT_D, Funct3::Fixed(0), None, Integer, Float),
+    fp(
+        "fclass.d",
+        0x1c,
+        FMT_D,
+        Funct3::Fixed(1),
+        Some(0),
+        Integer,
+        Float,
+    ),
+    fp("fcvt.w.d", 0x18, FMT_D, DYNAMIC, Some(0), Integer, Float),
+    fp("fcvt.wu.d", 0x18, FMT_D, DYNAMIC, Some(1), Integer, Float),
+    fp("fcvt.d.w", 0x1a, FMT_D, EXACT, Some(0), Float, Integer),
+    fp("fcvt.d.wu", 0x1a, FMT_D, EXACT, Some(1), Float, Integer),
+];
+
+impl Definition {
+    /// The bits of an encoding the instruction fixes, as a mask and their values
+    fn fixed_bits(&self, xlen: Xlen) -> (u32, u32) {
+        const OPCODE: u32 = 0x7f;
+        const FUNCT3: u32 = 0x7 << 12;
+        const RS2: u32 = 0x1f << 20;
+        const FUNCT7: u32 = 0x7f << 25;
+        const RD: u32 = 0x1f << 7;
+        const RS1: u32 = 0x1f << 15;
+        const FM: u32 = 0xf << 28;
+        let (mask, bits) = match self.format {
+            R(funct3, funct7) => (FUNCT3 | FUNCT7, funct3 << 12 | funct7 << 25),
+            I(funct3) | Load(funct3) | Store(funct3) | Branch(funct3) | FpLoad(funct3)
+            | FpStore(funct3) | Csr(funct3) | CsrImmediate(funct3) => (FUNCT3, funct3 << 12),
+            Shift(funct3, funct7) => {
+                // RV64 shifts of whole registers take the sixth bit of the shift amount from
+                // funct7
+                let funct7_mask = match (xlen, self.opcode) {
+                    (Xlen::Rv64, OPCODE_OP_IMM) => FUNCT7 & !(1 << 25),
+                    _ => FUNCT7,
+                };
+                (FUNCT3 | funct7_mask, funct3 << 12 | funct7 << 25)
+            }
+            Upper | Jal => (0, 0),
+            Jalr => (FUNCT3, 0),
+            System(imm) => (!OPCODE, imm << 20),
+            Fence => (FM | RS1 | FUNCT3 | RD, 0),
+            FixedFence(funct3, imm) => (!OPCODE, funct3 << 12 | imm << 20),
+            Fp {
+                funct7,
+                funct3,
+                rs2,
+                ..
+            } => {
+                let (funct3_mask, funct3) = match funct3 {
+                    Funct3::Fixed(funct3) => (FUNCT3, funct3 << 12),
+                    Funct3::Rounding(_) => (0, 0),
+                };
+                let (rs2_mask, rs2) = rs2.map_or((0, 0), |rs2| (RS2, rs2 << 20));
+                (FUNCT7 | funct3_mask | rs2_mask, funct7 << 25 | funct3 | rs2)
+            }
+            FpFused(fmt) => (0b11 << 25, fmt << 25),
+            LoadReserved(funct7) => (
+                FUNCT3 | RS2 | FUNCT7 & !(ORDERING << 25),
+                0x2 << 12 | funct7 << 25,
+            ),
+            Atomic(funct7) => (
+                FUNCT3 | FUNCT7 & !(ORDERING << 25),
+                0x2 << 12 | funct7 << 25,
+            ),
+        };
+        (OPCODE | mask, self.opcode | bits)
+    }
+}
+
+/// The definition of `mnemonic`, the ordering suffix of an atomic instruction folded into
+/// the aq and rl bits of its funct7
+pub(crate) fn lookup(mnemonic: &str) -> Option<Definition> {
+    if let Some(definition) = INSTRUCTIONS
+        .iter()
+        .find(|definition| definition.mnemonic == mnemonic)
+    {
+        return Some(*definition);
+    }
+    let (operation, ordering) = match mnemonic.rsplit_once('.')? {
+        (operation, "aq") => (operation, 0b10),
+        (operation, "rl") => (operation, 0b01),
+        (operation, "aqrl") => (operation, 0b11),
+        _ => return None,
+    };
+    let definition = INSTRUCTIONS
+        .iter()
+        .find(|definition| definition.mnemonic == operation)?;
+    let format = match definition.format {
+        LoadReserved(funct7) => LoadReserved(funct7 | ordering),
+        Atomic(funct7) => Atomic(funct7 | ordering),
+        _ => return None,
+    };
+    Some(Definition {
+        format,
+        ..*definition
+    })
+}
+
+/// The instruction `word` encodes on an `xlen` bit target, `None` if the assembler doesn't
+/// know it. Atomic instructions are returned without their ordering, the aq and rl bits of
+/// `word` hold it.
+pub(crate) fn decode(word: u32, xlen: Xlen) -> Option<Definition> {
+    INSTRUCTIONS
+        .iter()
+        .filter(|definition| xlen == Xlen::Rv64 || !definition.rv64)
+        .find(|definition| {
+            let (mask, bits) = definition.fixed_bits(xlen);
+            word & mask == bits
+        })
+        .copied()
+}
+
+/// Whether the mnemonic names a built-in instruction, of any XLEN
+pub(crate) fn is_known(mnemonic: &str) -> bool {
+    lookup(mnemonic).is_some()
+}
+
+/// Whether the mnemonic names an instruction that only exists on RV64
+pub(crate) fn is_rv64_only(mnemonic: &str) -> bool {
+    lookup(mnemonic).is_some_and(|definition| definition.rv64)
+}
+
+/// Whether the instruction's second operand names a CSR
+pub(crate) fn takes_csr(mnemonic: &str) -> bool {
+    matches!(
+        lookup(mnemonic),
+        Some(Definition {
+            format: Csr(_) | CsrImmediate(_),
+            ..
+        })
+    )
+}
+
+/// Rounding mode the instruction is encoded with when its operand is left out, `None` if it
+/// doesn't take one
+pub(crate) fn default_rounding_mode(format: Format) -> Option<u32> {
+    match format {
+        FpFused(_) => Some(DYNAMIC_ROUNDING),
+        Fp {
+            funct3: Funct3::Rounding(mode),
+            ..
+        } => Some(mode),
+        _ => None,
+    }
+}
+
+/// Whether the instruction takes an optional rounding mode as its last operand
+pub(crate) fn takes_rounding_mode(mnemonic: &str) -> bool {
+    lookup(mnemonic).is_some_and(|definition| default_rounding_mode(definition.format).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        // Every entry decodes back to itself from the bits it fixes
+        for definition in INSTRUCTIONS {
+            let (_, bits) = definition.fixed_bits(Xlen::Rv64);
+            assert_eq!(
+                decode(bits, Xlen::Rv64).map(|decoded| decoded.mnemonic),
+                Some(definition.mnemonic)
+            );
+            assert_eq!(
+                INSTRUCTIONS
+                    .iter()
+                    .filter(|other| other.mnemonic == definition.mnemonic)
+                    .count(),
+                1
+            );
+        }
+        assert_eq!(
+            lookup("amoswap.w.aqrl").map(|definition| definition.format),
+            Some(Atomic(0x01 << 2 | 0b11))
+        );
+        assert_eq!(lookup("addi.aq"), None);
+        assert!(is_rv64_only("sraiw") && !is_rv64_only("srai"));
+        assert!(takes_csr("csrrsi") && takes_rounding_mode("fmadd.d"));
+        assert!(!takes_rounding_mode("fsgnj.s"));
+        assert_eq!(
+            decode(0x8330000f, Xlen::Rv32).map(|decoded| decoded.mnemonic),
+            Some("fence.tso")
+        );
+        // A fence with rd set or of another fence mode isn't one
+        assert_eq!(decode(0x0ff0008f, Xlen::Rv32), None);
+        assert_eq!(decode(0x1ff0000f, Xlen::Rv32), None);
+        // RV64's sixth shift amount bit isn't an RV32 instruction
+        assert_eq!(decode(0x02051513, Xlen::Rv32), None);
+        assert_eq!(
+            decode(0x02051513, Xlen::Rv64).map(|decoded| decoded.mnemonic),
+            Some("slli")
+        );
+    }
+}
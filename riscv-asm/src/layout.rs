@@ -0,0 +1,429 @@
+//! Sizes of the instructions whose encoding depends on addresses.
+//!
+//! `call` and `tail` are a single `jal` when their label is within its ±1 MiB reach, an
+//! AUIPC and a JALR otherwise. `li` of a label or of an expression only known once addresses
+//! are, and `la` of a `.equ` constant, take as few instructions as the value needs. `la` of
+//! a label stays an AUIPC and an ADDI, which keeps the program working wherever it is
+//! loaded. With [`AssemblerOptions::relax_branches`], conditional branches to labels beyond
+//! their ±4 KiB reach become a branch on the opposite condition over a `jal`.
+//!
+//! Sizes depend on addresses and addresses on sizes, so the program is laid out again until
+//! no instruction needs more room than the last layout gave it. Instructions only ever grow,
+//! which ends the iteration; one whose final form is shorter than its room, because the
+//! code growing after it moved a label closer to a value that fits fewer instructions, is
+//! padded with `nop`s.
+
+use std::collections::BTreeMap;
+
+use tracing::debug;
+
+use crate::{
+    assembler::{AssemblerOptions, MemoryMap, allocate_memory},
+    encoder::{BRANCH_RANGE, Xlen},
+    error::AssemblerError,
+    parser::{Instruction, Operand, ParsedItem, load_immediate_instructions},
+    symbol_table::SymbolTable,
+};
+
+/// Offsets a `jal` reaches, 21 bits in steps of 2
+const JUMP_RANGE: std::ops::RangeInclusive<i64> = -(1 << 20)..=(1 << 20) - 2;
+
+/// Register `tail` goes through, t1 like GNU as, leaving ra alone
+const TAIL_REGISTER: u8 = 6;
+
+/// Lays out the program `parsed_items` stands for, giving every instruction the size it
+/// needs and every label its address. Returns the items with the sized instructions in
+/// their final form, and where each of them is.
+pub(crate) fn lay_out(
+    parsed_items: &[ParsedItem],
+    symbol_table: &mut SymbolTable,
+    options: &AssemblerOptions,
+) -> anyhow::Result<(Vec<ParsedItem>, MemoryMap)> {
+    // Instructions given more than one instruction's room so far
+    let mut sizes: BTreeMap<usize, usize> = BTreeMap::new();
+    loop {
+        let (items, starts) = expand(parsed_items, &sizes, symbol_table, options.xlen);
+        let mut memory_map = MemoryMap::with_section_bases(options.section_bases.clone());
+        allocate_memory(&mut memory_map, symbol_table, &items, &options.plugins)?;
+        let mut grown = false;
+        for (index, item) in parsed_items.iter().enumerate() {
+            let ParsedItem::Instruction(instruction) = item else {
+                continue;
+            };
+            let address = memory_map.address_of(starts[index]);
+            let needed = needed_size(instruction, address, symbol_table, options)?;
+            if needed > sizes.get(&index).copied().unwrap_or(1) {
+                debug!(location = %instruction.location, mnemonic = instruction.mnemonic, needed, "growing instruction");
+                sizes.insert(index, needed);
+                grown = true;
+            }
+        }
+        if !grown {
+            // The addresses are final, and with them the values sized instructions load
+            let (items, _) = expand(parsed_items, &sizes, symbol_table, options.xlen);
+            return Ok((items, memory_map));
+        }
+    }
+}
+
+/// `parsed_items` with every instruction layout decides about in its worst case form, for
+/// relocatable objects, whose addresses the linker decides. `li` of a label loads its
+/// `%hi` and `%lo` parts.
+pub(crate) fn worst_case(
+    parsed_items: &[ParsedItem],
+    symbol_table: &SymbolTable,
+    xlen: Xlen,
+) -> anyhow::Result<Vec<ParsedItem>> {
+    let mut expanded = Vec::with_capacity(parsed_items.len());
+    for item in parsed_items {
+        let ParsedItem::Instruction(instruction) = item else {
+            expanded.push(item.clone());
+            continue;
+        };
+        let instructions = match (instruction.mnemonic.as_str(), &instruction.operands[..]) {
+            ("call" | "tail", _) => far_jump(instruction),
+            ("la", [Operand::Register(rd), Operand::Symbol(symbol)]) => {
+                match symbol_table.constant(symbol) {
+                    Some(value) => {
+                        load_immediate_instructions(*rd, value, xlen, &instruction.location)?
+                    }
+                    None => pc_relative_address(*rd, symbol, instruction),
+                }
+            }
+            ("li", [Operand::Register(rd), Operand::Symbol(symbol)]) => {
+                match symbol_table.constant(symbol) {
+                    Some(value) => {
+                        load_immediate_instructions(*rd, value, xlen, &instruction.location)?
+                    }
+                    None => vec![
+                        with_operands(
+                            instruction,
+                            "lui",
+                            vec![Operand::Register(*rd), Operand::Hi(symbol.clone())],
+                        ),
+                        with_operands(
+                            instruction,
+                            "addi",
+                            vec![
+                                Operand::Register(*rd),
+                                Operand::Register(*rd),
+                                Operand::Lo {
+                                    symbol: symbol.clone(),
+                                    base: None,
+                                },
+                            ],
+                        ),
+                    ],
+                }
+            }
+            ("li", _) => {
+                return Err(AssemblerError::ParserError {
+                    message: "'li' of an expression of labels needs a linked program, \
+                              load the label with 'la' and add to it"
+                        .to_string(),
+                    location: instruction.location.clone(),
+                }
+                .into());
+            }
+            _ => vec![instruction.clone()],
+        };
+        expanded.extend(instructions.into_iter().map(ParsedItem::Instruction));
+    }
+    Ok(expanded)
+}
+
+/// `parsed_items` with the instructions layout decides about in the form `sizes` gives them
+/// room for, and the index of each item's first instruction in the result
+fn expand(
+    parsed_items: &[ParsedItem],
+    sizes: &BTreeMap<usize, usize>,
+    symbol_table: &SymbolTable,
+    xlen: Xlen,
+) -> (Vec<ParsedItem>, Vec<usize>) {
+    let mut expanded = Vec::with_capacity(parsed_items.len());
+    let mut starts = Vec::with_capacity(parsed_items.len());
+    for (index, item) in parsed_items.iter().enumerate() {
+        starts.push(expanded.len());
+        let ParsedItem::Instruction(instruction) = item else {
+            expanded.push(item.clone());
+            continue;
+        };
+        let size = sizes.get(&index).copied().unwrap_or(1);
+        let mut instructions = form(instruction, size, symbol_table, xlen);
+        while instructions.len() < size {
+            instructions.push(with_operands(
+                instruction,
+                "addi",
+                vec![
+                    Operand::Register(0),
+                    Operand::Register(0),
+                    Operand::Immediate(0),
+                ],
+            ));
+        }
+        expanded.extend(instructions.into_iter().map(ParsedItem::Instruction));
+    }
+    (expanded, starts)
+}
+
+/// The instructions `instruction` stands for with room for `size` of them. Values that
+/// can't be known yet count as 0, [`needed_size`] reports what is wrong with them once
+/// addresses are known.
+fn form(
+    instruction: &Instruction,
+    size: usize,
+    symbol_table: &SymbolTable,
+    xlen: Xlen,
+) -> Vec<Instruction> {
+    match (instruction.mnemonic.as_str(), &instruction.operands[..]) {
+        ("call" | "tail", _) if size == 1 => {
+            let (rd, target) = match &instruction.operands[..] {
+                [rd, target] => (rd.clone(), target.clone()),
+                [target] => (Operand::Register(0), target.clone()),
+                _ => unreachable!(
+                    "the parser checked the operands of '{}'",
+                    instruction.mnemonic
+                ),
+            };
+            vec![with_operands(instruction, "jal", vec![rd, target])]
+        }
+        ("call" | "tail", _) => far_jump(instruction),
+        ("la", [Operand::Register(rd), Operand::Symbol(symbol)]) => {
+            match symbol_table.constant(symbol) {
+                Some(value) => load_immediate_instructions(*rd, value, xlen, &instruction.location)
+                    .unwrap_or_default(),
+                None => pc_relative_address(*rd, symbol, instruction),
+            }
+        }
+        ("li", [Operand::Register(rd), value]) => {
+            let value = operand_value(value, symbol_table).unwrap_or(0);
+            load_immediate_instructions(*rd, value, xlen, &instruction.location).unwrap_or_default()
+        }
+        (mnemonic, [rs1, rs2, target @ Operand::Symbol(_)]) if size == 2 => {
+            match inverse_branch(mnemonic) {
+                Some(inverse) => vec![
+                    with_operands(
+                        instruction,
+                        inverse,
+                        vec![rs1.clone(), rs2.clone(), Operand::Immediate(8)],
+                    ),
+                    with_operands(
+                        instruction,
+                        "jal",
+                        vec![Operand::Register(0), target.clone()],
+                    ),
+                ],
+                None => vec![instruction.clone()],
+            }
+        }
+        _ => vec![instruction.clone()],
+    }
+}
+
+/// Instructions `instruction` at `address` needs with the addresses `symbol_table` has
+fn needed_size(
+    instruction: &Instruction,
+    address: u32,
+    symbol_table: &SymbolTable,
+    options: &AssemblerOptions,
+) -> anyhow::Result<usize> {
+    let error = |message: String| AssemblerError::ParserError {
+        message,
+        location: instruction.location.clone(),
+    };
+    let offset = |target: &str| {
+        symbol_table
+            .address(target)
+            .map(|target| target as i64 - address as i64)
+    };
+    let size = match (instruction.mnemonic.as_str(), &instruction.operands[..]) {
+        ("call", [_, Operand::Symbol(target)]) | ("tail", [Operand::Symbol(target)]) => {
+            match offset(target) {
+                Some(offset) if JUMP_RANGE.contains(&offset) => 1,
+                _ => 2,
+            }
+        }
+        ("la", [Operand::Register(_), Operand::Symbol(symbol)]) => {
+            match symbol_table.constant(symbol) {
+                Some(_) => form(instruction, 1, symbol_table, options.xlen).len(),
+                None => 2,
+            }
+        }
+        ("li", [Operand::Register(rd), value]) => {
+            let value = operand_value(value, symbol_table).map_err(error)?;
+            load_immediate_instructions(*rd, value, options.xlen, &instruction.location)?.len()
+        }
+        (mnemonic, [_, _, Operand::Symbol(target)])
+            if options.relax_branches && inverse_branch(mnemonic).is_some() =>
+        {
+            match offset(target) {
+                Some(offset) if !BRANCH_RANGE.contains(&offset) => 2,
+                _ => 1,
+            }
+        }
+        _ => 1,
+    };
+    Ok(size)
+}
+
+/// The value of the operand of `li`
+fn operand_value(operand: &Operand, symbol_table: &SymbolTable) -> Result<i64, String> {
+    let value_of = |name: &str| symbol_table.address(name).map(i64::from);
+    match operand {
+        Operand::Symbol(name) => {
+            value_of(name).ok_or_else(|| format!("Undefined symbol: {}", name))
+        }
+        Operand::Expression(expression) => expression.evaluate(&value_of),
+        Operand::Immediate(value) => Ok(*value),
+        _ => Err("'li' expects a value".to_string()),
+    }
+}
+
+/// `call` or `tail` through an AUIPC, reaching anywhere in the address space
+fn far_jump(instruction: &Instruction) -> Vec<Instruction> {
+    let (rd, base, symbol) = match &instruction.operands[..] {
+        [Operand::Register(rd), Operand::Symbol(symbol)] => (*rd, *rd, symbol),
+        [Operand::Symbol(symbol)] => (0, TAIL_REGISTER, symbol),
+        _ => unreachable!(
+            "the parser checked the operands of '{}'",
+            instruction.mnemonic
+        ),
+    };
+    vec![
+        with_operands(
+            instruction,
+            "auipc",
+            vec![Operand::Register(base), Operand::PcrelHi(symbol.clone())],
+        ),
+        with_operands(
+            instruction,
+            "jalr",
+            vec![
+                Operand::Register(rd),
+                Operand::PcrelLo {
+                    symbol: symbol.clone(),
+                    base: Some(base),
+                },
+            ],
+        ),
+    ]
+}
+
+/// `la rd, symbol` relative to the PC
+fn pc_relative_address(rd: u8, symbol: &str, instruction: &Instruction) -> Vec<Instruction> {
+    vec![
+        with_operands(
+            instruction,
+            "auipc",
+            vec![Operand::Register(rd), Operand::PcrelHi(symbol.to_string())],
+        ),
+        with_operands(
+            instruction,
+            "addi",
+            vec![
+                Operand::Register(rd),
+                Operand::Register(rd),
+                Operand::PcrelLo {
+                    symbol: symbol.to_string(),
+                    base: None,
+                },
+            ],
+        ),
+    ]
+}
+
+/// An instruction at the location of `instruction`
+fn with_operands(instruction: &Instruction, mnemonic: &str, operands: Vec<Operand>) -> Instruction {
+    Instruction {
+        mnemonic: mnemonic.to_string(),
+        operands,
+        location: instruction.location.clone(),
+        operand_locations: Vec::new(),
+        compressed: None,
+    }
+}
+
+/// The conditional branch taken exactly when `mnemonic` isn't
+fn inverse_branch(mnemonic: &str) -> Option<&'static str> {
+    match mnemonic {
+        "beq" => Some("bne"),
+        "bne" => Some("beq"),
+        "blt" => Some("bge"),
+        "bge" => Some("blt"),
+        "bltu" => Some("bgeu"),
+        "bgeu" => Some("bltu"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssemblerOptions, assemble, assemble_program};
+
+    fn words(source: &str) -> Vec<u32> {
+        assemble(source)
+            .unwrap()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_minimal_forms() {
+        // jal ra, f; jal zero, f; ret
+        assert_eq!(
+            words("call f\ntail f\nf: ret"),
+            [0x008000ef, 0x0040006f, 0x00008067]
+        );
+        // addi a0, zero, 42; lui a1, 0x12345; addi a1, a1, 0x678
+        assert_eq!(
+            words("li a0, SMALL\nli a1, LARGE\n.equ SMALL, 42\n.equ LARGE, 0x12345678"),
+            [0x02a00513, 0x123455b7, 0x67858593]
+        );
+        // la of a constant loads its value, of a label the offset from the PC
+        assert_eq!(words(".equ PORT, 0x100\nla a0, PORT"), [0x10000513]);
+        assert_eq!(words("la a0, x\nx:"), [0x00000517, 0x00850513]);
+        // li of a label or an expression of labels loads its address: addi a0, zero, 8
+        assert_eq!(
+            words("li a0, end - start\nstart: nop\nnop\nend:"),
+            [0x00800513, 0x13, 0x13]
+        );
+    }
+
+    #[test]
+    fn test_far_calls_and_growth() {
+        let program = assemble_program(
+            "call far\n.space 0x100000\nfar: ret",
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        // auipc ra, 0x100; jalr ra, 8(ra)
+        assert_eq!(
+            &program.bytes[..8],
+            &[0x97, 0x00, 0x10, 0x00, 0xe7, 0x80, 0x80, 0x00]
+        );
+        assert_eq!(program.symbols.address("far"), Some(0x100008));
+
+        // `li` growing takes `far` out of the reach of a `jal`, so the call grows too
+        let program = assemble_program(
+            "call far\nli a0, end\n.space 0xffff6\nfar: ret\nend:",
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(program.symbols.address("end"), Some(0x10000a));
+        let word = |address: usize| {
+            u32::from_le_bytes(program.bytes[address..address + 4].try_into().unwrap())
+        };
+        // auipc ra, 0x100; jalr ra, 6(ra); lui a0, 0x100; addi a0, a0, 10
+        assert_eq!(
+            [word(0), word(4), word(8), word(12)],
+            [0x00100097, 0x006080e7, 0x00100537, 0x00a50513]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(assemble("li a0, LARGE\n.equ LARGE, 0x100000000").is_err());
+        assert!(assemble("li a0, 1 / (end - start)\nstart:\nend:").is_err());
+    }
+}
@@ -1,5 +1,34 @@
-#![feature(trim_prefix_suffix)]
+pub mod analysis;
 pub mod assembler;
+pub mod cache;
+pub mod compressed;
+pub mod conditional;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod elf;
+pub mod encoder;
 pub mod error;
+pub mod expression;
+pub mod include;
+pub mod isa;
+pub mod layout;
+pub mod listing;
+pub mod macros;
+pub mod parser;
+pub mod plugin;
+pub mod preprocess;
+pub mod register;
+pub mod section;
+pub mod source;
+pub mod suggest;
+pub mod symbol_table;
+#[cfg(test)]
+mod test_support;
 pub mod tokenizer;
-pub use assembler::assemble;
+pub mod xref;
+pub use assembler::{
+    AssembledProgram, AssemblerOptions, DataObject, SkippedLine, assemble, assemble_object,
+    assemble_program, assemble_with_options, parse,
+};
+pub use plugin::{AssemblerPlugin, Plugins};
+pub use source::SourceInput;
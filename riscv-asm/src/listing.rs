@@ -0,0 +1,234 @@
+//! Assembly listings: every source line next to its address, the machine code it became and
+//! the values of the symbols it uses, for checking encodings by hand.
+
+use crate::{
+    assembler::MemoryMap,
+    parser::{Operand, ParsedItem},
+    symbol_table::SymbolTable,
+};
+
+/// Machine code or data rows show at most this many bytes, the rest continue on the next row
+const BYTES_PER_ROW: usize = 8;
+
+/// Bytes an item placed in the program image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListingEntry {
+    /// 1-based source line the item is on
+    pub line: u64,
+    pub address: u32,
+    pub len: u32,
+    /// An instruction, shown as its 16 or 32 bit word instead of bytes
+    pub is_instruction: bool,
+    /// Symbols the item refers to and their values
+    pub symbols: Vec<(String, u32)>,
+}
+
+/// The items of a program by source line, see [`Listing::render`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Listing {
+    /// In source order, a line can have several, e.g. a pseudoinstruction's expansion
+    entries: Vec<ListingEntry>,
+    /// Address of the program image's first byte
+    origin: u32,
+}
+
+impl Listing {
+    pub(crate) fn build(
+        symbol_table: &SymbolTable,
+        memory_map: &MemoryMap,
+        parsed_items: &[ParsedItem],
+    ) -> Self {
+        let mut entries = Vec::new();
+        for (index, item) in parsed_items.iter().enumerate() {
+            let (location, len, operands, is_instruction) = match item {
+                ParsedItem::Label { location, .. } => (location, 0, &[][..], false),
+                ParsedItem::Instruction(instruction) => (
+                    &instruction.location,
+                    instruction.size(),
+                    &instruction.operands[..],
+                    true,
+                ),
+                ParsedItem::Directive {
+                    name,
+                    args,
+                    location,
+                } => {
+                    let len = match memory_map.fixed_data(index) {
+                        Some(data) => data.len() as u32,
+                        None if name == ".word" => 4 * args.len() as u32,
+                        None => 0,
+                    };
+                    (location, len, &args[..], false)
+                }
+            };
+            // The listing is of the source itself, without the files it includes
+            if location.file.is_some() {
+                continue;
+            }
+            let mut symbols: Vec<(String, u32)> = Vec::new();
+            // Labels the assembler made up, like those of literals, mean nothing to a reader
+            for name in operands.iter().filter_map(Operand::symbol) {
+                if !name.starts_with(".L")
+                    && let Some(address) = symbol_table.address(name)
+                    && !symbols.iter().any(|(known, _)| known == name)
+                {
+                    symbols.push((name.to_string(), address));
+                }
+            }
+            entries.push(ListingEntry {
+                line: location.line,
+                address: memory_map.address_of(index),
+                len,
+                is_instruction,
+                symbols,
+            });
+        }
+        // Literal pools are parsed at the end of the program but belong to their loads
+        entries.sort_by_key(|entry| entry.line);
+        Self {
+            entries,
+            origin: memory_map.origin(),
+        }
+    }
+
+    pub fn entries(&self) -> &[ListingEntry] {
+        &self.entries
+    }
+
+    /// The listing of `source` as text, taking machine code and data from `bytes`, the
+    /// program's image starting at its origin
+    ///
+    /// ```text
+    ///  line  address   code                     source
+    ///     3  00000008  00a00413                   li s0, 10   [loop = 0x00000010]
+    /// ```
+    ///
+    /// Labels and directives placing nothing show the address they are at, blank lines and
+    /// comments none.
+    pub fn render(&self, source: &str, bytes: &[u8]) -> String {
+        let mut text = String::from(" line  address   code                     source\n");
+        let mut entries = self.entries.iter().peekable();
+        for (index, source_line) in source.split('\n').enumerate() {
+            let line = index as u64 + 1;
+            let mut rows = Vec::new();
+            let mut symbols: Vec<&(String, u32)> = Vec::new();
+            let mut line_address = None;
+            while let Some(entry) = entries.next_if(|entry| entry.line == line) {
+                line_address.get_or_insert(entry.address);
+                // Pseudoinstructions expanding to several entries use the same symbols
+                for symbol in &entry.symbols {
+                    if !symbols.iter().any(|(known, _)| *known == symbol.0) {
+                        symbols.push(symbol);
+                    }
+                }
+                rows.extend(code_rows(entry, bytes, self.origin));
+            }
+            let source_line = source_line.trim_end();
+            let mut first = match rows.first() {
+                Some((address, code)) => format_row(Some(line), Some(*address), code),
+                None => format_row(Some(line), line_address, ""),
+            };
+            first.push_str(source_line);
+            if !symbols.is_empty() {
+                let values: Vec<String> = symbols
+                    .iter()
+                    .map(|(name, value)| format!("{} = {:#010x}", name, value))
+                    .collect();
+                first.push_str(&format!("   [{}]", values.join(", ")));
+            }
+            text.push_str(first.trim_end());
+            text.push('\n');
+            for (address, code) in rows.iter().skip(1) {
+                text.push_str(format_row(None, Some(*address), code).trim_end());
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+/// Rows of an entry's code: instructions as one word each, data as up to
+/// [`BYTES_PER_ROW`] bytes
+fn code_rows(entry: &ListingEntry, bytes: &[u8], origin: u32) -> Vec<(u32, String)> {
+    let data = entry.address.checked_sub(origin).and_then(|start| {
+        let start = start as usize;
+        bytes.get(start..start + entry.len as usize)
+    });
+    let Some(data) = data else {
+        return Vec::new();
+    };
+    if entry.is_instruction {
+        let word = data
+            .iter()
+            .rev()
+            .fold(0u32, |word, byte| word << 8 | *byte as u32);
+        let code = match data.len() {
+            2 => format!("{:04x}", word),
+            _ => format!("{:08x}", word),
+        };
+        return vec![(entry.address, code)];
+    }
+    data.chunks(BYTES_PER_ROW)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let code: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            (
+                entry.address + (index * BYTES_PER_ROW) as u32,
+                code.join(" "),
+            )
+        })
+        .collect()
+}
+
+fn format_row(line: Option<u64>, address: Option<u32>, code: &str) -> String {
+    let line = line.map_or(String::new(), |line| line.to_string());
+    let address = address.map_or(String::new(), |address| format!("{:08x}", address));
+    format!("{:>5}  {:<8}  {:<23}  ", line, address, code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssemblerOptions, assemble_program};
+
+    #[test]
+    fn test_listing() {
+        let source = "main:\n    li a0, 0x12345\nloop: beq a0, zero, done  # count down\n    dec a0\n    jal zero, loop\n\ndone:\n    lw a1, =main\n    .word 1, 2, 3\n";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let listing = program.listing.render(source, &program.bytes);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                " line  address   code                     source",
+                "    1  00000000                           main:",
+                "    2  00000000  00012537                     li a0, 0x12345",
+                "       00000004  34550513",
+                "    3  00000008  00050663                 loop: beq a0, zero, done  # count down   [done = 0x00000014]",
+                "    4  0000000c  fff50513                     dec a0",
+                "    5  00000010  ff9ff06f                     jal zero, loop   [loop = 0x00000008]",
+                "    6",
+                "    7  00000014                           done:",
+                "    8  00000014  00000597                     lw a1, =main   [main = 0x00000000]",
+                "       00000018  0145a583",
+                "       00000028  00 00 00 00",
+                "    9  0000001c  01 00 00 00 02 00 00 00      .word 1, 2, 3",
+                "       00000024  03 00 00 00",
+                "   10  00000028",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expanded_symbols_listed_once() {
+        let source = "    la a3, msg\nmsg: .byte 1\n";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let listing = program.listing.render(source, &program.bytes);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[1],
+            "    1  00000000  00000697                     la a3, msg   [msg = 0x00000008]"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! Assembler macros, expanded line by line before the source is tokenized:
+//!
+//! ```text
+//! .macro push reg, size=4
+//!     addi sp, sp, -\size
+//!     sw \reg, 0(sp)
+//! .endm
+//!
+//!     push ra             # addi sp, sp, -4 + sw ra, 0(sp)
+//!     push a0, 8
+//! ```
+//!
+//! Parameters follow the macro's name, separated by commas or spaces, and may have a default
+//! value after `=`. In the body `\name` stands for an argument and `\@` for a number that's
+//! different in every expansion, for labels local to one expansion like `loop\@:`. `\()`
+//! stands for nothing, to end a parameter name right before more text as in `\reg\()_save`.
+//! Arguments are separated by commas and taken by position or as `name=value`, missing ones
+//! without a default are an error. A macro can use other macros, and define them.
+//!
+//! Expanded lines keep the location of the invocation, so errors, listings and the
+//! debugger's line map point at the line using the macro.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::{
+    error::{AssemblerError, Redefined, SourceLocation},
+    include::Line,
+};
+
+/// Expansions within expansions before giving up, for macros using themselves
+const MAX_DEPTH: usize = 100;
+
+/// Lines macros may expand to in all, for macros using others many times over
+const MAX_LINES: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    /// Parameter names and their default values
+    parameters: Vec<(String, Option<String>)>,
+    body: Vec<String>,
+    /// Line of the `.macro` directive
+    location: SourceLocation,
+}
+
+/// The macros defined so far and how many expansions there were, for `\@`
+#[derive(Debug, Default)]
+pub(crate) struct Macros {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// Lines the expansions so far came to
+    lines: usize,
+}
+
+impl Macros {
+    /// The lines the macro `line` uses expands to, the labels in front of it first, `None` if
+    /// it doesn't use one. `depth` is the number of expansions `line` comes from.
+    pub fn expand<'a>(
+        &mut self,
+        line: &Line<'a>,
+        depth: usize,
+    ) -> anyhow::Result<Option<Vec<Line<'a>>>> {
+        let location = line.location.clone();
+        let (labels, statement) = split_labels(&line.text);
+        let (name, arguments) = split_statement(statement);
+        let Some(definition) = self.macros.get(name) else {
+            return Ok(None);
+        };
+        if depth == MAX_DEPTH {
+            return Err(macro_error(
+                &format!("macro '{}' expands too deeply, does it use itself?", name),
+                location,
+            ));
+        }
+        let body = substitute(name, definition, arguments, self.expansions, &location)?;
+        self.expansions += 1;
+        self.lines += body.len();
+        if self.lines > MAX_LINES {
+            return Err(macro_error(
+                &format!("macros expand to more than {} lines", MAX_LINES),
+                location,
+            ));
+        }
+        let mut expanded = Vec::new();
+        if !labels.trim().is_empty() {
+            let span = match line.expanded {
+                true => line.span.clone(),
+                false => line.span.start..line.span.start + labels.len(),
+            };
+            expanded.push(Line {
+                text: Cow::Owned(labels.to_string()),
+                span,
+                ..line.clone()
+            });
+        }
+        expanded.extend(body.into_iter().map(|text| Line {
+            text: Cow::Owned(text),
+            location: location.clone(),
+            span: line.span.clone(),
+            expanded: true,
+        }));
+        Ok(Some(expanded))
+    }
+
+    /// Reads the definition `.macro NAME PARAMETERS` up to its `.endm` from `lines`
+    pub fn define<'a>(
+        &mut self,
+        labels: &str,
+        header: &str,
+        location: SourceLocation,
+        lines: &mut impl Iterator<Item = Line<'a>>,
+    ) -> anyhow::Result<()> {
+        if !labels.trim().is_empty() {
+            return Err(macro_error(
+                "a macro definition can't have a label",
+                location,
+            ));
+        }
+        let header = strip_comment(header);
+        let mut words = header
+            .split([',', ' ', '\t'])
+            .filter(|word| !word.is_empty());
+        let Some(name) = words.next().filter(|name| is_name(name)) else {
+            return Err(macro_error("'.macro' needs a name", location));
+        };
+        let mut parameters: Vec<(String, Option<String>)> = Vec::new();
+        for parameter in words {
+            let (parameter, default) = match parameter.split_once('=') {
+                Some((parameter, default)) => (parameter, Some(default.to_string())),
+                None => (parameter, None),
+            };
+            if !is_name(parameter) {
+                return Err(macro_error(
+                    &format!("invalid parameter '{}' of macro '{}'", parameter, name),
+                    location,
+                ));
+            }
+            if parameters.iter().any(|(other, _)| other == parameter) {
+                return Err(macro_error(
+                    &format!("macro '{}' has two parameters named '{}'", name, parameter),
+                    location,
+                ));
+            }
+            parameters.push((parameter.to_string(), default));
+        }
+
+        // Definitions inside the body are only made when it's expanded
+        let mut body = Vec::new();
+        let mut nesting = 0;
+        loop {
+            let Some(Line { text, .. }) = lines.next() else {
+                return Err(macro_error(
+                    &format!("macro '{}' has no '.endm'", name),
+                    location,
+                ));
+            };
+            let directive = split_labels(&text).1.split([' ', '\t']).next();
+            match directive.map(str::to_ascii_lowercase).as_deref() {
+                Some(".macro") => nesting += 1,
+                Some(".endm") if nesting == 0 => break,
+                Some(".endm") => nesting -= 1,
+                _ => {}
+            }
+            body.push(text.into_owned());
+        }
+
+        if let Some(previous) = self.macros.get(name) {
+            return Err(AssemblerError::Redefinition {
+                what: Redefined::Macro,
+                message: format!("macro '{}' is already defined", name),
+                location,
+                first: previous.location.clone(),
+            }
+            .into());
+        }
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                parameters,
+                body,
+                location,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// The body of `definition` with the `arguments` of its use in place of the parameters, and
+/// `expansion` in place of `\@`
+fn substitute(
+    name: &str,
+    definition: &Macro,
+    arguments: &str,
+    expansion: usize,
+    location: &SourceLocation,
+) -> anyhow::Result<Vec<String>> {
+    let mut values: Vec<Option<String>> = definition
+        .parameters
+        .iter()
+        .map(|(_, default)| default.clone())
+        .collect();
+    let arguments = split_arguments(strip_comment(arguments));
+    for (position, argument) in arguments.iter().enumerate() {
+        let named = argument.split_once('=').and_then(|(parameter, value)| {
+            let index = definition
+                .parameters
+                .iter()
+                .position(|(name, _)| name == parameter.trim())?;
+            Some((index, value.trim()))
+        });
+        let (index, value) = match named {
+            Some(named) => named,
+            None if position < definition.parameters.len() => (position, argument.as_str()),
+            None => {
+                return Err(macro_error(
+                    &format!(
+                        "macro '{}' takes {} argument{}, found {}",
+                        name,
+                        definition.parameters.len(),
+                        if definition.parameters.len() == 1 {
+                            ""
+                        } else {
+                            "s"
+                        },
+                        arguments.len()
+                    ),
+                    location.clone(),
+                ));
+            }
+        };
+        values[index] = Some(value.to_string());
+    }
+    let values = definition
+        .parameters
+        .iter()
+        .zip(values)
+        .map(|((parameter, _), value)| {
+            value
+                .map(|value| (parameter.as_str(), value))
+                .ok_or_else(|| {
+                    macro_error(
+                        &format!("macro '{}' needs a value for '{}'", name, parameter),
+                        location.clone(),
+                    )
+                })
+        })
+        .collect::<anyhow::Result<HashMap<&str, String>>>()?;
+
+    definition
+        .body
+        .iter()
+        .map(|text| {
+            let mut substituted = String::new();
+            let mut rest = text.as_str();
+            let mut in_string = false;
+            while let Some(c) = rest.chars().next() {
+                rest = &rest[c.len_utf8()..];
+                match c {
+                    '"' => in_string = !in_string,
+                    '\\' if rest.starts_with('@') => {
+                        substituted.push_str(&expansion.to_string());
+                        rest = &rest[1..];
+                        continue;
+                    }
+                    '\\' if rest.starts_with("()") => {
+                        rest = &rest[2..];
+                        continue;
+                    }
+                    '\\' => {
+                        let len = rest
+                            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                            .unwrap_or(rest.len());
+                        if let Some(value) = values.get(&rest[..len]) {
+                            substituted.push_str(value);
+                            rest = &rest[len..];
+                            continue;
+                        }
+                        // Escapes like "\n" in strings stay as they are
+                        if !in_string {
+                            return Err(macro_error(
+                                &format!("macro '{}' has no parameter '{}'", name, &rest[..len]),
+                                location.clone(),
+                            ));
+                        }
+                        // The escaped character can't end the string
+                        if let Some(escaped) = rest.chars().next() {
+                            substituted.push(c);
+                            substituted.push(escaped);
+                            rest = &rest[escaped.len_utf8()..];
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                substituted.push(c);
+            }
+            Ok(substituted)
+        })
+        .collect()
+}
+
+/// The labels a line starts with, and the statement after them
+pub(crate) fn split_labels(text: &str) -> (&str, &str) {
+    let mut rest = text;
+    // Numeric local labels too, like "1:"
+    while let Some((label, after)) = rest.split_once(':')
+        && (is_name(label.trim()) || label.trim().parse::<u64>().is_ok())
+    {
+        rest = after;
+    }
+    (&text[..text.len() - rest.len()], rest.trim())
+}
+
+/// The first word of a statement, its mnemonic or directive, and the rest of it
+pub(crate) fn split_statement(statement: &str) -> (&str, &str) {
+    let mut words = statement.splitn(2, [' ', '\t']);
+    let first = words.next().unwrap_or_default();
+    (first, words.next().unwrap_or_default().trim())
+}
+
+/// A line's text before its comment
+pub(crate) fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '#' if !in_string => return &text[..index],
+            '"' if !escaped => in_string = !in_string,
+            _ => {}
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    text
+}
+
+/// The comma separated arguments of a macro's use, commas in parentheses and strings don't
+/// separate them
+fn split_arguments(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut arguments = vec![String::new()];
+    let mut nesting = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        match c {
+            '"' if !escaped => in_string = !in_string,
+            '(' if !in_string => nesting += 1,
+            ')' if !in_string => nesting = nesting.saturating_sub(1),
+            ',' if !in_string && nesting == 0 => {
+                arguments.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        escaped = c == '\\' && !escaped;
+        arguments.last_mut().unwrap().push(c);
+    }
+    arguments
+        .into_iter()
+        .map(|argument| argument.trim().to_string())
+        .collect()
+}
+
+pub(crate) fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn macro_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssemblerOptions, preprocess::preprocess};
+
+    fn expand(source: &str) -> anyhow::Result<Vec<String>> {
+        Ok(preprocess(source, None, &AssemblerOptions::default())?
+            .into_iter()
+            .map(|line| format!("{}: {}", line.location.line, line.text.trim()))
+            .collect())
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let source = "\
+.macro push reg, size=4
+    addi sp, sp, -\\size
+    sw \\reg, 0(sp)
+.endm
+.macro spin
+wait\\@: j wait\\@
+.endm
+start: push ra
+    push size=8, reg=a0  # named
+    spin
+    spin";
+        assert_eq!(
+            expand(source).unwrap(),
+            [
+                "8: start:",
+                "8: addi sp, sp, -4",
+                "8: sw ra, 0(sp)",
+                "9: addi sp, sp, -8",
+                "9: sw a0, 0(sp)",
+                // Counting every expansion so far, the two of push too
+                "10: wait2: j wait2",
+                "11: wait3: j wait3",
+            ]
+        );
+
+        // Macros using and defining macros, and arguments with commas in parentheses
+        let source = "\
+.macro outer value
+    .macro inner
+        .word \\value
+    .endm
+    inner
+    .half \\()\\value
+.endm
+outer (1, 2)
+.macro store reg, address
+    sw \\reg, \\address
+.endm
+store a1, 8(sp)";
+        assert_eq!(
+            expand(source).unwrap(),
+            ["8: .word (1, 2)", "8: .half (1, 2)", "12: sw a1, 8(sp)"]
+        );
+
+        assert!(expand(".macro m\nnop").is_err());
+        assert!(expand(".endm").is_err());
+        assert!(expand(".macro m a\n.endm\nm 1, 2").is_err());
+        assert!(expand(".macro m a\n.endm\nm").is_err());
+        assert!(expand(".macro m\n\\b\n.endm\nm").is_err());
+        assert_eq!(
+            expand(".macro m\n.endm\n.macro m\n.endm")
+                .unwrap_err()
+                .to_string(),
+            "Macro error: macro 'm' is already defined at line 3, column 1, first defined at \
+             line 1, column 1"
+        );
+        assert!(expand(".macro m\nm\n.endm\nm").is_err());
+        // Escapes in strings aren't parameters
+        assert_eq!(
+            expand(".macro m\n.string \"a\\n\\\"\"\n.endm\nm").unwrap(),
+            ["4: .string \"a\\n\\\"\""]
+        );
+    }
+}
@@ -0,0 +1,2615 @@
+//! Tokens to the items the assembler lays out: labels, instructions and directives.
+//!
+//! The items are a typed view of the program for tools that need its structure rather than
+//! its bytes, see [`parse`](crate::parse). Instructions are base instructions whose
+//! operands fit them: pseudoinstructions and `c.*` mnemonics come out as the base
+//! instructions they stand for, so one statement can give several items. `.equ`, `.set`,
+//! `.option` and `.register` are applied while parsing and leave no items of their own.
+//! Conditional assembly is done before parsing, see [`conditional`](crate::conditional).
+
+use std::{borrow::Cow, collections::BTreeMap, iter::Peekable, num::IntErrorKind, str::Chars};
+
+use tracing::{debug, trace};
+
+use crate::{
+    analysis::{Finding, FindingKind},
+    assembler::AssemblerOptions,
+    compressed,
+    encoder::{CSR_RANGE, Xlen, check_operands, csr_number, encode, rounding_mode},
+    error::{AssemblerError, SourceLocation, Span},
+    expression::{BinaryOperator, Expression},
+    isa::{self, Format, is_rv64_only, takes_csr, takes_rounding_mode},
+    plugin::Plugins,
+    register::{RegisterSet, float_register_number, register_number},
+    section::Section,
+    suggest::{did_you_mean, float_registers_like, mnemonics_like, registers_like},
+    symbol_table::SymbolTable,
+    tokenizer::{Base, Token, TokenKind},
+};
+
+/// An operand of an instruction or argument of a directive
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Operand {
+    /// x0-x31, whichever name or alias the source used
+    Register(u8),
+    /// f0-f31
+    FloatRegister(u8),
+    /// Static rounding mode of a floating point instruction (`rne`, `rtz`, ...)
+    RoundingMode(u8),
+    /// CSR of a CSR instruction, given by name (`mstatus`, `fcsr`, ...)
+    Csr(u16),
+    /// Number, or expression of numbers and `.equ` symbols
+    Immediate(i64),
+    /// Reference to a label, resolved during code generation
+    Symbol(String),
+    /// `offset(base)` memory operand
+    Memory { offset: i64, base: u8 },
+    /// `%hi(symbol)`, the high 20 bits of a label's address for LUI, rounded so that adding
+    /// the sign extended `%lo` gives the address
+    Hi(String),
+    /// `%lo(symbol)`, the low 12 bits of a label's address. With a base it is the offset of
+    /// a memory operand, `%lo(symbol)(base)`, otherwise an immediate.
+    Lo { symbol: String, base: Option<u8> },
+    /// High 20 bits of the offset from the instruction to a label, the AUIPC half of a PC
+    /// relative address
+    PcrelHi(String),
+    /// The low 12 bits of the offset to a label from the AUIPC right before the instruction,
+    /// a memory operand's offset with a base, otherwise an immediate
+    PcrelLo { symbol: String, base: Option<u8> },
+    /// Bytes of a string literal of `.ascii` and `.asciz`, without its quotes and with its
+    /// escapes replaced
+    String(Vec<u8>),
+    /// Expression using labels, whose value is only known once addresses are. Expressions
+    /// of numbers and `.equ` symbols defined before them become immediates right away.
+    Expression(Expression),
+}
+
+impl Operand {
+    /// The label the operand refers to, if any
+    pub fn symbol(&self) -> Option<&str> {
+        match self {
+            Operand::Symbol(name)
+            | Operand::Hi(name)
+            | Operand::Lo { symbol: name, .. }
+            | Operand::PcrelHi(name)
+            | Operand::PcrelLo { symbol: name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// A base instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instruction {
+    /// Lowercase, `addi` or `amoswap.w.aq`
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    /// Start of the statement the instruction comes from
+    pub location: SourceLocation,
+    /// Start of each operand, for errors about one of them. Empty for instructions the
+    /// assembler expands others into.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub operand_locations: Vec<SourceLocation>,
+    /// Compressed (`c.*`) form the instruction is encoded in, `None` for the 32 bit encoding
+    pub compressed: Option<String>,
+}
+
+impl Instruction {
+    /// Size of the encoded instruction in bytes
+    pub fn size(&self) -> u32 {
+        if self.compressed.is_some() { 2 } else { 4 }
+    }
+}
+
+/// One thing a statement places or defines
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ParsedItem {
+    /// `name:`, numeric local labels named `.Llocal<number>_<definition>`
+    Label {
+        name: String,
+        location: SourceLocation,
+    },
+    Instruction(Instruction),
+    /// A directive the assembler acts on when laying out the program, like `.word` or
+    /// `.text`
+    Directive {
+        /// Lowercase, with its dot
+        name: String,
+        args: Vec<Operand>,
+        location: SourceLocation,
+    },
+}
+
+impl ParsedItem {
+    /// Start of the statement the item comes from
+    pub fn location(&self) -> &SourceLocation {
+        match self {
+            ParsedItem::Label { location, .. } | ParsedItem::Directive { location, .. } => location,
+            ParsedItem::Instruction(instruction) => &instruction.location,
+        }
+    }
+}
+
+/// An item with the span of the statement it comes from, which the other items of the
+/// statement share
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpannedItem {
+    pub item: ParsedItem,
+    pub span: Span,
+}
+
+/// Parses tokens into [`ParsedItem`]s
+pub struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    /// Span of each item parsed so far
+    spans: Vec<Span>,
+    /// First token of the statement being parsed
+    statement_start: usize,
+    register_set: RegisterSet,
+    xlen: Xlen,
+    /// Names declared with `.register name, reg` (or through the options)
+    register_aliases: BTreeMap<String, u8>,
+    /// Compress instructions where possible, toggled with `.option rvc` and `.option norvc`
+    rvc: bool,
+    /// Settings saved by `.option push`
+    option_stack: Vec<bool>,
+    /// Where each operand of the last operand list starts, for errors about one of them
+    operand_locations: Vec<SourceLocation>,
+    /// Values loaded with `lw rd, =value` since the last pool, with the labels they get
+    literal_pool: Vec<Literal>,
+    /// Literals placed so far, numbering their labels
+    literal_count: usize,
+    /// Definitions of each numeric local label so far, numbering their symbols
+    local_labels: BTreeMap<u64, usize>,
+    /// Handlers of instructions the assembler doesn't know
+    plugins: Plugins,
+    /// Symbols defined before the first line, see [`AssemblerOptions::defines`]
+    defines: BTreeMap<String, u32>,
+    /// Findings only the source shows, like a `li` the expansion turns into an `addi`
+    findings: Vec<Finding>,
+}
+
+/// Start of the symbols numeric local labels stand for, the first `1:` defines `.Llocal1_1`,
+/// the next `.Llocal1_2` and so on
+const LOCAL_LABEL_PREFIX: &str = ".Llocal";
+
+/// Start of the labels of literal pool entries, `.Lliteral0` and on
+pub(crate) const LITERAL_LABEL_PREFIX: &str = ".Lliteral";
+
+/// A value waiting in the literal pool
+struct Literal {
+    label: String,
+    value: Operand,
+    /// The first load of the value
+    location: SourceLocation,
+}
+
+impl<'a> Parser<'a> {
+    /// A parser for RV32I with the default options
+    pub fn new(tokens: Vec<Token<'a>>) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            spans: Vec::new(),
+            statement_start: 0,
+            register_set: RegisterSet::default(),
+            xlen: Xlen::default(),
+            register_aliases: BTreeMap::new(),
+            rvc: false,
+            option_stack: Vec::new(),
+            operand_locations: Vec::new(),
+            literal_pool: Vec::new(),
+            literal_count: 0,
+            local_labels: BTreeMap::new(),
+            plugins: Plugins::new(),
+            defines: BTreeMap::new(),
+            findings: Vec::new(),
+        }
+    }
+
+    /// A parser applying the target, register set, aliases, defines and plugins of
+    /// `options`
+    pub fn with_options(tokens: Vec<Token<'a>>, options: &AssemblerOptions) -> Self {
+        Self {
+            register_set: options.register_set,
+            xlen: options.xlen,
+            register_aliases: options.register_aliases.clone(),
+            plugins: options.plugins.clone(),
+            defines: options.defines.clone(),
+            ..Self::new(tokens)
+        }
+    }
+
+    /// Findings about the source parsed so far, see [`FindingKind::LiFitsAddi`]
+    pub fn take_findings(&mut self) -> Vec<Finding> {
+        std::mem::take(&mut self.findings)
+    }
+
+    /// Parses every statement, recording label definitions and references in `symbol_table`
+    pub fn parse_all(&mut self, symbol_table: &mut SymbolTable) -> anyhow::Result<Vec<ParsedItem>> {
+        let mut items = Vec::new();
+        for (name, value) in &self.defines {
+            symbol_table.define_absolute(name, *value, SourceLocation::new(0, 0))?;
+        }
+
+        loop {
+            self.end_statement(items.len());
+            let token = self.next_token();
+            match token.kind {
+                TokenKind::EndOfFile => {
+                    // Literals not placed by a `.ltorg` go after the rest of the program
+                    items.extend(self.flush_literal_pool(&token, symbol_table)?);
+                    break;
+                }
+                TokenKind::Newline => continue,
+                TokenKind::Identifier
+                    if self.peek_kind() != Some(&TokenKind::Colon)
+                        && self
+                            .plugins
+                            .instruction(&token_text(&token).to_lowercase())
+                            .is_some() =>
+                {
+                    let operands = self.parse_operands(symbol_table)?;
+                    items.push(ParsedItem::Instruction(Instruction {
+                        mnemonic: token_text(&token).to_lowercase(),
+                        operands,
+                        location: token.location,
+                        operand_locations: self.operand_locations.clone(),
+                        compressed: None,
+                    }));
+                }
+                TokenKind::Number(Base::Dec) if self.peek_kind() == Some(&TokenKind::Colon) => {
+                    self.next_token();
+                    let name = self.define_local_label(&token)?;
+                    trace!(location = %token.location, name, "numeric local label");
+                    symbol_table.define(&name, token.location.clone())?;
+                    items.push(ParsedItem::Label {
+                        name,
+                        location: token.location,
+                    });
+                }
+                TokenKind::Identifier => {
+                    let name = token_text(&token).to_string();
+                    if self.peek_kind() != Some(&TokenKind::Colon) {
+                        return Err(parser_error(
+                            &format!(
+                                "Unknown instruction '{}'{}",
+                                name,
+                                did_you_mean(&mnemonics_like(&name))
+                            ),
+                            token.location,
+                        ));
+                    }
+                    self.next_token();
+                    trace!(location = %token.location, name, "label");
+                    symbol_table.define(&name, token.location.clone())?;
+                    items.push(ParsedItem::Label {
+                        name,
+                        location: token.location,
+                    });
+                }
+                TokenKind::Instruction if token_text(&token).starts_with("c.") => {
+                    // RV64 reassigns some of the compressed encodings
+                    if self.xlen == Xlen::Rv64 {
+                        return Err(parser_error(
+                            &format!(
+                                "Compressed instruction '{}' is not supported on RV64",
+                                token_text(&token)
+                            ),
+                            token.location,
+                        ));
+                    }
+                    let operands = self.parse_operands(symbol_table)?;
+                    let instruction =
+                        compressed::expand(token_text(&token), operands, &token.location)?;
+                    items.push(ParsedItem::Instruction(instruction));
+                }
+                TokenKind::Instruction
+                    if token_text(&token).eq_ignore_ascii_case("lw") && self.literal_follows() =>
+                {
+                    items.extend(self.parse_literal_load(&token, symbol_table)?);
+                }
+                TokenKind::Instruction => {
+                    let mnemonic = token_text(&token).to_lowercase();
+                    if self.xlen == Xlen::Rv32 && is_rv64_only(&mnemonic) {
+                        return Err(parser_error(
+                            &format!("'{}' is only available on RV64", mnemonic),
+                            token.location,
+                        ));
+                    }
+                    let mut operands = if takes_rounding_mode(&mnemonic) {
+                        self.parse_operands_with_rounding_mode(symbol_table)?
+                    } else if takes_csr(&mnemonic) {
+                        self.parse_operands_with_csr(1, symbol_table)?
+                    } else if mnemonic == "fence" {
+                        self.parse_fence_sets()?
+                    } else {
+                        self.parse_operands(symbol_table)?
+                    };
+                    fold_constants(&mnemonic, &mut operands, symbol_table);
+                    resolve_pcrel_lo(&items, &mut operands).map_err(|index| {
+                        parser_error(
+                            "%pcrel_lo() has to name the label of the AUIPC right before it",
+                            self.operand_locations[index].clone(),
+                        )
+                    })?;
+                    if is_pseudo_form(&mnemonic, &operands) {
+                        let expanded = expand_pseudoinstruction(
+                            &mnemonic,
+                            operands,
+                            self.xlen,
+                            &token.location,
+                        )?;
+                        items.extend(expanded.into_iter().map(|instruction| {
+                            ParsedItem::Instruction(self.maybe_compress(instruction))
+                        }));
+                        continue;
+                    }
+                    check_operands(&mnemonic, &operands).map_err(|mismatch| {
+                        let location = mismatch
+                            .operand
+                            .and_then(|index| self.operand_locations.get(index).cloned())
+                            .unwrap_or_else(|| token.location.clone());
+                        parser_error(&mismatch.message, location)
+                    })?;
+                    let instruction = Instruction {
+                        mnemonic,
+                        operands,
+                        location: token.location,
+                        operand_locations: self.operand_locations.clone(),
+                        compressed: None,
+                    };
+                    items.push(ParsedItem::Instruction(self.maybe_compress(instruction)));
+                }
+                TokenKind::Pseudoinstruction => {
+                    let operands = match pseudo_csr_operand(&token_text(&token).to_lowercase()) {
+                        Some(index) => self.parse_operands_with_csr(index, symbol_table)?,
+                        None => self.parse_operands(symbol_table)?,
+                    };
+                    let expanded = expand_pseudoinstruction(
+                        token_text(&token),
+                        operands,
+                        self.xlen,
+                        &token.location,
+                    )?;
+                    trace!(
+                        location = %token.location,
+                        pseudoinstruction = token_text(&token),
+                        expanded = expanded.len(),
+                        "expanded pseudoinstruction"
+                    );
+                    if token_text(&token).eq_ignore_ascii_case("li")
+                        && let [addi] = expanded.as_slice()
+                        && addi.mnemonic == "addi"
+                        && let Some(Operand::Immediate(value)) = addi.operands.last()
+                    {
+                        self.findings.push(Finding {
+                            kind: FindingKind::LiFitsAddi,
+                            message: format!(
+                                "'li' of {} is an 'addi' from zero, the value fits its 12 bits",
+                                value
+                            ),
+                            location: token.location.clone(),
+                        });
+                    }
+                    items.extend(expanded.into_iter().map(|instruction| {
+                        ParsedItem::Instruction(self.maybe_compress(instruction))
+                    }));
+                }
+                TokenKind::Directive if token_text(&token).eq_ignore_ascii_case(".register") => {
+                    self.parse_register_alias()?;
+                }
+                TokenKind::Directive if token_text(&token).eq_ignore_ascii_case(".ltorg") => {
+                    if !self.at_line_end() {
+                        let extra = self.next_token();
+                        return Err(parser_error(
+                            &format!("Unexpected {} after .ltorg", describe(&extra)),
+                            extra.location,
+                        ));
+                    }
+                    items.extend(self.flush_literal_pool(&token, symbol_table)?);
+                }
+                TokenKind::Directive if token_text(&token).eq_ignore_ascii_case(".option") => {
+                    self.parse_option()?;
+                }
+                TokenKind::Directive if token_text(&token).eq_ignore_ascii_case(".section") => {
+                    items.push(self.parse_section(token)?);
+                }
+                TokenKind::Directive
+                    if [".equ", ".set"]
+                        .iter()
+                        .any(|name| token_text(&token).eq_ignore_ascii_case(name)) =>
+                {
+                    self.parse_absolute_symbol(&token, symbol_table)?;
+                }
+                TokenKind::Directive
+                    if token_text(&token).eq_ignore_ascii_case(".vector_table") =>
+                {
+                    items.extend(self.parse_vector_table(&token, symbol_table)?);
+                }
+                TokenKind::Directive => {
+                    let args = self.parse_operands(symbol_table)?;
+                    items.push(ParsedItem::Directive {
+                        name: token_text(&token).to_lowercase(),
+                        args,
+                        location: token.location,
+                    });
+                }
+                _ => {
+                    return Err(parser_error(
+                        &format!("Unexpected {}", describe(&token)),
+                        token.location,
+                    ));
+                }
+            }
+        }
+
+        self.end_statement(items.len());
+
+        debug!(
+            items = items.len(),
+            symbols = symbol_table.len(),
+            "parsed program"
+        );
+        Ok(items)
+    }
+
+    /// Like [`parse_all`](Self::parse_all), with the span of each item's statement
+    pub fn parse_spanned(
+        &mut self,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<SpannedItem>> {
+        let items = self.parse_all(symbol_table)?;
+        let spans = std::mem::take(&mut self.spans);
+        Ok(items
+            .into_iter()
+            .zip(spans)
+            .map(|(item, span)| SpannedItem { item, span })
+            .collect())
+    }
+
+    /// Gives the items up to the first `items` that don't have a span yet the span of the
+    /// statement just parsed, and starts the next statement
+    fn end_statement(&mut self, items: usize) {
+        let position = self.position.min(self.tokens.len());
+        let statement = &self.tokens[self.statement_start.min(position)..position];
+        let mut covered = statement
+            .iter()
+            .filter(|token| !matches!(token.kind, TokenKind::Newline | TokenKind::EndOfFile));
+        let span = match (covered.next(), covered.next_back()) {
+            (Some(first), last) => {
+                let last = last.unwrap_or(first);
+                let width = last.text.chars().count();
+                Span {
+                    start: first.location.clone(),
+                    end: SourceLocation {
+                        col: last.location.col + width as u64,
+                        ..last.location.clone()
+                    },
+                }
+            }
+            // Literals placed at the end of the program
+            (None, _) => {
+                let end = self.tokens.last().map(|token| token.location.clone());
+                Span {
+                    start: end.clone().unwrap_or_default(),
+                    end: end.unwrap_or_default(),
+                }
+            }
+        };
+        self.spans.resize(items.max(self.spans.len()), span);
+        self.statement_start = self.position;
+    }
+
+    /// Like [`Parser::parse_operands`], but a trailing rounding mode name is taken as such
+    /// rather than as a label
+    fn parse_operands_with_rounding_mode(
+        &mut self,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        self.operand_locations.clear();
+        loop {
+            self.mark_operand();
+            let token = self.tokens.get(self.position);
+            let rounding_mode = token
+                .filter(|token| token.kind == TokenKind::Identifier)
+                .and_then(|token| rounding_mode(token_text(token)));
+            match rounding_mode {
+                Some(mode) if !operands.is_empty() => {
+                    self.position += 1;
+                    operands.push(Operand::RoundingMode(mode));
+                }
+                _ => operands.push(self.parse_operand(symbol_table)?),
+            }
+
+            if self.at_line_end() {
+                break;
+            }
+            self.expect(TokenKind::Comma, "',' between operands")?;
+        }
+        Ok(operands)
+    }
+
+    /// Like [`Parser::parse_operands`], but reads a name or number in place `index` as a
+    /// CSR, so CSR names stay free for labels everywhere else
+    fn parse_operands_with_csr(
+        &mut self,
+        index: usize,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        self.operand_locations.clear();
+        loop {
+            self.mark_operand();
+            let token = self
+                .tokens
+                .get(self.position)
+                .filter(|_| operands.len() == index);
+            let csr = match token {
+                Some(token) if token.kind == TokenKind::Identifier => csr_number(token_text(token)),
+                Some(token) if matches!(token.kind, TokenKind::Number(_)) => {
+                    let number = parse_number(token)?;
+                    if !CSR_RANGE.contains(&number) {
+                        return Err(parser_error(
+                            &format!("CSR number {:#x} is more than 12 bits", number),
+                            token.location.clone(),
+                        ));
+                    }
+                    Some(number as u16)
+                }
+                _ => None,
+            };
+            match csr {
+                Some(csr) => {
+                    self.position += 1;
+                    operands.push(Operand::Csr(csr));
+                }
+                None => operands.push(self.parse_operand(symbol_table)?),
+            }
+
+            if self.at_line_end() {
+                break;
+            }
+            self.expect(TokenKind::Comma, "',' between operands")?;
+        }
+        Ok(operands)
+    }
+
+    /// Reads the `iorw` sets of a `fence` as the bits they stand for, `0` is the empty set
+    fn parse_fence_sets(&mut self) -> anyhow::Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        self.operand_locations.clear();
+        while !self.at_line_end() {
+            if !operands.is_empty() {
+                self.expect(TokenKind::Comma, "',' between operands")?;
+            }
+            self.mark_operand();
+            let token = self.next_token();
+            let text = token_text(&token);
+            let bits = match token.kind {
+                TokenKind::Number(Base::Dec) if text == "0" => Some(0),
+                TokenKind::Identifier => text.chars().try_fold(0, |bits, letter| {
+                    let bit = 0b1000 >> "iorw".find(letter)?;
+                    (bits & bit == 0).then_some(bits | bit)
+                }),
+                _ => None,
+            };
+            let bits = bits.ok_or_else(|| {
+                parser_error(
+                    &format!(
+                        "Expected a set of 'iorw' accesses for 'fence', found {}",
+                        describe(&token)
+                    ),
+                    token.location.clone(),
+                )
+            })?;
+            operands.push(Operand::Immediate(bits));
+        }
+        Ok(operands)
+    }
+
+    /// Parses a comma separated operand list up to the end of the line
+    fn parse_operands(&mut self, symbol_table: &mut SymbolTable) -> anyhow::Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        self.operand_locations.clear();
+
+        if self.at_line_end() {
+            return Ok(operands);
+        }
+
+        loop {
+            self.mark_operand();
+            operands.push(self.parse_operand(symbol_table)?);
+
+            if self.at_line_end() {
+                break;
+            }
+            let token = self.next_token();
+            if token.kind != TokenKind::Comma {
+                return Err(parser_error(
+                    &format!("Expected ',' between operands, found {}", describe(&token)),
+                    token.location,
+                ));
+            }
+        }
+
+        Ok(operands)
+    }
+
+    fn parse_operand(&mut self, symbol_table: &mut SymbolTable) -> anyhow::Result<Operand> {
+        if self.expression_follows() {
+            return self.parse_expression_operand(symbol_table);
+        }
+        let token = self.next_token();
+        match token.kind {
+            TokenKind::Register => Ok(Operand::Register(self.resolve_register(&token)?)),
+            TokenKind::FloatRegister => {
+                let text = token_text(&token);
+                let number = float_register_number(text).ok_or_else(|| {
+                    parser_error(
+                        &format!(
+                            "Unknown register '{}'{}",
+                            text,
+                            did_you_mean(&float_registers_like(text))
+                        ),
+                        token.location.clone(),
+                    )
+                })?;
+                Ok(Operand::FloatRegister(number))
+            }
+            TokenKind::Number(_) => {
+                let value = parse_number(&token)?;
+                if self.peek_kind() == Some(&TokenKind::LParen) {
+                    let base = self.parse_base_register()?;
+                    Ok(Operand::Memory {
+                        offset: value,
+                        base,
+                    })
+                } else {
+                    Ok(Operand::Immediate(value))
+                }
+            }
+            TokenKind::LParen => {
+                // Step back so the base register is parsed the same way as with an offset
+                self.position -= 1;
+                let base = self.parse_base_register()?;
+                Ok(Operand::Memory { offset: 0, base })
+            }
+            TokenKind::Identifier if self.register_aliases.contains_key(token_text(&token)) => {
+                Ok(Operand::Register(self.resolve_register(&token)?))
+            }
+            TokenKind::Identifier => {
+                let name = token_text(&token);
+                symbol_table.add_reference(name, token.location.clone());
+                Ok(Operand::Symbol(name.to_string()))
+            }
+            TokenKind::String => Ok(Operand::String(parse_string(&token)?)),
+            TokenKind::Modifier => self.parse_modifier(&token, symbol_table),
+            _ => Err(parser_error(
+                &format!("Expected operand, found {}", describe(&token)),
+                token.location,
+            )),
+        }
+    }
+
+    /// Whether the next operand is an expression rather than a lone number, label or
+    /// `(reg)` memory operand
+    fn expression_follows(&self) -> bool {
+        let kind = |offset: usize| {
+            self.tokens
+                .get(self.position + offset)
+                .map(|token| &token.kind)
+        };
+        let is_register = |offset: usize| {
+            self.tokens
+                .get(self.position + offset)
+                .is_some_and(|token| {
+                    token.kind == TokenKind::Register
+                        || token.kind == TokenKind::Identifier
+                            && self.register_aliases.contains_key(token_text(token))
+                })
+        };
+        match kind(0) {
+            Some(TokenKind::Operator) => true,
+            Some(TokenKind::Number(_)) => kind(1) == Some(&TokenKind::Operator),
+            // `NAME(sp)` takes a constant's value as the offset
+            Some(TokenKind::Identifier) => {
+                matches!(kind(1), Some(TokenKind::Operator | TokenKind::LParen))
+            }
+            Some(TokenKind::LParen) => !(is_register(1) && kind(2) == Some(&TokenKind::RParen)),
+            _ => false,
+        }
+    }
+
+    /// Parses an expression operand, optionally followed by a base register. Constant
+    /// expressions are evaluated right away, see [`Operand::Expression`].
+    fn parse_expression_operand(
+        &mut self,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Operand> {
+        let location = self.tokens[self.position].location.clone();
+        let expression = self.parse_expression(symbol_table, 0)?;
+        let constant = |name: &str| symbol_table.constant(name);
+        let operand = match expression {
+            // A constant as a memory offset, other bare names are left to the instruction
+            Expression::Symbol(name) => match constant(&name) {
+                Some(value) if self.peek_kind() == Some(&TokenKind::LParen) => {
+                    Operand::Immediate(value)
+                }
+                _ => Operand::Symbol(name),
+            },
+            expression
+                if expression
+                    .symbols()
+                    .iter()
+                    .all(|name| constant(name).is_some()) =>
+            {
+                let value = expression
+                    .evaluate(&constant)
+                    .map_err(|message| parser_error(&message, location.clone()))?;
+                Operand::Immediate(value)
+            }
+            expression => Operand::Expression(expression),
+        };
+        if self.peek_kind() != Some(&TokenKind::LParen) {
+            return Ok(operand);
+        }
+        let Operand::Immediate(offset) = operand else {
+            return Err(parser_error(
+                "A memory offset has to be a constant, labels' addresses aren't known yet",
+                location,
+            ));
+        };
+        let base = self.parse_base_register()?;
+        Ok(Operand::Memory { offset, base })
+    }
+
+    /// Parses an expression of operators binding at least as tightly as `min_precedence`
+    fn parse_expression(
+        &mut self,
+        symbol_table: &mut SymbolTable,
+        min_precedence: u8,
+    ) -> anyhow::Result<Expression> {
+        let mut left = self.parse_unary(symbol_table)?;
+        loop {
+            let operator = self
+                .tokens
+                .get(self.position)
+                .filter(|token| token.kind == TokenKind::Operator)
+                .and_then(|token| BinaryOperator::from_text(token_text(token)))
+                .filter(|operator| operator.precedence() >= min_precedence);
+            let Some(operator) = operator else {
+                return Ok(left);
+            };
+            self.position += 1;
+            // Operators of the same precedence group to the left
+            let right = self.parse_expression(symbol_table, operator.precedence() + 1)?;
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+    }
+
+    /// Parses a number, a symbol, a parenthesized expression or a unary operator and its
+    /// operand
+    fn parse_unary(&mut self, symbol_table: &mut SymbolTable) -> anyhow::Result<Expression> {
+        let token = self.next_token();
+        match (token.kind.clone(), token_text(&token)) {
+            (TokenKind::Operator, "-") => Ok(Expression::Negate(Box::new(
+                self.parse_unary(symbol_table)?,
+            ))),
+            (TokenKind::Operator, "~") => {
+                Ok(Expression::Not(Box::new(self.parse_unary(symbol_table)?)))
+            }
+            (TokenKind::Operator, "!") => Ok(Expression::LogicalNot(Box::new(
+                self.parse_unary(symbol_table)?,
+            ))),
+            (TokenKind::Operator, "+") => self.parse_unary(symbol_table),
+            (TokenKind::Number(_), _) => Ok(Expression::Number(parse_number(&token)?)),
+            (TokenKind::Identifier, name) if !self.register_aliases.contains_key(name) => {
+                symbol_table.add_reference(name, token.location.clone());
+                Ok(Expression::Symbol(name.to_string()))
+            }
+            (TokenKind::LParen, _) => {
+                let expression = self.parse_expression(symbol_table, 0)?;
+                self.expect(TokenKind::RParen, "')' closing the expression")?;
+                Ok(expression)
+            }
+            _ => Err(parser_error(
+                &format!(
+                    "Expected a number or label in the expression, found {}",
+                    describe(&token)
+                ),
+                token.location,
+            )),
+        }
+    }
+
+    /// Parses the rest of `%hi(symbol)`, `%lo(symbol)`, `%pcrel_hi(symbol)` or
+    /// `%pcrel_lo(label)`, the low parts optionally followed by a base register. The label of
+    /// `%pcrel_lo` is the AUIPC's, see [`resolve_pcrel_lo`].
+    fn parse_modifier(
+        &mut self,
+        modifier: &Token,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Operand> {
+        let name = token_text(modifier).to_lowercase();
+        if !["%hi", "%lo", "%pcrel_hi", "%pcrel_lo"].contains(&name.as_str()) {
+            return Err(parser_error(
+                &format!(
+                    "Unknown operator '{}', expected %hi, %lo, %pcrel_hi or %pcrel_lo",
+                    name
+                ),
+                modifier.location.clone(),
+            ));
+        }
+        self.expect(TokenKind::LParen, &format!("'(' after {}", name))?;
+        let token = self.next_token();
+        if token.kind != TokenKind::Identifier {
+            return Err(parser_error(
+                &format!("Expected a label in {}(), found {}", name, describe(&token)),
+                token.location,
+            ));
+        }
+        let symbol = token_text(&token).to_string();
+        symbol_table.add_reference(&symbol, token.location);
+        self.expect(TokenKind::RParen, "')'")?;
+        let base = if self.peek_kind() == Some(&TokenKind::LParen) {
+            Some(self.parse_base_register()?)
+        } else {
+            None
+        };
+        match (name.as_str(), base) {
+            ("%hi", None) => Ok(Operand::Hi(symbol)),
+            ("%pcrel_hi", None) => Ok(Operand::PcrelHi(symbol)),
+            ("%lo", base) => Ok(Operand::Lo { symbol, base }),
+            ("%pcrel_lo", base) => Ok(Operand::PcrelLo { symbol, base }),
+            _ => Err(parser_error(
+                &format!("{}() is an upper part and can't take a base register", name),
+                modifier.location.clone(),
+            )),
+        }
+    }
+
+    /// Parses "(reg)"
+    fn parse_base_register(&mut self) -> anyhow::Result<u8> {
+        self.expect(TokenKind::LParen, "'('")?;
+        let token = self.next_token();
+        if !matches!(token.kind, TokenKind::Register | TokenKind::Identifier) {
+            return Err(parser_error(
+                &format!("Expected base register, found {}", describe(&token)),
+                token.location,
+            ));
+        }
+        let base = self.resolve_register(&token)?;
+        self.expect(TokenKind::RParen, "')'")?;
+        Ok(base)
+    }
+
+    /// Parses the rest of `.register name, reg`
+    fn parse_register_alias(&mut self) -> anyhow::Result<()> {
+        let name = self.next_token();
+        if name.kind != TokenKind::Identifier {
+            return Err(parser_error(
+                &format!(
+                    "Expected a new register name after .register, found {}",
+                    describe(&name)
+                ),
+                name.location,
+            ));
+        }
+        self.expect(TokenKind::Comma, "','")?;
+        let register = self.next_token();
+        if !matches!(register.kind, TokenKind::Register | TokenKind::Identifier) {
+            return Err(parser_error(
+                &format!("Expected a register, found {}", describe(&register)),
+                register.location,
+            ));
+        }
+        let number = self.resolve_register(&register)?;
+        if !self.at_line_end() {
+            let extra = self.next_token();
+            return Err(parser_error(
+                &format!("Unexpected {} after .register", describe(&extra)),
+                extra.location,
+            ));
+        }
+        trace!(location = %name.location, alias = token_text(&name), number, "register alias");
+        self.register_aliases
+            .insert(token_text(&name).to_string(), number);
+        Ok(())
+    }
+
+    /// Parses the rest of `.option rvc|norvc|push|pop`
+    fn parse_option(&mut self) -> anyhow::Result<()> {
+        let option = self.next_token();
+        match token_text(&option).to_lowercase().as_str() {
+            "rvc" => self.rvc = true,
+            "norvc" => self.rvc = false,
+            "push" => self.option_stack.push(self.rvc),
+            "pop" => {
+                self.rvc = self.option_stack.pop().ok_or_else(|| {
+                    parser_error(
+                        "'.option pop' without a matching '.option push'",
+                        option.location.clone(),
+                    )
+                })?;
+            }
+            _ => {
+                return Err(parser_error(
+                    &format!(
+                        "Expected rvc, norvc, push or pop after .option, found {}",
+                        describe(&option)
+                    ),
+                    option.location,
+                ));
+            }
+        }
+        if !self.at_line_end() {
+            let extra = self.next_token();
+            return Err(parser_error(
+                &format!("Unexpected {} after .option", describe(&extra)),
+                extra.location,
+            ));
+        }
+        trace!(location = %option.location, rvc = self.rvc, "option");
+        Ok(())
+    }
+
+    /// Parses the rest of `.section name[, "flags"...]` into the directive of the section
+    /// it switches to, the flags are left to the section
+    fn parse_section(&mut self, directive: Token) -> anyhow::Result<ParsedItem> {
+        let name = self.next_token();
+        let text = token_text(&name).to_lowercase();
+        let section = match name.kind {
+            TokenKind::Directive | TokenKind::Identifier => Section::from_section_name(&text),
+            _ => None,
+        };
+        let Some(section) = section else {
+            return Err(parser_error(
+                &format!(
+                    "Expected .text, .rodata, .data or .bss (or a subsection of one) after \
+                     .section, found {}",
+                    describe(&name)
+                ),
+                name.location,
+            ));
+        };
+        while !self.at_line_end() {
+            self.next_token();
+        }
+        trace!(location = %name.location, section = section.name(), "section");
+        Ok(ParsedItem::Directive {
+            name: section.name().to_string(),
+            args: Vec::new(),
+            location: directive.location,
+        })
+    }
+
+    /// Whether the rest of the line has a `=`, making it a literal pool load
+    fn literal_follows(&self) -> bool {
+        self.tokens[self.position..]
+            .iter()
+            .take_while(|token| !matches!(token.kind, TokenKind::Newline | TokenKind::EndOfFile))
+            .any(|token| token.kind == TokenKind::Equals)
+    }
+
+    /// Parses the rest of `lw rd, =value` (or `=label`) into an AUIPC and LW reading the
+    /// value from the literal pool. Loads of the same value share its pool entry.
+    fn parse_literal_load(
+        &mut self,
+        mnemonic: &Token,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<ParsedItem>> {
+        let register = self.next_token();
+        if !matches!(register.kind, TokenKind::Register | TokenKind::Identifier) {
+            return Err(parser_error(
+                &format!("Expected a register, found {}", describe(&register)),
+                register.location,
+            ));
+        }
+        let rd = self.resolve_register(&register)?;
+        // x0 reads as zero, so it can't hold the address of the pool entry
+        if rd == 0 {
+            return Err(parser_error(
+                "A literal can't be loaded into x0",
+                register.location,
+            ));
+        }
+        self.expect(TokenKind::Comma, "','")?;
+        self.expect(TokenKind::Equals, "'='")?;
+        let token = self.next_token();
+        let value = match token.kind {
+            TokenKind::Number(_) => {
+                let value = parse_number(&token)?;
+                // LW sign extends, so RV64 can only load what fits in an i32
+                let range = match self.xlen {
+                    Xlen::Rv32 => i32::MIN as i64..=u32::MAX as i64,
+                    Xlen::Rv64 => i32::MIN as i64..=i32::MAX as i64,
+                };
+                if !range.contains(&value) {
+                    return Err(parser_error(
+                        &format!("Literal {} does not fit in a word", value),
+                        token.location,
+                    ));
+                }
+                Operand::Immediate(value)
+            }
+            TokenKind::Identifier => {
+                let name = token_text(&token);
+                symbol_table.add_reference(name, token.location.clone());
+                Operand::Symbol(name.to_string())
+            }
+            _ => {
+                return Err(parser_error(
+                    &format!(
+                        "Expected a number or label after '=', found {}",
+                        describe(&token)
+                    ),
+                    token.location,
+                ));
+            }
+        };
+        if !self.at_line_end() {
+            let extra = self.next_token();
+            return Err(parser_error(
+                &format!("Unexpected {} after the literal", describe(&extra)),
+                extra.location,
+            ));
+        }
+
+        let label = match self
+            .literal_pool
+            .iter()
+            .find(|literal| literal.value == value)
+        {
+            Some(literal) => literal.label.clone(),
+            None => {
+                let label = format!("{}{}", LITERAL_LABEL_PREFIX, self.literal_count);
+                self.literal_count += 1;
+                self.literal_pool.push(Literal {
+                    label: label.clone(),
+                    value,
+                    location: mnemonic.location.clone(),
+                });
+                label
+            }
+        };
+        symbol_table.add_reference(&label, mnemonic.location.clone());
+        trace!(location = %mnemonic.location, label, "literal load");
+        let instruction = |name: &str, operand: Operand| {
+            ParsedItem::Instruction(Instruction {
+                mnemonic: name.to_string(),
+                operands: vec![Operand::Register(rd), operand],
+                location: mnemonic.location.clone(),
+                operand_locations: Vec::new(),
+                compressed: None,
+            })
+        };
+        Ok(vec![
+            instruction("auipc", Operand::PcrelHi(label.clone())),
+            instruction(
+                "lw",
+                Operand::PcrelLo {
+                    symbol: label,
+                    base: Some(rd),
+                },
+            ),
+        ])
+    }
+
+    /// Places the waiting literals at a word boundary, after a `.ltorg` or at the end of the
+    /// program. The `.ltorg` item the literals follow says how many there are.
+    fn flush_literal_pool(
+        &mut self,
+        directive: &Token,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<ParsedItem>> {
+        if self.literal_pool.is_empty() {
+            return Ok(Vec::new());
+        }
+        debug!(location = %directive.location, literals = self.literal_pool.len(), "literal pool");
+        let mut items = vec![ParsedItem::Directive {
+            name: ".ltorg".to_string(),
+            args: vec![Operand::Immediate(self.literal_pool.len() as i64)],
+            location: directive.location.clone(),
+        }];
+        for literal in std::mem::take(&mut self.literal_pool) {
+            symbol_table.define(&literal.label, literal.location.clone())?;
+            items.push(ParsedItem::Label {
+                name: literal.label,
+                location: literal.location.clone(),
+            });
+            items.push(ParsedItem::Directive {
+                name: ".word".to_string(),
+                args: vec![literal.value],
+                location: literal.location,
+            });
+        }
+        Ok(items)
+    }
+
+    /// With `.option rvc`, picks the compressed form if the instruction has one.
+    ///
+    /// Sizes have to be known before labels get their addresses, so only instructions that
+    /// don't refer to labels are compressed; `c.j`, `c.beqz` and the like have to be written
+    /// out explicitly.
+    fn maybe_compress(&self, instruction: Instruction) -> Instruction {
+        if !self.rvc
+            || self.xlen == Xlen::Rv64
+            || instruction
+                .operands
+                .iter()
+                .any(|operand| matches!(operand, Operand::Symbol(_) | Operand::Expression(_)))
+        {
+            return instruction;
+        }
+        let form = encode(&instruction, 0, &SymbolTable::new(), self.xlen)
+            .ok()
+            .and_then(|word| compressed::compress(word).first().map(|(form, _)| *form));
+        Instruction {
+            compressed: form.map(str::to_string),
+            ..instruction
+        }
+    }
+
+    /// Parses the rest of `.equ name, address` (or `.set`)
+    fn parse_absolute_symbol(
+        &mut self,
+        directive: &Token,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<()> {
+        let directive = token_text(directive).to_lowercase();
+        let name = self.next_token();
+        if name.kind != TokenKind::Identifier {
+            return Err(parser_error(
+                &format!(
+                    "Expected a symbol name after {}, found {}",
+                    directive,
+                    describe(&name)
+                ),
+                name.location,
+            ));
+        }
+        self.expect(TokenKind::Comma, "','")?;
+        let value = self.tokens[self.position.min(self.tokens.len() - 1)].clone();
+        let constant = match self.parse_operand(symbol_table)? {
+            Operand::Immediate(value) => Some(value),
+            // Another constant's value
+            Operand::Symbol(name) => symbol_table.constant(&name),
+            _ => None,
+        };
+        let Some(address) = constant else {
+            return Err(parser_error(
+                &format!(
+                    "Expected an address, a number or an expression of earlier {} symbols, \
+                     found {}",
+                    directive,
+                    describe(&value)
+                ),
+                value.location,
+            ));
+        };
+        // Negative values wrap around like addresses computed by the program would
+        let address = i32::try_from(address)
+            .map(|address| address as u32)
+            .or_else(|_| u32::try_from(address))
+            .map_err(|_| {
+                parser_error(
+                    &format!("Address {} is out of the 32 bit range", address),
+                    value.location.clone(),
+                )
+            })?;
+        if !self.at_line_end() {
+            let extra = self.next_token();
+            return Err(parser_error(
+                &format!("Unexpected {} after {}", describe(&extra), directive),
+                extra.location,
+            ));
+        }
+        trace!(location = %name.location, name = token_text(&name), address, "absolute symbol");
+        symbol_table.define_absolute(token_text(&name), address, name.location.clone())?;
+        Ok(())
+    }
+
+    /// Parses a line whose statement is a conditional assembly directive, returning whether
+    /// the branch an `.if`, `.ifdef`, `.ifndef` or `.elseif` starts is assembled, `None` for
+    /// `.else` and `.endif`. See [`conditional`](crate::conditional).
+    pub(crate) fn parse_conditional(
+        &mut self,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Option<bool>> {
+        let directive = self.next_directive();
+        let name = token_text(&directive).to_lowercase();
+        if name == ".else" || name == ".endif" {
+            self.end_conditional_directive(&name)?;
+            return Ok(None);
+        }
+        self.parse_condition(&name, symbol_table).map(Some)
+    }
+
+    /// Parses a line whose statement is `.equ` or `.set`, for conditions on the symbol
+    pub(crate) fn parse_constant(&mut self, symbol_table: &mut SymbolTable) -> anyhow::Result<()> {
+        let directive = self.next_directive();
+        self.parse_absolute_symbol(&directive, symbol_table)
+    }
+
+    /// The directive of the line, past the labels before it
+    fn next_directive(&mut self) -> Token<'a> {
+        loop {
+            let token = self.next_token();
+            if matches!(token.kind, TokenKind::Directive | TokenKind::EndOfFile) {
+                return token;
+            }
+        }
+    }
+
+    /// Parses the rest of `.if expression`, `.elseif expression`, `.ifdef name` or
+    /// `.ifndef name`, returning whether the branch it starts is assembled.
+    ///
+    /// Expressions can only use numbers and symbols defined with `.equ` (or `-D`) before,
+    /// branches are assembled if they aren't 0. `.ifdef` asks whether a label or `.equ`
+    /// symbol was defined on an earlier line.
+    fn parse_condition(
+        &mut self,
+        directive: &str,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<bool> {
+        let condition = if directive == ".ifdef" || directive == ".ifndef" {
+            let name = self.next_token();
+            if name.kind != TokenKind::Identifier {
+                return Err(parser_error(
+                    &format!(
+                        "Expected a symbol name after {}, found {}",
+                        directive,
+                        describe(&name)
+                    ),
+                    name.location,
+                ));
+            }
+            let defined = symbol_table
+                .get(token_text(&name))
+                .is_some_and(|symbol| symbol.definition.is_some());
+            defined == (directive == ".ifdef")
+        } else {
+            let location = self.tokens[self.position.min(self.tokens.len() - 1)]
+                .location
+                .clone();
+            let expression = self.parse_expression(symbol_table, 0)?;
+            let constant = |name: &str| symbol_table.constant(name);
+            if let Some(name) = expression
+                .symbols()
+                .into_iter()
+                .find(|name| constant(name).is_none())
+            {
+                return Err(parser_error(
+                    &format!(
+                        "The condition of {} can only use earlier .equ symbols, '{}' isn't one",
+                        directive, name
+                    ),
+                    location,
+                ));
+            }
+            let value = expression
+                .evaluate(&constant)
+                .map_err(|message| parser_error(&message, location))?;
+            value != 0
+        };
+        self.end_conditional_directive(directive)?;
+        Ok(condition)
+    }
+
+    /// Fails unless a conditional directive is at the end of its line
+    fn end_conditional_directive(&mut self, directive: &str) -> anyhow::Result<()> {
+        if self.at_line_end() {
+            return Ok(());
+        }
+        let extra = self.next_token();
+        Err(parser_error(
+            &format!("Unexpected {} after {}", describe(&extra), directive),
+            extra.location,
+        ))
+    }
+
+    /// Parses the rest of `.vector_table name, handler...` into an alignment directive, the
+    /// table's label and one `jal zero, handler` per entry.
+    ///
+    /// The layout is the one `mtvec` expects in vectored mode: exceptions go to the first
+    /// entry and interrupt cause N to entry N, so handlers are listed in cause order.
+    fn parse_vector_table(
+        &mut self,
+        directive: &Token,
+        symbol_table: &mut SymbolTable,
+    ) -> anyhow::Result<Vec<ParsedItem>> {
+        let name = self.next_token();
+        if name.kind != TokenKind::Identifier {
+            return Err(parser_error(
+                &format!(
+                    "Expected a table name after .vector_table, found {}",
+                    describe(&name)
+                ),
+                name.location,
+            ));
+        }
+        let comma = self.next_token();
+        if comma.kind != TokenKind::Comma {
+            return Err(parser_error(
+                &format!(
+                    "'.vector_table' expects at least one handler, found {}",
+                    describe(&comma)
+                ),
+                comma.location,
+            ));
+        }
+        let handlers = self.parse_operands(symbol_table)?;
+
+        let table = token_text(&name).to_string();
+        symbol_table.define(&table, name.location.clone())?;
+        // The hardware refers to the table through mtvec, it's never dead
+        symbol_table.add_reference(&table, name.location.clone());
+        let mut items = vec![
+            ParsedItem::Directive {
+                name: ".vector_table".to_string(),
+                args: vec![Operand::Immediate(handlers.len() as i64)],
+                location: directive.location.clone(),
+            },
+            ParsedItem::Label {
+                name: table,
+                location: name.location,
+            },
+        ];
+        for handler in handlers {
+            if !matches!(handler, Operand::Symbol(_)) {
+                return Err(parser_error(
+                    "'.vector_table' handlers must be labels",
+                    directive.location.clone(),
+                ));
+            }
+            items.push(ParsedItem::Instruction(Instruction {
+                mnemonic: "jal".to_string(),
+                operands: vec![Operand::Register(0), handler],
+                location: directive.location.clone(),
+                operand_locations: Vec::new(),
+                compressed: None,
+            }));
+        }
+        trace!(location = %directive.location, entries = items.len() - 2, "vector table");
+        Ok(items)
+    }
+
+    /// Register number of a register name or alias, checked against the target register set
+    fn resolve_register(&self, token: &Token) -> anyhow::Result<u8> {
+        let text = token_text(token);
+        let number = register_number(text)
+            .or_else(|| self.register_aliases.get(text).copied())
+            .ok_or_else(|| {
+                parser_error(
+                    &format!(
+                        "Unknown register '{}'{}",
+                        text,
+                        did_you_mean(&registers_like(text))
+                    ),
+                    token.location.clone(),
+                )
+            })?;
+        if !self.register_set.contains(number) {
+            return Err(parser_error(
+                &format!(
+                    "Register '{}' (x{}) is not available on RV32E",
+                    text, number
+                ),
+                token.location.clone(),
+            ));
+        }
+        Ok(number)
+    }
+
+    fn expect(&mut self, kind: TokenKind, expected: &str) -> anyhow::Result<Token<'a>> {
+        let token = self.next_token();
+        if token.kind != kind {
+            return Err(parser_error(
+                &format!("Expected {}, found {}", expected, describe(&token)),
+                token.location,
+            ));
+        }
+        Ok(token)
+    }
+
+    /// Records that the next token starts an operand
+    fn mark_operand(&mut self) {
+        let index = self.position.min(self.tokens.len() - 1);
+        self.operand_locations
+            .push(self.tokens[index].location.clone());
+    }
+
+    fn next_token(&mut self) -> Token<'a> {
+        // The tokenizer always terminates the stream with EndOfFile, so keep returning it
+        let index = self.position.min(self.tokens.len() - 1);
+        self.position += 1;
+        let token = self.tokens[index].clone();
+        match local_label_reference(&token) {
+            Some((number, forward)) => {
+                let defined = self.local_labels.get(&number).copied().unwrap_or_default();
+                Token {
+                    text: Cow::Owned(local_label_symbol(number, defined + usize::from(forward))),
+                    ..token
+                }
+            }
+            None => token,
+        }
+    }
+
+    /// Numbers another definition of the numeric local label `number`, returning its symbol
+    fn define_local_label(&mut self, number: &Token) -> anyhow::Result<String> {
+        let Ok(number) = token_text(number).parse::<u64>() else {
+            return Err(parser_error(
+                &format!("Invalid numeric label '{}'", token_text(number)),
+                number.location.clone(),
+            ));
+        };
+        let defined = self.local_labels.entry(number).or_default();
+        *defined += 1;
+        Ok(local_label_symbol(number, *defined))
+    }
+
+    fn peek_kind(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn at_line_end(&self) -> bool {
+        matches!(
+            self.peek_kind(),
+            None | Some(TokenKind::Newline) | Some(TokenKind::EndOfFile)
+        )
+    }
+}
+
+/// Expands a pseudoinstruction into the base instructions it stands for
+fn expand_pseudoinstruction(
+    mnemonic: &str,
+    operands: Vec<Operand>,
+    xlen: Xlen,
+    location: &SourceLocation,
+) -> anyhow::Result<Vec<Instruction>> {
+    let mnemonic = mnemonic.to_lowercase();
+    let instruction = |mnemonic: &str, operands: Vec<Operand>| Instruction {
+        mnemonic: mnemonic.to_string(),
+        operands,
+        location: location.clone(),
+        operand_locations: Vec::new(),
+        compressed: None,
+    };
+    let wrong_operands = |expected: &str| {
+        parser_error(
+            &format!("'{}' expects {}", mnemonic, expected),
+            location.clone(),
+        )
+    };
+    // An AUIPC of the upper part of the offset to `symbol` into `base`, then `mnemonic`
+    // adding the lower part to it
+    let symbol_access = |mnemonic: &str, first: Operand, symbol: &str, base: u8| {
+        vec![
+            instruction(
+                "auipc",
+                vec![
+                    Operand::Register(base),
+                    Operand::PcrelHi(symbol.to_string()),
+                ],
+            ),
+            instruction(
+                mnemonic,
+                vec![
+                    first,
+                    Operand::PcrelLo {
+                        symbol: symbol.to_string(),
+                        base: Some(base),
+                    },
+                ],
+            ),
+        ]
+    };
+
+    let expanded = match (mnemonic.as_str(), operands.as_slice()) {
+        ("inc", [Operand::Register(rd)]) => vec![instruction(
+            "addi",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rd),
+                Operand::Immediate(1),
+            ],
+        )],
+        ("inc", _) => return Err(wrong_operands("rd")),
+        ("dec", [Operand::Register(rd)]) => vec![instruction(
+            "addi",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rd),
+                Operand::Immediate(-1),
+            ],
+        )],
+        ("dec", _) => return Err(wrong_operands("rd")),
+        ("mv", [Operand::Register(rd), Operand::Register(rs1)]) => vec![instruction(
+            "addi",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rs1),
+                Operand::Immediate(0),
+            ],
+        )],
+        ("mv", _) => return Err(wrong_operands("rd, rs1")),
+        ("nop", []) => vec![instruction(
+            "addi",
+            vec![
+                Operand::Register(0),
+                Operand::Register(0),
+                Operand::Immediate(0),
+            ],
+        )],
+        ("nop", _) => return Err(wrong_operands("no operands")),
+        ("neg", [Operand::Register(rd)]) => vec![instruction(
+            "sub",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(0),
+                Operand::Register(*rd),
+            ],
+        )],
+        ("neg", [Operand::Register(rd), Operand::Register(rs)]) => vec![instruction(
+            "sub",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(0),
+                Operand::Register(*rs),
+            ],
+        )],
+        ("neg", _) => return Err(wrong_operands("rd or rd, rs")),
+        ("li", [Operand::Register(rd), Operand::Immediate(value)]) => {
+            load_immediate_instructions(*rd, *value, xlen, location)?
+        }
+        // Values only known once addresses are take as many instructions as they need, see
+        // [`layout`](crate::layout)
+        (
+            "li",
+            [
+                Operand::Register(_),
+                Operand::Symbol(_) | Operand::Expression(_),
+            ],
+        ) => {
+            vec![instruction("li", operands.clone())]
+        }
+        ("li", _) => return Err(wrong_operands("rd, imm or rd, label")),
+        ("not", [Operand::Register(rd), Operand::Register(rs)]) => vec![instruction(
+            "xori",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rs),
+                Operand::Immediate(-1),
+            ],
+        )],
+        ("negw" | "sext.w", _) if xlen == Xlen::Rv32 => {
+            return Err(parser_error(
+                &format!("'{}' is only available on RV64", mnemonic),
+                location.clone(),
+            ));
+        }
+        ("negw", [Operand::Register(rd), Operand::Register(rs)]) => vec![instruction(
+            "subw",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(0),
+                Operand::Register(*rs),
+            ],
+        )],
+        ("sext.w", [Operand::Register(rd), Operand::Register(rs)]) => vec![instruction(
+            "addiw",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rs),
+                Operand::Immediate(0),
+            ],
+        )],
+        ("seqz", [Operand::Register(rd), Operand::Register(rs)]) => vec![instruction(
+            "sltiu",
+            vec![
+                Operand::Register(*rd),
+                Operand::Register(*rs),
+                Operand::Immediate(1),
+            ],
+        )],
+        // Comparisons with zero, x0 goes first to compare the other way around
+        ("snez" | "sltz" | "sgtz", [Operand::Register(rd), Operand::Register(rs)]) => {
+            let (mnemonic, rs1, rs2) = match mnemonic.as_str() {
+                "snez" => ("sltu", 0, *rs),
+                "sltz" => ("slt", *rs, 0),
+                _ => ("slt", 0, *rs),
+            };
+            vec![instruction(
+                mnemonic,
+                vec![
+                    Operand::Register(*rd),
+                    Operand::Register(rs1),
+                    Operand::Register(rs2),
+                ],
+            )]
+        }
+        ("csrr", [Operand::Register(rd), Operand::Csr(csr)]) => vec![instruction(
+            "csrrs",
+            vec![
+                Operand::Register(*rd),
+                Operand::Csr(*csr),
+                Operand::Register(0),
+            ],
+        )],
+        ("csrr", _) => return Err(wrong_operands("rd, csr")),
+        // Writes, sets and clears of a CSR that don't read it, they write x0
+        ("csrw" | "csrs" | "csrc", [Operand::Csr(csr), Operand::Register(rs)]) => {
+            vec![instruction(
+                &format!("csrr{}", &mnemonic[3..]),
+                vec![
+                    Operand::Register(0),
+                    Operand::Csr(*csr),
+                    Operand::Register(*rs),
+                ],
+            )]
+        }
+        ("csrw" | "csrs" | "csrc", _) => return Err(wrong_operands("csr, rs")),
+        ("csrwi" | "csrsi" | "csrci", [Operand::Csr(csr), Operand::Immediate(uimm)]) => {
+            vec![instruction(
+                &format!("csrr{}", &mnemonic[3..]),
+                vec![
+                    Operand::Register(0),
+                    Operand::Csr(*csr),
+                    Operand::Immediate(*uimm),
+                ],
+            )]
+        }
+        ("csrwi" | "csrsi" | "csrci", _) => return Err(wrong_operands("csr, uimm")),
+        ("not" | "negw" | "sext.w" | "seqz" | "snez" | "sltz" | "sgtz", _) => {
+            return Err(wrong_operands("rd, rs"));
+        }
+        (
+            "fmv.s" | "fabs.s" | "fneg.s" | "fmv.d" | "fabs.d" | "fneg.d",
+            [Operand::FloatRegister(rd), Operand::FloatRegister(rs)],
+        ) => {
+            let (operation, precision) = mnemonic.split_once('.').unwrap_or_default();
+            let sign = match operation {
+                "fmv" => "fsgnj",
+                "fabs" => "fsgnjx",
+                _ => "fsgnjn",
+            };
+            vec![instruction(
+                &format!("{}.{}", sign, precision),
+                vec![
+                    Operand::FloatRegister(*rd),
+                    Operand::FloatRegister(*rs),
+                    Operand::FloatRegister(*rs),
+                ],
+            )]
+        }
+        ("fmv.s" | "fabs.s" | "fneg.s" | "fmv.d" | "fabs.d" | "fneg.d", _) => {
+            return Err(wrong_operands("fd, fs"));
+        }
+        (
+            "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz",
+            [
+                Operand::Register(rs),
+                target @ (Operand::Symbol(_) | Operand::Immediate(_)),
+            ],
+        ) => {
+            let (mnemonic, rs1, rs2) = match mnemonic.as_str() {
+                "beqz" => ("beq", *rs, 0),
+                "bnez" => ("bne", *rs, 0),
+                "blez" => ("bge", 0, *rs),
+                "bgez" => ("bge", *rs, 0),
+                "bltz" => ("blt", *rs, 0),
+                _ => ("blt", 0, *rs),
+            };
+            vec![instruction(
+                mnemonic,
+                vec![
+                    Operand::Register(rs1),
+                    Operand::Register(rs2),
+                    target.clone(),
+                ],
+            )]
+        }
+        ("beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz", _) => {
+            return Err(wrong_operands("rs, label"));
+        }
+        // The branches the base set lacks, with the registers swapped
+        (
+            "bgt" | "ble" | "bgtu" | "bleu",
+            [
+                Operand::Register(rs),
+                Operand::Register(rt),
+                target @ (Operand::Symbol(_) | Operand::Immediate(_)),
+            ],
+        ) => {
+            let mnemonic = match mnemonic.as_str() {
+                "bgt" => "blt",
+                "ble" => "bge",
+                "bgtu" => "bltu",
+                _ => "bgeu",
+            };
+            vec![instruction(
+                mnemonic,
+                vec![
+                    Operand::Register(*rt),
+                    Operand::Register(*rs),
+                    target.clone(),
+                ],
+            )]
+        }
+        ("bgt" | "ble" | "bgtu" | "bleu", _) => return Err(wrong_operands("rs, rt, label")),
+        ("j", [target @ (Operand::Symbol(_) | Operand::Immediate(_))]) => {
+            vec![instruction(
+                "jal",
+                vec![Operand::Register(0), target.clone()],
+            )]
+        }
+        ("j", _) => return Err(wrong_operands("label")),
+        ("jr", [Operand::Register(rs)]) => vec![instruction(
+            "jalr",
+            vec![
+                Operand::Register(0),
+                Operand::Memory {
+                    offset: 0,
+                    base: *rs,
+                },
+            ],
+        )],
+        ("jr", _) => return Err(wrong_operands("rs")),
+        ("ret", []) => vec![instruction(
+            "jalr",
+            vec![Operand::Register(0), Operand::Memory { offset: 0, base: 1 }],
+        )],
+        ("ret", _) => return Err(wrong_operands("no operands")),
+        ("jal", [target @ (Operand::Symbol(_) | Operand::Immediate(_))]) => {
+            vec![instruction(
+                "jal",
+                vec![Operand::Register(1), target.clone()],
+            )]
+        }
+        ("jalr", [Operand::Register(rs)]) | ("jalr", [_, Operand::Register(rs)]) => {
+            let rd = match operands.as_slice() {
+                [Operand::Register(rd), _] => *rd,
+                _ => 1,
+            };
+            vec![instruction(
+                "jalr",
+                vec![
+                    Operand::Register(rd),
+                    Operand::Memory {
+                        offset: 0,
+                        base: *rs,
+                    },
+                ],
+            )]
+        }
+        // Calls and address loads are sized once addresses are known, see
+        // [`layout`](crate::layout)
+        ("call", [Operand::Register(0), _]) => {
+            return Err(parser_error(
+                "'call' can't link through x0, use 'tail'",
+                location.clone(),
+            ));
+        }
+        ("call", [Operand::Symbol(symbol)]) => vec![instruction(
+            "call",
+            vec![Operand::Register(1), Operand::Symbol(symbol.clone())],
+        )],
+        ("call", [Operand::Register(_), Operand::Symbol(_)]) | ("tail", [Operand::Symbol(_)]) => {
+            vec![instruction(&mnemonic, operands.clone())]
+        }
+        ("call", _) => return Err(wrong_operands("label or rd, label")),
+        ("tail", _) => return Err(wrong_operands("label")),
+        ("la" | "lla", [Operand::Register(0), _]) => {
+            return Err(parser_error(
+                "An address can't be loaded into x0",
+                location.clone(),
+            ));
+        }
+        ("la" | "lla", [Operand::Register(_), Operand::Symbol(_)]) => {
+            vec![instruction("la", operands.clone())]
+        }
+        ("la" | "lla", _) => return Err(wrong_operands("rd, label")),
+        // Loads and stores of a label's contents, through the register loaded or a scratch
+        // register for the address
+        (_, [Operand::Register(0), Operand::Symbol(_)]) if is_load(&mnemonic) => {
+            return Err(parser_error(
+                "An address can't be loaded into x0",
+                location.clone(),
+            ));
+        }
+        (_, [Operand::Register(rd), Operand::Symbol(symbol)]) if is_load(&mnemonic) => {
+            symbol_access(&mnemonic, Operand::Register(*rd), symbol, *rd)
+        }
+        (_, [_, Operand::Symbol(_), Operand::Register(0)]) => {
+            return Err(parser_error(
+                "An address can't be loaded into x0",
+                location.clone(),
+            ));
+        }
+        (
+            "flw" | "fld",
+            [
+                rd @ Operand::FloatRegister(_),
+                Operand::Symbol(symbol),
+                Operand::Register(rt),
+            ],
+        )
+        | (
+            "sb" | "sh" | "sw" | "sd",
+            [
+                rd @ Operand::Register(_),
+                Operand::Symbol(symbol),
+                Operand::Register(rt),
+            ],
+        )
+        | (
+            "fsw" | "fsd",
+            [
+                rd @ Operand::FloatRegister(_),
+                Operand::Symbol(symbol),
+                Operand::Register(rt),
+            ],
+        ) => symbol_access(&mnemonic, rd.clone(), symbol, *rt),
+        _ => {
+            return Err(parser_error(
+                &format!("Unsupported pseudoinstruction '{}'", mnemonic),
+                location.clone(),
+            ));
+        }
+    };
+
+    Ok(expanded)
+}
+
+/// Replaces earlier `.equ` constants in the immediate operands of `mnemonic` by their
+/// values. Branch and jump targets and the labels of loads and stores stay symbols, they
+/// are addresses rather than offsets.
+fn fold_constants(mnemonic: &str, operands: &mut [Operand], symbol_table: &SymbolTable) {
+    let takes_immediate = isa::lookup(mnemonic).is_some_and(|definition| {
+        matches!(
+            definition.format,
+            Format::I(_)
+                | Format::Shift(..)
+                | Format::Upper
+                | Format::Jalr
+                | Format::CsrImmediate(_)
+        )
+    });
+    if !takes_immediate {
+        return;
+    }
+    for operand in operands {
+        if let Operand::Symbol(name) = operand
+            && let Some(value) = symbol_table.constant(name)
+        {
+            *operand = Operand::Immediate(value);
+        }
+    }
+}
+
+/// Replaces the AUIPC label of each `%pcrel_lo(label)` in `operands` by the label its
+/// `%pcrel_hi` refers to, the AUIPC being the last of the `items` so far. Errors with the
+/// index of an operand whose label isn't such an AUIPC's.
+fn resolve_pcrel_lo(items: &[ParsedItem], operands: &mut [Operand]) -> Result<(), usize> {
+    let mut previous = items.iter().rev();
+    let target = match previous.next() {
+        Some(ParsedItem::Instruction(Instruction {
+            mnemonic, operands, ..
+        })) if mnemonic == "auipc" => match operands.as_slice() {
+            [_, Operand::PcrelHi(target)] => Some(target),
+            _ => None,
+        },
+        _ => None,
+    };
+    let labels: Vec<&str> = previous
+        .map_while(|item| match item {
+            ParsedItem::Label { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    for (index, operand) in operands.iter_mut().enumerate() {
+        if let Operand::PcrelLo { symbol, .. } = operand {
+            match target {
+                Some(target) if labels.contains(&symbol.as_str()) => *symbol = target.clone(),
+                _ => return Err(index),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Place of the CSR among the operands of a CSR pseudoinstruction
+fn pseudo_csr_operand(mnemonic: &str) -> Option<usize> {
+    match mnemonic {
+        "csrr" => Some(1),
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => Some(0),
+        _ => None,
+    }
+}
+
+/// Whether the operands of the base instruction `mnemonic` are a form
+/// [`expand_pseudoinstruction`] handles, like `jal label`, `jalr rs` or `lw rd, label`
+fn is_pseudo_form(mnemonic: &str, operands: &[Operand]) -> bool {
+    match operands {
+        [Operand::Symbol(_) | Operand::Immediate(_)] => mnemonic == "jal",
+        [Operand::Register(_)] | [Operand::Register(_), Operand::Register(_)] => mnemonic == "jalr",
+        [_, Operand::Symbol(_)] => is_load(mnemonic),
+        [_, Operand::Symbol(_), Operand::Register(_)] => {
+            matches!(
+                mnemonic,
+                "flw" | "fld" | "sb" | "sh" | "sw" | "sd" | "fsw" | "fsd"
+            )
+        }
+        _ => false,
+    }
+}
+
+fn is_load(mnemonic: &str) -> bool {
+    matches!(mnemonic, "lb" | "lh" | "lw" | "lbu" | "lhu" | "ld" | "lwu")
+}
+
+/// The instructions of `li rd, value`, see [`load_immediate`]
+pub(crate) fn load_immediate_instructions(
+    rd: u8,
+    value: i64,
+    xlen: Xlen,
+    location: &SourceLocation,
+) -> anyhow::Result<Vec<Instruction>> {
+    // RV32 takes signed and unsigned 32 bit values alike, they name the same bits
+    let value = match xlen {
+        Xlen::Rv32 if (i32::MIN as i64..=u32::MAX as i64).contains(&value) => value as i32 as i64,
+        Xlen::Rv32 => {
+            return Err(parser_error(
+                &format!("Immediate {} does not fit in 32 bits", value),
+                location.clone(),
+            ));
+        }
+        Xlen::Rv64 => value,
+    };
+    let mut source = 0;
+    Ok(load_immediate(value, xlen)
+        .into_iter()
+        .map(|(mnemonic, imm)| {
+            let mut operands = vec![Operand::Register(rd)];
+            if mnemonic != "lui" {
+                operands.push(Operand::Register(source));
+            }
+            operands.push(Operand::Immediate(imm));
+            source = rd;
+            Instruction {
+                mnemonic: mnemonic.to_string(),
+                operands,
+                location: location.clone(),
+                operand_locations: Vec::new(),
+                compressed: None,
+            }
+        })
+        .collect())
+}
+
+/// Instructions building `value` in a register, each a mnemonic and its immediate. The first
+/// reads x0 (or is a LUI), the rest the register the previous ones left the value in.
+///
+/// The low 12 bits go into an ADDI, which sign-extends them, so the upper part is rounded up
+/// when bit 11 is set. Values beyond 32 bits (RV64 only) are built from their upper bits,
+/// shifted into place, then the low 12 bits are added.
+pub(crate) fn load_immediate(value: i64, xlen: Xlen) -> Vec<(&'static str, i64)> {
+    let lower = value << 52 >> 52;
+    if value as i32 as i64 == value {
+        let upper = (value as i32).wrapping_sub(lower as i32) as u32 >> 12;
+        let mut sequence = Vec::new();
+        if upper != 0 {
+            sequence.push(("lui", upper as i64));
+        }
+        if lower != 0 || upper == 0 {
+            // On RV64 LUI sign-extends bit 31, ADDIW wraps the sum back into 32 bits
+            let add = if upper != 0 && xlen == Xlen::Rv64 {
+                "addiw"
+            } else {
+                "addi"
+            };
+            sequence.push((add, lower));
+        }
+        return sequence;
+    }
+    let upper = value.wrapping_add(0x800) >> 12;
+    let shift = 12 + upper.trailing_zeros() as i64;
+    let mut sequence = load_immediate(upper >> (shift - 12), xlen);
+    sequence.push(("slli", shift));
+    if lower != 0 {
+        sequence.push(("addi", lower));
+    }
+    sequence
+}
+
+fn parse_number(token: &Token) -> anyhow::Result<i64> {
+    let text = token_text(token);
+    let invalid = || {
+        parser_error(
+            &format!("Invalid number '{}'", text),
+            token.location.clone(),
+        )
+    };
+    let out_of_range = || {
+        parser_error(
+            &format!("Number '{}' is out of range", text),
+            token.location.clone(),
+        )
+    };
+    let radix = match token.kind {
+        TokenKind::Number(Base::Char) => return parse_char(token),
+        TokenKind::Number(Base::Hex) => 16,
+        TokenKind::Number(Base::Bin) => 2,
+        TokenKind::Number(Base::Oct) => 8,
+        _ => {
+            return text.parse::<i64>().map_err(|error| match error.kind() {
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => out_of_range(),
+                _ => invalid(),
+            });
+        }
+    };
+    // Prefixed numbers may use all 64 bits, like "0xffffffffffffffff" for -1
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, &digits[2..]),
+        None => (false, &text[2..]),
+    };
+    let value = u64::from_str_radix(digits, radix).map_err(|error| match error.kind() {
+        IntErrorKind::PosOverflow => out_of_range(),
+        _ => invalid(),
+    })? as i64;
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// The value of a character literal, like `'A'` or `'\n'`
+fn parse_char(token: &Token) -> anyhow::Result<i64> {
+    let text = token_text(token);
+    let error = |message: &str| parser_error(message, token.location.clone());
+    let inner = &text[1..text.len() - 1];
+    let mut chars = inner.chars().peekable();
+    let value = match chars.next() {
+        Some('\\') => escape_sequence(&mut chars).map_err(|message| error(&message))? as char,
+        Some(c) => c,
+        None => return Err(error("Empty character literal")),
+    };
+    if chars.next().is_some() {
+        return Err(error(&format!(
+            "Character literal {} has more than one character",
+            text
+        )));
+    }
+    Ok(value as i64)
+}
+
+/// The bytes of a string literal, in UTF-8 but for its escapes, which give bytes
+fn parse_string(token: &Token) -> anyhow::Result<Vec<u8>> {
+    let text = token_text(token);
+    let inner = &text[1..text.len() - 1];
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    let mut col = token.location.col + 1;
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            col += 1;
+            continue;
+        }
+        let remaining = chars.clone().count();
+        let byte = escape_sequence(&mut chars).map_err(|message| {
+            let location = SourceLocation {
+                col,
+                ..token.location.clone()
+            };
+            parser_error(&message, location)
+        })?;
+        bytes.push(byte);
+        col += 1 + (remaining - chars.clone().count()) as u64;
+    }
+    Ok(bytes)
+}
+
+/// The byte the escape sequence after a backslash stands for, taking its characters from
+/// `chars`. Like GNU as, `\x` takes up to two hex digits and `\0` starts up to three octal
+/// ones.
+fn escape_sequence(chars: &mut Peekable<Chars>) -> Result<u8, String> {
+    let c = chars.next().ok_or("Escape '\\' is missing its character")?;
+    let byte = match c {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        'a' => 0x07,
+        'b' => 0x08,
+        'f' => 0x0c,
+        'v' => 0x0b,
+        '\\' | '\'' | '"' => c as u8,
+        'x' => {
+            let mut digits = String::new();
+            while digits.len() < 2
+                && let Some(digit) = chars.next_if(char::is_ascii_hexdigit)
+            {
+                digits.push(digit);
+            }
+            u8::from_str_radix(&digits, 16)
+                .map_err(|_| "Escape '\\x' needs hex digits".to_string())?
+        }
+        '0'..='7' => {
+            let mut digits = c.to_string();
+            while digits.len() < 3
+                && let Some(digit) = chars.next_if(|c| c.is_digit(8))
+            {
+                digits.push(digit);
+            }
+            u8::from_str_radix(&digits, 8)
+                .map_err(|_| format!("Escape '\\{}' is more than a byte", digits))?
+        }
+        _ => return Err(format!("Unknown escape '\\{}'", c)),
+    };
+    Ok(byte)
+}
+
+/// The number of a reference to a numeric local label, `1f` or `1b`, and whether it refers
+/// forward
+fn local_label_reference(token: &Token) -> Option<(u64, bool)> {
+    if token.kind != TokenKind::Identifier {
+        return None;
+    }
+    let text = token.text.as_ref();
+    let (number, forward) = match text.strip_suffix('f') {
+        Some(number) => (number, true),
+        None => (text.strip_suffix('b')?, false),
+    };
+    if !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((number.parse().ok()?, forward))
+}
+
+fn local_label_symbol(number: u64, instance: usize) -> String {
+    format!("{}{}_{}", LOCAL_LABEL_PREFIX, number, instance)
+}
+
+/// The number of the numeric local label `symbol` stands for and which of its definitions
+/// it is, 0 for a backward reference before the first
+pub(crate) fn local_label_instance(symbol: &str) -> Option<(&str, usize)> {
+    let (number, instance) = symbol.strip_prefix(LOCAL_LABEL_PREFIX)?.split_once('_')?;
+    Some((number, instance.parse().ok()?))
+}
+
+fn token_text<'t>(token: &'t Token) -> &'t str {
+    &token.text
+}
+
+fn describe(token: &Token) -> String {
+    match token.kind {
+        TokenKind::Newline => "end of line".to_string(),
+        TokenKind::EndOfFile => "end of file".to_string(),
+        _ => format!("'{}'", token_text(token)),
+    }
+}
+
+pub(crate) fn parser_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{tokenize, tokenize_file};
+
+    fn parse(source: &str) -> anyhow::Result<(Vec<ParsedItem>, SymbolTable)> {
+        let mut symbol_table = SymbolTable::new();
+        let items = Parser::new(tokenize(source)?).parse_all(&mut symbol_table)?;
+        Ok((items, symbol_table))
+    }
+
+    fn instructions(items: &[ParsedItem]) -> Vec<&Instruction> {
+        items
+            .iter()
+            .filter_map(|item| match item {
+                ParsedItem::Instruction(instruction) => Some(instruction),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "start: li a0, 0x12345\n  .word start, 8 # data\n\tlw a1, =7\n";
+        let items = crate::parse(source, &AssemblerOptions::default()).unwrap();
+        let spans: Vec<_> = items
+            .iter()
+            .map(|spanned| {
+                let Span { start, end } = &spanned.span;
+                ((start.line, start.col), (end.line, end.col))
+            })
+            .collect();
+        // `li` expands to a LUI and an ADDI, the literal pool goes at the end
+        assert_eq!(
+            spans,
+            [
+                ((1, 1), (1, 7)),
+                ((1, 8), (1, 22)),
+                ((1, 8), (1, 22)),
+                ((2, 3), (2, 17)),
+                ((3, 2), (3, 11)),
+                ((3, 2), (3, 11)),
+                ((4, 1), (4, 1)),
+                ((4, 1), (4, 1)),
+                ((4, 1), (4, 1)),
+            ]
+        );
+        assert!(matches!(&items[3].item, ParsedItem::Directive { name, .. } if name == ".word"));
+    }
+
+    #[test]
+    fn test_label_and_operands() {
+        let (items, symbols) = parse("start: add a0, a1, t0\n beq a0, zero, start").unwrap();
+        assert!(matches!(&items[0], ParsedItem::Label { name, .. } if name == "start"));
+        let instructions = instructions(&items);
+        assert_eq!(
+            instructions[0].operands,
+            [
+                Operand::Register(10),
+                Operand::Register(11),
+                Operand::Register(5)
+            ]
+        );
+        assert_eq!(
+            instructions[1].operands[2],
+            Operand::Symbol("start".to_string())
+        );
+        assert_eq!(symbols.get("start").unwrap().references.len(), 1);
+    }
+
+    #[test]
+    fn test_memory_operand() {
+        let (items, _) = parse("lw a0, -8(sp)\nsw a0, (sp)").unwrap();
+        let instructions = instructions(&items);
+        assert_eq!(
+            instructions[0].operands[1],
+            Operand::Memory {
+                offset: -8,
+                base: 2
+            }
+        );
+        assert_eq!(
+            instructions[1].operands[1],
+            Operand::Memory { offset: 0, base: 2 }
+        );
+    }
+
+    #[test]
+    fn test_li_expansion() {
+        let (items, _) = parse("li a0, 5\nli a1, 0x12345800\nli a2, 0x1000").unwrap();
+        let instructions = instructions(&items);
+        let mnemonics: Vec<&str> = instructions.iter().map(|i| i.mnemonic.as_str()).collect();
+        assert_eq!(mnemonics, ["addi", "lui", "addi", "lui"]);
+        // 0x12345800 needs the upper part rounded up because 0x800 is negative as a 12 bit value
+        assert_eq!(instructions[1].operands[1], Operand::Immediate(0x12346));
+        assert_eq!(instructions[2].operands[2], Operand::Immediate(-0x800));
+    }
+
+    /// Runs a `load_immediate` sequence the way the hardware would, checking each immediate
+    /// fits its instruction
+    fn run_sequence(sequence: &[(&str, i64)], xlen: Xlen) -> i64 {
+        let mut register = 0i64;
+        for &(mnemonic, imm) in sequence {
+            register = match mnemonic {
+                "lui" => {
+                    assert!((0..=0xfffff).contains(&imm), "lui {:#x}", imm);
+                    (imm << 12) as i32 as i64
+                }
+                "addi" | "addiw" => {
+                    assert!((-2048..2048).contains(&imm), "{} {}", mnemonic, imm);
+                    let sum = register.wrapping_add(imm);
+                    if mnemonic == "addiw" {
+                        sum as i32 as i64
+                    } else {
+                        sum
+                    }
+                }
+                "slli" => {
+                    assert!((1..64).contains(&imm) && xlen == Xlen::Rv64, "slli {}", imm);
+                    register << imm
+                }
+                _ => panic!("unexpected {}", mnemonic),
+            };
+            if xlen == Xlen::Rv32 {
+                register = register as i32 as i64;
+            }
+        }
+        register
+    }
+
+    #[test]
+    fn test_li_boundary_values() {
+        let mut values = vec![
+            0x7ff,
+            0x800,
+            0xfff,
+            0x1000,
+            0x7ffff7ff,
+            0x7ffff800,
+            0x12345678,
+            0xdeadbeef,
+            0x123456789abcdef0,
+            0x7fff_ffff_ffff_f800,
+            0x0000_0800_0000_0800,
+            0x1_0000_0001,
+            i64::MAX,
+            i64::MIN,
+        ];
+        for bit in 0..64 {
+            values.push(1 << bit);
+            values.push(-1 << bit);
+            // Low 12 bits with bit 11 set, the case that needs the upper part rounded up
+            values.push(1i64.wrapping_shl(bit) | 0xfff);
+            values.push(1i64.wrapping_shl(bit) | 0x800);
+        }
+        for value in values.clone() {
+            for delta in -2..=2i64 {
+                let value = value.wrapping_add(delta);
+                let sequence = load_immediate(value, Xlen::Rv64);
+                assert!(sequence.len() <= 8, "{:#x}: {:?}", value, sequence);
+                assert_eq!(run_sequence(&sequence, Xlen::Rv64), value, "{:?}", sequence);
+
+                let value = value as i32 as i64;
+                let sequence = load_immediate(value, Xlen::Rv32);
+                assert!(sequence.len() <= 2, "{:#x}: {:?}", value, sequence);
+                assert_eq!(run_sequence(&sequence, Xlen::Rv32), value, "{:?}", sequence);
+            }
+        }
+        // A value whose low 12 bits are 0 is a single LUI
+        assert_eq!(load_immediate(0x12345000, Xlen::Rv32), [("lui", 0x12345)]);
+        assert_eq!(
+            load_immediate(0x80000000u32 as i32 as i64, Xlen::Rv32),
+            [("lui", 0x80000)]
+        );
+        assert_eq!(
+            load_immediate(0x7fffffff, Xlen::Rv64),
+            [("lui", 0x80000), ("addiw", -1)]
+        );
+    }
+
+    #[test]
+    fn test_li_range() {
+        let parse_with = |source: &str, options: &AssemblerOptions| {
+            Parser::with_options(tokenize(source).unwrap(), options)
+                .parse_all(&mut SymbolTable::new())
+        };
+        let rv32 = AssemblerOptions::default();
+        // Unsigned 32 bit values are the same bits as negative ones
+        let items = parse_with(
+            "li a0, 0xffffffff
+li a1, -2147483648",
+            &rv32,
+        )
+        .unwrap();
+        let expanded = instructions(&items);
+        assert_eq!(expanded[0].mnemonic, "addi");
+        assert_eq!(expanded[0].operands[2], Operand::Immediate(-1));
+        assert_eq!(expanded[1].operands[1], Operand::Immediate(0x80000));
+        let error = parse_with("li a0, 0x100000000", &rv32).unwrap_err();
+        assert!(error.to_string().contains("32 bits"), "{}", error);
+        assert!(parse_with("li a0, -2147483649", &rv32).is_err());
+
+        // On RV64 0xffffffff is zero-extended
+        let items = parse_with("li a0, 0xffffffff", &AssemblerOptions::rv64i()).unwrap();
+        let mnemonics: Vec<&str> = instructions(&items)
+            .iter()
+            .map(|i| i.mnemonic.as_str())
+            .collect();
+        assert_eq!(mnemonics, ["addi", "slli", "addi"]);
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let (items, _) = parse(
+            "addi a0, a0, 0b1010\naddi a0, a0, 0O17\naddi a0, a0, -0x10\naddi a0, a0, 'A'
+addi a0, a0, '\\n'\naddi a0, a0, '\\''\n.dword 0xffffffffffffffff",
+        )
+        .unwrap();
+        let values: Vec<_> = instructions(&items)
+            .iter()
+            .map(|instruction| instruction.operands[2].clone())
+            .collect();
+        let immediates = [10, 15, -16, 65, 10, 39].map(Operand::Immediate);
+        assert_eq!(values, immediates);
+        let ParsedItem::Directive { args, .. } = &items[6] else {
+            panic!("expected .dword, got {:?}", items[6]);
+        };
+        assert_eq!(args[0], Operand::Immediate(-1));
+
+        let error = |source| parse(source).unwrap_err().to_string();
+        assert!(error("li a0, 0b102").contains("Invalid number '0b102'"));
+        assert!(error("li a0, 0x1ffffffffffffffff").contains("out of range"));
+        assert!(error("li a0, 99999999999999999999").contains("out of range"));
+        assert!(error("li a0, 'AB'").contains("more than one character"));
+        assert!(error("li a0, ''").contains("Empty character literal"));
+        assert!(error("li a0, '\\q'").contains("Unknown escape"));
+    }
+
+    #[test]
+    fn test_register_alias() {
+        let (items, symbols) = parse(
+            ".register base, s3
+lw a0, 4(base)
+mv base, a0",
+        )
+        .unwrap();
+        let instructions = instructions(&items);
+        assert_eq!(
+            instructions[0].operands[1],
+            Operand::Memory {
+                offset: 4,
+                base: 19
+            }
+        );
+        assert_eq!(instructions[1].operands[0], Operand::Register(19));
+        assert!(symbols.is_empty());
+        assert!(parse(".register zero, a0").is_err());
+        assert!(parse(".register alias, nope").is_err());
+    }
+
+    #[test]
+    fn test_float_operands() {
+        let (items, symbols) = parse(
+            "fadd.s fa0, fa1, ft11, rtz
+fsd fs0, 8(sp)
+jal zero, rtz
+rtz:",
+        )
+        .unwrap();
+        let instructions = instructions(&items);
+        assert_eq!(
+            instructions[0].operands,
+            [
+                Operand::FloatRegister(10),
+                Operand::FloatRegister(11),
+                Operand::FloatRegister(31),
+                Operand::RoundingMode(1),
+            ]
+        );
+        assert_eq!(instructions[1].operands[0], Operand::FloatRegister(8));
+        // Outside of floating point instructions the name is an ordinary label
+        assert_eq!(
+            instructions[2].operands[1],
+            Operand::Symbol("rtz".to_string())
+        );
+        assert_eq!(symbols.len(), 1);
+    }
+
+    #[test]
+    fn test_embedded_register_set() {
+        let options = AssemblerOptions {
+            register_set: RegisterSet::Embedded,
+            ..AssemblerOptions::default()
+        };
+        let parse_e = |source: &str| {
+            Parser::with_options(tokenize(source).unwrap(), &options)
+                .parse_all(&mut SymbolTable::new())
+        };
+        assert!(parse_e("add a0, a1, a5").is_ok());
+        let error = parse_e("add a0, a1, a6").unwrap_err().to_string();
+        assert!(error.contains("a6") && error.contains("RV32E"), "{}", error);
+        assert!(parse_e("lw a0, 0(s2)").is_err());
+        assert!(parse_e(".register big, x16").is_err());
+    }
+
+    #[test]
+    fn test_operand_checking() {
+        let message = |source| parse(source).unwrap_err().to_string();
+        assert_eq!(
+            message("sw a0, 8"),
+            "Parser error: 'sw' expects rs2, offset(rs1); found immediate 8 for offset(rs1) \
+             at line 1, column 8"
+        );
+        assert_eq!(
+            message("nop\n  add a0, a1"),
+            "Parser error: 'add' expects rd, rs1, rs2; found 2 operands at line 2, column 3"
+        );
+        assert_eq!(
+            message("addi a0, a1, 1, 2"),
+            "Parser error: 'addi' expects rd, rs1, imm; found 4 operands at line 1, column 17"
+        );
+        assert!(message("lr.w a0, 4(a1)").contains("found memory operand 4(x11) for (rs1)"));
+        assert!(message("fadd.s fa0, fa1, a2").contains("found register x12 for fs2"));
+        // Either form of jalr, and an optional rounding mode
+        assert!(parse("jalr ra, 4(a0)\n jalr ra, a0, 4\n fadd.s fa0, fa1, fa2, rtz").is_ok());
+    }
+
+    #[test]
+    fn test_conditional_assembly() {
+        let mnemonics = |source: &str, defines: &[(&str, u32)]| {
+            let options = AssemblerOptions {
+                defines: defines
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), *value))
+                    .collect(),
+                ..AssemblerOptions::default()
+            };
+            let items = Parser::with_options(tokenize_file(source, None, &options)?, &options)
+                .parse_all(&mut SymbolTable::new())?;
+            anyhow::Ok(
+                instructions(&items)
+                    .iter()
+                    .map(|instruction| instruction.mnemonic.clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let source = "
+            .equ XLEN, 32
+            .if XLEN == 64
+                ld a0, 0(sp)
+            .elseif XLEN >= 32 && XLEN < 64 && !(XLEN != 32 || XLEN > 32)
+                lw a0, 0(sp)
+              .ifdef FAST
+                slli a0, a0, 1
+              .else
+                add a0, a0, a0
+              .endif
+            .else
+                frobnicate
+            .endif
+            .ifndef FAST
+                ebreak
+            .endif";
+        assert_eq!(mnemonics(source, &[]).unwrap(), ["lw", "add", "ebreak"]);
+        assert_eq!(mnemonics(source, &[("FAST", 1)]).unwrap(), ["lw", "slli"]);
+        // Comparisons bind tighter than the logical operators, '&&' tighter than '||'
+        let source = ".if 1 || 0 && 0\nnop\n.endif\n.if 2 <= 1 == 0\nnop\n.endif";
+        assert_eq!(mnemonics(source, &[]).unwrap().len(), 2);
+        let source = ".IFDEF RV32E\nli t2, 1\n.ELSE\nli t6, 1\n.ENDIF";
+        assert_eq!(mnemonics(source, &[("RV32E", 1)]).unwrap(), ["addi"]);
+        // Labels count once they're defined
+        let source = "start:\n.ifdef start\nnop\n.endif\n.ifdef end\nnop\n.endif\nend:";
+        assert_eq!(mnemonics(source, &[]).unwrap().len(), 1);
+
+        let message = |source: &str| mnemonics(source, &[]).unwrap_err().to_string();
+        assert_eq!(
+            message("nop\n.if 1\nnop"),
+            "Parser error: '.if' without an '.endif' at line 2, column 1"
+        );
+        assert_eq!(
+            message(".if 0\n.if 1\n.endif"),
+            "Parser error: '.if' without an '.endif' at line 1, column 1"
+        );
+        assert_eq!(
+            message(".endif"),
+            "Parser error: '.endif' without an '.if' at line 1, column 1"
+        );
+        assert!(message(".if 1\n.else\n.else\n.endif").contains("after the '.else'"));
+        assert!(message(".if later\n.endif\n.equ later, 1").contains("'later' isn't one"));
+        assert!(message(".ifdef 4\n.endif").contains("Expected a symbol name"));
+        assert!(message(".if 1\n.endif extra").contains("Unexpected 'extra' after .endif"));
+    }
+
+    #[test]
+    fn test_unknown_instruction() {
+        assert!(parse("frobnicate a0").is_err());
+        assert!(parse("add a0 a1, a2").is_err());
+        assert!(parse("mv a0").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Extension points for embedders: directives and instructions the assembler doesn't know,
+//! handled by an [`AssemblerPlugin`] registered in
+//! [`AssemblerOptions::plugins`](crate::AssemblerOptions::plugins).
+//!
+//! A course can add metadata directives like `.grade_meta`, and an experimental ISA
+//! extension its mnemonics with their encodings, without forking the crate. Their operands
+//! are parsed like those of built-in instructions, so registers, numbers, labels and
+//! `offset(base)` all work.
+
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use crate::{
+    parser::{Instruction, Operand},
+    symbol_table::SymbolTable,
+};
+
+/// Custom directives and instructions. Names are matched in lowercase, directives with
+/// their leading dot; a name the assembler already knows stays the built-in one.
+pub trait AssemblerPlugin: Send + Sync {
+    /// Name of the plugin
+    fn name(&self) -> &str;
+
+    fn handles_directive(&self, _name: &str) -> bool {
+        false
+    }
+
+    fn handles_instruction(&self, _mnemonic: &str) -> bool {
+        false
+    }
+
+    /// Bytes one of the plugin's directives places at the location counter, none for
+    /// metadata. Labels don't have addresses yet when this is called. `Err` with a message
+    /// if the operands are wrong.
+    fn directive(&self, _name: &str, _args: &[Operand]) -> Result<Vec<u8>, String> {
+        Ok(Vec::new())
+    }
+
+    /// Encodes one of the plugin's instructions placed at `address` into its 32 bit word
+    fn encode(
+        &self,
+        instruction: &Instruction,
+        _address: u32,
+        _symbol_table: &SymbolTable,
+    ) -> Result<u32, String> {
+        Err(format!("'{}' has no encoding", instruction.mnemonic))
+    }
+}
+
+/// The plugins of an assembler run, asked in the order they were added
+#[derive(Clone, Default)]
+pub struct Plugins(Vec<Arc<dyn AssemblerPlugin>>);
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, plugin: impl AssemblerPlugin + 'static) -> Self {
+        self.push(plugin);
+        self
+    }
+
+    pub fn push(&mut self, plugin: impl AssemblerPlugin + 'static) {
+        self.0.push(Arc::new(plugin));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn AssemblerPlugin> {
+        self.0.iter().map(|plugin| plugin.as_ref())
+    }
+
+    /// The first plugin handling directive `name`
+    pub fn directive(&self, name: &str) -> Option<&dyn AssemblerPlugin> {
+        self.iter().find(|plugin| plugin.handles_directive(name))
+    }
+
+    /// The first plugin handling instruction `mnemonic`
+    pub fn instruction(&self, mnemonic: &str) -> Option<&dyn AssemblerPlugin> {
+        self.iter()
+            .find(|plugin| plugin.handles_instruction(mnemonic))
+    }
+}
+
+/// Lists the plugins by name
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.iter().map(|plugin| plugin.name()))
+            .finish()
+    }
+}
+
+/// The same plugin instances in the same order
+impl PartialEq for Plugins {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|(plugin, other)| Arc::ptr_eq(plugin, other))
+    }
+}
+
+impl Eq for Plugins {}
+
+impl Hash for Plugins {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for plugin in self.iter() {
+            plugin.name().hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssemblerOptions, assemble_program};
+
+    /// `.grade_meta points` metadata, `.magic` placing a marker word, and a custom-0
+    /// `mac rd, rs1, rs2`
+    struct Course;
+
+    impl AssemblerPlugin for Course {
+        fn name(&self) -> &str {
+            "course"
+        }
+
+        fn handles_directive(&self, name: &str) -> bool {
+            matches!(name, ".grade_meta" | ".magic")
+        }
+
+        fn handles_instruction(&self, mnemonic: &str) -> bool {
+            mnemonic == "mac"
+        }
+
+        fn directive(&self, name: &str, args: &[Operand]) -> Result<Vec<u8>, String> {
+            match (name, args) {
+                (".grade_meta", [Operand::Immediate(_)]) => Ok(Vec::new()),
+                (".grade_meta", _) => Err("'.grade_meta' expects the points".to_string()),
+                _ => Ok(0xc0ffee00u32.to_le_bytes().to_vec()),
+            }
+        }
+
+        fn encode(
+            &self,
+            instruction: &Instruction,
+            _address: u32,
+            _symbol_table: &SymbolTable,
+        ) -> Result<u32, String> {
+            match instruction.operands[..] {
+                [
+                    Operand::Register(rd),
+                    Operand::Register(rs1),
+                    Operand::Register(rs2),
+                ] => Ok(0x0b | (rd as u32) << 7 | (rs1 as u32) << 15 | (rs2 as u32) << 20),
+                _ => Err("'mac' expects rd, rs1, rs2".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_plugin_directives_and_instructions() {
+        let options = AssemblerOptions {
+            plugins: Plugins::new().with(Course),
+            ..AssemblerOptions::default()
+        };
+        let source = "
+            .grade_meta 10
+            MAC a0, a1, a2
+            .magic
+            nop
+        ";
+        let program = assemble_program(source, &options).unwrap();
+        assert_eq!(&program.bytes[0..4], &0x00c5850bu32.to_le_bytes());
+        assert_eq!(&program.bytes[4..8], &0xc0ffee00u32.to_le_bytes());
+        assert_eq!(program.bytes.len(), 12);
+        assert_eq!(
+            program
+                .plugin_directives
+                .iter()
+                .map(|directive| (directive.name.as_str(), directive.address))
+                .collect::<Vec<_>>(),
+            [(".grade_meta", 0), (".magic", 4)]
+        );
+        assert_eq!(program.line_map[0].1.line, 3);
+
+        assert!(assemble_program(".grade_meta", &options).is_err());
+        assert!(assemble_program("mac a0, 1", &options).is_err());
+        assert!(assemble_program("mac a0, a1, a2", &AssemblerOptions::default()).is_err());
+        assert_eq!(format!("{:?}", options.plugins), "[\"course\"]");
+        assert_eq!(options.clone(), options);
+    }
+}
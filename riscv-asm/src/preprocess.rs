@@ -0,0 +1,143 @@
+//! The lines of the program once includes, conditionals and macros are applied, in one pass
+//! so each sees the lines the others leave: a conditional can skip an `.include` or a macro
+//! definition, an included file can define macros and a macro can expand to conditionals.
+//! See [`include`](crate::include), [`conditional`](crate::conditional) and
+//! [`macros`](crate::macros).
+
+use std::{borrow::Cow, path::Path};
+
+use crate::{
+    assembler::AssemblerOptions,
+    conditional::Conditionals,
+    error::{AssemblerError, SourceLocation},
+    include::{Includes, Line, source_lines},
+    macros::{Macros, split_labels, split_statement},
+};
+
+/// The lines of `source` to tokenize, read from the file at `path` if any
+pub fn preprocess<'a>(
+    source: &'a str,
+    path: Option<&Path>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<Line<'a>>> {
+    let mut preprocessor = Preprocessor {
+        includes: Includes::new(path, &options.include_paths),
+        conditionals: Conditionals::new(options)?,
+        macros: Macros::default(),
+    };
+    let mut output = Vec::new();
+    preprocessor.run(source_lines(Cow::Borrowed(source), None), 0, &mut output)?;
+    preprocessor.conditionals.finish()?;
+    Ok(output)
+}
+
+struct Preprocessor<'o> {
+    includes: Includes<'o>,
+    conditionals: Conditionals<'o>,
+    macros: Macros,
+}
+
+impl Preprocessor<'_> {
+    /// Adds what `lines` come to to `output`, `depth` being the number of macro expansions
+    /// they come from
+    fn run<'a>(
+        &mut self,
+        lines: Vec<Line<'a>>,
+        depth: usize,
+        output: &mut Vec<Line<'a>>,
+    ) -> anyhow::Result<()> {
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            let Some(line) = self.conditionals.filter(line)? else {
+                continue;
+            };
+            let (labels, statement) = split_labels(&line.text);
+            let (first, rest) = split_statement(statement);
+            if first.eq_ignore_ascii_case(".macro") {
+                self.macros
+                    .define(labels, rest, line.location.clone(), &mut lines)?;
+            } else if first.eq_ignore_ascii_case(".endm") {
+                return Err(preprocess_error(
+                    "'.endm' without a '.macro'",
+                    line.location.clone(),
+                ));
+            } else if let Some(included) = self.includes.start(&line)? {
+                self.run(included, depth, output)?;
+                self.includes.end();
+            } else if let Some(expanded) = self.macros.expand(&line, depth)? {
+                self.run(expanded, depth + 1, output)?;
+            } else {
+                self.conditionals.record(&line)?;
+                output.push(line);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn preprocess_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    AssemblerError::ParserError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str, options: &AssemblerOptions) -> anyhow::Result<Vec<String>> {
+        Ok(preprocess(source, None, options)?
+            .into_iter()
+            .map(|line| line.text.trim().to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_conditionals_guard_includes_and_macros() {
+        let options = AssemblerOptions::default();
+        let source = "\
+.ifdef FAST
+.include \"missing.s\"
+.endif
+.if 1
+.macro load reg
+    lw \\reg, 0(sp)
+.endm
+.else
+.macro load reg
+    ld \\reg, 0(sp)
+.endm
+.endif
+load a0";
+        assert_eq!(texts(source, &options).unwrap(), ["lw a0, 0(sp)"]);
+        let options = AssemblerOptions {
+            defines: [("FAST".to_string(), 1)].into(),
+            ..AssemblerOptions::default()
+        };
+        assert!(
+            texts(source, &options)
+                .unwrap_err()
+                .to_string()
+                .contains("can't find included file 'missing.s'")
+        );
+
+        // Macros expand to conditionals, on the symbols defined so far
+        let source = "\
+.macro pick value
+.if \\value > 1
+    slli a0, a0, 1
+.else
+    nop
+.endif
+.endm
+.equ TWO, 2
+pick TWO
+done: pick 1";
+        assert_eq!(
+            texts(source, &AssemblerOptions::default()).unwrap(),
+            [".equ TWO, 2", "slli a0, a0, 1", "done:", "nop"]
+        );
+    }
+}
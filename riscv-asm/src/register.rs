@@ -0,0 +1,147 @@
+/// Registers a program may use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RegisterSet {
+    /// x0-x31 (RV32I)
+    #[default]
+    Full,
+    /// x0-x15 only (RV32E)
+    Embedded,
+}
+
+impl RegisterSet {
+    pub fn contains(self, register: u8) -> bool {
+        match self {
+            RegisterSet::Full => register < 32,
+            RegisterSet::Embedded => register < 16,
+        }
+    }
+}
+
+/// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// ABI names of f0-f31
+pub const FLOAT_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Returns the register number for a numeric ("x5") or ABI ("t0") register name
+pub fn register_number(name: &str) -> Option<u8> {
+    let number = match name {
+        "zero" => 0,
+        "ra" => 1,
+        "sp" => 2,
+        "gp" => 3,
+        "tp" => 4,
+        "t0" => 5,
+        "t1" => 6,
+        "t2" => 7,
+        "s0" | "fp" => 8,
+        "s1" => 9,
+        "a0" => 10,
+        "a1" => 11,
+        "a2" => 12,
+        "a3" => 13,
+        "a4" => 14,
+        "a5" => 15,
+        "a6" => 16,
+        "a7" => 17,
+        "s2" => 18,
+        "s3" => 19,
+        "s4" => 20,
+        "s5" => 21,
+        "s6" => 22,
+        "s7" => 23,
+        "s8" => 24,
+        "s9" => 25,
+        "s10" => 26,
+        "s11" => 27,
+        "t3" => 28,
+        "t4" => 29,
+        "t5" => 30,
+        "t6" => 31,
+        _ => {
+            let number = name.strip_prefix('x')?.parse::<u8>().ok()?;
+            // Reject "x05" and similar spellings
+            if number > 31 || name != format!("x{number}") {
+                return None;
+            }
+            number
+        }
+    };
+    Some(number)
+}
+
+/// Returns the register number for a numeric ("f5") or ABI ("ft5") floating point register
+/// name
+pub fn float_register_number(name: &str) -> Option<u8> {
+    let (prefix, index) = name
+        .find(|c: char| c.is_ascii_digit())
+        .map(|split| name.split_at(split))?;
+    let index = index.parse::<u8>().ok()?;
+    // Reject "f05" and similar spellings
+    if name != format!("{prefix}{index}") {
+        return None;
+    }
+    let number = match (prefix, index) {
+        ("f", 0..=31) => index,
+        ("ft", 0..=7) => index,
+        ("ft", 8..=11) => index + 20,
+        ("fs", 0..=1) => index + 8,
+        ("fs", 2..=11) => index + 16,
+        ("fa", 0..=7) => index + 10,
+        _ => return None,
+    };
+    Some(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(register_number("zero"), Some(0));
+        assert_eq!(register_number("x0"), Some(0));
+        assert_eq!(register_number("fp"), register_number("s0"));
+        assert_eq!(register_number("t6"), Some(31));
+        assert_eq!(register_number("x31"), Some(31));
+        assert_eq!(register_number("x32"), None);
+        assert_eq!(register_number("x05"), None);
+        assert_eq!(register_number("loop"), None);
+        for (number, name) in ABI_NAMES.iter().enumerate() {
+            assert_eq!(register_number(name), Some(number as u8));
+        }
+    }
+
+    #[test]
+    fn test_float_register_names() {
+        assert_eq!(float_register_number("f0"), Some(0));
+        assert_eq!(float_register_number("f31"), Some(31));
+        assert_eq!(float_register_number("ft7"), Some(7));
+        assert_eq!(float_register_number("fs1"), Some(9));
+        assert_eq!(float_register_number("fa0"), Some(10));
+        assert_eq!(float_register_number("fs2"), Some(18));
+        assert_eq!(float_register_number("ft11"), Some(31));
+        assert_eq!(float_register_number("f32"), None);
+        assert_eq!(float_register_number("f05"), None);
+        assert_eq!(float_register_number("fa8"), None);
+        assert_eq!(float_register_number("fence"), None);
+        for (number, name) in FLOAT_ABI_NAMES.iter().enumerate() {
+            assert_eq!(float_register_number(name), Some(number as u8));
+        }
+    }
+
+    #[test]
+    fn test_register_sets() {
+        assert!(RegisterSet::Full.contains(31));
+        assert!(RegisterSet::Embedded.contains(15));
+        assert!(!RegisterSet::Embedded.contains(16));
+    }
+}
@@ -0,0 +1,87 @@
+//! The sections a program is laid out in.
+//!
+//! `.text`, `.rodata`, `.data` and `.bss`, or `.section` with one of those names, switch
+//! the section the following lines go to. Each section has a location counter of its own,
+//! so code and data can be interleaved in the source and still end up apart. Subsections
+//! like `.text.startup` or `.rodata.str1.1` and the small data sections `.sdata`, `.sbss`
+//! and `.srodata` are folded into their section, the way a default linker script does.
+
+/// A section of the program
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Section {
+    #[default]
+    Text,
+    Data,
+    Bss,
+    /// Read-only data
+    Rodata,
+}
+
+/// Sections in the order they are placed in memory when their addresses aren't given
+pub const LAYOUT_ORDER: [Section; 4] =
+    [Section::Text, Section::Rodata, Section::Data, Section::Bss];
+
+impl Section {
+    /// The section a `.text`, `.rodata`, `.data` or `.bss` directive switches to
+    pub fn from_directive(name: &str) -> Option<Self> {
+        match name {
+            ".text" => Some(Section::Text),
+            ".data" => Some(Section::Data),
+            ".bss" => Some(Section::Bss),
+            ".rodata" => Some(Section::Rodata),
+            _ => None,
+        }
+    }
+
+    /// The section the lines after `.section name` go to, subsections included
+    pub fn from_section_name(name: &str) -> Option<Self> {
+        match name.strip_prefix('.')?.split('.').next()? {
+            "text" => Some(Section::Text),
+            "data" | "sdata" => Some(Section::Data),
+            "bss" | "sbss" => Some(Section::Bss),
+            "rodata" | "srodata" => Some(Section::Rodata),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Bss => ".bss",
+            Section::Rodata => ".rodata",
+        }
+    }
+}
+
+/// Where a section ended up, see [`AssembledProgram::sections`](crate::AssembledProgram)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SectionExtent {
+    pub section: Section,
+    pub address: u32,
+    pub size: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_names() {
+        assert_eq!(Section::from_section_name(".text"), Some(Section::Text));
+        assert_eq!(
+            Section::from_section_name(".text.startup"),
+            Some(Section::Text)
+        );
+        assert_eq!(
+            Section::from_section_name(".rodata.str1.1"),
+            Some(Section::Rodata)
+        );
+        assert_eq!(Section::from_section_name(".sbss"), Some(Section::Bss));
+        assert_eq!(Section::from_section_name(".textual"), None);
+        assert_eq!(Section::from_section_name(".init_array"), None);
+        assert_eq!(Section::from_directive(".rodata"), Some(Section::Rodata));
+    }
+}
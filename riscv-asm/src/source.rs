@@ -0,0 +1,157 @@
+//! Where the assembler reads a program from: text already in memory, a file, or any reader.
+//!
+//! Everything [`assemble`](crate::assemble) and its variants take converts into a
+//! [`SourceInput`], so embedders (a web playground, tests, build scripts) can hand over
+//! whatever they have without writing it to a temporary file first.
+
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+enum Content<'a> {
+    Text(Cow<'a, str>),
+    File(PathBuf),
+    Reader(Box<dyn Read + 'a>),
+}
+
+/// Source of a program, with the name errors refer to it by
+pub struct SourceInput<'a> {
+    content: Content<'a>,
+    name: Option<String>,
+}
+
+impl<'a> SourceInput<'a> {
+    pub fn text(text: impl Into<Cow<'a, str>>) -> Self {
+        Self {
+            content: Content::Text(text.into()),
+            name: None,
+        }
+    }
+
+    /// A file, read when the program is assembled and named by its path
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            name: Some(path.display().to_string()),
+            content: Content::File(path),
+        }
+    }
+
+    /// Reads the source to its end when the program is assembled
+    pub fn reader(reader: impl Read + 'a) -> Self {
+        Self {
+            content: Content::Reader(Box::new(reader)),
+            name: None,
+        }
+    }
+
+    /// Names the source in errors, e.g. `main.s` for text from an editor
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file the source is read from, if it is one
+    pub fn path(&self) -> Option<&Path> {
+        match &self.content {
+            Content::File(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The whole source text
+    pub fn read(self) -> io::Result<Cow<'a, str>> {
+        match self.content {
+            Content::Text(text) => Ok(text),
+            Content::File(path) => fs::read_to_string(path).map(Cow::Owned),
+            Content::Reader(mut reader) => {
+                let mut text = String::new();
+                reader.read_to_string(&mut text)?;
+                Ok(Cow::Owned(text))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SourceInput<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let content = match &self.content {
+            Content::Text(text) => format!("{} bytes of text", text.len()),
+            Content::File(path) => format!("file {}", path.display()),
+            Content::Reader(_) => "reader".to_string(),
+        };
+        f.debug_struct("SourceInput")
+            .field("content", &content)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<'a> From<&'a str> for SourceInput<'a> {
+    fn from(text: &'a str) -> Self {
+        Self::text(text)
+    }
+}
+
+impl<'a> From<&'a String> for SourceInput<'a> {
+    fn from(text: &'a String) -> Self {
+        Self::text(text.as_str())
+    }
+}
+
+impl From<String> for SourceInput<'_> {
+    fn from(text: String) -> Self {
+        Self::text(text)
+    }
+}
+
+impl From<&Path> for SourceInput<'_> {
+    fn from(path: &Path) -> Self {
+        Self::file(path)
+    }
+}
+
+impl From<&PathBuf> for SourceInput<'_> {
+    fn from(path: &PathBuf) -> Self {
+        Self::file(path)
+    }
+}
+
+impl From<PathBuf> for SourceInput<'_> {
+    fn from(path: PathBuf) -> Self {
+        Self::file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inputs_read_the_same_text() {
+        let path = std::env::temp_dir().join(format!("source-input-{}.s", std::process::id()));
+        fs::write(&path, "nop\n").unwrap();
+        let file = SourceInput::from(path.as_path());
+        assert_eq!(file.name(), Some(path.display().to_string().as_str()));
+        assert_eq!(file.read().unwrap(), "nop\n");
+        fs::remove_file(&path).unwrap();
+
+        let reader = SourceInput::reader("nop\n".as_bytes()).with_name("stdin");
+        assert_eq!(reader.name(), Some("stdin"));
+        assert_eq!(reader.read().unwrap(), "nop\n");
+        assert!(matches!(
+            SourceInput::from("nop\n").read().unwrap(),
+            Cow::Borrowed("nop\n")
+        ));
+        assert!(SourceInput::file("/nonexistent/main.s").read().is_err());
+    }
+}
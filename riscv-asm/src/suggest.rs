@@ -0,0 +1,157 @@
+//! "Did you mean" suggestions for misspelled mnemonics and registers.
+//!
+//! Candidates are the known names one edit away from the misspelling: a character
+//! inserted, removed or replaced, or two neighbouring characters swapped, as in `adi` for
+//! `addi` or `a8` for `a0`. Rather than measuring the distance to every known name, the
+//! names one edit away are generated and looked up with the same functions the tokenizer
+//! and parser classify names with.
+
+use crate::{
+    register::{float_register_number, register_number},
+    tokenizer::{TokenKind, classify_identifier},
+};
+
+/// Characters names are made of, the ones an edit can insert or replace with
+const NAME_CHARACTERS: &str = "abcdefghijklmnopqrstuvwxyz0123456789._";
+
+/// Suggestions offered at most, the closest first
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Instructions and pseudoinstructions one edit away from `name`
+pub fn mnemonics_like(name: &str) -> Vec<String> {
+    one_edit_away(name, |candidate| {
+        matches!(
+            classify_identifier(candidate),
+            TokenKind::Instruction | TokenKind::Pseudoinstruction
+        )
+    })
+}
+
+/// Integer registers one edit away from `name`, by numeric or ABI name
+pub fn registers_like(name: &str) -> Vec<String> {
+    one_edit_away(name, |candidate| register_number(candidate).is_some())
+}
+
+/// Floating point registers one edit away from `name`
+pub fn float_registers_like(name: &str) -> Vec<String> {
+    one_edit_away(name, |candidate| float_register_number(candidate).is_some())
+}
+
+/// `, did you mean 'a' or 'b'?` for the `suggestions`, nothing without any
+pub fn did_you_mean(suggestions: &[String]) -> String {
+    let quoted: Vec<String> = suggestions
+        .iter()
+        .map(|suggestion| format!("'{}'", suggestion))
+        .collect();
+    match quoted.as_slice() {
+        [] => String::new(),
+        [only] => format!(", did you mean {}?", only),
+        [rest @ .., last] => format!(", did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+/// The names one edit away from `name` that `is_known`, up to [`MAX_SUGGESTIONS`] of them.
+///
+/// A doubled letter typed once or a letter typed twice come first, then replacements and
+/// swaps, which keep the length, and then other insertions and removals. Edits further from
+/// the start of the name come before ones closer to it, the first characters being the ones
+/// people get right most often.
+fn one_edit_away(name: &str, is_known: impl Fn(&str) -> bool) -> Vec<String> {
+    let name = name.to_lowercase();
+    let chars: Vec<char> = name.chars().collect();
+    if chars.is_empty() || is_known(&name) {
+        return Vec::new();
+    }
+    // Each candidate with its rank, lower ranks are closer
+    let mut candidates: Vec<(usize, String)> = Vec::new();
+    let mut add = |rank: usize, candidate: Vec<char>| {
+        let candidate: String = candidate.into_iter().collect();
+        if !is_known(&candidate) {
+            return;
+        }
+        // The same name can come from several edits, the closest counts
+        match candidates.iter_mut().find(|(_, known)| *known == candidate) {
+            Some((known_rank, _)) => *known_rank = rank.min(*known_rank),
+            None => candidates.push((rank, candidate)),
+        }
+    };
+    let end = chars.len();
+    for index in (0..end).rev() {
+        let rank = end - index;
+        for replacement in NAME_CHARACTERS.chars() {
+            let mut candidate = chars.clone();
+            candidate[index] = replacement;
+            add(rank, candidate);
+        }
+        if index + 1 < end {
+            let mut candidate = chars.clone();
+            candidate.swap(index, index + 1);
+            add(rank, candidate);
+        }
+    }
+    for index in (0..=end).rev() {
+        let rank = end + 1 + (end - index);
+        let neighbours =
+            [index.checked_sub(1), Some(index)].map(|at| at.and_then(|at| chars.get(at)));
+        for insertion in NAME_CHARACTERS.chars() {
+            let mut candidate = chars.clone();
+            candidate.insert(index, insertion);
+            // A doubled letter typed once is the likeliest typo of all
+            add(
+                if neighbours.contains(&Some(&insertion)) {
+                    0
+                } else {
+                    rank
+                },
+                candidate,
+            );
+        }
+        if index < end {
+            let mut candidate = chars.clone();
+            let removed = candidate.remove(index);
+            // And one typed twice
+            add(
+                if candidate.get(index) == Some(&removed) {
+                    0
+                } else {
+                    rank
+                },
+                candidate,
+            );
+        }
+    }
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions() {
+        assert_eq!(mnemonics_like("adi"), ["addi", "add", "andi"]);
+        assert_eq!(mnemonics_like("ADDD"), ["add", "addi", "addw"]);
+        assert_eq!(mnemonics_like("lwi"), ["lwu", "lui", "lw"]);
+        assert_eq!(mnemonics_like("jla"), ["jal", "lla", "la"]);
+        assert!(mnemonics_like("addi").is_empty());
+        assert!(mnemonics_like("frobnicate").is_empty());
+        assert_eq!(registers_like("a8"), ["a0", "a1", "a2"]);
+        assert_eq!(registers_like("sp1"), ["s11", "sp", "s1"]);
+        assert_eq!(float_registers_like("fa9"), ["fa0", "fa1", "fa2"]);
+
+        assert_eq!(did_you_mean(&[]), "");
+        assert_eq!(
+            did_you_mean(&["addi".to_string()]),
+            ", did you mean 'addi'?"
+        );
+        assert_eq!(
+            did_you_mean(&["a0".to_string(), "a1".to_string(), "a2".to_string()]),
+            ", did you mean 'a0', 'a1' or 'a2'?"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+
+use crate::error::{AssemblerError, Redefined, SourceLocation};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symbol {
+    /// Address assigned during memory allocation
+    pub address: Option<u32>,
+    /// Where the symbol was defined, `None` if it was only referenced
+    pub definition: Option<SourceLocation>,
+    /// Every place the symbol is used, in source order
+    pub references: Vec<SourceLocation>,
+    /// Defined with `.equ` or `.set` at a fixed address, rather than by its position in the
+    /// program
+    pub absolute: bool,
+}
+
+/// Labels defined and referenced by a program.
+///
+/// Symbols are kept in a `BTreeMap` so every iteration over the table (and
+/// anything derived from it, like error lists or symbol dumps) comes out in the
+/// same order on every run.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SymbolTable {
+    symbols: BTreeMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn define(&mut self, name: &str, location: SourceLocation) -> Result<(), AssemblerError> {
+        let symbol = self.symbols.entry(name.to_string()).or_default();
+        if let Some(previous) = &symbol.definition {
+            return Err(AssemblerError::Redefinition {
+                what: Redefined::Symbol,
+                message: format!("Symbol '{}' already defined", name),
+                location,
+                first: previous.clone(),
+            });
+        }
+        symbol.definition = Some(location);
+        Ok(())
+    }
+
+    /// Defines a symbol with a fixed address, which doesn't move with the load address
+    pub fn define_absolute(
+        &mut self,
+        name: &str,
+        address: u32,
+        location: SourceLocation,
+    ) -> Result<(), AssemblerError> {
+        self.define(name, location)?;
+        let symbol = self.symbols.entry(name.to_string()).or_default();
+        symbol.address = Some(address);
+        symbol.absolute = true;
+        Ok(())
+    }
+
+    pub fn add_reference(&mut self, name: &str, location: SourceLocation) {
+        self.symbols
+            .entry(name.to_string())
+            .or_default()
+            .references
+            .push(location);
+    }
+
+    pub fn set_address(&mut self, name: &str, address: u32) {
+        self.symbols.entry(name.to_string()).or_default().address = Some(address);
+    }
+
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).and_then(|symbol| symbol.address)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    /// Value of a symbol defined with `.equ` or `.set`, `None` for labels
+    pub fn constant(&self, name: &str) -> Option<i64> {
+        self.get(name)
+            .filter(|symbol| symbol.absolute)
+            .and_then(|symbol| symbol.address)
+            .map(i64::from)
+    }
+
+    /// Iterates over symbols sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        self.symbols
+            .iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
+    }
+
+    /// Resolved symbols sorted by address, ties broken by name
+    pub fn sorted_by_address(&self) -> Vec<(&str, u32)> {
+        let mut symbols: Vec<(&str, u32)> = self
+            .iter()
+            .filter_map(|(name, symbol)| symbol.address.map(|address| (name, address)))
+            .collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        symbols
+    }
+
+    /// Symbols that are referenced but never defined, ordered by their first use
+    pub fn check_for_unresolved(&self) -> Vec<(String, SourceLocation)> {
+        let mut unresolved: Vec<(String, SourceLocation)> = self
+            .iter()
+            .filter(|(_, symbol)| symbol.definition.is_none())
+            .filter_map(|(name, symbol)| {
+                symbol
+                    .references
+                    .first()
+                    .map(|location| (name.to_string(), location.clone()))
+            })
+            .collect();
+        unresolved.sort_by(|a, b| {
+            (a.1.line, a.1.col)
+                .cmp(&(b.1.line, b.1.col))
+                .then(a.0.cmp(&b.0))
+        });
+        unresolved
+    }
+
+    /// Renders resolved symbols as an nm-style symbol file, one `ADDRESS TYPE NAME` line each.
+    ///
+    /// Every label marks code, so labels are written as text (`T`) symbols and `.equ` symbols
+    /// as absolute (`A`) ones.
+    pub fn to_symbol_file(&self) -> String {
+        self.sorted_by_address()
+            .into_iter()
+            .map(|(name, address)| {
+                let kind = if self.symbols[name].absolute {
+                    'A'
+                } else {
+                    'T'
+                };
+                format!("{:08x} {} {}\n", address, kind, name)
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(line: u64, col: u64) -> SourceLocation {
+        SourceLocation::new(line, col)
+    }
+
+    #[test]
+    fn test_iteration_order_ignores_insertion_order() {
+        let names = ["zeta", "alpha", "mid", "beta", "omega"];
+        let mut forward = SymbolTable::new();
+        let mut backward = SymbolTable::new();
+        for (i, name) in names.iter().enumerate() {
+            forward.define(name, location(i as u64 + 1, 1)).unwrap();
+        }
+        for (i, name) in names.iter().enumerate().rev() {
+            backward.define(name, location(i as u64 + 1, 1)).unwrap();
+        }
+
+        let forward_names: Vec<&str> = forward.iter().map(|(name, _)| name).collect();
+        let backward_names: Vec<&str> = backward.iter().map(|(name, _)| name).collect();
+        assert_eq!(forward_names, backward_names);
+        assert_eq!(forward_names, ["alpha", "beta", "mid", "omega", "zeta"]);
+    }
+
+    #[test]
+    fn test_sorted_by_address_breaks_ties_by_name() {
+        let mut table = SymbolTable::new();
+        table.set_address("second", 4);
+        table.set_address("b_first", 0);
+        table.set_address("a_first", 0);
+        assert_eq!(
+            table.sorted_by_address(),
+            [("a_first", 0), ("b_first", 0), ("second", 4)]
+        );
+    }
+
+    #[test]
+    fn test_unresolved_ordered_by_first_reference() {
+        let mut table = SymbolTable::new();
+        table.add_reference("late", location(9, 5));
+        table.add_reference("early", location(2, 10));
+        table.add_reference("same_line_b", location(5, 1));
+        table.add_reference("same_line_a", location(5, 1));
+        table.add_reference("early", location(20, 1));
+        table.define("defined", location(1, 1)).unwrap();
+        table.add_reference("defined", location(3, 1));
+
+        let names: Vec<String> = table
+            .check_for_unresolved()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["early", "same_line_a", "same_line_b", "late"]);
+    }
+
+    #[test]
+    fn test_absolute_symbols() {
+        let mut table = SymbolTable::new();
+        table.define("start", location(1, 1)).unwrap();
+        table.set_address("start", 0);
+        table
+            .define_absolute("UART", 0x1000_0000, location(2, 1))
+            .unwrap();
+        assert!(table.define_absolute("start", 4, location(3, 1)).is_err());
+        assert_eq!(table.address("UART"), Some(0x1000_0000));
+        assert_eq!(
+            table.to_symbol_file(),
+            "00000000 T start\n10000000 A UART\n"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_definition() {
+        let mut table = SymbolTable::new();
+        table.define("loop", location(1, 1)).unwrap();
+        let error = table.define("loop", location(4, 1)).unwrap_err();
+        assert!(matches!(
+            &error,
+            AssemblerError::Redefinition { first, .. } if first.line == 1
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Symbol error: Symbol 'loop' already defined at line 4, column 1, first defined at \
+             line 1, column 1"
+        );
+    }
+}
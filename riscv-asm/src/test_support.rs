@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests
+
+/// xorshift32 from `seed`, a fixed seed keeps failures of randomized tests reproducible
+pub(crate) fn xorshift32(seed: u32) -> impl FnMut() -> u32 {
+    let mut state = seed;
+    move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    }
+}
+
+/// xorshift64 from `seed`, like [`xorshift32`] for when 32 bits aren't enough
+pub(crate) fn xorshift64(seed: u64) -> impl FnMut() -> u64 {
+    let mut state = seed;
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
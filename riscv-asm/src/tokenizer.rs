@@ -1,31 +1,60 @@
-use anyhow::anyhow;
-
-use crate::error::{AssemblerError, SourceLocation};
-
+use std::{borrow::Cow, ops::Range, path::Path};
+
+use tracing::{debug, trace};
+
+use crate::{
+    assembler::AssemblerOptions,
+    compressed,
+    error::{AssemblerError, SourceLocation},
+    include::Line,
+    isa,
+    preprocess::preprocess,
+    register::float_register_number,
+};
+
+/// A token of the source, borrowing its text from the source where that's the line's own
+/// text, not one an included file or a macro supplied
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Token {
-    kind: TokenKind,
-    text: Option<String>,
-    location: SourceLocation,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// As written, empty for line ends and the end of the file
+    pub text: Cow<'a, str>,
+    pub location: SourceLocation,
+    /// Bytes of its file the token takes up, of the macro's use for tokens a macro
+    /// expanded to
+    pub span: Range<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Base {
     Dec,
     Hex,
+    Bin,
+    Oct,
+    /// A character in single quotes, like `'A'` or `'\n'`
+    Char,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum TokenKind {
     Instruction,       // "add", "sub", "lui", etc.
     Pseudoinstruction, // "mv", "dec", etc.
     Directive,         // ".word", ".text", ".global"
     Identifier,        // labels
     Register,          // "x0", "zero", "sp", etc.
+    FloatRegister,     // "f0", "ft0", "fa0", etc.
     Comment,           // Text after "#"
     Number(Base),
     Comma,
     Colon,
+    Equals,   // "=" before the value of a literal pool load
+    Modifier, // "%hi", "%lo", "%pcrel_hi", "%pcrel_lo"
+    Operator, // "+", "<<", "~", etc. in expressions
     LParen,
     RParen,
     Newline,
@@ -33,223 +62,267 @@ pub enum TokenKind {
     String,
 }
 
-pub fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+/// Splits `source` into tokens, after applying its includes, conditionals and macros (see
+/// [`crate::preprocess`])
+pub fn tokenize(source: &str) -> anyhow::Result<Vec<Token<'_>>> {
+    tokenize_file(source, None, &AssemblerOptions::default())
+}
+
+/// Like [`tokenize`], for `source` read from the file at `path`, looking for the files it
+/// includes next to it and then in the include paths of `options`, whose defines the
+/// conditions can use
+pub fn tokenize_file<'a>(
+    source: &'a str,
+    path: Option<&Path>,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Vec<Token<'a>>> {
+    let lines = preprocess(source, path, options)?;
+    tokenize_lines(lines, source.len())
+}
+
+/// The tokens of `lines`, `end` being the offset of the end of the file for the span of the
+/// last token
+pub(crate) fn tokenize_lines(lines: Vec<Line<'_>>, end: usize) -> anyhow::Result<Vec<Token<'_>>> {
     let mut tokens = Vec::new();
+    let mut file = None;
     let mut line_num = 1;
-    let mut lines = source.lines();
+    let mut lines = lines.into_iter().peekable();
 
     while let Some(line) = lines.next() {
+        file = line.location.file.clone();
+        line_num = line.location.line;
         let mut col_num = 1;
-        let mut chars = line.chars().peekable();
-
-        while let Some(char) = chars.next() {
+        let mut chars = line.text.char_indices().peekable();
+        // Tokens of lines of the source itself borrow it
+        let text_at = |range: Range<usize>| match &line.text {
+            Cow::Borrowed(text) => Cow::Borrowed(&text[range]),
+            Cow::Owned(text) => Cow::Owned(text[range].to_string()),
+        };
+        let span_at = |range: Range<usize>| match line.expanded {
+            true => line.span.clone(),
+            false => line.span.start + range.start..line.span.start + range.end,
+        };
+
+        while let Some((start, char)) = chars.next() {
             let location = SourceLocation {
+                file: file.clone(),
                 line: line_num,
                 col: col_num,
             };
 
-            match char {
+            let kind = match char {
                 // Whitespace
                 ' ' | '\t' | '\r' => {
                     col_num += 1;
+                    continue;
                 }
                 // Comment (ignored)
-                '#' => {
-                    // tokens.push(Token {
-                    //     kind: TokenKind::Comment,
-                    //     text: Some(chars.filter(|c| !c.is_whitespace()).collect()),
-                    //     location,
-                    // });
-                    break;
-                }
+                '#' => break,
                 // Punctuation
-                ',' => {
-                    tokens.push(Token {
-                        kind: TokenKind::Comma,
-                        text: Some(char.to_string()),
-                        location,
-                    });
-                    col_num += 1;
+                ',' => TokenKind::Comma,
+                ':' => TokenKind::Colon,
+                '=' if chars.next_if(|&(_, c)| c == '=').is_none() => TokenKind::Equals,
+                '(' => TokenKind::LParen,
+                ')' => TokenKind::RParen,
+                // Binary and unary operators. A '-' or '%' right after an operand is one, as in
+                // "end-4" or "size%4", elsewhere they start a negative number or a relocation.
+                // "==" got here past the '=' of literal loads.
+                '+' | '*' | '/' | '&' | '|' | '^' | '~' | '!' | '<' | '>' | '=' | '-' | '%'
+                    if !matches!(char, '-' | '%')
+                        || ends_operand(tokens.last())
+                        || (char == '-'
+                            && !chars.peek().is_some_and(|(_, c)| c.is_ascii_digit())) =>
+                {
+                    // The second character of "<<", "<=", ">>", ">=", "!=", "&&" and "||"
+                    match char {
+                        '<' | '>' => chars.next_if(|&(_, c)| c == char || c == '='),
+                        '!' => chars.next_if(|&(_, c)| c == '='),
+                        '&' | '|' => chars.next_if(|&(_, c)| c == char),
+                        _ => None,
+                    };
+                    TokenKind::Operator
                 }
-                ':' => {
-                    tokens.push(Token {
-                        kind: TokenKind::Colon,
-                        text: Some(char.to_string()),
-                        location,
-                    });
-                    col_num += 1;
-                }
-                '(' => {
-                    tokens.push(Token {
-                        kind: TokenKind::LParen,
-                        text: Some(char.to_string()),
-                        location,
-                    });
-                    col_num += 1;
-                }
-                ')' => {
-                    tokens.push(Token {
-                        kind: TokenKind::RParen,
-                        text: Some(char.to_string()),
-                        location,
-                    });
-                    col_num += 1;
+                // Relocation operators, the parser checks the name
+                '%' => {
+                    while chars
+                        .next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                        .is_some()
+                    {}
+                    TokenKind::Modifier
                 }
                 // Numbers
                 '-' | '0'..='9' => {
-                    let mut base = Base::Dec;
-                    let mut text = String::new();
-                    text.push(char);
-                    col_num += 1;
-
-                    if char == '-' {
-                        if !chars.peek().is_some_and(|c| c.is_ascii_digit()) {
-                            anyhow!("expected digit after '-' on {}", location);
-                        }
-                        while let Some(c) = chars.peek() {
-                            if c.is_ascii_digit() {
-                                text.push(chars.next().unwrap()); // SAFETY: we know that next
-                                // character exists after peeking
-                                col_num += 1;
-                            } else {
-                                break;
-                            }
-                        }
-                    } else if char == '0' && chars.peek() == Some(&'x') {
-                        base = Base::Hex;
-                        text.push(chars.next().unwrap()); // SAFETY: already checked that the next char exists and is 'x'
-                        col_num += 1;
-                        while let Some(c) = chars.peek() {
-                            if c.is_ascii_hexdigit() {
-                                text.push(chars.next().unwrap()); // SAFETY: we know that next character exists after peeking
-                                col_num += 1;
-                            } else {
-                                break;
-                            }
+                    let zero =
+                        char == '0' || char == '-' && chars.next_if(|(_, c)| *c == '0').is_some();
+                    // "0b" alone refers back to the local label 0, it needs a digit to be binary
+                    let mut after = chars.clone();
+                    let base = match after.next().map(|(_, c)| c.to_ascii_lowercase()) {
+                        Some('x') if zero => Base::Hex,
+                        Some('b') if zero && after.peek().is_some_and(|(_, c)| c.is_digit(2)) => {
+                            Base::Bin
                         }
+                        Some('o') if zero => Base::Oct,
+                        _ => Base::Dec,
+                    };
+                    let radix = match base {
+                        Base::Hex => 16,
+                        Base::Bin => 2,
+                        Base::Oct => 8,
+                        _ => 10,
+                    };
+                    if radix != 10 {
+                        chars.next();
+                    }
+                    while chars.next_if(|(_, c)| c.is_digit(radix)).is_some() {}
+
+                    // References to numeric local labels, like "1f" and "2b"
+                    let mut after = chars.clone();
+                    if char != '-'
+                        && base == Base::Dec
+                        && after.next_if(|(_, c)| matches!(c, 'f' | 'b')).is_some()
+                        && !after.peek().is_some_and(|(_, c)| is_symbol_char(*c))
+                    {
+                        chars.next();
+                        TokenKind::Identifier
                     } else {
-                        while let Some(c) = chars.peek() {
-                            if c.is_ascii_digit() {
-                                text.push(chars.next().unwrap()); // SAFETY: we know that next character exists after peeking
-                                col_num += 1;
-                            } else {
-                                break;
-                            }
-                        }
+                        // Along with any letters or digits after it, so "0b102" is one number
+                        // the parser rejects
+                        while chars.next_if(|(_, c)| c.is_ascii_alphanumeric()).is_some() {}
+                        TokenKind::Number(base)
                     }
-                    // Can update col_num based on text length instead
-
-                    tokens.push(Token {
-                        kind: TokenKind::Number(base),
-                        text: Some(text),
-                        location,
-                    })
                 }
-                // Directive (".text", ".DATA", etc.)
+                // Directive (".text", ".DATA", ".vector_table", etc.), or a label starting
+                // with a dot like ".Lloop", which is defined with a colon or is an operand
                 '.' => {
-                    let mut text = String::new();
-                    text.push(char);
-                    col_num += 1;
-
-                    while let Some(c) = chars.peek() {
-                        if c.is_ascii_alphabetic() {
-                            text.push(chars.next().unwrap()); // SAFETY: we know that next character exists after peeking
-                            col_num += 1;
-                        } else {
-                            break;
-                        }
+                    while chars.next_if(|(_, c)| is_symbol_char(*c)).is_some() {}
+                    let defined = chars.peek().is_some_and(|(_, c)| *c == ':');
+                    let starts_statement = tokens.last().is_none_or(|token: &Token| {
+                        matches!(token.kind, TokenKind::Newline | TokenKind::Colon)
+                    });
+                    match starts_statement && !defined {
+                        true => TokenKind::Directive,
+                        false => TokenKind::Identifier,
                     }
-
-                    tokens.push(Token {
-                        kind: TokenKind::Directive,
-                        text: Some(line.to_string()),
-                        location,
-                    })
                 }
                 // Identifiers (instruction, register, label, etc.)
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    let mut text = String::new();
-                    text.push(char);
-                    col_num += 1;
-
-                    while let Some(c) = chars.peek() {
-                        if c.is_ascii_alphabetic() || c == &'_' {
-                            text.push(chars.next().unwrap()); // SAFETY: we know that next character exists after peeking
-                            col_num += 1;
-                        } else {
+                'a'..='z' | 'A'..='Z' | '_' | '$' => {
+                    // Dots for compressed mnemonics like "c.addi"
+                    while chars.next_if(|(_, c)| is_symbol_char(*c)).is_some() {}
+                    let end = chars.peek().map_or(line.text.len(), |(index, _)| *index);
+                    let text = &line.text[start..end];
+                    let kind = classify_identifier(text);
+                    trace!(%location, text, ?kind, "identifier");
+                    kind
+                }
+                // Character literals, the parser checks there's one character
+                '\'' => {
+                    let mut escaped = false;
+                    let mut terminated = false;
+                    for (_, c) in chars.by_ref() {
+                        if c == '\'' && !escaped {
+                            terminated = true;
                             break;
                         }
+                        escaped = c == '\\' && !escaped;
                     }
 
-                    let kind = classify_identifier(&text);
-                    tokens.push(Token {
-                        kind,
-                        text: Some(text.to_string()),
-                        location,
-                    })
+                    if !terminated {
+                        return Err(tokenizer_error("unterminated character literal", location));
+                    }
+                    TokenKind::Number(Base::Char)
                 }
                 // String literals
                 '"' => {
-                    let mut text = String::new();
-                    text.push(char);
-                    col_num += 1;
-
                     let mut escaped = false;
-                    while let Some(c) = chars.next() {
-                        text.push(c);
-                        col_num += 1;
-
+                    let mut terminated = false;
+                    for (_, c) in chars.by_ref() {
                         if c == '"' && !escaped {
+                            terminated = true;
                             break;
                         }
-                        if c == '\\' {
-                            escaped = true;
-                            continue;
-                        } else {
-                            escaped = false
-                        }
+                        escaped = c == '\\' && !escaped;
                     }
 
-                    if !text.ends_with('"') {
-                        anyhow!("unterminated string literal on {}", location);
+                    if !terminated {
+                        return Err(tokenizer_error("unterminated string literal", location));
                     }
-
-                    tokens.push(Token {
-                        kind: TokenKind::String,
-                        text: Some(text.to_string()),
-                        location,
-                    })
+                    TokenKind::String
                 }
                 _ => {
-                    anyhow!("unexpected character '{}' on {}", char, location);
+                    return Err(tokenizer_error(
+                        &format!("unexpected character '{}'", char),
+                        location,
+                    ));
                 }
-            }
+            };
+
+            let end = chars.peek().map_or(line.text.len(), |(index, _)| *index);
+            let text = text_at(start..end);
+            col_num += text.chars().count() as u64;
+            tokens.push(Token {
+                kind,
+                text,
+                location,
+                span: span_at(start..end),
+            });
         }
 
-        tokens.push(Token {
-            kind: TokenKind::Newline,
-            text: None,
-            location: SourceLocation {
-                line: line_num,
-                col: col_num,
-            },
-        });
-        line_num += 1;
+        // The last piece of the split has no line break after it
+        if lines.peek().is_some() {
+            tokens.push(Token {
+                kind: TokenKind::Newline,
+                text: Cow::Borrowed(""),
+                location: SourceLocation {
+                    file: file.clone(),
+                    line: line_num,
+                    col: col_num,
+                },
+                span: span_at(line.text.len()..line.text.len()),
+            });
+        }
     }
 
     tokens.push(Token {
         kind: TokenKind::EndOfFile,
-        text: None,
+        text: Cow::Borrowed(""),
         location: SourceLocation {
+            file,
             line: line_num,
             col: 1,
         },
+        span: end..end,
     });
 
+    debug!(tokens = tokens.len(), lines = line_num, "tokenized source");
     anyhow::Ok(tokens)
 }
 
-fn classify_identifier(s: &str) -> TokenKind {
+/// Whether `c` can be part of a symbol's name after its first character, like GNU as
+/// allows
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$')
+}
+
+/// Whether `token` can end an operand, so an operator after it is a binary one
+fn ends_operand(token: Option<&Token<'_>>) -> bool {
+    token.is_some_and(|token| {
+        matches!(
+            token.kind,
+            TokenKind::Number(_) | TokenKind::Identifier | TokenKind::RParen
+        )
+    })
+}
+
+fn tokenizer_error(message: &str, location: SourceLocation) -> anyhow::Error {
+    debug!(%location, message, "tokenizer error");
+    AssemblerError::TokenizerError {
+        message: message.to_string(),
+        location,
+    }
+    .into()
+}
+
+pub(crate) fn classify_identifier(s: &str) -> TokenKind {
     match s {
         // Registers
         "zero" | "ra" | "sp" | "gp" | "tp" | "fp" | "s0" | "s1" | "s2" | "s3" | "s4" | "s5"
@@ -258,20 +331,18 @@ fn classify_identifier(s: &str) -> TokenKind {
         | "x3" | "x4" | "x5" | "x6" | "x7" | "x8" | "x9" | "x10" | "x11" | "x12" | "x13"
         | "x14" | "x15" | "x16" | "x17" | "x18" | "x19" | "x20" | "x21" | "x22" | "x23" | "x24"
         | "x25" | "x26" | "x27" | "x28" | "x29" | "x30" | "x31" => TokenKind::Register,
-        // Instructions (RV32I)
-        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" | // R-type
-        "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" | "slli" | "srli" | "srai" | // I-type (ALU)
-        "lb" | "lh" | "lw" | "lbu" | "lhu" | // I-type (Load)
-        "jalr" | // I-type (Jump)
-        "sb" | "sh" | "sw" | // S-type
-        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | // B-type
-        "lui" | "auipc" | // U-type
-        "jal" | // J-type
-        "ecall" => TokenKind::Instruction,
+        mnemonic if isa::is_known(mnemonic) => TokenKind::Instruction, // isa::INSTRUCTIONS
+        mnemonic if compressed::MNEMONICS.contains(&mnemonic) => TokenKind::Instruction, // C extension
+        name if float_register_number(name).is_some() => TokenKind::FloatRegister,
         // Pseudoinstructions
-        "inc" | "dec" | "mv" | "nop" | "neg" | "li" => TokenKind::Pseudoinstruction,
+        "inc" | "dec" | "mv" | "nop" | "neg" | "li" | "not" | "negw" | "sext.w" | "seqz"
+        | "snez" | "sltz" | "sgtz" | "fmv.s" | "fabs.s" | "fneg.s" | "fmv.d" | "fabs.d"
+        | "fneg.d" | "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" | "bgt" | "ble"
+        | "bgtu" | "bleu" | "j" | "jr" | "ret" | "call" | "tail" | "la" | "lla" | "csrr"
+        | "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => TokenKind::Pseudoinstruction,
         // Default to identifier (likely a label)
-        _ => TokenKind::Identifier,}
+        _ => TokenKind::Identifier,
+    }
 }
 
 //  Unit Tests
@@ -285,18 +356,18 @@ mod tests {
         let tokens = tokenize(code).unwrap();
         assert_eq!(tokens.len(), 10); // loop, :, Identifier(addi), Register(x1), Comma, Register(x0), Comma, Integer(5), Newline, EOF
         assert_eq!(tokens[0].kind, TokenKind::Identifier); // "loop" -> classified as Identifier initially
-        assert_eq!(tokens[0].text, Some("loop".to_string()));
+        assert_eq!(tokens[0].text, "loop");
         assert_eq!(tokens[1].kind, TokenKind::Colon);
         assert_eq!(tokens[2].kind, TokenKind::Instruction);
-        assert_eq!(tokens[2].text, Some("addi".to_string()));
+        assert_eq!(tokens[2].text, "addi");
         assert_eq!(tokens[3].kind, TokenKind::Register);
-        assert_eq!(tokens[3].text, Some("x1".to_string()));
+        assert_eq!(tokens[3].text, "x1");
         assert_eq!(tokens[4].kind, TokenKind::Comma);
         assert_eq!(tokens[5].kind, TokenKind::Register);
-        assert_eq!(tokens[5].text, Some("x0".to_string()));
+        assert_eq!(tokens[5].text, "x0");
         assert_eq!(tokens[6].kind, TokenKind::Comma);
         assert_eq!(tokens[7].kind, TokenKind::Number(Base::Dec));
-        assert_eq!(tokens[7].text, Some("5".to_string()));
+        assert_eq!(tokens[7].text, "5");
         assert_eq!(tokens[8].kind, TokenKind::Newline);
         assert_eq!(tokens[9].kind, TokenKind::EndOfFile); // Added EOF token
     }
@@ -308,8 +379,7 @@ mod tests {
         assert!(
             tokens
                 .iter()
-                .any(|t| t.kind == TokenKind::Number(Base::Hex)
-                    && t.text == Some("0xFF".to_string()))
+                .any(|t| t.kind == TokenKind::Number(Base::Hex) && t.text == "0xFF")
         );
     }
 
@@ -320,35 +390,35 @@ mod tests {
 
         // Check the instruction token
         assert_eq!(tokens[0].kind, TokenKind::Instruction);
-        assert_eq!(tokens[0].text, Some("lb".to_string()));
+        assert_eq!(tokens[0].text, "lb");
 
         // Check the register token
         assert_eq!(tokens[1].kind, TokenKind::Register);
-        assert_eq!(tokens[1].text, Some("a0".to_string()));
+        assert_eq!(tokens[1].text, "a0");
 
         // Check the comma token
         assert_eq!(tokens[2].kind, TokenKind::Comma);
-        assert_eq!(tokens[2].text, Some(",".to_string()));
+        assert_eq!(tokens[2].text, ",");
 
         // Check the immediate value token
         assert_eq!(tokens[3].kind, TokenKind::Number(Base::Dec));
-        assert_eq!(tokens[3].text, Some("8".to_string()));
+        assert_eq!(tokens[3].text, "8");
 
         // Check the left parenthesis token
         assert_eq!(tokens[4].kind, TokenKind::LParen);
-        assert_eq!(tokens[4].text, Some("(".to_string()));
+        assert_eq!(tokens[4].text, "(");
 
         // Check the base register token
         assert_eq!(tokens[5].kind, TokenKind::Register);
-        assert_eq!(tokens[5].text, Some("sp".to_string()));
+        assert_eq!(tokens[5].text, "sp");
 
         // Check the right parenthesis token
         assert_eq!(tokens[6].kind, TokenKind::RParen);
-        assert_eq!(tokens[6].text, Some(")".to_string()));
+        assert_eq!(tokens[6].text, ")");
 
         // Check the right parenthesis token
         assert_eq!(tokens[7].kind, TokenKind::EndOfFile);
-        assert_eq!(tokens[7].text, Some("".to_string()));
+        assert_eq!(tokens[7].text, "");
 
         // Verify the total number of tokens
         assert_eq!(tokens.len(), 8);
@@ -361,12 +431,12 @@ mod tests {
         assert!(
             tokens
                 .iter()
-                .any(|t| t.kind == TokenKind::Directive && t.text == Some(".data".to_string()))
+                .any(|t| t.kind == TokenKind::Directive && t.text == ".data")
         );
         assert!(
             tokens
                 .iter()
-                .any(|t| t.kind == TokenKind::Directive && t.text == Some(".word".to_string()))
+                .any(|t| t.kind == TokenKind::Directive && t.text == ".word")
         );
     }
 
@@ -378,7 +448,7 @@ mod tests {
         assert!(
             tokens
                 .iter()
-                .any(|t| t.kind == TokenKind::Instruction && t.text == Some("add".to_string()))
+                .any(|t| t.kind == TokenKind::Instruction && t.text == "add")
         ); // Check instruction is present
         assert_eq!(tokens.last().unwrap().kind, TokenKind::EndOfFile); // Should end with EOF, not newline if no newline after comment
     }
@@ -387,16 +457,122 @@ mod tests {
     fn test_location() {
         let code = "line1\n line2: lw x1, 0(sp)";
         let tokens = tokenize(code).unwrap();
-        let lw_token = tokens
-            .iter()
-            .find(|t| t.text == Some("lw".to_string()))
-            .unwrap();
+        let lw_token = tokens.iter().find(|t| t.text == "lw").unwrap();
         assert_eq!(lw_token.location.line, 2);
         assert!(lw_token.location.col > 1); // Should not be column 1
-        let sp_token = tokens
-            .iter()
-            .find(|t| t.text == Some("sp".to_string()))
-            .unwrap();
+        let sp_token = tokens.iter().find(|t| t.text == "sp").unwrap();
         assert_eq!(sp_token.location.line, 2);
     }
+
+    #[test]
+    fn test_spans() {
+        let code = ".macro two\nnop\nnop\n.endm\n.string \"é\", \"x\"\n  two";
+        let tokens = tokenize(code).unwrap();
+        let spanned: Vec<_> = tokens
+            .iter()
+            .filter(|token| !token.text.is_empty())
+            .map(|token| (token.text.as_ref(), token.span.clone()))
+            .collect();
+        // Lines of the source borrow it, those of the macro's body cover its use
+        assert_eq!(
+            spanned,
+            [
+                (".string", 25..32),
+                ("\"é\"", 33..37),
+                (",", 37..38),
+                ("\"x\"", 39..42),
+                ("nop", 43..48),
+                ("nop", 43..48),
+            ]
+        );
+        assert!(matches!(tokens[0].text, Cow::Borrowed(".string")));
+        assert!(matches!(tokens[5].text, Cow::Owned(_)));
+        assert_eq!(&code[tokens[1].span.clone()], "\"é\"");
+        // Columns count characters, spans bytes
+        assert_eq!(tokens[2].location.col, 12);
+    }
+
+    #[test]
+    fn test_operators() {
+        let kinds = |code| {
+            tokenize(code)
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.kind, token.text.into_owned()))
+                .collect::<Vec<_>>()
+        };
+        let operator = |text: &str| (TokenKind::Operator, text.to_string());
+        // A minus after an operand subtracts, before a digit elsewhere it's the number's sign
+        let tokens = kinds("end-4, -4 <<");
+        assert_eq!(tokens[1], operator("-"));
+        assert_eq!(tokens[2], (TokenKind::Number(Base::Dec), "4".to_string()));
+        assert_eq!(tokens[4], (TokenKind::Number(Base::Dec), "-4".to_string()));
+        assert_eq!(tokens[5], operator("<<"));
+        assert_eq!(kinds("-(a)")[0], operator("-"));
+        let tokens = kinds("a<b <= c >= d>e == f != !g && h || i");
+        let operators: Vec<&str> = tokens
+            .iter()
+            .filter(|(kind, _)| *kind == TokenKind::Operator)
+            .map(|(_, text)| text.as_str())
+            .collect();
+        assert_eq!(
+            operators,
+            ["<", "<=", ">=", ">", "==", "!=", "!", "&&", "||"]
+        );
+        // A lone '=' is a literal load's
+        assert_eq!(kinds("=x")[0], (TokenKind::Equals, "=".to_string()));
+    }
+
+    #[test]
+    fn test_symbol_names() {
+        let kind = |code| tokenize(code).unwrap()[0].kind.clone();
+        for register in ["a0", "t0", "zero", "x31"] {
+            assert_eq!(kind(register), TokenKind::Register, "{register}");
+        }
+        // Anything longer than a register's name is a symbol
+        for label in [
+            "a0x", "a0.b", "a0$", "x32", "loop2", "my.func", "$tmp", "a$b",
+        ] {
+            let tokens = tokenize(label).unwrap();
+            assert_eq!(tokens[0].kind, TokenKind::Identifier, "{label}");
+            assert_eq!(tokens[0].text, label);
+            assert_eq!(tokens[1].kind, TokenKind::EndOfFile, "{label}");
+        }
+        // Dot-leading names are labels when defined or used, directives otherwise
+        let tokens = tokenize(".Lx: j .Lx\n.word .L1").unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::Identifier);
+        assert_eq!(tokens[3].kind, TokenKind::Identifier);
+        assert_eq!(tokens[5].kind, TokenKind::Directive);
+        assert_eq!(tokens[6].kind, TokenKind::Identifier);
+        assert_eq!(kind("x: .word 1"), TokenKind::Identifier);
+        assert_eq!(
+            tokenize("x: .word 1").unwrap()[2].kind,
+            TokenKind::Directive
+        );
+    }
+
+    #[test]
+    fn test_number_bases() {
+        let tokens = tokenize("0b1010, 0o17, -0x10, 'A', '\\'', 0b, 1b").unwrap();
+        let numbers: Vec<_> = tokens
+            .iter()
+            .filter(|token| token.kind != TokenKind::Comma)
+            .map(|token| (token.kind.clone(), token.text.as_ref()))
+            .collect();
+        assert_eq!(
+            numbers[..7],
+            [
+                (TokenKind::Number(Base::Bin), "0b1010"),
+                (TokenKind::Number(Base::Oct), "0o17"),
+                (TokenKind::Number(Base::Hex), "-0x10"),
+                (TokenKind::Number(Base::Char), "'A'"),
+                (TokenKind::Number(Base::Char), "'\\''"),
+                // Without binary digits these are local label references
+                (TokenKind::Identifier, "0b"),
+                (TokenKind::Identifier, "1b"),
+            ]
+        );
+        assert_eq!(tokenize("0b102").unwrap()[0].text, "0b102");
+        assert!(tokenize("li a0, 'A").is_err());
+    }
 }
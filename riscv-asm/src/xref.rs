@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    assembler::MemoryMap, error::SourceLocation, parser::ParsedItem, symbol_table::SymbolTable,
+};
+
+/// How an instruction uses the label it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ReferenceKind {
+    /// Conditional branch target
+    Branch,
+    /// `jal` target
+    Jump,
+    /// Any other use of the label's address
+    Address,
+}
+
+/// An instruction referring to a label
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Reference {
+    /// Address of the referencing instruction
+    pub address: u32,
+    pub mnemonic: String,
+    pub kind: ReferenceKind,
+    pub location: SourceLocation,
+}
+
+/// Every instruction referring to each label, for understanding and refactoring programs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CrossReferences {
+    /// Defined labels and their address, with references in address order
+    labels: BTreeMap<String, (u32, Vec<Reference>)>,
+}
+
+impl CrossReferences {
+    pub(crate) fn build(
+        symbol_table: &SymbolTable,
+        memory_map: &MemoryMap,
+        parsed_items: &[ParsedItem],
+    ) -> Self {
+        let mut labels: BTreeMap<String, (u32, Vec<Reference>)> = symbol_table
+            .iter()
+            .filter_map(|(name, symbol)| Some((name.to_string(), (symbol.address?, Vec::new()))))
+            .collect();
+        for (index, item) in parsed_items.iter().enumerate() {
+            let ParsedItem::Instruction(instruction) = item else {
+                continue;
+            };
+            let kind = match instruction.mnemonic.as_str() {
+                "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => ReferenceKind::Branch,
+                "jal" => ReferenceKind::Jump,
+                _ => ReferenceKind::Address,
+            };
+            for operand in &instruction.operands {
+                if let Some(name) = operand.symbol()
+                    && let Some((_, references)) = labels.get_mut(name)
+                {
+                    references.push(Reference {
+                        address: memory_map.address_of(index),
+                        mnemonic: instruction.mnemonic.clone(),
+                        kind,
+                        location: instruction.location.clone(),
+                    });
+                }
+            }
+        }
+        Self { labels }
+    }
+
+    /// Instructions referring to `label` in address order, `None` if no such label is defined
+    pub fn references_to(&self, label: &str) -> Option<&[Reference]> {
+        self.labels
+            .get(label)
+            .map(|(_, references)| references.as_slice())
+    }
+
+    /// Labels sorted by address (ties by name) with their address and references
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32, &[Reference])> {
+        let mut labels: Vec<_> = self
+            .labels
+            .iter()
+            .map(|(name, (address, references))| (name.as_str(), *address, references.as_slice()))
+            .collect();
+        labels.sort_by_key(|&(name, address, _)| (address, name));
+        labels.into_iter()
+    }
+
+    /// Human readable report, one block per label listing who refers to it
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for (name, address, references) in self.iter() {
+            report.push_str(&format!("{} ({:#010x})\n", name, address));
+            if references.is_empty() {
+                report.push_str("    no references\n");
+            }
+            for reference in references {
+                report.push_str(&format!(
+                    "    {:#010x}  {:<6} {}\n",
+                    reference.address, reference.mnemonic, reference.location
+                ));
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssemblerOptions, assemble_program};
+
+    use super::*;
+
+    #[test]
+    fn test_cross_references() {
+        let source = "main:\n jal ra, count\n jal zero, done\ncount:\n beq a0, zero, done\n dec a0\n jal zero, count\ndone:\n";
+        let program = assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let xrefs = &program.xrefs;
+
+        let done = xrefs.references_to("done").unwrap();
+        let summary: Vec<(u32, ReferenceKind)> = done
+            .iter()
+            .map(|reference| (reference.address, reference.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            [(0x04, ReferenceKind::Jump), (0x08, ReferenceKind::Branch)]
+        );
+        assert_eq!(done[1].location.line, 5);
+        assert_eq!(xrefs.references_to("count").unwrap().len(), 2);
+        assert_eq!(xrefs.references_to("main"), Some(&[][..]));
+        assert_eq!(xrefs.references_to("missing"), None);
+
+        let names: Vec<&str> = xrefs.iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["main", "count", "done"]);
+        assert!(
+            xrefs
+                .report()
+                .starts_with("main (0x00000000)\n    no references\ncount")
+        );
+    }
+}
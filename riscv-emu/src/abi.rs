@@ -0,0 +1,192 @@
+//! Calling convention checks: a function has to return with the callee-saved registers, sp
+//! and s0-s11, holding what they held when it was called.
+//!
+//! Calls are the jumps linking into ra (`jal`, `jalr`, `call`), a return is a `jalr` not
+//! linking anywhere that goes to the address a call left in ra. Returns unwinding several
+//! calls at once, like a `longjmp`, close all of them but only the outermost is checked.
+//! Tail calls are fine, the function tail called returns for the caller.
+
+use std::fmt;
+
+/// The registers a function has to preserve, and their ABI names
+const CALLEE_SAVED: [(usize, &str); 13] = [
+    (2, "sp"),
+    (8, "s0"),
+    (9, "s1"),
+    (18, "s2"),
+    (19, "s3"),
+    (20, "s4"),
+    (21, "s5"),
+    (22, "s6"),
+    (23, "s7"),
+    (24, "s8"),
+    (25, "s9"),
+    (26, "s10"),
+    (27, "s11"),
+];
+
+/// Calls tracked at once, deeper recursion forgets the outermost calls
+const MAX_FRAMES: usize = 1 << 16;
+
+/// A function returned with a callee-saved register changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbiViolation {
+    /// Address of the returning instruction
+    pub pc: u32,
+    /// Address of the function, where the call went to
+    pub function: u32,
+    /// Index of the register
+    pub register: usize,
+    pub name: &'static str,
+    /// Value at the call
+    pub expected: u64,
+    /// Value at the return
+    pub actual: u64,
+}
+
+impl fmt::Display for AbiViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "function at {:#010x} returned (pc {:#010x}) with {} changed from {:#x} to {:#x}",
+            self.function, self.pc, self.name, self.expected, self.actual
+        )
+    }
+}
+
+/// A call not returned from yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    function: u32,
+    return_address: u32,
+    saved: [u64; CALLEE_SAVED.len()],
+}
+
+/// Follows calls and returns, see [`AbiChecker::record`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AbiChecker {
+    frames: Vec<Frame>,
+}
+
+impl AbiChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the (expanded) `instruction` retired at `pc`, taking the registers from
+    /// `before` to `after` and continuing at `next_pc`. Returns the callee-saved registers a
+    /// return found changed.
+    pub fn record(
+        &mut self,
+        pc: u32,
+        instruction: u32,
+        before: &[u64; 32],
+        after: &[u64; 32],
+        next_pc: u32,
+    ) -> Vec<AbiViolation> {
+        let opcode = instruction & 0x7f;
+        let rd = (instruction >> 7) & 0x1f;
+        if !matches!(opcode, 0b1101111 | 0b1100111) {
+            return Vec::new();
+        }
+        if rd == 1 {
+            if self.frames.len() == MAX_FRAMES {
+                self.frames.remove(0);
+            }
+            self.frames.push(Frame {
+                function: next_pc,
+                return_address: after[1] as u32,
+                saved: CALLEE_SAVED.map(|(register, _)| before[register]),
+            });
+            return Vec::new();
+        }
+        let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == next_pc)
+            .filter(|_| opcode == 0b1100111 && rd == 0)
+        else {
+            return Vec::new();
+        };
+        let frame = self.frames[depth];
+        self.frames.truncate(depth);
+        CALLEE_SAVED
+            .iter()
+            .zip(frame.saved)
+            .filter(|((register, _), expected)| after[*register] != *expected)
+            .map(|(&(register, name), expected)| AbiViolation {
+                pc,
+                function: frame.function,
+                register,
+                name,
+                expected,
+                actual: after[register],
+            })
+            .collect()
+    }
+
+    /// Forgets the open calls, for a reset
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abi_checks() {
+        const JAL_RA: u32 = 0x0100_00ef; // jal ra, 16
+        const RET: u32 = 0x0000_8067; // jalr zero, 0(ra)
+        let mut checker = AbiChecker::new();
+        let mut regs = [0u64; 32];
+        regs[2] = 0x1000;
+        regs[8] = 7;
+        let call = |checker: &mut AbiChecker, regs: &mut [u64; 32], pc: u32| {
+            let before = *regs;
+            regs[1] = (pc + 4).into();
+            assert!(
+                checker
+                    .record(pc, JAL_RA, &before, regs, pc + 16)
+                    .is_empty()
+            );
+        };
+
+        // Returns with everything restored
+        call(&mut checker, &mut regs, 0);
+        assert!(checker.record(16, RET, &regs, &regs, 4).is_empty());
+
+        // A nested call clobbering s0 and sp
+        call(&mut checker, &mut regs, 0);
+        call(&mut checker, &mut regs, 20);
+        regs[8] = 9;
+        regs[2] = 0xff8;
+        assert_eq!(
+            checker.record(40, RET, &regs, &regs, 24),
+            [
+                AbiViolation {
+                    pc: 40,
+                    function: 36,
+                    register: 2,
+                    name: "sp",
+                    expected: 0x1000,
+                    actual: 0xff8,
+                },
+                AbiViolation {
+                    pc: 40,
+                    function: 36,
+                    register: 8,
+                    name: "s0",
+                    expected: 7,
+                    actual: 9,
+                },
+            ]
+        );
+        // Computed jumps elsewhere aren't returns
+        assert!(checker.record(28, RET, &regs, &regs, 100).is_empty());
+        // The outer call has to restore them too
+        assert_eq!(checker.record(28, RET, &regs, &regs, 4).len(), 2);
+        assert_eq!(checker.record(28, RET, &regs, &regs, 4).len(), 0);
+    }
+}
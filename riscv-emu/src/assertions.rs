@@ -0,0 +1,158 @@
+//! Assertions on memory for tests of programs, so a test checking the data structures a
+//! program built reads like the data rather than like byte poking:
+//!
+//! ```ignore
+//! machine.assert_mem_u32(0x1000, 42);
+//! machine.assert_c_string(0x2000, "hello");
+//! ```
+//!
+//! Each panics with the address and both values when memory holds something else, or when
+//! the address isn't in memory. Values are little-endian like the machine's own, the `_be`
+//! variants are for big-endian data such as network headers.
+
+use std::fmt::Debug;
+
+use crate::machine::Machine;
+
+impl Machine {
+    /// The `N` bytes at `address`, panicking if they aren't all in memory
+    #[track_caller]
+    fn expect_memory<const N: usize>(&self, address: u32) -> [u8; N] {
+        match self.read_memory(address, N) {
+            Ok(bytes) => bytes.try_into().unwrap(),
+            Err(error) => panic!("{} bytes at {:#010x} can't be read: {}", N, address, error),
+        }
+    }
+
+    #[track_caller]
+    pub fn assert_mem_bytes(&self, address: u32, expected: &[u8]) {
+        let actual = match self.read_memory(address, expected.len()) {
+            Ok(bytes) => bytes,
+            Err(error) => panic!(
+                "{} bytes at {:#010x} can't be read: {}",
+                expected.len(),
+                address,
+                error
+            ),
+        };
+        assert_memory(address, &actual[..], expected);
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u8(&self, address: u32, expected: u8) {
+        assert_memory(address, self.expect_memory::<1>(address)[0], expected);
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u16(&self, address: u32, expected: u16) {
+        let actual = u16::from_le_bytes(self.expect_memory(address));
+        assert_memory(address, Hex(actual), Hex(expected));
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u32(&self, address: u32, expected: u32) {
+        let actual = u32::from_le_bytes(self.expect_memory(address));
+        assert_memory(address, Hex(actual), Hex(expected));
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u64(&self, address: u32, expected: u64) {
+        let actual = u64::from_le_bytes(self.expect_memory(address));
+        assert_memory(address, Hex(actual), Hex(expected));
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u16_be(&self, address: u32, expected: u16) {
+        let actual = u16::from_be_bytes(self.expect_memory(address));
+        assert_memory(address, Hex(actual), Hex(expected));
+    }
+
+    #[track_caller]
+    pub fn assert_mem_u32_be(&self, address: u32, expected: u32) {
+        let actual = u32::from_be_bytes(self.expect_memory(address));
+        assert_memory(address, Hex(actual), Hex(expected));
+    }
+
+    /// Consecutive 32 bit words starting at `address`, e.g. an array the program sorted
+    #[track_caller]
+    pub fn assert_mem_words(&self, address: u32, expected: &[u32]) {
+        let actual: Vec<Hex<u32>> = (0..expected.len() as u32)
+            .map(|index| u32::from_le_bytes(self.expect_memory(address + 4 * index)))
+            .map(Hex)
+            .collect();
+        let expected: Vec<Hex<u32>> = expected.iter().copied().map(Hex).collect();
+        assert_memory(address, actual, expected);
+    }
+
+    /// The NUL-terminated string at `address` is `expected`: its bytes followed by a NUL
+    #[track_caller]
+    pub fn assert_c_string(&self, address: u32, expected: &str) {
+        let mut actual = Vec::new();
+        // Reading one byte past the expected length shows a missing terminator
+        while actual.len() <= expected.len() {
+            let byte = self.expect_memory::<1>(address + actual.len() as u32)[0];
+            if byte == 0 {
+                break;
+            }
+            actual.push(byte);
+        }
+        assert_memory(address, String::from_utf8_lossy(&actual), expected.into());
+    }
+}
+
+#[track_caller]
+fn assert_memory<T: Debug + PartialEq>(address: u32, actual: T, expected: T) {
+    assert!(
+        actual == expected,
+        "memory at {:#010x} holds {:?}, expected {:?}",
+        address,
+        actual,
+        expected
+    );
+}
+
+/// Shows numbers in hex in failed assertions
+#[derive(PartialEq)]
+struct Hex<T>(T);
+
+impl<T: std::fmt::LowerHex> Debug for Hex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use crate::machine::Machine;
+
+    #[test]
+    fn test_memory_assertions() {
+        let mut machine = Machine::new(Vec::new(), 0x100);
+        machine
+            .write_memory(0x10, &[0x78, 0x56, 0x34, 0x12, b'h', b'i', 0, b'!'])
+            .unwrap();
+        machine.assert_mem_u32(0x10, 0x1234_5678);
+        machine.assert_mem_u32_be(0x10, 0x7856_3412);
+        machine.assert_mem_u16(0x12, 0x1234);
+        machine.assert_mem_u8(0x13, 0x12);
+        machine.assert_mem_words(0x10, &[0x1234_5678, 0x2100_6968]);
+        machine.assert_c_string(0x14, "hi");
+        machine.assert_mem_bytes(0x16, b"\0!");
+
+        let failure = |check: fn(&Machine)| {
+            let payload = panic::catch_unwind(AssertUnwindSafe(|| check(&machine))).unwrap_err();
+            payload.downcast::<String>().unwrap()
+        };
+        assert_eq!(
+            *failure(|machine| machine.assert_mem_u32(0x10, 1)),
+            "memory at 0x00000010 holds 0x12345678, expected 0x1"
+        );
+        assert_eq!(
+            *failure(|machine| machine.assert_c_string(0x14, "h")),
+            "memory at 0x00000014 holds \"hi\", expected \"h\""
+        );
+        assert!(failure(|machine| machine.assert_mem_u64(0xfc, 0)).contains("can't be read"));
+    }
+}
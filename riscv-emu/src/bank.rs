@@ -0,0 +1,166 @@
+//! Banked memory: same-sized banks sharing one window of the address space, and a register
+//! selecting the bank the window shows.
+//!
+//! Overlays are built on it: a program bigger than its address space keeps parts of itself in
+//! different banks, and code outside the window selects a bank before calling into it. The
+//! window is executable like a loaded image, and the banks are writable so they can hold data
+//! as well.
+
+use std::ops::Range;
+
+use crate::{bus::Device, error::EmuError};
+
+/// Offset of the bank select register from the end of the window, a word holding the
+/// number of the bank the window shows
+pub const BANK_SELECT: u32 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankedMemory {
+    base: u32,
+    bank_size: u32,
+    banks: Vec<Vec<u8>>,
+    /// Contents the banks were created with, restored by a reset
+    initial: Vec<Vec<u8>>,
+    selected: usize,
+}
+
+impl BankedMemory {
+    /// Banks of `bank_size` bytes mapped at `base`, filled with `banks` and zero padded.
+    /// Bank 0 is selected.
+    pub fn new(base: u32, bank_size: u32, banks: Vec<Vec<u8>>) -> Result<Self, EmuError> {
+        let config_error = |message: String| Err(EmuError::ConfigError { message });
+        if banks.is_empty() {
+            return config_error("banked memory needs at least one bank".to_string());
+        }
+        if bank_size == 0
+            || base
+                .checked_add(bank_size)
+                .is_none_or(|end| end > u32::MAX - 4)
+        {
+            return config_error(format!(
+                "{} byte banks don't fit at {:#010x}",
+                bank_size, base
+            ));
+        }
+        let mut padded = Vec::with_capacity(banks.len());
+        for (index, mut bank) in banks.into_iter().enumerate() {
+            if bank.len() > bank_size as usize {
+                return config_error(format!(
+                    "bank {} holds {} bytes, more than the bank size of {}",
+                    index,
+                    bank.len(),
+                    bank_size
+                ));
+            }
+            bank.resize(bank_size as usize, 0);
+            padded.push(bank);
+        }
+        Ok(Self {
+            base,
+            bank_size,
+            initial: padded.clone(),
+            banks: padded,
+            selected: 0,
+        })
+    }
+
+    /// Addresses the selected bank is seen at
+    pub fn window(&self) -> Range<u32> {
+        self.base..self.base + self.bank_size
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Switches the window to `bank`, returns false if there is no such bank
+    pub fn select(&mut self, bank: usize) -> bool {
+        if bank >= self.banks.len() {
+            return false;
+        }
+        self.selected = bank;
+        true
+    }
+
+    pub fn bank(&self, bank: usize) -> Option<&[u8]> {
+        self.banks.get(bank).map(Vec::as_slice)
+    }
+}
+
+impl Device for BankedMemory {
+    fn name(&self) -> &str {
+        "banks"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// The window and the bank select register after it
+    fn size(&self) -> u32 {
+        self.bank_size + 4
+    }
+
+    fn load(&mut self, offset: u32, len: usize) -> Option<u32> {
+        if offset == self.bank_size + BANK_SELECT {
+            return (len == 4).then_some(self.selected as u32);
+        }
+        let start = offset as usize;
+        let bytes = self.banks[self.selected].get(start..start.checked_add(len)?)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u32),
+        )
+    }
+
+    /// Selecting a bank that doesn't exist is a bus error
+    fn store(&mut self, offset: u32, len: usize, value: u32) -> Option<()> {
+        if offset == self.bank_size + BANK_SELECT {
+            return (len == 4 && self.select(value as usize)).then_some(());
+        }
+        let start = offset as usize;
+        let bytes = self.banks[self.selected].get_mut(start..start.checked_add(len)?)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..len]);
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        self.banks = self.initial.clone();
+        self.selected = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_switches_the_window() {
+        let mut banks =
+            BankedMemory::new(0x1000, 0x100, vec![vec![1, 2, 3, 4], vec![5, 6]]).unwrap();
+        assert_eq!(banks.load(0, 4), Some(0x04030201));
+        banks.store(0x100, 4, 1).unwrap();
+        assert_eq!(banks.load(0, 4), Some(0x00000605));
+        assert_eq!(banks.load(0x100, 4), Some(1));
+
+        // Each bank keeps its own contents until a reset
+        banks.store(4, 1, 9).unwrap();
+        assert!(banks.select(0));
+        assert_eq!(banks.load(4, 1), Some(0));
+        assert_eq!(banks.bank(1).unwrap()[4], 9);
+        banks.reset();
+        assert_eq!(banks.bank(1).unwrap()[4], 0);
+
+        assert_eq!(banks.store(0x100, 4, 2), None);
+        assert_eq!(banks.load(0xfe, 4), None);
+        assert_eq!(banks.window(), 0x1000..0x1100);
+        assert!(BankedMemory::new(0, 2, vec![vec![0; 3]]).is_err());
+        assert!(BankedMemory::new(0, 2, Vec::new()).is_err());
+    }
+}
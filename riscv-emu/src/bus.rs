@@ -0,0 +1,258 @@
+//! The memory bus between the hart and everything it addresses: the shared program image,
+//! writable memory and memory-mapped devices.
+//!
+//! A [`Device`] claims a range of addresses and serves the loads and stores falling into it.
+//! Devices are looked up before memory, so one mapped inside RAM shadows the memory below
+//! it, the way the CLINT sits at its usual address inside the 64 MiB the CLI gives programs.
+
+use std::{any::Any, sync::Arc};
+
+use crate::{metrics::TouchedPages, plic::Plic};
+
+/// A memory-mapped peripheral
+pub trait Device: Any + Send {
+    /// Name of the device's region in the memory layout
+    fn name(&self) -> &str;
+    /// First address of the device's registers
+    fn base(&self) -> u32;
+    /// Bytes of address space the registers take
+    fn size(&self) -> u32;
+    /// Reads `len` (1, 2 or 4) bytes at `offset` from [`Device::base`], `None` for a bus
+    /// error
+    fn load(&mut self, offset: u32, len: usize) -> Option<u32>;
+    /// Writes the low `len` bytes of `value` at `offset`, `None` for a bus error
+    fn store(&mut self, offset: u32, len: usize, value: u32) -> Option<()>;
+    /// Puts the device back into its power-on state
+    fn reset(&mut self);
+    /// Advances the device by one retired instruction
+    fn tick(&mut self) {}
+    /// Bits of mip the device drives, see [`crate::csr::MSI`], [`crate::csr::MTI`] and
+    /// [`crate::csr::MEI`]
+    fn interrupts(&self) -> u64 {
+        0
+    }
+    /// The PLIC source the device's interrupt line is wired to and whether it is raised
+    fn external_interrupt(&self) -> Option<(usize, bool)> {
+        None
+    }
+}
+
+/// Whatever the hart loads from and stores to
+pub trait Bus {
+    /// Reads `len` (at most 4) bytes as a little-endian value, `None` for a bus error
+    fn load(&mut self, address: u32, len: usize) -> Option<u32>;
+    /// Writes the low `len` bytes of `value`, `None` for a bus error
+    fn store(&mut self, address: u32, len: usize, value: u32) -> Option<()>;
+
+    /// Reads a little-endian doubleword as two words
+    fn load_double(&mut self, address: u32) -> Option<u64> {
+        let low = self.load(address, 4)? as u64;
+        let high = self.load(address.wrapping_add(4), 4)? as u64;
+        Some(high << 32 | low)
+    }
+}
+
+/// The program image at address 0, writable memory around it and the attached devices
+pub struct SystemBus {
+    /// Program code mapped at address 0, read-only and shared by every CPU running it
+    pub program: Arc<[u8]>,
+    /// Writable memory, addresses covered by `program` are served from there instead
+    pub dram: Vec<u8>,
+    /// Pages of `dram` written by stores and the machine, for the memory use metric
+    touched: TouchedPages,
+    devices: Vec<Box<dyn Device>>,
+}
+
+impl SystemBus {
+    pub fn new(program: Arc<[u8]>, memory_size: usize) -> Self {
+        Self {
+            program,
+            // Zeroed allocations are lazily backed by the OS, untouched memory costs nothing
+            dram: vec![0; memory_size],
+            touched: TouchedPages::new(memory_size),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Maps `device` at its address range. Devices attached later don't shadow earlier
+    /// ones, overlaps are for [`MemoryLayout::validate`](crate::layout::MemoryLayout) to
+    /// report.
+    pub fn attach(&mut self, device: impl Device) {
+        self.devices.push(Box::new(device));
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &dyn Device> {
+        self.devices.iter().map(|device| device.as_ref())
+    }
+
+    /// The first attached device of type `T`
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        self.devices
+            .iter()
+            .find_map(|device| (device.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.devices
+            .iter_mut()
+            .find_map(|device| (device.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Advances every device by one retired instruction, then passes their interrupt lines
+    /// on to the PLIC if there is one
+    pub fn tick(&mut self) {
+        for device in &mut self.devices {
+            device.tick();
+        }
+        let lines: Vec<(usize, bool)> = self
+            .devices
+            .iter()
+            .filter_map(|device| device.external_interrupt())
+            .collect();
+        if !lines.is_empty()
+            && let Some(plic) = self.device_mut::<Plic>()
+        {
+            for (source, raised) in lines {
+                plic.set_level(source, raised);
+            }
+        }
+    }
+
+    /// The mip bits driven by any device
+    pub fn interrupts(&self) -> u64 {
+        self.devices
+            .iter()
+            .fold(0, |pending, device| pending | device.interrupts())
+    }
+
+    pub fn reset_devices(&mut self) {
+        for device in &mut self.devices {
+            device.reset();
+        }
+    }
+
+    /// Bytes of writable memory in use, counted in whole pages written through stores or
+    /// the machine's memory accessors
+    pub fn memory_written(&self) -> usize {
+        self.touched.bytes()
+    }
+
+    /// Records a write to `dram` made outside of stores
+    pub(crate) fn touch(&mut self, start: usize, len: usize) {
+        self.touched.touch(start, len);
+    }
+
+    /// Zeroes writable memory, with a fresh allocation instead of clearing in place so
+    /// memory that is never touched again stays unbacked
+    pub(crate) fn clear_memory(&mut self) {
+        self.dram = vec![0; self.dram.len()];
+        self.touched = TouchedPages::new(self.dram.len());
+    }
+
+    /// The device whose range holds `address`, and the offset into it
+    fn device_at(&mut self, address: u32) -> Option<(&mut Box<dyn Device>, u32)> {
+        self.devices.iter_mut().find_map(|device| {
+            let offset = address.wrapping_sub(device.base());
+            (offset < device.size()).then_some((device, offset))
+        })
+    }
+}
+
+impl Bus for SystemBus {
+    /// The program image shadows the memory below it
+    fn load(&mut self, address: u32, len: usize) -> Option<u32> {
+        if let Some((device, offset)) = self.device_at(address) {
+            return device.load(offset, len);
+        }
+        let mut value = 0;
+        for offset in (0..len).rev() {
+            let index = (address as usize).checked_add(offset)?;
+            let byte = self
+                .program
+                .get(index)
+                .or_else(|| self.dram.get(index))
+                .copied()?;
+            value = value << 8 | byte as u32;
+        }
+        Some(value)
+    }
+
+    /// `None` outside of memory or inside the read-only program image
+    fn store(&mut self, address: u32, len: usize, value: u32) -> Option<()> {
+        if let Some((device, offset)) = self.device_at(address) {
+            return device.store(offset, len, value);
+        }
+        let start = address as usize;
+        if start < self.program.len() {
+            return None;
+        }
+        let bytes = self.dram.get_mut(start..start.checked_add(len)?)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..len]);
+        self.touched.touch(start, len);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts its loads and keeps the last stored value
+    #[derive(Default)]
+    struct Register {
+        value: u32,
+        loads: u32,
+    }
+
+    impl Device for Register {
+        fn name(&self) -> &str {
+            "register"
+        }
+
+        fn base(&self) -> u32 {
+            0x100
+        }
+
+        fn size(&self) -> u32 {
+            4
+        }
+
+        fn load(&mut self, _offset: u32, _len: usize) -> Option<u32> {
+            self.loads += 1;
+            Some(self.value)
+        }
+
+        fn store(&mut self, _offset: u32, _len: usize, value: u32) -> Option<()> {
+            self.value = value;
+            Some(())
+        }
+
+        fn reset(&mut self) {
+            *self = Self::default();
+        }
+    }
+
+    #[test]
+    fn test_devices_shadow_memory() {
+        let mut bus = SystemBus::new(Arc::from([0x13, 0, 0, 0]), 0x200);
+        bus.attach(Register::default());
+        bus.store(0x100, 4, 7).unwrap();
+        assert_eq!(bus.load(0x103, 1), Some(7));
+        assert_eq!(bus.dram[0x100], 0);
+        assert_eq!(bus.device::<Register>().unwrap().loads, 1);
+
+        // Memory on either side is still memory, the program image is read-only
+        bus.store(0x104, 4, 0x11223344).unwrap();
+        assert_eq!(bus.load_double(0x100), Some(0x11223344_00000007));
+        assert_eq!(bus.store(0, 4, 0), None);
+        assert_eq!(bus.load(0, 4), Some(0x13));
+        assert_eq!(bus.load(0x1fe, 4), None);
+
+        bus.reset_devices();
+        assert_eq!(bus.device_mut::<Register>().unwrap().value, 0);
+        assert_eq!(
+            bus.devices().map(Device::name).collect::<Vec<_>>(),
+            ["register"]
+        );
+    }
+}
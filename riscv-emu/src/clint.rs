@@ -0,0 +1,141 @@
+//! Core-local interruptor: the machine timer and the software interrupt of the single hart,
+//! at the address the SiFive CLINT and QEMU's virt machine put it.
+//!
+//! mtime advances once per retired instruction rather than with the host's clock, so an
+//! interrupt-driven program is interrupted at the same points on every run.
+
+use crate::{
+    bus::Device,
+    csr::{MSI, MTI},
+};
+
+/// Address the CLINT's registers are mapped at
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+
+/// Offsets of the registers from [`CLINT_BASE`]
+pub const MSIP: u32 = 0x0000;
+pub const MTIMECMP: u32 = 0x4000;
+pub const MTIME: u32 = 0xbff8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clint {
+    /// Machine software interrupt pending, bit 0 of msip
+    pub msip: bool,
+    /// The timer interrupt is pending while mtime >= mtimecmp
+    pub mtimecmp: u64,
+    pub mtime: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self {
+            msip: false,
+            // No timer interrupt until the program sets a deadline
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timer_pending(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    /// Start and value of the register holding the `len` bytes at `offset`
+    fn register(&self, offset: u32, len: usize) -> Option<(u32, u64)> {
+        let (start, size, value) = match offset {
+            MSIP..0x0004 => (MSIP, 4, self.msip as u64),
+            MTIMECMP..0x4008 => (MTIMECMP, 8, self.mtimecmp),
+            MTIME..0xc000 => (MTIME, 8, self.mtime),
+            _ => return None,
+        };
+        (offset as u64 + len as u64 <= start as u64 + size).then_some((start, value))
+    }
+}
+
+impl Device for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn base(&self) -> u32 {
+        CLINT_BASE
+    }
+
+    fn size(&self) -> u32 {
+        CLINT_SIZE
+    }
+
+    /// `None` outside of the registers. The 64 bit registers can be read in halves as RV32
+    /// programs do.
+    fn load(&mut self, offset: u32, len: usize) -> Option<u32> {
+        let (start, value) = self.register(offset, len)?;
+        let shift = (offset - start) * 8;
+        let mask = (1u64 << (len * 8)) - 1;
+        Some((value >> shift & mask) as u32)
+    }
+
+    fn store(&mut self, offset: u32, len: usize, value: u32) -> Option<()> {
+        let (start, old) = self.register(offset, len)?;
+        let shift = (offset - start) * 8;
+        let mask = ((1u64 << (len * 8)) - 1) << shift;
+        let new = old & !mask | (value as u64) << shift & mask;
+        match start {
+            MSIP => self.msip = new & 1 != 0,
+            MTIMECMP => self.mtimecmp = new,
+            _ => self.mtime = new,
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    fn interrupts(&self) -> u64 {
+        let software = if self.msip { MSI } else { 0 };
+        let timer = if self.timer_pending() { MTI } else { 0 };
+        software | timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::new();
+        assert!(!clint.timer_pending());
+        // An RV32 program sets mtimecmp one half at a time
+        clint.store(MTIMECMP, 4, 10).unwrap();
+        clint.store(MTIMECMP + 4, 4, 0).unwrap();
+        assert_eq!(clint.mtimecmp, 10);
+        for _ in 0..10 {
+            clint.tick();
+        }
+        assert!(clint.timer_pending());
+        assert_eq!(clint.load(MTIME, 4), Some(10));
+        assert_eq!(clint.load(MTIME + 4, 4), Some(0));
+
+        clint.store(MSIP, 4, 0xffff_ffff).unwrap();
+        assert!(clint.msip);
+        assert_eq!(clint.load(MSIP, 4), Some(1));
+        assert_eq!(clint.load(MSIP + 2, 1), Some(0));
+
+        // Between the registers, or straddling the end of one
+        assert_eq!(clint.load(0x8, 4), None);
+        assert_eq!(clint.store(MTIMECMP + 6, 4, 0), None);
+        assert_eq!(clint.interrupts(), MSI | MTI);
+    }
+}
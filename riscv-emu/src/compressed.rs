@@ -0,0 +1,249 @@
+//! Decoding of the C extension's 16 bit instructions.
+//!
+//! Each compressed instruction is expanded into the 32 bit base instruction it stands for,
+//! which the cpu then executes like any other. Instructions whose low two bits are `11` are
+//! 32 bits long, all others are 16 bit parcels.
+
+/// Length in bytes of the instruction starting with the given parcel
+pub fn instruction_length(parcel: u32) -> u32 {
+    if parcel & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// The 32 bit instruction a compressed instruction stands for, `None` for reserved and
+/// unsupported (floating point) encodings
+pub fn expand(parcel: u16) -> Option<u32> {
+    let c = parcel as u32;
+    let funct3 = c >> 13;
+    let rd = (c >> 7) & 0x1f;
+    let rs2 = (c >> 2) & 0x1f;
+    // x8-x15 in the 3 bit register fields
+    let rd_prime = 8 + ((c >> 2) & 0x7);
+    let rs1_prime = 8 + ((c >> 7) & 0x7);
+    // Sign-extended 6 bit immediate of c.addi, c.li, c.andi
+    let imm6 = sign_extend(bit(c, 12) << 5 | (c >> 2) & 0x1f, 6);
+
+    match (c & 0b11, funct3) {
+        (0b00, 0b000) => {
+            // C.ADDI4SPN
+            let imm = bits(c, 12, 11) << 4 | bits(c, 10, 7) << 6 | bit(c, 6) << 2 | bit(c, 5) << 3;
+            (imm != 0).then(|| i_type(imm as i32, 2, 0b000, rd_prime, 0b0010011))
+        }
+        (0b00, 0b010) => {
+            // C.LW
+            Some(i_type(lw_offset(c), rs1_prime, 0b010, rd_prime, 0b0000011))
+        }
+        (0b00, 0b110) => {
+            // C.SW
+            Some(s_type(lw_offset(c), rd_prime, rs1_prime, 0b010))
+        }
+        (0b01, 0b000) => {
+            // C.ADDI, C.NOP
+            Some(i_type(imm6, rd, 0b000, rd, 0b0010011))
+        }
+        (0b01, 0b001) => {
+            // C.JAL
+            Some(j_type(j_offset(c), 1))
+        }
+        (0b01, 0b010) => {
+            // C.LI
+            Some(i_type(imm6, 0, 0b000, rd, 0b0010011))
+        }
+        (0b01, 0b011) if rd == 2 => {
+            // C.ADDI16SP
+            let imm = sign_extend(
+                bit(c, 12) << 9
+                    | bit(c, 6) << 4
+                    | bit(c, 5) << 6
+                    | bits(c, 4, 3) << 7
+                    | bit(c, 2) << 5,
+                10,
+            );
+            (imm != 0).then(|| i_type(imm, 2, 0b000, 2, 0b0010011))
+        }
+        (0b01, 0b011) => {
+            // C.LUI
+            let imm = sign_extend(bit(c, 12) << 17 | (c >> 2 & 0x1f) << 12, 18);
+            (imm != 0).then_some(imm as u32 & 0xfffff000 | rd << 7 | 0b0110111)
+        }
+        (0b01, 0b100) => {
+            let rd = rs1_prime;
+            match bits(c, 11, 10) {
+                // C.SRLI, C.SRAI, shift amounts of 32 and above are reserved on RV32
+                0b00 if bit(c, 12) == 0 => Some(i_type(imm6 & 0x1f, rd, 0b101, rd, 0b0010011)),
+                0b01 if bit(c, 12) == 0 => {
+                    Some(i_type(0x400 | imm6 & 0x1f, rd, 0b101, rd, 0b0010011))
+                }
+                // C.ANDI
+                0b10 => Some(i_type(imm6, rd, 0b111, rd, 0b0010011)),
+                0b11 if bit(c, 12) == 0 => {
+                    // C.SUB, C.XOR, C.OR, C.AND
+                    let (funct7, funct3) = match bits(c, 6, 5) {
+                        0b00 => (0x20, 0b000),
+                        0b01 => (0, 0b100),
+                        0b10 => (0, 0b110),
+                        _ => (0, 0b111),
+                    };
+                    Some(r_type(funct7, rd_prime, rd, funct3, rd))
+                }
+                _ => None,
+            }
+        }
+        (0b01, 0b101) => {
+            // C.J
+            Some(j_type(j_offset(c), 0))
+        }
+        (0b01, 0b110 | 0b111) => {
+            // C.BEQZ, C.BNEZ
+            let offset = sign_extend(
+                bit(c, 12) << 8
+                    | bits(c, 11, 10) << 3
+                    | bits(c, 6, 5) << 6
+                    | bits(c, 4, 3) << 1
+                    | bit(c, 2) << 5,
+                9,
+            );
+            Some(b_type(offset, rs1_prime, funct3 & 1))
+        }
+        (0b10, 0b000) if bit(c, 12) == 0 => {
+            // C.SLLI
+            Some(i_type(rs2 as i32, rd, 0b001, rd, 0b0010011))
+        }
+        (0b10, 0b010) if rd != 0 => {
+            // C.LWSP
+            let offset = bit(c, 12) << 5 | bits(c, 6, 4) << 2 | bits(c, 3, 2) << 6;
+            Some(i_type(offset as i32, 2, 0b010, rd, 0b0000011))
+        }
+        (0b10, 0b100) => match (bit(c, 12), rd, rs2) {
+            // Reserved
+            (0, 0, 0) => None,
+            // C.JR
+            (0, _, 0) => Some(i_type(0, rd, 0b000, 0, 0b1100111)),
+            // C.MV
+            (0, _, _) => Some(r_type(0, rs2, 0, 0b000, rd)),
+            // C.EBREAK
+            (_, 0, 0) => Some(0x0010_0073),
+            // C.JALR
+            (_, _, 0) => Some(i_type(0, rd, 0b000, 1, 0b1100111)),
+            // C.ADD
+            _ => Some(r_type(0, rs2, rd, 0b000, rd)),
+        },
+        (0b10, 0b110) => {
+            // C.SWSP
+            let offset = bits(c, 12, 9) << 2 | bits(c, 8, 7) << 6;
+            Some(s_type(offset as i32, rs2, 2, 0b010))
+        }
+        _ => None,
+    }
+}
+
+fn bit(value: u32, index: u32) -> u32 {
+    (value >> index) & 1
+}
+
+/// Bits `high` down to `low` of the value, moved to the bottom
+fn bits(value: u32, high: u32, low: u32) -> u32 {
+    (value >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+fn sign_extend(value: u32, width: u32) -> i32 {
+    ((value << (32 - width)) as i32) >> (32 - width)
+}
+
+/// Offset of c.lw and c.sw
+fn lw_offset(c: u32) -> i32 {
+    (bits(c, 12, 10) << 3 | bit(c, 6) << 2 | bit(c, 5) << 6) as i32
+}
+
+/// Offset of c.j and c.jal
+fn j_offset(c: u32) -> i32 {
+    sign_extend(
+        bit(c, 12) << 11
+            | bit(c, 11) << 4
+            | bits(c, 10, 9) << 8
+            | bit(c, 8) << 10
+            | bit(c, 7) << 6
+            | bit(c, 6) << 7
+            | bits(c, 5, 3) << 1
+            | bit(c, 2) << 5,
+        12,
+    )
+}
+
+fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0b0100011
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0b0110011
+}
+
+/// A branch comparing `rs1` to x0
+fn b_type(imm: i32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | 0b1100011
+}
+
+fn j_type(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | 0b1101111
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        // Compressed encodings and their base instructions, taken from the LLVM assembler
+        let cases = [
+            (0x0001, 0x00000013), // c.nop
+            (0x1141, 0xff010113), // c.addi sp, -16
+            (0x4515, 0x00500513), // c.li a0, 5
+            (0x717d, 0xff010113), // c.addi16sp sp, -16
+            (0x0028, 0x00810513), // c.addi4spn a0, sp, 8
+            (0x757d, 0xfffff537), // c.lui a0, 0xfffff
+            (0x852e, 0x00b00533), // c.mv a0, a1
+            (0x952e, 0x00b50533), // c.add a0, a1
+            (0x8c05, 0x40940433), // c.sub s0, s1
+            (0x997d, 0xfff57513), // c.andi a0, -1
+            (0x850d, 0x40355513), // c.srai a0, 3
+            (0x050a, 0x00251513), // c.slli a0, 2
+            (0x41c8, 0x0045a503), // c.lw a0, 4(a1)
+            (0xc1c8, 0x00a5a223), // c.sw a0, 4(a1)
+            (0x40b2, 0x00c12083), // c.lwsp ra, 12(sp)
+            (0xc606, 0x00112623), // c.swsp ra, 12(sp)
+            (0xc501, 0x00050463), // c.beqz a0, 8
+            (0xfd75, 0xfe051ee3), // c.bnez a0, -4
+            (0xbfe5, 0xff9ff06f), // c.j -8
+            (0x2201, 0x100000ef), // c.jal 256
+            (0x8082, 0x00008067), // c.jr ra
+            (0x9502, 0x000500e7), // c.jalr a0
+            (0x9002, 0x00100073), // c.ebreak
+        ];
+        for (parcel, expected) in cases {
+            assert_eq!(expand(parcel), Some(expected), "{:#06x}", parcel);
+        }
+        // Reserved encodings
+        for parcel in [0x0000, 0x6101, 0x6001, 0x8002, 0x1002, 0x9d01] {
+            assert_eq!(expand(parcel), None, "{:#06x}", parcel);
+        }
+        assert_eq!(instruction_length(0x4515), 2);
+        assert_eq!(instruction_length(0x00500513), 4);
+    }
+}
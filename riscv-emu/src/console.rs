@@ -0,0 +1,333 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+
+use crate::host_io::BackgroundReader;
+
+/// How long [`Console::wait_byte`] waits for a streamed byte before checking whether the
+/// stream has ended
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where console input comes from
+pub enum ConsoleInput {
+    /// Fixed bytes supplied up front, the guest sees end of input once they run out
+    Buffer(VecDeque<u8>),
+    /// The host's stdin, read on a background thread started on first use
+    HostStdin(Option<BackgroundReader>),
+    /// Any other background reader (socket, pipe, ...)
+    Reader(BackgroundReader),
+}
+
+impl ConsoleInput {
+    /// Copy of the remaining bytes, `None` for streams that can't be replayed
+    fn snapshot(&self) -> Option<VecDeque<u8>> {
+        match self {
+            Self::Buffer(buffer) => Some(buffer.clone()),
+            Self::HostStdin(_) | Self::Reader(_) => None,
+        }
+    }
+}
+
+/// Guest console: input source plus live and captured output.
+///
+/// Console devices and system calls go through this instead of touching the host's stdio
+/// directly, so embedders and tests fully control program I/O.
+pub struct Console {
+    input: ConsoleInput,
+    /// Output destination other than the host's stdout (socket, pty)
+    sink: Option<Box<dyn Write + Send>>,
+    echo: bool,
+    capture: bool,
+    capture_limit: Option<usize>,
+    captured: Vec<u8>,
+    /// Bytes written after the capture buffer was full
+    dropped: usize,
+    bytes_written: usize,
+    /// Contents of a `ConsoleInput::Buffer` input when it was set, for `reset`
+    initial_input: Option<VecDeque<u8>>,
+}
+
+impl Console {
+    pub fn new(input: ConsoleInput) -> Self {
+        Self {
+            initial_input: input.snapshot(),
+            input,
+            sink: None,
+            echo: false,
+            capture: false,
+            capture_limit: None,
+            captured: Vec::new(),
+            dropped: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Interactive console: host stdin in, host stdout out
+    pub fn host() -> Self {
+        Self::new(ConsoleInput::HostStdin(None)).with_echo(true)
+    }
+
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Keeps up to `limit` bytes of output (unlimited when `None`)
+    pub fn with_capture(mut self, limit: Option<usize>) -> Self {
+        self.capture = true;
+        self.capture_limit = limit;
+        self
+    }
+
+    /// Replaces the input and output ends, keeping the capture settings.
+    ///
+    /// Output stops being echoed to the host's stdout since it now goes to `sink`.
+    pub fn attach(&mut self, input: ConsoleInput, sink: Box<dyn Write + Send>) {
+        self.initial_input = input.snapshot();
+        self.input = input;
+        self.sink = Some(sink);
+        self.echo = false;
+    }
+
+    /// Waits for a client on `listener` and connects the console to it
+    pub fn attach_tcp(&mut self, listener: &TcpListener) -> io::Result<SocketAddr> {
+        let (stream, peer) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let reader = BackgroundReader::spawn(stream.try_clone()?);
+        self.attach(ConsoleInput::Reader(reader), Box::new(stream));
+        Ok(peer)
+    }
+
+    /// Connects the console to a new pseudo-terminal and returns the path to open it with
+    #[cfg(unix)]
+    pub fn attach_pty(&mut self) -> io::Result<std::path::PathBuf> {
+        let pty = crate::host_io::open_pty()?;
+        let reader = BackgroundReader::spawn(pty.master.try_clone()?);
+        let path = pty.path.clone();
+        self.attach(ConsoleInput::Reader(reader), Box::new(PtyWriter(pty)));
+        Ok(path)
+    }
+
+    /// Forgets all output and rewinds buffered input, as if the guest had never run.
+    ///
+    /// Streamed input (stdin, sockets, ptys) can't be rewound and keeps its position.
+    pub fn reset(&mut self) {
+        self.captured.clear();
+        self.dropped = 0;
+        self.bytes_written = 0;
+        if let Some(initial) = &self.initial_input {
+            self.input = ConsoleInput::Buffer(initial.clone());
+        }
+    }
+
+    /// Next input byte, `None` if nothing is available right now or input has ended
+    pub fn read_byte(&mut self) -> Option<u8> {
+        match &mut self.input {
+            ConsoleInput::Buffer(buffer) => buffer.pop_front(),
+            ConsoleInput::HostStdin(reader) => reader
+                .get_or_insert_with(BackgroundReader::stdin)
+                .try_read(),
+            ConsoleInput::Reader(reader) => reader.try_read(),
+        }
+    }
+
+    /// Next input byte, waiting for it to arrive. `None` once input has ended.
+    pub fn wait_byte(&mut self) -> Option<u8> {
+        loop {
+            let byte = match &mut self.input {
+                ConsoleInput::Buffer(buffer) => return buffer.pop_front(),
+                ConsoleInput::HostStdin(reader) => reader
+                    .get_or_insert_with(BackgroundReader::stdin)
+                    .read_timeout(INPUT_POLL_INTERVAL),
+                ConsoleInput::Reader(reader) => reader.read_timeout(INPUT_POLL_INTERVAL),
+            };
+            if byte.is_some() || self.input_exhausted() {
+                return byte;
+            }
+        }
+    }
+
+    /// Whether the guest can never receive more input
+    pub fn input_exhausted(&mut self) -> bool {
+        match &mut self.input {
+            ConsoleInput::Buffer(buffer) => buffer.is_empty(),
+            ConsoleInput::HostStdin(None) => false,
+            ConsoleInput::HostStdin(Some(reader)) | ConsoleInput::Reader(reader) => {
+                reader.is_exhausted()
+            }
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.bytes_written += bytes.len();
+
+        if self.echo {
+            let mut stdout = io::stdout().lock();
+            // Guest output is best effort, a closed host stdout must not stop the guest
+            let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+        }
+
+        if let Some(sink) = &mut self.sink {
+            let _ = sink.write_all(bytes).and_then(|_| sink.flush());
+        }
+
+        if self.capture {
+            let room = self.capture_limit.map_or(bytes.len(), |limit| {
+                limit.saturating_sub(self.captured.len())
+            });
+            let kept = room.min(bytes.len());
+            self.captured.extend_from_slice(&bytes[..kept]);
+            self.dropped += bytes.len() - kept;
+        }
+    }
+
+    /// Captured output
+    pub fn output(&self) -> &[u8] {
+        &self.captured
+    }
+
+    /// Captured output, with invalid UTF-8 replaced
+    pub fn output_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.captured).into_owned()
+    }
+
+    /// True if output was lost because the capture limit was reached
+    pub fn output_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Total bytes the guest wrote, whether or not they were captured
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+/// Writes to the pty master while keeping the whole pty alive
+#[cfg(unix)]
+struct PtyWriter(crate::host_io::Pty);
+
+#[cfg(unix)]
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.master.flush()
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffered_input() {
+        let mut console = Console::new(ConsoleInput::Buffer(b"ab".iter().copied().collect()));
+        assert!(!console.input_exhausted());
+        assert_eq!(console.read_byte(), Some(b'a'));
+        assert_eq!(console.read_byte(), Some(b'b'));
+        assert_eq!(console.read_byte(), None);
+        assert!(console.input_exhausted());
+    }
+
+    #[test]
+    fn test_reset_rewinds_buffered_input() {
+        let mut console =
+            Console::new(ConsoleInput::Buffer(b"ab".iter().copied().collect())).with_capture(None);
+        assert_eq!(console.read_byte(), Some(b'a'));
+        console.write(b"out");
+        console.reset();
+        assert!(console.output().is_empty());
+        assert_eq!(console.bytes_written(), 0);
+        assert_eq!(console.read_byte(), Some(b'a'));
+        assert_eq!(console.read_byte(), Some(b'b'));
+    }
+
+    #[test]
+    fn test_capture_limit() {
+        let mut console = Console::new(ConsoleInput::Buffer(VecDeque::new())).with_capture(Some(5));
+        console.write(b"hel");
+        console.write(b"lo world");
+        assert_eq!(console.output(), b"hello");
+        assert!(console.output_truncated());
+        assert_eq!(console.bytes_written(), 11);
+    }
+
+    #[test]
+    fn test_tcp_console() {
+        use std::{io::Read, net::TcpStream, time::Duration};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"k").unwrap();
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).unwrap();
+            reply
+        });
+
+        let mut console = Console::new(ConsoleInput::Buffer(VecDeque::new())).with_capture(None);
+        console.attach_tcp(&listener).unwrap();
+        let mut input = None;
+        for _ in 0..100 {
+            input = console.read_byte();
+            if input.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(input, Some(b'k'));
+        console.write(b"ok");
+        assert_eq!(&client.join().unwrap(), b"ok");
+        assert_eq!(console.output(), b"ok");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pty_console() {
+        use std::{fs::OpenOptions, io::Read, time::Duration};
+
+        let mut console = Console::new(ConsoleInput::Buffer(VecDeque::new()));
+        let path = console.attach_pty().unwrap();
+        let mut terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+
+        terminal.write_all(b"z").unwrap();
+        let mut input = None;
+        for _ in 0..100 {
+            input = console.read_byte();
+            if input.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(input, Some(b'z'));
+
+        console.write(b"hi");
+        let mut output = [0u8; 2];
+        terminal.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"hi");
+    }
+
+    #[test]
+    fn test_no_capture_by_default() {
+        let mut console = Console::new(ConsoleInput::Buffer(VecDeque::new()));
+        console.write(b"hello");
+        assert!(console.output().is_empty());
+        assert!(!console.output_truncated());
+    }
+}
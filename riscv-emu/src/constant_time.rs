@@ -0,0 +1,215 @@
+//! Constant-time analysis: runs a program on two inputs and reports what an attacker timing
+//! it or watching its memory traffic could tell apart.
+//!
+//! Code handling secrets, a key comparison say, should take the same path through the
+//! program and touch the same addresses whatever the secret is. The runs are compared step
+//! by step: the first place they branch differently, every instruction that accessed a
+//! different address before that, and how long each run took. Comparisons stop where the
+//! paths split, after that the steps no longer correspond.
+
+use crate::{
+    machine::{ExitReason, Machine, RunLimits, RunOptions},
+    shadow::MemoryAccess,
+};
+
+/// An executed instruction and the memory it accessed, as a side channel sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Step {
+    pc: u32,
+    address: Option<u32>,
+}
+
+/// Where the two runs stopped executing the same instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchDivergence {
+    /// Index of the first step that differs
+    pub step: usize,
+    /// The instruction both runs executed last, usually the input-dependent branch
+    pub pc: Option<u32>,
+    /// Where each run went next, `None` for a run that had already stopped
+    pub next: [Option<u32>; 2],
+}
+
+/// An instruction that accessed a different address in each run, e.g. a table lookup
+/// indexed by a secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressLeak {
+    /// Index of the first step the instruction made differing accesses at
+    pub step: usize,
+    pub pc: u32,
+    pub addresses: [u32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantTimeReport {
+    /// Instructions each run executed
+    pub instructions: [u64; 2],
+    /// Cycles each run took
+    pub cycles: [u64; 2],
+    pub exit_reasons: [ExitReason; 2],
+    pub divergence: Option<BranchDivergence>,
+    /// One per instruction, in the order they were first found
+    pub address_leaks: Vec<AddressLeak>,
+}
+
+impl ConstantTimeReport {
+    /// Whether neither the path, the accessed addresses nor the duration depended on the
+    /// input
+    pub fn is_constant_time(&self) -> bool {
+        self.divergence.is_none()
+            && self.address_leaks.is_empty()
+            && self.instructions[0] == self.instructions[1]
+            && self.cycles[0] == self.cycles[1]
+    }
+}
+
+/// Builds a machine with `make_machine` for each of the `inputs`, fed to the program as its
+/// console input, runs both and compares what they did
+pub fn audit_constant_time(
+    make_machine: impl Fn(&RunOptions) -> Machine,
+    options: &RunOptions,
+    inputs: [&[u8]; 2],
+    limits: &RunLimits,
+) -> ConstantTimeReport {
+    let run = |input: &[u8]| {
+        let options = RunOptions {
+            input: Some(input.to_vec()),
+            echo_output: false,
+            ..options.clone()
+        };
+        let mut machine = make_machine(&options);
+        let (steps, exit_reason) = record(&mut machine, limits);
+        (
+            steps,
+            exit_reason,
+            machine.instructions_retired(),
+            machine.cycles(),
+        )
+    };
+    let (first, first_reason, first_instructions, first_cycles) = run(inputs[0]);
+    let (second, second_reason, second_instructions, second_cycles) = run(inputs[1]);
+
+    let mut divergence = None;
+    let mut address_leaks: Vec<AddressLeak> = Vec::new();
+    for step in 0..first.len().max(second.len()) {
+        let (a, b) = (first.get(step), second.get(step));
+        if a.map(|a| a.pc) != b.map(|b| b.pc) {
+            divergence = Some(BranchDivergence {
+                step,
+                pc: step.checked_sub(1).map(|previous| first[previous].pc),
+                next: [a.map(|a| a.pc), b.map(|b| b.pc)],
+            });
+            break;
+        }
+        if let (Some(a), Some(b)) = (a, b)
+            && let (Some(first_address), Some(second_address)) = (a.address, b.address)
+            && first_address != second_address
+            && !address_leaks.iter().any(|leak| leak.pc == a.pc)
+        {
+            address_leaks.push(AddressLeak {
+                step,
+                pc: a.pc,
+                addresses: [first_address, second_address],
+            });
+        }
+    }
+
+    ConstantTimeReport {
+        instructions: [first_instructions, second_instructions],
+        cycles: [first_cycles, second_cycles],
+        exit_reasons: [first_reason, second_reason],
+        divergence,
+        address_leaks,
+    }
+}
+
+/// Runs `machine` like [`Machine::run`], keeping every step
+fn record(machine: &mut Machine, limits: &RunLimits) -> (Vec<Step>, ExitReason) {
+    let mut steps = Vec::new();
+    let exit_reason = loop {
+        let pc = machine.cpu.pc;
+        if !machine.in_loaded_image(pc) {
+            break ExitReason::EndOfProgram;
+        }
+        if limits
+            .max_instructions
+            .is_some_and(|max| steps.len() as u64 >= max)
+        {
+            break ExitReason::InstructionLimit;
+        }
+        let address = machine
+            .instruction_at(pc)
+            .and_then(|(instruction, _)| MemoryAccess::of(instruction, &machine.cpu.regs))
+            .map(|access| access.address);
+        steps.push(Step { pc, address });
+        if let Err(exception) = machine.step() {
+            break ExitReason::from_exception(exception);
+        }
+    };
+    (steps, exit_reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::SyscallMode;
+
+    fn audit(words: &[u32], inputs: [&[u8]; 2]) -> ConstantTimeReport {
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let options = RunOptions {
+            syscalls: SyscallMode::Bare,
+            ..RunOptions::default()
+        };
+        audit_constant_time(
+            |options| Machine::with_options(program.clone(), 0x1000, options),
+            &options,
+            inputs,
+            &RunLimits::default(),
+        )
+    }
+
+    #[test]
+    fn test_constant_time_audit() {
+        // Reads a byte and adds it to itself, whatever it is
+        let constant = [
+            0x00c00893, // addi a7, zero, 12, read_char
+            0x00000073, // ecall
+            0x00a50533, // add a0, a0, a0
+        ];
+        let report = audit(&constant, [b"a", b"b"]);
+        assert!(report.is_constant_time(), "{:?}", report);
+        assert_eq!(report.instructions, [3, 3]);
+
+        // Skips an instruction on 'a', and loads from an address depending on the byte
+        let leaky = [
+            0x00c00893, // addi a7, zero, 12
+            0x00000073, // ecall
+            0x06100293, // addi t0, zero, 'a'
+            0x00550463, // beq a0, t0, 8
+            0x00000013, // nop
+            0x10054303, // lbu t1, 256(a0)
+        ];
+        let report = audit(&leaky, [b"b", b"c"]);
+        assert_eq!(report.divergence, None);
+        assert_eq!(
+            report.address_leaks,
+            [AddressLeak {
+                step: 5,
+                pc: 20,
+                addresses: [0x162, 0x163],
+            }]
+        );
+        assert!(!report.is_constant_time());
+
+        let report = audit(&leaky, [b"a", b"b"]);
+        assert_eq!(
+            report.divergence,
+            Some(BranchDivergence {
+                step: 4,
+                pc: Some(12),
+                next: [Some(20), Some(16)],
+            })
+        );
+        assert_eq!(report.instructions, [5, 6]);
+    }
+}
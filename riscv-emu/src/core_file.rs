@@ -0,0 +1,237 @@
+//! ELF core files of a machine's state, for post-mortem debugging with riscv gdb.
+//!
+//! The file has the layout Linux writes for riscv32 processes: an `NT_PRSTATUS` note with the
+//! registers, and a `PT_LOAD` segment for every run of memory pages that aren't all zero.
+//! `gdb program.elf core` then shows registers, backtraces and memory as of the dump.
+
+use std::io::{self, Write};
+
+use crate::{cpu::Exception, machine::Machine};
+
+const EM_RISCV: u16 = 243;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+/// `struct elf_prstatus` of a 32 bit target
+const PRSTATUS_SIZE: usize = 204;
+/// Offset of `pr_reg` (pc, then x1-x31) in the prstatus
+const PRSTATUS_REGS: usize = 72;
+/// Granularity at which all-zero memory is left out
+const PAGE_SIZE: usize = 4096;
+
+const SIGILL: u16 = 4;
+const SIGTRAP: u16 = 5;
+const SIGBUS: u16 = 7;
+const SIGSEGV: u16 = 11;
+
+/// A memory segment of the core file
+struct Segment {
+    address: u32,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+/// Writes the machine's registers and memory as an ELF core file.
+///
+/// `exception` is the reason for the dump, if any, and is reported to gdb as the signal a
+/// Linux process would have received for it. Memory pages that are all zero are left out to
+/// keep dumps of mostly unused memory small, gdb can't read them from the core.
+pub fn write_core_file(
+    writer: &mut impl Write,
+    machine: &Machine,
+    exception: Option<Exception>,
+) -> io::Result<()> {
+    let segments = segments(machine);
+    let headers_size = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (1 + segments.len());
+    let note = prstatus_note(machine, exception);
+
+    let mut output = Vec::with_capacity(headers_size + note.len());
+    // e_ident: ELF, 32 bit, little-endian, version 1, System V ABI
+    output.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    output.extend_from_slice(&ET_CORE.to_le_bytes());
+    output.extend_from_slice(&EM_RISCV.to_le_bytes());
+    output.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    output.extend_from_slice(&0u32.to_le_bytes()); // e_entry
+    output.extend_from_slice(&(ELF_HEADER_SIZE as u32).to_le_bytes()); // e_phoff
+    output.extend_from_slice(&0u32.to_le_bytes()); // e_shoff
+    output.extend_from_slice(&0u32.to_le_bytes()); // e_flags, soft-float ABI
+    output.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    output.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    output.extend_from_slice(&(1 + segments.len() as u16).to_le_bytes()); // e_phnum
+    output.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+    let mut offset = headers_size;
+    program_header(&mut output, PT_NOTE, offset, 0, note.len(), 0, 4);
+    offset += note.len();
+    for segment in &segments {
+        program_header(
+            &mut output,
+            PT_LOAD,
+            offset,
+            segment.address,
+            segment.data.len(),
+            segment.flags,
+            PAGE_SIZE as u32,
+        );
+        offset += segment.data.len();
+    }
+    output.extend_from_slice(&note);
+
+    writer.write_all(&output)?;
+    for segment in &segments {
+        writer.write_all(&segment.data)?;
+    }
+    writer.flush()
+}
+
+fn program_header(
+    output: &mut Vec<u8>,
+    kind: u32,
+    offset: usize,
+    address: u32,
+    size: usize,
+    flags: u32,
+    align: u32,
+) {
+    for field in [
+        kind,
+        offset as u32,
+        address, // p_vaddr
+        address, // p_paddr
+        size as u32,
+        size as u32,
+        flags,
+        align,
+    ] {
+        output.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+/// Name "CORE" and a prstatus with the signal and registers
+fn prstatus_note(machine: &Machine, exception: Option<Exception>) -> Vec<u8> {
+    let mut prstatus = vec![0; PRSTATUS_SIZE];
+    let signal = match exception {
+        Some(Exception::IllegalInstruction { .. }) => SIGILL,
+        Some(
+            Exception::InstructionAccessFault { .. }
+            | Exception::LoadAccessFault { .. }
+            | Exception::StoreAccessFault { .. },
+        ) => SIGSEGV,
+        Some(Exception::InstructionAddressMisaligned { .. }) => SIGBUS,
+        Some(Exception::Breakpoint { .. }) => SIGTRAP,
+        Some(Exception::EnvironmentCall { .. }) | None => 0,
+    };
+    prstatus[0..4].copy_from_slice(&u32::from(signal).to_le_bytes()); // si_signo
+    prstatus[12..14].copy_from_slice(&signal.to_le_bytes()); // pr_cursig
+    prstatus[24..28].copy_from_slice(&1u32.to_le_bytes()); // pr_pid
+    // The core is 32 bit, RV64 registers are cut down to their low halves
+    let mut regs = machine.cpu.regs.map(|value| value as u32);
+    // gdb's register map puts the pc where x0 would be
+    regs[0] = machine.cpu.pc;
+    for (index, value) in regs.iter().enumerate() {
+        let start = PRSTATUS_REGS + index * 4;
+        prstatus[start..start + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let mut note = Vec::with_capacity(20 + PRSTATUS_SIZE);
+    note.extend_from_slice(&5u32.to_le_bytes()); // namesz, including the terminator
+    note.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    // The name is padded to a multiple of 4
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(&prstatus);
+    note
+}
+
+/// Runs of pages with data, the read-only program image separate from writable memory
+fn segments(machine: &Machine) -> Vec<Segment> {
+    let program = &machine.cpu.bus.program;
+    let dram = &machine.cpu.bus.dram;
+    let mut segments: Vec<Segment> = Vec::new();
+    if !program.is_empty() {
+        segments.push(Segment {
+            address: 0,
+            flags: PF_R | PF_X,
+            data: program.to_vec(),
+        });
+    }
+    // Writable memory starts where the program image ends, not necessarily on a page boundary
+    let mut start = program.len();
+    while start < dram.len() {
+        let end = (start / PAGE_SIZE + 1) * PAGE_SIZE;
+        let page = &dram[start..end.min(dram.len())];
+        if page.iter().any(|&byte| byte != 0) {
+            match segments.last_mut() {
+                Some(segment)
+                    if segment.flags & PF_W != 0
+                        && segment.address as usize + segment.data.len() == start =>
+                {
+                    segment.data.extend_from_slice(page)
+                }
+                _ => segments.push(Segment {
+                    address: start as u32,
+                    flags: PF_R | PF_W | PF_X,
+                    data: page.to_vec(),
+                }),
+            }
+        }
+        start = end;
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_core_file_layout() {
+        // addi a0, zero, 7; unimp
+        let program = [0x00700513u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let mut machine = Machine::new(program, 0x4000);
+        let exception = machine.step().and_then(|()| machine.step()).unwrap_err();
+        machine.write_memory(0x2ffc, &[1, 2, 3, 4, 5]).unwrap();
+
+        let mut core = Vec::new();
+        write_core_file(&mut core, &machine, Some(exception)).unwrap();
+        assert_eq!(&core[..6], b"\x7fELF\x01\x01");
+        assert_eq!(u16::from_le_bytes([core[16], core[17]]), ET_CORE);
+        assert_eq!(u16::from_le_bytes([core[18], core[19]]), EM_RISCV);
+        // The note, the program and the two pages the write touched
+        assert_eq!(u16::from_le_bytes([core[44], core[45]]), 3);
+
+        let phdr = |index: usize| ELF_HEADER_SIZE + index * PROGRAM_HEADER_SIZE;
+        let note = field(&core, phdr(0) + 4) as usize;
+        assert_eq!(field(&core, note + 8), NT_PRSTATUS);
+        let prstatus = note + 20;
+        assert_eq!(
+            u16::from_le_bytes([core[prstatus + 12], core[prstatus + 13]]),
+            SIGILL
+        );
+        let regs = prstatus + PRSTATUS_REGS;
+        assert_eq!(field(&core, regs), 4);
+        assert_eq!(field(&core, regs + 10 * 4), 7);
+
+        assert_eq!(field(&core, phdr(1) + 8), 0);
+        assert_eq!(field(&core, phdr(1) + 16), 8);
+        assert_eq!(field(&core, phdr(2) + 8), 0x2000);
+        assert_eq!(field(&core, phdr(2) + 16), 0x2000);
+        let data = field(&core, phdr(2) + 4) as usize;
+        assert_eq!(&core[data + 0xffc..data + 0x1001], &[1, 2, 3, 4, 5]);
+        assert_eq!(core.len(), data + 0x2000);
+    }
+}
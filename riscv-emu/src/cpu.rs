@@ -1,100 +1,1545 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use tracing::{trace, warn};
+
+use crate::{
+    bus::{Bus, SystemBus},
+    compressed,
+    csr::{self, Csrs},
+    float::{self, Float, Outcome, RoundingMode},
+    tags::TagMemory,
+};
+
+/// Base integer ISA the CPU implements
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BaseIsa {
+    #[default]
+    Rv32I,
+    /// Embedded variant with only x0-x15
+    Rv32E,
+    /// 64 bit registers. Compressed instructions aren't supported here yet, and the pc and
+    /// addresses remain 32 bits: programs run in the low 4GiB.
+    Rv64I,
+}
+
+impl BaseIsa {
+    /// Width of the integer registers in bits
+    pub fn xlen(self) -> u32 {
+        match self {
+            BaseIsa::Rv32I | BaseIsa::Rv32E => 32,
+            BaseIsa::Rv64I => 64,
+        }
+    }
+}
+
+/// Standard extensions the CPU implements on top of its base ISA, instructions of the
+/// others are illegal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// Integer multiplication and division
+    pub m: bool,
+    /// Atomics
+    pub a: bool,
+    /// Single precision floating point
+    pub f: bool,
+    /// Double precision floating point
+    pub d: bool,
+    /// Compressed instructions, never available on RV64
+    pub c: bool,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self {
+            m: true,
+            a: true,
+            f: true,
+            d: true,
+            c: true,
+        }
+    }
+}
+
+impl Extensions {
+    /// The bare base ISA
+    pub fn none() -> Self {
+        Self {
+            m: false,
+            a: false,
+            f: false,
+            d: false,
+            c: false,
+        }
+    }
+
+    /// Whether the (expanded) `instruction` belongs to the base ISA or an enabled extension
+    fn allow(&self, instruction: u32) -> bool {
+        let funct3 = (instruction >> 12) & 0x7;
+        let format = (instruction >> 25) & 0b11;
+        match instruction & 0x7f {
+            0b0110011 | 0b0111011 => self.m || instruction >> 25 != 0x01,
+            0b0101111 => self.a,
+            0b0000111 | 0b0100111 => {
+                if funct3 == 3 {
+                    self.d
+                } else {
+                    self.f
+                }
+            }
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 | 0b1010011 => {
+                if format == 1 {
+                    self.d
+                } else {
+                    self.f
+                }
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Synchronous exception raised by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// A jump or branch to, or execution starting at, an address that isn't a multiple of
+    /// the instruction alignment: 2 bytes with the C extension, 4 on RV64
+    InstructionAddressMisaligned {
+        pc: u32,
+        target: u32,
+    },
+    IllegalInstruction {
+        pc: u32,
+        instruction: u32,
+    },
+    /// The pc points outside of memory
+    InstructionAccessFault {
+        pc: u32,
+    },
+    /// A load from outside of memory
+    LoadAccessFault {
+        pc: u32,
+        address: u32,
+    },
+    /// A store outside of memory or into the read-only program image
+    StoreAccessFault {
+        pc: u32,
+        address: u32,
+    },
+    /// `ecall`, a request to the execution environment
+    EnvironmentCall {
+        pc: u32,
+    },
+    /// `ebreak`
+    Breakpoint {
+        pc: u32,
+    },
+}
+
+impl Exception {
+    /// Address of the instruction that raised the exception
+    pub fn pc(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned { pc, .. }
+            | Exception::IllegalInstruction { pc, .. }
+            | Exception::InstructionAccessFault { pc }
+            | Exception::LoadAccessFault { pc, .. }
+            | Exception::StoreAccessFault { pc, .. }
+            | Exception::EnvironmentCall { pc }
+            | Exception::Breakpoint { pc } => pc,
+        }
+    }
+
+    /// Exception code, the value of mcause when it traps
+    pub fn cause(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned { .. } => 0,
+            Exception::InstructionAccessFault { .. } => 1,
+            Exception::IllegalInstruction { .. } => 2,
+            Exception::Breakpoint { .. } => 3,
+            Exception::LoadAccessFault { .. } => 5,
+            Exception::StoreAccessFault { .. } => 7,
+            // From machine mode, the only mode there is
+            Exception::EnvironmentCall { .. } => 11,
+        }
+    }
+
+    /// The value mtval is set to when it traps: the faulting address or instruction
+    pub fn value(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned { target, .. } => target,
+            Exception::IllegalInstruction { instruction, .. } => instruction,
+            Exception::InstructionAccessFault { pc } | Exception::Breakpoint { pc } => pc,
+            Exception::LoadAccessFault { address, .. }
+            | Exception::StoreAccessFault { address, .. } => address,
+            Exception::EnvironmentCall { .. } => 0,
+        }
+    }
+}
+
 pub struct Cpu {
     /// Program counter
     pub pc: u32,
-    /// Registers
-    pub regs: [u32; 32],
-    /// Program code
-    pub dram: Vec<u8>,
+    /// Registers, on RV32 only the low 32 bits are used and the high ones stay zero
+    pub regs: [u64; 32],
+    /// Program image, memory and devices
+    pub bus: SystemBus,
+    pub base_isa: BaseIsa,
+    pub extensions: Extensions,
+    /// Floating point registers f0-f31, single precision values are NaN-boxed
+    pub fregs: [u64; 32],
+    /// Floating point control and status: the accrued exception flags (fflags) in bits 0-4,
+    /// the dynamic rounding mode (frm) in bits 5-7
+    pub fcsr: u32,
+    /// Machine-mode CSRs, see [`crate::csr`]
+    pub csrs: Csrs,
+    /// Tag bits checked on loads and stores, see [`crate::tags`]
+    pub tags: Option<TagMemory>,
+    /// Most recently fetched instruction
+    last_instruction: u32,
+    /// Word reserved by the last `lr.w`, any store to it (by this hart or anyone else) makes
+    /// the next `sc.w` fail
+    reservation: Option<u32>,
+    /// Sleeping in a `wfi` until an enabled interrupt is pending
+    waiting: bool,
 }
 
 impl Cpu {
     pub fn new_with_instructions(instructions: Vec<u8>) -> Self {
+        Self::new_with_program(instructions.into(), 0)
+    }
+
+    pub fn new_with_program(program: Arc<[u8]>, memory_size: usize) -> Self {
         Self {
             pc: 0,
             regs: [0; 32],
-            dram: instructions,
+            bus: SystemBus::new(program, memory_size),
+            base_isa: BaseIsa::default(),
+            extensions: Extensions::default(),
+            fregs: [0; 32],
+            fcsr: 0,
+            csrs: Csrs::new(),
+            tags: None,
+            last_instruction: 0,
+            reservation: None,
+            waiting: false,
+        }
+    }
+
+    /// Clears the registers, resets the devices and restarts execution at `pc`, memory is
+    /// left alone
+    pub fn reset(&mut self, pc: u32) {
+        self.pc = pc;
+        self.regs = [0; 32];
+        self.fregs = [0; 32];
+        self.fcsr = 0;
+        self.csrs = Csrs::new();
+        if let Some(tags) = &mut self.tags {
+            tags.reset();
         }
+        self.bus.reset_devices();
+        self.last_instruction = 0;
+        self.reservation = None;
+        self.waiting = false;
+    }
+
+    /// Writes `value` to x`index` truncated to XLEN, writes to x0 are discarded
+    pub fn set_register(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.write(index, value);
+        }
+    }
+
+    pub fn last_instruction(&self) -> u32 {
+        self.last_instruction
     }
 
-    pub fn step(&mut self) {
+    /// Bytes of writable memory in use, counted in whole pages written through stores or the
+    /// machine's memory accessors
+    pub fn memory_written(&self) -> usize {
+        self.bus.memory_written()
+    }
+
+    /// Records a write to `dram` made outside of stores
+    pub(crate) fn touch_memory(&mut self, start: usize, len: usize) {
+        self.bus.touch(start, len);
+        self.invalidate_reservation(start, len);
+    }
+
+    /// Word reserved by `lr.w`, if the reservation is still valid
+    pub fn reservation(&self) -> Option<u32> {
+        self.reservation
+    }
+
+    /// Whether a `wfi` still waits for an interrupt. It wakes once an interrupt mie enables
+    /// is pending, whether or not mstatus.MIE lets it trap.
+    pub fn is_waiting(&mut self) -> bool {
+        if self.waiting && self.bus.interrupts() & self.csrs.mie != 0 {
+            self.waiting = false;
+        }
+        self.waiting
+    }
+
+    /// Drops the reservation if the `len` bytes at `start` overlap the reserved word
+    fn invalidate_reservation(&mut self, start: usize, len: usize) {
+        if let Some(reserved) = self.reservation
+            && start < reserved as usize + 4
+            && (reserved as usize) < start.saturating_add(len)
+        {
+            self.reservation = None;
+        }
+    }
+
+    /// Zeroes writable memory, with a fresh allocation instead of clearing in place so memory
+    /// that is never touched again stays unbacked
+    pub(crate) fn clear_memory(&mut self) {
+        self.bus.clear_memory();
+        self.reservation = None;
+    }
+
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let pc = self.pc;
+        if pc & self.alignment_mask() != 0 {
+            return Err(Exception::InstructionAddressMisaligned { pc, target: pc });
+        }
         // Fetch instruction
-        let instruction = self.fetch();
+        let instruction = self
+            .fetch()
+            .ok_or(Exception::InstructionAccessFault { pc })?;
+        self.last_instruction = instruction;
+        trace!(
+            pc = format_args!("{:#010x}", pc),
+            instruction = format_args!("{:#010x}", instruction),
+            "step"
+        );
 
-        // Increment program counter (4 bytes, 32 bits per instruction)
-        self.pc += 4;
-        // Reset the "0" register
-        self.regs[0] = 0;
+        let length = compressed::instruction_length(instruction);
+        let illegal = Exception::IllegalInstruction { pc, instruction };
+        let expanded = if length == 2 {
+            if self.base_isa == BaseIsa::Rv64I || !self.extensions.c {
+                return Err(illegal);
+            }
+            compressed::expand(instruction as u16).ok_or(illegal)?
+        } else {
+            instruction
+        };
+        if self.base_isa == BaseIsa::Rv32E && uses_upper_registers(expanded)
+            || !self.extensions.allow(expanded)
+        {
+            return Err(illegal);
+        }
+
+        // Increment program counter (2 or 4 bytes, depending on the instruction)
+        self.pc = pc.wrapping_add(length);
 
         // Decode instruction
         // &
         // Execute the instruction
-        self.execute(instruction);
+        let result = self.execute(expanded, pc);
+        // Writes to x0 are discarded
+        self.regs[0] = 0;
+        if result.is_err() {
+            // A trapping instruction doesn't retire, the pc stays on it
+            self.pc = pc;
+        }
+        result
+    }
+
+    /// Low bits that must be clear in the address of an instruction, RV64 always runs
+    /// without the C extension
+    fn alignment_mask(&self) -> u32 {
+        match self.base_isa {
+            BaseIsa::Rv64I => 0b11,
+            _ if !self.extensions.c => 0b11,
+            _ => 0b01,
+        }
+    }
+
+    /// Checks the target of a taken jump or branch at `pc`
+    fn jump_target(&self, pc: u32, target: u32) -> Result<u32, Exception> {
+        match target & self.alignment_mask() {
+            0 => Ok(target),
+            _ => Err(Exception::InstructionAddressMisaligned { pc, target }),
+        }
+    }
+
+    /// Takes a trap for `exception` if the program installed a handler by pointing mtvec at
+    /// it, and returns whether it did. Without a handler (mtvec is 0) the exception is left
+    /// to the caller, which usually ends the run.
+    pub fn take_trap(&mut self, exception: Exception) -> bool {
+        if self.csrs.mtvec & !0b11 == 0 {
+            return false;
+        }
+        self.enter_trap(exception.cause(), exception.value().into(), exception.pc());
+        true
+    }
+
+    /// Takes the highest priority interrupt that is pending and enabled, if interrupts are
+    /// on and the program installed a handler. Returns whether it did, the pc is then at
+    /// the handler and the interrupted instruction is where MRET returns to.
+    pub fn take_interrupt(&mut self) -> bool {
+        self.csrs.mip = self.bus.interrupts();
+        let pending = self.csrs.mip & self.csrs.mie;
+        if pending == 0 || self.csrs.mstatus & csr::MSTATUS_MIE == 0 || self.csrs.mtvec & !0b11 == 0
+        {
+            return false;
+        }
+        // External before software before timer interrupts
+        let code = [(csr::MEI, 11), (csr::MSI, 3), (csr::MTI, 7)]
+            .into_iter()
+            .find(|(bit, _)| pending & bit != 0)
+            .map_or(0, |(_, code)| code);
+        let interrupt = 1 << (self.base_isa.xlen() - 1);
+        self.enter_trap(interrupt | code, 0, self.pc);
+        true
+    }
+
+    /// Enters the trap handler for `cause` (with the interrupt bit at XLEN-1 for
+    /// interrupts), returning to `epc` on MRET
+    pub(crate) fn enter_trap(&mut self, cause: u64, value: u64, epc: u32) {
+        let csrs = &mut self.csrs;
+        csrs.mepc = epc.into();
+        csrs.mcause = cause;
+        csrs.mtval = value;
+        // The handler runs with interrupts off, MRET restores them from MPIE
+        let enabled = csrs.mstatus & csr::MSTATUS_MIE != 0;
+        csrs.mstatus &= !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+        if enabled {
+            csrs.mstatus |= csr::MSTATUS_MPIE;
+        }
+        let base = (csrs.mtvec & !0b11) as u32;
+        let interrupt = cause >> (self.base_isa.xlen() - 1) != 0;
+        self.pc = if interrupt && csrs.mtvec & 0b11 == 1 {
+            // Vectored mode, every interrupt has its own entry
+            let code = self.wrap(cause << 1) >> 1;
+            base.wrapping_add(4 * code as u32)
+        } else {
+            base
+        };
+        self.reservation = None;
+        self.waiting = false;
+    }
+
+    /// Reads the instruction at the pc, `None` if it isn't backed by memory. Compressed
+    /// instructions are returned as their 16 bit parcel.
+    fn fetch(&mut self) -> Option<u32> {
+        let parcel = self.load(self.pc, 2)?;
+        match compressed::instruction_length(parcel) {
+            2 => Some(parcel),
+            _ => self.load(self.pc, 4),
+        }
+    }
+
+    fn load(&mut self, address: u32, len: usize) -> Option<u32> {
+        self.bus.load(address, len)
+    }
+
+    /// Loads data through the bus, `None` for tagged words when tags are checked
+    fn load_data(&mut self, address: u32, len: usize) -> Option<u32> {
+        if !self.tags_allow(address, len, false) {
+            return None;
+        }
+        self.bus.load(address, len)
+    }
+
+    fn load_double(&mut self, address: u32) -> Option<u64> {
+        if !self.tags_allow(address, 8, false) {
+            return None;
+        }
+        self.bus.load_double(address)
+    }
+
+    fn tags_allow(&self, address: u32, len: usize, store: bool) -> bool {
+        self.tags
+            .as_ref()
+            .is_none_or(|tags| tags.allows(address, len, store))
     }
 
-    fn fetch(&self) -> u32 {
-        let index = self.pc as usize;
+    /// Stores through the bus, a store to the reserved word drops the reservation
+    fn store(&mut self, address: u32, len: usize, value: u32) -> Option<()> {
+        if !self.tags_allow(address, len, true) {
+            return None;
+        }
+        self.bus.store(address, len, value)?;
+        self.invalidate_reservation(address as usize, len);
+        Some(())
+    }
+
+    /// Writes a doubleword as two words, the upper half first so a fault leaves memory
+    /// untouched
+    fn store_double(&mut self, address: u32, value: u64) -> Option<()> {
+        self.store(address.wrapping_add(4), 4, (value >> 32) as u32)?;
+        self.store(address, 4, value as u32)
+    }
+
+    /// Truncates `value` to XLEN bits, the form registers hold their values in
+    fn wrap(&self, value: u64) -> u64 {
+        match self.base_isa.xlen() {
+            32 => value as u32 as u64,
+            _ => value,
+        }
+    }
 
-        // Using little-endian
-        self.dram[index] as u32
-            | (self.dram[index + 1] as u32) << 8
-            | (self.dram[index + 2] as u32) << 16
-            | (self.dram[index + 3] as u32) << 24
+    /// A register value as a signed XLEN bit integer
+    fn signed(&self, value: u64) -> i64 {
+        match self.base_isa.xlen() {
+            32 => value as u32 as i32 as i64,
+            _ => value as i64,
+        }
+    }
+
+    fn write(&mut self, rd: usize, value: u64) {
+        self.regs[rd] = self.wrap(value);
     }
 
-    fn execute(&mut self, instruction: u32) {
-        let immediate = instruction;
+    /// Writes a 32 bit result, sign-extended to XLEN
+    fn write_word(&mut self, rd: usize, value: u32) {
+        self.write(rd, value as i32 as u64);
+    }
+
+    /// Executes the instruction fetched from `pc`, `self.pc` already points past it
+    fn execute(&mut self, instruction: u32, pc: u32) -> Result<(), Exception> {
         let opcode = instruction & 0x7f; // 7 bits
         let rd = ((instruction >> 7) & 0x1f) as usize; // 5 bits
-        #[allow(unused_variables)]
         let funct3 = ((instruction >> 12) & 0x7) as usize; // 3 bits
         let rs1 = ((instruction >> 15) & 0x1f) as usize; // 5 bits
         let rs2 = ((instruction >> 20) & 0x1f) as usize; // 5 bits
-        #[allow(unused_variables)]
         let funct7 = ((instruction >> 25) & 0x7f) as usize; // 7 bits
+        let illegal = Exception::IllegalInstruction { pc, instruction };
+
+        // Sign extended immediates of the different instruction formats, the ones combined
+        // with registers extended to XLEN
+        let imm_i = self.wrap((instruction as i32 >> 20) as u64);
+        let imm_s =
+            self.wrap(((instruction as i32 >> 25) << 5 | (instruction as i32 >> 7) & 0x1f) as u64);
+        let imm_b = ((instruction & 0x8000_0000) as i32 >> 19) as u32
+            | (instruction << 4) & 0x800
+            | (instruction >> 20) & 0x7e0
+            | (instruction >> 7) & 0x1e;
+        let imm_u = instruction & 0xffff_f000;
+        let imm_j = ((instruction & 0x8000_0000) as i32 >> 11) as u32
+            | (instruction & 0x000f_f000)
+            | (instruction >> 9) & 0x800
+            | (instruction >> 20) & 0x7fe;
 
+        let rv64 = self.base_isa == BaseIsa::Rv64I;
+        let xlen = self.base_isa.xlen();
+        let a = self.regs[rs1];
+        let b = self.regs[rs2];
+        let (signed_a, signed_b) = (self.signed(a), self.signed(b));
         match opcode {
             // IMMEDIATE
             0b0110111 => {
                 // LUI
-                self.regs[rd] = immediate & 0xFFFFF000;
+                self.write_word(rd, imm_u);
+            }
+            0b0010111 => {
+                // AUIPC
+                self.write(rd, (pc as u64).wrapping_add(imm_u as i32 as u64));
             }
             0b0010011 => {
-                // ADDI
-                self.regs[rd] = self.regs[rs1].wrapping_add((immediate as i32 >> 20) as u32);
+                // Shift amounts take a sixth bit from funct7 on RV64
+                let shamt = (instruction >> 20) & (xlen - 1);
+                let funct = if rv64 { funct7 & !1 } else { funct7 };
+                let value = match (funct3, funct) {
+                    (0x0, _) => a.wrapping_add(imm_i),                  // ADDI
+                    (0x2, _) => (signed_a < self.signed(imm_i)) as u64, // SLTI
+                    (0x3, _) => (a < imm_i) as u64,                     // SLTIU
+                    (0x4, _) => a ^ imm_i,                              // XORI
+                    (0x6, _) => a | imm_i,                              // ORI
+                    (0x7, _) => a & imm_i,                              // ANDI
+                    (0x1, 0x00) => a << shamt,                          // SLLI
+                    (0x5, 0x00) => a >> shamt,                          // SRLI
+                    (0x5, 0x20) => (signed_a >> shamt) as u64,          // SRAI
+                    _ => return Err(illegal),
+                };
+                self.write(rd, value);
             }
             // REGULAR
             0b0110011 => {
+                let shamt = b as u32 & (xlen - 1);
+                let value = match (funct3, funct7) {
+                    (0x0, 0x00) => a.wrapping_add(b),            // ADD
+                    (0x0, 0x20) => a.wrapping_sub(b),            // SUB
+                    (0x1, 0x00) => a << shamt,                   // SLL
+                    (0x2, 0x00) => (signed_a < signed_b) as u64, // SLT
+                    (0x3, 0x00) => (a < b) as u64,               // SLTU
+                    (0x4, 0x00) => a ^ b,                        // XOR
+                    (0x5, 0x00) => a >> shamt,                   // SRL
+                    (0x5, 0x20) => (signed_a >> shamt) as u64,   // SRA
+                    (0x6, 0x00) => a | b,                        // OR
+                    (0x7, 0x00) => a & b,                        // AND
+                    (0x0, 0x01) => a.wrapping_mul(b),            // MUL
+                    (0x1, 0x01) => ((signed_a as i128 * signed_b as i128) >> xlen) as u64, // MULH
+                    (0x2, 0x01) => ((signed_a as i128 * b as i128) >> xlen) as u64, // MULHSU
+                    (0x3, 0x01) => ((a as u128 * b as u128) >> xlen) as u64, // MULHU
+                    // Division never traps: dividing by zero gives all ones (the remainder
+                    // the dividend) and the overflowing MIN / -1 gives MIN (remainder 0)
+                    (0x4, 0x01) if b == 0 => u64::MAX, // DIV
+                    (0x4, 0x01) => signed_a.wrapping_div(signed_b) as u64, // DIV
+                    (0x5, 0x01) => a.checked_div(b).unwrap_or(u64::MAX), // DIVU
+                    (0x6, 0x01) if b == 0 => a,        // REM
+                    (0x6, 0x01) => signed_a.wrapping_rem(signed_b) as u64, // REM
+                    (0x7, 0x01) => a.checked_rem(b).unwrap_or(a), // REMU
+                    _ => return Err(illegal),
+                };
+                self.write(rd, value);
+            }
+            // IMMEDIATE-32, RV64 only
+            0b0011011 if rv64 => {
+                let (a, shamt) = (a as u32, rs2 as u32);
+                let value = match (funct3, funct7) {
+                    (0x0, _) => a.wrapping_add(imm_i as u32),  // ADDIW
+                    (0x1, 0x00) => a << shamt,                 // SLLIW
+                    (0x5, 0x00) => a >> shamt,                 // SRLIW
+                    (0x5, 0x20) => (a as i32 >> shamt) as u32, // SRAIW
+                    _ => return Err(illegal),
+                };
+                self.write_word(rd, value);
+            }
+            // REGULAR-32, RV64 only
+            0b0111011 if rv64 => {
+                let (a, b) = (a as u32, b as u32);
+                let shamt = b & 0x1f;
+                let value = match (funct3, funct7) {
+                    (0x0, 0x00) => a.wrapping_add(b),                        // ADDW
+                    (0x0, 0x20) => a.wrapping_sub(b),                        // SUBW
+                    (0x1, 0x00) => a << shamt,                               // SLLW
+                    (0x5, 0x00) => a >> shamt,                               // SRLW
+                    (0x5, 0x20) => (a as i32 >> shamt) as u32,               // SRAW
+                    (0x0, 0x01) => a.wrapping_mul(b),                        // MULW
+                    (0x4, 0x01) if b == 0 => u32::MAX,                       // DIVW
+                    (0x4, 0x01) => (a as i32).wrapping_div(b as i32) as u32, // DIVW
+                    (0x5, 0x01) => a.checked_div(b).unwrap_or(u32::MAX),     // DIVUW
+                    (0x6, 0x01) if b == 0 => a,                              // REMW
+                    (0x6, 0x01) => (a as i32).wrapping_rem(b as i32) as u32, // REMW
+                    (0x7, 0x01) => a.checked_rem(b).unwrap_or(a),            // REMUW
+                    _ => return Err(illegal),
+                };
+                self.write_word(rd, value);
+            }
+            // LOADS
+            0b0000011 => {
+                let address = a.wrapping_add(imm_i) as u32;
+                let fault = Exception::LoadAccessFault { pc, address };
+                let value = match funct3 {
+                    0x0 => self.load_data(address, 1).ok_or(fault)? as i8 as u64, // LB
+                    0x1 => self.load_data(address, 2).ok_or(fault)? as i16 as u64, // LH
+                    0x2 => self.load_data(address, 4).ok_or(fault)? as i32 as u64, // LW
+                    0x3 if rv64 => self.load_double(address).ok_or(fault)?,       // LD
+                    0x4 => self.load_data(address, 1).ok_or(fault)? as u64,       // LBU
+                    0x5 => self.load_data(address, 2).ok_or(fault)? as u64,       // LHU
+                    0x6 if rv64 => self.load_data(address, 4).ok_or(fault)? as u64, // LWU
+                    _ => return Err(illegal),
+                };
+                self.write(rd, value);
+            }
+            // STORES
+            0b0100011 => {
+                let address = a.wrapping_add(imm_s) as u32;
+                let fault = Exception::StoreAccessFault { pc, address };
                 match funct3 {
-                    0x0 => {
-                        if funct7 == 0x20 {
-                            // SUB
-                            self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]);
-                        } else if funct7 == 0x0 {
-                            // ADD
-                            self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]);
+                    0x0 => self.store(address, 1, b as u32),      // SB
+                    0x1 => self.store(address, 2, b as u32),      // SH
+                    0x2 => self.store(address, 4, b as u32),      // SW
+                    0x3 if rv64 => self.store_double(address, b), // SD
+                    _ => return Err(illegal),
+                }
+                .ok_or(fault)?;
+            }
+            // BRANCHES
+            0b1100011 => {
+                let taken = match funct3 {
+                    0x0 => a == b,               // BEQ
+                    0x1 => a != b,               // BNE
+                    0x4 => signed_a < signed_b,  // BLT
+                    0x5 => signed_a >= signed_b, // BGE
+                    0x6 => a < b,                // BLTU
+                    0x7 => a >= b,               // BGEU
+                    _ => return Err(illegal),
+                };
+                if taken {
+                    self.pc = self.jump_target(pc, pc.wrapping_add(imm_b))?;
+                }
+            }
+            // JUMPS
+            0b1101111 => {
+                // JAL
+                let target = self.jump_target(pc, pc.wrapping_add(imm_j))?;
+                self.write(rd, self.pc as u64);
+                self.pc = target;
+            }
+            0b1100111 if funct3 == 0 => {
+                // JALR
+                let link = self.pc;
+                self.pc = self.jump_target(pc, a.wrapping_add(imm_i) as u32 & !1)?;
+                self.write(rd, link as u64);
+            }
+            // FENCE and FENCE.I, memory and the instructions fetched from it are always
+            // coherent for a single hart
+            0b0001111 if funct3 <= 1 => {}
+            // AMO
+            0b0101111 if funct3 == 2 => {
+                let funct5 = funct7 >> 2;
+                let (a, b) = (a as u32, b as u32);
+                // Misaligned atomics raise access faults, they can't be emulated by splitting
+                // them up like ordinary accesses could
+                let load_fault = Exception::LoadAccessFault { pc, address: a };
+                let store_fault = Exception::StoreAccessFault { pc, address: a };
+                match funct5 {
+                    0x02 if rs2 == 0 => {
+                        // LR.W
+                        if !a.is_multiple_of(4) {
+                            return Err(load_fault);
+                        }
+                        let value = self.load_data(a, 4).ok_or(load_fault)?;
+                        self.write_word(rd, value);
+                        self.reservation = Some(a);
+                    }
+                    0x03 => {
+                        // SC.W, the reservation is used up whether or not it succeeds
+                        if !a.is_multiple_of(4) {
+                            return Err(store_fault);
+                        }
+                        let reserved = self.reservation.take() == Some(a);
+                        if reserved {
+                            self.store(a, 4, b).ok_or(store_fault)?;
+                        }
+                        self.write(rd, !reserved as u64);
+                    }
+                    _ => {
+                        let operation: fn(u32, u32) -> u32 = match funct5 {
+                            0x01 => |_, b| b,                               // AMOSWAP.W
+                            0x00 => u32::wrapping_add,                      // AMOADD.W
+                            0x04 => |a, b| a ^ b,                           // AMOXOR.W
+                            0x0c => |a, b| a & b,                           // AMOAND.W
+                            0x08 => |a, b| a | b,                           // AMOOR.W
+                            0x10 => |a, b| (a as i32).min(b as i32) as u32, // AMOMIN.W
+                            0x14 => |a, b| (a as i32).max(b as i32) as u32, // AMOMAX.W
+                            0x18 => u32::min,                               // AMOMINU.W
+                            0x1c => u32::max,                               // AMOMAXU.W
+                            _ => return Err(illegal),
+                        };
+                        if !a.is_multiple_of(4) {
+                            return Err(store_fault);
                         }
-                        dbg!("Undefined");
+                        let old = self.load_data(a, 4).ok_or(store_fault)?;
+                        self.store(a, 4, operation(old, b)).ok_or(store_fault)?;
+                        self.write_word(rd, old);
+                    }
+                }
+            }
+            // LOAD-FP
+            0b0000111 if funct3 == 2 || funct3 == 3 => {
+                // FLW, FLD
+                let address = a.wrapping_add(imm_i) as u32;
+                let fault = Exception::LoadAccessFault { pc, address };
+                self.fregs[rd] = if funct3 == 2 {
+                    0xffff_ffff_0000_0000 | self.load_data(address, 4).ok_or(fault)? as u64
+                } else {
+                    self.load_double(address).ok_or(fault)?
+                };
+            }
+            // STORE-FP
+            0b0100111 if funct3 == 2 || funct3 == 3 => {
+                // FSW, FSD
+                let address = a.wrapping_add(imm_s) as u32;
+                let fault = Exception::StoreAccessFault { pc, address };
+                let value = self.fregs[rs2];
+                if funct3 == 3 {
+                    self.store_double(address, value)
+                } else {
+                    self.store(address, 4, value as u32)
+                }
+                .ok_or(fault)?;
+            }
+            // FMADD, FMSUB, FNMSUB, FNMADD
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => {
+                let mode = self.rounding_mode(funct3 as u32).ok_or(illegal)?;
+                let rs3 = (instruction >> 27) as usize;
+                let negate_product = opcode & 0b1000 != 0;
+                let negate_addend = opcode == 0b1000111 || opcode == 0b1001111;
+                match funct7 & 0b11 {
+                    0 => {
+                        let outcome =
+                            self.fused::<f32>(rs1, rs2, rs3, negate_product, negate_addend, mode);
+                        self.write_float(rd, outcome);
                     }
-                    0x4 => {
-                        // XOR
-                        self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
+                    1 => {
+                        let outcome =
+                            self.fused::<f64>(rs1, rs2, rs3, negate_product, negate_addend, mode);
+                        self.write_float(rd, outcome);
                     }
-                    0x6 => {
-                        // OR
-                        self.regs[rd] = self.regs[rs1] | self.regs[rs2];
+                    _ => return Err(illegal),
+                }
+            }
+            // OP-FP
+            0b1010011 => {
+                let funct5 = funct7 >> 2;
+                match (funct5, funct7 & 0b11) {
+                    (0x08, 0) if rs2 == 1 => {
+                        // FCVT.S.D
+                        let mode = self.rounding_mode(funct3 as u32).ok_or(illegal)?;
+                        let outcome = float::narrow(f64::unbox(self.fregs[rs1]), mode);
+                        self.write_float(rd, outcome);
                     }
-                    0x7 => {
-                        // AND
-                        self.regs[rd] = self.regs[rs1] & self.regs[rs2];
+                    (0x08, 1) if rs2 == 0 => {
+                        // FCVT.D.S
+                        let outcome = float::widen(f32::unbox(self.fregs[rs1]));
+                        self.write_float(rd, outcome);
                     }
-                    _ => {
-                        dbg!("Not implemented");
+                    (0x1c, 0) if rs2 == 0 && funct3 == 0 => {
+                        // FMV.X.W
+                        self.write_word(rd, self.fregs[rs1] as u32);
                     }
+                    (0x1e, 0) if rs2 == 0 && funct3 == 0 => {
+                        // FMV.W.X
+                        self.fregs[rd] = 0xffff_ffff_0000_0000 | a & 0xffff_ffff;
+                    }
+                    (_, 0) => self.execute_op_fp::<f32>(instruction, illegal)?,
+                    (_, 1) => self.execute_op_fp::<f64>(instruction, illegal)?,
+                    _ => return Err(illegal),
+                }
+            }
+            // CSRRW, CSRRS, CSRRC and their immediate forms, which take rs1 as a 5 bit value
+            0b1110011 if funct3 & 0b11 != 0 => {
+                let address = (instruction >> 20) as u16;
+                let source = if funct3 & 0b100 != 0 { rs1 as u64 } else { a };
+                // Only CSRRW always writes, setting or clearing no bits leaves the CSR alone
+                let writes = funct3 & 0b11 == 1 || rs1 != 0;
+                let old = self.read_csr(address).ok_or(illegal)?;
+                if writes {
+                    let value = match funct3 & 0b11 {
+                        1 => source,
+                        2 => old | source,
+                        _ => old & !source,
+                    };
+                    self.write_csr(address, self.wrap(value)).ok_or(illegal)?;
                 }
+                self.write(rd, old);
             }
+            // SYSTEM
+            0b1110011 => match instruction {
+                // ECALL
+                0x0000_0073 => return Err(Exception::EnvironmentCall { pc }),
+                // EBREAK
+                0x0010_0073 => return Err(Exception::Breakpoint { pc }),
+                // MRET
+                0x3020_0073 => {
+                    self.pc = self.jump_target(pc, self.csrs.mepc as u32)?;
+                    let csrs = &mut self.csrs;
+                    let enabled = csrs.mstatus & csr::MSTATUS_MPIE != 0;
+                    csrs.mstatus |= csr::MSTATUS_MPIE;
+                    csrs.mstatus &= !csr::MSTATUS_MIE;
+                    if enabled {
+                        csrs.mstatus |= csr::MSTATUS_MIE;
+                    }
+                }
+                // WFI, without any interrupt enabled nothing could wake it so it does nothing
+                0x1050_0073 => {
+                    self.waiting = self.csrs.mie != 0 && self.bus.interrupts() & self.csrs.mie == 0;
+                }
+                _ => {
+                    warn!(
+                        instruction = format_args!("{:#010x}", instruction),
+                        "SYSTEM instruction not implemented"
+                    );
+                    return Err(illegal);
+                }
+            },
+            _ => return Err(illegal),
+        }
+        Ok(())
+    }
+
+    /// Value of the CSR at `address`, `None` if it isn't implemented
+    pub fn read_csr(&self, address: u16) -> Option<u64> {
+        match address {
+            csr::FFLAGS | csr::FRM | csr::FCSR if !self.extensions.f => None,
+            csr::FFLAGS => Some((self.fcsr & 0x1f).into()),
+            csr::FRM => Some((self.fcsr >> 5 & 0b111).into()),
+            csr::FCSR => Some(self.fcsr.into()),
+            csr::MISA => Some(self.misa()),
+            csr::MHARTID => Some(0),
+            _ => self
+                .csrs
+                .read(address)
+                .or_else(|| self.tags.as_ref()?.read_csr(address)),
+        }
+    }
+
+    /// Writes a CSR like a CSR instruction would, `None` if it isn't implemented or is
+    /// read-only
+    pub fn write_csr(&mut self, address: u16, value: u64) -> Option<()> {
+        if csr::is_read_only(address) {
+            return None;
+        }
+        let value32 = value as u32;
+        match address {
+            csr::FFLAGS | csr::FRM | csr::FCSR if !self.extensions.f => return None,
+            csr::FFLAGS => self.fcsr = self.fcsr & !0x1f | value32 & 0x1f,
+            csr::FRM => self.fcsr = self.fcsr & 0x1f | (value32 & 0b111) << 5,
+            csr::FCSR => self.fcsr = value32 & 0xff,
+            // The extensions can't be turned off
+            csr::MISA => {}
             _ => {
-                dbg!("Not implemented");
+                return self
+                    .csrs
+                    .write(address, value)
+                    .or_else(|| self.tags.as_mut()?.write_csr(address, value));
+            }
+        }
+        Some(())
+    }
+
+    /// The base ISA and extensions in the format of misa
+    fn misa(&self) -> u64 {
+        let extension = |letter: u8, enabled: bool| u64::from(enabled) << (letter - b'A');
+        let Extensions { m, a, f, d, c } = self.extensions;
+        let extensions =
+            extension(b'M', m) | extension(b'A', a) | extension(b'F', f) | extension(b'D', d);
+        match self.base_isa {
+            BaseIsa::Rv32I => 1 << 30 | extensions | extension(b'I', true) | extension(b'C', c),
+            BaseIsa::Rv32E => 1 << 30 | extensions | extension(b'E', true) | extension(b'C', c),
+            BaseIsa::Rv64I => 2 << 62 | extensions | extension(b'I', true),
+        }
+    }
+
+    /// The OP-FP operations that exist in both precisions
+    fn execute_op_fp<T: Float>(
+        &mut self,
+        instruction: u32,
+        illegal: Exception,
+    ) -> Result<(), Exception> {
+        let rd = ((instruction >> 7) & 0x1f) as usize;
+        let funct3 = (instruction >> 12) & 0x7;
+        let rs1 = ((instruction >> 15) & 0x1f) as usize;
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let funct5 = instruction >> 27;
+        let a = T::unbox(self.fregs[rs1]);
+        let b = T::unbox(self.fregs[rs2]);
+        let mode = self.rounding_mode(funct3);
+        match (funct5, funct3) {
+            (0x00, _) => self.write_float(rd, float::add(a, b, mode.ok_or(illegal)?)),
+            (0x01, _) => self.write_float(rd, float::sub(a, b, mode.ok_or(illegal)?)),
+            (0x02, _) => self.write_float(rd, float::mul(a, b, mode.ok_or(illegal)?)),
+            (0x03, _) => self.write_float(rd, float::div(a, b, mode.ok_or(illegal)?)),
+            (0x0b, _) if rs2 == 0 => self.write_float(rd, float::sqrt(a, mode.ok_or(illegal)?)),
+            // FSGNJ, FSGNJN, FSGNJX
+            (0x04, 0) => self.write_float(rd, (a.copysign(b), 0)),
+            (0x04, 1) => self.write_float(rd, (a.copysign(-b), 0)),
+            (0x04, 2) => {
+                let value = if b.is_sign_negative() { -a } else { a };
+                self.write_float(rd, (value, 0));
+            }
+            // FMIN, FMAX
+            (0x05, 0 | 1) => self.write_float(rd, float::min_max(a, b, funct3 == 1)),
+            // FLE, FLT, FEQ
+            (0x14, 0) => {
+                let ordering = [Ordering::Less, Ordering::Equal];
+                self.write_integer(rd, float::compare(a, b, &ordering, true));
+            }
+            (0x14, 1) => {
+                self.write_integer(rd, float::compare(a, b, &[Ordering::Less], true));
+            }
+            (0x14, 2) => {
+                self.write_integer(rd, float::compare(a, b, &[Ordering::Equal], false));
+            }
+            // FCVT.W, FCVT.WU
+            (0x18, _) if rs2 <= 1 => {
+                let (value, flags) = float::to_integer(a, mode.ok_or(illegal)?, rs2 == 0);
+                self.write_integer(rd, (value, flags));
+            }
+            // FCVT.S.W, FCVT.S.WU (FCVT.D.*)
+            (0x1a, _) if rs2 <= 1 => {
+                let source = self.regs[rs1] as u32;
+                let value = if rs2 == 0 {
+                    source as i32 as f64
+                } else {
+                    source as f64
+                };
+                self.write_float(rd, T::round_from_f64(value, mode.ok_or(illegal)?));
+            }
+            // FCLASS
+            (0x1c, 1) if rs2 == 0 => self.write_word(rd, float::classify(a)),
+            _ => return Err(illegal),
+        }
+        Ok(())
+    }
+
+    /// `±(rs1 * rs2) ± rs3` in the precision `T`
+    fn fused<T: Float>(
+        &self,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        negate_product: bool,
+        negate_addend: bool,
+        mode: RoundingMode,
+    ) -> Outcome<T> {
+        let a = T::unbox(self.fregs[rs1]);
+        let b = T::unbox(self.fregs[rs2]);
+        let c = T::unbox(self.fregs[rs3]);
+        let a = if negate_product { -a } else { a };
+        let c = if negate_addend { -c } else { c };
+        float::fused_multiply_add(a, b, c, mode)
+    }
+
+    /// Resolves the `rm` field, `None` if it (or for dynamic rounding fcsr's frm) holds a
+    /// reserved mode
+    fn rounding_mode(&self, rm: u32) -> Option<RoundingMode> {
+        let rm = if rm == 0b111 {
+            (self.fcsr >> 5) & 0b111
+        } else {
+            rm
+        };
+        RoundingMode::from_bits(rm)
+    }
+
+    fn write_float<T: Float>(&mut self, rd: usize, (value, flags): Outcome<T>) {
+        self.fregs[rd] = value.boxed();
+        self.fcsr |= flags;
+    }
+
+    fn write_integer<T: Into<u32>>(&mut self, rd: usize, (value, flags): Outcome<T>) {
+        self.write_word(rd, value.into());
+        self.fcsr |= flags;
+    }
+}
+
+/// Whether any register field the instruction's format uses names x16-x31
+fn uses_upper_registers(instruction: u32) -> bool {
+    let opcode = instruction & 0x7f;
+    let rd = (instruction >> 7) & 0x1f;
+    let rs1 = (instruction >> 15) & 0x1f;
+    let rs2 = (instruction >> 20) & 0x1f;
+    let fields: &[u32] = match opcode {
+        // LUI, AUIPC, JAL
+        0b0110111 | 0b0010111 | 0b1101111 => &[rd],
+        // JALR, loads, OP-IMM
+        0b1100111 | 0b0000011 | 0b0010011 => &[rd, rs1],
+        // Branches, stores
+        0b1100011 | 0b0100011 => &[rs1, rs2],
+        // OP, AMO
+        0b0110011 | 0b0101111 => &[rd, rs1, rs2],
+        // SYSTEM: CSR instructions use rd and rs1 (a value in the immediate forms), ECALL and
+        // EBREAK have them zeroed
+        0b1110011 if instruction & 0x4000 != 0 => &[rd],
+        0b1110011 => &[rd, rs1],
+        // Floating point loads and stores address through rs1
+        0b0000111 | 0b0100111 => &[rs1],
+        // OP-FP: comparisons, conversions to integers, FCLASS and FMV.X.W write an x register,
+        // conversions from integers and FMV.W.X read one
+        0b1010011 => match instruction >> 27 {
+            0x14 | 0x18 | 0x1c => &[rd],
+            0x1a | 0x1e => &[rs1],
+            _ => &[],
+        },
+        _ => &[],
+    };
+    fields.iter().any(|&register| register >= 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::xorshift32;
+
+    fn cpu_with(words: &[u32]) -> Cpu {
+        Cpu::new_with_instructions(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    #[test]
+    fn test_rv32e_rejects_upper_registers() {
+        // addi a5, zero, 1; addi a6, zero, 1
+        let mut cpu = cpu_with(&[0x00100793, 0x00100813]);
+        cpu.base_isa = BaseIsa::Rv32E;
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.regs[15], 1);
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::IllegalInstruction {
+                pc: 4,
+                instruction: 0x00100813
+            })
+        );
+        assert_eq!(cpu.regs[16], 0);
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_fetch_outside_memory_faults() {
+        // The upper half starts a 32 bit instruction that runs past the end of memory
+        let mut cpu = cpu_with(&[0x00130013]);
+        cpu.pc = 2;
+        assert_eq!(cpu.step(), Err(Exception::InstructionAccessFault { pc: 2 }));
+        cpu.pc = u32::MAX - 1;
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::InstructionAccessFault { pc: u32::MAX - 1 })
+        );
+    }
+
+    #[test]
+    fn test_random_instructions_never_panic() {
+        let mut next = xorshift32(0x2545_f491);
+        for _ in 0..64 {
+            let words: Vec<u32> = (0..64).map(|_| next()).collect();
+            let mut cpu = cpu_with(&words);
+            cpu.pc = next() % 320;
+            for _ in 0..128 {
+                if cpu.step().is_err() {
+                    break;
+                }
             }
         }
     }
+
+    #[test]
+    fn test_jal_and_jalr() {
+        // jal ra, 8; nop; jalr zero, 0(ra)
+        let mut cpu = cpu_with(&[0x008000ef, 0x00000013, 0x00008067]);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.regs[1]), (8, 4));
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 4);
+        // jal zero, -4 never writes x0
+        let mut cpu = cpu_with(&[0x00000013, 0xffdff06f]);
+        cpu.pc = 4;
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.regs[0]), (0, 0));
+    }
+
+    fn run(cpu: &mut Cpu, steps: usize) {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        let words: [u32; 8] = [
+            0x02000293, // addi t0, zero, 32
+            0xffe00313, // addi t1, zero, -2
+            0x0062a023, // sw t1, 0(t0)
+            0x00028383, // lb t2, 0(t0)
+            0x0022de03, // lhu t3, 2(t0)
+            0x0012ce83, // lbu t4, 1(t0)
+            0x00602023, // sw t1, 0(zero)
+            0x0402a383, // lw t2, 64(t0)
+        ];
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = Cpu::new_with_program(program.into(), 64);
+        run(&mut cpu, 6);
+        assert_eq!(cpu.bus.dram[32..36], [0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(cpu.regs[7], -2i32 as u32 as u64);
+        assert_eq!(cpu.regs[28], 0xffff);
+        assert_eq!(cpu.regs[29], 0xff);
+        // The program image is read-only
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::StoreAccessFault { pc: 24, address: 0 })
+        );
+        assert_eq!(cpu.pc, 24);
+        cpu.pc = 28;
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::LoadAccessFault {
+                pc: 28,
+                address: 96
+            })
+        );
+        assert_eq!(cpu.regs[7], -2i32 as u32 as u64);
+    }
+
+    #[test]
+    fn test_shifts_compares_and_branches() {
+        let mut cpu = cpu_with(&[
+            0xff800293, // addi t0, zero, -8
+            0x4012d313, // srai t1, t0, 1
+            0x01c2d393, // srli t2, t0, 28
+            0x0002ae33, // slt t3, t0, zero
+            0x0002beb3, // sltu t4, t0, zero
+            0x0002c463, // blt t0, zero, 8
+            0x00100513, // addi a0, zero, 1
+            0xfe507ee3, // bgeu zero, t0, -4
+            0x00001597, // auipc a1, 1
+        ]);
+        run(&mut cpu, 6);
+        assert_eq!(cpu.regs[6], -4i32 as u32 as u64);
+        assert_eq!(cpu.regs[7], 0xf);
+        assert_eq!((cpu.regs[28], cpu.regs[29]), (1, 0));
+        // The branch skipped the addi, the bgeu falls through
+        assert_eq!((cpu.pc, cpu.regs[10]), (28, 0));
+        run(&mut cpu, 2);
+        assert_eq!(cpu.regs[11], 0x1020);
+    }
+
+    #[test]
+    fn test_compressed_instructions() {
+        let program: Vec<u8> = [
+            &0x4515u16.to_le_bytes()[..], // 0x0 c.li a0, 5
+            &0x0505u16.to_le_bytes(),     // 0x2 c.addi a0, 1
+            &0x00150593u32.to_le_bytes(), // 0x4 addi a1, a0, 1
+            &0x2011u16.to_le_bytes(),     // 0x8 c.jal 0xc
+            &0x0000u16.to_le_bytes(),     // 0xa illegal
+            &0x952eu16.to_le_bytes(),     // 0xc c.add a0, a1
+            &0x8082u16.to_le_bytes(),     // 0xe c.jr ra
+        ]
+        .concat();
+        let mut cpu = Cpu::new_with_instructions(program);
+        run(&mut cpu, 2);
+        assert_eq!((cpu.pc, cpu.regs[10]), (4, 6));
+        run(&mut cpu, 2);
+        assert_eq!((cpu.pc, cpu.regs[11], cpu.regs[1]), (0xc, 7, 0xa));
+        run(&mut cpu, 2);
+        assert_eq!((cpu.pc, cpu.regs[10]), (0xa, 13));
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::IllegalInstruction {
+                pc: 0xa,
+                instruction: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let mut cpu = cpu_with(&[
+            0x10000293, // addi t0, zero, 0x100
+            0x30529573, // csrrw a0, mtvec, t0
+            0x305025f3, // csrrs a1, mtvec, zero
+            0x30046073, // csrrsi zero, mstatus, 8
+            0x30047673, // csrrci a2, mstatus, 8
+            0x0020d073, // csrrwi zero, frm, 1
+            0x003026f3, // csrrs a3, fcsr, zero
+            0x30102773, // csrrs a4, misa, zero
+            0xf1429073, // csrrw zero, mhartid, t0
+            0x7c0027f3, // csrrs a5, 0x7c0, zero
+            0xf14027f3, // csrrs a5, mhartid, zero
+        ]);
+        run(&mut cpu, 8);
+        assert_eq!((cpu.regs[10], cpu.regs[11]), (0, 0x100));
+        assert_eq!(cpu.csrs.mtvec, 0x100);
+        // MPP always reads as machine mode
+        assert_eq!(cpu.regs[12], 0x1808);
+        assert_eq!(cpu.csrs.mstatus, 0);
+        assert_eq!((cpu.regs[13], cpu.fcsr), (0x20, 0x20));
+        // RV32 with I, M, A, F, D and C
+        assert_eq!(cpu.regs[14], 0x4000_112d);
+
+        // Writing a read-only CSR or touching an unknown one is illegal
+        assert!(matches!(
+            cpu.step(),
+            Err(Exception::IllegalInstruction { pc: 32, .. })
+        ));
+        cpu.pc += 4;
+        assert!(cpu.step().is_err());
+        cpu.pc += 4;
+        cpu.regs[15] = 1;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs[15], 0);
+
+        // The immediate of csrrwi is a value, x16 in its rs1 field is fine on RV32E
+        let mut cpu = cpu_with(&[0x3408d073]); // csrrwi zero, mscratch, 17
+        cpu.base_isa = BaseIsa::Rv32E;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.csrs.mscratch, 17);
+    }
+
+    #[test]
+    fn test_trap_entry_and_mret() {
+        // jalr zero, 2(zero) is fine with the C extension, misaligned without it
+        let mut cpu = cpu_with(&[0x00200067]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 2);
+        let mut cpu = cpu_with(&[0x00200067]);
+        cpu.base_isa = BaseIsa::Rv64I;
+        let exception = cpu.step().unwrap_err();
+        assert_eq!(
+            exception,
+            Exception::InstructionAddressMisaligned { pc: 0, target: 2 }
+        );
+
+        // Without a handler the exception is left alone
+        assert!(!cpu.take_trap(exception));
+        cpu.csrs.mtvec = 0x100;
+        cpu.csrs.mstatus = csr::MSTATUS_MIE;
+        assert!(cpu.take_trap(exception));
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!((cpu.csrs.mepc, cpu.csrs.mcause, cpu.csrs.mtval), (0, 0, 2));
+        assert_eq!(cpu.csrs.mstatus, csr::MSTATUS_MPIE);
+
+        // Vectored mode only spreads out interrupts, machine timer interrupt is cause 7
+        cpu.csrs.mtvec = 0x101;
+        cpu.enter_trap(1 << 63 | 7, 0, 0x40);
+        assert_eq!(cpu.pc, 0x11c);
+        cpu.enter_trap(2, 0, 0x40);
+        assert_eq!(cpu.pc, 0x100);
+
+        // mret returns to mepc and turns interrupts back on
+        let mut cpu = cpu_with(&[0x30200073]);
+        cpu.csrs.mepc = 0x40;
+        cpu.csrs.mstatus = csr::MSTATUS_MPIE;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.csrs.mstatus, csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+    }
+
+    #[test]
+    fn test_rv64() {
+        let words: [u32; 14] = [
+            0xfff00513, // addi a0, zero, -1
+            0x02055593, // srli a1, a0, 32
+            0x0015861b, // addiw a2, a1, 1
+            0x000586bb, // addw a3, a1, zero
+            0x00b13023, // sd a1, 0(sp)
+            0x00013703, // ld a4, 0(sp)
+            0x00012783, // lw a5, 0(sp)
+            0x00016283, // lwu t0, 0(sp)
+            0x03f51313, // slli t1, a0, 63
+            0x43f35393, // srai t2, t1, 63
+            0x02b584bb, // mulw s1, a1, a1
+            0x80000437, // lui s0, 0x80000
+            0x02a538b3, // mulhu a7, a0, a0
+            0x00032e33, // slt t3, t1, zero
+        ];
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = Cpu::new_with_program(program.clone().into(), 256);
+        cpu.base_isa = BaseIsa::Rv64I;
+        cpu.regs[2] = 128;
+        run(&mut cpu, words.len());
+        assert_eq!(
+            cpu.regs[10..15],
+            [u64::MAX, 0xffff_ffff, 0, u64::MAX, 0xffff_ffff]
+        );
+        assert_eq!(cpu.bus.dram[128..136], 0xffff_ffffu64.to_le_bytes());
+        // lw sign-extends, lwu doesn't
+        assert_eq!((cpu.regs[15], cpu.regs[5]), (u64::MAX, 0xffff_ffff));
+        assert_eq!((cpu.regs[6], cpu.regs[7]), (1 << 63, u64::MAX));
+        assert_eq!((cpu.regs[9], cpu.regs[8]), (1, 0xffff_ffff_8000_0000));
+        assert_eq!(cpu.regs[17], u64::MAX - 1);
+        assert_eq!(cpu.regs[28], 1);
+
+        // The same program on RV32: registers stay 32 bits and shifts by 32 or more are
+        // illegal, like the RV64 instructions
+        let mut cpu = Cpu::new_with_program(program.into(), 256);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs[10], 0xffff_ffff);
+        assert!(matches!(
+            cpu.step(),
+            Err(Exception::IllegalInstruction { pc: 4, .. })
+        ));
+        cpu.pc = 8;
+        assert!(cpu.step().is_err());
+
+        // No compressed instructions on RV64
+        let mut cpu = Cpu::new_with_instructions(vec![0x05, 0x05]); // c.addi a0, 1
+        cpu.base_isa = BaseIsa::Rv64I;
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn test_floating_point() {
+        let words: [u32; 19] = [
+            0x00100513, // addi a0, zero, 1
+            0x00300593, // addi a1, zero, 3
+            0x08000293, // addi t0, zero, 128
+            0xd0057553, // fcvt.s.w fa0, a0
+            0xd005f5d3, // fcvt.s.w fa1, a1
+            0x18b57653, // fdiv.s fa2, fa0, fa1
+            0x18b516d3, // fdiv.s fa3, fa0, fa1, rtz
+            0xe0060653, // fmv.x.w a2, fa2
+            0xe00686d3, // fmv.x.w a3, fa3
+            0x42060753, // fcvt.d.s fa4, fa2
+            0x72e777c3, // fmadd.d fa5, fa4, fa4, fa4
+            0x00f2b027, // fsd fa5, 0(t0)
+            0x0002b807, // fld fa6, 0(t0)
+            0xa307a753, // feq.d a4, fa5, fa6
+            0xe00517d3, // fclass.s a5, fa0
+            0xc0063853, // fcvt.w.s a6, fa2, rup
+            0x20a518d3, // fsgnjn.s fa7, fa0, fa0
+            0xa0a898d3, // flt.s a7, fa7, fa0
+            0x18b57653, // fdiv.s fa2, fa0, fa1
+        ];
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = Cpu::new_with_program(program.into(), 256);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.fregs[10], 0xffff_ffff_3f80_0000);
+        assert_eq!(cpu.fcsr, 0);
+        // The dynamic rounding mode in fcsr is round to nearest, rtz is static
+        run(&mut cpu, 4);
+        assert_eq!((cpu.regs[12], cpu.regs[13]), (0x3eaa_aaab, 0x3eaa_aaaa));
+        assert_eq!(cpu.fcsr, float::FLAG_INEXACT);
+        run(&mut cpu, 6);
+        let third = (1.0f32 / 3.0) as f64;
+        assert_eq!(f64::from_bits(cpu.fregs[15]), third.mul_add(third, third));
+        assert_eq!(cpu.bus.dram[128..136], cpu.fregs[15].to_le_bytes());
+        assert_eq!(cpu.fregs[16], cpu.fregs[15]);
+        assert_eq!(cpu.regs[14], 1);
+        // Positive normal number
+        assert_eq!(cpu.regs[15], 1 << 6);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs[16], 1);
+        assert_eq!(cpu.fregs[17], 0xffff_ffff_bf80_0000);
+        assert_eq!(cpu.regs[17], 1);
+        // A reserved dynamic rounding mode makes rounding instructions illegal
+        cpu.fcsr = 5 << 5;
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::IllegalInstruction {
+                pc: 72,
+                instruction: 0x18b57653
+            })
+        );
+        cpu.fcsr = 1 << 5;
+        cpu.step().unwrap();
+        assert_eq!(cpu.fregs[12], 0xffff_ffff_3eaa_aaaa);
+        assert_eq!(cpu.fcsr, 1 << 5 | float::FLAG_INEXACT);
+    }
+
+    #[test]
+    fn test_atomics() {
+        let words: [u32; 17] = [
+            0x08000293, // addi t0, zero, 128
+            0x00500313, // addi t1, zero, 5
+            0x0062a023, // sw t1, 0(t0)
+            0x1002a52f, // lr.w a0, (t0)
+            0x00150593, // addi a1, a0, 1
+            0x18b2a62f, // sc.w a2, a1, (t0)
+            0x1002a52f, // lr.w a0, (t0)
+            0x0002a023, // sw zero, 0(t0)
+            0x18b2a6af, // sc.w a3, a1, (t0)
+            0x18b2a72f, // sc.w a4, a1, (t0)
+            0xffd00313, // addi t1, zero, -3
+            0x0062a7af, // amoadd.w a5, t1, (t0)
+            0x00700313, // addi t1, zero, 7
+            0xc062a82f, // amominu.w a6, t1, (t0)
+            0x0e62a42f, // amoswap.w.aqrl s0, t1, (t0)
+            0x00228393, // addi t2, t0, 2
+            0x1003a52f, // lr.w a0, (t2)
+        ];
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = Cpu::new_with_program(program.into(), 256);
+        run(&mut cpu, 6);
+        assert_eq!((cpu.regs[10], cpu.regs[12]), (5, 0));
+        assert_eq!(cpu.bus.dram[128..132], 6u32.to_le_bytes());
+        assert_eq!(cpu.reservation(), None);
+        // The store between lr.w and sc.w breaks the reservation, a second sc.w has none
+        run(&mut cpu, 4);
+        assert_eq!((cpu.regs[13], cpu.regs[14]), (1, 1));
+        assert_eq!(cpu.bus.dram[128..132], [0; 4]);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.regs[15], 0);
+        // min(-3, 7) unsigned
+        assert_eq!(cpu.regs[16], -3i32 as u32 as u64);
+        assert_eq!(cpu.regs[8], 7);
+        assert_eq!(cpu.bus.dram[128..132], 7u32.to_le_bytes());
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::LoadAccessFault {
+                pc: 64,
+                address: 130
+            })
+        );
+        // Writes from outside the hart break reservations too
+        cpu.pc = 12;
+        cpu.step().unwrap();
+        assert_eq!(cpu.reservation(), Some(128));
+        cpu.touch_memory(131, 2);
+        assert_eq!(cpu.reservation(), None);
+    }
+
+    #[test]
+    fn test_multiply_and_divide() {
+        // mul, mulh, mulhsu, mulhu, div, divu, rem, remu with rd = a2, rs1 = a0, rs2 = a1
+        let ops = |a: u32, b: u32| {
+            let words: Vec<u32> = (0..8).map(|funct3| 0x02b50633 | funct3 << 12).collect();
+            let mut cpu = cpu_with(&words);
+            cpu.regs[10] = a.into();
+            cpu.regs[11] = b.into();
+            (0..8)
+                .map(|_| {
+                    cpu.step().unwrap();
+                    cpu.regs[12] as u32
+                })
+                .collect::<Vec<_>>()
+        };
+        let (minus_one, min) = (u32::MAX, i32::MIN as u32);
+        assert_eq!(
+            ops(-7i32 as u32, 2),
+            [
+                -14i32 as u32,
+                minus_one,
+                minus_one,
+                1,
+                -3i32 as u32,
+                0x7fff_fffc,
+                minus_one,
+                1
+            ]
+        );
+        assert_eq!(ops(7, 0), [0, 0, 0, 0, minus_one, minus_one, 7, 7]);
+        assert_eq!(
+            ops(min, minus_one),
+            [min, 0, min, 0x7fff_ffff, min, 0, 0, min]
+        );
+    }
+
+    #[test]
+    fn test_system_instructions() {
+        // ecall; ebreak; addi zero, zero, 5
+        let mut cpu = cpu_with(&[0x00000073, 0x00100073, 0x00500013]);
+        assert_eq!(cpu.step(), Err(Exception::EnvironmentCall { pc: 0 }));
+        cpu.pc = 4;
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint { pc: 4 }));
+        cpu.pc = 8;
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs[0], 0);
+    }
+
+    #[test]
+    fn test_rv32i_allows_upper_registers() {
+        let mut cpu = cpu_with(&[0x00100813]);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.regs[16], 1);
+    }
+
+    #[test]
+    fn test_disabled_extensions() {
+        let words = [
+            0x00600513, // addi a0, zero, 6
+            0x02a505b3, // mul a1, a0, a0
+            0x00a1262f, // amoadd.w a2, a0, (sp)
+            0xd0057053, // fcvt.s.w ft0, a0
+            0xd20500d3, // fcvt.d.w ft1, a0
+            0x003026f3, // csrrs a3, fcsr, zero
+            0x00200067, // jalr zero, 2(zero)
+            0x00010001, // c.nop; c.nop
+        ];
+        let mut cpu = cpu_with(&words);
+        cpu.extensions = Extensions::none();
+        assert_eq!(cpu.step(), Ok(()));
+        for (index, &instruction) in words.iter().enumerate().skip(1) {
+            let pc = 4 * index as u32;
+            cpu.pc = pc;
+            let expected = match index {
+                6 => Exception::InstructionAddressMisaligned { pc, target: 2 },
+                7 => Exception::IllegalInstruction {
+                    pc,
+                    instruction: 0x0001,
+                },
+                _ => Exception::IllegalInstruction { pc, instruction },
+            };
+            assert_eq!(cpu.step(), Err(expected), "{:#010x}", instruction);
+        }
+        // misa lists the base ISA alone
+        assert_eq!(cpu.read_csr(csr::MISA), Some(1 << 30 | 1 << 8));
+
+        // Only D is missing
+        let mut cpu = cpu_with(&words[3..5]);
+        cpu.extensions.d = false;
+        assert_eq!(cpu.step(), Ok(()));
+        assert!(cpu.step().is_err());
+    }
 }
@@ -0,0 +1,142 @@
+//! Control and status registers of machine mode, the only privilege level the emulator has.
+//!
+//! Every register here is WARL (write any, read legal): writes keep only the bits the
+//! emulator implements, the rest read as fixed values. The floating point CSRs and the
+//! constant ones (misa, mhartid) are served by [`Cpu`](crate::cpu::Cpu) itself.
+
+use crate::tags;
+
+/// Accrued floating point exception flags, bits 0-4 of fcsr
+pub const FFLAGS: u16 = 0x001;
+/// Dynamic rounding mode, bits 5-7 of fcsr
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MHARTID: u16 = 0xf14;
+
+/// Global machine interrupt enable in mstatus
+pub const MSTATUS_MIE: u64 = 1 << 3;
+/// mstatus.MIE from before the current trap
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+/// Privilege level before the current trap, always machine mode
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+
+/// Machine software, timer and external interrupt bits of mie and mip
+pub const MSI: u64 = 1 << 3;
+pub const MTI: u64 = 1 << 7;
+pub const MEI: u64 = 1 << 11;
+
+/// Registers whose top two address bits are set can only be read
+pub fn is_read_only(address: u16) -> bool {
+    address >> 10 == 0b11
+}
+
+/// Name of a CSR the emulator implements
+pub fn name(address: u16) -> Option<&'static str> {
+    Some(match address {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MHARTID => "mhartid",
+        // Custom, with tagged memory only
+        tags::MTAGCTL => "mtagctl",
+        tags::MTAGADDR => "mtagaddr",
+        tags::MTAG => "mtag",
+        _ => return None,
+    })
+}
+
+/// The machine-mode trap setup and handling registers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Csrs {
+    pub mstatus: u64,
+    pub mie: u64,
+    /// Pending interrupts, driven by devices and read-only to the program
+    pub mip: u64,
+    pub mtvec: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+}
+
+impl Csrs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of the register at `address`, `None` unless it's one of these
+    pub fn read(&self, address: u16) -> Option<u64> {
+        Some(match address {
+            MSTATUS => self.mstatus | MSTATUS_MPP,
+            MIE => self.mie,
+            MIP => self.mip,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            _ => return None,
+        })
+    }
+
+    /// Writes the legal bits of `value`, `None` unless the register is one of these
+    pub fn write(&mut self, address: u16, value: u64) -> Option<()> {
+        match address {
+            MSTATUS => self.mstatus = value & (MSTATUS_MIE | MSTATUS_MPIE),
+            MIE => self.mie = value & (MSI | MTI | MEI),
+            MIP => {}
+            // Direct and vectored mode, the reserved modes fold onto them
+            MTVEC => self.mtvec = value & !0b10,
+            MSCRATCH => self.mscratch = value,
+            // Instructions are at least 2 byte aligned
+            MEPC => self.mepc = value & !1,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_keep_legal_bits() {
+        let mut csrs = Csrs::new();
+        csrs.write(MSTATUS, u64::MAX).unwrap();
+        assert_eq!(
+            csrs.read(MSTATUS),
+            Some(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)
+        );
+        csrs.write(MTVEC, 0x1003).unwrap();
+        assert_eq!(csrs.read(MTVEC), Some(0x1001));
+        csrs.write(MEPC, 0x203).unwrap();
+        assert_eq!(csrs.read(MEPC), Some(0x202));
+        csrs.write(MIP, MTI).unwrap();
+        assert_eq!(csrs.read(MIP), Some(0));
+        assert_eq!(csrs.write(0x7c0, 1), None);
+        assert!(is_read_only(MHARTID));
+        assert!(!is_read_only(MSCRATCH));
+        assert_eq!(name(MEPC), Some("mepc"));
+    }
+}
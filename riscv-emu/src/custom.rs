@@ -0,0 +1,135 @@
+//! Instructions in the opcode space the ISA reserves for custom extensions, carried out by
+//! handlers registered with [`Machine::add_custom_instructions`].
+//!
+//! A course or research project defines its experimental instructions in the custom-0 and
+//! custom-1 major opcodes, assembles them with an assembler plugin and executes them here
+//! without forking the emulator. Without a handler taking it, such an instruction is
+//! illegal as before.
+
+use crate::{cpu::Exception, machine::Machine};
+
+/// Major opcode of custom-0
+pub const CUSTOM_0: u32 = 0b0001011;
+/// Major opcode of custom-1
+pub const CUSTOM_1: u32 = 0b0101011;
+
+/// A 32 bit instruction word of a custom opcode, split into the R-type fields most custom
+/// instructions use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomInstruction {
+    pub word: u32,
+    /// [`CUSTOM_0`] or [`CUSTOM_1`]
+    pub opcode: u32,
+    pub rd: usize,
+    pub funct3: u32,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct7: u32,
+}
+
+impl CustomInstruction {
+    /// Splits `word` into its fields, `None` if it isn't in a custom opcode space
+    pub fn decode(word: u32) -> Option<Self> {
+        let opcode = word & 0x7f;
+        if opcode != CUSTOM_0 && opcode != CUSTOM_1 {
+            return None;
+        }
+        Some(Self {
+            word,
+            opcode,
+            rd: (word >> 7 & 0x1f) as usize,
+            funct3: word >> 12 & 0x7,
+            rs1: (word >> 15 & 0x1f) as usize,
+            rs2: (word >> 20 & 0x1f) as usize,
+            funct7: word >> 25,
+        })
+    }
+
+    /// The sign extended immediate of an I-type encoding, for instructions using one
+    pub fn imm_i(&self) -> i32 {
+        self.word as i32 >> 20
+    }
+}
+
+/// Executes custom instructions, see [`Machine::add_custom_instructions`]
+pub trait CustomInstructions: Send {
+    /// Whether the handler executes `instruction`, others are left to the next handler
+    fn handles(&self, instruction: &CustomInstruction) -> bool;
+
+    /// Executes `instruction`, reading and writing the machine's registers and memory. The
+    /// pc already points past it, a handler can move it to jump. `Err` traps instead, with
+    /// the pc back on the instruction.
+    fn execute(
+        &mut self,
+        instruction: &CustomInstruction,
+        machine: &mut Machine,
+    ) -> Result<(), Exception>;
+
+    /// Forgets state earlier instructions left behind, for [`Machine::reset`]
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{ExitReason, RunLimits};
+
+    /// `mac rd, rs1, rs2` (custom-0, funct3 0): rd += rs1 * rs2
+    struct Mac {
+        executed: u32,
+    }
+
+    impl CustomInstructions for Mac {
+        fn handles(&self, instruction: &CustomInstruction) -> bool {
+            instruction.opcode == CUSTOM_0 && instruction.funct3 == 0
+        }
+
+        fn execute(
+            &mut self,
+            instruction: &CustomInstruction,
+            machine: &mut Machine,
+        ) -> Result<(), Exception> {
+            let regs = &machine.cpu.regs;
+            let product = regs[instruction.rs1].wrapping_mul(regs[instruction.rs2]);
+            let value = regs[instruction.rd].wrapping_add(product);
+            machine.cpu.set_register(instruction.rd, value);
+            self.executed += 1;
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            self.executed = 0;
+        }
+    }
+
+    fn program(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_custom_instructions_execute() {
+        // li a0, 2; li a1, 3; li a2, 4; mac a0, a1, a2; ebreak
+        let words = [0x00200513, 0x00300593, 0x00400613, 0x00c5850b, 0x00100073];
+        let mut machine = Machine::new(program(&words), 0);
+        machine.add_custom_instructions(Mac { executed: 0 });
+        let outcome = machine.run(&RunLimits::default());
+        assert!(matches!(
+            outcome.exit_reason,
+            ExitReason::Exception(Exception::Breakpoint { pc: 16 })
+        ));
+        assert_eq!(machine.cpu.regs[10], 14);
+        assert_eq!(machine.instructions_retired(), 4);
+
+        // Other funct3 values and custom-1 stay illegal
+        for word in [0x00c5950b, 0x00c5852b] {
+            let mut machine = Machine::new(program(&[word]), 0);
+            machine.add_custom_instructions(Mac { executed: 0 });
+            assert!(matches!(
+                machine.step(),
+                Err(Exception::IllegalInstruction { pc: 0, .. })
+            ));
+        }
+        assert_eq!(CustomInstruction::decode(0x00000013), None);
+        assert_eq!(CustomInstruction::decode(0xfff5850b).unwrap().imm_i(), -1);
+    }
+}
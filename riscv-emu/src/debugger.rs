@@ -0,0 +1,509 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    error::EmuError,
+    line_map::LineMap,
+    machine::{ExitReason, Machine, RunLimits},
+};
+
+/// Link registers, calls write the return address to one of them (ra or t0)
+const LINK_REGISTERS: [u32; 2] = [1, 5];
+
+/// A call the program hasn't returned from yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the call instruction
+    pub call_site: u32,
+    /// Address of the called function
+    pub function: u32,
+    /// Where execution continues once the function returns
+    pub return_address: u32,
+    /// Stack pointer at the call
+    pub stack_pointer: u32,
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested step, step-over or finish completed
+    Done,
+    Breakpoint {
+        pc: u32,
+    },
+    /// The 32 bit word at a watched address changed
+    Watchpoint {
+        address: u32,
+        old: u32,
+        new: u32,
+    },
+    /// The program stopped running or was interrupted, see [`ExitReason`]
+    Exited(ExitReason),
+}
+
+/// What a single instruction does to the call stack
+enum ControlFlow {
+    Call,
+    Return,
+    Other,
+}
+
+/// Drives a [`Machine`] for interactive debugging.
+///
+/// Every step is watched for calls and returns (jumps linking through ra or t0, and
+/// `jalr` through them without linking), building a shadow call stack that `step_over` and
+/// `finish` use to run whole functions at once.
+pub struct Debugger {
+    pub machine: Machine,
+    breakpoints: BTreeSet<u32>,
+    /// Watched addresses and the word last seen there
+    watchpoints: BTreeMap<u32, u32>,
+    call_stack: Vec<Frame>,
+    /// Applied to every resume, so a runaway loop can't hang the session
+    pub limits: RunLimits,
+    /// Source lines of the loaded program, empty for binaries without debug information
+    pub line_map: LineMap,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            call_stack: Vec::new(),
+            limits: RunLimits::default(),
+            line_map: LineMap::new(),
+        }
+    }
+
+    /// Returns false if a breakpoint was already set at `address`
+    pub fn add_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns false if no breakpoint was set at `address`
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Breakpoint addresses in ascending order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops execution whenever the word at `address` changes, returns false if it was
+    /// already watched or isn't backed by memory
+    pub fn add_watchpoint(&mut self, address: u32) -> bool {
+        match self.read_word(address) {
+            Some(value) if !self.watchpoints.contains_key(&address) => {
+                self.watchpoints.insert(address, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns false if `address` wasn't watched
+    pub fn remove_watchpoint(&mut self, address: u32) -> bool {
+        self.watchpoints.remove(&address).is_some()
+    }
+
+    /// Watched addresses in ascending order
+    pub fn watchpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    /// Resets the machine to start the program over, breakpoints and watchpoints stay set
+    pub fn restart(&mut self) {
+        self.machine.reset();
+        self.call_stack.clear();
+        let addresses: Vec<u32> = self.watchpoints.keys().copied().collect();
+        for address in addresses {
+            if let Some(value) = self.read_word(address) {
+                self.watchpoints.insert(address, value);
+            }
+        }
+    }
+
+    /// Moves the pc to `address` without running anything, e.g. to another image's entry
+    /// point. The call stack starts over there.
+    pub fn jump(&mut self, address: u32) {
+        self.machine.cpu.pc = address;
+        self.call_stack.clear();
+    }
+
+    /// Active calls, outermost first
+    pub fn backtrace(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> StopReason {
+        match self.execute_one() {
+            Some(reason) => StopReason::Exited(reason),
+            None => StopReason::Done,
+        }
+    }
+
+    /// Steps over calls: a call runs until it returns, anything else is a single step
+    pub fn step_over(&mut self) -> StopReason {
+        match self.control_flow().0 {
+            ControlFlow::Call => {
+                let depth = self.call_stack.len();
+                self.run_until(|debugger| debugger.call_stack.len() <= depth)
+            }
+            ControlFlow::Return | ControlFlow::Other => self.step(),
+        }
+    }
+
+    /// Runs until the current function returns, `None` in the outermost frame
+    pub fn finish(&mut self) -> Option<StopReason> {
+        let depth = self.call_stack.len().checked_sub(1)?;
+        Some(self.run_until(|debugger| debugger.call_stack.len() <= depth))
+    }
+
+    /// Runs until one of `addresses` is reached, as if temporary breakpoints were set there
+    pub fn run_to(&mut self, addresses: &[u32]) -> StopReason {
+        self.run_until(|debugger| addresses.contains(&debugger.machine.cpu.pc))
+    }
+
+    /// Runs until code generated for `line` of `file` is reached, `None` if the line has no code
+    pub fn run_to_line(&mut self, file: Option<&str>, line: u64) -> Option<StopReason> {
+        let addresses = self.line_map.addresses_for_line(file, line);
+        if addresses.is_empty() {
+            return None;
+        }
+        Some(self.run_to(&addresses))
+    }
+
+    /// Runs until a breakpoint is hit or the program stops
+    pub fn resume(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    /// Steps until `done` holds after an instruction, a breakpoint is reached, or the
+    /// program stops
+    fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool) -> StopReason {
+        let start = self.machine.instructions_retired();
+        loop {
+            if self
+                .limits
+                .max_instructions
+                .is_some_and(|max| self.machine.instructions_retired() - start >= max)
+            {
+                return StopReason::Exited(ExitReason::InstructionLimit);
+            }
+            if self.machine.take_interrupt() {
+                return StopReason::Exited(ExitReason::Interrupted);
+            }
+            if let Some(reason) = self.execute_one() {
+                return StopReason::Exited(reason);
+            }
+            if let Some(stop) = self.check_watchpoints() {
+                return stop;
+            }
+            if done(self) {
+                return StopReason::Done;
+            }
+            let pc = self.machine.cpu.pc;
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint { pc };
+            }
+        }
+    }
+
+    /// Reports the first watched word that changed, and remembers its new value
+    fn check_watchpoints(&mut self) -> Option<StopReason> {
+        let (address, old, new) = self.watchpoints.iter().find_map(|(&address, &old)| {
+            let new = self.read_word(address)?;
+            (new != old).then_some((address, old, new))
+        })?;
+        self.watchpoints.insert(address, new);
+        Some(StopReason::Watchpoint { address, old, new })
+    }
+
+    /// Sets the `len` bytes at `address` to `value`. Memory is written the way
+    /// [`Machine::write_memory`] does, nothing at all unless every byte is writable.
+    pub fn fill_memory(&mut self, address: u32, len: usize, value: u8) -> Result<(), EmuError> {
+        self.machine.write_memory(address, &vec![value; len])?;
+        self.rebase_watchpoints();
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `source` to `destination`, the ranges may overlap
+    pub fn copy_memory(
+        &mut self,
+        source: u32,
+        destination: u32,
+        len: usize,
+    ) -> Result<(), EmuError> {
+        let bytes = self.machine.read_memory(source, len)?;
+        self.machine.write_memory(destination, &bytes)?;
+        self.rebase_watchpoints();
+        Ok(())
+    }
+
+    /// Addresses `pattern` starts at in memory, the program image included, the first
+    /// `limit` of them in ascending order
+    pub fn find_memory(&self, pattern: &[u8], limit: usize) -> Vec<u32> {
+        let len = self.machine.memory_size();
+        let Ok(memory) = self.machine.read_memory(0, len) else {
+            return Vec::new();
+        };
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        memory
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(address, _)| address as u32)
+            .take(limit)
+            .collect()
+    }
+
+    /// Takes the words at the watched addresses as they are now, edits from the debugger
+    /// aren't changes the program made
+    fn rebase_watchpoints(&mut self) {
+        let addresses: Vec<u32> = self.watchpoints.keys().copied().collect();
+        for address in addresses {
+            if let Some(word) = self.read_word(address) {
+                self.watchpoints.insert(address, word);
+            }
+        }
+    }
+
+    fn read_word(&self, address: u32) -> Option<u32> {
+        let bytes = self.machine.read_memory(address, 4).ok()?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Executes the instruction at the pc and tracks calls, `Some` if the program stopped
+    fn execute_one(&mut self) -> Option<ExitReason> {
+        let pc = self.machine.cpu.pc;
+        if !self.machine.in_loaded_image(pc) {
+            return Some(ExitReason::EndOfProgram);
+        }
+        let (control_flow, length) = self.control_flow();
+        let stack_pointer = self.machine.cpu.regs[2] as u32;
+        if let Err(exception) = self.machine.step() {
+            return Some(ExitReason::from_exception(exception));
+        }
+        match control_flow {
+            ControlFlow::Call => self.call_stack.push(Frame {
+                call_site: pc,
+                function: self.machine.cpu.pc,
+                return_address: pc.wrapping_add(length),
+                stack_pointer,
+            }),
+            ControlFlow::Return => {
+                self.call_stack.pop();
+            }
+            ControlFlow::Other => {}
+        }
+        None
+    }
+
+    /// The instruction at the pc, expanded if it's compressed, and its length in bytes
+    fn current_instruction(&self) -> Option<(u32, u32)> {
+        self.machine.instruction_at(self.machine.cpu.pc)
+    }
+
+    /// Classifies the instruction at the pc, along with its length
+    fn control_flow(&self) -> (ControlFlow, u32) {
+        let Some((instruction, length)) = self.current_instruction() else {
+            return (ControlFlow::Other, 4);
+        };
+        let opcode = instruction & 0x7f;
+        let rd = (instruction >> 7) & 0x1f;
+        let rs1 = (instruction >> 15) & 0x1f;
+        let control_flow = match opcode {
+            // JAL, JALR
+            0b1101111 | 0b1100111 if LINK_REGISTERS.contains(&rd) => ControlFlow::Call,
+            0b1100111 if rd == 0 && LINK_REGISTERS.contains(&rs1) => ControlFlow::Return,
+            _ => ControlFlow::Other,
+        };
+        (control_flow, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// main calls `helper` twice, `helper` calls `leaf`
+    fn debugger() -> Debugger {
+        let program = [
+            0x00c000ef, // 0x00 main: jal ra, helper
+            0x008000ef, // 0x04       jal ra, helper
+            0x0180006f, // 0x08       jal zero, end
+            0x00050513, // 0x0c helper: addi a0, a0, 0
+            0x00c002ef, // 0x10         jal t0, leaf
+            0x00150513, // 0x14         addi a0, a0, 1
+            0x00008067, // 0x18         jalr zero, 0(ra)
+            0x00028067, // 0x1c leaf: jalr zero, 0(t0)
+        ];
+        let program = program
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect();
+        Debugger::new(Machine::new(program, 64))
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.step_over(), StopReason::Done);
+        assert_eq!(debugger.machine.cpu.pc, 0x04);
+        assert_eq!(debugger.machine.cpu.regs[10], 1);
+        assert!(debugger.backtrace().is_empty());
+    }
+
+    #[test]
+    fn test_next_stops_at_breakpoints_inside_the_call() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(0x14);
+        assert_eq!(debugger.step_over(), StopReason::Breakpoint { pc: 0x14 });
+        assert_eq!(debugger.backtrace().len(), 1);
+        assert_eq!(debugger.backtrace()[0].return_address, 0x04);
+    }
+
+    #[test]
+    fn test_finish_returns_to_the_caller() {
+        let mut debugger = debugger();
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.machine.cpu.pc, 0x10);
+        assert_eq!(debugger.finish(), Some(StopReason::Done));
+        assert_eq!(debugger.machine.cpu.pc, 0x04);
+        assert_eq!(debugger.finish(), None);
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Exited(ExitReason::EndOfProgram)
+        );
+    }
+
+    #[test]
+    fn test_run_to_line() {
+        let mut debugger = debugger();
+        for (index, line) in [1, 2, 3, 5, 6, 7, 8, 10].into_iter().enumerate() {
+            debugger
+                .line_map
+                .insert(index as u32 * 4, "examples/calls.s", line);
+        }
+        assert_eq!(debugger.run_to_line(Some("calls.s"), 4), None);
+        assert_eq!(
+            debugger.run_to_line(Some("calls.s"), 7),
+            Some(StopReason::Done)
+        );
+        assert_eq!(debugger.machine.cpu.pc, 0x14);
+        // Another hit of the same line, after the second call
+        assert_eq!(debugger.run_to_line(None, 7), Some(StopReason::Done));
+        assert_eq!(debugger.machine.cpu.regs[10], 1);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        assert!(debugger.add_watchpoint(0x30));
+        assert!(!debugger.add_watchpoint(0x30));
+        assert!(!debugger.add_watchpoint(0x40));
+        debugger.step();
+        // Change the word the way a store from the program would
+        debugger.machine.write_memory(0x30, &[7, 0, 0, 0]).unwrap();
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint {
+                address: 0x30,
+                old: 0,
+                new: 7
+            }
+        );
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), [0x30]);
+    }
+
+    #[test]
+    fn test_memory_editing() {
+        let mut debugger = debugger();
+        debugger.add_watchpoint(0x30);
+        debugger.fill_memory(0x30, 6, 0xaa).unwrap();
+        assert_eq!(
+            debugger.machine.read_memory(0x2f, 8).unwrap(),
+            [0, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0]
+        );
+        // Overlapping copies move the bytes as they were
+        debugger.machine.write_memory(0x30, &[1, 2, 3, 4]).unwrap();
+        debugger.copy_memory(0x30, 0x32, 4).unwrap();
+        assert_eq!(
+            debugger.machine.read_memory(0x30, 8).unwrap(),
+            [1, 2, 1, 2, 3, 4, 0, 0]
+        );
+        assert_eq!(debugger.find_memory(&[1, 2], 4), [0x30, 0x32]);
+        assert_eq!(debugger.find_memory(&[1, 2], 1), [0x30]);
+        assert_eq!(debugger.find_memory(&[4, 0, 0, 0], 4), [0x35]);
+        // Jumps to helper (0x0c) in the program image count too
+        assert_eq!(debugger.find_memory(&0x00c000efu32.to_le_bytes(), 4), [0]);
+
+        // The program image is read-only and writes past the end of memory fail whole
+        assert!(debugger.fill_memory(0x00, 4, 0).is_err());
+        assert!(debugger.copy_memory(0x30, 0x3e, 4).is_err());
+        assert_eq!(debugger.machine.read_memory(0x3e, 2).unwrap(), [0, 0]);
+
+        // The debugger's own edits don't trip watchpoints
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Exited(ExitReason::EndOfProgram)
+        );
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(0x14);
+        assert!(debugger.add_watchpoint(0x30));
+        debugger.machine.write_memory(0x30, &[7, 0, 0, 0]).unwrap();
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint {
+                address: 0x30,
+                old: 0,
+                new: 7
+            }
+        );
+        debugger.resume();
+        assert_eq!(debugger.backtrace().len(), 1);
+
+        debugger.restart();
+        assert_eq!(debugger.machine.cpu.pc, 0);
+        assert!(debugger.backtrace().is_empty());
+        // The watched word is back to 0 and that counts as the starting value
+        assert_eq!(debugger.resume(), StopReason::Breakpoint { pc: 0x14 });
+        assert_eq!(debugger.machine.cpu.regs[10], 0);
+    }
+
+    #[test]
+    fn test_interrupt_stops_the_debugger() {
+        let mut debugger = debugger();
+        debugger.machine.interrupt_handle().request();
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Exited(ExitReason::Interrupted)
+        );
+        assert_eq!(debugger.machine.cpu.pc, 0);
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Exited(ExitReason::EndOfProgram)
+        );
+    }
+
+    #[test]
+    fn test_limits_stop_runaway_programs() {
+        // jal zero, 0
+        let mut debugger = Debugger::new(Machine::new(0x0000006fu32.to_le_bytes().to_vec(), 64));
+        debugger.limits.max_instructions = Some(100);
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Exited(ExitReason::InstructionLimit)
+        );
+    }
+}
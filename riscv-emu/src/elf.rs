@@ -0,0 +1,830 @@
+//! Loading ELF executables built by external toolchains, 32 or 64 bit little-endian RISC-V.
+//!
+//! The `PT_LOAD` segments become an [`Image`] with the file's entry point, the space of a
+//! segment past its file contents (`.bss`) zero-filled. The symbol table, if the file wasn't
+//! stripped, comes along for traces and the debugger, and the program headers' location for
+//! the auxiliary vector a Linux process starts with. Addresses of 64 bit files have to fit in
+//! 32 bits, like everything else the emulator loads.
+//!
+//! [`write_executable`] goes the other way, for tools converting images to ELF.
+
+use thiserror::Error;
+
+use crate::{
+    image::{Image, ImageError},
+    linux::{AT_ENTRY, AT_PHDR, AT_PHENT, AT_PHNUM},
+    symbols::Symbols,
+};
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Largest segment loaded, 256 MiB: its `.bss` is zero-filled in the image, and no memory
+/// the emulator gives a program comes near
+const MAX_SEGMENT_SIZE: u64 = 1 << 28;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+/// Why an ELF file couldn't be loaded
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    #[error("not an ELF file")]
+    NotElf,
+    /// A valid ELF file the emulator can't run, e.g. one for another architecture
+    #[error("unsupported ELF file: {0}")]
+    Unsupported(String),
+    /// A header or table extends past the end of the file
+    #[error("truncated ELF file: {0} extends past the end")]
+    Truncated(&'static str),
+    #[error(transparent)]
+    Image(#[from] ImageError),
+}
+
+/// Where the program headers are in memory, for AT_PHDR and friends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeaders {
+    /// `None` if no loaded segment contains them
+    pub address: Option<u32>,
+    pub entry_size: u16,
+    pub count: u16,
+}
+
+/// A loaded ELF executable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    /// The loaded segments, and the entry point
+    pub image: Image,
+    /// The symbol table, empty for a stripped file
+    pub symbols: Symbols,
+    /// ELFCLASS64, the program is for RV64
+    pub is_64: bool,
+    pub program_headers: ProgramHeaders,
+    /// The sections loaded with the program in address order, empty if the file doesn't
+    /// name its sections
+    pub sections: Vec<ElfSection>,
+}
+
+/// A section of a program, where it's loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfSection {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Whether `bytes` start with the ELF magic
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+impl Elf {
+    /// Parses an executable, or a position-independent one linked to run at its addresses
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        if !is_elf(bytes) {
+            return Err(ElfError::NotElf);
+        }
+        let reader = match bytes.get(4..6) {
+            Some([ELFCLASS32, ELFDATA2LSB]) => Reader {
+                bytes,
+                is_64: false,
+            },
+            Some([ELFCLASS64, ELFDATA2LSB]) => Reader { bytes, is_64: true },
+            Some([ELFCLASS32 | ELFCLASS64, _]) => {
+                return Err(ElfError::Unsupported("big-endian file".to_string()));
+            }
+            _ => return Err(ElfError::Unsupported("unknown class".to_string())),
+        };
+        let header = reader.header()?;
+        if header.machine != EM_RISCV {
+            return Err(ElfError::Unsupported(format!(
+                "machine {} isn't RISC-V",
+                header.machine
+            )));
+        }
+        if header.kind != ET_EXEC && header.kind != ET_DYN {
+            return Err(ElfError::Unsupported(format!(
+                "type {} isn't an executable",
+                header.kind
+            )));
+        }
+
+        let mut image = Image::new();
+        image.entry = Some(address(header.entry)?);
+        let mut program_headers = ProgramHeaders {
+            address: None,
+            entry_size: header.phentsize,
+            count: header.phnum,
+        };
+        let mut phdr = None;
+        for index in 0..header.phnum as u64 {
+            let segment = reader
+                .program_header(header.phoff.saturating_add(index * header.phentsize as u64))?;
+            match segment.kind {
+                PT_PHDR => phdr = Some(address(segment.vaddr)?),
+                PT_LOAD if segment.memsz > 0 => {
+                    if segment.filesz > segment.memsz {
+                        return Err(ElfError::Unsupported(format!(
+                            "segment at {:#x} has more file contents than memory",
+                            segment.vaddr
+                        )));
+                    }
+                    let start = address(segment.vaddr)?;
+                    let end = (start as u64).checked_add(segment.memsz);
+                    if end.is_none_or(|end| end > 1 << 32) {
+                        return Err(ImageError::AddressOverflow {
+                            address: start,
+                            len: usize::try_from(segment.memsz).unwrap_or(usize::MAX),
+                        }
+                        .into());
+                    }
+                    if segment.memsz > MAX_SEGMENT_SIZE {
+                        return Err(ElfError::Unsupported(format!(
+                            "segment at {:#x} takes {} bytes of memory, more than the {} MiB \
+                             loaded",
+                            start,
+                            segment.memsz,
+                            MAX_SEGMENT_SIZE >> 20
+                        )));
+                    }
+                    let mut data = reader
+                        .slice(segment.offset, segment.filesz, "segment")?
+                        .to_vec();
+                    data.resize(segment.memsz as usize, 0);
+                    image.add_segment(start, data)?;
+                    let contents = segment.offset..segment.offset + segment.filesz;
+                    if contents.contains(&header.phoff) {
+                        program_headers.address =
+                            Some(start.wrapping_add((header.phoff - segment.offset) as u32));
+                    }
+                }
+                _ => {}
+            }
+        }
+        program_headers.address = phdr.or(program_headers.address);
+
+        Ok(Self {
+            image,
+            symbols: reader.symbols(&header)?,
+            is_64: reader.is_64,
+            program_headers,
+            sections: reader.sections(&header)?,
+        })
+    }
+
+    /// The auxiliary vector entries describing the program, for
+    /// [`start_process`](crate::linux::start_process)
+    pub fn auxv(&self) -> Vec<(u64, u64)> {
+        let mut auxv = Vec::new();
+        if let Some(address) = self.program_headers.address {
+            auxv.extend([
+                (AT_PHDR, address as u64),
+                (AT_PHENT, self.program_headers.entry_size as u64),
+                (AT_PHNUM, self.program_headers.count as u64),
+            ]);
+        }
+        if let Some(entry) = self.image.entry {
+            auxv.push((AT_ENTRY, entry as u64));
+        }
+        auxv
+    }
+}
+
+/// Writes `image` as a 32 bit executable with a segment for each of its segments, every one
+/// readable, writable and executable as the image doesn't say which is which. `sections`
+/// name parts of the image, their flags follow from their names (`.text*` is code, `.bss*`
+/// has no contents, `.rodata*` is read-only). `symbols` go in the symbol table, in the
+/// section they're in, absolute if none.
+pub fn write_executable(image: &Image, symbols: &Symbols, sections: &[ElfSection]) -> Vec<u8> {
+    const HEADER_SIZE: usize = 52;
+    const PROGRAM_HEADER_SIZE: usize = 32;
+    const SECTION_HEADER_SIZE: u16 = 40;
+    const SYMBOL_SIZE: usize = 16;
+
+    let mut file = vec![0; HEADER_SIZE + PROGRAM_HEADER_SIZE * image.segments().len()];
+    let put = |file: &mut Vec<u8>, at: usize, bytes: &[u8]| {
+        file[at..at + bytes.len()].copy_from_slice(bytes)
+    };
+    // Contents at offsets as aligned as their addresses, for loaders mapping them
+    let mut offsets = Vec::new();
+    for (index, segment) in image.segments().iter().enumerate() {
+        file.resize(
+            file.len().next_multiple_of(4) + segment.address as usize % 4,
+            0,
+        );
+        offsets.push(file.len());
+        let fields = [
+            PT_LOAD,
+            file.len() as u32,
+            segment.address,
+            segment.address,
+            segment.data.len() as u32,
+            segment.data.len() as u32,
+            PF_R | PF_W | PF_X,
+            4,
+        ];
+        for (field, value) in fields.into_iter().enumerate() {
+            let at = HEADER_SIZE + PROGRAM_HEADER_SIZE * index + 4 * field;
+            put(&mut file, at, &value.to_le_bytes());
+        }
+        file.extend_from_slice(&segment.data);
+    }
+    let offset_of = |address: u32| {
+        image
+            .segments()
+            .iter()
+            .zip(&offsets)
+            .find(|(segment, _)| {
+                (segment.address as u64..segment.end()).contains(&(address as u64))
+            })
+            .map(|(segment, offset)| offset + (address - segment.address) as usize)
+    };
+
+    // Symbols follow the null one, locals first as the format wants
+    let mut names = vec![0];
+    let mut symtab = vec![0; SYMBOL_SIZE];
+    let mut locals = 1;
+    let mut symbols: Vec<_> = symbols.iter().collect();
+    symbols.sort_by_key(|symbol| symbol.kind.is_ascii_uppercase());
+    for symbol in symbols {
+        let section = sections
+            .iter()
+            .position(|section| {
+                let end = section.address as u64 + section.size as u64;
+                (section.address as u64..end).contains(&(symbol.address as u64))
+            })
+            .filter(|_| !symbol.kind.eq_ignore_ascii_case(&'a'))
+            .map_or(SHN_ABS, |index| index as u16 + 1);
+        let binding = match symbol.kind.is_ascii_uppercase() {
+            true => STB_GLOBAL,
+            false => {
+                locals += 1;
+                STB_LOCAL
+            }
+        };
+        symtab.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        symtab.extend_from_slice(&symbol.address.to_le_bytes());
+        symtab.extend_from_slice(&0u32.to_le_bytes()); // st_size
+        symtab.extend_from_slice(&[binding << 4, 0]);
+        symtab.extend_from_slice(&section.to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+        names.push(0);
+    }
+
+    // The image's sections, then .symtab, .strtab and .shstrtab
+    let mut section_names = vec![0];
+    let mut name = |name: &str| {
+        let offset = section_names.len() as u32;
+        section_names.extend_from_slice(name.as_bytes());
+        section_names.push(0);
+        offset
+    };
+    let mut headers = vec![[0; 10]];
+    for section in sections {
+        let (kind, flags) = match section.name.as_str() {
+            name if name.starts_with(".text") => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+            name if name.starts_with(".bss") => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+            name if name.starts_with(".rodata") => (SHT_PROGBITS, SHF_ALLOC),
+            _ => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+        };
+        let offset = offset_of(section.address);
+        headers.push([
+            name(&section.name),
+            if offset.is_some() { kind } else { SHT_NOBITS },
+            flags as u32,
+            section.address,
+            offset.unwrap_or_default() as u32,
+            section.size,
+            0,
+            0,
+            1,
+            0,
+        ]);
+    }
+    let symtab_index = headers.len() as u32;
+    for (section, kind, contents, link, info, entry_size) in [
+        (
+            ".symtab",
+            SHT_SYMTAB,
+            &symtab,
+            symtab_index + 1,
+            locals,
+            SYMBOL_SIZE as u32,
+        ),
+        (".strtab", SHT_STRTAB, &names, 0, 0, 0),
+    ] {
+        file.resize(file.len().next_multiple_of(4), 0);
+        headers.push([
+            name(section),
+            kind,
+            0,
+            0,
+            file.len() as u32,
+            contents.len() as u32,
+            link,
+            info,
+            4,
+            entry_size,
+        ]);
+        file.extend_from_slice(contents);
+    }
+    let shstrtab = name(".shstrtab");
+    headers.push([
+        shstrtab,
+        SHT_STRTAB,
+        0,
+        0,
+        file.len() as u32,
+        section_names.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    ]);
+    file.extend_from_slice(&section_names);
+
+    file.resize(file.len().next_multiple_of(4), 0);
+    let shoff = file.len() as u32;
+    for header in &headers {
+        for value in header {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    // e_ident: ELF, 32 bit, little-endian, version 1, System V ABI
+    put(
+        &mut file,
+        0,
+        &[0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB, 1],
+    );
+    put(&mut file, 16, &ET_EXEC.to_le_bytes());
+    put(&mut file, 18, &EM_RISCV.to_le_bytes());
+    put(&mut file, 20, &1u32.to_le_bytes()); // e_version
+    let entry = image.entry.or(image.start()).unwrap_or_default();
+    put(&mut file, 24, &entry.to_le_bytes());
+    put(&mut file, 28, &(HEADER_SIZE as u32).to_le_bytes()); // e_phoff
+    put(&mut file, 32, &shoff.to_le_bytes());
+    put(&mut file, 40, &(HEADER_SIZE as u16).to_le_bytes()); // e_ehsize
+    put(&mut file, 42, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    put(
+        &mut file,
+        44,
+        &(image.segments().len() as u16).to_le_bytes(),
+    );
+    put(&mut file, 46, &SECTION_HEADER_SIZE.to_le_bytes());
+    put(&mut file, 48, &(headers.len() as u16).to_le_bytes());
+    put(&mut file, 50, &(headers.len() as u16 - 1).to_le_bytes()); // e_shstrndx
+    file
+}
+
+/// A 64 bit file's address, which has to fit in 32 bits
+fn address(value: u64) -> Result<u32, ElfError> {
+    u32::try_from(value)
+        .map_err(|_| ElfError::Unsupported(format!("address {:#x} is past 4GiB", value)))
+}
+
+struct Header {
+    kind: u16,
+    machine: u16,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    /// Index of the section holding the section names
+    shstrndx: u16,
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+struct SectionHeader {
+    /// Offset of the name in the section names
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    entsize: u64,
+}
+
+/// Reads the structures of either class, whose fields differ in width and order
+struct Reader<'a> {
+    bytes: &'a [u8],
+    is_64: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u64, len: u64, what: &'static str) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated(what))?;
+        let len = usize::try_from(len).map_err(|_| ElfError::Truncated(what))?;
+        start
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or(ElfError::Truncated(what))
+    }
+
+    fn u8(&self, offset: u64, what: &'static str) -> Result<u8, ElfError> {
+        Ok(self.slice(offset, 1, what)?[0])
+    }
+
+    fn u16(&self, offset: u64, what: &'static str) -> Result<u16, ElfError> {
+        let bytes = self.slice(offset, 2, what)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&self, offset: u64, what: &'static str) -> Result<u32, ElfError> {
+        let bytes = self.slice(offset, 4, what)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: u64, what: &'static str) -> Result<u64, ElfError> {
+        let bytes = self.slice(offset, 8, what)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// An address, offset or size: 4 bytes in 32 bit files, 8 in 64 bit ones
+    fn word(&self, offset: u64, what: &'static str) -> Result<u64, ElfError> {
+        if self.is_64 {
+            self.u64(offset, what)
+        } else {
+            self.u32(offset, what).map(u64::from)
+        }
+    }
+
+    fn header(&self) -> Result<Header, ElfError> {
+        let what = "ELF header";
+        let word = if self.is_64 { 8 } else { 4 };
+        // e_entry, e_phoff and e_shoff follow e_ident, e_type, e_machine and e_version
+        let e_flags = 24 + 3 * word;
+        Ok(Header {
+            kind: self.u16(16, what)?,
+            machine: self.u16(18, what)?,
+            entry: self.word(24, what)?,
+            phoff: self.word(24 + word, what)?,
+            shoff: self.word(24 + 2 * word, what)?,
+            phentsize: self.u16(e_flags + 6, what)?,
+            phnum: self.u16(e_flags + 8, what)?,
+            shentsize: self.u16(e_flags + 10, what)?,
+            shnum: self.u16(e_flags + 12, what)?,
+            shstrndx: self.u16(e_flags + 14, what)?,
+        })
+    }
+
+    fn program_header(&self, offset: u64) -> Result<ProgramHeader, ElfError> {
+        let what = "program header";
+        // 64 bit files move p_flags up next to p_type to align the rest
+        Ok(if self.is_64 {
+            ProgramHeader {
+                kind: self.u32(offset, what)?,
+                offset: self.u64(offset + 8, what)?,
+                vaddr: self.u64(offset + 16, what)?,
+                filesz: self.u64(offset + 32, what)?,
+                memsz: self.u64(offset + 40, what)?,
+            }
+        } else {
+            ProgramHeader {
+                kind: self.u32(offset, what)?,
+                offset: self.u32(offset + 4, what)?.into(),
+                vaddr: self.u32(offset + 8, what)?.into(),
+                filesz: self.u32(offset + 16, what)?.into(),
+                memsz: self.u32(offset + 20, what)?.into(),
+            }
+        })
+    }
+
+    fn section_header(&self, header: &Header, index: u32) -> Result<SectionHeader, ElfError> {
+        let what = "section header";
+        let word = if self.is_64 { 8 } else { 4 };
+        let offset = header
+            .shoff
+            .saturating_add(index as u64 * header.shentsize as u64);
+        // sh_name, sh_type, then sh_flags, sh_addr, sh_offset, sh_size as words
+        Ok(SectionHeader {
+            name: self.u32(offset, what)?,
+            kind: self.u32(offset + 4, what)?,
+            flags: self.word(offset + 8, what)?,
+            address: self.word(offset + 8 + word, what)?,
+            offset: self.word(offset + 8 + 2 * word, what)?,
+            size: self.word(offset + 8 + 3 * word, what)?,
+            link: self.u32(offset + 8 + 4 * word, what)?,
+            entsize: self.word(offset + 16 + 5 * word, what)?,
+        })
+    }
+
+    /// The allocated sections by name, in address order
+    fn sections(&self, header: &Header) -> Result<Vec<ElfSection>, ElfError> {
+        if header.shoff == 0 || header.shstrndx == 0 || header.shstrndx >= header.shnum {
+            return Ok(Vec::new());
+        }
+        let names = self.section_header(header, header.shstrndx as u32)?;
+        let names = self.slice(names.offset, names.size, "section names")?;
+        let mut sections = Vec::new();
+        for index in 1..header.shnum as u32 {
+            let section = self.section_header(header, index)?;
+            if section.flags & SHF_ALLOC == 0 || section.size == 0 {
+                continue;
+            }
+            let name = names
+                .get(section.name as usize..)
+                .and_then(|rest| rest.split(|&byte| byte == 0).next())
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            let size = u32::try_from(section.size)
+                .map_err(|_| ElfError::Unsupported(format!("section '{}' is past 4GiB", name)))?;
+            sections.push(ElfSection {
+                name: name.into_owned(),
+                address: address(section.address)?,
+                size,
+            });
+        }
+        sections.sort_by_key(|section| section.address);
+        Ok(sections)
+    }
+
+    /// The defined symbols of `.symtab` with nm type letters, without section, file and
+    /// mapping (`$x`, `$d`) symbols
+    fn symbols(&self, header: &Header) -> Result<Symbols, ElfError> {
+        let mut symbols = Symbols::new();
+        if header.shoff == 0 {
+            return Ok(symbols);
+        }
+        let sections = (0..header.shnum as u32)
+            .map(|index| self.section_header(header, index))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(symtab) = sections.iter().find(|section| section.kind == SHT_SYMTAB) else {
+            return Ok(symbols);
+        };
+        let strtab = sections
+            .get(symtab.link as usize)
+            .ok_or_else(|| ElfError::Unsupported("symbol table without names".to_string()))?;
+        let names = self.slice(strtab.offset, strtab.size, "string table")?;
+        let entry_size = match symtab.entsize {
+            0 if self.is_64 => 24,
+            0 => 16,
+            size => size,
+        };
+
+        let what = "symbol table";
+        for index in 1..symtab.size / entry_size {
+            let offset = symtab.offset.saturating_add(index * entry_size);
+            let (value, info, section) = if self.is_64 {
+                (
+                    self.u64(offset + 8, what)?,
+                    self.u8(offset + 4, what)?,
+                    self.u16(offset + 6, what)?,
+                )
+            } else {
+                (
+                    self.u32(offset + 4, what)?.into(),
+                    self.u8(offset + 12, what)?,
+                    self.u16(offset + 14, what)?,
+                )
+            };
+            let name_offset = self.u32(offset, what)? as usize;
+            let name = names
+                .get(name_offset..)
+                .and_then(|rest| rest.split(|&byte| byte == 0).next())
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            let kind = info & 0xf;
+            if name.is_empty()
+                || name.starts_with('$')
+                || section == SHN_UNDEF
+                || kind == STT_SECTION
+                || kind == STT_FILE
+            {
+                continue;
+            }
+            let letter = match sections.get(section as usize) {
+                _ if section == SHN_ABS => 'a',
+                Some(section) if section.kind == SHT_NOBITS => 'b',
+                Some(section) if section.flags & SHF_EXECINSTR != 0 => 't',
+                Some(section) if section.flags & SHF_WRITE != 0 => 'd',
+                _ => 'r',
+            };
+            let letter = if info >> 4 == STB_LOCAL {
+                letter
+            } else {
+                letter.to_ascii_uppercase()
+            };
+            symbols.insert_with_kind(&name, value as u32, letter);
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32 bit executable with a code segment at 0x1000 holding the program headers, a
+    /// data segment at 0x2000 with 4 bytes of file contents and 4 of .bss, and a symbol table
+    fn executable() -> Vec<u8> {
+        let mut file = vec![0; 0x220];
+        let put16 = |file: &mut Vec<u8>, at: usize, value: u16| {
+            file[at..at + 2].copy_from_slice(&value.to_le_bytes())
+        };
+        let put32 = |file: &mut Vec<u8>, at: usize, value: u32| {
+            file[at..at + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        file[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+        put16(&mut file, 16, ET_EXEC);
+        put16(&mut file, 18, EM_RISCV);
+        put32(&mut file, 24, 0x1004); // e_entry
+        put32(&mut file, 28, 52); // e_phoff
+        put32(&mut file, 32, 0x100); // e_shoff
+        put16(&mut file, 42, 32); // e_phentsize
+        put16(&mut file, 44, 2); // e_phnum
+        put16(&mut file, 46, 40); // e_shentsize
+        put16(&mut file, 48, 5); // e_shnum
+
+        // Code: the headers and two instructions from offset 0, data at offset 0xa0
+        for (at, fields) in [
+            (52, [PT_LOAD, 0, 0x1000, 0x1000, 0x9c, 0x9c, 5, 0x1000]),
+            (84, [PT_LOAD, 0xa0, 0x2000, 0x2000, 4, 8, 6, 0x1000]),
+        ] {
+            for (index, value) in fields.into_iter().enumerate() {
+                put32(&mut file, at + 4 * index, value);
+            }
+        }
+        put32(&mut file, 0x94, 0x00000013);
+        put32(&mut file, 0x98, 0x00100073);
+        put32(&mut file, 0xa0, 0xdeadbeef);
+
+        // Sections: null, .text, .data, .symtab linked to .strtab
+        for (index, fields) in [
+            (1, [1, 1, 6, 0x1000, 0, 0x9c, 0, 0, 4, 0]),
+            (2, [7, 1, 3, 0x2000, 0xa0, 8, 0, 0, 4, 0]),
+            (3, [13, SHT_SYMTAB, 0, 0, 0x1d0, 48, 4, 0, 4, 16]),
+            (4, [21, 3, 0, 0, 0x200, 16, 0, 0, 1, 0]),
+        ] {
+            for (field, value) in fields.into_iter().enumerate() {
+                put32(&mut file, 0x100 + 40 * index + 4 * field, value);
+            }
+        }
+        // Symbols after the null one: global _start in .text, local counter in .data
+        put32(&mut file, 0x1e0, 1);
+        put32(&mut file, 0x1e4, 0x1004);
+        file[0x1ec] = 0x12;
+        put16(&mut file, 0x1ee, 1);
+        put32(&mut file, 0x1f0, 8);
+        put32(&mut file, 0x1f4, 0x2000);
+        file[0x1fc] = 0x01;
+        put16(&mut file, 0x1fe, 2);
+        file[0x200..0x210].copy_from_slice(b"\0_start\0counter\0");
+        file
+    }
+
+    #[test]
+    fn test_parse_executable() {
+        let elf = Elf::parse(&executable()).unwrap();
+        assert!(!elf.is_64);
+        assert_eq!(elf.image.entry, Some(0x1004));
+        let segments = elf.image.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x1000);
+        assert_eq!(
+            &segments[0].data[0x94..],
+            &[0x13, 0, 0, 0, 0x73, 0, 0x10, 0]
+        );
+        assert_eq!(segments[1].address, 0x2000);
+        assert_eq!(segments[1].data, [0xef, 0xbe, 0xad, 0xde, 0, 0, 0, 0]);
+
+        assert_eq!(elf.symbols.address("_start"), Some(0x1004));
+        assert_eq!(elf.symbols.info("_start").unwrap().kind, 'T');
+        assert_eq!(elf.symbols.info("counter").unwrap().kind, 'd');
+        // Without section names
+        assert!(elf.sections.is_empty());
+        assert_eq!(
+            elf.auxv(),
+            [
+                (AT_PHDR, 0x1034),
+                (AT_PHENT, 32),
+                (AT_PHNUM, 2),
+                (AT_ENTRY, 0x1004)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_executable() {
+        let mut image = Image::new();
+        image
+            .add_segment(0x1000, vec![0x13, 0, 0, 0, 0x73, 0, 0x10, 0])
+            .unwrap();
+        image
+            .add_segment(0x2002, vec![0xef, 0xbe, 0, 0, 0, 0])
+            .unwrap();
+        image.entry = Some(0x1004);
+        let mut symbols = Symbols::new();
+        symbols.insert("_start", 0x1004);
+        symbols.insert_with_kind("counter", 0x2002, 'd');
+        symbols.insert_with_kind("SIZE", 16, 'A');
+        let sections = [
+            ElfSection {
+                name: ".text".to_string(),
+                address: 0x1000,
+                size: 8,
+            },
+            ElfSection {
+                name: ".data".to_string(),
+                address: 0x2002,
+                size: 2,
+            },
+            ElfSection {
+                name: ".bss".to_string(),
+                address: 0x2004,
+                size: 4,
+            },
+        ];
+
+        let file = write_executable(&image, &symbols, &sections);
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(elf.image, image);
+        assert_eq!(elf.symbols, symbols);
+        assert_eq!(elf.sections, sections);
+        // Contents as aligned in the file as in memory
+        let offset = u32::from_le_bytes(file[52 + 36..][..4].try_into().unwrap());
+        assert_eq!(offset % 4, 2);
+        assert!(Elf::parse(&write_executable(&Image::new(), &Symbols::new(), &[])).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert_eq!(Elf::parse(b"\x13\0\0\0"), Err(ElfError::NotElf));
+        let mut file = executable();
+        file[18] = 62; // x86-64
+        assert!(matches!(Elf::parse(&file), Err(ElfError::Unsupported(_))));
+        let file = executable();
+        assert_eq!(
+            Elf::parse(&file[..0x90]),
+            Err(ElfError::Truncated("segment"))
+        );
+    }
+
+    #[test]
+    fn test_rejects_huge_segments() {
+        // p_memsz of the data segment: far too much .bss, then past the address space
+        let mut file = executable();
+        file[104..108].copy_from_slice(&0x20000000u32.to_le_bytes());
+        assert!(matches!(Elf::parse(&file), Err(ElfError::Unsupported(_))));
+        file[104..108].copy_from_slice(&0xfffffff0u32.to_le_bytes());
+        assert!(matches!(
+            Elf::parse(&file),
+            Err(ElfError::Image(ImageError::AddressOverflow { .. }))
+        ));
+
+        // An ELF64 p_memsz near u64::MAX
+        let mut file = vec![0; 0x78];
+        file[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, 1, 1]);
+        file[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        file[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        file[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        file[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        file[56..58].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+        file[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+        file[80..88].copy_from_slice(&0x1000u64.to_le_bytes()); // p_vaddr
+        file[104..112].copy_from_slice(&(u64::MAX - 8).to_le_bytes()); // p_memsz
+        assert_eq!(
+            Elf::parse(&file),
+            Err(ElfError::Image(ImageError::AddressOverflow {
+                address: 0x1000,
+                len: usize::MAX - 8
+            }))
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use thiserror::Error;
+
+use crate::{image::ImageError, layout::LayoutError, symbols::SymbolFileError};
+
+/// Errors returned by the emulator's public APIs, the counterpart of the assembler's
+/// `AssemblerError`
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum EmuError {
+    #[error("Load error: {0}")]
+    LoadError(#[from] LoadError),
+    #[error("Image error: {0}")]
+    ImageError(#[from] ImageError),
+    #[error("Symbol file error: {0}")]
+    SymbolFileError(#[from] SymbolFileError),
+    #[error("Layout error: {0}")]
+    LayoutError(#[from] LayoutError),
+    /// A host-side memory access outside of the machine's memory
+    #[error("Bus fault: {len} byte access at {address:#010x}")]
+    BusFault { address: u32, len: usize },
+    #[error("Configuration error: {message}")]
+    ConfigError { message: String },
+    #[error("Instruction limit of {limit} exhausted")]
+    LimitExhausted { limit: u64 },
+}
+
+/// Why an image couldn't be loaded
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image doesn't fit in memory at the requested address
+    #[error("{len} byte image at {load_addr:#010x} does not fit in {memory_size} bytes of memory")]
+    OutOfMemory {
+        load_addr: u32,
+        len: usize,
+        memory_size: usize,
+    },
+    /// The image would overwrite the shared, read-only program image
+    #[error("image at {load_addr:#010x} overlaps the {program_len} byte program at address 0")]
+    OverlapsProgram { load_addr: u32, program_len: usize },
+}
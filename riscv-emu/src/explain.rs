@@ -0,0 +1,759 @@
+//! One-line explanations of executed instructions with their operand values filled in, for
+//! students stepping through a program.
+//!
+//! `addi x5, x0, 5` explains as `addi x5, x0, 5 → x5 = 0 + 5 = 5`. What an instruction
+//! computes comes from [`INSTRUCTIONS`], which describes the encoding, operands and operation
+//! of every instruction of the base ISAs and the M, A and Zicsr extensions. Others (the F and
+//! D extensions) are shown by their encoding only. Compressed instructions are explained as
+//! the instruction they expand to.
+
+use crate::{compressed, cpu::Exception, csr};
+
+use Operation::*;
+use Values::*;
+
+/// How the operands of an instruction are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// rd, rs1, rs2
+    R,
+    /// rd, rs1, imm
+    I,
+    /// rd, rs1, shamt
+    Shift,
+    /// rd, imm(rs1), also used by `jalr`
+    Load,
+    /// rs2, imm(rs1)
+    Store,
+    /// rs1, rs2, offset
+    Branch,
+    /// rd, imm where imm is the upper 20 bits
+    Upper,
+    /// rd, offset
+    Jump,
+    /// rd, (rs1)
+    LoadReserved,
+    /// rd, rs2, (rs1)
+    Atomic,
+    /// rd, csr, rs1
+    Csr,
+    /// rd, csr, uimm
+    CsrImmediate,
+    /// No operands
+    None,
+}
+
+/// How the values an operation works on are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Values {
+    Signed,
+    Unsigned,
+    /// Bit patterns, for the logical operations
+    Hex,
+    /// The low 32 bits as a signed number, for the word operations of RV64I
+    Word,
+}
+
+/// What an instruction computes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// rd = rs1 OP rs2, or the immediate in place of rs2
+    Binary(&'static str, Values),
+    /// rd = 1 if rs1 OP rs2 holds, else 0
+    SetIf(&'static str, Values),
+    /// rd = the upper XLEN bits of rs1 * rs2
+    MultiplyHigh(Values),
+    LoadUpper,
+    AddUpperToPc,
+    Load(Values),
+    /// Stores the low `n` bytes of rs2
+    Store(u32),
+    /// Jumps to pc + offset if rs1 OP rs2 holds
+    BranchIf(&'static str, Values),
+    JumpAndLink,
+    JumpAndLinkRegister,
+    LoadReserved,
+    StoreConditional,
+    /// mem = mem OP rs2, an operator or a function name like `min`; `None` for a swap
+    AtomicMemory(Option<&'static str>, Values),
+    CsrReadWrite,
+    CsrReadSet,
+    CsrReadClear,
+    EnvironmentCall,
+    Breakpoint,
+    TrapReturn,
+    WaitForInterrupt,
+    Fence,
+    FenceInstructions,
+}
+
+/// An entry of the instruction table: instructions whose bits under `mask` equal `pattern`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    pub mask: u32,
+    pub pattern: u32,
+    pub format: Format,
+    pub operation: Operation,
+}
+
+const OPCODE: u32 = 0x7f;
+const FUNCT3: u32 = 0x707f;
+/// RV64I's shift amounts take the low bit of funct7
+const FUNCT6: u32 = 0xfc00707f;
+const FUNCT7: u32 = 0xfe00707f;
+/// funct5 of the atomics, ignoring the aq and rl bits
+const FUNCT5: u32 = 0xf800707f;
+
+const OP: u32 = 0b0110011;
+const OP_IMM: u32 = 0b0010011;
+const OP_32: u32 = 0b0111011;
+const OP_IMM_32: u32 = 0b0011011;
+const LOAD: u32 = 0b0000011;
+const STORE: u32 = 0b0100011;
+const BRANCH: u32 = 0b1100011;
+const SYSTEM: u32 = 0b1110011;
+
+const fn exact(
+    mnemonic: &'static str,
+    mask: u32,
+    pattern: u32,
+    format: Format,
+    operation: Operation,
+) -> InstructionInfo {
+    InstructionInfo {
+        mnemonic,
+        mask,
+        pattern,
+        format,
+        operation,
+    }
+}
+
+/// Instructions told apart by their major opcode alone
+const fn u(
+    mnemonic: &'static str,
+    opcode: u32,
+    format: Format,
+    operation: Operation,
+) -> InstructionInfo {
+    exact(mnemonic, OPCODE, opcode, format, operation)
+}
+
+/// Instructions told apart by opcode and funct3
+const fn i(
+    mnemonic: &'static str,
+    funct3: u32,
+    opcode: u32,
+    format: Format,
+    operation: Operation,
+) -> InstructionInfo {
+    exact(mnemonic, FUNCT3, funct3 << 12 | opcode, format, operation)
+}
+
+/// Register-register instructions, told apart by opcode, funct3 and funct7
+const fn r(
+    mnemonic: &'static str,
+    funct7: u32,
+    funct3: u32,
+    opcode: u32,
+    operation: Operation,
+) -> InstructionInfo {
+    exact(
+        mnemonic,
+        FUNCT7,
+        funct7 << 25 | funct3 << 12 | opcode,
+        Format::R,
+        operation,
+    )
+}
+
+/// Shifts by an immediate, whose funct7 shrinks to 6 bits for RV64I's wider shift amounts
+const fn shift(
+    mnemonic: &'static str,
+    funct7: u32,
+    funct3: u32,
+    opcode: u32,
+    operation: Operation,
+) -> InstructionInfo {
+    let mask = if opcode == OP_IMM { FUNCT6 } else { FUNCT7 };
+    exact(
+        mnemonic,
+        mask,
+        funct7 << 25 | funct3 << 12 | opcode,
+        Format::Shift,
+        operation,
+    )
+}
+
+/// The word sized atomics, told apart by funct5
+const fn atomic(
+    mnemonic: &'static str,
+    funct5: u32,
+    format: Format,
+    operation: Operation,
+) -> InstructionInfo {
+    exact(
+        mnemonic,
+        FUNCT5,
+        funct5 << 27 | 0b010 << 12 | 0b0101111,
+        format,
+        operation,
+    )
+}
+
+/// Every instruction that can be explained
+pub const INSTRUCTIONS: &[InstructionInfo] = &[
+    u("lui", 0b0110111, Format::Upper, LoadUpper),
+    u("auipc", 0b0010111, Format::Upper, AddUpperToPc),
+    u("jal", 0b1101111, Format::Jump, JumpAndLink),
+    i("jalr", 0, 0b1100111, Format::Load, JumpAndLinkRegister),
+    i("beq", 0, BRANCH, Format::Branch, BranchIf("==", Signed)),
+    i("bne", 1, BRANCH, Format::Branch, BranchIf("!=", Signed)),
+    i("blt", 4, BRANCH, Format::Branch, BranchIf("<", Signed)),
+    i("bge", 5, BRANCH, Format::Branch, BranchIf(">=", Signed)),
+    i("bltu", 6, BRANCH, Format::Branch, BranchIf("<", Unsigned)),
+    i("bgeu", 7, BRANCH, Format::Branch, BranchIf(">=", Unsigned)),
+    i("lb", 0, LOAD, Format::Load, Load(Signed)),
+    i("lh", 1, LOAD, Format::Load, Load(Signed)),
+    i("lw", 2, LOAD, Format::Load, Load(Signed)),
+    i("ld", 3, LOAD, Format::Load, Load(Signed)),
+    i("lbu", 4, LOAD, Format::Load, Load(Unsigned)),
+    i("lhu", 5, LOAD, Format::Load, Load(Unsigned)),
+    i("lwu", 6, LOAD, Format::Load, Load(Unsigned)),
+    i("sb", 0, STORE, Format::Store, Store(1)),
+    i("sh", 1, STORE, Format::Store, Store(2)),
+    i("sw", 2, STORE, Format::Store, Store(4)),
+    i("sd", 3, STORE, Format::Store, Store(8)),
+    i("addi", 0, OP_IMM, Format::I, Binary("+", Signed)),
+    i("slti", 2, OP_IMM, Format::I, SetIf("<", Signed)),
+    i("sltiu", 3, OP_IMM, Format::I, SetIf("<", Unsigned)),
+    i("xori", 4, OP_IMM, Format::I, Binary("^", Hex)),
+    i("ori", 6, OP_IMM, Format::I, Binary("|", Hex)),
+    i("andi", 7, OP_IMM, Format::I, Binary("&", Hex)),
+    shift("slli", 0, 1, OP_IMM, Binary("<<", Hex)),
+    shift("srli", 0, 5, OP_IMM, Binary(">>", Unsigned)),
+    shift("srai", 0x20, 5, OP_IMM, Binary(">>", Signed)),
+    r("add", 0, 0, OP, Binary("+", Signed)),
+    r("sub", 0x20, 0, OP, Binary("-", Signed)),
+    r("sll", 0, 1, OP, Binary("<<", Hex)),
+    r("slt", 0, 2, OP, SetIf("<", Signed)),
+    r("sltu", 0, 3, OP, SetIf("<", Unsigned)),
+    r("xor", 0, 4, OP, Binary("^", Hex)),
+    r("srl", 0, 5, OP, Binary(">>", Unsigned)),
+    r("sra", 0x20, 5, OP, Binary(">>", Signed)),
+    r("or", 0, 6, OP, Binary("|", Hex)),
+    r("and", 0, 7, OP, Binary("&", Hex)),
+    i("addiw", 0, OP_IMM_32, Format::I, Binary("+", Word)),
+    shift("slliw", 0, 1, OP_IMM_32, Binary("<<", Word)),
+    shift("srliw", 0, 5, OP_IMM_32, Binary(">>", Word)),
+    shift("sraiw", 0x20, 5, OP_IMM_32, Binary(">>", Word)),
+    r("addw", 0, 0, OP_32, Binary("+", Word)),
+    r("subw", 0x20, 0, OP_32, Binary("-", Word)),
+    r("sllw", 0, 1, OP_32, Binary("<<", Word)),
+    r("srlw", 0, 5, OP_32, Binary(">>", Word)),
+    r("sraw", 0x20, 5, OP_32, Binary(">>", Word)),
+    // M extension
+    r("mul", 1, 0, OP, Binary("*", Signed)),
+    r("mulh", 1, 1, OP, MultiplyHigh(Signed)),
+    r("mulhsu", 1, 2, OP, MultiplyHigh(Signed)),
+    r("mulhu", 1, 3, OP, MultiplyHigh(Unsigned)),
+    r("div", 1, 4, OP, Binary("/", Signed)),
+    r("divu", 1, 5, OP, Binary("/", Unsigned)),
+    r("rem", 1, 6, OP, Binary("%", Signed)),
+    r("remu", 1, 7, OP, Binary("%", Unsigned)),
+    r("mulw", 1, 0, OP_32, Binary("*", Word)),
+    r("divw", 1, 4, OP_32, Binary("/", Word)),
+    r("divuw", 1, 5, OP_32, Binary("/", Word)),
+    r("remw", 1, 6, OP_32, Binary("%", Word)),
+    r("remuw", 1, 7, OP_32, Binary("%", Word)),
+    // A extension
+    atomic("lr.w", 0x02, Format::LoadReserved, LoadReserved),
+    atomic("sc.w", 0x03, Format::Atomic, StoreConditional),
+    atomic("amoswap.w", 0x01, Format::Atomic, AtomicMemory(None, Word)),
+    atomic(
+        "amoadd.w",
+        0x00,
+        Format::Atomic,
+        AtomicMemory(Some("+"), Word),
+    ),
+    atomic(
+        "amoxor.w",
+        0x04,
+        Format::Atomic,
+        AtomicMemory(Some("^"), Hex),
+    ),
+    atomic(
+        "amoand.w",
+        0x0c,
+        Format::Atomic,
+        AtomicMemory(Some("&"), Hex),
+    ),
+    atomic(
+        "amoor.w",
+        0x08,
+        Format::Atomic,
+        AtomicMemory(Some("|"), Hex),
+    ),
+    atomic(
+        "amomin.w",
+        0x10,
+        Format::Atomic,
+        AtomicMemory(Some("min"), Word),
+    ),
+    atomic(
+        "amomax.w",
+        0x14,
+        Format::Atomic,
+        AtomicMemory(Some("max"), Word),
+    ),
+    atomic(
+        "amominu.w",
+        0x18,
+        Format::Atomic,
+        AtomicMemory(Some("minu"), Hex),
+    ),
+    atomic(
+        "amomaxu.w",
+        0x1c,
+        Format::Atomic,
+        AtomicMemory(Some("maxu"), Hex),
+    ),
+    // Zicsr
+    i("csrrw", 1, SYSTEM, Format::Csr, CsrReadWrite),
+    i("csrrs", 2, SYSTEM, Format::Csr, CsrReadSet),
+    i("csrrc", 3, SYSTEM, Format::Csr, CsrReadClear),
+    i("csrrwi", 5, SYSTEM, Format::CsrImmediate, CsrReadWrite),
+    i("csrrsi", 6, SYSTEM, Format::CsrImmediate, CsrReadSet),
+    i("csrrci", 7, SYSTEM, Format::CsrImmediate, CsrReadClear),
+    exact("ecall", u32::MAX, 0x00000073, Format::None, EnvironmentCall),
+    exact("ebreak", u32::MAX, 0x00100073, Format::None, Breakpoint),
+    exact("mret", u32::MAX, 0x30200073, Format::None, TrapReturn),
+    exact("wfi", u32::MAX, 0x10500073, Format::None, WaitForInterrupt),
+    i("fence", 0, 0b0001111, Format::None, Fence),
+    i("fence.i", 1, 0b0001111, Format::None, FenceInstructions),
+];
+
+/// The table entry of an (expanded) instruction
+pub fn lookup(instruction: u32) -> Option<&'static InstructionInfo> {
+    INSTRUCTIONS
+        .iter()
+        .find(|info| instruction & info.mask == info.pattern)
+}
+
+/// Fields and register values of one executed instruction
+struct Executed<'a> {
+    instruction: u32,
+    pc: u32,
+    next_pc: u32,
+    length: u32,
+    before: &'a [u64; 32],
+    after: &'a [u64; 32],
+    xlen: u32,
+}
+
+impl Executed<'_> {
+    fn rd(&self) -> usize {
+        ((self.instruction >> 7) & 0x1f) as usize
+    }
+
+    fn rs1(&self) -> usize {
+        ((self.instruction >> 15) & 0x1f) as usize
+    }
+
+    fn rs2(&self) -> usize {
+        ((self.instruction >> 20) & 0x1f) as usize
+    }
+
+    fn imm_i(&self) -> i64 {
+        (self.instruction as i32 >> 20) as i64
+    }
+
+    fn imm_s(&self) -> i64 {
+        ((self.instruction as i32 >> 25) << 5 | (self.instruction as i32 >> 7) & 0x1f) as i64
+    }
+
+    fn imm_b(&self) -> i64 {
+        let instruction = self.instruction;
+        (((instruction & 0x8000_0000) as i32 >> 19) as u32
+            | (instruction << 4) & 0x800
+            | (instruction >> 20) & 0x7e0
+            | (instruction >> 7) & 0x1e) as i32 as i64
+    }
+
+    fn imm_j(&self) -> i64 {
+        let instruction = self.instruction;
+        (((instruction & 0x8000_0000) as i32 >> 11) as u32
+            | (instruction & 0x000f_f000)
+            | (instruction >> 9) & 0x800
+            | (instruction >> 20) & 0x7fe) as i32 as i64
+    }
+
+    fn shamt(&self) -> u64 {
+        ((self.instruction >> 20) & 0x3f) as u64
+    }
+
+    fn csr(&self) -> String {
+        let address = (self.instruction >> 20) as u16;
+        csr::name(address).map_or_else(|| format!("{:#05x}", address), str::to_string)
+    }
+
+    fn show(&self, value: u64, values: Values) -> String {
+        let value = match self.xlen {
+            32 => value & 0xffff_ffff,
+            _ => value,
+        };
+        match values {
+            Signed if self.xlen == 32 => (value as u32 as i32).to_string(),
+            Signed => (value as i64).to_string(),
+            Unsigned => value.to_string(),
+            Hex => format!("{:#x}", value),
+            Word => (value as u32 as i32).to_string(),
+        }
+    }
+
+    /// Source and immediate operands as written in assembly
+    fn operands(&self, format: Format) -> String {
+        let (rd, rs1, rs2) = (self.rd(), self.rs1(), self.rs2());
+        match format {
+            Format::R => format!("x{}, x{}, x{}", rd, rs1, rs2),
+            Format::I => format!("x{}, x{}, {}", rd, rs1, self.imm_i()),
+            Format::Shift => format!("x{}, x{}, {}", rd, rs1, self.shamt()),
+            Format::Load => format!("x{}, {}(x{})", rd, self.imm_i(), rs1),
+            Format::Store => format!("x{}, {}(x{})", rs2, self.imm_s(), rs1),
+            Format::Branch => format!("x{}, x{}, {}", rs1, rs2, self.imm_b()),
+            Format::Upper => format!("x{}, {:#x}", rd, self.instruction >> 12),
+            Format::Jump => format!("x{}, {}", rd, self.imm_j()),
+            Format::LoadReserved => format!("x{}, (x{})", rd, rs1),
+            Format::Atomic => format!("x{}, x{}, (x{})", rd, rs2, rs1),
+            Format::Csr => format!("x{}, {}, x{}", rd, self.csr(), rs1),
+            Format::CsrImmediate => format!("x{}, {}, {}", rd, self.csr(), rs1),
+            Format::None => String::new(),
+        }
+    }
+
+    /// `rd = expression = result`, results written to x0 are left out since they're dropped
+    fn assign(&self, expression: &str, values: Values) -> String {
+        match self.rd() {
+            0 => format!("x0 = {}, discarded (x0 is always 0)", expression),
+            rd => format!(
+                "x{} = {} = {}",
+                rd,
+                expression,
+                self.show(self.after[rd], values)
+            ),
+        }
+    }
+
+    /// `mem[base + offset] = mem[address]`, or just `mem[address]` without an offset
+    fn memory(&self, offset: i64) -> String {
+        let base = self.before[self.rs1()] as u32;
+        let address = base.wrapping_add(offset as u32);
+        match offset {
+            0 => format!("mem[{:#010x}]", address),
+            _ => format!("mem[{:#x} + {}] = mem[{:#010x}]", base, offset, address),
+        }
+    }
+
+    fn effect(&self, operation: Operation, format: Format) -> String {
+        let a = self.before[self.rs1()];
+        let b = match format {
+            Format::I => self.imm_i() as u64,
+            Format::Shift => self.shamt(),
+            _ => self.before[self.rs2()],
+        };
+        let rd = self.rd();
+        match operation {
+            Binary(op, values) => {
+                let expression =
+                    format!("{} {} {}", self.show(a, values), op, self.show(b, values));
+                self.assign(&expression, values)
+            }
+            SetIf(op, values) => {
+                let expression =
+                    format!("({} {} {})", self.show(a, values), op, self.show(b, values));
+                self.assign(&expression, Unsigned)
+            }
+            MultiplyHigh(values) => {
+                let expression = format!(
+                    "({} * {}) >> {}",
+                    self.show(a, values),
+                    self.show(b, values),
+                    self.xlen
+                );
+                self.assign(&expression, values)
+            }
+            LoadUpper => self.assign(&format!("{:#x} << 12", self.instruction >> 12), Hex),
+            AddUpperToPc => self.assign(
+                &format!("{:#x} + ({:#x} << 12)", self.pc, self.instruction >> 12),
+                Hex,
+            ),
+            Load(values) => self.assign(&self.memory(self.imm_i()), values),
+            Store(bytes) => {
+                let bits = bytes * 8;
+                let value = ((self.before[self.rs2()] << (64 - bits)) as i64) >> (64 - bits);
+                format!("{} = {}", self.memory(self.imm_s()), value)
+            }
+            BranchIf(op, values) => {
+                let condition = format!("{} {} {}", self.show(a, values), op, self.show(b, values));
+                if self.next_pc == self.pc.wrapping_add(self.length) {
+                    format!("{} is false, continue at {:#010x}", condition, self.next_pc)
+                } else {
+                    format!("{} is true, jump to {:#010x}", condition, self.next_pc)
+                }
+            }
+            JumpAndLink | JumpAndLinkRegister => {
+                let target = match operation {
+                    JumpAndLink => format!("{:#010x}", self.next_pc),
+                    _ => format!(
+                        "{:#x} + {} = {:#010x}",
+                        a as u32,
+                        self.imm_i(),
+                        self.next_pc
+                    ),
+                };
+                match rd {
+                    0 => format!("jump to {}", target),
+                    _ => format!(
+                        "x{} = {:#010x} (the return address), jump to {}",
+                        rd,
+                        self.pc.wrapping_add(self.length),
+                        target
+                    ),
+                }
+            }
+            LoadReserved => format!("{}, reserving it", self.assign(&self.memory(0), Word)),
+            StoreConditional => {
+                let store = format!("{} = {}", self.memory(0), self.show(b, Word));
+                match (rd, self.after[rd]) {
+                    (0, _) => format!("{} if still reserved", store),
+                    (_, 0) => format!("{}, x{} = 0 (the reservation held)", store, rd),
+                    _ => format!("nothing stored, x{} = 1 (the reservation was lost)", rd),
+                }
+            }
+            AtomicMemory(op, values) => {
+                let memory = self.memory(0);
+                let old = match rd {
+                    0 => memory.clone(),
+                    _ => self.show(self.after[rd], values),
+                };
+                let b = self.show(b, values);
+                let new = match op {
+                    None => b,
+                    Some(op) if op.starts_with(char::is_alphabetic) => {
+                        format!("{}({}, {})", op, old, b)
+                    }
+                    Some(op) => format!("{} {} {}", old, op, b),
+                };
+                match rd {
+                    0 => format!("{} = {}", memory, new),
+                    _ => format!("x{} = {} = {}, then {} = {}", rd, memory, old, memory, new),
+                }
+            }
+            CsrReadWrite | CsrReadSet | CsrReadClear => {
+                let csr = self.csr();
+                let source = match format {
+                    Format::CsrImmediate => self.rs1() as u64,
+                    _ => a,
+                };
+                let write = match operation {
+                    CsrReadWrite => Some(format!("{} = {}", csr, self.show(source, Hex))),
+                    // Setting or clearing no bits doesn't write the CSR
+                    _ if self.rs1() == 0 => None,
+                    CsrReadSet => Some(format!("{} |= {}", csr, self.show(source, Hex))),
+                    _ => Some(format!("{} &= !{}", csr, self.show(source, Hex))),
+                };
+                let read = (rd != 0).then(|| self.assign(&csr, Hex));
+                [read, write]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(", then ")
+            }
+            EnvironmentCall => format!("call the environment with a7 = {}", self.before[17]),
+            Breakpoint => "stop at a breakpoint".to_string(),
+            TrapReturn => format!("return from the trap to mepc = {:#010x}", self.next_pc),
+            WaitForInterrupt => "wait until an enabled interrupt is pending".to_string(),
+            Fence => "order memory accesses, which a single hart always sees in order".to_string(),
+            FenceInstructions => {
+                "see stores in the instructions fetched after it, a single hart always does"
+                    .to_string()
+            }
+        }
+    }
+}
+
+/// Explains the instruction `parcel` at `pc`, which changed the registers from `before` to
+/// `after` and left the pc at `next_pc`
+pub fn explain(
+    pc: u32,
+    parcel: u32,
+    before: &[u64; 32],
+    after: &[u64; 32],
+    next_pc: u32,
+    xlen: u32,
+) -> String {
+    let length = compressed::instruction_length(parcel);
+    let instruction = match length {
+        2 => compressed::expand(parcel as u16).unwrap_or(parcel),
+        _ => parcel,
+    };
+    let Some(info) = lookup(instruction) else {
+        return format!(
+            "{:#010x} (no explanation for this instruction)",
+            instruction
+        );
+    };
+    let executed = Executed {
+        instruction,
+        pc,
+        next_pc,
+        length,
+        before,
+        after,
+        xlen,
+    };
+    let operands = executed.operands(info.format);
+    let effect = executed.effect(info.operation, info.format);
+    match operands.as_str() {
+        "" => format!("{} → {}", info.mnemonic, effect),
+        _ => format!("{} {} → {}", info.mnemonic, operands, effect),
+    }
+}
+
+/// Explains an instruction raising `exception`, which trapped to the handler at `handler`
+pub fn explain_trap(exception: &Exception, handler: u32) -> String {
+    let what = match exception {
+        Exception::InstructionAddressMisaligned { .. } => "misaligned instruction address",
+        Exception::IllegalInstruction { .. } => "illegal instruction",
+        Exception::InstructionAccessFault { .. } => "instruction access fault",
+        Exception::LoadAccessFault { .. } => "load access fault",
+        Exception::StoreAccessFault { .. } => "store access fault",
+        Exception::EnvironmentCall { .. } => "environment call",
+        Exception::Breakpoint { .. } => "breakpoint",
+    };
+    format!(
+        "{} → mepc = {:#010x}, mcause = {}, mtval = {:#x}, jump to the handler at {:#010x}",
+        what,
+        exception.pc(),
+        exception.cause(),
+        exception.value(),
+        handler
+    )
+}
+
+/// Explains taking the interrupt `cause` (mcause), which interrupted the program before the
+/// instruction at mepc and jumped to `handler`
+pub fn explain_interrupt(cause: u64, handler: u32) -> String {
+    let what = match cause & 0xff {
+        3 => "machine software interrupt",
+        7 => "machine timer interrupt",
+        11 => "machine external interrupt",
+        _ => "interrupt",
+    };
+    format!(
+        "{} → mcause = {:#x}, jump to the handler at {:#010x}",
+        what, cause, handler
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regs(values: &[(usize, u64)]) -> [u64; 32] {
+        let mut regs = [0; 32];
+        for &(index, value) in values {
+            regs[index] = value;
+        }
+        regs
+    }
+
+    #[test]
+    fn test_table_entries_are_distinct() {
+        for info in INSTRUCTIONS {
+            assert_eq!(info.pattern & !info.mask, 0, "{}", info.mnemonic);
+            assert_eq!(lookup(info.pattern), Some(info), "{}", info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_explanations_substitute_values() {
+        // addi x5, x0, 5
+        let after = regs(&[(5, 5)]);
+        assert_eq!(
+            explain(0, 0x00500293, &[0; 32], &after, 4, 32),
+            "addi x5, x0, 5 → x5 = 0 + 5 = 5"
+        );
+        // sub x7, x5, x6 going negative
+        let before = regs(&[(5, 2), (6, 3)]);
+        let after = regs(&[(5, 2), (6, 3), (7, 0xffff_ffff)]);
+        assert_eq!(
+            explain(0, 0x406283b3, &before, &after, 4, 32),
+            "sub x7, x5, x6 → x7 = 2 - 3 = -1"
+        );
+        // lw x10, 8(x2)
+        let before = regs(&[(2, 0x100)]);
+        let after = regs(&[(2, 0x100), (10, 42)]);
+        assert_eq!(
+            explain(0, 0x00812503, &before, &after, 4, 32),
+            "lw x10, 8(x2) → x10 = mem[0x100 + 8] = mem[0x00000108] = 42"
+        );
+        // sb x10, 0(x2) stores the low byte
+        let before = regs(&[(2, 0x100), (10, 0x1ff)]);
+        assert_eq!(
+            explain(0, 0x00a10023, &before, &before, 4, 32),
+            "sb x10, 0(x2) → mem[0x00000100] = -1"
+        );
+        // Writes to x0 are dropped: addi x0, x5, 3
+        let before = regs(&[(5, 1)]);
+        assert_eq!(
+            explain(0, 0x00328013, &before, &before, 4, 32),
+            "addi x0, x5, 3 → x0 = 1 + 3, discarded (x0 is always 0)"
+        );
+    }
+
+    #[test]
+    fn test_control_flow_and_csrs() {
+        // bne x10, x0, -8 at 0x8, taken and not
+        let before = regs(&[(10, 3)]);
+        assert_eq!(
+            explain(0x8, 0xfe051ce3, &before, &before, 0x0, 32),
+            "bne x10, x0, -8 → 3 != 0 is true, jump to 0x00000000"
+        );
+        assert_eq!(
+            explain(0x8, 0xfe051ce3, &[0; 32], &[0; 32], 0xc, 32),
+            "bne x10, x0, -8 → 0 != 0 is false, continue at 0x0000000c"
+        );
+        // jal x1, 16 at 0x4
+        let after = regs(&[(1, 0x8)]);
+        assert_eq!(
+            explain(0x4, 0x010000ef, &[0; 32], &after, 0x14, 32),
+            "jal x1, 16 → x1 = 0x00000008 (the return address), jump to 0x00000014"
+        );
+        // c.jr ra, explained as jalr x0, 0(x1)
+        let before = regs(&[(1, 0x8)]);
+        assert_eq!(
+            explain(0x20, 0x8082, &before, &before, 0x8, 32),
+            "jalr x0, 0(x1) → jump to 0x8 + 0 = 0x00000008"
+        );
+        // csrrs x11, mtvec, x0 only reads
+        let after = regs(&[(11, 0x100)]);
+        assert_eq!(
+            explain(0, 0x305025f3, &[0; 32], &after, 4, 32),
+            "csrrs x11, mtvec, x0 → x11 = mtvec = 0x100"
+        );
+        // csrrw x10, mtvec, x5
+        let before = regs(&[(5, 0x200)]);
+        let after = regs(&[(5, 0x200), (10, 0x100)]);
+        assert_eq!(
+            explain(0, 0x30529573, &before, &after, 4, 32),
+            "csrrw x10, mtvec, x5 → x10 = mtvec = 0x100, then mtvec = 0x200"
+        );
+        // fadd.s has no table entry
+        assert_eq!(
+            explain(0, 0x003100d3, &[0; 32], &[0; 32], 4, 32),
+            "0x003100d3 (no explanation for this instruction)"
+        );
+    }
+}
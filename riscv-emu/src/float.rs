@@ -0,0 +1,768 @@
+//! Arithmetic of the F and D extensions: IEEE 754 operations in all five rounding modes, the
+//! exception flags they raise and the NaN rules RISC-V adds on top.
+//!
+//! Host floats only round to nearest even. The other modes start from that result and move
+//! it by one ulp when the exact result lies on the wrong side of it, which the sign of the
+//! rounding error tells. The error of additions and products is computed exactly, that of
+//! quotients, square roots and fused multiply-adds through a fused multiply-add residual.
+
+use std::{
+    cmp::Ordering,
+    num::FpCategory,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+/// fflags: the result was rounded
+pub const FLAG_INEXACT: u32 = 0x01;
+/// fflags: the rounded result is tiny (subnormal or zero) and inexact
+pub const FLAG_UNDERFLOW: u32 = 0x02;
+/// fflags: the result exceeded the largest finite value
+pub const FLAG_OVERFLOW: u32 = 0x04;
+/// fflags: a finite non-zero number was divided by zero
+pub const FLAG_DIVIDE_BY_ZERO: u32 = 0x08;
+/// fflags: the operation has no meaningful result, or was given a signaling NaN
+pub const FLAG_INVALID: u32 = 0x10;
+
+/// How results are rounded, the `rm` field of instructions and the `frm` field of fcsr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even (`rne`)
+    NearestEven,
+    /// Round towards zero (`rtz`)
+    TowardZero,
+    /// Round down, towards negative infinity (`rdn`)
+    Down,
+    /// Round up, towards positive infinity (`rup`)
+    Up,
+    /// Round to nearest, ties away from zero (`rmm`)
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Decodes a static rounding mode, `None` for the reserved encodings and for 7 (dynamic,
+    /// which the caller resolves through fcsr)
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Some(match bits {
+            0 => Self::NearestEven,
+            1 => Self::TowardZero,
+            2 => Self::Down,
+            3 => Self::Up,
+            4 => Self::NearestMaxMagnitude,
+            _ => return None,
+        })
+    }
+}
+
+/// Single or double precision, as stored in a 64 bit floating point register
+pub trait Float:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    const ZERO: Self;
+    const MAX: Self;
+    const MIN_POSITIVE: Self;
+    /// Power of two that lifts any product below `MIN_POSITIVE` back into the normal range
+    const SCALE: Self;
+
+    /// Value of a register, single precision values that aren't NaN-boxed read as the
+    /// canonical NaN
+    fn unbox(register: u64) -> Self;
+    /// Register contents holding the value, NaN-boxed for single precision
+    fn boxed(self) -> u64;
+    fn canonical_nan() -> Self;
+    fn is_signaling(self) -> bool;
+
+    fn is_nan(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn classify(self) -> FpCategory;
+    fn abs(self) -> Self;
+    fn copysign(self, sign: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn to_f64(self) -> f64;
+    /// Rounds a double (in practice a converted integer) to this precision
+    fn round_from_f64(value: f64, mode: RoundingMode) -> Outcome<Self>;
+}
+
+impl Float for f32 {
+    const ZERO: Self = 0.0;
+    const MAX: Self = f32::MAX;
+    const MIN_POSITIVE: Self = f32::MIN_POSITIVE;
+    const SCALE: Self = 18446744073709551616.0; // 2^64
+
+    fn unbox(register: u64) -> Self {
+        if register >> 32 == 0xffff_ffff {
+            f32::from_bits(register as u32)
+        } else {
+            Self::canonical_nan()
+        }
+    }
+
+    fn boxed(self) -> u64 {
+        0xffff_ffff_0000_0000 | self.to_bits() as u64
+    }
+
+    fn canonical_nan() -> Self {
+        f32::from_bits(0x7fc0_0000)
+    }
+
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.to_bits() & 0x0040_0000 == 0
+    }
+
+    fn is_nan(self) -> bool {
+        f32::is_nan(self)
+    }
+
+    fn is_finite(self) -> bool {
+        f32::is_finite(self)
+    }
+
+    fn is_infinite(self) -> bool {
+        f32::is_infinite(self)
+    }
+
+    fn is_sign_negative(self) -> bool {
+        f32::is_sign_negative(self)
+    }
+
+    fn classify(self) -> FpCategory {
+        f32::classify(self)
+    }
+
+    fn abs(self) -> Self {
+        f32::abs(self)
+    }
+
+    fn copysign(self, sign: Self) -> Self {
+        f32::copysign(self, sign)
+    }
+
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        f32::mul_add(self, a, b)
+    }
+
+    fn next_up(self) -> Self {
+        f32::next_up(self)
+    }
+
+    fn next_down(self) -> Self {
+        f32::next_down(self)
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn round_from_f64(value: f64, mode: RoundingMode) -> Outcome<Self> {
+        narrow(value, mode)
+    }
+}
+
+impl Float for f64 {
+    const ZERO: Self = 0.0;
+    const MAX: Self = f64::MAX;
+    const MIN_POSITIVE: Self = f64::MIN_POSITIVE;
+    const SCALE: Self = 340282366920938463463374607431768211456.0; // 2^128
+
+    fn unbox(register: u64) -> Self {
+        f64::from_bits(register)
+    }
+
+    fn boxed(self) -> u64 {
+        self.to_bits()
+    }
+
+    fn canonical_nan() -> Self {
+        f64::from_bits(0x7ff8_0000_0000_0000)
+    }
+
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.to_bits() & 0x0008_0000_0000_0000 == 0
+    }
+
+    fn is_nan(self) -> bool {
+        f64::is_nan(self)
+    }
+
+    fn is_finite(self) -> bool {
+        f64::is_finite(self)
+    }
+
+    fn is_infinite(self) -> bool {
+        f64::is_infinite(self)
+    }
+
+    fn is_sign_negative(self) -> bool {
+        f64::is_sign_negative(self)
+    }
+
+    fn classify(self) -> FpCategory {
+        f64::classify(self)
+    }
+
+    fn abs(self) -> Self {
+        f64::abs(self)
+    }
+
+    fn copysign(self, sign: Self) -> Self {
+        f64::copysign(self, sign)
+    }
+
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        f64::mul_add(self, a, b)
+    }
+
+    fn next_up(self) -> Self {
+        f64::next_up(self)
+    }
+
+    fn next_down(self) -> Self {
+        f64::next_down(self)
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn round_from_f64(value: f64, _: RoundingMode) -> Outcome<Self> {
+        (value, 0)
+    }
+}
+
+/// A result and the fflags it raised
+pub type Outcome<T> = (T, u32);
+
+/// The canonical NaN if any operand is a NaN, raising the invalid flag for signaling ones
+fn propagate_nan<T: Float>(operands: &[T]) -> Option<Outcome<T>> {
+    if !operands.iter().any(|operand| operand.is_nan()) {
+        return None;
+    }
+    let signaling = operands.iter().any(|operand| operand.is_signaling());
+    Some((T::canonical_nan(), if signaling { FLAG_INVALID } else { 0 }))
+}
+
+/// Sign of the rounding `error` (exact result minus `result`)
+fn direction<T: Float>(error: T) -> Ordering {
+    error.partial_cmp(&T::ZERO).unwrap_or(Ordering::Equal)
+}
+
+/// Whether the exact result `result + error` lies halfway between `result` and its neighbour
+fn is_tie<T: Float>(result: T, error: T) -> bool {
+    let neighbour = match direction(error) {
+        Ordering::Greater => result.next_up(),
+        Ordering::Less => result.next_down(),
+        Ordering::Equal => return false,
+    };
+    neighbour - result == error + error
+}
+
+/// Applies `mode` to a result rounded to nearest even.
+///
+/// `error` is the side of `result` the exact result lies on, `tie` whether it lies exactly
+/// halfway to the neighbouring value. `finite_operands` tells an overflow from an infinite
+/// operand.
+fn round<T: Float>(
+    result: T,
+    error: Ordering,
+    tie: bool,
+    mode: RoundingMode,
+    finite_operands: bool,
+) -> Outcome<T> {
+    if result.is_infinite() {
+        if !finite_operands {
+            return (result, 0);
+        }
+        // Overflowed, the directed modes stop at the largest finite value on one side
+        let negative = result.is_sign_negative();
+        let value = match mode {
+            RoundingMode::TowardZero => T::MAX.copysign(result),
+            RoundingMode::Down if !negative => T::MAX,
+            RoundingMode::Up if negative => -T::MAX,
+            _ => result,
+        };
+        return (value, FLAG_OVERFLOW | FLAG_INEXACT);
+    }
+    if error == Ordering::Equal {
+        return (result, 0);
+    }
+    let up = error == Ordering::Greater;
+    let value = match mode {
+        RoundingMode::NearestEven => result,
+        RoundingMode::TowardZero if up && result.is_sign_negative() => result.next_up(),
+        RoundingMode::TowardZero if !up && !result.is_sign_negative() => result.next_down(),
+        RoundingMode::TowardZero => result,
+        RoundingMode::Down if !up => result.next_down(),
+        RoundingMode::Up if up => result.next_up(),
+        RoundingMode::Down | RoundingMode::Up => result,
+        RoundingMode::NearestMaxMagnitude => {
+            let neighbour = if up {
+                result.next_up()
+            } else {
+                result.next_down()
+            };
+            if tie && neighbour.abs() > result.abs() {
+                neighbour
+            } else {
+                result
+            }
+        }
+    };
+    let mut flags = FLAG_INEXACT;
+    if value.is_infinite() {
+        flags |= FLAG_OVERFLOW;
+    } else if value.abs() < T::MIN_POSITIVE {
+        flags |= FLAG_UNDERFLOW;
+    }
+    (value, flags)
+}
+
+pub fn add<T: Float>(a: T, b: T, mode: RoundingMode) -> Outcome<T> {
+    if let Some(nan) = propagate_nan(&[a, b]) {
+        return nan;
+    }
+    let sum = a + b;
+    if sum.is_nan() {
+        // Infinities of opposite signs
+        return (T::canonical_nan(), FLAG_INVALID);
+    }
+    let finite = a.is_finite() && b.is_finite();
+    if !sum.is_finite() {
+        return round(sum, Ordering::Equal, false, mode, finite);
+    }
+    // Knuth's TwoSum, the rounding error of the addition exactly
+    let b_virtual = sum - a;
+    let error = (a - (sum - b_virtual)) + (b - b_virtual);
+    if sum == T::ZERO && direction(error) == Ordering::Equal {
+        // An exact zero sum of opposite signs is -0 when rounding down, +0 otherwise
+        let negative = if a.is_sign_negative() == b.is_sign_negative() {
+            a.is_sign_negative()
+        } else {
+            mode == RoundingMode::Down
+        };
+        let zero = if negative { -T::ZERO } else { T::ZERO };
+        return (zero, 0);
+    }
+    round(sum, direction(error), is_tie(sum, error), mode, finite)
+}
+
+pub fn sub<T: Float>(a: T, b: T, mode: RoundingMode) -> Outcome<T> {
+    add(a, -b, mode)
+}
+
+pub fn mul<T: Float>(a: T, b: T, mode: RoundingMode) -> Outcome<T> {
+    if let Some(nan) = propagate_nan(&[a, b]) {
+        return nan;
+    }
+    let product = a * b;
+    if product.is_nan() {
+        // Zero times infinity
+        return (T::canonical_nan(), FLAG_INVALID);
+    }
+    let finite = a.is_finite() && b.is_finite();
+    if !product.is_finite() {
+        return round(product, Ordering::Equal, false, mode, finite);
+    }
+    if product.abs() < T::MIN_POSITIVE && a != T::ZERO && b != T::ZERO {
+        return round(
+            product,
+            tiny_product_error(a, b, product),
+            false,
+            mode,
+            finite,
+        );
+    }
+    let error = a.mul_add(b, -product);
+    round(
+        product,
+        direction(error),
+        is_tie(product, error),
+        mode,
+        finite,
+    )
+}
+
+/// Sign of the rounding error of a product below `MIN_POSITIVE`, whose residual would itself
+/// underflow. The product is redone with the smaller operand scaled up, where it is normal.
+fn tiny_product_error<T: Float>(a: T, b: T, product: T) -> Ordering {
+    let (small, large) = if a.abs() < b.abs() { (a, b) } else { (b, a) };
+    let scaled = small * T::SCALE;
+    let scaled_product = scaled * large;
+    match direction(scaled_product - product * T::SCALE) {
+        Ordering::Equal => direction(scaled.mul_add(large, -scaled_product)),
+        error => error,
+    }
+}
+
+pub fn div<T: Float>(a: T, b: T, mode: RoundingMode) -> Outcome<T> {
+    if let Some(nan) = propagate_nan(&[a, b]) {
+        return nan;
+    }
+    let quotient = a / b;
+    if quotient.is_nan() {
+        // Zero by zero or infinity by infinity
+        return (T::canonical_nan(), FLAG_INVALID);
+    }
+    if b == T::ZERO {
+        let flags = if a.is_finite() {
+            FLAG_DIVIDE_BY_ZERO
+        } else {
+            0
+        };
+        return (quotient, flags);
+    }
+    let finite = a.is_finite() && b.is_finite();
+    if !quotient.is_finite() || !finite {
+        return round(quotient, Ordering::Equal, false, mode, finite);
+    }
+    // a - quotient * b has the sign of the error times the sign of b
+    let remainder = (-quotient).mul_add(b, a);
+    let error = if b.is_sign_negative() {
+        direction(remainder).reverse()
+    } else {
+        direction(remainder)
+    };
+    round(quotient, error, false, mode, true)
+}
+
+pub fn sqrt<T: Float>(a: T, mode: RoundingMode) -> Outcome<T> {
+    if let Some(nan) = propagate_nan(&[a]) {
+        return nan;
+    }
+    if a < T::ZERO {
+        return (T::canonical_nan(), FLAG_INVALID);
+    }
+    let root = a.sqrt();
+    if !root.is_finite() || root == T::ZERO {
+        return (root, 0);
+    }
+    let remainder = (-root).mul_add(root, a);
+    round(root, direction(remainder), false, mode, true)
+}
+
+/// `a * b + c` with a single rounding, the negated forms pass negated operands
+pub fn fused_multiply_add<T: Float>(a: T, b: T, c: T, mode: RoundingMode) -> Outcome<T> {
+    let invalid_product = (a.is_infinite() && b == T::ZERO) || (a == T::ZERO && b.is_infinite());
+    if let Some((nan, flags)) = propagate_nan(&[a, b, c]) {
+        let flags = if invalid_product { FLAG_INVALID } else { flags };
+        return (nan, flags);
+    }
+    let result = a.mul_add(b, c);
+    if result.is_nan() {
+        return (T::canonical_nan(), FLAG_INVALID);
+    }
+    let finite = a.is_finite() && b.is_finite() && c.is_finite();
+    if !result.is_finite() || !finite {
+        return round(result, Ordering::Equal, false, mode, finite);
+    }
+    // The exact result is product + product_error + c, summed with TwoSum
+    let product = a * b;
+    let product_error = a.mul_add(b, -product);
+    let sum = product + c;
+    let b_virtual = sum - product;
+    let sum_error = (product - (sum - b_virtual)) + (c - b_virtual);
+    let error = ((sum - result) + sum_error) + product_error;
+    if result == T::ZERO && direction(error) == Ordering::Equal {
+        let negative = if (product.is_sign_negative()) == c.is_sign_negative() {
+            c.is_sign_negative()
+        } else {
+            mode == RoundingMode::Down
+        };
+        return (if negative { -T::ZERO } else { T::ZERO }, 0);
+    }
+    round(result, direction(error), false, mode, true)
+}
+
+/// fmin and fmax: a NaN operand yields the other operand, -0 is smaller than +0
+pub fn min_max<T: Float>(a: T, b: T, max: bool) -> Outcome<T> {
+    let flags = if a.is_signaling() || b.is_signaling() {
+        FLAG_INVALID
+    } else {
+        0
+    };
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => T::canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => {
+            // Equal, possibly zeros of opposite signs
+            if a.is_sign_negative() != max { a } else { b }
+        }
+        _ if (a < b) != max => a,
+        _ => b,
+    };
+    (value, flags)
+}
+
+/// feq (`signaling` false), flt and fle: false for NaN operands, which are invalid unless
+/// feq compares quiet NaNs
+pub fn compare<T: Float>(a: T, b: T, ordering: &[Ordering], signaling: bool) -> Outcome<bool> {
+    if a.is_nan() || b.is_nan() {
+        let invalid = signaling || a.is_signaling() || b.is_signaling();
+        return (false, if invalid { FLAG_INVALID } else { 0 });
+    }
+    let result = a
+        .partial_cmp(&b)
+        .is_some_and(|order| ordering.contains(&order));
+    (result, 0)
+}
+
+/// fclass: a mask with one bit set for the value's category
+pub fn classify<T: Float>(a: T) -> u32 {
+    let negative = a.is_sign_negative();
+    let bit = match a.classify() {
+        FpCategory::Infinite if negative => 0,
+        FpCategory::Normal if negative => 1,
+        FpCategory::Subnormal if negative => 2,
+        FpCategory::Zero if negative => 3,
+        FpCategory::Zero => 4,
+        FpCategory::Subnormal => 5,
+        FpCategory::Normal => 6,
+        FpCategory::Infinite => 7,
+        FpCategory::Nan if a.is_signaling() => 8,
+        FpCategory::Nan => 9,
+    };
+    1 << bit
+}
+
+/// fcvt.w and fcvt.wu: NaNs and out of range values saturate and are invalid
+pub fn to_integer<T: Float>(a: T, mode: RoundingMode, signed: bool) -> Outcome<u32> {
+    let value = a.to_f64();
+    let rounded = match mode {
+        RoundingMode::NearestEven => value.round_ties_even(),
+        RoundingMode::TowardZero => value.trunc(),
+        RoundingMode::Down => value.floor(),
+        RoundingMode::Up => value.ceil(),
+        RoundingMode::NearestMaxMagnitude => value.round(),
+    };
+    let (min, max) = if signed {
+        (i32::MIN as f64, i32::MAX as f64)
+    } else {
+        (0.0, u32::MAX as f64)
+    };
+    let saturated = |value: f64| {
+        if signed {
+            value as i32 as u32
+        } else {
+            value as u32
+        }
+    };
+    if value.is_nan() {
+        return (saturated(max), FLAG_INVALID);
+    }
+    if rounded < min || rounded > max {
+        let limit = if rounded < min { min } else { max };
+        return (saturated(limit), FLAG_INVALID);
+    }
+    let flags = if rounded != value { FLAG_INEXACT } else { 0 };
+    (saturated(rounded), flags)
+}
+
+/// fcvt.s.d, and through it conversions of integers to single precision
+pub fn narrow(a: f64, mode: RoundingMode) -> Outcome<f32> {
+    if let Some(nan) = propagate_nan(&[a]) {
+        return (f32::canonical_nan(), nan.1);
+    }
+    let result = a as f32;
+    if result.is_infinite() {
+        return round(result, Ordering::Equal, false, mode, a.is_finite());
+    }
+    let error = a - result as f64;
+    let tie = match direction(error) {
+        Ordering::Greater => result.next_up() as f64 - result as f64 == error + error,
+        Ordering::Less => result.next_down() as f64 - result as f64 == error + error,
+        Ordering::Equal => false,
+    };
+    round(result, direction(error), tie, mode, true)
+}
+
+/// fcvt.d.s, always exact
+pub fn widen(a: f32) -> Outcome<f64> {
+    match propagate_nan(&[a]) {
+        Some((_, flags)) => (f64::canonical_nan(), flags),
+        None => (a as f64, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use RoundingMode::*;
+
+    #[test]
+    fn test_rounding_modes() {
+        // 1 + 2^-24 lies exactly halfway between 1 and the next single precision value
+        let halfway = f32::EPSILON / 2.0;
+        assert_eq!(add(1.0f32, halfway, NearestEven), (1.0, FLAG_INEXACT));
+        assert_eq!(
+            add(1.0f32, halfway, NearestMaxMagnitude),
+            (1.0 + f32::EPSILON, FLAG_INEXACT)
+        );
+        assert_eq!(add(1.0f32, halfway, Up), (1.0 + f32::EPSILON, FLAG_INEXACT));
+        assert_eq!(
+            add(-1.0f32, -halfway, Down),
+            (-1.0 - f32::EPSILON, FLAG_INEXACT)
+        );
+        assert_eq!(add(-1.0f32, -halfway, TowardZero), (-1.0, FLAG_INEXACT));
+        assert_eq!(add(1.5f32, 2.25, Up), (3.75, 0));
+
+        // 1/3 rounds down to nearest, so rounding up takes the next value
+        let (nearest, _) = div(1.0f64, 3.0, NearestEven);
+        assert_eq!(div(1.0f64, 3.0, Up), (nearest.next_up(), FLAG_INEXACT));
+        assert_eq!(div(1.0f64, 3.0, Down), (nearest, FLAG_INEXACT));
+        assert_eq!(div(-1.0f64, 3.0, TowardZero), (-nearest, FLAG_INEXACT));
+
+        let (nearest, _) = sqrt(2.0f32, NearestEven);
+        assert_eq!(sqrt(2.0f32, Up), (nearest.next_up(), FLAG_INEXACT));
+        assert_eq!(sqrt(2.0f32, Down), (nearest, FLAG_INEXACT));
+        assert_eq!(sqrt(4.0f32, Down), (2.0, 0));
+
+        // 0.1 as a double isn't a single precision value
+        let (down, _) = narrow(0.1, Down);
+        let (up, _) = narrow(0.1, Up);
+        assert_eq!(down.next_up(), up);
+        assert_eq!(narrow(0.5, TowardZero), (0.5, 0));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        assert_eq!(
+            mul(f32::MAX, 2.0, NearestEven),
+            (f32::INFINITY, FLAG_OVERFLOW | FLAG_INEXACT)
+        );
+        assert_eq!(
+            mul(f32::MAX, 2.0, TowardZero),
+            (f32::MAX, FLAG_OVERFLOW | FLAG_INEXACT)
+        );
+        assert_eq!(
+            mul(-f32::MAX, 2.0, Up),
+            (-f32::MAX, FLAG_OVERFLOW | FLAG_INEXACT)
+        );
+        assert_eq!(
+            mul(f64::MIN_POSITIVE, 0.3, NearestEven).1,
+            FLAG_UNDERFLOW | FLAG_INEXACT
+        );
+        assert_eq!(
+            div(1.0f32, 0.0, NearestEven),
+            (f32::INFINITY, FLAG_DIVIDE_BY_ZERO)
+        );
+        assert_eq!(div(0.0f32, 0.0, NearestEven).1, FLAG_INVALID);
+        assert_eq!(sqrt(-1.0f64, NearestEven).1, FLAG_INVALID);
+        assert_eq!(
+            add(f64::INFINITY, f64::NEG_INFINITY, NearestEven).1,
+            FLAG_INVALID
+        );
+        assert_eq!(add(1.0f32, -1.0, Down), (-0.0, 0));
+        assert!(add(1.0f32, -1.0, Down).0.is_sign_negative());
+        assert!(!add(1.0f32, -1.0, NearestEven).0.is_sign_negative());
+
+        // Quiet NaNs propagate silently as the canonical NaN, signaling ones are invalid
+        let quiet = f32::NAN;
+        let signaling = f32::from_bits(0x7f80_0001);
+        assert_eq!(add(quiet, 1.0, NearestEven).0.to_bits(), 0x7fc0_0000);
+        assert_eq!(add(quiet, 1.0, NearestEven).1, 0);
+        assert_eq!(add(signaling, 1.0, NearestEven).1, FLAG_INVALID);
+        assert_eq!(
+            fused_multiply_add(f32::INFINITY, 0.0, quiet, NearestEven).1,
+            FLAG_INVALID
+        );
+        assert_eq!(fused_multiply_add(2.0f64, 3.0, 1.0, NearestEven), (7.0, 0));
+    }
+
+    #[test]
+    fn test_comparisons_and_classes() {
+        assert_eq!(
+            min_max(-0.0f32, 0.0, false).0.to_bits(),
+            (-0.0f32).to_bits()
+        );
+        assert_eq!(min_max(-0.0f32, 0.0, true).0.to_bits(), 0);
+        assert_eq!(min_max(f64::NAN, 2.0, false), (2.0, 0));
+        assert_eq!(
+            min_max(f64::NAN, f64::NAN, true).0.to_bits(),
+            0x7ff8_0000_0000_0000
+        );
+
+        let order_lt = [Ordering::Less];
+        assert_eq!(compare(1.0f32, 2.0, &order_lt, true), (true, 0));
+        assert_eq!(
+            compare(f32::NAN, 2.0, &order_lt, true),
+            (false, FLAG_INVALID)
+        );
+        assert_eq!(
+            compare(f32::NAN, 2.0, &[Ordering::Equal], false),
+            (false, 0)
+        );
+
+        assert_eq!(classify(f32::NEG_INFINITY), 1 << 0);
+        assert_eq!(classify(-0.0f64), 1 << 3);
+        assert_eq!(classify(f64::MIN_POSITIVE / 2.0), 1 << 5);
+        assert_eq!(classify(f32::from_bits(0x7f80_0001)), 1 << 8);
+        assert_eq!(classify(f32::NAN), 1 << 9);
+    }
+
+    #[test]
+    fn test_integer_conversions() {
+        assert_eq!(to_integer(2.5f32, NearestEven, true), (2, FLAG_INEXACT));
+        assert_eq!(
+            to_integer(2.5f32, NearestMaxMagnitude, true),
+            (3, FLAG_INEXACT)
+        );
+        assert_eq!(
+            to_integer(-2.5f64, Down, true),
+            (-3i32 as u32, FLAG_INEXACT)
+        );
+        assert_eq!(
+            to_integer(-2.5f64, TowardZero, true),
+            (-2i32 as u32, FLAG_INEXACT)
+        );
+        assert_eq!(to_integer(7.0f64, Up, false), (7, 0));
+        assert_eq!(to_integer(-1.0f32, NearestEven, false), (0, FLAG_INVALID));
+        assert_eq!(
+            to_integer(1e10f64, NearestEven, true),
+            (i32::MAX as u32, FLAG_INVALID)
+        );
+        assert_eq!(
+            to_integer(f32::NAN, NearestEven, true),
+            (i32::MAX as u32, FLAG_INVALID)
+        );
+        assert_eq!(
+            to_integer(f32::NAN, NearestEven, false),
+            (u32::MAX, FLAG_INVALID)
+        );
+        assert_eq!(to_integer(-0.25f32, NearestEven, false), (0, FLAG_INEXACT));
+
+        // 2^24 + 1 has no single precision representation
+        assert_eq!(
+            narrow(16_777_217.0, NearestEven),
+            (16_777_216.0, FLAG_INEXACT)
+        );
+        assert_eq!(narrow(16_777_217.0, Up), (16_777_218.0, FLAG_INEXACT));
+        assert_eq!(widen(1.5), (1.5, 0));
+    }
+
+    #[test]
+    fn test_nan_boxing() {
+        assert_eq!(1.5f32.boxed(), 0xffff_ffff_3fc0_0000);
+        assert_eq!(f32::unbox(0xffff_ffff_3fc0_0000), 1.5);
+        // A double in the register isn't a valid single
+        assert_eq!(f32::unbox(1.5f64.to_bits()).to_bits(), 0x7fc0_0000);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+/// Host input read on a background thread.
+///
+/// Reading stdin (or a socket) blocks, so the blocking read happens on its own thread and
+/// the bytes are handed over through a channel. Devices poll with [`BackgroundReader::try_read`],
+/// which never blocks, so a guest waiting for input doesn't freeze the step loop or a frontend.
+pub struct BackgroundReader {
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    /// Set once the reader hit end of input (or an error)
+    disconnected: bool,
+}
+
+impl BackgroundReader {
+    pub fn spawn<R: Read + Send + 'static>(mut reader: R) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(count) => {
+                        if sender.send(buffer[..count].to_vec()).is_err() {
+                            // Receiver dropped, nobody wants the input anymore
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            receiver,
+            pending: VecDeque::new(),
+            disconnected: false,
+        }
+    }
+
+    pub fn stdin() -> Self {
+        Self::spawn(std::io::stdin())
+    }
+
+    /// Returns the next input byte if one has already arrived
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.pending.is_empty() {
+            self.poll();
+        }
+        self.pending.pop_front()
+    }
+
+    /// Waits at most `timeout` for the next input byte
+    pub fn read_timeout(&mut self, timeout: Duration) -> Option<u8> {
+        if self.pending.is_empty() && !self.disconnected {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(RecvTimeoutError::Disconnected) => self.disconnected = true,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+        self.try_read()
+    }
+
+    /// Whether input bytes are ready to be read without waiting
+    pub fn has_data(&mut self) -> bool {
+        if self.pending.is_empty() {
+            self.poll();
+        }
+        !self.pending.is_empty()
+    }
+
+    /// True once the input stream has ended and every byte has been consumed
+    pub fn is_exhausted(&mut self) -> bool {
+        !self.has_data() && self.disconnected
+    }
+
+    fn poll(&mut self) {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Host pseudo-terminal, users attach to its slave side with `screen`, `picocom`, ...
+#[cfg(unix)]
+pub struct Pty {
+    /// Master side, read and written by the emulator
+    pub master: std::fs::File,
+    /// Held open so the master stays usable before (and between) terminals attaching
+    pub slave: std::fs::File,
+    pub path: std::path::PathBuf,
+}
+
+/// Opens a raw-mode pseudo-terminal
+#[cfg(unix)]
+pub fn open_pty() -> io::Result<Pty> {
+    use std::{
+        ffi::CStr,
+        fs::{File, OpenOptions},
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    // SAFETY: plain libc calls, every return value is checked and the master fd is owned by the
+    // File right after it's created
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 128];
+        if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let path =
+            std::path::PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().as_ref());
+
+        let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+        // Raw mode: no echo or line buffering, the guest sees every key as it's typed
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Pty {
+            master,
+            slave,
+            path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::mpsc::Sender, time::Instant};
+
+    use super::*;
+
+    /// Reader that blocks until the test feeds it data
+    struct ChannelReader(Receiver<Vec<u8>>);
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.recv() {
+                Ok(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    fn blocking_reader() -> (Sender<Vec<u8>>, BackgroundReader) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, BackgroundReader::spawn(ChannelReader(receiver)))
+    }
+
+    #[test]
+    fn test_reads_all_input() {
+        let mut reader = BackgroundReader::spawn(Cursor::new(b"hi\n".to_vec()));
+        let mut input = Vec::new();
+        while let Some(byte) = reader.read_timeout(Duration::from_secs(1)) {
+            input.push(byte);
+        }
+        assert_eq!(input, b"hi\n");
+        assert!(reader.is_exhausted());
+    }
+
+    #[test]
+    fn test_waiting_for_input_does_not_block() {
+        let (sender, mut reader) = blocking_reader();
+
+        let start = Instant::now();
+        assert_eq!(reader.try_read(), None);
+        assert!(!reader.has_data());
+        assert!(!reader.is_exhausted());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        sender.send(b"x".to_vec()).unwrap();
+        assert_eq!(reader.read_timeout(Duration::from_secs(1)), Some(b'x'));
+
+        drop(sender);
+        assert_eq!(reader.read_timeout(Duration::from_secs(1)), None);
+        assert!(reader.is_exhausted());
+    }
+}
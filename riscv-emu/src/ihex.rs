@@ -0,0 +1,175 @@
+//! Intel HEX reading and writing, the 32 bit (I32HEX) variant
+
+use crate::image::{Image, ImageError};
+
+/// Data bytes per record, what most tools emit
+const BYTES_PER_RECORD: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Parses Intel HEX text, records after the end-of-file record are ignored
+pub fn parse(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::new();
+    let mut base: u32 = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| ImageError::Parse {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("record does not start with ':'".to_string()))?;
+        let bytes = decode_hex(record).ok_or_else(|| error("invalid hex digits".to_string()))?;
+        // Length, 16 bit address, type and checksum
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error(
+                "record length does not match its byte count".to_string(),
+            ));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("checksum mismatch".to_string()));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => image
+                .add_segment(base.wrapping_add(offset), data.to_vec())
+                .map_err(|source| error(source.to_string()))?,
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+                image.entry = Some((segment << 4) + offset);
+            }
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                image.entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            kind => return Err(error(format!("unsupported record type {:02x}", kind))),
+        }
+    }
+    Ok(image)
+}
+
+/// Writes the image as Intel HEX with extended linear address records where needed
+pub fn write(image: &Image) -> String {
+    let mut text = String::new();
+    let mut base: u32 = 0;
+
+    for segment in image.segments() {
+        let mut address = segment.address;
+        let mut data = &segment.data[..];
+        while !data.is_empty() {
+            let upper = address & 0xffff_0000;
+            if upper != base {
+                write_record(
+                    &mut text,
+                    EXTENDED_LINEAR_ADDRESS,
+                    0,
+                    &((upper >> 16) as u16).to_be_bytes(),
+                );
+                base = upper;
+            }
+            // Records never cross a 64KiB boundary
+            let to_boundary = 0x1_0000 - (address & 0xffff) as usize;
+            let len = data.len().min(BYTES_PER_RECORD).min(to_boundary);
+            write_record(&mut text, DATA, address as u16, &data[..len]);
+            data = &data[len..];
+            address = address.wrapping_add(len as u32);
+        }
+    }
+    if let Some(entry) = image.entry {
+        write_record(&mut text, START_LINEAR_ADDRESS, 0, &entry.to_be_bytes());
+    }
+    write_record(&mut text, END_OF_FILE, 0, &[]);
+    text
+}
+
+fn write_record(text: &mut String, kind: u8, offset: u16, data: &[u8]) {
+    let mut record = vec![data.len() as u8];
+    record.extend(offset.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+
+    text.push(':');
+    for byte in record {
+        text.push_str(&format!("{:02X}", byte));
+    }
+    text.push('\n');
+}
+
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_known_records() {
+        let mut image = Image::from_binary(&[0x13, 0x05, 0xa0, 0x02], 0).unwrap();
+        image.entry = Some(0);
+        assert_eq!(
+            write(&image),
+            ":040000001305A00242\n:0400000500000000F7\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_round_trip_across_64k_boundary() {
+        let mut image = Image::new();
+        image
+            .add_segment(0xfff8, (0..40).collect::<Vec<u8>>())
+            .unwrap();
+        image.add_segment(0x8000_0000, vec![1, 2, 3]).unwrap();
+        image.entry = Some(0x8000_0000);
+
+        let text = write(&image);
+        assert!(text.contains(":020000040001F9\n"));
+        assert_eq!(parse(&text).unwrap(), image);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            parse(":040000001305A0022B\n"),
+            Err(ImageError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            parse(":00000001FF\n0400000013"),
+            Ok(image) if image.is_empty()
+        ));
+        assert!(matches!(
+            parse("\n:0300000013\n"),
+            Err(ImageError::Parse { line: 2, .. })
+        ));
+    }
+}
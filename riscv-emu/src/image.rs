@@ -0,0 +1,347 @@
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{ihex, memh, srec};
+
+/// Contiguous bytes placed at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// One past the last byte, 64 bit so a segment ending at 4GiB doesn't wrap
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// A memory image in a file-format independent form: sorted, non-overlapping segments and an
+/// optional entry point.
+///
+/// Images convert between the formats the tools exchange, e.g. a flat binary and Intel HEX,
+/// and can be padded or aligned on the way.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    segments: Vec<Segment>,
+    pub entry: Option<u32>,
+}
+
+/// On-disk representation of an [`Image`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Raw bytes, placed at a base address given separately
+    Binary,
+    IntelHex,
+    /// Motorola S-records
+    Srec,
+    /// Verilog `$readmemh` text of 32 bit words, for FPGA soft cores and testbenches
+    Memh,
+}
+
+/// Why an image couldn't be built or parsed
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// Two segments cover the same address
+    #[error("overlapping data at {address:#010x}")]
+    Overlap { address: u32 },
+    /// The image extends past the 32 bit address space
+    #[error("{len} bytes at {address:#010x} extend past the 32 bit address space")]
+    AddressOverflow { address: u32, len: usize },
+    /// A malformed record, `line` is 1-based
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+impl ImageFormat {
+    /// Guesses the format from a file extension, anything unknown is a flat binary
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("hex" | "ihex" | "ihx") => ImageFormat::IntelHex,
+            Some("srec" | "sre" | "s19" | "s28" | "s37" | "mot") => ImageFormat::Srec,
+            Some("mem" | "memh" | "vmem") => ImageFormat::Memh,
+            _ => ImageFormat::Binary,
+        }
+    }
+
+    /// Recognizes Intel HEX and S-record text by their first record, and `$readmemh` text
+    /// starting with an address, anything else is binary
+    pub fn detect(bytes: &[u8]) -> Self {
+        let text = bytes.trim_ascii_start();
+        let first_line = text.split(|byte| *byte == b'\n').next().unwrap_or_default();
+        let first_line = first_line.trim_ascii_end();
+        let is_hex = |digits: &[u8]| !digits.is_empty() && digits.iter().all(u8::is_ascii_hexdigit);
+        match first_line {
+            [b':', digits @ ..] if is_hex(digits) => ImageFormat::IntelHex,
+            [b'S', kind, digits @ ..] if kind.is_ascii_digit() && is_hex(digits) => {
+                ImageFormat::Srec
+            }
+            [b'@', digits @ ..] if is_hex(digits) => ImageFormat::Memh,
+            _ => ImageFormat::Binary,
+        }
+    }
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A flat binary loaded at `address`
+    pub fn from_binary(bytes: &[u8], address: u32) -> Result<Self, ImageError> {
+        let mut image = Self::new();
+        image.add_segment(address, bytes.to_vec())?;
+        Ok(image)
+    }
+
+    /// Parses `bytes` in `format`, `base` is only used by formats without addresses
+    pub fn parse(bytes: &[u8], format: ImageFormat, base: u32) -> Result<Self, ImageError> {
+        match format {
+            ImageFormat::Binary => Self::from_binary(bytes, base),
+            ImageFormat::IntelHex => ihex::parse(&String::from_utf8_lossy(bytes)),
+            ImageFormat::Srec => srec::parse(&String::from_utf8_lossy(bytes)),
+            ImageFormat::Memh => memh::parse(&String::from_utf8_lossy(bytes)),
+        }
+    }
+
+    /// Serializes the image, gaps in flat binaries and partial `$readmemh` words are filled
+    /// with `fill`
+    pub fn write(&self, format: ImageFormat, fill: u8) -> Vec<u8> {
+        match format {
+            ImageFormat::Binary => self.to_binary(fill),
+            ImageFormat::IntelHex => ihex::write(self).into_bytes(),
+            ImageFormat::Srec => srec::write(self).into_bytes(),
+            ImageFormat::Memh => memh::write(self, fill).into_bytes(),
+        }
+    }
+
+    /// Segments sorted by address
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Adds `data` at `address`, merging it with a segment it directly follows or precedes
+    pub fn add_segment(&mut self, address: u32, data: Vec<u8>) -> Result<(), ImageError> {
+        if address as u64 + data.len() as u64 > 1 << 32 {
+            return Err(ImageError::AddressOverflow {
+                address,
+                len: data.len(),
+            });
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let segment = Segment { address, data };
+        let index = self
+            .segments
+            .partition_point(|existing| existing.address < address);
+        if let Some(previous) = index.checked_sub(1).map(|index| &self.segments[index])
+            && previous.end() > address as u64
+        {
+            return Err(ImageError::Overlap { address });
+        }
+        if let Some(next) = self.segments.get(index)
+            && (next.address as u64) < segment.end()
+        {
+            return Err(ImageError::Overlap {
+                address: next.address,
+            });
+        }
+
+        self.segments.insert(index, segment);
+        // Merge with the neighbours so consecutive records become one segment
+        if index + 1 < self.segments.len()
+            && self.segments[index].end() == self.segments[index + 1].address as u64
+        {
+            let next = self.segments.remove(index + 1);
+            self.segments[index].data.extend(next.data);
+        }
+        if index > 0 && self.segments[index - 1].end() == self.segments[index].address as u64 {
+            let current = self.segments.remove(index);
+            self.segments[index - 1].data.extend(current.data);
+        }
+        Ok(())
+    }
+
+    /// Lowest address holding data
+    pub fn start(&self) -> Option<u32> {
+        self.segments.first().map(|segment| segment.address)
+    }
+
+    /// One past the highest address holding data
+    pub fn end(&self) -> Option<u64> {
+        self.segments.last().map(Segment::end)
+    }
+
+    /// Total bytes of data, not counting gaps
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Flattens the image from its lowest address, filling gaps with `fill`
+    pub fn to_binary(&self, fill: u8) -> Vec<u8> {
+        let Some(start) = self.start() else {
+            return Vec::new();
+        };
+        let mut bytes = Vec::new();
+        for segment in &self.segments {
+            bytes.resize((segment.address - start) as usize, fill);
+            bytes.extend_from_slice(&segment.data);
+        }
+        bytes
+    }
+
+    /// Fills every gap between segments with `fill`, leaving a single segment
+    pub fn fill_gaps(&mut self, fill: u8) {
+        if let Some(start) = self.start() {
+            let data = self.to_binary(fill);
+            self.segments = vec![Segment {
+                address: start,
+                data,
+            }];
+        }
+    }
+
+    /// The data of the image from `address` up to (but not including) `end`, with the same
+    /// entry point
+    pub fn extract(&self, address: u32, end: u64) -> Image {
+        let segments = self
+            .segments
+            .iter()
+            .filter_map(|segment| {
+                let start = segment.address.max(address);
+                let stop = segment.end().min(end);
+                (u64::from(start) < stop).then(|| Segment {
+                    address: start,
+                    data: segment.data[(start - segment.address) as usize..]
+                        [..(stop - u64::from(start)) as usize]
+                        .to_vec(),
+                })
+            })
+            .collect();
+        Image {
+            segments,
+            entry: self.entry,
+        }
+    }
+
+    /// Extends the image with `fill` up to (but not including) `end`
+    pub fn pad_to(&mut self, end: u32, fill: u8) {
+        match self.segments.last_mut() {
+            Some(last) if last.end() < end as u64 => {
+                let len = (end - last.address) as usize;
+                last.data.resize(len, fill);
+            }
+            _ => {}
+        }
+    }
+
+    /// Pads the end of the image with `fill` to a multiple of `alignment` bytes
+    pub fn align_end(&mut self, alignment: u32, fill: u8) {
+        if let Some(end) = self.end()
+            && alignment > 1
+        {
+            let aligned = end.div_ceil(alignment as u64) * alignment as u64;
+            // Alignment can't leave the address space, an end of 4GiB is already aligned
+            self.pad_to(aligned.min(u32::MAX as u64) as u32, fill);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::xorshift32;
+
+    #[test]
+    fn test_adjacent_segments_merge() {
+        let mut image = Image::new();
+        image.add_segment(0x10, vec![3, 4]).unwrap();
+        image.add_segment(0x0c, vec![1, 2, 0, 0]).unwrap();
+        image.add_segment(0x20, vec![9]).unwrap();
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.segments()[0].data, [1, 2, 0, 0, 3, 4]);
+        assert_eq!(
+            image.add_segment(0x0e, vec![7]),
+            Err(ImageError::Overlap { address: 0x0e })
+        );
+    }
+
+    #[test]
+    fn test_flatten_fills_gaps() {
+        let mut image = Image::from_binary(&[1, 2], 0x100).unwrap();
+        image.add_segment(0x104, vec![5]).unwrap();
+        assert_eq!(image.to_binary(0xff), [1, 2, 0xff, 0xff, 5]);
+        image.fill_gaps(0);
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.len(), 5);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(ImageFormat::detect(b":00000001FF\n"), ImageFormat::IntelHex);
+        assert_eq!(
+            ImageFormat::detect(b"\r\nS0030000FC\r\nS9030000FC"),
+            ImageFormat::Srec
+        );
+        // addi a0, zero, 42 is not text
+        assert_eq!(
+            ImageFormat::detect(&[0x13, 0x05, 0xa0, 0x02]),
+            ImageFormat::Binary
+        );
+        assert_eq!(ImageFormat::detect(b":not hex"), ImageFormat::Binary);
+        assert_eq!(
+            ImageFormat::detect(b"@00000000\n00000013"),
+            ImageFormat::Memh
+        );
+    }
+
+    #[test]
+    fn test_pad_and_align() {
+        let mut image = Image::from_binary(&[1, 2, 3], 0x1000).unwrap();
+        image.align_end(4, 0);
+        assert_eq!(image.to_binary(0), [1, 2, 3, 0]);
+        image.pad_to(0x1008, 0xee);
+        assert_eq!(image.end(), Some(0x1008));
+        // Padding never truncates
+        image.pad_to(0x1002, 0);
+        assert_eq!(image.len(), 8);
+    }
+
+    #[test]
+    fn test_extract() {
+        let mut image = Image::from_binary(&[1, 2, 3, 4], 0x1000).unwrap();
+        image.add_segment(0x2000, vec![5, 6]).unwrap();
+        image.entry = Some(0x1000);
+        let part = image.extract(0x1002, 0x2001);
+        assert_eq!(part.segments().len(), 2);
+        assert_eq!(part.start(), Some(0x1002));
+        assert_eq!(part.to_binary(0).len(), 0xfff);
+        assert_eq!(part.entry, Some(0x1000));
+        assert!(image.extract(0x1004, 0x2000).is_empty());
+        assert_eq!(image.extract(0, 1 << 32), image);
+    }
+
+    #[test]
+    fn test_corrupt_records_never_panic() {
+        let records = ":10FFF800000102030405060708090A0B0C0D0E0F78\n:0400000508000000EF\n\
+                       S30980000000130520023C\nS705800000007A\n";
+        let mut next = xorshift32(0x1234_5678);
+        for _ in 0..2000 {
+            let mut bytes = records.as_bytes().to_vec();
+            for _ in 0..next() % 4 {
+                let index = next() as usize % bytes.len();
+                bytes[index] = b"0123456789ABCDEFS:\n"[next() as usize % 19];
+            }
+            for format in [ImageFormat::IntelHex, ImageFormat::Srec, ImageFormat::Memh] {
+                let _ = Image::parse(&bytes, format, 0);
+            }
+        }
+    }
+}
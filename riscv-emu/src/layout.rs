@@ -0,0 +1,157 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// What a memory region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegionKind {
+    /// Code or data loaded from an image
+    Image,
+    Stack,
+    Heap,
+    /// Memory-mapped device registers, outside of RAM
+    Device,
+}
+
+/// A named address range in the machine's memory map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub kind: RegionKind,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub fn new(name: &str, kind: RegionKind, start: u32, size: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            start,
+            size,
+        }
+    }
+
+    /// One past the last byte, 64 bit so a region ending at 4GiB doesn't wrap
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.size as u64
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        (self.start as u64) < other.end() && (other.start as u64) < self.end()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:#010x}..{:#010x})",
+            self.name,
+            self.start,
+            self.end()
+        )
+    }
+}
+
+/// A memory map that can't work
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    #[error("{first} overlaps {second}")]
+    Overlap { first: Region, second: Region },
+    /// A RAM region extends past the end of memory
+    #[error("{region} does not fit in {memory_size:#x} bytes of memory")]
+    OutOfMemory { region: Region, memory_size: usize },
+}
+
+/// Everything placed in a machine's address space, checked as a whole before a run starts.
+///
+/// Images, stack and heap live in RAM (`0..memory_size`) and must fit there. Devices may sit
+/// anywhere. No two regions may share an address, so a stack growing into data or an image
+/// loaded over a device register map is reported up front instead of corrupting state later.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryLayout {
+    memory_size: usize,
+    regions: Vec<Region>,
+}
+
+impl MemoryLayout {
+    pub fn new(memory_size: usize) -> Self {
+        Self {
+            memory_size,
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, region: Region) {
+        self.regions.push(region);
+    }
+
+    /// Regions in the order they were added
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// First address past every RAM region, where free memory starts
+    pub fn ram_end(&self) -> u64 {
+        self.regions
+            .iter()
+            .filter(|region| region.kind != RegionKind::Device)
+            .map(Region::end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Reports the lowest region that doesn't fit or overlaps another one
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        let mut regions: Vec<&Region> = self.regions.iter().collect();
+        regions.sort_by_key(|region| (region.start, region.end()));
+        for (index, region) in regions.iter().enumerate() {
+            if region.kind != RegionKind::Device && region.end() > self.memory_size as u64 {
+                return Err(LayoutError::OutOfMemory {
+                    region: (*region).clone(),
+                    memory_size: self.memory_size,
+                });
+            }
+            if let Some(other) = regions[index + 1..]
+                .iter()
+                .find(|other| region.overlaps(other))
+            {
+                return Err(LayoutError::Overlap {
+                    first: (*region).clone(),
+                    second: (*other).clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut layout = MemoryLayout::new(0x10000);
+        layout.add(Region::new("program", RegionKind::Image, 0, 0x100));
+        layout.add(Region::new("stack", RegionKind::Stack, 0xf000, 0x1000));
+        layout.add(Region::new("uart", RegionKind::Device, 0x1000_0000, 0x100));
+        assert_eq!(layout.validate(), Ok(()));
+        assert_eq!(layout.ram_end(), 0x10000);
+
+        let mut heap = layout.clone();
+        heap.add(Region::new("heap", RegionKind::Heap, 0x100, 0xf000));
+        assert_eq!(
+            heap.validate().unwrap_err().to_string(),
+            "heap (0x00000100..0x0000f100) overlaps stack (0x0000f000..0x00010000)"
+        );
+
+        let mut data = layout.clone();
+        data.add(Region::new("data", RegionKind::Image, 0x10000, 0x10));
+        assert!(matches!(
+            data.validate(),
+            Err(LayoutError::OutOfMemory { region, .. }) if region.name == "data"
+        ));
+    }
+}
@@ -1,2 +1,40 @@
+pub mod abi;
+mod assertions;
+pub mod bank;
+pub mod bus;
+pub mod clint;
+pub mod compressed;
+pub mod console;
+pub mod constant_time;
+pub mod core_file;
 pub mod cpu;
-
+pub mod csr;
+pub mod custom;
+pub mod debugger;
+pub mod digest;
+pub mod elf;
+pub mod error;
+pub mod explain;
+mod float;
+pub mod host_io;
+mod ihex;
+pub mod image;
+pub mod layout;
+pub mod line_map;
+pub mod linux;
+pub mod loops;
+pub mod machine;
+mod memh;
+pub mod metrics;
+pub mod plic;
+pub mod shadow;
+mod srec;
+pub mod symbols;
+pub mod syscalls;
+pub mod tags;
+#[cfg(test)]
+mod test_support;
+pub mod trace;
+pub mod trace_file;
+pub mod uart;
+pub mod uninit;
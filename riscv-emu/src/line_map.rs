@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, path::Path};
+
+/// A line of a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    /// 1-based
+    pub line: u64,
+}
+
+/// Source line of every instruction address, for source-level debugging
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineMap {
+    by_address: BTreeMap<u32, SourceLine>,
+}
+
+impl LineMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: u32, file: &str, line: u64) {
+        self.by_address.insert(
+            address,
+            SourceLine {
+                file: file.to_string(),
+                line,
+            },
+        );
+    }
+
+    /// Adds the lines of another program, e.g. an image loaded next to this one
+    pub fn extend(&mut self, other: LineMap) {
+        self.by_address.extend(other.by_address);
+    }
+
+    /// Source line of the instruction at `address`
+    pub fn line_at(&self, address: u32) -> Option<&SourceLine> {
+        self.by_address.get(&address)
+    }
+
+    /// First address of each block of instructions generated for `line`.
+    ///
+    /// `file` matches by trailing path components, so `main.s` finds `src/main.s`. Without a
+    /// file every file is searched.
+    pub fn addresses_for_line(&self, file: Option<&str>, line: u64) -> Vec<u32> {
+        let mut addresses = Vec::new();
+        let mut previous: Option<&SourceLine> = None;
+        for (address, source) in &self.by_address {
+            let matches = source.line == line
+                && file.is_none_or(|file| Path::new(&source.file).ends_with(file));
+            // Pseudoinstructions expand to several instructions, only stop at the first
+            if matches && previous != Some(source) {
+                addresses.push(*address);
+            }
+            previous = Some(source);
+        }
+        addresses
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addresses_for_line() {
+        let mut map = LineMap::new();
+        map.insert(0, "src/main.s", 1);
+        map.insert(4, "src/main.s", 3);
+        map.insert(8, "src/main.s", 3);
+        map.insert(12, "lib/util.s", 3);
+        map.insert(16, "src/main.s", 3);
+
+        assert_eq!(map.addresses_for_line(Some("main.s"), 3), [4, 16]);
+        assert_eq!(map.addresses_for_line(Some("ain.s"), 3), Vec::<u32>::new());
+        assert_eq!(map.addresses_for_line(None, 3), [4, 12, 16]);
+        assert_eq!(map.line_at(8).map(|source| source.line), Some(3));
+    }
+}
@@ -0,0 +1,694 @@
+//! Linux system calls for statically linked user-mode programs, run the way qemu-user runs
+//! them: the program's `ecall`s are carried out by the host rather than by a kernel inside
+//! the machine.
+//!
+//! Numbers and structure layouts are those of the generic RISC-V Linux ABI, with `long`s
+//! XLEN bits wide. File descriptors 0 to 2 are the console, other files can only be opened
+//! below a host directory given with [`LinuxSyscalls::with_root`]. Clocks count retired
+//! instructions at a nominal 1 GHz and `getrandom` returns a fixed sequence, so runs stay
+//! reproducible. Calls that aren't emulated fail with ENOSYS, which C libraries cope with.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    error::EmuError,
+    machine::Machine,
+    syscalls::{SyscallHandler, SyscallOutcome},
+};
+
+pub const SYS_IOCTL: u32 = 29;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_WRITEV: u32 = 66;
+/// Only on RV64, 32 bit programs use statx
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_SET_ROBUST_LIST: u32 = 99;
+pub const SYS_CLOCK_GETTIME: u32 = 113;
+pub const SYS_RT_SIGACTION: u32 = 134;
+pub const SYS_RT_SIGPROCMASK: u32 = 135;
+pub const SYS_UNAME: u32 = 160;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_GETPID: u32 = 172;
+pub const SYS_GETUID: u32 = 174;
+pub const SYS_GETEUID: u32 = 175;
+pub const SYS_GETGID: u32 = 176;
+pub const SYS_GETEGID: u32 = 177;
+pub const SYS_GETTID: u32 = 178;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_MUNMAP: u32 = 215;
+/// Anonymous mappings only
+pub const SYS_MMAP: u32 = 222;
+pub const SYS_MPROTECT: u32 = 226;
+pub const SYS_GETRANDOM: u32 = 278;
+/// clock_gettime with 64 bit seconds, what 32 bit programs call
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+/// Auxiliary vector entries, see [`start_process`]
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_SECURE: u64 = 23;
+/// Address of 16 random bytes, for stack protector canaries and pointer mangling
+pub const AT_RANDOM: u64 = 25;
+
+pub const PAGE_SIZE: u32 = 4096;
+/// Room kept free below the initial stack pointer for the stack, mappings go below it
+pub const STACK_RESERVE: u32 = 1 << 20;
+/// Process and thread id the program sees
+pub const PID: i64 = 1;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const ENODEV: i64 = 19;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+
+/// `openat` relative to the current directory
+const AT_FDCWD: i64 = -100;
+const O_ACCMODE: u64 = 0o3;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const MAP_ANONYMOUS: u64 = 0x20;
+/// Longest path `openat` reads
+const PATH_MAX: usize = 4096;
+/// Most bytes one `read` takes from a file
+const MAX_READ: usize = 1 << 20;
+/// Nanoseconds a retired instruction takes on the nominal clock
+const NANOSECONDS_PER_INSTRUCTION: u64 = 1;
+
+/// The Linux system calls a C library's startup, stdio and malloc make, see the module
+/// documentation. Unknown calls return -ENOSYS rather than ending the run.
+#[derive(Debug, Default)]
+pub struct LinuxSyscalls {
+    /// Host directory files are opened in, `None` if the program can't open any
+    root: Option<PathBuf>,
+    /// Open files by descriptor, from 3 up
+    files: BTreeMap<u64, File>,
+    /// Where the heap starts and the current program break, set on the first `brk`
+    heap: Option<(u32, u32)>,
+    /// Lowest mapped address, anonymous mappings grow down from below the stack
+    mappings_start: Option<u32>,
+    /// State of the `getrandom` generator
+    random: u64,
+}
+
+impl LinuxSyscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the program open files below `root`, which its absolute paths are relative to
+    /// as well. Paths with `..` in them are refused.
+    pub fn with_root(self, root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: Some(root.into()),
+            ..self
+        }
+    }
+
+    /// Host path for the program's `path`, `None` if it's outside the root
+    fn host_path(&self, path: &Path) -> Option<PathBuf> {
+        let root = self.root.as_ref()?;
+        let mut host = root.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => host.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(host)
+    }
+
+    fn openat(&mut self, machine: &Machine, dirfd: i64, path: u32, flags: u64) -> i64 {
+        let Some(path) = read_c_string(machine, path) else {
+            return -EFAULT;
+        };
+        let path = PathBuf::from(String::from_utf8_lossy(&path).into_owned());
+        if dirfd != AT_FDCWD && !path.is_absolute() {
+            return -EBADF;
+        }
+        let Some(host) = self.host_path(&path) else {
+            return -EACCES;
+        };
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        options
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+        match options.open(host) {
+            Ok(file) => {
+                let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap_or(3);
+                self.files.insert(fd, file);
+                fd as i64
+            }
+            Err(error) => -errno(&error),
+        }
+    }
+
+    fn read(&mut self, machine: &mut Machine, fd: u64, buffer: u32, count: usize) -> i64 {
+        let bytes = match fd {
+            // A terminal hands over a line at a time
+            0 => {
+                let mut bytes = Vec::new();
+                let mut next = match count {
+                    0 => None,
+                    _ => machine.console.wait_byte(),
+                };
+                while let Some(byte) = next {
+                    bytes.push(byte);
+                    next = if byte == b'\n' || bytes.len() == count {
+                        None
+                    } else {
+                        machine.console.read_byte()
+                    };
+                }
+                bytes
+            }
+            _ => {
+                let Some(file) = self.files.get_mut(&fd) else {
+                    return -EBADF;
+                };
+                let mut bytes = vec![0; count.min(MAX_READ)];
+                match file.read(&mut bytes) {
+                    Ok(read) => bytes.truncate(read),
+                    Err(error) => return -errno(&error),
+                }
+                bytes
+            }
+        };
+        match machine.write_memory(buffer, &bytes) {
+            Ok(()) => bytes.len() as i64,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    fn write(&mut self, machine: &mut Machine, fd: u64, bytes: &[u8]) -> i64 {
+        match fd {
+            1 | 2 => machine.console.write(bytes),
+            _ => {
+                let Some(file) = self.files.get_mut(&fd) else {
+                    return -EBADF;
+                };
+                if let Err(error) = file.write_all(bytes) {
+                    return -errno(&error);
+                }
+            }
+        }
+        bytes.len() as i64
+    }
+
+    /// Gathers the buffers of an iovec array, pairs of a pointer and a length
+    fn writev(&mut self, machine: &mut Machine, fd: u64, vectors: u32, count: u64) -> i64 {
+        let word = word_size(machine);
+        let mut bytes = Vec::new();
+        for index in 0..count.min(1024) as u32 {
+            let entry = vectors.wrapping_add(index * 2 * word);
+            let (Some(base), Some(len)) = (
+                read_word(machine, entry),
+                read_word(machine, entry.wrapping_add(word)),
+            ) else {
+                return -EFAULT;
+            };
+            match machine.read_memory(base as u32, len as usize) {
+                Ok(buffer) => bytes.extend(buffer),
+                Err(_) => return -EFAULT,
+            }
+        }
+        self.write(machine, fd, &bytes)
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let position = match whence {
+            0 => match u64::try_from(offset) {
+                Ok(offset) => SeekFrom::Start(offset),
+                Err(_) => return -EINVAL,
+            },
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+        match self.files.get_mut(&fd) {
+            Some(file) => file
+                .seek(position)
+                .map_or_else(|error| -errno(&error), |position| position as i64),
+            None if fd <= 2 => -ESPIPE,
+            None => -EBADF,
+        }
+    }
+
+    /// Fills in the 128 byte RV64 `struct stat`
+    fn fstat(&self, machine: &mut Machine, fd: u64, buffer: u32) -> i64 {
+        // Character device for the console, regular file otherwise, read-write for the owner
+        let (mode, size) = match (fd, self.files.get(&fd)) {
+            (0..=2, _) => (0o020620, 0),
+            (_, Some(file)) => match file.metadata() {
+                Ok(metadata) if metadata.is_dir() => (0o040755, metadata.len()),
+                Ok(metadata) => (0o100644, metadata.len()),
+                Err(error) => return -errno(&error),
+            },
+            (_, None) => return -EBADF,
+        };
+        let mut stat = [0u8; 128];
+        stat[8..16].copy_from_slice(&(fd + 1).to_le_bytes());
+        stat[16..20].copy_from_slice(&(mode as u32).to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        stat[56..60].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        match machine.write_memory(buffer, &stat) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    /// Moves the program break to `address` if it's between the start of the heap and the
+    /// stack or the mappings, whichever is lower. Returns the new break, or the old one if
+    /// it can't move.
+    fn brk(&mut self, machine: &mut Machine, address: u32) -> u32 {
+        let (start, current) = *self.heap.get_or_insert_with(|| {
+            let start = machine
+                .layout()
+                .ram_end()
+                .next_multiple_of(PAGE_SIZE as u64);
+            let start = u32::try_from(start).unwrap_or(u32::MAX);
+            (start, start)
+        });
+        let limit = self.mappings_start.unwrap_or(stack_top(machine));
+        if address < start || address > limit {
+            return current;
+        }
+        // Memory the heap grows into reads as zero, even if it was in use before
+        if address > current
+            && machine
+                .write_memory(current, &vec![0; (address - current) as usize])
+                .is_err()
+        {
+            return current;
+        }
+        self.heap = Some((start, address));
+        address
+    }
+
+    /// Maps `len` bytes of zeroed memory below the earlier mappings
+    fn mmap(&mut self, machine: &mut Machine, len: u64, flags: u64) -> i64 {
+        if flags & MAP_ANONYMOUS == 0 {
+            return -ENODEV;
+        }
+        if len == 0 {
+            return -EINVAL;
+        }
+        let top = self.mappings_start.unwrap_or(stack_limit(machine));
+        let heap_end = self.heap.map_or(0, |(_, current)| current);
+        let Some(start) = u32::try_from(len.next_multiple_of(PAGE_SIZE as u64))
+            .ok()
+            .and_then(|len| top.checked_sub(len))
+            .filter(|&start| start >= heap_end)
+        else {
+            return -ENOMEM;
+        };
+        if machine
+            .write_memory(start, &vec![0; (top - start) as usize])
+            .is_err()
+        {
+            return -ENOMEM;
+        }
+        self.mappings_start = Some(start);
+        start as i64
+    }
+
+    fn getrandom(&mut self, machine: &mut Machine, buffer: u32, len: usize) -> i64 {
+        let mut bytes = vec![0; len.min(MAX_READ)];
+        fill_random(&mut self.random, &mut bytes);
+        match machine.write_memory(buffer, &bytes) {
+            Ok(()) => bytes.len() as i64,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    /// `struct utsname`, six NUL padded strings of 65 bytes
+    fn uname(machine: &mut Machine, buffer: u32) -> i64 {
+        let architecture = format!("riscv{}", machine.cpu.base_isa.xlen());
+        let fields = ["Linux", "riscv-emu", "6.6.0", "#1", &architecture, "(none)"];
+        let mut utsname = vec![0; 65 * fields.len()];
+        for (index, field) in fields.iter().enumerate() {
+            utsname[index * 65..index * 65 + field.len()].copy_from_slice(field.as_bytes());
+        }
+        match machine.write_memory(buffer, &utsname) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    /// Writes the time as two values of `width` bytes: seconds and the fraction of a second
+    /// in units of `fraction` nanoseconds
+    fn write_time(machine: &mut Machine, buffer: u32, width: usize, fraction: u64) -> i64 {
+        let nanoseconds = machine.instructions_retired() * NANOSECONDS_PER_INSTRUCTION;
+        let mut time = Vec::new();
+        for value in [
+            nanoseconds / 1_000_000_000,
+            nanoseconds % 1_000_000_000 / fraction,
+        ] {
+            time.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+        match machine.write_memory(buffer, &time) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+}
+
+impl SyscallHandler for LinuxSyscalls {
+    fn call(&mut self, machine: &mut Machine) -> Option<SyscallOutcome> {
+        let xlen = machine.cpu.base_isa.xlen();
+        let word = word_size(machine) as usize;
+        let [a0, a1, a2, a3] = std::array::from_fn(|index| {
+            let value = machine.cpu.regs[10 + index];
+            match xlen {
+                32 => value as u32 as u64,
+                _ => value,
+            }
+        });
+        let signed = |value: u64| match xlen {
+            32 => value as u32 as i32 as i64,
+            _ => value as i64,
+        };
+        let result = match machine.cpu.regs[17] as u32 {
+            SYS_READ => self.read(machine, a0, a1 as u32, a2 as usize),
+            SYS_WRITE => match machine.read_memory(a1 as u32, a2 as usize) {
+                Ok(bytes) => self.write(machine, a0, &bytes),
+                Err(_) => -EFAULT,
+            },
+            SYS_WRITEV => self.writev(machine, a0, a1 as u32, a2),
+            SYS_OPENAT => self.openat(machine, signed(a0), a1 as u32, a2),
+            SYS_CLOSE => match self.files.remove(&a0) {
+                Some(_) => 0,
+                None if a0 <= 2 => 0,
+                None => -EBADF,
+            },
+            SYS_LSEEK => self.lseek(a0, signed(a1), a2),
+            SYS_FSTAT if xlen == 64 => self.fstat(machine, a0, a1 as u32),
+            SYS_IOCTL => match a0 {
+                0..=2 => -ENOTTY,
+                fd if self.files.contains_key(&fd) => -ENOTTY,
+                _ => -EBADF,
+            },
+            SYS_BRK => self.brk(machine, a0 as u32) as i64,
+            SYS_MMAP => self.mmap(machine, a1, a3),
+            // Mappings are never reused, so there's nothing to unmap or protect
+            SYS_MUNMAP | SYS_MPROTECT => 0,
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                // The status as the parent process would see it
+                return Some(SyscallOutcome::Exit(a0 as u32 & 0xff));
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => PID,
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+            // Signals are never delivered and there is a single thread
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => 0,
+            SYS_UNAME => Self::uname(machine, a0 as u32),
+            SYS_CLOCK_GETTIME => Self::write_time(machine, a1 as u32, word, 1),
+            SYS_CLOCK_GETTIME64 => Self::write_time(machine, a1 as u32, 8, 1),
+            SYS_GETTIMEOFDAY if a0 == 0 => 0,
+            SYS_GETTIMEOFDAY => Self::write_time(machine, a0 as u32, word, 1000),
+            SYS_GETRANDOM => self.getrandom(machine, a0 as u32, a1 as usize),
+            _ => -ENOSYS,
+        };
+        machine.cpu.regs[10] = match xlen {
+            32 => result as u32 as u64,
+            _ => result as u64,
+        };
+        Some(SyscallOutcome::Return)
+    }
+
+    /// Closes the program's files and forgets its heap and mappings, the root stays
+    fn reset(&mut self) {
+        *self = Self {
+            root: self.root.take(),
+            ..Self::new()
+        };
+    }
+}
+
+/// Lays out the program's arguments, environment and auxiliary vector below the stack
+/// pointer (the top of memory if sp is 0) the way Linux starts a process, and points sp at
+/// argc. `auxv` entries are added to AT_PAGESZ, AT_RANDOM and the ids, e.g. AT_PHDR for a
+/// loaded ELF file. Like the loaded images, the stack is laid out again by a reset.
+pub fn start_process(
+    machine: &mut Machine,
+    args: &[String],
+    env: &[String],
+    auxv: &[(u64, u64)],
+) -> Result<u32, EmuError> {
+    let word = word_size(machine) as usize;
+    let top = stack_top(machine) & !0xf;
+
+    // The random bytes and the strings go at the top, the vectors pointing at them below
+    let mut strings = vec![0; 16];
+    fill_random(&mut 0, &mut strings);
+    let mut offsets = Vec::new();
+    for text in args.iter().chain(env) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(text.as_bytes());
+        strings.push(0);
+    }
+    let too_big = || EmuError::ConfigError {
+        message: format!(
+            "the program's arguments and environment don't fit below {:#010x}",
+            top
+        ),
+    };
+    let strings_start = top
+        .checked_sub(u32::try_from(strings.len()).map_err(|_| too_big())?)
+        .ok_or_else(too_big)?;
+    let pointer = |offset: &u64| strings_start as u64 + offset;
+    let mut vectors = vec![args.len() as u64];
+    vectors.extend(offsets[..args.len()].iter().map(pointer));
+    vectors.push(0);
+    vectors.extend(offsets[args.len()..].iter().map(pointer));
+    vectors.push(0);
+    let defaults = [
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_RANDOM, strings_start as u64),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0),
+    ];
+    for (key, value) in defaults.iter().chain(auxv) {
+        vectors.extend([*key, *value]);
+    }
+    vectors.extend([AT_NULL, 0]);
+
+    let stack_pointer = u32::try_from(vectors.len() * word)
+        .ok()
+        .and_then(|size| strings_start.checked_sub(size))
+        .ok_or_else(too_big)?
+        & !0xf;
+    let mut stack = Vec::new();
+    for value in vectors {
+        stack.extend_from_slice(&value.to_le_bytes()[..word]);
+    }
+    stack.resize((strings_start - stack_pointer) as usize, 0);
+    stack.extend_from_slice(&strings);
+    machine.preload(stack_pointer, &stack)?;
+    machine.set_stack_pointer(stack_pointer);
+    Ok(stack_pointer)
+}
+
+/// Bytes in a `long` or pointer
+fn word_size(machine: &Machine) -> u32 {
+    machine.cpu.base_isa.xlen() / 8
+}
+
+fn read_word(machine: &Machine, address: u32) -> Option<u64> {
+    let bytes = machine
+        .read_memory(address, word_size(machine) as usize)
+        .ok()?;
+    let mut value = [0; 8];
+    value[..bytes.len()].copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+/// The NUL-terminated string at `address`, `None` if it runs out of memory or is longer
+/// than [`PATH_MAX`]
+fn read_c_string(machine: &Machine, address: u32) -> Option<Vec<u8>> {
+    let mut text = Vec::new();
+    loop {
+        let byte = machine
+            .read_memory(address.wrapping_add(text.len() as u32), 1)
+            .ok()?[0];
+        if byte == 0 {
+            return Some(text);
+        }
+        if text.len() == PATH_MAX {
+            return None;
+        }
+        text.push(byte);
+    }
+}
+
+/// The stack pointer, or the top of memory if sp is 0
+fn stack_top(machine: &Machine) -> u32 {
+    match machine.cpu.regs[2] as u32 {
+        0 => u32::try_from(machine.cpu.bus.dram.len()).unwrap_or(u32::MAX),
+        stack_pointer => stack_pointer,
+    }
+}
+
+/// Lowest address the stack may grow down to
+fn stack_limit(machine: &Machine) -> u32 {
+    stack_top(machine).saturating_sub(STACK_RESERVE) & !(PAGE_SIZE - 1)
+}
+
+/// Fills `bytes` from a SplitMix64 generator, the same sequence on every run
+fn fill_random(state: &mut u64, bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = *state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^= value >> 31;
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// The Linux errno for a host error
+fn errno(error: &io::Error) -> i64 {
+    match error.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::Unsupported => EPERM,
+        _ => EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        machine::{ExitReason, RunLimits, RunOptions},
+        syscalls::SyscallMode,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_linux_syscalls() {
+        let words = [
+            0x00012503, // lw a0, 0(sp), argc
+            0x00412583, // lw a1, 4(sp), argv[0]
+            0x01000613, // addi a2, zero, 16
+            0x04000893, // addi a7, zero, 64, write
+            0x00050693, // addi a3, a0, 0
+            0x00100513, // addi a0, zero, 1
+            0x00000073, // ecall
+            0x00000513, // addi a0, zero, 0
+            0x0d600893, // addi a7, zero, 214, brk
+            0x00000073, // ecall
+            0x00050413, // addi s0, a0, 0
+            0x40050513, // addi a0, a0, 0x400
+            0x00000073, // ecall
+            0x408504b3, // sub s1, a0, s0
+            0x3e700513, // addi a0, zero, 999, not a system call
+            0x00050893, // addi a7, a0, 0
+            0x00000073, // ecall
+            0x00050913, // addi s2, a0, 0
+            0x10068513, // addi a0, a3, 0x100
+            0x05e00893, // addi a7, zero, 94, exit_group
+            0x00000073, // ecall
+        ];
+        let program: Vec<u8> = words
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect();
+        let options = RunOptions {
+            echo_output: false,
+            capture_output: true,
+            syscalls: SyscallMode::Linux,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(program, 0x10000, &options);
+        let args = ["hello-world-prog".to_string(), "x".to_string()];
+        let stack_pointer = start_process(&mut machine, &args, &["A=1".to_string()], &[]).unwrap();
+        assert_eq!(stack_pointer % 16, 0);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall);
+        // argc + 0x100, as an exit status
+        assert_eq!(outcome.exit_code, Some(2));
+        assert_eq!(machine.console.output(), b"hello-world-prog");
+        // The heap starts on the page after the program and grows by the 0x400 asked for
+        assert_eq!(machine.cpu.regs[8], 0x1000);
+        assert_eq!(machine.cpu.regs[9], 0x400);
+        assert_eq!(machine.cpu.regs[18] as u32 as i32 as i64, -ENOSYS);
+
+        // The stack is laid out again after a reset
+        machine.reset();
+        machine.run(&RunLimits::default());
+        assert_eq!(machine.console.output(), b"hello-world-prog");
+    }
+
+    #[test]
+    fn test_files_stay_below_the_root() {
+        let root = std::env::temp_dir().join(format!("linux-root-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data.txt"), "contents").unwrap();
+        let mut linux = LinuxSyscalls::new().with_root(&root);
+        let mut machine = Machine::new(Vec::new(), 0x1000);
+        machine.write_memory(0x100, b"/data.txt\0").unwrap();
+        machine.write_memory(0x200, b"../data.txt\0").unwrap();
+
+        let fd = linux.openat(&machine, AT_FDCWD, 0x100, 0);
+        assert_eq!(fd, 3);
+        assert_eq!(linux.read(&mut machine, 3, 0x300, 4), 4);
+        assert_eq!(machine.read_memory(0x300, 4).unwrap(), b"cont");
+        assert_eq!(linux.lseek(3, 0, 2), 8);
+        assert_eq!(linux.openat(&machine, AT_FDCWD, 0x200, 0), -EACCES);
+        assert_eq!(
+            LinuxSyscalls::new().openat(&machine, AT_FDCWD, 0x100, 0),
+            -EACCES
+        );
+        assert_eq!(linux.read(&mut machine, 4, 0x300, 4), -EBADF);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
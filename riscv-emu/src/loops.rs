@@ -0,0 +1,252 @@
+//! Loop detection from the control flow of a run, for finding where a program spends its time.
+//!
+//! A loop is a taken backward branch or `jal zero` (the back-edge at its latch) and its
+//! target (the loop header). Each time the back-edge is taken after the loop was left, a new
+//! entry of the loop starts; it ends when the latch falls through, or when a branch or jump
+//! (a `ret`, say) leaves the range between header and latch. Calls made from inside the loop
+//! count towards it.
+//!
+//! The instructions before the first back-edge of an entry can't be told apart from the code
+//! leading up to the loop, so instruction counts cover the iterations after it.
+
+use std::collections::BTreeMap;
+
+/// Entries of different loops that are tracked at once, e.g. nested loops. Entries left in
+/// ways the profiler doesn't see (by a trap, say) are dropped oldest first beyond this.
+pub const MAX_ACTIVE_LOOPS: usize = 64;
+
+/// Everything known about one loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Target of the back-edge, the first instruction of the loop
+    pub header: u32,
+    /// Address of the back-edge, the last instruction of the loop
+    pub latch: u32,
+    /// Times the loop was entered
+    pub entries: u64,
+    /// Times the back-edge was taken
+    pub back_edges: u64,
+    /// Largest number of iterations of a single entry
+    pub max_trip_count: u64,
+    /// Instructions executed in the iterations following a back-edge
+    pub instructions: u64,
+}
+
+impl LoopStats {
+    /// Iterations across all entries, every entry runs the body once more than it takes the
+    /// back-edge
+    pub fn iterations(&self) -> u64 {
+        self.back_edges + self.entries
+    }
+
+    pub fn average_trip_count(&self) -> f64 {
+        self.iterations() as f64 / self.entries as f64
+    }
+
+    pub fn instructions_per_iteration(&self) -> f64 {
+        self.instructions as f64 / self.back_edges as f64
+    }
+}
+
+/// A loop entry that is still running
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Activation {
+    header: u32,
+    latch: u32,
+    /// Instruction count at the first back-edge
+    started: u64,
+    back_edges: u64,
+}
+
+/// Loops seen in a run, fed every retired control transfer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopProfile {
+    loops: BTreeMap<(u32, u32), LoopStats>,
+    active: Vec<Activation>,
+}
+
+impl LoopProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the (expanded) `instruction` at `pc`, `length` bytes long, which left the pc at
+    /// `next_pc`. `retired` counts the instructions executed so far, including this one.
+    pub fn record(&mut self, pc: u32, instruction: u32, length: u32, next_pc: u32, retired: u64) {
+        let opcode = instruction & 0x7f;
+        let rd = (instruction >> 7) & 0x1f;
+        let taken = next_pc != pc.wrapping_add(length);
+        match opcode {
+            0b1100011 if !taken => {
+                // A latch falling through ends its loop
+                if let Some(index) = self.active.iter().rposition(|active| active.latch == pc) {
+                    let activation = self.active.remove(index);
+                    self.finish(activation, retired);
+                }
+                return;
+            }
+            0b1100011 | 0b1101111 | 0b1100111 if taken => {}
+            _ => return,
+        }
+        let linking = opcode != 0b1100011 && rd != 0;
+        if !linking {
+            self.leave(pc, next_pc, retired);
+        }
+        if linking || opcode == 0b1100111 || next_pc > pc {
+            return;
+        }
+        match self
+            .active
+            .iter_mut()
+            .find(|active| active.header == next_pc && active.latch == pc)
+        {
+            Some(active) => active.back_edges += 1,
+            None => {
+                if self.active.len() == MAX_ACTIVE_LOOPS {
+                    self.active.remove(0);
+                }
+                self.active.push(Activation {
+                    header: next_pc,
+                    latch: pc,
+                    started: retired,
+                    back_edges: 1,
+                });
+            }
+        }
+    }
+
+    /// Ends the loops a jump from `pc` to `next_pc` leaves
+    fn leave(&mut self, pc: u32, next_pc: u32, retired: u64) {
+        let mut index = 0;
+        while index < self.active.len() {
+            let active = &self.active[index];
+            let inside = active.header..=active.latch;
+            if inside.contains(&pc) && !inside.contains(&next_pc) {
+                let activation = self.active.remove(index);
+                self.finish(activation, retired);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self, activation: Activation, retired: u64) {
+        let stats = self
+            .loops
+            .entry((activation.header, activation.latch))
+            .or_insert(LoopStats {
+                header: activation.header,
+                latch: activation.latch,
+                entries: 0,
+                back_edges: 0,
+                max_trip_count: 0,
+                instructions: 0,
+            });
+        stats.entries += 1;
+        stats.back_edges += activation.back_edges;
+        stats.max_trip_count = stats.max_trip_count.max(activation.back_edges + 1);
+        stats.instructions += retired - activation.started;
+    }
+
+    /// Every loop seen, hottest (most instructions executed) first. Loops still running are
+    /// counted up to `retired` instructions.
+    pub fn hot_loops(&self, retired: u64) -> Vec<LoopStats> {
+        let mut profile = self.clone();
+        for activation in std::mem::take(&mut profile.active) {
+            profile.finish(activation, retired);
+        }
+        let mut loops: Vec<_> = profile.loops.into_values().collect();
+        loops.sort_by(|a, b| {
+            b.instructions
+                .cmp(&a.instructions)
+                .then(a.header.cmp(&b.header))
+        });
+        loops
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loops.is_empty() && self.active.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.loops.clear();
+        self.active.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bne a0, zero, -8 at 0x8, back to 0x0
+    const BACK_EDGE: u32 = 0xfe051ce3;
+    // bne a1, zero, -4 at 0x8, back to 0x4
+    const INNER_BACK_EDGE: u32 = 0xfe059ee3;
+    // jal zero, 8
+    const JUMP: u32 = 0x0080006f;
+
+    #[test]
+    fn test_trip_counts_and_iteration_lengths() {
+        let mut profile = LoopProfile::new();
+        let mut retired = 0;
+        // Two entries of a 3 instruction loop at 0x0..=0x8, running 4 and 2 iterations
+        for trips in [4, 2] {
+            for trip in 1..=trips {
+                retired += 3;
+                let next_pc = if trip < trips { 0x0 } else { 0xc };
+                profile.record(0x8, BACK_EDGE, 4, next_pc, retired);
+            }
+            retired += 10;
+        }
+        let loops = profile.hot_loops(retired);
+        assert_eq!(loops.len(), 1);
+        let stats = &loops[0];
+        assert_eq!((stats.header, stats.latch), (0x0, 0x8));
+        assert_eq!(
+            (stats.entries, stats.iterations(), stats.max_trip_count),
+            (2, 6, 4)
+        );
+        assert_eq!(stats.average_trip_count(), 3.0);
+        assert_eq!(stats.instructions_per_iteration(), 3.0);
+    }
+
+    #[test]
+    fn test_jumping_out_ends_an_entry() {
+        let mut profile = LoopProfile::new();
+        profile.record(0x8, BACK_EDGE, 4, 0x0, 3);
+        // A call out of the loop and back doesn't end it
+        profile.record(0x4, 0x0c0000ef, 4, 0xc4, 4); // jal ra, 0xc0
+        profile.record(0xc4, 0x00008067, 4, 0x8, 5); // ret
+        profile.record(0x8, BACK_EDGE, 4, 0x0, 6);
+        // Breaking out of it does
+        profile.record(0x4, JUMP, 4, 0xc, 8);
+        let loops = profile.hot_loops(100);
+        assert_eq!(loops[0].entries, 1);
+        assert_eq!(loops[0].back_edges, 2);
+        assert_eq!(loops[0].instructions, 5);
+    }
+
+    #[test]
+    fn test_nested_loops_are_ranked_by_instructions() {
+        let mut profile = LoopProfile::new();
+        let mut retired = 0;
+        for outer in 0..3 {
+            for inner in 0..5 {
+                retired += 1;
+                let next_pc = if inner < 4 { 0x4 } else { 0x8 + 4 };
+                profile.record(0x8, INNER_BACK_EDGE, 4, next_pc, retired);
+            }
+            retired += 2;
+            // The outer latch at 0xc
+            let next_pc = if outer < 2 { 0x0 } else { 0x10 };
+            profile.record(0xc, 0xfe051ae3, 4, next_pc, retired); // bne a0, zero, -12
+        }
+        let loops = profile.hot_loops(retired);
+        let summary: Vec<_> = loops
+            .iter()
+            .map(|stats| (stats.header, stats.entries, stats.iterations()))
+            .collect();
+        assert_eq!(summary, [(0x0, 1, 3), (0x4, 3, 15)]);
+        assert!(loops[0].instructions > loops[1].instructions);
+    }
+}
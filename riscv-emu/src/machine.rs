@@ -0,0 +1,1818 @@
+use std::{
+    io::{self, Write},
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use tracing::debug;
+
+use crate::{
+    abi::{AbiChecker, AbiViolation},
+    bank::BankedMemory,
+    clint::Clint,
+    compressed,
+    console::{Console, ConsoleInput},
+    cpu::{BaseIsa, Cpu, Exception, Extensions},
+    custom::{CustomInstruction, CustomInstructions},
+    digest::Sha256,
+    elf::Elf,
+    error::{EmuError, LoadError},
+    explain::{explain, explain_interrupt, explain_trap},
+    image::Image,
+    layout::{MemoryLayout, Region, RegionKind},
+    linux::LinuxSyscalls,
+    loops::{LoopProfile, LoopStats},
+    metrics::{Metrics, MetricsHandle, PUBLISH_INTERVAL},
+    plic::Plic,
+    shadow::{BoundsViolation, MemoryAccess, ShadowMemory},
+    symbols::Symbols,
+    syscalls::{BareSyscalls, SyscallHandler, SyscallLog, SyscallMode, SyscallOutcome},
+    tags::TagMemory,
+    trace::{Trace, TraceEntry, TraceFilter},
+    trace_file::TraceWriter,
+    uart::Uart,
+    uninit::{InitializedMemory, UninitializedRead},
+};
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The pc ran past the last loaded instruction
+    EndOfProgram,
+    /// The instruction limit was reached before the program finished
+    InstructionLimit,
+    /// The program executed an `ecall` no system call handler took, or exited through one
+    EnvironmentCall,
+    /// An instruction raised an exception the program has no trap handler for
+    Exception(Exception),
+    /// Stopped through the machine's [`Interrupt`], the state is intact and the run can be
+    /// resumed
+    Interrupted,
+}
+
+impl ExitReason {
+    /// Reason to stop for an exception raised by a step
+    pub(crate) fn from_exception(exception: Exception) -> Self {
+        match exception {
+            Exception::EnvironmentCall { .. } => ExitReason::EnvironmentCall,
+            exception => ExitReason::Exception(exception),
+        }
+    }
+}
+
+/// Asks a running machine to stop, from another thread or a signal handler.
+///
+/// The machine checks it before every instruction and consumes the request when it stops.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a request is pending
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Consumes a pending request, returning whether there was one
+    pub fn take(&self) -> bool {
+        self.is_requested() && self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Resource limits for a single run
+#[derive(Debug, Clone, Default)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+}
+
+/// How a machine is configured and its console wired up
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub base_isa: BaseIsa,
+    pub extensions: Extensions,
+    /// Bytes fed to the guest as console input, `None` reads the host's stdin
+    pub input: Option<Vec<u8>>,
+    /// Forward guest output to the host's stdout as it is written
+    pub echo_output: bool,
+    /// Keep a copy of the guest's output, see [`Console::output`]
+    pub capture_output: bool,
+    /// Upper bound on captured bytes, output beyond it is counted but dropped
+    pub max_captured_output: Option<usize>,
+    /// Map a CLINT at [`CLINT_BASE`](crate::clint::CLINT_BASE) for timer and software interrupts
+    pub clint: bool,
+    /// Map a PLIC at [`PLIC_BASE`](crate::plic::PLIC_BASE) for external interrupts of devices
+    pub plic: bool,
+    /// Map a 16550 UART at [`UART_BASE`](crate::uart::UART_BASE) on the console
+    pub uart: bool,
+    /// System calls emulated on `ecall`
+    pub syscalls: SyscallMode,
+    /// Keep a tag bit per word of memory, checked on loads and stores through custom CSRs,
+    /// see [`crate::tags`]
+    pub tagged_memory: bool,
+    /// An `ecall` the system call handler doesn't know raises an illegal instruction
+    /// exception instead of ending the run
+    pub strict_syscalls: bool,
+    /// Report loads of memory nothing was written to, see [`crate::uninit`]
+    pub check_uninitialized: bool,
+    /// Report functions returning with callee-saved registers changed, see [`crate::abi`]
+    pub check_abi: bool,
+    /// Make loads and stores just below the stack fault, see [`Machine::set_stack_limit`]
+    pub stack_guard: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            base_isa: BaseIsa::default(),
+            extensions: Extensions::default(),
+            input: None,
+            echo_output: true,
+            capture_output: false,
+            max_captured_output: None,
+            clint: false,
+            plic: false,
+            uart: false,
+            syscalls: SyscallMode::None,
+            tagged_memory: false,
+            strict_syscalls: false,
+            check_uninitialized: false,
+            check_abi: false,
+            stack_guard: false,
+        }
+    }
+}
+
+impl RunOptions {
+    /// The machine of an introductory course: RV32I without any extensions, the
+    /// [`BareSyscalls`] and nothing else, and uninitialized reads, calling convention
+    /// violations and stack overflows caught
+    pub fn strict_rv32i() -> Self {
+        Self {
+            base_isa: BaseIsa::Rv32I,
+            extensions: Extensions::none(),
+            syscalls: SyscallMode::Bare,
+            strict_syscalls: true,
+            check_uninitialized: true,
+            check_abi: true,
+            stack_guard: true,
+            ..Self::default()
+        }
+    }
+
+    fn console(&self) -> Console {
+        let input = match &self.input {
+            Some(bytes) => ConsoleInput::Buffer(bytes.iter().copied().collect()),
+            None => ConsoleInput::HostStdin(None),
+        };
+        let console = Console::new(input).with_echo(self.echo_output);
+        if self.capture_output {
+            console.with_capture(self.max_captured_output)
+        } else {
+            console
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub exit_reason: ExitReason,
+    /// Value of a0 when the program finished, `None` if it was stopped by a limit
+    pub exit_code: Option<u32>,
+    /// Instructions executed during this run
+    pub instructions: u64,
+}
+
+/// Bounds violations kept per run, a loop overflowing an array would otherwise report
+/// every iteration. Uninitialized reads and ABI violations are capped alike.
+pub const MAX_BOUNDS_VIOLATIONS: usize = 256;
+
+/// Bytes below the stack that fault with [`RunOptions::stack_guard`]
+pub const STACK_GUARD_SIZE: u32 = 4096;
+
+/// Where traced instructions go
+enum TraceSink {
+    Memory(Trace),
+    /// Encoded as they execute, so long runs don't hold the whole trace in memory
+    Stream {
+        writer: Box<TraceWriter<Box<dyn Write + Send>>>,
+        /// First write error, nothing more is written after it
+        error: Option<io::Error>,
+    },
+}
+
+/// A CPU with a loaded program and the bookkeeping needed to run it to completion
+pub struct Machine {
+    pub cpu: Cpu,
+    pub console: Console,
+    /// Symbols for reports and the debugger, empty unless a symbol file or assembler output
+    /// provided them
+    pub symbols: Symbols,
+    /// Address ranges holding loaded images, the run ends when the pc leaves all of them
+    images: Vec<Range<u32>>,
+    /// Images and preloaded data copied into writable memory, replayed by [`Machine::reset`]
+    loaded: Vec<(u32, Vec<u8>)>,
+    /// Hash of the images as they were loaded, see [`Machine::image_digest`]
+    image_hasher: Sha256,
+    /// Where execution starts after a reset
+    entry: u32,
+    /// Initial stack pointer, restored by a reset
+    stack_pointer: u32,
+    instructions_retired: u64,
+    /// Executed instructions, recorded while tracing is enabled
+    trace: Option<TraceSink>,
+    /// Which instructions make it into the trace
+    trace_filter: TraceFilter,
+    interrupt: Interrupt,
+    syscalls: SyscallLog,
+    /// Carries out `ecall`s, see [`Machine::set_syscall_handler`]
+    syscall_handler: Option<Box<dyn SyscallHandler>>,
+    /// Execute instructions of the custom opcodes, see [`Machine::add_custom_instructions`]
+    custom_instructions: Vec<Box<dyn CustomInstructions>>,
+    /// Where runs publish their metrics for [`Machine::metrics_handle`]
+    metrics: MetricsHandle,
+    /// Data objects whose bounds are checked, see [`Machine::enable_bounds_checking`]
+    shadow: Option<ShadowMemory>,
+    bounds_violations: Vec<BoundsViolation>,
+    /// Bytes written so far, with [`RunOptions::check_uninitialized`]
+    initialized: Option<InitializedMemory>,
+    uninitialized_reads: Vec<UninitializedRead>,
+    /// Open calls, with [`RunOptions::check_abi`]
+    abi: Option<AbiChecker>,
+    abi_violations: Vec<AbiViolation>,
+    /// Whether [`Machine::set_stack_limit`] places a guard region
+    guard_stack: bool,
+    /// Addresses loads and stores fault at, below the stack
+    stack_guard: Option<Range<u32>>,
+    strict_syscalls: bool,
+    loops: Option<LoopProfile>,
+    /// Where each executed instruction is explained, see [`Machine::explain_to`]
+    explain: Option<Box<dyn Write + Send>>,
+}
+
+impl Machine {
+    /// Loads `program` at address 0 of a `memory_size` byte memory
+    pub fn new(program: Vec<u8>, memory_size: usize) -> Self {
+        Self::with_options(program, memory_size, &RunOptions::default())
+    }
+
+    pub fn with_options(program: Vec<u8>, memory_size: usize, options: &RunOptions) -> Self {
+        Self::from_shared(program.into(), memory_size, options)
+    }
+
+    /// Creates a machine running a program image shared with other machines.
+    ///
+    /// The image is never copied, so many machines (e.g. one per grader test case, each on
+    /// its own thread) only pay for their registers and the memory they actually write.
+    pub fn from_shared(program: Arc<[u8]>, memory_size: usize, options: &RunOptions) -> Self {
+        let mut images = Vec::new();
+        let mut image_hasher = Sha256::new();
+        if !program.is_empty() {
+            images.push(0..program.len() as u32);
+            hash_image(&mut image_hasher, 0, &program);
+        }
+        let initialized = options
+            .check_uninitialized
+            .then(|| InitializedMemory::new(memory_size, program.len()));
+        let mut cpu = Cpu::new_with_program(program, memory_size);
+        cpu.base_isa = options.base_isa;
+        cpu.extensions = options.extensions;
+        if options.clint {
+            cpu.bus.attach(Clint::new());
+        }
+        if options.plic {
+            cpu.bus.attach(Plic::new());
+        }
+        if options.uart {
+            cpu.bus.attach(Uart::new());
+        }
+        if options.tagged_memory {
+            cpu.tags = Some(TagMemory::new());
+        }
+        let syscall_handler: Option<Box<dyn SyscallHandler>> = match options.syscalls {
+            SyscallMode::None => None,
+            SyscallMode::Bare => Some(Box::new(BareSyscalls::new())),
+            SyscallMode::Linux => Some(Box::new(LinuxSyscalls::new())),
+        };
+        Self {
+            cpu,
+            console: options.console(),
+            symbols: Symbols::new(),
+            images,
+            loaded: Vec::new(),
+            image_hasher,
+            entry: 0,
+            stack_pointer: 0,
+            instructions_retired: 0,
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            interrupt: Interrupt::default(),
+            syscalls: SyscallLog::new(),
+            syscall_handler,
+            custom_instructions: Vec::new(),
+            metrics: MetricsHandle::default(),
+            shadow: None,
+            bounds_violations: Vec::new(),
+            initialized,
+            uninitialized_reads: Vec::new(),
+            abi: options.check_abi.then(AbiChecker::new),
+            abi_violations: Vec::new(),
+            guard_stack: options.stack_guard,
+            stack_guard: None,
+            strict_syscalls: options.strict_syscalls,
+            loops: None,
+            explain: None,
+        }
+    }
+
+    /// Like [`Machine::from_shared`], but rejects configurations the 32 bit address space
+    /// can't hold
+    pub fn try_from_shared(
+        program: Arc<[u8]>,
+        memory_size: usize,
+        options: &RunOptions,
+    ) -> Result<Self, EmuError> {
+        for (what, size) in [("program", program.len()), ("memory", memory_size)] {
+            if size as u64 > 1 << 32 {
+                return Err(EmuError::ConfigError {
+                    message: format!(
+                        "{} size of {} bytes exceeds the 32 bit address space",
+                        what, size
+                    ),
+                });
+            }
+        }
+        Ok(Self::from_shared(program, memory_size, options))
+    }
+
+    /// Steps until the program finishes or a limit is hit
+    pub fn run(&mut self, limits: &RunLimits) -> RunOutcome {
+        let start = self.instructions_retired;
+
+        let exit_reason = loop {
+            if !self.in_loaded_image(self.cpu.pc) {
+                break ExitReason::EndOfProgram;
+            }
+            if limits
+                .max_instructions
+                .is_some_and(|max| self.instructions_retired - start >= max)
+            {
+                break ExitReason::InstructionLimit;
+            }
+            if self.interrupt.take() {
+                break ExitReason::Interrupted;
+            }
+            if let Err(exception) = self.step() {
+                break ExitReason::from_exception(exception);
+            }
+            if self.instructions_retired.is_multiple_of(PUBLISH_INTERVAL) {
+                self.metrics.publish(&self.metrics());
+            }
+        };
+        self.metrics.publish(&self.metrics());
+
+        let outcome = RunOutcome {
+            exit_reason,
+            exit_code: match exit_reason {
+                ExitReason::EndOfProgram | ExitReason::EnvironmentCall => {
+                    Some(self.cpu.regs[10] as u32)
+                }
+                ExitReason::InstructionLimit
+                | ExitReason::Exception(_)
+                | ExitReason::Interrupted => None,
+            },
+            instructions: self.instructions_retired - start,
+        };
+        debug!(?outcome, "run finished");
+        outcome
+    }
+
+    /// Like [`Machine::run`], but hitting the instruction limit is an error
+    pub fn run_checked(&mut self, limits: &RunLimits) -> Result<RunOutcome, EmuError> {
+        let outcome = self.run(limits);
+        match (outcome.exit_reason, limits.max_instructions) {
+            (ExitReason::InstructionLimit, Some(limit)) => Err(EmuError::LimitExhausted { limit }),
+            _ => Ok(outcome),
+        }
+    }
+
+    /// Bytes of memory, the addresses [`Machine::read_memory`] reaches
+    pub fn memory_size(&self) -> usize {
+        self.cpu.bus.dram.len().max(self.cpu.bus.program.len())
+    }
+
+    /// Reads `len` bytes of memory as the program sees it
+    pub fn read_memory(&self, address: u32, len: usize) -> Result<Vec<u8>, EmuError> {
+        let start = address as usize;
+        // The shared program image shadows the memory below it
+        (start..start.saturating_add(len))
+            .map(|index| {
+                self.cpu
+                    .bus
+                    .program
+                    .get(index)
+                    .or_else(|| self.cpu.bus.dram.get(index))
+                    .copied()
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(EmuError::BusFault { address, len })
+    }
+
+    /// Writes `bytes` to memory, the shared program image is read-only
+    pub fn write_memory(&mut self, address: u32, bytes: &[u8]) -> Result<(), EmuError> {
+        let start = address as usize;
+        let end = start.saturating_add(bytes.len());
+        let fault = EmuError::BusFault {
+            address,
+            len: bytes.len(),
+        };
+        if !bytes.is_empty() && start < self.cpu.bus.program.len() {
+            return Err(fault);
+        }
+        self.cpu
+            .bus
+            .dram
+            .get_mut(start..end)
+            .ok_or(fault)?
+            .copy_from_slice(bytes);
+        self.wrote_memory(start, bytes.len());
+        Ok(())
+    }
+
+    /// Copies a raw image into memory at `load_addr`, and jumps to `entry` if given.
+    ///
+    /// Can be called repeatedly to place several images, e.g. code at 0x0 and a data
+    /// blob at 0x10000.
+    pub fn load_binary(
+        &mut self,
+        bytes: &[u8],
+        load_addr: u32,
+        entry: Option<u32>,
+    ) -> Result<(), EmuError> {
+        let start = load_addr as usize;
+        let end = start.saturating_add(bytes.len());
+        if end > self.cpu.bus.dram.len() {
+            return Err(LoadError::OutOfMemory {
+                load_addr,
+                len: bytes.len(),
+                memory_size: self.cpu.bus.dram.len(),
+            }
+            .into());
+        }
+        if !bytes.is_empty() && start < self.cpu.bus.program.len() {
+            return Err(LoadError::OverlapsProgram {
+                load_addr,
+                program_len: self.cpu.bus.program.len(),
+            }
+            .into());
+        }
+
+        self.cpu.bus.dram[start..end].copy_from_slice(bytes);
+        self.wrote_memory(start, bytes.len());
+        if !bytes.is_empty() {
+            self.images
+                .push(load_addr..u32::try_from(end).unwrap_or(u32::MAX));
+            self.loaded.push((load_addr, bytes.to_vec()));
+            hash_image(&mut self.image_hasher, load_addr, bytes);
+        }
+        if let Some(entry) = entry {
+            self.set_entry(entry);
+        }
+        Ok(())
+    }
+
+    /// Loads every segment of an Intel HEX, S-record or binary image at its own address, and
+    /// jumps to the image's entry point if it has one
+    pub fn load_image(&mut self, image: &Image) -> Result<(), EmuError> {
+        for segment in image.segments() {
+            self.load_binary(&segment.data, segment.address, None)?;
+        }
+        if let Some(entry) = image.entry {
+            self.set_entry(entry);
+        }
+        Ok(())
+    }
+
+    /// Loads an ELF executable's segments and jumps to its entry point like
+    /// [`Machine::load_image`], and adds its symbols for traces and reports. Load `elf.image`
+    /// instead to leave the symbols out.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), EmuError> {
+        self.load_image(&elf.image)?;
+        for symbol in elf.symbols.iter() {
+            self.symbols
+                .insert_with_kind(symbol.name, symbol.address, symbol.kind);
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` to memory and writes them again on every reset, for what a program
+    /// starts out with besides its images, like the arguments on a Linux process's stack.
+    /// Unlike an image the bytes aren't code the program may run.
+    pub fn preload(&mut self, address: u32, bytes: &[u8]) -> Result<(), EmuError> {
+        self.write_memory(address, bytes)?;
+        self.loaded.push((address, bytes.to_vec()));
+        Ok(())
+    }
+
+    /// Address ranges of the loaded images, in the order they were loaded
+    pub fn images(&self) -> &[Range<u32>] {
+        &self.images
+    }
+
+    /// SHA-256 over the loaded images in load order, each as its address and length (both
+    /// 32 bit little-endian) followed by its bytes. Taken when they were loaded, so stores
+    /// into an image during a run don't change it.
+    pub fn image_digest(&self) -> [u8; 32] {
+        self.image_hasher.clone().finish()
+    }
+
+    /// Where execution starts after a reset
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Jumps to `entry`, and makes it the address [`Machine::reset`] restarts from
+    pub fn set_entry(&mut self, entry: u32) {
+        self.entry = entry;
+        self.cpu.pc = entry;
+    }
+
+    /// Sets sp, and makes it the value [`Machine::reset`] restores
+    pub fn set_stack_pointer(&mut self, stack_pointer: u32) {
+        self.stack_pointer = stack_pointer;
+        self.cpu.regs[2] = stack_pointer.into();
+    }
+
+    /// Makes `bottom` the lowest address of the stack. With [`RunOptions::stack_guard`] the
+    /// [`STACK_GUARD_SIZE`] bytes below it become a guard region, loads and stores touching
+    /// it fault like ones outside of memory, so a stack overflow stops the program before it
+    /// overwrites the heap or data.
+    pub fn set_stack_limit(&mut self, bottom: u32) {
+        if self.guard_stack {
+            self.stack_guard = Some(bottom.saturating_sub(STACK_GUARD_SIZE)..bottom);
+        }
+    }
+
+    /// Records a write to memory made outside of stores, like loading an image
+    fn wrote_memory(&mut self, start: usize, len: usize) {
+        self.cpu.touch_memory(start, len);
+        if let Some(initialized) = &mut self.initialized {
+            initialized.mark(start as u32, len);
+        }
+    }
+
+    /// Puts the machine back into its freshly loaded state without rebuilding it.
+    ///
+    /// Registers are cleared but for the initial stack pointer, the pc returns to the entry
+    /// point, writable memory is zeroed and the loaded images are copied back in, the console
+    /// forgets its output and the instruction count and any trace start over. Symbols and the
+    /// shared program are kept.
+    pub fn reset(&mut self) {
+        self.cpu.reset(self.entry);
+        self.cpu.regs[2] = self.stack_pointer.into();
+        self.cpu.clear_memory();
+        if let Some(initialized) = &mut self.initialized {
+            initialized.clear();
+        }
+        for (address, bytes) in &self.loaded {
+            let start = *address as usize;
+            self.cpu.bus.dram[start..start + bytes.len()].copy_from_slice(bytes);
+            self.cpu.touch_memory(start, bytes.len());
+            if let Some(initialized) = &mut self.initialized {
+                initialized.mark(*address, bytes.len());
+            }
+        }
+        self.console.reset();
+        self.instructions_retired = 0;
+        self.syscalls.clear();
+        if let Some(handler) = &mut self.syscall_handler {
+            handler.reset();
+        }
+        for handler in &mut self.custom_instructions {
+            handler.reset();
+        }
+        self.bounds_violations.clear();
+        self.uninitialized_reads.clear();
+        if let Some(abi) = &mut self.abi {
+            abi.clear();
+        }
+        self.abi_violations.clear();
+        if let Some(loops) = &mut self.loops {
+            loops.clear();
+        }
+        self.metrics.publish(&self.metrics());
+        // A streamed trace can't be rewound, it simply carries on with the new run
+        if let Some(TraceSink::Memory(trace)) = &mut self.trace {
+            *trace = Trace::default();
+        }
+    }
+
+    /// Memory map holding the loaded images, stack, heap and devices can be added before
+    /// [`MemoryLayout::validate`] checks it
+    pub fn layout(&self) -> MemoryLayout {
+        let mut layout = MemoryLayout::new(self.cpu.bus.dram.len().max(self.cpu.bus.program.len()));
+        for (index, image) in self.images.iter().enumerate() {
+            let name = if index == 0 && image.start == 0 && !self.cpu.bus.program.is_empty() {
+                "program".to_string()
+            } else {
+                format!("image at {:#x}", image.start)
+            };
+            layout.add(Region::new(
+                &name,
+                RegionKind::Image,
+                image.start,
+                image.end - image.start,
+            ));
+        }
+        for device in self.cpu.bus.devices() {
+            layout.add(Region::new(
+                device.name(),
+                RegionKind::Device,
+                device.base(),
+                device.size(),
+            ));
+        }
+        layout
+    }
+
+    /// Whether a full instruction at `address` lies inside a loaded image or the window of
+    /// banked memory. Runs before every step, so it reads memory without allocating and only
+    /// looks for banked memory outside the images.
+    pub(crate) fn in_loaded_image(&self, address: u32) -> bool {
+        let length = self.parcel_at(address).map_or(4, |parcel| {
+            compressed::instruction_length(u32::from(parcel))
+        });
+        let contains = |image: &Range<u32>| {
+            address >= image.start && address.saturating_add(length) <= image.end
+        };
+        self.images.iter().any(contains)
+            || self
+                .cpu
+                .bus
+                .device::<BankedMemory>()
+                .is_some_and(|banks| contains(&banks.window()))
+    }
+
+    /// The instruction at `pc`, expanded if it's compressed, and its length in bytes
+    pub(crate) fn instruction_at(&self, pc: u32) -> Option<(u32, u32)> {
+        let parcel = self.parcel_at(pc)?;
+        match compressed::instruction_length(parcel as u32) {
+            2 => Some((compressed::expand(parcel)?, 2)),
+            length => {
+                let high = self.parcel_at(pc.checked_add(2)?)?;
+                Some((u32::from(parcel) | u32::from(high) << 16, length))
+            }
+        }
+    }
+
+    /// The 16 bits at `address` as [`Machine::read_memory`] reads them
+    fn parcel_at(&self, address: u32) -> Option<u16> {
+        let byte = |address: u32| {
+            let index = address as usize;
+            self.cpu
+                .bus
+                .program
+                .get(index)
+                .or_else(|| self.cpu.bus.dram.get(index))
+                .copied()
+        };
+        Some(u16::from_le_bytes([
+            byte(address)?,
+            byte(address.checked_add(1)?)?,
+        ]))
+    }
+
+    /// Executes a single instruction. An exception traps to the program's handler if it
+    /// installed one through mtvec, and is returned otherwise.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let pc = self.cpu.pc;
+        if self.cpu.take_interrupt() {
+            if let Some(writer) = &mut self.explain {
+                let explanation = explain_interrupt(self.cpu.csrs.mcause, self.cpu.pc);
+                if writeln!(writer, "{:#010x}  {}", pc, explanation).is_err() {
+                    self.explain = None;
+                }
+            }
+            return Ok(());
+        }
+        // A sleeping `wfi` retires again every cycle, so devices' time passes and instruction
+        // limits still end a run nothing wakes
+        if self.cpu.is_waiting() {
+            self.instructions_retired += 1;
+            self.cpu.bus.tick();
+            self.service_uart();
+            return Ok(());
+        }
+        let checks_access =
+            self.shadow.is_some() || self.initialized.is_some() || self.stack_guard.is_some();
+        let access = match checks_access {
+            true => self
+                .instruction_at(pc)
+                .and_then(|(instruction, _)| MemoryAccess::of(instruction, &self.cpu.regs)),
+            false => None,
+        };
+        let before = (self.explain.is_some() || self.abi.is_some()).then_some(self.cpu.regs);
+        let mut result = match access.and_then(|access| self.guard_fault(pc, access)) {
+            Some(fault) => Err(fault),
+            None => self.step_traced(),
+        };
+        if let Err(Exception::IllegalInstruction { pc, instruction }) = result
+            && let Some(instruction) = CustomInstruction::decode(instruction)
+            && let Some(outcome) = self.execute_custom(pc, &instruction)
+        {
+            result = outcome;
+        }
+        if let Err(Exception::EnvironmentCall { pc }) = result
+            && let Some(outcome) = self.emulate_syscall(pc)
+        {
+            return match outcome {
+                SyscallOutcome::Return => Ok(()),
+                SyscallOutcome::Exit(_) => result,
+            };
+        }
+        if let Err(Exception::EnvironmentCall { pc }) = result
+            && self.strict_syscalls
+        {
+            result = Err(Exception::IllegalInstruction {
+                pc,
+                instruction: self.cpu.last_instruction(),
+            });
+        }
+        match result {
+            // A handled exception doesn't stop the program, only its handler sees it
+            Err(exception) if self.cpu.take_trap(exception) => {
+                if let Some(writer) = &mut self.explain {
+                    let explanation = explain_trap(&exception, self.cpu.pc);
+                    if writeln!(writer, "{:#010x}  {}", pc, explanation).is_err() {
+                        self.explain = None;
+                    }
+                }
+                return Ok(());
+            }
+            Err(Exception::EnvironmentCall { pc }) => {
+                self.syscalls.record(pc, &self.cpu.regs, None)
+            }
+            // A faulting access never happened, only retired ones are checked
+            Ok(()) => {
+                if let (Some(shadow), Some(access)) = (&self.shadow, access)
+                    && self.bounds_violations.len() < MAX_BOUNDS_VIOLATIONS
+                    && let Some(violation) = shadow.check(pc, access)
+                {
+                    self.bounds_violations.push(violation);
+                }
+                if let (Some(initialized), Some(access)) = (&mut self.initialized, access) {
+                    if access.write {
+                        initialized.mark(access.address, access.len as usize);
+                    } else if self.uninitialized_reads.len() < MAX_BOUNDS_VIOLATIONS
+                        && let Some(read) = initialized.check(pc, access)
+                    {
+                        self.uninitialized_reads.push(read);
+                    }
+                }
+                let parcel = self.cpu.last_instruction();
+                let length = compressed::instruction_length(parcel);
+                let instruction = match length {
+                    2 => compressed::expand(parcel as u16).unwrap_or(parcel),
+                    _ => parcel,
+                };
+                if let (Some(abi), Some(before)) = (&mut self.abi, &before) {
+                    let violations =
+                        abi.record(pc, instruction, before, &self.cpu.regs, self.cpu.pc);
+                    let room = MAX_BOUNDS_VIOLATIONS - self.abi_violations.len();
+                    self.abi_violations
+                        .extend(violations.into_iter().take(room));
+                }
+                if let Some(loops) = &mut self.loops {
+                    loops.record(
+                        pc,
+                        instruction,
+                        length,
+                        self.cpu.pc,
+                        self.instructions_retired,
+                    );
+                }
+                if let (Some(writer), Some(before)) = (&mut self.explain, before) {
+                    let explanation = explain(
+                        pc,
+                        self.cpu.last_instruction(),
+                        &before,
+                        &self.cpu.regs,
+                        self.cpu.pc,
+                        self.cpu.base_isa.xlen(),
+                    );
+                    // Like a streamed trace, nothing more is written after an error
+                    if writeln!(writer, "{:#010x}  {}", pc, explanation).is_err() {
+                        self.explain = None;
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// The access fault of `access`, made by the instruction at `pc`, if it touches the
+    /// guard region below the stack
+    fn guard_fault(&self, pc: u32, access: MemoryAccess) -> Option<Exception> {
+        let guard = self.stack_guard.as_ref()?;
+        let end = access.address as u64 + access.len as u64;
+        if u64::from(guard.start) >= end || access.address >= guard.end {
+            return None;
+        }
+        let address = access.address;
+        Some(match access.write {
+            true => Exception::StoreAccessFault { pc, address },
+            false => Exception::LoadAccessFault { pc, address },
+        })
+    }
+
+    /// Executes a single instruction and records it if tracing is enabled
+    fn step_traced(&mut self) -> Result<(), Exception> {
+        match &mut self.trace {
+            Some(trace) => {
+                let pc = self.cpu.pc;
+                let before = self.cpu.regs;
+                self.cpu.step()?;
+                let instruction = self.cpu.last_instruction();
+                if self.trace_filter.matches(pc, instruction, &before) {
+                    let write = (1..32)
+                        .find(|&index| self.cpu.regs[index] != before[index])
+                        .map(|index| (index as u8, self.cpu.regs[index] as u32));
+                    let entry = TraceEntry {
+                        pc,
+                        instruction,
+                        write,
+                    };
+                    match trace {
+                        TraceSink::Memory(trace) => trace.entries.push(entry),
+                        TraceSink::Stream {
+                            writer,
+                            error: error @ None,
+                        } => *error = writer.write(&entry).err(),
+                        TraceSink::Stream { .. } => {}
+                    }
+                }
+            }
+            None => self.cpu.step()?,
+        }
+        self.instructions_retired += 1;
+        self.cpu.bus.tick();
+        self.service_uart();
+        Ok(())
+    }
+
+    /// Lets the system call handler carry out the `ecall` at `pc`. On a return the `ecall`
+    /// retires, on an exit a0 holds the exit code and the pc stays on it.
+    fn emulate_syscall(&mut self, pc: u32) -> Option<SyscallOutcome> {
+        let mut handler = self.syscall_handler.take()?;
+        let before = self.cpu.regs;
+        let outcome = handler.call(self);
+        self.syscall_handler = Some(handler);
+        match outcome? {
+            SyscallOutcome::Return => {
+                self.syscalls.record(pc, &before, Some(self.cpu.regs[10]));
+                self.cpu.pc = pc.wrapping_add(4);
+                self.instructions_retired += 1;
+            }
+            SyscallOutcome::Exit(code) => {
+                self.syscalls.record(pc, &before, None);
+                self.cpu.regs[10] = code.into();
+            }
+        }
+        outcome
+    }
+
+    /// Lets the first handler taking it execute the custom instruction at `pc`, `None` if
+    /// none does. The instruction retires unless the handler traps.
+    fn execute_custom(
+        &mut self,
+        pc: u32,
+        instruction: &CustomInstruction,
+    ) -> Option<Result<(), Exception>> {
+        let index = self
+            .custom_instructions
+            .iter()
+            .position(|handler| handler.handles(instruction))?;
+        let mut handlers = std::mem::take(&mut self.custom_instructions);
+        self.cpu.pc = pc.wrapping_add(4);
+        let result = handlers[index].execute(instruction, self);
+        self.custom_instructions = handlers;
+        self.cpu.regs[0] = 0;
+        match result {
+            Ok(()) => self.instructions_retired += 1,
+            Err(_) => self.cpu.pc = pc,
+        }
+        Some(result)
+    }
+
+    /// Adds `handler` for instructions in the custom-0 and custom-1 opcode spaces, asked
+    /// after the handlers added before it
+    pub fn add_custom_instructions(&mut self, handler: impl CustomInstructions + 'static) {
+        self.custom_instructions.push(Box::new(handler));
+    }
+
+    /// Installs `handler` to carry out the program's `ecall`s, in place of the one
+    /// [`RunOptions::syscalls`] chose
+    pub fn set_syscall_handler(&mut self, handler: impl SyscallHandler + 'static) {
+        self.syscall_handler = Some(Box::new(handler));
+    }
+
+    /// Passes what the program sent through the UART to the console, and console input to
+    /// the UART as its receive FIFO makes room
+    fn service_uart(&mut self) {
+        let Some(uart) = self.cpu.bus.device_mut::<Uart>() else {
+            return;
+        };
+        let output = uart.take_output();
+        if !output.is_empty() {
+            self.console.write(&output);
+        }
+        if uart.can_receive()
+            && let Some(byte) = self.console.read_byte()
+        {
+            uart.receive(byte);
+        }
+    }
+
+    /// Handle that stops [`Machine::run`] (and debugger runs) before the next instruction
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    pub(crate) fn take_interrupt(&self) -> bool {
+        self.interrupt.take()
+    }
+
+    /// Starts recording every executed instruction
+    pub fn enable_tracing(&mut self) {
+        self.enable_filtered_tracing(TraceFilter::default());
+    }
+
+    /// Starts recording the executed instructions `filter` matches, replacing any previous
+    /// filter
+    pub fn enable_filtered_tracing(&mut self, filter: TraceFilter) {
+        self.trace_filter = filter;
+        self.trace
+            .get_or_insert_with(|| TraceSink::Memory(Trace::default()));
+    }
+
+    /// Like [`Machine::enable_filtered_tracing`], but encodes entries to `writer` as they
+    /// execute instead of keeping them, see [`Machine::finish_trace`]
+    pub fn stream_trace(
+        &mut self,
+        writer: TraceWriter<Box<dyn Write + Send>>,
+        filter: TraceFilter,
+    ) {
+        self.trace_filter = filter;
+        self.trace = Some(TraceSink::Stream {
+            writer: Box::new(writer),
+            error: None,
+        });
+    }
+
+    /// Returns the trace recorded so far and stops tracing, a streamed trace is left alone
+    pub fn take_trace(&mut self) -> Trace {
+        match self.trace.take() {
+            Some(TraceSink::Memory(trace)) => trace,
+            other => {
+                self.trace = other;
+                Trace::default()
+            }
+        }
+    }
+
+    /// Stops a streamed trace and flushes it, reporting the first error writing it.
+    /// Returns the number of entries written, 0 if the trace wasn't streamed.
+    pub fn finish_trace(&mut self) -> io::Result<u64> {
+        match self.trace.take() {
+            Some(TraceSink::Stream { writer, error }) => {
+                if let Some(error) = error {
+                    return Err(error);
+                }
+                let entries = writer.len();
+                writer.finish()?.flush()?;
+                Ok(entries)
+            }
+            other => {
+                self.trace = other;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Writes a line to `writer` explaining every instruction executed from now on, with its
+    /// operand values filled in
+    pub fn explain_to(&mut self, writer: Box<dyn Write + Send>) {
+        self.explain = Some(writer);
+    }
+
+    pub fn stop_explaining(&mut self) {
+        self.explain = None;
+    }
+
+    pub fn is_explaining(&self) -> bool {
+        self.explain.is_some()
+    }
+
+    /// Total instructions executed since the machine was created
+    pub fn instructions_retired(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    /// Cycles elapsed, every instruction takes a single cycle
+    pub fn cycles(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    /// Environment calls made since the last reset
+    pub fn syscalls(&self) -> &SyscallLog {
+        &self.syscalls
+    }
+
+    /// Checks every load and store against the bounds of `shadow`'s objects from now on,
+    /// replacing any objects given before
+    pub fn enable_bounds_checking(&mut self, shadow: ShadowMemory) {
+        self.shadow = Some(shadow);
+    }
+
+    /// Accesses past the end of data objects since the last reset, the first
+    /// [`MAX_BOUNDS_VIOLATIONS`] of them
+    pub fn bounds_violations(&self) -> &[BoundsViolation] {
+        &self.bounds_violations
+    }
+
+    /// Loads of memory nothing was written to since the last reset, the first
+    /// [`MAX_BOUNDS_VIOLATIONS`] of them, empty unless [`RunOptions::check_uninitialized`]
+    /// is set
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead] {
+        &self.uninitialized_reads
+    }
+
+    /// Returns with callee-saved registers changed since the last reset, the first
+    /// [`MAX_BOUNDS_VIOLATIONS`] of them, empty unless [`RunOptions::check_abi`] is set
+    pub fn abi_violations(&self) -> &[AbiViolation] {
+        &self.abi_violations
+    }
+
+    /// Detects loops from the control flow of every instruction executed from now on
+    pub fn enable_loop_profiling(&mut self) {
+        self.loops.get_or_insert_with(LoopProfile::new);
+    }
+
+    /// Loops executed since the last reset, hottest first, empty unless loop profiling is
+    /// enabled
+    pub fn hot_loops(&self) -> Vec<LoopStats> {
+        self.loops
+            .as_ref()
+            .map_or_else(Vec::new, |loops| loops.hot_loops(self.instructions_retired))
+    }
+
+    /// Current resource usage
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            instructions_retired: self.instructions_retired,
+            memory_in_use: (self.cpu.bus.program.len() + self.cpu.memory_written()) as u64,
+            output_bytes: self.console.bytes_written() as u64,
+            open_files: 0,
+        }
+    }
+
+    /// Handle that shows [`Machine::metrics`] as of the last few thousand instructions of a
+    /// run, see [`crate::metrics`]
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+}
+
+/// Adds an image to the digest of [`Machine::image_digest`]
+fn hash_image(hasher: &mut Sha256, address: u32, bytes: &[u8]) {
+    hasher.update(&address.to_le_bytes());
+    hasher.update(&(bytes.len() as u32).to_le_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{image::ImageFormat, shadow::DataObject};
+
+    fn program(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_runs_to_end_of_program() {
+        // addi a0, zero, 5; addi a0, a0, 2
+        let mut machine = Machine::new(program(&[0x00500513, 0x00250513]), 64);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert_eq!(outcome.exit_code, Some(7));
+        assert_eq!(outcome.instructions, 2);
+    }
+
+    #[test]
+    fn test_ecall_ends_the_run() {
+        // addi a0, zero, 3; ecall; addi a0, zero, 4
+        let mut machine = Machine::new(program(&[0x00300513, 0x00000073, 0x00400513]), 64);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall);
+        assert_eq!(outcome.exit_code, Some(3));
+        assert_eq!((outcome.instructions, machine.cpu.pc), (1, 4));
+        let stats: Vec<_> = machine.syscalls().iter().collect();
+        assert_eq!((stats.len(), stats[0].number, stats[0].count), (1, 0, 1));
+        assert_eq!(stats[0].last.arguments[0], 3);
+        machine.reset();
+        assert!(machine.syscalls().is_empty());
+    }
+
+    #[test]
+    fn test_program_ending_in_a_compressed_instruction() {
+        // addi a0, zero, 3; c.addi a0, 1
+        let mut bytes = program(&[0x00300513]);
+        bytes.extend_from_slice(&0x0505u16.to_le_bytes());
+        let mut machine = Machine::new(bytes, 64);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert_eq!((outcome.instructions, outcome.exit_code), (2, Some(4)));
+    }
+
+    #[test]
+    fn test_console_from_options() {
+        let options = RunOptions {
+            input: Some(b"in".to_vec()),
+            echo_output: false,
+            capture_output: true,
+            max_captured_output: Some(3),
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(Vec::new(), 64, &options);
+        assert_eq!(machine.console.read_byte(), Some(b'i'));
+        machine.console.write(b"output");
+        assert_eq!(machine.console.output(), b"out");
+    }
+
+    #[test]
+    fn test_machines_share_one_program() {
+        // addi a0, a0, 1; add a0, a0, a0
+        let shared: Arc<[u8]> = program(&[0x00150513, 0x00a50533]).into();
+        let options = RunOptions {
+            input: Some(Vec::new()),
+            echo_output: false,
+            ..RunOptions::default()
+        };
+
+        let handles: Vec<_> = (0..64u32)
+            .map(|case| {
+                let mut machine = Machine::from_shared(shared.clone(), 4096, &options);
+                std::thread::spawn(move || {
+                    machine.cpu.regs[10] = case.into();
+                    machine.run(&RunLimits::default()).exit_code
+                })
+            })
+            .collect();
+        for (case, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), Some((case as u32 + 1) * 2));
+        }
+        // Every machine dropped its handle, nothing copied the image
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn test_rv32e_exception_stops_the_run() {
+        // addi a6, zero, 1
+        let options = RunOptions {
+            base_isa: BaseIsa::Rv32E,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(program(&[0x00100813]), 64, &options);
+        let outcome = machine.run(&RunLimits::default());
+        assert!(matches!(
+            outcome.exit_reason,
+            ExitReason::Exception(Exception::IllegalInstruction { pc: 0, .. })
+        ));
+        assert_eq!(outcome.instructions, 0);
+    }
+
+    #[test]
+    fn test_load_binary_with_entry() {
+        let mut machine = Machine::new(Vec::new(), 0x20000);
+        // Data blob first so the code image is the one holding the entry point
+        machine.load_binary(&[1, 2, 3, 4], 0x10000, None).unwrap();
+        // addi a0, zero, 9
+        machine
+            .load_binary(&program(&[0x00900513]), 0x100, Some(0x100))
+            .unwrap();
+        assert_eq!(&machine.cpu.bus.dram[0x10000..0x10004], &[1, 2, 3, 4]);
+
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_code, Some(9));
+        assert_eq!(outcome.instructions, 1);
+        assert_eq!(machine.cpu.pc, 0x104);
+    }
+
+    #[test]
+    fn test_load_binary_errors() {
+        let mut machine = Machine::new(program(&[0x00000013]), 64);
+        assert!(matches!(
+            machine.load_binary(&[0; 8], 60, None),
+            Err(EmuError::LoadError(LoadError::OutOfMemory { .. }))
+        ));
+        assert!(matches!(
+            machine.load_binary(&[0; 4], 0, None),
+            Err(EmuError::LoadError(LoadError::OverlapsProgram { .. }))
+        ));
+        assert!(machine.load_binary(&[0; 4], 4, None).is_ok());
+    }
+
+    #[test]
+    fn test_load_srec_image() {
+        // addi a0, zero, 42 at 0x100, entry 0x100
+        let srec = "S10701001305A0023D\nS9030100FB\n";
+        let image = Image::parse(srec.as_bytes(), ImageFormat::detect(srec.as_bytes()), 0).unwrap();
+
+        let mut machine = Machine::new(Vec::new(), 0x1000);
+        machine.load_image(&image).unwrap();
+        assert_eq!(machine.cpu.pc, 0x100);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_code, Some(42));
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut machine = Machine::new(program(&[0x02a00513]), 16);
+        assert_eq!(machine.read_memory(2, 4).unwrap(), [0xa0, 0x02, 0, 0]);
+        machine.write_memory(8, &[1, 2]).unwrap();
+        assert_eq!(machine.read_memory(8, 2).unwrap(), [1, 2]);
+        assert_eq!(
+            machine.write_memory(0, &[0]),
+            Err(EmuError::BusFault { address: 0, len: 1 })
+        );
+        assert_eq!(
+            machine.read_memory(12, 8),
+            Err(EmuError::BusFault {
+                address: 12,
+                len: 8
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_checked_reports_exhausted_limits() {
+        let mut machine = Machine::new(program(&[0x00000013; 10]), 64);
+        let limits = RunLimits {
+            max_instructions: Some(3),
+        };
+        assert_eq!(
+            machine.run_checked(&limits),
+            Err(EmuError::LimitExhausted { limit: 3 })
+        );
+    }
+
+    #[test]
+    fn test_reset_restores_the_loaded_state() {
+        let options = RunOptions {
+            input: Some(b"x".to_vec()),
+            echo_output: false,
+            capture_output: true,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(Vec::new(), 0x1000, &options);
+        // addi a0, zero, 5; addi a0, a0, 2
+        machine
+            .load_binary(&program(&[0x00500513, 0x00250513]), 0x100, Some(0x100))
+            .unwrap();
+        machine.load_binary(&[1, 2, 3, 4], 0x800, None).unwrap();
+        assert_eq!(machine.run(&RunLimits::default()).exit_code, Some(7));
+        machine.write_memory(0x800, &[9; 8]).unwrap();
+        machine.console.read_byte();
+        machine.console.write(b"out");
+
+        machine.reset();
+        assert_eq!(machine.cpu.pc, 0x100);
+        assert_eq!(machine.cpu.regs, [0; 32]);
+        assert_eq!(machine.instructions_retired(), 0);
+        assert_eq!(
+            machine.read_memory(0x800, 8).unwrap(),
+            [1, 2, 3, 4, 0, 0, 0, 0]
+        );
+        assert!(machine.console.output().is_empty());
+        assert_eq!(machine.console.read_byte(), Some(b'x'));
+        // Runs exactly like the first time
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_code, Some(7));
+        assert_eq!(outcome.instructions, 2);
+    }
+
+    #[test]
+    fn test_interrupt_stops_and_resumes() {
+        // jal zero, 0
+        let mut machine = Machine::new(program(&[0x0000006f]), 64);
+        let interrupt = machine.interrupt_handle();
+        let stopper = std::thread::spawn({
+            let interrupt = interrupt.clone();
+            move || interrupt.request()
+        });
+        let outcome = machine.run(&RunLimits::default());
+        stopper.join().unwrap();
+        assert_eq!(outcome.exit_reason, ExitReason::Interrupted);
+        assert_eq!(outcome.exit_code, None);
+        assert!(!interrupt.is_requested());
+        // The request was consumed, the run picks up where it stopped
+        let outcome = machine.run(&RunLimits {
+            max_instructions: Some(5),
+        });
+        assert_eq!(outcome.exit_reason, ExitReason::InstructionLimit);
+    }
+
+    #[test]
+    fn test_metrics_are_pollable_during_a_run() {
+        // lui t0, 1; sw zero, 0(t0); sw zero, 8(t0); jal zero, 0
+        let mut machine = Machine::new(
+            program(&[0x000012b7, 0x0002a023, 0x0002a423, 0x0000006f]),
+            0x3000,
+        );
+        machine.console.write(b"hi");
+        let handle = machine.metrics_handle();
+        let interrupt = machine.interrupt_handle();
+        // A host enforcing an instruction quota by polling
+        let watcher = std::thread::spawn(move || {
+            while handle.snapshot().instructions_retired < 2 * PUBLISH_INTERVAL {
+                std::thread::yield_now();
+            }
+            interrupt.request();
+        });
+        let outcome = machine.run(&RunLimits::default());
+        watcher.join().unwrap();
+        assert_eq!(outcome.exit_reason, ExitReason::Interrupted);
+
+        let metrics = machine.metrics();
+        assert!(metrics.instructions_retired >= 2 * PUBLISH_INTERVAL);
+        // Both stores hit the same page
+        assert_eq!(metrics.memory_in_use, 16 + 4096);
+        assert_eq!(metrics.output_bytes, 2);
+        assert_eq!(machine.metrics_handle().snapshot(), metrics);
+
+        machine.reset();
+        assert_eq!(machine.metrics().memory_in_use, 16);
+        assert_eq!(machine.metrics_handle().snapshot().instructions_retired, 0);
+    }
+
+    #[test]
+    fn test_loop_profiling() {
+        let mut bytes = program(&[
+            0x00a00513, // addi a0, zero, 10
+            0x00158593, // addi a1, a1, 1
+            0xfff50513, // addi a0, a0, -1
+        ]);
+        bytes.extend_from_slice(&[0x65, 0xfd]); // c.bnez a0, -8
+        let mut machine = Machine::new(bytes, 64);
+        machine.enable_loop_profiling();
+        machine.run(&RunLimits::default());
+        let loops = machine.hot_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].header, loops[0].latch), (0x4, 0xc));
+        assert_eq!((loops[0].entries, loops[0].iterations()), (1, 10));
+        assert_eq!(loops[0].instructions_per_iteration(), 3.0);
+        machine.reset();
+        assert!(machine.hot_loops().is_empty());
+    }
+
+    #[test]
+    fn test_bounds_checking() {
+        let mut machine = Machine::new(
+            program(&[
+                0x10000293, // addi t0, zero, 0x100
+                0x00c2a503, // lw a0, 12(t0)
+                0x0102a503, // lw a0, 16(t0)
+                0x00a2aa23, // sw a0, 20(t0)
+            ]),
+            0x200,
+        );
+        machine.enable_bounds_checking(ShadowMemory::new([DataObject {
+            name: "table".to_string(),
+            address: 0x100,
+            size: 16,
+        }]));
+        machine.run(&RunLimits::default());
+        let violations: Vec<_> = machine
+            .bounds_violations()
+            .iter()
+            .map(|violation| (violation.pc, violation.access.write, violation.distance))
+            .collect();
+        assert_eq!(violations, [(8, false, 0), (12, true, 4)]);
+        machine.reset();
+        assert!(machine.bounds_violations().is_empty());
+    }
+
+    #[test]
+    fn test_strict_rv32i_profile() {
+        let options = RunOptions::strict_rv32i();
+        let mut machine = Machine::with_options(
+            program(&[
+                0x10000293, // addi t0, zero, 0x100
+                0x0052a023, // sw t0, 0(t0)
+                0x0002a503, // lw a0, 0(t0)
+                0x0042a583, // lw a1, 4(t0)
+                0x02b50533, // mul a0, a0, a1
+            ]),
+            0x200,
+            &options,
+        );
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(
+            outcome.exit_reason,
+            ExitReason::Exception(Exception::IllegalInstruction {
+                pc: 16,
+                instruction: 0x02b50533
+            })
+        );
+        let reads: Vec<_> = machine
+            .uninitialized_reads()
+            .iter()
+            .map(|read| (read.pc, read.address))
+            .collect();
+        assert_eq!(reads, [(12, 0x104)]);
+
+        // ecall numbers the bare system calls don't have are illegal
+        let mut machine = Machine::with_options(program(&[0x00000073]), 64, &options);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(
+            outcome.exit_reason,
+            ExitReason::Exception(Exception::IllegalInstruction {
+                pc: 0,
+                instruction: 0x00000073
+            })
+        );
+
+        // lui sp, 1; sw zero, 0(sp) with the stack starting at 0x1800
+        let mut machine =
+            Machine::with_options(program(&[0x00001137, 0x00012023]), 0x2000, &options);
+        machine.set_stack_limit(0x1800);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(
+            outcome.exit_reason,
+            ExitReason::Exception(Exception::StoreAccessFault {
+                pc: 4,
+                address: 0x1000
+            })
+        );
+    }
+
+    #[test]
+    fn test_filtered_tracing() {
+        // addi a0, zero, 1; lw a1, 0(zero); addi a0, a0, 1
+        let mut machine = Machine::new(program(&[0x00100513, 0x00002583, 0x00150513]), 64);
+        machine.enable_filtered_tracing(TraceFilter {
+            register: Some((10, 1)),
+            ..TraceFilter::default()
+        });
+        machine.run(&RunLimits::default());
+        let trace = machine.take_trace();
+        let pcs: Vec<u32> = trace.entries.iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [4, 8]);
+        assert_eq!(trace.entries[1].write, Some((10, 2)));
+    }
+
+    /// Shared buffer a test can read back after the machine finished writing
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streamed_trace() {
+        use crate::trace_file::TraceReader;
+
+        let mut machine = Machine::new(program(&[0x00500513, 0x00250513]), 64);
+        machine.enable_tracing();
+        machine.run(&RunLimits::default());
+        let expected = machine.take_trace();
+
+        let buffer = Shared::default();
+        let writer = TraceWriter::new(Box::new(buffer.clone()) as Box<dyn Write + Send>).unwrap();
+        machine.stream_trace(writer, TraceFilter::default());
+        machine.reset();
+        machine.run(&RunLimits::default());
+        assert!(machine.take_trace().is_empty());
+        assert_eq!(machine.finish_trace().unwrap(), 2);
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let trace = TraceReader::new(bytes.as_slice())
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(trace, expected);
+    }
+
+    #[test]
+    fn test_trap_handler() {
+        let mut machine = Machine::new(
+            program(&[
+                0x01800293, // addi t0, zero, 0x18
+                0x30529073, // csrrw zero, mtvec, t0
+                0x30046073, // csrrsi zero, mstatus, 8
+                0x7c002573, // csrrs a0, 0x7c0, zero, illegal
+                0x00100593, // addi a1, zero, 1
+                0x0200006f, // jal zero, 32 to the end
+                // The handler skips the faulting instruction
+                0x34202673, // csrrs a2, mcause, zero
+                0x341026f3, // csrrs a3, mepc, zero
+                0x34302773, // csrrs a4, mtval, zero
+                0x300027f3, // csrrs a5, mstatus, zero
+                0x00468693, // addi a3, a3, 4
+                0x34169073, // csrrw zero, mepc, a3
+                0x30200073, // mret
+            ]),
+            64,
+        );
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        let regs = &machine.cpu.regs;
+        assert_eq!(regs[11], 1);
+        assert_eq!((regs[12], regs[13], regs[14]), (2, 0x10, 0x7c002573));
+        // Interrupts were off in the handler and are back on after mret
+        assert_eq!(regs[15], 0x1880);
+        assert_eq!(machine.cpu.csrs.mstatus, 0x88);
+        // The faulting instruction didn't retire
+        assert_eq!(outcome.instructions, 12);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut machine = Machine::new(
+            program(&[
+                0x02004337, // lui t1, 0x2004, mtimecmp
+                0x00500293, // addi t0, zero, 5
+                0x00532023, // sw t0, 0(t1)
+                0x00032223, // sw zero, 4(t1)
+                0x02c00293, // addi t0, zero, 0x2c
+                0x30529073, // csrrw zero, mtvec, t0
+                0x08000293, // addi t0, zero, 0x80
+                0x3042a073, // csrrs zero, mie, t0
+                0x30046073, // csrrsi zero, mstatus, 8
+                0x0000006f, // jal zero, 0 until the timer fires
+                0x0200006f, // jal zero, 32 to the end
+                // The handler turns the timer off and returns past the loop
+                0x34202573, // csrrs a0, mcause, zero
+                0xfff00293, // addi t0, zero, -1
+                0x00532223, // sw t0, 4(t1)
+                0x341025f3, // csrrs a1, mepc, zero
+                0x00458593, // addi a1, a1, 4
+                0x34159073, // csrrw zero, mepc, a1
+                0x30200073, // mret
+            ]),
+            128,
+        );
+        machine.cpu.bus.attach(Clint::new());
+        assert!(
+            machine
+                .layout()
+                .regions()
+                .iter()
+                .any(|region| region.name == "clint")
+        );
+        let outcome = machine.run(&RunLimits {
+            max_instructions: Some(1000),
+        });
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert_eq!(machine.cpu.regs[10], 0x8000_0007);
+        assert_eq!(machine.cpu.regs[11], 0x28);
+        assert!(!machine.cpu.bus.device::<Clint>().unwrap().timer_pending());
+    }
+
+    #[test]
+    fn test_wfi_sleeps_until_interrupt() {
+        let mut machine = Machine::new(
+            program(&[
+                0x02004337, // lui t1, 0x2004, mtimecmp
+                0x01400293, // addi t0, zero, 20
+                0x00532023, // sw t0, 0(t1)
+                0x00032223, // sw zero, 4(t1)
+                0x08000293, // addi t0, zero, 0x80
+                0x3042a073, // csrrs zero, mie, t0, with mstatus.MIE off
+                0x10500073, // wfi until the timer is pending
+                0x0200c3b7, // lui t2, 0x200c
+                0xff83a503, // lw a0, -8(t2), mtime
+                0x0000100f, // fence.i
+            ]),
+            128,
+        );
+        machine.cpu.bus.attach(Clint::new());
+        let limits = RunLimits {
+            max_instructions: Some(1000),
+        };
+        let outcome = machine.run(&limits);
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert!(
+            (20..25).contains(&machine.cpu.regs[10]),
+            "{}",
+            machine.cpu.regs[10]
+        );
+
+        // Nothing can wake a wfi without interrupts enabled, so it doesn't sleep
+        let mut machine = Machine::new(program(&[0x10500073]), 128);
+        assert_eq!(machine.run(&limits).instructions, 1);
+        // Nothing does wake it without a timer, the limit ends the run
+        let sleeper = program(&[0x08000293, 0x3042a073, 0x10500073, 0x00000013]);
+        let mut machine = Machine::new(sleeper, 128);
+        let outcome = machine.run(&limits);
+        assert_eq!(outcome.exit_reason, ExitReason::InstructionLimit);
+        assert_eq!(machine.cpu.pc, 12);
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        let mut machine = Machine::new(
+            program(&[
+                0x0c000337, // lui t1, 0xc000, source priorities
+                0x00100293, // addi t0, zero, 1
+                0x00532a23, // sw t0, 20(t1), priority of source 5
+                0x0c0023b7, // lui t2, 0xc002, enable bits
+                0x02000293, // addi t0, zero, 32
+                0x0053a023, // sw t0, 0(t2)
+                0x03800293, // addi t0, zero, 0x38
+                0x30529073, // csrrw zero, mtvec, t0
+                0x000012b7, // lui t0, 1
+                0x0012d293, // srli t0, t0, 1, MEIE
+                0x3042a073, // csrrs zero, mie, t0
+                0x30046073, // csrrsi zero, mstatus, 8
+                0x0000006f, // jal zero, 0 until the device interrupts
+                0x0200006f, // jal zero, 32 to the end
+                // The handler claims the interrupt and returns past the loop
+                0x0c2003b7, // lui t2, 0xc200
+                0x0043a503, // lw a0, 4(t2), claim
+                0x342025f3, // csrrs a1, mcause, zero
+                0x34102673, // csrrs a2, mepc, zero
+                0x00460613, // addi a2, a2, 4
+                0x34161073, // csrrw zero, mepc, a2
+                0x30200073, // mret
+            ]),
+            128,
+        );
+        machine.cpu.bus.attach(Plic::new());
+        machine
+            .cpu
+            .bus
+            .device_mut::<Plic>()
+            .unwrap()
+            .set_level(5, true);
+        let outcome = machine.run(&RunLimits {
+            max_instructions: Some(1000),
+        });
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        let regs = &machine.cpu.regs;
+        assert_eq!((regs[10], regs[11], regs[12]), (5, 0x8000_000b, 0x34));
+        // Claimed but not completed, so the raised line doesn't interrupt again
+        let plic = machine.cpu.bus.device::<Plic>().unwrap();
+        assert_eq!((plic.claimed, plic.pending), (1 << 5, 0));
+    }
+
+    #[test]
+    fn test_uart_receive_interrupts() {
+        let options = RunOptions {
+            input: Some(b"ab".to_vec()),
+            echo_output: false,
+            capture_output: true,
+            plic: true,
+            uart: true,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(
+            program(&[
+                0x0c000337, // lui t1, 0xc000, source priorities
+                0x00100293, // addi t0, zero, 1
+                0x02532423, // sw t0, 40(t1), priority of the UART's source 10
+                0x0c0023b7, // lui t2, 0xc002, enable bits
+                0x40000293, // addi t0, zero, 0x400
+                0x0053a023, // sw t0, 0(t2)
+                0x10000eb7, // lui t4, 0x10000, the UART
+                0x00100293, // addi t0, zero, 1
+                0x005e80a3, // sb t0, 1(t4), interrupt on received data
+                0x04800293, // addi t0, zero, 0x48
+                0x30529073, // csrrw zero, mtvec, t0
+                0x000012b7, // lui t0, 1
+                0x0012d293, // srli t0, t0, 1, MEIE
+                0x3042a073, // csrrs zero, mie, t0
+                0x30046073, // csrrsi zero, mstatus, 8
+                0x00200293, // addi t0, zero, 2
+                0x00541063, // bne s0, t0, 0 until two bytes came in
+                0x0240006f, // jal zero, 36 to the end
+                // The handler echoes each byte in upper case
+                0x0c2003b7, // lui t2, 0xc200
+                0x0043ae03, // lw t3, 4(t2), claim
+                0x000ecf03, // lbu t5, 0(t4)
+                0xfe0f0f13, // addi t5, t5, -32
+                0x01ee8023, // sb t5, 0(t4)
+                0x00140413, // addi s0, s0, 1
+                0x01c3a223, // sw t3, 4(t2), complete
+                0x30200073, // mret
+            ]),
+            128,
+            &options,
+        );
+        let outcome = machine.run(&RunLimits {
+            max_instructions: Some(1000),
+        });
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert_eq!(machine.console.output(), b"AB");
+        let names: Vec<&str> = machine
+            .cpu
+            .bus
+            .devices()
+            .map(|device| device.name())
+            .collect();
+        assert_eq!(names, ["plic", "uart"]);
+    }
+
+    #[test]
+    fn test_overlays_in_banked_memory() {
+        let mut machine = Machine::new(
+            program(&[
+                0x000012b7, // lui t0, 1, the window
+                0x00100313, // addi t1, zero, 1
+                0x0062a823, // sw t1, 16(t0), select bank 1
+                0x000280e7, // jalr ra, 0(t0)
+                0x00050413, // addi s0, a0, 0
+                0x0002a823, // sw zero, 16(t0), select bank 0
+                0x000280e7, // jalr ra, 0(t0)
+                0x00850533, // add a0, a0, s0
+            ]),
+            0x100,
+        );
+        let overlay = |value: u32| program(&[0x00000513 | value << 20, 0x00008067]);
+        let banks = BankedMemory::new(0x1000, 0x10, vec![overlay(1), overlay(2)]).unwrap();
+        machine.cpu.bus.attach(banks);
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EndOfProgram);
+        assert_eq!((outcome.exit_code, outcome.instructions), (Some(3), 12));
+        // The window isn't an image, the program only runs there
+        assert_eq!(machine.images().len(), 1);
+
+        machine.reset();
+        assert_eq!(
+            machine.cpu.bus.device::<BankedMemory>().unwrap().selected(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_explaining() {
+        let mut machine = Machine::new(program(&[0x00500513, 0x00250593]), 64);
+        let buffer = Shared::default();
+        machine.explain_to(Box::new(buffer.clone()));
+        machine.run(&RunLimits::default());
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "0x00000000  addi x10, x0, 5 → x10 = 0 + 5 = 5\n\
+             0x00000004  addi x11, x10, 2 → x11 = 5 + 2 = 7\n"
+        );
+    }
+
+    #[test]
+    fn test_layout_catches_overlapping_images() {
+        let mut machine = Machine::new(program(&[0x00000013; 4]), 0x1000);
+        machine.load_binary(&[0; 0x100], 0x200, None).unwrap();
+        machine.load_binary(&[0; 0x10], 0x280, None).unwrap();
+        let layout = machine.layout();
+        let names: Vec<&str> = layout
+            .regions()
+            .iter()
+            .map(|region| region.name.as_str())
+            .collect();
+        assert_eq!(names, ["program", "image at 0x200", "image at 0x280"]);
+        assert_eq!(
+            layout.validate().map_err(EmuError::from),
+            Err(EmuError::LayoutError(crate::layout::LayoutError::Overlap {
+                first: Region::new("image at 0x200", RegionKind::Image, 0x200, 0x100),
+                second: Region::new("image at 0x280", RegionKind::Image, 0x280, 0x10),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut machine = Machine::new(program(&[0x00000013; 10]), 64);
+        let outcome = machine.run(&RunLimits {
+            max_instructions: Some(3),
+        });
+        assert_eq!(outcome.exit_reason, ExitReason::InstructionLimit);
+        assert_eq!(outcome.exit_code, None);
+        assert_eq!(machine.instructions_retired(), 3);
+    }
+}
@@ -0,0 +1,159 @@
+//! Verilog `$readmemh` reading and writing, for memories of 32 bit words.
+//!
+//! Every word is written on a line of its own as 8 hex digits, little-endian like the hart
+//! sees it, and every segment starts with an `@` address. Addresses count words, so a
+//! testbench can hand the file to a `reg [31:0] mem [...]` array as is.
+
+use std::collections::BTreeMap;
+
+use crate::image::{Image, ImageError};
+
+const WORD_SIZE: u32 = 4;
+
+/// Parses `$readmemh` text of 32 bit words, `//` and `/* */` comments are skipped. Words
+/// before the first `@` address start at address 0.
+pub fn parse(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::new();
+    let mut address: u64 = 0;
+    let mut in_comment = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| ImageError::Parse {
+            line: index + 1,
+            message,
+        };
+        let code = strip_comments(line, &mut in_comment);
+        let mut data = Vec::new();
+        let mut start = address;
+        for item in code.split_whitespace() {
+            if let Some(digits) = item.strip_prefix('@') {
+                let words = u32::from_str_radix(digits, 16)
+                    .map_err(|_| error(format!("invalid address '{}'", item)))?;
+                add_words(&mut image, start, std::mem::take(&mut data)).map_err(&error)?;
+                address = words as u64 * WORD_SIZE as u64;
+                start = address;
+                continue;
+            }
+            if item.len() > 8 {
+                return Err(error(format!("'{}' is wider than 32 bits", item)));
+            }
+            let word = u32::from_str_radix(item, 16)
+                .map_err(|_| error(format!("invalid hex word '{}'", item)))?;
+            data.extend(word.to_le_bytes());
+            address += WORD_SIZE as u64;
+        }
+        add_words(&mut image, start, data).map_err(&error)?;
+    }
+    Ok(image)
+}
+
+/// `line` without its comments, `in_comment` carries a block comment over to the next line
+fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut code = String::new();
+    let mut rest = line;
+    loop {
+        if *in_comment {
+            let Some((_, after)) = rest.split_once("*/") else {
+                return code;
+            };
+            rest = after;
+            *in_comment = false;
+        }
+        match (rest.find("//"), rest.find("/*")) {
+            (Some(line_start), block_start)
+                if block_start.is_none_or(|start| line_start < start) =>
+            {
+                code.push_str(&rest[..line_start]);
+                return code;
+            }
+            (_, Some(block_start)) => {
+                // A comment separates the words around it
+                code.push_str(&rest[..block_start]);
+                code.push(' ');
+                rest = &rest[block_start + 2..];
+                *in_comment = true;
+            }
+            (_, None) => {
+                code.push_str(rest);
+                return code;
+            }
+        }
+    }
+}
+
+fn add_words(image: &mut Image, address: u64, data: Vec<u8>) -> Result<(), String> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let address =
+        u32::try_from(address).map_err(|_| format!("address {:#x} is too large", address))?;
+    image
+        .add_segment(address, data)
+        .map_err(|source| source.to_string())
+}
+
+/// Writes the image as 32 bit words, bytes of a word no segment covers are `fill`
+pub fn write(image: &Image, fill: u8) -> String {
+    // Segments needn't be word aligned, and two may share a word
+    let mut words: BTreeMap<u32, [u8; WORD_SIZE as usize]> = BTreeMap::new();
+    for segment in image.segments() {
+        for (offset, byte) in segment.data.iter().enumerate() {
+            let address = segment.address + offset as u32;
+            let word = words
+                .entry(address / WORD_SIZE)
+                .or_insert([fill; WORD_SIZE as usize]);
+            word[(address % WORD_SIZE) as usize] = *byte;
+        }
+    }
+
+    let mut text = String::new();
+    let mut next = None;
+    for (index, word) in words {
+        if next != Some(index) {
+            text.push_str(&format!("@{:08x}\n", index));
+        }
+        text.push_str(&format!("{:08x}\n", u32::from_le_bytes(word)));
+        next = index.checked_add(1);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut image = Image::from_binary(&[0x13, 0x05, 0xa0, 0x02, 0x73], 0x100).unwrap();
+        image.add_segment(0x202, vec![0xaa, 0xbb]).unwrap();
+        let text = write(&image, 0);
+        assert_eq!(text, "@00000040\n02a00513\n00000073\n@00000080\nbbaa0000\n");
+        // Partial words come back padded
+        let mut padded =
+            Image::from_binary(&[0x13, 0x05, 0xa0, 0x02, 0x73, 0, 0, 0], 0x100).unwrap();
+        padded.add_segment(0x200, vec![0, 0, 0xaa, 0xbb]).unwrap();
+        assert_eq!(parse(&text).unwrap(), padded);
+    }
+
+    #[test]
+    fn test_parse_comments_and_errors() {
+        let image = parse(
+            "// boot rom\n\
+             00000013 /* nop */ 00100073\n\
+             /* data\n\
+             follows */ @10 deadbeef // the marker\n",
+        )
+        .unwrap();
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.segments()[0].data, [0x13, 0, 0, 0, 0x73, 0, 0x10, 0]);
+        assert_eq!(image.segments()[1].address, 0x40);
+        assert_eq!(image.segments()[1].data, 0xdeadbeefu32.to_le_bytes());
+
+        assert!(matches!(
+            parse("00000013\n123456789\n"),
+            Err(ImageError::Parse { line: 2, .. })
+        ));
+        assert!(parse("@xyz\n").is_err());
+        assert!(parse("00000013\n@0 00000013\n").is_err());
+    }
+}
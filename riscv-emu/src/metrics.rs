@@ -0,0 +1,142 @@
+//! Resource usage of a running guest, for hosts that watch many guests and enforce quotas.
+//!
+//! [`Machine::metrics`](crate::machine::Machine::metrics) reads the exact counters between
+//! runs. While [`Machine::run`](crate::machine::Machine::run) holds the machine, a
+//! [`MetricsHandle`] polled from another thread sees them as of the last few thousand
+//! instructions; a host over its quota stops the guest with the machine's
+//! [`Interrupt`](crate::machine::Interrupt), which leaves it intact for inspection.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+/// Instructions between two updates of the [`MetricsHandle`] during a run
+pub const PUBLISH_INTERVAL: u64 = 4096;
+
+/// Size of the pages guest memory use is counted in
+pub const PAGE_SIZE: usize = 4096;
+
+/// Counters of a machine at one point of its run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Instructions executed since the machine was created
+    pub instructions_retired: u64,
+    /// Bytes of guest memory in use: the program image plus every page of writable memory
+    /// written since the last reset
+    pub memory_in_use: u64,
+    /// Bytes the guest wrote to its console since the last reset
+    pub output_bytes: u64,
+    /// Host files the guest holds open, always 0 until file system calls are supported
+    pub open_files: u32,
+}
+
+/// Shared view of a machine's [`Metrics`], readable while the machine runs on another thread
+#[derive(Debug, Clone, Default)]
+pub struct MetricsHandle(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    instructions_retired: AtomicU64,
+    memory_in_use: AtomicU64,
+    output_bytes: AtomicU64,
+    open_files: AtomicU32,
+}
+
+impl MetricsHandle {
+    /// The most recently published counters
+    pub fn snapshot(&self) -> Metrics {
+        Metrics {
+            instructions_retired: self.0.instructions_retired.load(Ordering::Relaxed),
+            memory_in_use: self.0.memory_in_use.load(Ordering::Relaxed),
+            output_bytes: self.0.output_bytes.load(Ordering::Relaxed),
+            open_files: self.0.open_files.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn publish(&self, metrics: &Metrics) {
+        let counters = &self.0;
+        counters
+            .instructions_retired
+            .store(metrics.instructions_retired, Ordering::Relaxed);
+        counters
+            .memory_in_use
+            .store(metrics.memory_in_use, Ordering::Relaxed);
+        counters
+            .output_bytes
+            .store(metrics.output_bytes, Ordering::Relaxed);
+        counters
+            .open_files
+            .store(metrics.open_files, Ordering::Relaxed);
+    }
+}
+
+/// Which pages of writable memory have been written
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TouchedPages {
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl TouchedPages {
+    pub(crate) fn new(memory_size: usize) -> Self {
+        Self {
+            // Zeroed like the memory itself, so the bitmap of a huge memory costs nothing
+            // until it is written
+            bits: vec![0; memory_size.div_ceil(PAGE_SIZE).div_ceil(64)],
+            count: 0,
+        }
+    }
+
+    /// Marks the pages of the `len` bytes at `start`
+    pub(crate) fn touch(&mut self, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for page in start / PAGE_SIZE..=(start + len - 1) / PAGE_SIZE {
+            let Some(word) = self.bits.get_mut(page / 64) else {
+                return;
+            };
+            let bit = 1 << (page % 64);
+            if *word & bit == 0 {
+                *word |= bit;
+                self.count += 1;
+            }
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_touched_pages() {
+        let mut pages = TouchedPages::new(4 * PAGE_SIZE);
+        pages.touch(10, 4);
+        pages.touch(20, 1);
+        assert_eq!(pages.bytes(), PAGE_SIZE);
+        // Straddles the second and third page
+        pages.touch(3 * PAGE_SIZE - 2, 4);
+        pages.touch(0, 0);
+        assert_eq!(pages.bytes(), 3 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_handle_shows_published_metrics() {
+        let handle = MetricsHandle::default();
+        let viewer = handle.clone();
+        let metrics = Metrics {
+            instructions_retired: 5,
+            memory_in_use: 4096,
+            output_bytes: 3,
+            open_files: 0,
+        };
+        handle.publish(&metrics);
+        assert_eq!(viewer.snapshot(), metrics);
+    }
+}
@@ -0,0 +1,198 @@
+//! Platform-level interrupt controller: collects the interrupt lines of devices and delivers
+//! the highest priority one to the hart as its machine external interrupt, at the address
+//! and with the register map of the SiFive PLIC and QEMU's virt machine.
+//!
+//! There is a single context, machine mode of the one hart. A handler claims the source it
+//! is serving by reading the claim register, and writes the source back when it's done; a
+//! claimed source doesn't interrupt again until then, even if its device keeps the line
+//! raised.
+
+use crate::{bus::Device, csr::MEI};
+
+/// Address the PLIC's registers are mapped at
+pub const PLIC_BASE: u32 = 0x0c00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+
+/// Interrupt sources including source 0, which means "no interrupt" and never fires
+pub const SOURCES: usize = 32;
+/// Priorities go from 1 to this, a source of priority 0 is never delivered
+pub const MAX_PRIORITY: u32 = 7;
+
+/// Offsets of the registers from [`PLIC_BASE`]: one priority word per source, then bit
+/// arrays of pending and enabled sources, then the context's threshold and claim register
+pub const PRIORITY: u32 = 0x00_0000;
+pub const PENDING: u32 = 0x00_1000;
+pub const ENABLE: u32 = 0x00_2000;
+pub const THRESHOLD: u32 = 0x20_0000;
+pub const CLAIM: u32 = 0x20_0004;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plic {
+    pub priority: [u32; SOURCES],
+    /// Bit per source, set when its line is raised and cleared by claiming it
+    pub pending: u32,
+    pub enabled: u32,
+    /// Only sources of a higher priority than this are delivered
+    pub threshold: u32,
+    /// Sources claimed by the handler that it hasn't completed yet
+    pub claimed: u32,
+    /// Interrupt lines as the devices last set them
+    levels: u32,
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raises or lowers the interrupt line of `source`, lines are level-triggered
+    pub fn set_level(&mut self, source: usize, raised: bool) {
+        if source == 0 || source >= SOURCES {
+            return;
+        }
+        if raised {
+            self.levels |= 1 << source;
+        } else {
+            self.levels &= !(1 << source);
+        }
+        self.update();
+    }
+
+    /// Whether an enabled source is pending above the threshold, i.e. whether the hart's
+    /// external interrupt is pending
+    pub fn interrupt_pending(&self) -> bool {
+        self.best().is_some()
+    }
+
+    /// Takes the highest priority pending source, 0 if there is none
+    fn claim(&mut self) -> u32 {
+        let Some(source) = self.best() else {
+            return 0;
+        };
+        self.pending &= !(1 << source);
+        self.claimed |= 1 << source;
+        source as u32
+    }
+
+    /// The source to deliver: the highest priority above the threshold, the lowest numbered
+    /// one among equals
+    fn best(&self) -> Option<usize> {
+        let candidates = self.pending & self.enabled;
+        (1..SOURCES)
+            .filter(|&source| candidates & 1 << source != 0)
+            .filter(|&source| self.priority[source] > self.threshold)
+            .min_by_key(|&source| MAX_PRIORITY - self.priority[source])
+    }
+
+    fn update(&mut self) {
+        self.pending |= self.levels & !self.claimed;
+    }
+}
+
+impl Device for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn base(&self) -> u32 {
+        PLIC_BASE
+    }
+
+    fn size(&self) -> u32 {
+        PLIC_SIZE
+    }
+
+    /// Reading the claim register claims an interrupt. `None` for other sizes than words,
+    /// unaligned words and offsets between the registers.
+    fn load(&mut self, offset: u32, len: usize) -> Option<u32> {
+        if len != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        Some(match offset {
+            PRIORITY..PENDING => *self.priority.get(offset as usize / 4)?,
+            PENDING => self.pending,
+            ENABLE => self.enabled,
+            THRESHOLD => self.threshold,
+            CLAIM => self.claim(),
+            // Pending and enable words of sources that don't exist
+            0x1004..0x1080 | 0x2004..0x2080 => 0,
+            _ => return None,
+        })
+    }
+
+    /// Writing a source to the claim register completes its interrupt
+    fn store(&mut self, offset: u32, len: usize, value: u32) -> Option<()> {
+        if len != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        match offset {
+            PRIORITY..PENDING => {
+                let source = offset as usize / 4;
+                // Source 0 has no priority to set
+                if source != 0 {
+                    *self.priority.get_mut(source)? = value.min(MAX_PRIORITY);
+                }
+            }
+            // Pending bits follow the devices
+            PENDING | 0x1004..0x1080 | 0x2004..0x2080 => {}
+            ENABLE => self.enabled = value & !1,
+            THRESHOLD => self.threshold = value.min(MAX_PRIORITY),
+            CLAIM => {
+                if let Some(bit) = 1u32.checked_shl(value)
+                    && self.enabled & bit != 0
+                {
+                    self.claimed &= !bit;
+                    self.update();
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn interrupts(&self) -> u64 {
+        if self.interrupt_pending() { MEI } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_and_complete() {
+        let mut plic = Plic::new();
+        plic.store(PRIORITY + 4 * 3, 4, 2).unwrap();
+        plic.store(PRIORITY + 4 * 5, 4, 6).unwrap();
+        plic.store(ENABLE, 4, 1 << 3 | 1 << 5).unwrap();
+        plic.set_level(3, true);
+        plic.set_level(5, true);
+        assert!(plic.interrupt_pending());
+        assert_eq!(plic.load(PENDING, 4), Some(1 << 3 | 1 << 5));
+
+        // Higher priority first, and a claimed source stays quiet while its line is raised
+        assert_eq!(plic.load(CLAIM, 4), Some(5));
+        assert_eq!(plic.load(CLAIM, 4), Some(3));
+        assert_eq!(plic.load(CLAIM, 4), Some(0));
+        assert!(!plic.interrupt_pending());
+        plic.set_level(3, false);
+        plic.store(CLAIM, 4, 3).unwrap();
+        plic.store(CLAIM, 4, 5).unwrap();
+        assert_eq!(plic.pending, 1 << 5);
+
+        // Nothing at or below the threshold is delivered
+        plic.store(THRESHOLD, 4, 6).unwrap();
+        assert!(!plic.interrupt_pending());
+        plic.store(THRESHOLD, 4, 5).unwrap();
+        assert!(plic.interrupt_pending());
+
+        assert_eq!(plic.load(PRIORITY + 4 * 5, 4), Some(6));
+        assert_eq!(plic.load(PRIORITY + 4 * SOURCES as u32, 4), None);
+        assert_eq!(plic.load(CLAIM, 2), None);
+        assert_eq!(plic.interrupts(), MEI);
+    }
+}
@@ -0,0 +1,214 @@
+//! Bounds checking of accesses to known data objects, in the spirit of AddressSanitizer.
+//!
+//! The bytes after each object (a labelled `.word` array, say) form a redzone up to the next
+//! object, at most [`REDZONE`] bytes. A load or store touching a redzone is reported with
+//! the object it overflows and how far past its end it starts. Objects placed back to back
+//! leave no redzone between them, overflowing one into the next goes unnoticed.
+
+use std::{collections::BTreeMap, fmt, ops::Bound};
+
+/// Bytes after an object that are checked for overflows
+pub const REDZONE: u32 = 64;
+
+/// A named block of memory whose bounds are known, e.g. from the assembler's data section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataObject {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+impl DataObject {
+    /// One past the last byte, 64 bit so an object ending at 4GiB doesn't wrap
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.size as u64
+    }
+}
+
+/// A load or store made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u32,
+    pub len: u32,
+    pub write: bool,
+}
+
+impl MemoryAccess {
+    /// The access of an (expanded) instruction with the registers as they are before it
+    /// executes, `None` for instructions that don't access memory
+    pub fn of(instruction: u32, regs: &[u64; 32]) -> Option<Self> {
+        let funct3 = (instruction >> 12) & 0x7;
+        let base = regs[((instruction >> 15) & 0x1f) as usize] as u32;
+        let imm_i = (instruction as i32 >> 20) as u32;
+        let imm_s = ((instruction as i32 >> 25) << 5) as u32 | (instruction >> 7) & 0x1f;
+        let (offset, len, write) = match (instruction & 0x7f, funct3) {
+            // Loads
+            (0b0000011, 0 | 4) => (imm_i, 1, false),
+            (0b0000011, 1 | 5) => (imm_i, 2, false),
+            (0b0000011, 2) => (imm_i, 4, false),
+            // Stores
+            (0b0100011, 0..=2) => (imm_s, 1 << funct3, true),
+            // Floating point loads and stores
+            (0b0000111, 2 | 3) => (imm_i, 1 << funct3, false),
+            (0b0100111, 2 | 3) => (imm_s, 1 << funct3, true),
+            // LR.W only reads, SC.W and the AMOs write
+            (0b0101111, 2) => (0, 4, instruction >> 27 != 0x02),
+            _ => return None,
+        };
+        Some(Self {
+            address: base.wrapping_add(offset),
+            len,
+            write,
+        })
+    }
+}
+
+/// An access that ran past the end of a data object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundsViolation {
+    /// Address of the accessing instruction
+    pub pc: u32,
+    pub access: MemoryAccess,
+    pub object: DataObject,
+    /// Bytes between the end of the object and the start of the access, 0 for accesses
+    /// straddling the end
+    pub distance: u32,
+}
+
+impl fmt::Display for BoundsViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} byte {} at {:#010x} (pc {:#010x}) is {} bytes past the end of '{}' ({} bytes at {:#010x})",
+            self.access.len,
+            if self.access.write { "write" } else { "read" },
+            self.access.address,
+            self.pc,
+            self.distance,
+            self.object.name,
+            self.object.size,
+            self.object.address
+        )
+    }
+}
+
+/// The data objects of a program, looked up by address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowMemory {
+    objects: BTreeMap<u32, DataObject>,
+}
+
+impl ShadowMemory {
+    /// Objects with no bytes are left out, they have no bounds to overflow
+    pub fn new(objects: impl IntoIterator<Item = DataObject>) -> Self {
+        let objects = objects
+            .into_iter()
+            .filter(|object| object.size > 0)
+            .map(|object| (object.address, object))
+            .collect();
+        Self { objects }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The violation if `access`, made by the instruction at `pc`, touches an object's
+    /// redzone
+    pub fn check(&self, pc: u32, access: MemoryAccess) -> Option<BoundsViolation> {
+        let (_, object) = self.objects.range(..=access.address).next_back()?;
+        let start = access.address as u64;
+        if start + access.len as u64 <= object.end() {
+            return None;
+        }
+        let next = self
+            .objects
+            .range((Bound::Excluded(object.address), Bound::Unbounded))
+            .next()
+            .map_or(u64::MAX, |(&address, _)| address as u64);
+        if start >= next.min(object.end() + REDZONE as u64) {
+            return None;
+        }
+        Some(BoundsViolation {
+            pc,
+            access,
+            object: object.clone(),
+            distance: start.saturating_sub(object.end()) as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(name: &str, address: u32, size: u32) -> DataObject {
+        DataObject {
+            name: name.to_string(),
+            address,
+            size,
+        }
+    }
+
+    fn read(address: u32, len: u32) -> MemoryAccess {
+        MemoryAccess {
+            address,
+            len,
+            write: false,
+        }
+    }
+
+    #[test]
+    fn test_accesses_past_the_end_are_flagged() {
+        let shadow = ShadowMemory::new([
+            object("table", 0x100, 16),
+            object("next", 0x110, 4),
+            object("last", 0x200, 8),
+        ]);
+        assert_eq!(shadow.check(0, read(0x10c, 4)), None);
+        assert_eq!(shadow.check(0, read(0xfc, 4)), None);
+        // table's bytes run straight into next, a load straddling both is still caught
+        let violation = shadow.check(8, read(0x10e, 4)).unwrap();
+        assert_eq!(
+            (violation.object.name.as_str(), violation.distance),
+            ("table", 0)
+        );
+        assert_eq!(shadow.check(0, read(0x110, 4)), None);
+
+        let violation = shadow.check(8, read(0x20c, 4)).unwrap();
+        assert_eq!(
+            (violation.object.name.as_str(), violation.distance),
+            ("last", 4)
+        );
+        assert_eq!(
+            violation.to_string(),
+            "4 byte read at 0x0000020c (pc 0x00000008) is 4 bytes past the end of 'last' \
+             (8 bytes at 0x00000200)"
+        );
+        // Beyond the redzone
+        assert_eq!(shadow.check(0, read(0x208 + REDZONE, 4)), None);
+    }
+
+    #[test]
+    fn test_memory_access_of_instructions() {
+        let mut regs = [0; 32];
+        regs[11] = 0x100;
+        // lw a0, 8(a1)
+        assert_eq!(MemoryAccess::of(0x0085a503, &regs), Some(read(0x108, 4)));
+        // sh a0, -2(a1)
+        assert_eq!(
+            MemoryAccess::of(0xfea59f23, &regs),
+            Some(MemoryAccess {
+                address: 0xfe,
+                len: 2,
+                write: true
+            })
+        );
+        // fld fa0, 0(a1)
+        assert_eq!(MemoryAccess::of(0x0005b507, &regs), Some(read(0x100, 8)));
+        // lr.w a0, (a1)
+        assert_eq!(MemoryAccess::of(0x1005a52f, &regs), Some(read(0x100, 4)));
+        // addi a0, a1, 8
+        assert_eq!(MemoryAccess::of(0x00858513, &regs), None);
+    }
+}
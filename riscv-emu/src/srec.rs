@@ -0,0 +1,146 @@
+//! Motorola S-record reading and writing
+
+use crate::image::{Image, ImageError};
+
+/// Data bytes per record
+const BYTES_PER_RECORD: usize = 16;
+
+/// Parses S-record text, header (S0) and count (S5/S6) records are skipped
+pub fn parse(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| ImageError::Parse {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            return Err(error("record does not start with 'S'".to_string()));
+        }
+        let kind = chars
+            .next()
+            .and_then(|kind| kind.to_digit(10))
+            .ok_or_else(|| error("missing record type".to_string()))?;
+        let bytes =
+            decode_hex(chars.as_str()).ok_or_else(|| error("invalid hex digits".to_string()))?;
+        // The count covers the address, data and checksum
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error(
+                "record length does not match its byte count".to_string(),
+            ));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+            return Err(error("checksum mismatch".to_string()));
+        }
+
+        let address_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(error(format!("unsupported record type S{}", kind))),
+        };
+        let fields = &bytes[1..bytes.len() - 1];
+        if fields.len() < address_len {
+            return Err(error("record too short for its address".to_string()));
+        }
+        let address = fields[..address_len]
+            .iter()
+            .fold(0u32, |address, byte| address << 8 | *byte as u32);
+        let data = &fields[address_len..];
+        match kind {
+            1..=3 => image
+                .add_segment(address, data.to_vec())
+                .map_err(|source| error(source.to_string()))?,
+            7..=9 => image.entry = Some(address),
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+/// Writes the image as S3 data records with an S7 start address
+pub fn write(image: &Image) -> String {
+    let mut text = String::new();
+    write_record(&mut text, 0, &[0, 0], &[]);
+    for segment in image.segments() {
+        for (index, chunk) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let address = segment.address + (index * BYTES_PER_RECORD) as u32;
+            write_record(&mut text, 3, &address.to_be_bytes(), chunk);
+        }
+    }
+    let entry = image.entry.or(image.start()).unwrap_or(0);
+    write_record(&mut text, 7, &entry.to_be_bytes(), &[]);
+    text
+}
+
+fn write_record(text: &mut String, kind: u8, address: &[u8], data: &[u8]) {
+    let mut record = vec![(address.len() + data.len() + 1) as u8];
+    record.extend(address);
+    record.extend(data);
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+
+    text.push_str(&format!("S{}", kind));
+    for byte in record {
+        text.push_str(&format!("{:02X}", byte));
+    }
+    text.push('\n');
+}
+
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() {
+        // addi a0, zero, 42 at 0x80000000, entry 0x80000000
+        let image = parse(
+            "S00600004844521B\n\
+             S30980000000130520023C\n\
+             S705800000007A\n",
+        )
+        .unwrap();
+        assert_eq!(image.start(), Some(0x8000_0000));
+        assert_eq!(image.to_binary(0), [0x13, 0x05, 0x20, 0x02]);
+        assert_eq!(image.entry, Some(0x8000_0000));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut image = Image::from_binary(&(0..20).collect::<Vec<u8>>(), 0x1000).unwrap();
+        image.add_segment(0x2000, vec![0xaa]).unwrap();
+        image.entry = Some(0x1000);
+        let text = write(&image);
+        assert!(text.starts_with("S0030000FC\n"));
+        assert!(text.ends_with("S70500001000EA\n"));
+        assert_eq!(parse(&text).unwrap(), image);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            parse("S1070000130520025F\n"),
+            Err(ImageError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            parse("S4030000FC\n"),
+            Err(ImageError::Parse { line: 1, .. })
+        ));
+        assert!(parse(":00000001FF\n").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use thiserror::Error;
+
+/// Symbol names by address, used to symbolize addresses in reports, traces and the debugger.
+///
+/// Loaded from nm-style symbol files (`ADDRESS TYPE NAME` per line) so binaries built by
+/// external toolchains can be symbolized too, e.g. `riscv64-unknown-elf-nm prog.elf > prog.sym`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// Names at each address, sorted so aliases always come out in the same order
+    by_address: BTreeMap<u32, Vec<String>>,
+    /// nm type letter of each symbol, `T` unless the symbol file said otherwise
+    kinds: BTreeMap<String, char>,
+}
+
+/// What is known about a single symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolInfo<'a> {
+    pub name: &'a str,
+    pub address: u32,
+    /// nm type letter, e.g. `T` for code or `D` for data
+    pub kind: char,
+    /// Bytes up to the next symbol, `None` for the last one
+    pub size: Option<u32>,
+}
+
+impl SymbolInfo<'_> {
+    /// Section the nm type letter places the symbol in
+    pub fn section(&self) -> &'static str {
+        match self.kind.to_ascii_lowercase() {
+            't' => ".text",
+            'd' => ".data",
+            'b' => ".bss",
+            'r' => ".rodata",
+            's' => ".sdata",
+            'a' => "*ABS*",
+            _ => "*UND*",
+        }
+    }
+}
+
+/// A malformed line in a symbol file
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}: {message}")]
+pub struct SymbolFileError {
+    /// 1-based line number
+    pub line: usize,
+    pub message: String,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses an nm-style symbol file.
+    ///
+    /// Lines are `ADDRESS TYPE NAME` or `ADDRESS NAME` with a hexadecimal address. Blank
+    /// lines, `#` comments and undefined symbols (nm prints them without an address) are
+    /// skipped.
+    pub fn parse(text: &str) -> Result<Self, SymbolFileError> {
+        let mut symbols = Self::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| SymbolFileError {
+                line: index + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (address, kind, name) = match fields[..] {
+                [kind, _] if kind == "U" || kind == "w" => continue,
+                [address, name] => (address, "T", name),
+                [address, kind, name] => (address, kind, name),
+                _ => {
+                    return Err(error(format!(
+                        "expected 'ADDRESS TYPE NAME', found '{}'",
+                        line
+                    )));
+                }
+            };
+            let hex = address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+                .unwrap_or(address);
+            // 64 bit toolchains print 16 digit addresses, keep the low 32 bits
+            let address = u64::from_str_radix(hex, 16)
+                .map_err(|_| error(format!("invalid address '{}'", address)))?;
+            let kind = match kind.chars().collect::<Vec<_>>()[..] {
+                [kind] if kind.is_ascii_alphabetic() => kind,
+                _ => return Err(error(format!("invalid symbol type '{}'", kind))),
+            };
+            symbols.insert_with_kind(name, address as u32, kind);
+        }
+        Ok(symbols)
+    }
+
+    /// Adds a code (`T`) symbol
+    pub fn insert(&mut self, name: &str, address: u32) {
+        self.insert_with_kind(name, address, 'T');
+    }
+
+    pub fn insert_with_kind(&mut self, name: &str, address: u32, kind: char) {
+        let names = self.by_address.entry(address).or_default();
+        if let Err(position) = names.binary_search_by(|existing| existing.as_str().cmp(name)) {
+            names.insert(position, name.to_string());
+        }
+        self.kinds.insert(name.to_string(), kind);
+    }
+
+    /// Every symbol sorted by address, aliases by name
+    pub fn iter(&self) -> impl Iterator<Item = SymbolInfo<'_>> {
+        self.by_address.iter().flat_map(move |(&address, names)| {
+            let size = self
+                .by_address
+                .range((Bound::Excluded(address), Bound::Unbounded))
+                .next()
+                .map(|(next, _)| next - address);
+            names.iter().map(move |name| SymbolInfo {
+                name,
+                address,
+                kind: self.kinds.get(name).copied().unwrap_or('T'),
+                size,
+            })
+        })
+    }
+
+    pub fn info(&self, name: &str) -> Option<SymbolInfo<'_>> {
+        self.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Symbols whose name matches `pattern`, a glob with `*` and `?` or else a substring
+    pub fn matching<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = SymbolInfo<'a>> {
+        self.iter().filter(move |symbol| {
+            if pattern.contains(['*', '?']) {
+                glob_match(pattern.as_bytes(), symbol.name.as_bytes())
+            } else {
+                symbol.name.contains(pattern)
+            }
+        })
+    }
+
+    /// Address of the symbol called `name`
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.by_address
+            .iter()
+            .find(|(_, names)| names.iter().any(|existing| existing == name))
+            .map(|(address, _)| *address)
+    }
+
+    /// Closest symbol at or below `address`, and the offset from it
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .map(|(start, names)| (names[0].as_str(), address - start))
+    }
+
+    /// `name` or `name+0xoffset` for `address`, `None` below the first symbol
+    pub fn symbolize(&self, address: u32) -> Option<String> {
+        self.lookup(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_address.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+}
+
+/// Matches `*` (any run of characters) and `?` (any single character)
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => glob_match(rest, text),
+        (Some((expected, rest)), Some((actual, text))) => {
+            expected == actual && glob_match(rest, text)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nm_output() {
+        let symbols = Symbols::parse(
+            "# produced by nm\n\
+             00000000 T _start\n\
+             0000000000000010 t loop\n\
+             \x20        U printf\n\
+             0x20 done\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.address("loop"), Some(0x10));
+        assert_eq!(symbols.address("printf"), None);
+        assert_eq!(symbols.address("done"), Some(0x20));
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        assert_eq!(
+            Symbols::parse("00000000 T main\nzz T bad\n"),
+            Err(SymbolFileError {
+                line: 2,
+                message: "invalid address 'zz'".to_string()
+            })
+        );
+        assert!(Symbols::parse("00000000 T main extra\n").is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let symbols =
+            Symbols::parse("00000000 T main\n00000010 t main_loop\n00000100 D counter\n").unwrap();
+        let names: Vec<&str> = symbols.matching("main").map(|symbol| symbol.name).collect();
+        assert_eq!(names, ["main", "main_loop"]);
+        let names: Vec<&str> = symbols.matching("*n?r").map(|symbol| symbol.name).collect();
+        assert_eq!(names, Vec::<&str>::new());
+        let names: Vec<&str> = symbols
+            .matching("c*t?r")
+            .map(|symbol| symbol.name)
+            .collect();
+        assert_eq!(names, ["counter"]);
+
+        let main_loop = symbols.info("main_loop").unwrap();
+        assert_eq!(main_loop.size, Some(0xf0));
+        assert_eq!(main_loop.section(), ".text");
+        let counter = symbols.info("counter").unwrap();
+        assert_eq!((counter.kind, counter.size), ('D', None));
+        assert_eq!(counter.section(), ".data");
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let mut symbols = Symbols::new();
+        symbols.insert("main", 0x100);
+        symbols.insert("loop", 0x110);
+        symbols.insert("alias", 0x110);
+        assert_eq!(symbols.symbolize(0xfc), None);
+        assert_eq!(symbols.symbolize(0x100).as_deref(), Some("main"));
+        assert_eq!(symbols.symbolize(0x108).as_deref(), Some("main+0x8"));
+        assert_eq!(symbols.symbolize(0x114).as_deref(), Some("alias+0x4"));
+    }
+}
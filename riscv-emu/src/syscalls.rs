@@ -0,0 +1,388 @@
+//! Environment calls a program makes: emulating them and keeping their books.
+//!
+//! Without a [`SyscallHandler`] every `ecall` ends the run. A handler carries the calls it
+//! knows out instead, [`BareSyscalls`] the RARS-style ones teaching material uses for console
+//! I/O and [`LinuxSyscalls`](crate::linux::LinuxSyscalls) those of Linux user-mode programs. Calls are summarized per system call number (a7), so a report can show what I/O a
+//! program asked the host for without keeping every single call of a long run.
+
+use std::collections::BTreeMap;
+
+use crate::machine::Machine;
+
+/// Registers a0-a5 carry a system call's arguments
+pub const ARGUMENT_COUNT: usize = 6;
+
+/// One environment call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallCall {
+    pub pc: u32,
+    /// a0-a5 at the time of the call
+    pub arguments: [u64; ARGUMENT_COUNT],
+    /// a0 after the call returned, `None` if it wasn't handled and ended the run instead
+    pub result: Option<u64>,
+}
+
+/// Every call to one system call number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallStats {
+    pub number: u32,
+    pub count: u64,
+    /// The most recent call
+    pub last: SyscallCall,
+}
+
+impl SyscallStats {
+    /// Conventional name of the call, see [`name`]
+    pub fn name(&self) -> Option<&'static str> {
+        name(self.number)
+    }
+}
+
+/// Environment calls of a run, by system call number
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyscallLog {
+    stats: BTreeMap<u32, SyscallStats>,
+}
+
+impl SyscallLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an `ecall` at `pc` with the registers as they were when it executed, and a0
+    /// after it returned
+    pub fn record(&mut self, pc: u32, regs: &[u64; 32], result: Option<u64>) {
+        let number = regs[17] as u32;
+        let mut arguments = [0; ARGUMENT_COUNT];
+        arguments.copy_from_slice(&regs[10..10 + ARGUMENT_COUNT]);
+        let call = SyscallCall {
+            pc,
+            arguments,
+            result,
+        };
+        self.stats
+            .entry(number)
+            .and_modify(|stats| {
+                stats.count += 1;
+                stats.last = call;
+            })
+            .or_insert(SyscallStats {
+                number,
+                count: 1,
+                last: call,
+            });
+    }
+
+    /// Per-number summaries, ordered by number
+    pub fn iter(&self) -> impl Iterator<Item = &SyscallStats> {
+        self.stats.values()
+    }
+
+    /// Number of calls across all numbers
+    pub fn total(&self) -> u64 {
+        self.stats.values().map(|stats| stats.count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.stats.clear();
+    }
+}
+
+/// Which system calls a machine emulates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyscallMode {
+    /// Every `ecall` ends the run, with a0 as the exit code
+    #[default]
+    None,
+    /// The calls of [`BareSyscalls`]
+    Bare,
+    /// The Linux ABI of [`LinuxSyscalls`](crate::linux::LinuxSyscalls), without access to
+    /// host files
+    Linux,
+}
+
+/// What the program does after an emulated call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    /// Carries on after the `ecall`
+    Return,
+    /// Ends the run with this exit code
+    Exit(u32),
+}
+
+/// Carries out environment calls, see [`Machine::set_syscall_handler`]
+pub trait SyscallHandler: Send {
+    /// Executes the call numbered by a7 with its arguments in a0-a5, leaving results in
+    /// the registers. `None` if the number is unknown, the `ecall` then ends the run as
+    /// it would without a handler.
+    fn call(&mut self, machine: &mut Machine) -> Option<SyscallOutcome>;
+
+    /// Forgets what earlier calls set up, for [`Machine::reset`]
+    fn reset(&mut self) {}
+}
+
+/// Prints a0 as a signed integer. Like the other call numbers of [`BareSyscalls`], it's the
+/// one RARS uses.
+pub const PRINT_INT: u32 = 1;
+/// Prints the NUL-terminated string at a0
+pub const PRINT_STRING: u32 = 4;
+/// Reads a line of input and returns the decimal integer on it, 0 if there is none
+pub const READ_INT: u32 = 5;
+/// Moves the program break up by a0 bytes and returns the old break, or -1 if memory is
+/// exhausted
+pub const SBRK: u32 = 9;
+/// Exits with code 0
+pub const EXIT: u32 = 10;
+pub const PRINT_CHAR: u32 = 11;
+/// Returns the next input byte, or -1 at the end of input
+pub const READ_CHAR: u32 = 12;
+pub const PRINT_HEX: u32 = 34;
+pub const PRINT_UNSIGNED: u32 = 36;
+/// Exits with the code in a0
+pub const EXIT2: u32 = 93;
+
+/// The RARS-style calls for console I/O, memory and exiting that student programs use
+/// instead of device drivers. Integers are XLEN bits wide, the heap grows from the end of
+/// the loaded images and stops at the stack pointer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BareSyscalls {
+    /// The program break, set on the first `sbrk`
+    program_break: Option<u32>,
+}
+
+impl BareSyscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sbrk(&mut self, machine: &Machine, increment: i64) -> Option<u32> {
+        let start = u32::try_from(machine.layout().ram_end().next_multiple_of(16)).ok()?;
+        let current = *self.program_break.get_or_insert(start);
+        let new = u32::try_from(current as i64 + increment).ok()?;
+        let stack_pointer = machine.cpu.regs[2] as u32;
+        let limit = match stack_pointer {
+            0 => machine.cpu.bus.dram.len() as u64,
+            _ => stack_pointer as u64,
+        };
+        if new < start || new as u64 > limit {
+            return None;
+        }
+        self.program_break = Some(new);
+        Some(current)
+    }
+}
+
+impl SyscallHandler for BareSyscalls {
+    fn call(&mut self, machine: &mut Machine) -> Option<SyscallOutcome> {
+        let xlen = machine.cpu.base_isa.xlen();
+        let a0 = machine.cpu.regs[10];
+        let signed = match xlen {
+            32 => a0 as u32 as i32 as i64,
+            _ => a0 as i64,
+        };
+        let result = match machine.cpu.regs[17] as u32 {
+            PRINT_INT => {
+                machine.console.write(signed.to_string().as_bytes());
+                None
+            }
+            PRINT_UNSIGNED => {
+                machine.console.write(a0.to_string().as_bytes());
+                None
+            }
+            PRINT_HEX => {
+                let digits = xlen as usize / 4;
+                machine
+                    .console
+                    .write(format!("0x{:0digits$x}", a0).as_bytes());
+                None
+            }
+            PRINT_CHAR => {
+                machine.console.write(&[a0 as u8]);
+                None
+            }
+            PRINT_STRING => {
+                let mut text = Vec::new();
+                let mut address = a0 as u32;
+                while let Ok(byte) = machine.read_memory(address, 1)
+                    && byte[0] != 0
+                {
+                    text.push(byte[0]);
+                    address = address.wrapping_add(1);
+                }
+                machine.console.write(&text);
+                None
+            }
+            READ_INT => {
+                let mut line = Vec::new();
+                while let Some(byte) = machine.console.wait_byte()
+                    && byte != b'\n'
+                {
+                    line.push(byte);
+                }
+                let value = String::from_utf8_lossy(&line).trim().parse::<i64>();
+                Some(value.unwrap_or(0))
+            }
+            READ_CHAR => Some(machine.console.wait_byte().map_or(-1, i64::from)),
+            SBRK => Some(self.sbrk(machine, signed).map_or(-1, i64::from)),
+            EXIT => return Some(SyscallOutcome::Exit(0)),
+            EXIT2 => return Some(SyscallOutcome::Exit(a0 as u32)),
+            _ => return None,
+        };
+        if let Some(value) = result {
+            machine.cpu.regs[10] = match xlen {
+                32 => value as u32 as u64,
+                _ => value as u64,
+            };
+        }
+        Some(SyscallOutcome::Return)
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+/// Name of a Linux system call number on RISC-V, for the calls teaching programs and
+/// [`LinuxSyscalls`](crate::linux::LinuxSyscalls) commonly see
+pub fn name(number: u32) -> Option<&'static str> {
+    Some(match number {
+        29 => "ioctl",
+        56 => "openat",
+        57 => "close",
+        62 => "lseek",
+        63 => "read",
+        64 => "write",
+        66 => "writev",
+        80 => "fstat",
+        93 => "exit",
+        94 => "exit_group",
+        96 => "set_tid_address",
+        99 => "set_robust_list",
+        113 => "clock_gettime",
+        134 => "rt_sigaction",
+        135 => "rt_sigprocmask",
+        160 => "uname",
+        169 => "gettimeofday",
+        172 => "getpid",
+        174 => "getuid",
+        175 => "geteuid",
+        176 => "getgid",
+        177 => "getegid",
+        178 => "gettid",
+        214 => "brk",
+        215 => "munmap",
+        222 => "mmap",
+        226 => "mprotect",
+        278 => "getrandom",
+        403 => "clock_gettime64",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::{ExitReason, RunLimits, RunOptions};
+
+    use super::*;
+
+    fn regs(number: u32, a0: u64) -> [u64; 32] {
+        let mut regs = [0; 32];
+        regs[17] = number.into();
+        regs[10] = a0;
+        regs[15] = 0xff;
+        regs
+    }
+
+    #[test]
+    fn test_calls_are_summarized_per_number() {
+        let mut log = SyscallLog::new();
+        log.record(0x10, &regs(64, 1), None);
+        log.record(0x20, &regs(93, 0), None);
+        log.record(0x30, &regs(64, 2), None);
+        assert_eq!(log.total(), 3);
+
+        let stats: Vec<_> = log.iter().collect();
+        assert_eq!(
+            stats
+                .iter()
+                .map(|stats| (stats.number, stats.name(), stats.count))
+                .collect::<Vec<_>>(),
+            [(64, Some("write"), 2), (93, Some("exit"), 1)]
+        );
+        assert_eq!(
+            stats[0].last,
+            SyscallCall {
+                pc: 0x30,
+                arguments: [2, 0, 0, 0, 0, 0xff],
+                result: None,
+            }
+        );
+        assert_eq!(name(1000), None);
+    }
+
+    #[test]
+    fn test_bare_syscalls() {
+        let words = [
+            0x00500893, // addi a7, zero, 5, read int
+            0x00000073, // ecall
+            0x00150513, // addi a0, a0, 1
+            0x00100893, // addi a7, zero, 1, print int
+            0x00000073, // ecall
+            0x20000513, // addi a0, zero, 0x200
+            0x00400893, // addi a7, zero, 4, print string
+            0x00000073, // ecall
+            0x01000513, // addi a0, zero, 16
+            0x00900893, // addi a7, zero, 9, sbrk
+            0x00000073, // ecall
+            0x00050413, // addi s0, a0, 0
+            0x00700513, // addi a0, zero, 7
+            0x05d00893, // addi a7, zero, 93, exit
+            0x00000073, // ecall
+        ];
+        let program: Vec<u8> = words
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect();
+        let options = RunOptions {
+            input: Some(b" -42\n".to_vec()),
+            echo_output: false,
+            capture_output: true,
+            syscalls: SyscallMode::Bare,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(program, 0x1000, &options);
+        machine.write_memory(0x200, b" ok\0").unwrap();
+        let outcome = machine.run(&RunLimits::default());
+        assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall);
+        assert_eq!(outcome.exit_code, Some(7));
+        assert_eq!(machine.console.output(), b"-41 ok");
+        // The heap starts right after the program, 16 byte aligned
+        assert_eq!(machine.cpu.regs[8], 64);
+        assert_eq!(outcome.instructions, 14);
+
+        let calls: Vec<_> = machine
+            .syscalls()
+            .iter()
+            .map(|stats| (stats.number, stats.last.result))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                (PRINT_INT, Some(0xffff_ffd7)),
+                (PRINT_STRING, Some(0x200)),
+                (READ_INT, Some(0xffff_ffd6)),
+                (SBRK, Some(64)),
+                (EXIT2, None),
+            ]
+        );
+
+        // The break starts over with the program
+        machine.reset();
+        machine.run(&RunLimits::default());
+        assert_eq!(machine.cpu.regs[8], 64);
+    }
+}
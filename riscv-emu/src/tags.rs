@@ -0,0 +1,168 @@
+//! Tagged memory: a tag bit on every 32 bit word, checked on loads and stores, for
+//! security architecture experiments like pointer integrity.
+//!
+//! A program tags the words it wants to protect, saved return addresses say, through a pair
+//! of custom CSRs: `mtagaddr` selects a word and `mtag` reads or writes its tag. `mtagctl`
+//! turns the checks on, an access touching a tagged word then faults with an access fault
+//! like one outside of memory would. Tags stay when other stores overwrite a word, they
+//! belong to the address and not to the value.
+
+use std::collections::BTreeSet;
+
+/// Which accesses tags are checked on, [`TAGCTL_LOAD`] and [`TAGCTL_STORE`]
+pub const MTAGCTL: u16 = 0x7c8;
+/// Address of the word `mtag` reads and writes the tag of
+pub const MTAGADDR: u16 = 0x7c9;
+/// Tag of the word at `mtagaddr` in bit 0
+pub const MTAG: u16 = 0x7ca;
+
+/// Loads of tagged words fault
+pub const TAGCTL_LOAD: u64 = 1 << 0;
+/// Stores to tagged words fault
+pub const TAGCTL_STORE: u64 = 1 << 1;
+
+const WORD_SIZE: u32 = 4;
+
+/// Tag bits of memory and the CSRs programs manage them with
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagMemory {
+    /// Indices (addresses divided by 4) of the tagged words
+    tagged: BTreeSet<u32>,
+    pub control: u64,
+    pub address: u32,
+}
+
+impl TagMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the word containing `address` is tagged
+    pub fn is_tagged(&self, address: u32) -> bool {
+        self.tagged.contains(&(address / WORD_SIZE))
+    }
+
+    pub fn set_tag(&mut self, address: u32, tag: bool) {
+        let word = address / WORD_SIZE;
+        if tag {
+            self.tagged.insert(word);
+        } else {
+            self.tagged.remove(&word);
+        }
+    }
+
+    /// Addresses of the tagged words, in ascending order
+    pub fn tagged_words(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tagged.iter().map(|word| word * WORD_SIZE)
+    }
+
+    /// Whether the checks [`MTAGCTL`] enables let an access of `len` bytes at `address`
+    /// through
+    pub fn allows(&self, address: u32, len: usize, store: bool) -> bool {
+        let check = if store { TAGCTL_STORE } else { TAGCTL_LOAD };
+        if self.control & check == 0 || len == 0 {
+            return true;
+        }
+        let last = address.wrapping_add(len as u32 - 1);
+        !self.is_tagged(address) && !self.is_tagged(last)
+    }
+
+    /// Value of one of the tag CSRs, `None` for any other address
+    pub fn read_csr(&self, address: u16) -> Option<u64> {
+        Some(match address {
+            MTAGCTL => self.control,
+            MTAGADDR => self.address.into(),
+            MTAG => self.is_tagged(self.address).into(),
+            _ => return None,
+        })
+    }
+
+    /// Writes one of the tag CSRs, `None` for any other address
+    pub fn write_csr(&mut self, address: u16, value: u64) -> Option<()> {
+        match address {
+            MTAGCTL => self.control = value & (TAGCTL_LOAD | TAGCTL_STORE),
+            MTAGADDR => self.address = value as u32,
+            MTAG => self.set_tag(self.address, value & 1 != 0),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Turns the checks off, the tags themselves stay like memory does
+    pub fn reset(&mut self) {
+        self.control = 0;
+        self.address = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::Exception,
+        machine::{Machine, RunOptions},
+    };
+
+    fn program(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_tag_checks() {
+        let words: [u32; 10] = [
+            0x10000293, // addi t0, zero, 0x100
+            0x7c929073, // csrrw zero, mtagaddr, t0
+            0x7ca0d073, // csrrwi zero, mtag, 1
+            0x0052a023, // sw t0, 0(t0), tags aren't checked yet
+            0x7c815073, // csrrwi zero, mtagctl, 2 (stores)
+            0x0002a303, // lw t1, 0(t0)
+            0x0062a223, // sw t1, 4(t0)
+            0x0052a123, // sw t0, 2(t0), misaligned onto the tagged word
+            0x7ca023f3, // csrrs t2, mtag, zero
+            0x0062a023, // sw t1, 0(t0)
+        ];
+        let options = RunOptions {
+            tagged_memory: true,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::with_options(program(&words), 0x1000, &options);
+        for _ in 0..7 {
+            machine.step().unwrap();
+        }
+        assert!(matches!(
+            machine.step(),
+            Err(Exception::StoreAccessFault {
+                pc: 28,
+                address: 0x102
+            })
+        ));
+        machine.cpu.pc += 4;
+        machine.step().unwrap();
+        assert_eq!(machine.cpu.regs[7], 1);
+        assert!(matches!(
+            machine.step(),
+            Err(Exception::StoreAccessFault {
+                pc: 36,
+                address: 0x100
+            })
+        ));
+        let tags = machine.cpu.tags.as_ref().unwrap();
+        assert_eq!(tags.tagged_words().collect::<Vec<_>>(), [0x100]);
+
+        // Without tagged memory the CSRs don't exist
+        let mut machine = Machine::new(program(&words[..2]), 0);
+        machine.step().unwrap();
+        assert!(matches!(
+            machine.step(),
+            Err(Exception::IllegalInstruction { pc: 4, .. })
+        ));
+
+        let mut tags = TagMemory::new();
+        tags.set_tag(0x200, true);
+        tags.write_csr(MTAGCTL, u64::MAX).unwrap();
+        assert_eq!(tags.read_csr(MTAGCTL), Some(TAGCTL_LOAD | TAGCTL_STORE));
+        assert!(!tags.allows(0x1ff, 2, false));
+        assert!(tags.allows(0x1fc, 4, false));
+        assert_eq!(tags.read_csr(0x7c0), None);
+    }
+}
@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests
+
+/// xorshift32 from `seed`, a fixed seed keeps failures of randomized tests reproducible
+pub(crate) fn xorshift32(seed: u32) -> impl FnMut() -> u32 {
+    let mut state = seed;
+    move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    }
+}
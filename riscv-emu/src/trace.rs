@@ -0,0 +1,260 @@
+use std::ops::Range;
+
+use crate::{
+    compressed,
+    machine::{Machine, RunLimits, RunOptions, RunOutcome},
+    symbols::Symbols,
+};
+
+/// One executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u32,
+    pub instruction: u32,
+    /// Register written by the instruction and the low 32 bits of its new value
+    pub write: Option<(u8, u32)>,
+}
+
+/// Instructions executed by a machine, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Index of the first entry that differs between the two traces, including one trace
+    /// simply being longer
+    pub fn first_divergence(&self, other: &Trace) -> Option<usize> {
+        self.entries
+            .iter()
+            .zip(&other.entries)
+            .position(|(a, b)| a != b)
+            .or_else(|| (self.len() != other.len()).then(|| self.len().min(other.len())))
+    }
+}
+
+/// Kinds of instructions a trace can be limited to, by major opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstructionClass {
+    Load,
+    Store,
+    /// Conditional branches
+    Branch,
+    /// `jal` and `jalr`
+    Jump,
+    /// Register and immediate arithmetic, `lui` and `auipc`
+    Alu,
+    /// `ecall`, `ebreak`, CSR accesses and fences
+    System,
+}
+
+impl InstructionClass {
+    /// Class of an encoded instruction, `None` for opcodes outside RV32I. Compressed
+    /// instructions are classified by the base instruction they expand to.
+    pub fn of(instruction: u32) -> Option<Self> {
+        let instruction = match compressed::instruction_length(instruction) {
+            2 => compressed::expand(instruction as u16)?,
+            _ => instruction,
+        };
+        match instruction & 0x7f {
+            0b0000011 => Some(Self::Load),
+            0b0100011 => Some(Self::Store),
+            0b1100011 => Some(Self::Branch),
+            0b1101111 | 0b1100111 => Some(Self::Jump),
+            0b0010011 | 0b0110011 | 0b0110111 | 0b0010111 | 0b0011011 | 0b0111011 => {
+                Some(Self::Alu)
+            }
+            0b1110011 | 0b0001111 => Some(Self::System),
+            _ => None,
+        }
+    }
+}
+
+/// Which executed instructions get recorded, so traces of long runs only keep what matters.
+///
+/// Every condition that is set must hold, the default filter records everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only instructions whose pc lies in one of these ranges
+    pub ranges: Vec<Range<u32>>,
+    /// Only instructions of one of these classes
+    pub classes: Vec<InstructionClass>,
+    /// Only while the register holds the value, checked before the instruction executes
+    pub register: Option<(u8, u32)>,
+}
+
+impl TraceFilter {
+    /// Limits the trace to the code of the function called `name`, reaching up to the next
+    /// symbol (or the end of the address space for the last one). `None` if there is no such
+    /// symbol.
+    pub fn within_function(mut self, symbols: &Symbols, name: &str) -> Option<Self> {
+        let symbol = symbols.info(name)?;
+        let end = symbol.size.map_or(u32::MAX, |size| symbol.address + size);
+        self.ranges.push(symbol.address..end);
+        Some(self)
+    }
+
+    /// Whether the instruction at `pc`, about to run with registers `regs`, is recorded
+    pub fn matches(&self, pc: u32, instruction: u32, regs: &[u64; 32]) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+            && (self.classes.is_empty()
+                || InstructionClass::of(instruction)
+                    .is_some_and(|class| self.classes.contains(&class)))
+            && self
+                .register
+                .is_none_or(|(register, value)| regs[register as usize] == value as u64)
+    }
+}
+
+/// Where two runs of the same program stopped agreeing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index into the traces
+    pub step: usize,
+    pub first: Option<TraceEntry>,
+    pub second: Option<TraceEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeterminismReport {
+    pub outcomes: [RunOutcome; 2],
+    pub divergence: Option<Divergence>,
+    /// Set when the runs differ in their console output
+    pub output_differs: bool,
+}
+
+impl DeterminismReport {
+    pub fn is_deterministic(&self) -> bool {
+        self.divergence.is_none() && !self.output_differs && self.outcomes[0] == self.outcomes[1]
+    }
+}
+
+/// Builds two machines with `make_machine` and identical options, runs both and compares the
+/// traces.
+///
+/// Anything that makes the runs disagree (host time, polling order, uninitialized state leaking
+/// in) shows up as a divergence. Host stdin can't be replayed, so when `options` doesn't supply
+/// input both runs get empty input instead.
+pub fn audit_determinism(
+    make_machine: impl Fn(&RunOptions) -> Machine,
+    options: &RunOptions,
+    limits: &RunLimits,
+) -> DeterminismReport {
+    let options = RunOptions {
+        input: Some(options.input.clone().unwrap_or_default()),
+        echo_output: false,
+        capture_output: true,
+        ..options.clone()
+    };
+
+    let run = || {
+        let mut machine = make_machine(&options);
+        machine.enable_tracing();
+        let outcome = machine.run(limits);
+        let output = machine.console.output().to_vec();
+        (outcome, machine.take_trace(), output)
+    };
+    let (first_outcome, first_trace, first_output) = run();
+    let (second_outcome, second_trace, second_output) = run();
+
+    let divergence = first_trace
+        .first_divergence(&second_trace)
+        .map(|step| Divergence {
+            step,
+            first: first_trace.entries.get(step).copied(),
+            second: second_trace.entries.get(step).copied(),
+        });
+
+    DeterminismReport {
+        outcomes: [first_outcome, second_outcome],
+        divergence,
+        output_differs: first_output != second_output,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pc: u32) -> TraceEntry {
+        TraceEntry {
+            pc,
+            instruction: 0x13,
+            write: None,
+        }
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let a = Trace {
+            entries: vec![entry(0), entry(4), entry(8)],
+        };
+        let b = Trace {
+            entries: vec![entry(0), entry(4), entry(12)],
+        };
+        let prefix = Trace {
+            entries: vec![entry(0), entry(4)],
+        };
+        assert_eq!(a.first_divergence(&a), None);
+        assert_eq!(a.first_divergence(&b), Some(2));
+        assert_eq!(a.first_divergence(&prefix), Some(2));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut symbols = Symbols::new();
+        symbols.insert("main", 0x00);
+        symbols.insert("helper", 0x10);
+        symbols.insert("end", 0x20);
+        let filter = TraceFilter {
+            classes: vec![InstructionClass::Load, InstructionClass::Store],
+            ..TraceFilter::default()
+        }
+        .within_function(&symbols, "helper")
+        .unwrap();
+        let regs = [0; 32];
+        // lw a0, 0(sp) and addi a0, a0, 1
+        assert!(filter.matches(0x14, 0x00012503, &regs));
+        assert!(!filter.matches(0x14, 0x00150513, &regs));
+        assert!(!filter.matches(0x20, 0x00012503, &regs));
+        assert!(
+            TraceFilter::default()
+                .within_function(&symbols, "missing")
+                .is_none()
+        );
+
+        let filter = TraceFilter {
+            register: Some((10, 3)),
+            ..TraceFilter::default()
+        };
+        let mut regs = [0; 32];
+        assert!(!filter.matches(0, 0x13, &regs));
+        regs[10] = 3;
+        assert!(filter.matches(0, 0x13, &regs));
+    }
+
+    #[test]
+    fn test_program_is_deterministic() {
+        // addi a0, zero, 3; addi a0, a0, -1; add a0, a0, a0
+        let program: Vec<u8> = [0x00300513u32, 0xfff50513, 0x00a50533]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let report = audit_determinism(
+            |options| Machine::with_options(program.clone(), 4096, options),
+            &RunOptions::default(),
+            &RunLimits::default(),
+        );
+        assert!(report.is_deterministic());
+        assert_eq!(report.outcomes[0].instructions, 3);
+        assert_eq!(report.outcomes[0].exit_code, Some(4));
+    }
+}
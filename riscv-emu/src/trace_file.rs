@@ -0,0 +1,357 @@
+//! Compact binary trace files, practical for runs of many millions of instructions.
+//!
+//! After a short header every entry is delta encoded against the previous one: sequential pcs
+//! take no space, an instruction is only stored again when the one at its pc changed, and a
+//! register write stores the difference to the register's previous value. The body can
+//! additionally be zstd compressed (with the `zstd` feature).
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use crate::{
+    compressed,
+    trace::{Trace, TraceEntry},
+};
+
+const MAGIC: [u8; 4] = *b"RVTR";
+const VERSION: u8 = 1;
+/// Header flag, the body is a zstd stream
+const FLAG_ZSTD: u8 = 1;
+
+/// The pc doesn't follow the previous instruction, a signed delta follows
+const TAG_JUMP: u8 = 1;
+/// The instruction differs from the one last seen at this pc, its 4 bytes follow
+const TAG_INSTRUCTION: u8 = 2;
+/// A register write follows: register number and signed delta to its old value
+const TAG_WRITE: u8 = 4;
+
+/// What writer and reader both remember about the entries seen so far
+#[derive(Default)]
+struct DeltaState {
+    next_pc: u32,
+    instructions: HashMap<u32, u32>,
+    regs: [u32; 32],
+}
+
+enum Output<W: Write> {
+    Plain(BufWriter<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+/// Encodes trace entries into a trace file as they come in
+pub struct TraceWriter<W: Write> {
+    output: Output<W>,
+    state: DeltaState,
+    entries: u64,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Uncompressed trace, only delta encoded
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        write_header(&mut writer, 0)?;
+        Ok(Self::with_output(Output::Plain(BufWriter::new(writer))))
+    }
+
+    /// Trace with a zstd compressed body, `level` as accepted by zstd (0 picks its default)
+    #[cfg(feature = "zstd")]
+    pub fn compressed(mut writer: W, level: i32) -> io::Result<Self> {
+        write_header(&mut writer, FLAG_ZSTD)?;
+        Ok(Self::with_output(Output::Zstd(zstd::Encoder::new(
+            writer, level,
+        )?)))
+    }
+
+    fn with_output(output: Output<W>) -> Self {
+        Self {
+            output,
+            state: DeltaState::default(),
+            entries: 0,
+        }
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        let mut record = Vec::with_capacity(16);
+        record.push(0);
+        let state = &mut self.state;
+        if entry.pc != state.next_pc {
+            record[0] |= TAG_JUMP;
+            write_varint(&mut record, entry.pc.wrapping_sub(state.next_pc) as i32);
+        }
+        if state.instructions.insert(entry.pc, entry.instruction) != Some(entry.instruction) {
+            record[0] |= TAG_INSTRUCTION;
+            record.extend_from_slice(&entry.instruction.to_le_bytes());
+        }
+        if let Some((register, value)) = entry.write {
+            record[0] |= TAG_WRITE;
+            let old = state.regs.get_mut(register as usize).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("register x{} out of range", register),
+                )
+            })?;
+            record.push(register);
+            write_varint(&mut record, value.wrapping_sub(*old) as i32);
+            *old = value;
+        }
+        state.next_pc = entry
+            .pc
+            .wrapping_add(compressed::instruction_length(entry.instruction));
+        self.entries += 1;
+        match &mut self.output {
+            Output::Plain(writer) => writer.write_all(&record),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(encoder) => encoder.write_all(&record),
+        }
+    }
+
+    /// Entries written so far
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Flushes everything (finishing the compressed stream) and returns the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self.output {
+            Output::Plain(writer) => writer.into_inner().map_err(io::IntoInnerError::into_error),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+fn write_header(writer: &mut impl Write, flags: u8) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION, flags])
+}
+
+/// Zigzag then LEB128, small deltas of either sign take a single byte
+fn write_varint(output: &mut Vec<u8>, value: i32) {
+    let mut value = ((value << 1) ^ (value >> 31)) as u32;
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+enum Input<R: Read> {
+    Plain(BufReader<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Plain(reader) => reader.read(buf),
+            #[cfg(feature = "zstd")]
+            Input::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Decodes a trace file entry by entry, without holding the whole trace in memory
+pub struct TraceReader<R: Read> {
+    input: Input<R>,
+    state: DeltaState,
+    done: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the header, compressed traces need the `zstd` feature
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 6];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid_data("not a trace file"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported trace version {}",
+                header[4]
+            )));
+        }
+        let input = if header[5] & FLAG_ZSTD == 0 {
+            Input::Plain(BufReader::new(reader))
+        } else {
+            #[cfg(feature = "zstd")]
+            {
+                Input::Zstd(zstd::Decoder::new(reader)?)
+            }
+            #[cfg(not(feature = "zstd"))]
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "compressed trace, rebuild with the zstd feature to read it",
+                ));
+            }
+        };
+        Ok(Self {
+            input,
+            state: DeltaState::default(),
+            done: false,
+        })
+    }
+
+    /// Reads the remaining entries into memory
+    pub fn read_all(self) -> io::Result<Trace> {
+        Ok(Trace {
+            entries: self.collect::<io::Result<_>>()?,
+        })
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<TraceEntry>> {
+        let mut tag = [0];
+        if self.input.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let [tag] = tag;
+        let state = &mut self.state;
+        let mut pc = state.next_pc;
+        if tag & TAG_JUMP != 0 {
+            pc = pc.wrapping_add(read_varint(&mut self.input)? as u32);
+        }
+        let instruction = if tag & TAG_INSTRUCTION != 0 {
+            let mut bytes = [0; 4];
+            self.input.read_exact(&mut bytes)?;
+            let instruction = u32::from_le_bytes(bytes);
+            state.instructions.insert(pc, instruction);
+            instruction
+        } else {
+            *state
+                .instructions
+                .get(&pc)
+                .ok_or_else(|| invalid_data(format!("no instruction recorded at {:#x}", pc)))?
+        };
+        let write = if tag & TAG_WRITE != 0 {
+            let mut register = [0];
+            self.input.read_exact(&mut register)?;
+            let [register] = register;
+            let delta = read_varint(&mut self.input)?;
+            let value = state
+                .regs
+                .get_mut(register as usize)
+                .ok_or_else(|| invalid_data(format!("register x{} out of range", register)))?;
+            *value = value.wrapping_add(delta as u32);
+            Some((register, *value))
+        } else {
+            None
+        };
+        state.next_pc = pc.wrapping_add(compressed::instruction_length(instruction));
+        Ok(Some(TraceEntry {
+            pc,
+            instruction,
+            write,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        // Stop after the end of the file or the first error, the state can't resync
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+fn read_varint(input: &mut impl Read) -> io::Result<i32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        value |= u32::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok((value >> 1) as i32 ^ -((value & 1) as i32));
+        }
+    }
+    Err(invalid_data("varint longer than 5 bytes"))
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<TraceEntry> {
+        // A loop counting a0 down from 3 that then jumps far away
+        let mut entries = Vec::new();
+        for a0 in (0..3).rev() {
+            entries.push(TraceEntry {
+                pc: 0x100,
+                instruction: 0xfff50513,
+                write: Some((10, a0)),
+            });
+            entries.push(TraceEntry {
+                pc: 0x104,
+                instruction: 0xfe051ee3,
+                write: None,
+            });
+        }
+        entries.push(TraceEntry {
+            pc: 0x8000_0000,
+            instruction: 0x00000073,
+            write: Some((31, u32::MAX)),
+        });
+        entries
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for entry in entries() {
+            writer.write(&entry).unwrap();
+        }
+        assert_eq!(writer.len(), 7);
+        let bytes = writer.finish().unwrap();
+        // Header, then the later loop iterations cost 4 and 1 bytes per entry
+        assert_eq!(bytes.len(), 42);
+
+        let trace = TraceReader::new(bytes.as_slice())
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(trace.entries, entries());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressed_round_trip() {
+        let mut writer = TraceWriter::compressed(Vec::new(), 0).unwrap();
+        for entry in entries() {
+            writer.write(&entry).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let trace = TraceReader::new(bytes.as_slice())
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(trace.entries, entries());
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        assert!(TraceReader::new(&b"ELF\x7f\x01\x00"[..]).is_err());
+        let mut bytes = TraceWriter::new(Vec::new()).unwrap().finish().unwrap();
+        // A sequential entry at a pc that never had an instruction
+        bytes.push(0);
+        let mut reader = TraceReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
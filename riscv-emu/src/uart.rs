@@ -0,0 +1,239 @@
+//! A 16550-compatible UART for console I/O through memory-mapped registers, at the address
+//! and on the PLIC source QEMU's virt machine gives its first UART.
+//!
+//! Only what a program talking to a terminal notices is modelled: transmitted bytes go out
+//! at once so the transmitter is always empty, received bytes wait in a 16 byte FIFO, and
+//! the divisor latch and the modem registers just hold what is written to them. The
+//! machine moves bytes between the UART and its [`Console`](crate::console::Console).
+//!
+//! Registers are a byte each, wider accesses are bus errors.
+
+use std::collections::VecDeque;
+
+use crate::bus::Device;
+
+/// Address the UART's registers are mapped at
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+/// PLIC source the UART's interrupt line is wired to
+pub const UART_IRQ: usize = 10;
+
+/// Offsets of the registers from [`UART_BASE`]. The first two are the divisor latch
+/// instead while LCR.DLAB is set.
+pub const RBR_THR: u32 = 0;
+pub const IER: u32 = 1;
+/// IIR when read, FCR when written
+pub const IIR_FCR: u32 = 2;
+pub const LCR: u32 = 3;
+pub const MCR: u32 = 4;
+pub const LSR: u32 = 5;
+pub const MSR: u32 = 6;
+pub const SCR: u32 = 7;
+
+/// Interrupt when received data is available
+pub const IER_RX_AVAILABLE: u8 = 1 << 0;
+/// Interrupt when the transmitter holding register is empty
+pub const IER_TX_EMPTY: u8 = 1 << 1;
+
+/// Line status: received data ready, transmitter holding register and transmitter empty
+pub const LSR_DATA_READY: u8 = 1 << 0;
+pub const LSR_TX_EMPTY: u8 = 1 << 5 | 1 << 6;
+
+/// Interrupt identification: nothing pending, or the pending interrupt
+const IIR_NONE: u8 = 0x01;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+/// Set in IIR while the FIFOs are enabled
+const IIR_FIFOS: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 1 << 7;
+const RX_FIFO_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Uart {
+    /// Received bytes the program hasn't read yet
+    rx: VecDeque<u8>,
+    /// Transmitted bytes the machine hasn't passed on yet
+    tx: Vec<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    /// The transmitter empty interrupt, cleared by reading it from IIR or writing THR
+    tx_empty_pending: bool,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the receive FIFO has room for another byte
+    pub fn can_receive(&self) -> bool {
+        self.rx.len() < RX_FIFO_SIZE
+    }
+
+    /// Puts a byte from the terminal into the receive FIFO, dropping it if the FIFO is full
+    /// as an overrun would
+    pub fn receive(&mut self, byte: u8) {
+        if self.can_receive() {
+            self.rx.push_back(byte);
+        }
+    }
+
+    /// Takes the bytes the program transmitted since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.tx)
+    }
+
+    /// The highest priority pending interrupt as IIR reports it
+    fn pending(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_TX_EMPTY != 0 && self.tx_empty_pending {
+            IIR_TX_EMPTY
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn base(&self) -> u32 {
+        UART_BASE
+    }
+
+    fn size(&self) -> u32 {
+        UART_SIZE
+    }
+
+    fn load(&mut self, offset: u32, len: usize) -> Option<u32> {
+        if len != 1 {
+            return None;
+        }
+        let value = match offset {
+            RBR_THR if self.dlab() => self.divisor as u8,
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let pending = self.pending();
+                if pending == IIR_TX_EMPTY {
+                    self.tx_empty_pending = false;
+                }
+                let fifos = if self.fcr & 1 != 0 { IIR_FIFOS } else { 0 };
+                pending | fifos
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                LSR_TX_EMPTY | ready
+            }
+            // Carrier detect, data set ready and clear to send: a terminal is always there
+            MSR => 0xb0,
+            SCR => self.scr,
+            _ => return None,
+        };
+        Some(value as u32)
+    }
+
+    fn store(&mut self, offset: u32, len: usize, value: u32) -> Option<()> {
+        if len != 1 {
+            return None;
+        }
+        let value = value as u8;
+        match offset {
+            RBR_THR if self.dlab() => self.divisor = self.divisor & 0xff00 | value as u16,
+            RBR_THR => {
+                self.tx.push(value);
+                // Sent at once, so the holding register is empty again right away
+                self.tx_empty_pending = true;
+            }
+            IER if self.dlab() => self.divisor = self.divisor & 0x00ff | (value as u16) << 8,
+            IER => {
+                // Enabling the interrupt raises it for the empty transmitter
+                if value & IER_TX_EMPTY != 0 && self.ier & IER_TX_EMPTY == 0 {
+                    self.tx_empty_pending = true;
+                }
+                self.ier = value & (IER_RX_AVAILABLE | IER_TX_EMPTY);
+            }
+            IIR_FCR => {
+                // Bit 1 clears the receive FIFO, the other reset bits have nothing to clear
+                if value & 0b10 != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & 1;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn external_interrupt(&self) -> Option<(usize, bool)> {
+        Some((UART_IRQ, self.pending() != IIR_NONE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut uart = Uart::new();
+        assert_eq!(uart.load(LSR, 1), Some(LSR_TX_EMPTY as u32));
+        uart.store(RBR_THR, 1, b'h' as u32).unwrap();
+        uart.store(RBR_THR, 1, b'i' as u32).unwrap();
+        assert_eq!(uart.take_output(), b"hi");
+
+        uart.receive(b'x');
+        assert_eq!(
+            uart.load(LSR, 1),
+            Some((LSR_TX_EMPTY | LSR_DATA_READY) as u32)
+        );
+        assert_eq!(uart.external_interrupt(), Some((UART_IRQ, false)));
+        uart.store(IER, 1, IER_RX_AVAILABLE as u32).unwrap();
+        assert_eq!(uart.external_interrupt(), Some((UART_IRQ, true)));
+        assert_eq!(uart.load(IIR_FCR, 1), Some(IIR_RX_AVAILABLE as u32));
+        assert_eq!(uart.load(RBR_THR, 1), Some(b'x' as u32));
+        assert_eq!(uart.external_interrupt(), Some((UART_IRQ, false)));
+
+        // The transmitter empty interrupt goes away once IIR reported it
+        uart.store(IER, 1, IER_TX_EMPTY as u32).unwrap();
+        assert_eq!(uart.load(IIR_FCR, 1), Some(IIR_TX_EMPTY as u32));
+        assert_eq!(uart.load(IIR_FCR, 1), Some(IIR_NONE as u32));
+
+        // The divisor latch hides the data and interrupt enable registers
+        uart.store(LCR, 1, LCR_DLAB as u32).unwrap();
+        uart.store(RBR_THR, 1, 0x03).unwrap();
+        uart.store(IER, 1, 0x00).unwrap();
+        assert_eq!(uart.take_output(), b"");
+        assert_eq!(uart.divisor, 3);
+        uart.store(LCR, 1, 0x03).unwrap();
+        assert_eq!(uart.load(IER, 1), Some(IER_TX_EMPTY as u32));
+        assert_eq!(uart.load(RBR_THR, 4), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! Uninitialized-read checking, in the spirit of MemorySanitizer.
+//!
+//! Every byte of writable memory has a bit telling whether anything was ever written to it:
+//! a loaded image, a store, or a system call filling a buffer. A load touching a byte
+//! nothing was written to is reported, it reads the zero a fresh machine happens to hold
+//! rather than a value the program computed. The program image is always initialized.
+
+use std::fmt;
+
+use crate::shadow::MemoryAccess;
+
+/// A load of memory nothing was written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UninitializedRead {
+    /// Address of the loading instruction
+    pub pc: u32,
+    pub access: MemoryAccess,
+    /// The first byte of the access that wasn't initialized
+    pub address: u32,
+}
+
+impl fmt::Display for UninitializedRead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} byte read at {:#010x} (pc {:#010x}) of uninitialized memory at {:#010x}",
+            self.access.len, self.access.address, self.pc, self.address
+        )
+    }
+}
+
+/// Which bytes of writable memory were initialized
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitializedMemory {
+    /// One bit per byte of memory
+    bits: Vec<u64>,
+    /// Bytes below this, the program image, count as initialized
+    program_len: u32,
+}
+
+impl InitializedMemory {
+    pub fn new(memory_size: usize, program_len: usize) -> Self {
+        Self {
+            bits: vec![0; memory_size.div_ceil(64)],
+            program_len: program_len as u32,
+        }
+    }
+
+    /// Marks the `len` bytes at `address` as initialized, bytes outside of memory are ignored
+    pub fn mark(&mut self, address: u32, len: usize) {
+        for byte in address as usize..(address as usize).saturating_add(len) {
+            if let Some(word) = self.bits.get_mut(byte / 64) {
+                *word |= 1 << (byte % 64);
+            }
+        }
+    }
+
+    pub fn is_initialized(&self, address: u32) -> bool {
+        let byte = address as usize;
+        address < self.program_len
+            || self
+                .bits
+                .get(byte / 64)
+                .is_none_or(|word| word & 1 << (byte % 64) != 0)
+    }
+
+    /// The report if the load `access`, made by the instruction at `pc`, reads a byte that
+    /// wasn't initialized. Addresses outside of memory, e.g. devices, are never reported.
+    pub fn check(&self, pc: u32, access: MemoryAccess) -> Option<UninitializedRead> {
+        if access.write {
+            return None;
+        }
+        let address = (0..access.len)
+            .map(|offset| access.address.wrapping_add(offset))
+            .find(|&address| !self.is_initialized(address))?;
+        Some(UninitializedRead {
+            pc,
+            access,
+            address,
+        })
+    }
+
+    /// Forgets every write, for a reset
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let mut memory = InitializedMemory::new(0x1000, 0x100);
+        memory.mark(0x200, 4);
+        let load = |address, len| MemoryAccess {
+            address,
+            len,
+            write: false,
+        };
+        assert_eq!(memory.check(0, load(0x200, 4)), None);
+        assert_eq!(memory.check(0, load(0x10, 4)), None);
+        assert_eq!(
+            memory.check(8, load(0x202, 4)),
+            Some(UninitializedRead {
+                pc: 8,
+                access: load(0x202, 4),
+                address: 0x204,
+            })
+        );
+        // Stores and addresses past the end of memory aren't checked
+        let store = MemoryAccess {
+            write: true,
+            ..load(0x300, 4)
+        };
+        assert_eq!(memory.check(0, store), None);
+        assert_eq!(memory.check(0, load(0x2000_0000, 4)), None);
+        memory.clear();
+        assert!(!memory.is_initialized(0x200));
+    }
+}
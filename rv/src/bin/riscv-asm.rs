@@ -0,0 +1,269 @@
+//! `riscv-asm`, the assembler on its own for Makefiles and scripts: sources in, a flat
+//! binary, Intel HEX or relocatable ELF object out.
+//!
+//! ```text
+//! riscv-asm -o boot.bin boot.s
+//! riscv-asm -march=rv64i -o main.o main.s util.s
+//! cat main.s | riscv-asm --format hex > main.hex
+//! ```
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::Context;
+use clap::Parser;
+use riscv_asm::{
+    AssemblerOptions, SourceInput,
+    cache::{BuildCache, DEFAULT_CACHE_DIR},
+    diagnostic::{Diagnostic, SourceFiles},
+    error::AssemblerError,
+};
+use riscv_emu::image::{Image, ImageFormat};
+
+/// Name diagnostics give the standard input
+const STDIN_NAME: &str = "<stdin>";
+
+#[derive(Parser)]
+#[command(
+    name = "riscv-asm",
+    version,
+    about = "Assemble RISC-V sources into a flat binary, Intel HEX or an ELF object"
+)]
+struct Cli {
+    /// Sources to assemble, as one program in the order given like `as` does. Standard
+    /// input without any, or for `-`.
+    files: Vec<PathBuf>,
+    /// Where to write the output, standard output without it or for `-`
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Output format, by default from the extension of --output: `.o` and `.elf` are ELF
+    /// objects, `.hex` Intel HEX, anything else a flat binary
+    #[arg(long)]
+    format: Option<OutputFormat>,
+    /// Also write a listing of the source to FILE, every line with its address, machine
+    /// code and the values of the labels it defines
+    #[arg(long, value_name = "FILE")]
+    listing: Option<PathBuf>,
+    /// Target ISA, GCC's `-march=rv32i` spelling works too
+    #[arg(long, default_value = "rv32i")]
+    march: March,
+    /// Directory to look for `.include`d files in, after the including file's own
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+    /// Reuse flat binaries from earlier runs whose sources, included files and options
+    /// haven't changed, kept in DIR
+    #[arg(
+        long,
+        value_name = "DIR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = DEFAULT_CACHE_DIR
+    )]
+    cache: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// The program image from its lowest address
+    Bin,
+    /// Relocatable ELF object for `ld` or `lld` to link
+    Elf,
+    /// Intel HEX, placed at the program's addresses
+    Hex,
+}
+
+impl OutputFormat {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("o" | "elf") => OutputFormat::Elf,
+            _ => match ImageFormat::from_path(path) {
+                ImageFormat::IntelHex => OutputFormat::Hex,
+                _ => OutputFormat::Bin,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum March {
+    Rv32i,
+    /// RV32I with registers x0-x15 only
+    Rv32e,
+    Rv64i,
+}
+
+impl March {
+    fn assembler_options(self) -> AssemblerOptions {
+        match self {
+            March::Rv32i => AssemblerOptions::default(),
+            March::Rv32e => AssemblerOptions::rv32e(),
+            March::Rv64i => AssemblerOptions::rv64i(),
+        }
+    }
+}
+
+/// The program's sources as one: a file's own text, or `.include`s of every file
+struct Source {
+    name: String,
+    text: String,
+    path: Option<PathBuf>,
+}
+
+impl Source {
+    fn read(files: &[PathBuf]) -> anyhow::Result<Self> {
+        let is_stdin = |file: &PathBuf| file.as_os_str() == "-";
+        match files {
+            [] => Self::stdin(),
+            [file] if is_stdin(file) => Self::stdin(),
+            [file] => Ok(Self {
+                name: file.display().to_string(),
+                text: fs::read_to_string(file)
+                    .with_context(|| format!("reading {}", file.display()))?,
+                path: Some(file.clone()),
+            }),
+            _ if files.iter().any(is_stdin) => {
+                anyhow::bail!("the standard input can only be assembled on its own")
+            }
+            _ => {
+                let mut text = String::new();
+                for file in files {
+                    let name = file.to_str().filter(|name| !name.contains('"'));
+                    let Some(name) = name else {
+                        anyhow::bail!("cannot include {}", file.display());
+                    };
+                    text.push_str(&format!(".include \"{}\"\n", name));
+                }
+                Ok(Self {
+                    name: "<command line>".to_string(),
+                    text,
+                    path: None,
+                })
+            }
+        }
+    }
+
+    fn stdin() -> anyhow::Result<Self> {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("reading the standard input")?;
+        Ok(Self {
+            name: STDIN_NAME.to_string(),
+            text,
+            path: None,
+        })
+    }
+
+    /// A file is assembled as one, so its includes are looked for next to it
+    fn input(&self) -> SourceInput<'_> {
+        match &self.path {
+            Some(path) => SourceInput::file(path),
+            None => SourceInput::text(self.text.as_str()).with_name(&self.name),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("riscv-asm: error: {:#}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    // GCC's `-march=rv32i` spelling, clap only knows `--march`
+    let args = std::env::args_os().map(|arg| match arg.to_str() {
+        Some(text) if text == "-march" || text.starts_with("-march=") => {
+            OsString::from(format!("-{}", text))
+        }
+        _ => arg,
+    });
+    let cli = Cli::parse_from(args);
+    let options = AssemblerOptions {
+        include_paths: cli.include_dirs.clone(),
+        ..cli.march.assembler_options()
+    };
+    let source = Source::read(&cli.files)?;
+    let output = cli.output.filter(|path| path.as_os_str() != "-");
+    let format = cli.format.unwrap_or_else(|| match &output {
+        Some(path) => OutputFormat::from_path(path),
+        None => OutputFormat::Bin,
+    });
+    if cli.listing.is_some() && (format == OutputFormat::Elf || cli.files.len() > 1) {
+        anyhow::bail!("--listing lists a single source assembled into a binary or HEX image");
+    }
+    if cli.cache.is_some() && (format != OutputFormat::Bin || cli.listing.is_some()) {
+        anyhow::bail!("--cache keeps flat binaries only, without a listing");
+    }
+
+    let bytes = match format {
+        OutputFormat::Bin if let Some(dir) = &cli.cache => {
+            let cache = BuildCache::new(dir);
+            let bytes = match &source.path {
+                Some(path) => cache.assemble_file(path, &options),
+                None => cache.assemble_with_options(&source.text, &options),
+            };
+            report(bytes, &source)?
+        }
+        OutputFormat::Elf => {
+            let object = riscv_asm::assemble_object(source.input(), &options);
+            report(object, &source)?
+        }
+        OutputFormat::Bin | OutputFormat::Hex => {
+            let program = riscv_asm::assemble_program(source.input(), &options);
+            let program = report(program, &source)?;
+            if let Some(path) = &cli.listing {
+                let listing = program.listing.render(&source.text, &program.bytes);
+                fs::write(path, listing).with_context(|| format!("writing {}", path.display()))?;
+            }
+            match format {
+                OutputFormat::Hex => Image::from_binary(&program.bytes, program.origin)?
+                    .write(ImageFormat::IntelHex, 0),
+                _ => program.bytes,
+            }
+        }
+    };
+    match output {
+        Some(path) => {
+            fs::write(&path, bytes).with_context(|| format!("writing {}", path.display()))
+        }
+        None => io::stdout()
+            .lock()
+            .write_all(&bytes)
+            .context("writing the standard output"),
+    }
+}
+
+/// The assembled output, or the error after printing the assembler's diagnostics
+fn report<T>(result: anyhow::Result<T>, source: &Source) -> anyhow::Result<T> {
+    let error = match result {
+        Ok(output) => return Ok(output),
+        Err(error) => error,
+    };
+    let Some(assembler_error) = error.downcast_ref::<AssemblerError>() else {
+        return Err(error);
+    };
+    let sources = SourceFiles::new(&source.name, &source.text);
+    let color = io::stderr().is_terminal();
+    let diagnostics = Diagnostic::from_error(assembler_error, &sources);
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic.render(&sources, color));
+    }
+    let errors = match diagnostics.len() {
+        1 => "error".to_string(),
+        count => format!("{} errors", count),
+    };
+    anyhow::bail!(
+        "could not assemble {} due to the {} above",
+        source.name,
+        errors
+    )
+}
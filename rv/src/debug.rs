@@ -0,0 +1,968 @@
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use riscv_asm::register::{ABI_NAMES, register_number};
+use riscv_emu::{
+    bank::BankedMemory,
+    cpu::Exception,
+    debugger::{Debugger, StopReason},
+    machine::ExitReason,
+};
+
+use crate::{
+    line_editor::{LineEditor, LineReader, default_history_path},
+    session::{Radix, Session},
+    terminal::{ExplanationWriter, Style, Terminal},
+};
+
+/// A parsed debugger command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DebugCommand {
+    Step,
+    Next,
+    Finish,
+    Continue,
+    Until(String),
+    Break(String),
+    Delete(String),
+    Watch(String),
+    Unwatch(String),
+    Display(String),
+    Undisplay(String),
+    SetRadix(Radix),
+    Explain(bool),
+    Breakpoints,
+    Images,
+    Entry(usize),
+    Symbols(Option<String>),
+    Symbol(String),
+    Examine(String, usize),
+    Fill {
+        location: String,
+        len: usize,
+        value: u8,
+    },
+    Copy {
+        source: String,
+        destination: String,
+        len: usize,
+    },
+    /// Bytes to search memory for
+    Find(Vec<u8>),
+    Registers,
+    Backtrace,
+    Restart,
+    Core(PathBuf),
+    Save(Option<PathBuf>),
+    Help,
+    Quit,
+}
+
+/// Command names, completed at the start of a line
+const COMMANDS: [&str; 26] = [
+    "step",
+    "next",
+    "finish",
+    "continue",
+    "until",
+    "break",
+    "delete",
+    "watch",
+    "unwatch",
+    "display",
+    "undisplay",
+    "set",
+    "explain",
+    "info",
+    "entry",
+    "x",
+    "fill",
+    "copy",
+    "find",
+    "backtrace",
+    "restart",
+    "run",
+    "core",
+    "save",
+    "help",
+    "quit",
+];
+
+/// Most addresses `find` lists, the lowest ones
+const MAX_MATCHES: usize = 32;
+
+const HELP: &str = "\
+step, s             execute one instruction
+next, n             step over calls
+finish, fin         run until the current function returns
+continue, c         run until a breakpoint or the end of the program
+until, u LOCATION   run until FILE:LINE, LINE, an address or a symbol is reached
+break, b LOCATION   set a breakpoint at an address or symbol
+delete, d LOCATION  remove a breakpoint
+watch LOCATION      stop when the word at an address or symbol changes
+unwatch LOCATION    remove a watchpoint
+display REGISTER    show a register whenever execution stops
+undisplay REGISTER  stop showing a register
+set radix hex|dec   how register values are shown
+explain on|off      explain each executed instruction with its operand values
+info breakpoints    list breakpoints and watchpoints
+info images         list the loaded images with their entry points, and the memory banks
+entry N             move the pc to the entry point of image N without running anything
+info registers, r   show registers
+info symbols [GLOB]  list symbols, optionally matching a glob or substring
+info symbol NAME    show a symbol's value, section and size
+x LOCATION [COUNT]  show COUNT memory words (default 4) at an address or symbol
+fill LOCATION LEN BYTE  set LEN bytes of memory to BYTE
+copy SRC DST LEN    copy LEN bytes of memory from SRC to DST, which may overlap
+find \"TEXT\"|WORD  list the addresses a string or 32 bit word is found at in memory
+backtrace, bt       show the call stack
+restart, run        start the program over, keeping breakpoints and watchpoints
+core FILE           write an ELF core file of the current state for gdb
+save [FILE]         save breakpoints, watchpoints and displays
+quit, q             leave the debugger";
+
+/// The debugger together with the session the user builds up while using it
+struct Repl {
+    debugger: Debugger,
+    session: Session,
+    /// Where the session is loaded from and saved to on exit
+    session_path: Option<PathBuf>,
+    /// Exception the program stopped with, recorded in core files
+    exception: Option<Exception>,
+}
+
+/// Reads commands from stdin until the user quits or input ends, with line editing, tab
+/// completion and a history kept in `~/.rv_history` on a terminal.
+///
+/// With a session file, the session stored there (if any) is restored first and the
+/// session is saved back when the debugger exits.
+pub fn run(debugger: Debugger, session_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let mut repl = Repl {
+        debugger,
+        session: Session::default(),
+        session_path,
+        exception: None,
+    };
+    if let Some(path) = repl.session_path.clone()
+        && path.exists()
+    {
+        repl.restore(Session::load(&path)?);
+        println!("restored session from {}", path.display());
+    }
+
+    let interrupt = repl.debugger.machine.interrupt_handle();
+    let mut editor: Box<dyn LineReader> = Box::new(LineEditor::new(default_history_path())?);
+    repl.print_stop();
+    while let Some(line) = editor.read_line("(rv) ", &|text| repl.complete(text))? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_command(line) {
+            Ok(DebugCommand::Quit) => break,
+            Ok(command) => {
+                // Ctrl-C at the prompt mustn't stop the command typed after it
+                interrupt.take();
+                repl.execute(command)
+            }
+            Err(message) => println!("{}", message),
+        }
+    }
+
+    if let Some(path) = &repl.session_path {
+        repl.session.save(path)?;
+    }
+    editor.save_history()?;
+    Ok(())
+}
+
+fn parse_command(line: &str) -> Result<DebugCommand, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next().map(str::to_string);
+    let extra = words.next();
+    let location = |argument: Option<String>| {
+        argument.ok_or_else(|| format!("'{}' needs an address or symbol", command))
+    };
+    let length = |len: &str| {
+        crate::parse_address(len)
+            .map(|len| len as usize)
+            .map_err(|_| format!("invalid length '{}'", len))
+    };
+    let register = |argument: Option<String>| {
+        argument
+            .filter(|name| register_number(name).is_some())
+            .ok_or_else(|| format!("'{}' needs a register name", command))
+    };
+    match (command, argument.as_deref(), extra) {
+        ("step" | "s", None, _) => Ok(DebugCommand::Step),
+        ("next" | "n", None, _) => Ok(DebugCommand::Next),
+        ("finish" | "fin", None, _) => Ok(DebugCommand::Finish),
+        ("continue" | "c", None, _) => Ok(DebugCommand::Continue),
+        ("until" | "u", _, None) => location(argument).map(DebugCommand::Until),
+        ("break" | "b", _, None) => location(argument).map(DebugCommand::Break),
+        ("delete" | "d", _, None) => location(argument).map(DebugCommand::Delete),
+        ("watch", _, None) => location(argument).map(DebugCommand::Watch),
+        ("unwatch", _, None) => location(argument).map(DebugCommand::Unwatch),
+        ("display", _, None) => register(argument).map(DebugCommand::Display),
+        ("undisplay", _, None) => register(argument).map(DebugCommand::Undisplay),
+        ("set", Some("radix"), Some("hex")) => Ok(DebugCommand::SetRadix(Radix::Hex)),
+        ("set", Some("radix"), Some("dec")) => Ok(DebugCommand::SetRadix(Radix::Dec)),
+        ("explain", Some("on"), None) => Ok(DebugCommand::Explain(true)),
+        ("explain", Some("off"), None) => Ok(DebugCommand::Explain(false)),
+        ("info", Some("breakpoints" | "b"), None) => Ok(DebugCommand::Breakpoints),
+        ("info", Some("images"), None) => Ok(DebugCommand::Images),
+        ("entry", Some(image), None) => image
+            .trim_start_matches('#')
+            .parse()
+            .map(DebugCommand::Entry)
+            .map_err(|_| format!("'entry' needs an image number, found '{}'", image)),
+        ("info", Some("registers" | "r"), None) | ("r", None, _) => Ok(DebugCommand::Registers),
+        ("info", Some("symbols"), pattern) if words.next().is_none() => {
+            Ok(DebugCommand::Symbols(pattern.map(str::to_string)))
+        }
+        ("info", Some("symbol"), Some(name)) if words.next().is_none() => {
+            Ok(DebugCommand::Symbol(name.to_string()))
+        }
+        ("x", Some(_), count) if words.next().is_none() => {
+            let count = match count {
+                Some(count) => count
+                    .parse()
+                    .map_err(|_| format!("invalid word count '{}'", count))?,
+                None => 4,
+            };
+            location(argument).map(|location| DebugCommand::Examine(location, count))
+        }
+        ("fill", Some(location), Some(len)) => match (words.next(), words.next()) {
+            (Some(value), None) => Ok(DebugCommand::Fill {
+                location: location.to_string(),
+                len: length(len)?,
+                value: crate::parse_byte(value)?,
+            }),
+            _ => Err("'fill' needs a location, a length and a byte value".to_string()),
+        },
+        ("copy", Some(source), Some(destination)) => match (words.next(), words.next()) {
+            (Some(len), None) => Ok(DebugCommand::Copy {
+                source: source.to_string(),
+                destination: destination.to_string(),
+                len: length(len)?,
+            }),
+            _ => Err("'copy' needs a source, a destination and a length".to_string()),
+        },
+        ("fill", ..) => Err("'fill' needs a location, a length and a byte value".to_string()),
+        ("copy", ..) => Err("'copy' needs a source, a destination and a length".to_string()),
+        ("find", ..) => {
+            parse_pattern(line.trim_start()[command.len()..].trim()).map(DebugCommand::Find)
+        }
+        ("backtrace" | "bt", None, _) => Ok(DebugCommand::Backtrace),
+        ("restart" | "run", None, _) => Ok(DebugCommand::Restart),
+        ("core", Some(path), None) => Ok(DebugCommand::Core(PathBuf::from(path))),
+        ("save", _, None) => Ok(DebugCommand::Save(argument.map(PathBuf::from))),
+        ("help" | "h", None, _) => Ok(DebugCommand::Help),
+        ("quit" | "q", None, _) => Ok(DebugCommand::Quit),
+        _ => Err(format!("unknown command '{}', try 'help'", line)),
+    }
+}
+
+impl Repl {
+    /// Applies a saved session, skipping locations that no longer resolve
+    fn restore(&mut self, session: Session) {
+        for location in &session.breakpoints {
+            match self.resolve(location) {
+                Ok(address) => {
+                    self.debugger.add_breakpoint(address);
+                }
+                Err(message) => println!("skipping breakpoint: {}", message),
+            }
+        }
+        for location in &session.watchpoints {
+            match self.resolve(location) {
+                Ok(address) => {
+                    self.debugger.add_watchpoint(address);
+                }
+                Err(message) => println!("skipping watchpoint: {}", message),
+            }
+        }
+        self.session = session;
+    }
+
+    fn execute(&mut self, command: DebugCommand) {
+        let stop = match command {
+            DebugCommand::Step => self.debugger.step(),
+            DebugCommand::Next => self.debugger.step_over(),
+            DebugCommand::Finish => match self.debugger.finish() {
+                Some(stop) => stop,
+                None => {
+                    println!("'finish' is not meaningful in the outermost frame");
+                    return;
+                }
+            },
+            DebugCommand::Continue => self.debugger.resume(),
+            DebugCommand::Until(location) => {
+                let stop = match parse_line(&location) {
+                    Some((file, line)) => self.debugger.run_to_line(file, line).ok_or_else(|| {
+                        format!(
+                            "no code generated for line {} of {}",
+                            line,
+                            file.unwrap_or("any file")
+                        )
+                    }),
+                    None => self
+                        .resolve(&location)
+                        .map(|address| self.debugger.run_to(&[address])),
+                };
+                match stop {
+                    Ok(stop) => stop,
+                    Err(message) => {
+                        println!("{}", message);
+                        return;
+                    }
+                }
+            }
+            DebugCommand::Break(location) => {
+                match self.resolve(&location) {
+                    Ok(address) if self.debugger.add_breakpoint(address) => {
+                        println!("breakpoint at {}", self.describe(address));
+                        self.session.breakpoints.push(location);
+                    }
+                    Ok(address) => println!("breakpoint already set at {:#010x}", address),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Delete(location) => {
+                match self.resolve(&location) {
+                    Ok(address) if self.debugger.remove_breakpoint(address) => {
+                        println!("deleted breakpoint at {:#010x}", address);
+                        self.forget(address, |session| &mut session.breakpoints);
+                    }
+                    Ok(address) => println!("no breakpoint at {:#010x}", address),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Watch(location) => {
+                match self.resolve(&location) {
+                    Ok(address) if self.debugger.add_watchpoint(address) => {
+                        println!("watching {}", self.describe(address));
+                        self.session.watchpoints.push(location);
+                    }
+                    Ok(address) => println!("can't watch {:#010x}", address),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Unwatch(location) => {
+                match self.resolve(&location) {
+                    Ok(address) if self.debugger.remove_watchpoint(address) => {
+                        println!("no longer watching {:#010x}", address);
+                        self.forget(address, |session| &mut session.watchpoints);
+                    }
+                    Ok(address) => println!("no watchpoint at {:#010x}", address),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Display(register) => {
+                if !self.session.displays.contains(&register) {
+                    self.session.displays.push(register);
+                }
+                self.print_displays();
+                return;
+            }
+            DebugCommand::Undisplay(register) => {
+                self.session.displays.retain(|display| *display != register);
+                return;
+            }
+            DebugCommand::SetRadix(radix) => {
+                self.session.radix = radix;
+                return;
+            }
+            DebugCommand::Explain(true) => {
+                let writer = ExplanationWriter::new(io::stdout(), Terminal::stdout());
+                self.debugger.machine.explain_to(Box::new(writer));
+                return;
+            }
+            DebugCommand::Explain(false) => {
+                self.debugger.machine.stop_explaining();
+                return;
+            }
+            DebugCommand::Breakpoints => {
+                for address in self.debugger.breakpoints() {
+                    println!("breakpoint {}", self.describe(address));
+                }
+                for address in self.debugger.watchpoints() {
+                    println!("watchpoint {}", self.describe(address));
+                }
+                return;
+            }
+            DebugCommand::Images => {
+                for (index, (image, entry)) in self.entry_points().into_iter().enumerate() {
+                    println!(
+                        "#{} {:#010x}..{:#010x} entry {}",
+                        index,
+                        image.start,
+                        image.end,
+                        self.describe(entry)
+                    );
+                }
+                if let Some(banks) = self.debugger.machine.cpu.bus.device::<BankedMemory>() {
+                    let window = banks.window();
+                    println!(
+                        "banks {:#010x}..{:#010x}, bank {} of {} selected",
+                        window.start,
+                        window.end,
+                        banks.selected(),
+                        banks.bank_count()
+                    );
+                }
+                return;
+            }
+            DebugCommand::Entry(index) => {
+                let Some((_, entry)) = self.entry_points().get(index).cloned() else {
+                    println!("no image #{}, see 'info images'", index);
+                    return;
+                };
+                self.debugger.jump(entry);
+                self.exception = None;
+                self.print_stop();
+                return;
+            }
+            DebugCommand::Symbols(pattern) => {
+                let symbols = &self.debugger.machine.symbols;
+                let mut found = false;
+                for symbol in symbols.matching(pattern.as_deref().unwrap_or("")) {
+                    println!("{:#010x} {} {}", symbol.address, symbol.kind, symbol.name);
+                    found = true;
+                }
+                if !found {
+                    println!("no matching symbols");
+                }
+                return;
+            }
+            DebugCommand::Symbol(name) => {
+                match self.debugger.machine.symbols.info(&name) {
+                    Some(symbol) => println!(
+                        "{} = {:#010x} in {}, size {}",
+                        symbol.name,
+                        symbol.address,
+                        symbol.section(),
+                        symbol
+                            .size
+                            .map_or("unknown".to_string(), |size| size.to_string())
+                    ),
+                    None => println!("no symbol named '{}'", name),
+                }
+                return;
+            }
+            DebugCommand::Examine(location, count) => {
+                match self.resolve(&location) {
+                    Ok(address) => self.print_memory(address, count),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Fill {
+                location,
+                len,
+                value,
+            } => {
+                let filled = self.resolve(&location).and_then(|address| {
+                    self.debugger
+                        .fill_memory(address, len, value)
+                        .map(|()| address)
+                        .map_err(|error| error.to_string())
+                });
+                match filled {
+                    Ok(address) => println!("filled {} bytes at {}", len, self.describe(address)),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Copy {
+                source,
+                destination,
+                len,
+            } => {
+                let copied = self.resolve(&source).and_then(|source| {
+                    let destination = self.resolve(&destination)?;
+                    self.debugger
+                        .copy_memory(source, destination, len)
+                        .map(|()| (source, destination))
+                        .map_err(|error| error.to_string())
+                });
+                match copied {
+                    Ok((source, destination)) => println!(
+                        "copied {} bytes from {} to {}",
+                        len,
+                        self.describe(source),
+                        self.describe(destination)
+                    ),
+                    Err(message) => println!("{}", message),
+                }
+                return;
+            }
+            DebugCommand::Find(pattern) => {
+                let matches = self.debugger.find_memory(&pattern, MAX_MATCHES + 1);
+                if matches.is_empty() {
+                    println!("not found");
+                }
+                for address in matches.iter().take(MAX_MATCHES) {
+                    println!("{}", self.describe(*address));
+                }
+                if matches.len() > MAX_MATCHES {
+                    println!("(only the first {} matches are listed)", MAX_MATCHES);
+                }
+                return;
+            }
+            DebugCommand::Registers => {
+                let terminal = Terminal::stdout();
+                let cpu = &self.debugger.machine.cpu;
+                println!(
+                    "{}  {}",
+                    terminal.paint(Style::Register, "pc"),
+                    self.describe(cpu.pc)
+                );
+                let xlen = cpu.base_isa.xlen();
+                let cells: Vec<String> = cpu
+                    .regs
+                    .iter()
+                    .zip(ABI_NAMES)
+                    .enumerate()
+                    .map(|(index, (value, name))| {
+                        let register = format!("x{:<2} {:<4}", index, name);
+                        format!(
+                            "{} {}",
+                            terminal.paint(Style::Register, register),
+                            self.session.radix.format_register(*value, xlen)
+                        )
+                    })
+                    .collect();
+                print!("{}", terminal.table(&cells));
+                return;
+            }
+            DebugCommand::Backtrace => {
+                println!("#0 {}", self.describe(self.debugger.machine.cpu.pc));
+                for (depth, frame) in self.debugger.backtrace().iter().rev().enumerate() {
+                    println!(
+                        "#{} {} called from {}",
+                        depth + 1,
+                        self.describe(frame.return_address),
+                        self.describe(frame.call_site)
+                    );
+                }
+                return;
+            }
+            DebugCommand::Restart => {
+                self.debugger.restart();
+                self.exception = None;
+                println!("program restarted");
+                self.print_stop();
+                return;
+            }
+            DebugCommand::Core(path) => {
+                match crate::write_core(&path, &self.debugger.machine, self.exception) {
+                    Ok(()) => println!("core file written to {}", path.display()),
+                    Err(error) => println!("{:#}", error),
+                }
+                return;
+            }
+            DebugCommand::Save(path) => {
+                let Some(path) = path.or_else(|| self.session_path.clone()) else {
+                    println!("no session file, use 'save FILE'");
+                    return;
+                };
+                match self.session.save(&path) {
+                    Ok(()) => println!("session saved to {}", path.display()),
+                    Err(error) => println!("{:#}", error),
+                }
+                return;
+            }
+            DebugCommand::Help => {
+                println!("{}", HELP);
+                return;
+            }
+            DebugCommand::Quit => return,
+        };
+
+        match stop {
+            StopReason::Done => {}
+            StopReason::Breakpoint { pc } => println!("breakpoint hit at {}", self.describe(pc)),
+            StopReason::Watchpoint { address, old, new } => println!(
+                "watchpoint {} changed from {} to {}",
+                self.describe(address),
+                self.session.radix.format(old),
+                self.session.radix.format(new)
+            ),
+            StopReason::Exited(ExitReason::EndOfProgram) => println!(
+                "program finished, exit code {}",
+                self.debugger.machine.cpu.regs[10]
+            ),
+            StopReason::Exited(ExitReason::EnvironmentCall) => println!(
+                "program exited through ecall, exit code {}",
+                self.debugger.machine.cpu.regs[10]
+            ),
+            StopReason::Exited(ExitReason::InstructionLimit) => {
+                println!("stopped after reaching the instruction limit")
+            }
+            StopReason::Exited(ExitReason::Exception(exception)) => {
+                self.exception = Some(exception);
+                println!("stopped by {:?}", exception)
+            }
+            StopReason::Exited(ExitReason::Interrupted) => println!("interrupted"),
+        }
+        self.print_stop();
+    }
+
+    /// Words completing the last word of `text`, a command line up to the cursor: command
+    /// names, then the arguments each command takes, symbols for locations
+    fn complete(&self, text: &str) -> Vec<String> {
+        let mut words: Vec<&str> = text.split_whitespace().collect();
+        if text.ends_with(char::is_whitespace) || text.is_empty() {
+            words.push("");
+        }
+        let Some((partial, words)) = words.split_last() else {
+            return Vec::new();
+        };
+        let candidates: Vec<&str> = match words {
+            [] => COMMANDS.to_vec(),
+            ["info"] => vec!["breakpoints", "images", "registers", "symbols", "symbol"],
+            ["set"] => vec!["radix"],
+            ["set", "radix"] => vec!["hex", "dec"],
+            ["explain"] => vec!["on", "off"],
+            ["display"] => ABI_NAMES.to_vec(),
+            ["undisplay"] => self.session.displays.iter().map(String::as_str).collect(),
+            [
+                "until" | "u" | "break" | "b" | "delete" | "d" | "watch" | "unwatch" | "x" | "fill"
+                | "copy",
+            ]
+            | ["copy", _]
+            | ["info", "symbol" | "symbols"] => self
+                .debugger
+                .machine
+                .symbols
+                .iter()
+                .map(|symbol| symbol.name)
+                .collect(),
+            _ => Vec::new(),
+        };
+        candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(partial))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Every loaded image and where it starts executing: the program's entry point for the
+    /// image holding it, the image's first address for the others
+    fn entry_points(&self) -> Vec<(Range<u32>, u32)> {
+        let machine = &self.debugger.machine;
+        machine
+            .images()
+            .iter()
+            .map(|image| {
+                let entry = if image.contains(&machine.entry()) {
+                    machine.entry()
+                } else {
+                    image.start
+                };
+                (image.clone(), entry)
+            })
+            .collect()
+    }
+
+    /// Words from `address` on, stopping at the end of memory
+    fn print_memory(&self, address: u32, count: usize) {
+        for index in 0..count {
+            let word_address = address.wrapping_add(4 * index as u32);
+            match self.debugger.machine.read_memory(word_address, 4) {
+                Ok(bytes) => {
+                    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    println!(
+                        "{}: {}",
+                        self.describe(word_address),
+                        self.session.radix.format(word)
+                    );
+                }
+                Err(error) => {
+                    println!("{}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Drops every saved location that resolves to `address`
+    fn forget(&mut self, address: u32, list: impl Fn(&mut Session) -> &mut Vec<String>) {
+        let mut locations = std::mem::take(list(&mut self.session));
+        locations.retain(|location| self.resolve(location) != Ok(address));
+        *list(&mut self.session) = locations;
+    }
+
+    /// Parses an address or looks up a symbol
+    fn resolve(&self, location: &str) -> Result<u32, String> {
+        crate::parse_address(location).or_else(|_| {
+            self.debugger
+                .machine
+                .symbols
+                .address(location)
+                .ok_or_else(|| format!("no symbol named '{}'", location))
+        })
+    }
+
+    /// `0x00000010 <main+0x10>`, the symbol only when one is known
+    fn describe(&self, address: u32) -> String {
+        let terminal = Terminal::stdout();
+        let address_text = terminal.paint(Style::Address, format!("{:#010x}", address));
+        match self.debugger.machine.symbols.symbolize(address) {
+            Some(symbol) => format!(
+                "{} {}",
+                address_text,
+                terminal.paint(Style::Symbol, format!("<{}>", symbol))
+            ),
+            None => address_text,
+        }
+    }
+
+    /// Where execution stopped and the instruction there, then every displayed register
+    fn print_stop(&self) {
+        let pc = self.debugger.machine.cpu.pc;
+        match self.debugger.line_map.line_at(pc) {
+            Some(source) => println!("at {} {}:{}", self.describe(pc), source.file, source.line),
+            None => println!("at {}", self.describe(pc)),
+        }
+        if let Some(text) = self.disassemble(pc) {
+            println!("    {}", Terminal::stdout().disassembly(&text));
+        }
+        self.print_displays();
+    }
+
+    /// The instruction at `address` as assembly, `None` outside memory
+    fn disassemble(&self, address: u32) -> Option<String> {
+        let machine = &self.debugger.machine;
+        let bytes = machine
+            .read_memory(address, 4)
+            .or_else(|_| machine.read_memory(address, 2))
+            .ok()?;
+        let mut word = [0; 4];
+        word[..bytes.len()].copy_from_slice(&bytes);
+        let disassembler = crate::disassembler(&machine.symbols, crate::xlen(machine.cpu.base_isa));
+        Some(
+            disassembler
+                .instruction(u32::from_le_bytes(word), address)
+                .text,
+        )
+    }
+
+    fn print_displays(&self) {
+        for name in &self.session.displays {
+            if let Some(register) = register_number(name) {
+                let cpu = &self.debugger.machine.cpu;
+                let value = cpu.regs[register as usize];
+                let value = self
+                    .session
+                    .radix
+                    .format_register(value, cpu.base_isa.xlen());
+                println!("  {} = {}", name, value);
+            }
+        }
+    }
+}
+
+/// Parses `FILE:LINE` or a bare decimal `LINE`
+fn parse_line(location: &str) -> Option<(Option<&str>, u64)> {
+    match location.rsplit_once(':') {
+        Some((file, line)) => Some((Some(file), line.parse().ok()?)),
+        None if location.bytes().all(|byte| byte.is_ascii_digit()) => {
+            Some((None, location.parse().ok()?))
+        }
+        None => None,
+    }
+}
+
+/// The bytes of a `find` pattern: a quoted string, taking \\n, \\t, \\0, \\\\ and \\" escapes,
+/// or a number as a little-endian 32 bit word
+fn parse_pattern(pattern: &str) -> Result<Vec<u8>, String> {
+    let Some(text) = pattern.strip_prefix('"') else {
+        return crate::parse_address(pattern)
+            .map(|word| word.to_le_bytes().to_vec())
+            .map_err(|_| "'find' needs a quoted string or a 32 bit word".to_string());
+    };
+    let Some(text) = text.strip_suffix('"').filter(|text| !text.is_empty()) else {
+        return Err(format!("invalid string {}", pattern));
+    };
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err(format!("invalid escape in {}", pattern)),
+            },
+            c => c,
+        };
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Default session file for `program`, next to it with a `.rvdbg` extension
+pub fn default_session_path(program: &Path) -> PathBuf {
+    let mut path = program.as_os_str().to_owned();
+    path.push(".rvdbg");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use riscv_emu::machine::Machine;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse_command("n"), Ok(DebugCommand::Next));
+        assert_eq!(parse_command("finish"), Ok(DebugCommand::Finish));
+        assert_eq!(
+            parse_command("b  loop"),
+            Ok(DebugCommand::Break("loop".to_string()))
+        );
+        assert_eq!(parse_command("info registers"), Ok(DebugCommand::Registers));
+        assert_eq!(
+            parse_command("set radix dec"),
+            Ok(DebugCommand::SetRadix(Radix::Dec))
+        );
+        assert_eq!(parse_command("explain on"), Ok(DebugCommand::Explain(true)));
+        assert!(parse_command("explain").is_err());
+        assert_eq!(
+            parse_command("display a0"),
+            Ok(DebugCommand::Display("a0".to_string()))
+        );
+        assert!(parse_command("display loop").is_err());
+        assert!(parse_command("break").is_err());
+        assert!(parse_command("until").is_err());
+        assert!(parse_command("next 3").is_err());
+        assert!(parse_command("break a b").is_err());
+        assert_eq!(
+            parse_command("info symbols"),
+            Ok(DebugCommand::Symbols(None))
+        );
+        assert_eq!(
+            parse_command("info symbol main"),
+            Ok(DebugCommand::Symbol("main".to_string()))
+        );
+        assert_eq!(
+            parse_command("x loop 8"),
+            Ok(DebugCommand::Examine("loop".to_string(), 8))
+        );
+        assert_eq!(
+            parse_command("x 0x100"),
+            Ok(DebugCommand::Examine("0x100".to_string(), 4))
+        );
+        assert!(parse_command("x loop many").is_err());
+        assert_eq!(parse_command("run"), Ok(DebugCommand::Restart));
+        assert_eq!(
+            parse_command("core out.core"),
+            Ok(DebugCommand::Core(PathBuf::from("out.core")))
+        );
+        assert_eq!(parse_command("info images"), Ok(DebugCommand::Images));
+        assert_eq!(parse_command("entry #1"), Ok(DebugCommand::Entry(1)));
+        assert!(parse_command("entry main").is_err());
+        assert_eq!(
+            parse_command("fill buffer 0x100 0xaa"),
+            Ok(DebugCommand::Fill {
+                location: "buffer".to_string(),
+                len: 0x100,
+                value: 0xaa
+            })
+        );
+        assert!(parse_command("fill buffer 16 256").is_err());
+        assert!(parse_command("fill buffer 16").is_err());
+        assert_eq!(
+            parse_command("copy 0x1000 dst 8"),
+            Ok(DebugCommand::Copy {
+                source: "0x1000".to_string(),
+                destination: "dst".to_string(),
+                len: 8
+            })
+        );
+        assert_eq!(
+            parse_command("find \"hello world\\n\""),
+            Ok(DebugCommand::Find(b"hello world\n".to_vec()))
+        );
+        assert_eq!(
+            parse_command("find 0xdeadbeef"),
+            Ok(DebugCommand::Find(vec![0xef, 0xbe, 0xad, 0xde]))
+        );
+        assert!(parse_command("find \"unterminated").is_err());
+        assert!(parse_command("find").is_err());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("src/main.s:12"), Some((Some("src/main.s"), 12)));
+        assert_eq!(parse_line("12"), Some((None, 12)));
+        assert_eq!(parse_line("0x12"), None);
+        assert_eq!(parse_line("loop"), None);
+    }
+
+    #[test]
+    fn test_session_tracks_breakpoints() {
+        let mut machine = Machine::new(vec![0x13, 0, 0, 0, 0x13, 0, 0, 0], 64);
+        machine.symbols.insert("second", 4);
+        let mut repl = Repl {
+            debugger: Debugger::new(machine),
+            session: Session::default(),
+            session_path: None,
+            exception: None,
+        };
+        repl.execute(DebugCommand::Break("second".to_string()));
+        repl.execute(DebugCommand::Break("0x0".to_string()));
+        repl.execute(DebugCommand::Watch("0x20".to_string()));
+        assert_eq!(repl.session.breakpoints, ["second", "0x0"]);
+        assert_eq!(repl.session.watchpoints, ["0x20"]);
+
+        // Deleting by address also forgets the symbolic spelling
+        repl.execute(DebugCommand::Delete("4".to_string()));
+        assert_eq!(repl.session.breakpoints, ["0x0"]);
+        assert_eq!(repl.debugger.breakpoints().collect::<Vec<_>>(), [0]);
+
+        let session = repl.session.clone();
+        let mut restored = Repl {
+            debugger: Debugger::new(Machine::new(vec![0x13, 0, 0, 0], 64)),
+            session: Session::default(),
+            session_path: None,
+            exception: None,
+        };
+        restored.restore(session);
+        assert_eq!(restored.debugger.breakpoints().collect::<Vec<_>>(), [0]);
+        assert_eq!(restored.debugger.watchpoints().collect::<Vec<_>>(), [0x20]);
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut machine = Machine::new(vec![0x13, 0, 0, 0], 64);
+        machine.symbols.insert("main", 0);
+        machine.symbols.insert("matrix", 0x20);
+        let repl = Repl {
+            debugger: Debugger::new(machine),
+            session: Session::default(),
+            session_path: None,
+            exception: None,
+        };
+        assert_eq!(repl.complete("fi"), ["finish", "fill", "find"]);
+        assert_eq!(repl.complete("info s"), ["symbols", "symbol"]);
+        assert_eq!(repl.complete("b ma"), ["main", "matrix"]);
+        assert_eq!(repl.complete("copy main m"), ["main", "matrix"]);
+        assert!(repl.complete("copy main matrix ").is_empty());
+        assert_eq!(repl.complete("display s1"), ["s1", "s10", "s11"]);
+        assert_eq!(repl.complete("set radix "), ["hex", "dec"]);
+        assert_eq!(repl.complete("").len(), COMMANDS.len());
+    }
+}
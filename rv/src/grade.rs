@@ -0,0 +1,430 @@
+//! Grading: runs one program on every test case in a directory, each on a machine of its own
+//! and several at once, and summarizes the results as JSON or JUnit XML for CI servers and
+//! learning management systems.
+//!
+//! A test case is a `NAME.json` file giving the program's console input, the output it must
+//! print and optionally the exit code (a0) it must finish with and its instruction limit:
+//!
+//! ```json
+//! { "input": "3 4\n", "expected_output": "7\n", "exit_code": 0, "max_instructions": 10000 }
+//! ```
+
+use std::{
+    fmt::Write,
+    fs,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Instant,
+};
+
+use anyhow::Context;
+use riscv_emu::machine::{ExitReason, Machine, RunLimits, RunOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::report::{TrapReport, exit_reason_name};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    /// File name without the `.json`
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub input: String,
+    pub expected_output: String,
+    pub exit_code: Option<u32>,
+    /// Overrides the limit given on the command line
+    pub max_instructions: Option<u64>,
+}
+
+/// The `.json` test cases in `dir`, sorted by name
+pub fn load_cases(dir: &Path) -> anyhow::Result<Vec<TestCase>> {
+    let mut cases = Vec::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let text =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let mut case: TestCase = serde_json::from_str(&text)
+            .with_context(|| format!("parsing test case {}", path.display()))?;
+        // SAFETY: the path has an extension, so it has a file name too
+        case.name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        cases.push(case);
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Passed,
+    WrongOutput,
+    WrongExitCode,
+    /// Still running when the instruction limit was reached
+    InstructionLimit,
+    /// Stopped by an exception, or the machine couldn't be set up
+    Error,
+}
+
+impl Verdict {
+    fn describe(self) -> &'static str {
+        match self {
+            Verdict::Passed => "passed",
+            Verdict::WrongOutput => "wrong output",
+            Verdict::WrongExitCode => "wrong exit code",
+            Verdict::InstructionLimit => "instruction limit exceeded",
+            Verdict::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub name: String,
+    pub verdict: Verdict,
+    /// Why the run stopped, named like in run reports, `None` if it couldn't start
+    pub exit_reason: Option<&'static str>,
+    pub exit_code: Option<u32>,
+    pub instructions: u64,
+    pub output: String,
+    /// The exception or setup error behind [`Verdict::Error`]
+    pub message: Option<String>,
+    /// Wall-clock time of the run
+    pub seconds: f64,
+}
+
+/// Results of all test cases of a program, see [`GradeSummary::to_json`] and
+/// [`GradeSummary::to_junit`]
+#[derive(Debug, Clone, Serialize)]
+pub struct GradeSummary {
+    pub program: String,
+    pub passed: usize,
+    pub failed: usize,
+    /// In the order of the test cases
+    pub cases: Vec<CaseResult>,
+}
+
+/// Runs every case on a machine `make_machine` builds from `options` with the case's input,
+/// on up to `jobs` threads. Cases without an instruction limit of their own get
+/// `max_instructions`.
+pub fn grade(
+    program: &str,
+    cases: &[TestCase],
+    make_machine: impl Fn(&RunOptions) -> anyhow::Result<Machine> + Sync,
+    options: &RunOptions,
+    max_instructions: u64,
+    jobs: usize,
+) -> GradeSummary {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, CaseResult)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, cases.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(case) = cases.get(index) else {
+                            break results;
+                        };
+                        results.push((
+                            index,
+                            run_case(case, &make_machine, options, max_instructions),
+                        ));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            // SAFETY: running a case doesn't panic, a panic of the emulator is a bug to surface
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    // Workers took the cases in turns
+    results.sort_by_key(|(index, _)| *index);
+    let cases: Vec<CaseResult> = results.into_iter().map(|(_, result)| result).collect();
+    let passed = cases
+        .iter()
+        .filter(|result| result.verdict == Verdict::Passed)
+        .count();
+    GradeSummary {
+        program: program.to_string(),
+        passed,
+        failed: cases.len() - passed,
+        cases,
+    }
+}
+
+fn run_case(
+    case: &TestCase,
+    make_machine: &impl Fn(&RunOptions) -> anyhow::Result<Machine>,
+    options: &RunOptions,
+    max_instructions: u64,
+) -> CaseResult {
+    let start = Instant::now();
+    let options = RunOptions {
+        input: Some(case.input.clone().into_bytes()),
+        echo_output: false,
+        capture_output: true,
+        ..options.clone()
+    };
+    let mut machine = match make_machine(&options) {
+        Ok(machine) => machine,
+        Err(error) => {
+            return CaseResult {
+                name: case.name.clone(),
+                verdict: Verdict::Error,
+                exit_reason: None,
+                exit_code: None,
+                instructions: 0,
+                output: String::new(),
+                message: Some(format!("{:#}", error)),
+                seconds: start.elapsed().as_secs_f64(),
+            };
+        }
+    };
+    let outcome = machine.run(&RunLimits {
+        max_instructions: Some(case.max_instructions.unwrap_or(max_instructions)),
+    });
+    let output = machine.console.output_lossy();
+    let (verdict, message) = match outcome.exit_reason {
+        ExitReason::InstructionLimit => (Verdict::InstructionLimit, None),
+        ExitReason::Exception(exception) => {
+            let trap = TrapReport::new(exception, &machine.symbols);
+            let message = format!("{} at {:#010x}", trap.cause, trap.pc);
+            (Verdict::Error, Some(message))
+        }
+        ExitReason::Interrupted => (Verdict::Error, Some("interrupted".to_string())),
+        ExitReason::EndOfProgram | ExitReason::EnvironmentCall => {
+            if output != case.expected_output {
+                (Verdict::WrongOutput, None)
+            } else if case
+                .exit_code
+                .is_some_and(|code| outcome.exit_code != Some(code))
+            {
+                (Verdict::WrongExitCode, None)
+            } else {
+                (Verdict::Passed, None)
+            }
+        }
+    };
+    CaseResult {
+        name: case.name.clone(),
+        verdict,
+        exit_reason: Some(exit_reason_name(outcome.exit_reason)),
+        exit_code: outcome.exit_code,
+        instructions: outcome.instructions,
+        output,
+        message,
+        seconds: start.elapsed().as_secs_f64(),
+    }
+}
+
+impl GradeSummary {
+    pub fn to_json(&self) -> String {
+        // SAFETY: the summary only contains plain data, so serialization can't fail
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// The summary as a JUnit XML test suite named after the program, failed cases carry
+    /// the expected output and what the program printed
+    pub fn to_junit(&self, cases: &[TestCase]) -> String {
+        let time: f64 = self.cases.iter().map(|result| result.seconds).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            self.cases.len(),
+            self.failed,
+            time
+        );
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            escape_xml(&self.program),
+            self.cases.len(),
+            self.failed,
+            time
+        );
+        for result in &self.cases {
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape_xml(&result.name),
+                escape_xml(&self.program),
+                result.seconds
+            );
+            if result.verdict == Verdict::Passed {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            let mut details = String::new();
+            if let Some(case) = cases.iter().find(|case| case.name == result.name) {
+                match result.verdict {
+                    Verdict::WrongOutput => {
+                        let _ = write!(details, "expected output:\n{}", case.expected_output);
+                    }
+                    Verdict::WrongExitCode => {
+                        let _ = write!(
+                            details,
+                            "expected exit code {:?}, got {:?}",
+                            case.exit_code, result.exit_code
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(message) = &result.message {
+                details.push_str(message);
+            }
+            let _ = writeln!(
+                xml,
+                "      <failure type=\"{:?}\" message=\"{}\">{}</failure>",
+                result.verdict,
+                result.verdict.describe(),
+                escape_xml(&details)
+            );
+            let _ = writeln!(
+                xml,
+                "      <system-out>{}</system-out>",
+                escape_xml(&result.output)
+            );
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    /// One line per case and a total, for the terminal
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        for result in &self.cases {
+            let _ = write!(
+                text,
+                "{:<4} {} ({} instructions)",
+                if result.verdict == Verdict::Passed {
+                    "ok"
+                } else {
+                    "FAIL"
+                },
+                result.name,
+                result.instructions
+            );
+            if result.verdict != Verdict::Passed {
+                let _ = write!(text, ": {}", result.verdict.describe());
+            }
+            if let Some(message) = &result.message {
+                let _ = write!(text, ": {}", message);
+            }
+            text.push('\n');
+        }
+        let _ = writeln!(
+            text,
+            "{}: {} of {} test cases passed",
+            self.program,
+            self.passed,
+            self.cases.len()
+        );
+        text
+    }
+}
+
+/// `text` with XML's special characters escaped, and control characters XML can't hold
+/// replaced
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use riscv_emu::syscalls::SyscallMode;
+
+    use super::*;
+
+    #[test]
+    fn test_grade() {
+        // Echoes one character and exits with it
+        let source = "
+            li a7, 12
+            ecall
+            mv s0, a0
+            li a7, 11
+            ecall
+            mv a0, s0
+            li a7, 93
+            ecall
+        ";
+        let program = riscv_asm::assemble(source).unwrap();
+        let case = |name: &str, input: &str, expected_output: &str, exit_code| TestCase {
+            name: name.to_string(),
+            input: input.to_string(),
+            expected_output: expected_output.to_string(),
+            exit_code,
+            max_instructions: None,
+        };
+        let mut cases = vec![
+            case("echo", "a", "a", Some(97)),
+            case("exit_code", "b", "b", Some(1)),
+            case("output", "c", "<d>", None),
+            case("slow", "d", "d", None),
+        ];
+        cases[3].max_instructions = Some(3);
+        let options = RunOptions {
+            syscalls: SyscallMode::Bare,
+            ..RunOptions::default()
+        };
+        let summary = grade(
+            "echo.s",
+            &cases,
+            |options| Ok(Machine::with_options(program.clone(), 0x1000, options)),
+            &options,
+            1000,
+            3,
+        );
+        let verdicts: Vec<(&str, Verdict)> = summary
+            .cases
+            .iter()
+            .map(|result| (result.name.as_str(), result.verdict))
+            .collect();
+        assert_eq!(
+            verdicts,
+            [
+                ("echo", Verdict::Passed),
+                ("exit_code", Verdict::WrongExitCode),
+                ("output", Verdict::WrongOutput),
+                ("slow", Verdict::InstructionLimit),
+            ]
+        );
+        assert_eq!((summary.passed, summary.failed), (1, 3));
+        assert_eq!(summary.cases[0].instructions, 7);
+
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["cases"][1]["verdict"], "wrong_exit_code");
+        assert_eq!(json["cases"][1]["exit_code"], 98);
+
+        let junit = summary.to_junit(&cases);
+        assert!(junit.contains("<testsuite name=\"echo.s\" tests=\"4\" failures=\"3\""));
+        assert!(junit.contains("<testcase name=\"echo\" classname=\"echo.s\""));
+        assert!(junit.contains("expected output:\n&lt;d&gt;</failure>"));
+        assert!(junit.contains("<system-out>c</system-out>"));
+        assert_eq!(junit.matches("<failure").count(), 3);
+    }
+}
@@ -0,0 +1,33 @@
+//! Ctrl-C handling: the first press stops the running program, a second one exits.
+
+use std::sync::OnceLock;
+
+use riscv_emu::machine::Interrupt;
+
+/// Interrupt of the machine being run, reachable from the signal handler
+static INTERRUPT: OnceLock<Interrupt> = OnceLock::new();
+
+/// Makes Ctrl-C request `interrupt` instead of killing the process.
+///
+/// The machine stops before its next instruction with its state intact. Pressing Ctrl-C again
+/// while the request is still pending exits right away, in case the process isn't running the
+/// machine at all. Only the first call has an effect, and only on unix hosts.
+pub fn install(interrupt: Interrupt) {
+    if INTERRUPT.set(interrupt).is_err() {
+        return;
+    }
+    #[cfg(unix)]
+    // SAFETY: the handler only loads and stores atomics and calls the async-signal-safe _exit
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    match INTERRUPT.get() {
+        Some(interrupt) if !interrupt.is_requested() => interrupt.request(),
+        // SAFETY: _exit may be called from a signal handler
+        _ => unsafe { libc::_exit(130) },
+    }
+}
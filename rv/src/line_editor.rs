@@ -0,0 +1,543 @@
+//! Line editing for the debugger prompt: a history kept between runs, tab completion and
+//! pasting several commands at once.
+//!
+//! On a terminal the line is edited in raw mode with the usual keys: the arrows, Home, End,
+//! Backspace and Delete, Ctrl-A, Ctrl-E, Ctrl-K, Ctrl-U and Ctrl-W, up and down going
+//! through the history. Tab completes the word before the cursor, and a second Tab lists
+//! the candidates when there are several. Ctrl-C discards the line, Ctrl-D on an empty line
+//! ends input. Pasted text is taken literally, each pasted line running as a command of its
+//! own. When stdin isn't a terminal lines are read as they come, without any editing.
+//!
+//! The editor is a small one of our own on `termios` rather than rustyline, which isn't among
+//! the dependencies `rv` is built from offline. The debugger reads through [`LineReader`], so
+//! rustyline or another crate can take its place without touching the prompt loop.
+
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Entries the history keeps, older ones are dropped
+const MAX_HISTORY: usize = 1000;
+
+/// Commands entered before, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    /// Reads a history file, one command per line. A missing file is an empty history.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut history = Self::default();
+        match fs::read_to_string(path) {
+            Ok(text) => text.lines().for_each(|line| history.add(line)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        Ok(history)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text: String = self
+            .entries
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+        fs::write(path, text)
+    }
+
+    /// Appends `line`, unless it's blank or repeats the last entry
+    pub fn add(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.entries.len() == MAX_HISTORY {
+            self.entries.remove(0);
+        }
+        self.entries.push(line.to_string());
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+}
+
+/// `~/.rv_history`, shared by every program debugged
+pub fn default_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rv_history"))
+}
+
+/// Words completing the last word of a line, given the line up to the cursor
+pub type Completer<'a> = dyn Fn(&str) -> Vec<String> + 'a;
+
+/// Where the debugger's commands come from
+pub trait LineReader {
+    /// Shows `prompt` and reads a line, `None` at the end of input. `complete` lists the
+    /// words the last word of the line so far could be.
+    fn read_line(&mut self, prompt: &str, complete: &Completer<'_>) -> io::Result<Option<String>>;
+
+    /// Keeps the lines read so far for the next run
+    fn save_history(&self) -> io::Result<()>;
+}
+
+/// Reads command lines from stdin, see the module documentation
+pub struct LineEditor {
+    history: History,
+    /// Where the history is loaded from and saved to
+    history_path: Option<PathBuf>,
+    /// Whether stdin and stdout are a terminal lines can be edited on
+    interactive: bool,
+    /// Inside a bracketed paste, which can go on over several lines
+    pasting: bool,
+}
+
+impl LineEditor {
+    /// An editor with the history stored at `history_path`, if any
+    pub fn new(history_path: Option<PathBuf>) -> io::Result<Self> {
+        let history = match &history_path {
+            Some(path) => History::load(path)?,
+            None => History::default(),
+        };
+        Ok(Self {
+            history,
+            history_path,
+            interactive: cfg!(unix) && io::stdin().is_terminal() && io::stdout().is_terminal(),
+            pasting: false,
+        })
+    }
+
+    fn edit_line(&mut self, prompt: &str, complete: &Completer<'_>) -> io::Result<Option<String>> {
+        let mut stdout = io::stdout();
+        let mut editing = Editing::new(self.history.entries());
+        let mut bytes = io::stdin().lock().bytes().map_while(Result::ok);
+        editing.render(prompt, &mut stdout)?;
+        loop {
+            let Some(key) = read_key(&mut bytes) else {
+                return Ok(None);
+            };
+            match key {
+                Key::PasteStart => self.pasting = true,
+                Key::PasteEnd => self.pasting = false,
+                key => match editing.handle(key, self.pasting, complete) {
+                    Outcome::Edited => {}
+                    Outcome::Submit(line) => return Ok(Some(line)),
+                    Outcome::EndOfInput => return Ok(None),
+                    Outcome::Discard => {
+                        write!(stdout, "^C\r\n")?;
+                        editing = Editing::new(self.history.entries());
+                    }
+                    Outcome::List(candidates) => {
+                        write!(stdout, "\r\n{}\r\n", candidates.join("  "))?
+                    }
+                },
+            }
+            editing.render(prompt, &mut stdout)?;
+        }
+    }
+}
+
+impl LineReader for LineEditor {
+    /// Lines entered on a terminal are added to the history
+    fn read_line(&mut self, prompt: &str, complete: &Completer<'_>) -> io::Result<Option<String>> {
+        if !self.interactive {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+        }
+
+        #[cfg(unix)]
+        let _raw_mode = RawMode::enable()?;
+        let line = self.edit_line(prompt, complete);
+        print!("\r\n");
+        io::stdout().flush()?;
+        if let Ok(Some(line)) = &line {
+            self.history.add(line);
+        }
+        line
+    }
+
+    fn save_history(&self) -> io::Result<()> {
+        match &self.history_path {
+            Some(path) => self.history.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A key press, decoded from the bytes a terminal sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    /// Ctrl-K
+    KillToEnd,
+    /// Ctrl-U
+    KillToStart,
+    /// Ctrl-W, the word before the cursor
+    DeleteWord,
+    /// Ctrl-C
+    Interrupt,
+    /// Ctrl-D
+    EndOfInput,
+    /// Start of a bracketed paste
+    PasteStart,
+    PasteEnd,
+    /// Control characters and escape sequences without a meaning here
+    Ignored,
+}
+
+/// The next key in `bytes`, `None` at the end of input
+fn read_key(bytes: &mut impl Iterator<Item = u8>) -> Option<Key> {
+    let key = match bytes.next()? {
+        0x01 => Key::Home,
+        0x02 => Key::Left,
+        0x03 => Key::Interrupt,
+        0x04 => Key::EndOfInput,
+        0x05 => Key::End,
+        0x06 => Key::Right,
+        0x08 | 0x7f => Key::Backspace,
+        b'\t' => Key::Tab,
+        b'\r' | b'\n' => Key::Enter,
+        0x0b => Key::KillToEnd,
+        0x0e => Key::Down,
+        0x10 => Key::Up,
+        0x15 => Key::KillToStart,
+        0x17 => Key::DeleteWord,
+        0x1b => read_escape(bytes),
+        byte if byte < 0x20 => Key::Ignored,
+        byte => {
+            let len = match byte.leading_ones() {
+                0 => 1,
+                len @ 2..=4 => len as usize,
+                _ => return Some(Key::Ignored),
+            };
+            let mut encoded = vec![byte];
+            encoded.extend(bytes.take(len - 1));
+            match std::str::from_utf8(&encoded) {
+                Ok(text) => Key::Char(text.chars().next()?),
+                Err(_) => Key::Ignored,
+            }
+        }
+    };
+    Some(key)
+}
+
+/// The key of an escape sequence, the ESC already read: `ESC [ params final` or `ESC O final`
+fn read_escape(bytes: &mut impl Iterator<Item = u8>) -> Key {
+    let introducer = bytes.next();
+    let mut params = String::new();
+    let last = loop {
+        match bytes.next() {
+            Some(byte @ (b'0'..=b'9' | b';')) if introducer == Some(b'[') => {
+                params.push(byte as char)
+            }
+            Some(byte) => break byte,
+            None => return Key::Ignored,
+        }
+    };
+    match (introducer, params.as_str(), last) {
+        (Some(b'[' | b'O'), "", b'A') => Key::Up,
+        (Some(b'[' | b'O'), "", b'B') => Key::Down,
+        (Some(b'[' | b'O'), "", b'C') => Key::Right,
+        (Some(b'[' | b'O'), "", b'D') => Key::Left,
+        (Some(b'[' | b'O'), "", b'H') | (Some(b'['), "1" | "7", b'~') => Key::Home,
+        (Some(b'[' | b'O'), "", b'F') | (Some(b'['), "4" | "8", b'~') => Key::End,
+        (Some(b'['), "3", b'~') => Key::Delete,
+        (Some(b'['), "200", b'~') => Key::PasteStart,
+        (Some(b'['), "201", b'~') => Key::PasteEnd,
+        _ => Key::Ignored,
+    }
+}
+
+/// What a key did to the line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Edited,
+    /// Enter, the line is done
+    Submit(String),
+    /// Ctrl-C, the line is dropped
+    Discard,
+    EndOfInput,
+    /// The candidates of an ambiguous completion, to show the user
+    List(Vec<String>),
+}
+
+/// A line being edited
+struct Editing<'a> {
+    line: Vec<char>,
+    cursor: usize,
+    history: &'a [String],
+    /// Index of the history entry shown, `history.len()` for the line being typed
+    position: usize,
+    /// The line being typed, kept while going through the history
+    draft: Vec<char>,
+    /// The last key was a Tab
+    tabbed: bool,
+}
+
+impl<'a> Editing<'a> {
+    fn new(history: &'a [String]) -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history,
+            position: history.len(),
+            draft: Vec::new(),
+            tabbed: false,
+        }
+    }
+
+    /// Applies `key`. Pasted text is inserted as is, its tabs as spaces.
+    fn handle(&mut self, key: Key, pasting: bool, complete: &Completer<'_>) -> Outcome {
+        let tabbed = std::mem::take(&mut self.tabbed);
+        match key {
+            Key::Char(c) => self.insert(&[c]),
+            Key::Tab if pasting => self.insert(&[' ']),
+            Key::Tab => {
+                self.tabbed = true;
+                return self.complete(complete, tabbed);
+            }
+            Key::Enter => return Outcome::Submit(self.line.iter().collect()),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up if self.position > 0 => self.show_history(self.position - 1),
+            Key::Down if self.position < self.history.len() => self.show_history(self.position + 1),
+            Key::KillToEnd => self.line.truncate(self.cursor),
+            Key::KillToStart => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::DeleteWord => {
+                let end = self.cursor;
+                while self.cursor > 0 && self.line[self.cursor - 1] == ' ' {
+                    self.cursor -= 1;
+                }
+                while self.cursor > 0 && self.line[self.cursor - 1] != ' ' {
+                    self.cursor -= 1;
+                }
+                self.line.drain(self.cursor..end);
+            }
+            Key::Interrupt => return Outcome::Discard,
+            Key::EndOfInput if self.line.is_empty() => return Outcome::EndOfInput,
+            Key::EndOfInput if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            _ => {}
+        }
+        Outcome::Edited
+    }
+
+    fn insert(&mut self, chars: &[char]) {
+        self.line
+            .splice(self.cursor..self.cursor, chars.iter().copied());
+        self.cursor += chars.len();
+    }
+
+    /// Shows history entry `position`, or the draft past the last one
+    fn show_history(&mut self, position: usize) {
+        if self.position == self.history.len() {
+            self.draft = self.line.clone();
+        }
+        self.position = position;
+        self.line = match self.history.get(position) {
+            Some(entry) => entry.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.cursor = self.line.len();
+    }
+
+    /// Completes the word before the cursor: a single candidate entirely, followed by a
+    /// space, several as far as they agree. When they don't agree any further a second Tab
+    /// lists them.
+    fn complete(&mut self, complete: &Completer<'_>, tabbed: bool) -> Outcome {
+        let before: String = self.line[..self.cursor].iter().collect();
+        let start = before.rfind(' ').map_or(0, |space| space + 1);
+        let word = &before[start..];
+        let mut candidates: Vec<String> = complete(&before)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let completion = match candidates.as_slice() {
+            [] => return Outcome::Edited,
+            [candidate] => format!("{} ", candidate),
+            [first, rest @ ..] => rest.iter().fold(first.clone(), |prefix, candidate| {
+                let len = prefix
+                    .chars()
+                    .zip(candidate.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(c, _)| c.len_utf8())
+                    .sum();
+                prefix[..len].to_string()
+            }),
+        };
+        if completion.len() > word.len() {
+            let added: Vec<char> = completion[word.len()..].chars().collect();
+            self.insert(&added);
+            Outcome::Edited
+        } else if tabbed {
+            Outcome::List(candidates)
+        } else {
+            Outcome::Edited
+        }
+    }
+
+    /// Redraws the line after `prompt` and puts the terminal's cursor at the line's
+    fn render(&self, prompt: &str, out: &mut impl Write) -> io::Result<()> {
+        let line: String = self.line.iter().collect();
+        write!(out, "\r{}{}\x1b[K", prompt, line)?;
+        let behind = self.line.len() - self.cursor;
+        if behind > 0 {
+            write!(out, "\x1b[{}D", behind)?;
+        }
+        out.flush()
+    }
+}
+
+/// The terminal switched to raw mode, with bracketed paste on, until dropped
+#[cfg(unix)]
+struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        // SAFETY: termios is plain data, tcgetattr fills it in
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: the pointer is to a live termios
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        // Keys arrive one at a time and unechoed, Ctrl-C as a key rather than SIGINT
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: as above. TCSANOW keeps typed-ahead and pasted input.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        print!("\x1b[?2004h");
+        Ok(Self { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?2004l");
+        let _ = io::stdout().flush();
+        // SAFETY: restores the settings read in enable
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `input` to a fresh line, returning the line and the last outcome
+    fn edit(input: &[u8], history: &[String], complete: &Completer<'_>) -> (String, Outcome) {
+        let mut editing = Editing::new(history);
+        let mut bytes = input.iter().copied();
+        let mut pasting = false;
+        let mut outcome = Outcome::Edited;
+        while let Some(key) = read_key(&mut bytes) {
+            outcome = match key {
+                Key::PasteStart => {
+                    pasting = true;
+                    continue;
+                }
+                Key::PasteEnd => {
+                    pasting = false;
+                    continue;
+                }
+                key => editing.handle(key, pasting, complete),
+            };
+        }
+        (editing.line.iter().collect(), outcome)
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        history.add("break main");
+        history.add("  ");
+        history.add("step");
+        history.add("step");
+        assert_eq!(history.entries(), ["break main", "step"]);
+        for index in 0..MAX_HISTORY {
+            history.add(&format!("x {}", index));
+        }
+        assert_eq!(history.entries().len(), MAX_HISTORY);
+        assert_eq!(history.entries()[0], "x 0");
+
+        let path = std::env::temp_dir().join(format!("rv-history-{}", std::process::id()));
+        history.save(&path).unwrap();
+        assert_eq!(History::load(&path).unwrap(), history);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(History::load(&path).unwrap(), History::default());
+    }
+
+    #[test]
+    fn test_editing() {
+        let none = |_: &str| Vec::new();
+        // Left twice, then insert, Home, Delete, End and Backspace
+        assert_eq!(
+            edit(b"stpe\x1b[D\x1b[De\x1b[H\x1b[3~\x1b[F\x7f", &[], &none).0,
+            "tep"
+        );
+        assert_eq!(edit(b"x loop 8\x17\x17", &[], &none).0, "x ");
+        assert_eq!(
+            edit(b"info\r", &[], &none).1,
+            Outcome::Submit("info".to_string())
+        );
+        assert_eq!(edit(b"\x04", &[], &none).1, Outcome::EndOfInput);
+        assert_eq!(edit(b"step\x03", &[], &none).1, Outcome::Discard);
+
+        // Up goes back through the history, Down returns to the line being typed
+        let history = ["break main".to_string(), "continue".to_string()];
+        assert_eq!(edit(b"s\x1b[A\x1b[A", &history, &none).0, "break main");
+        assert_eq!(edit(b"s\x1b[A\x1b[A\x1b[B\x1b[B", &history, &none).0, "s");
+
+        let commands = |_: &str| vec!["step".to_string(), "save".to_string()];
+        assert_eq!(edit(b"st\t", &[], &commands).0, "step ");
+        assert_eq!(edit(b"s\t", &[], &commands).1, Outcome::Edited);
+        assert_eq!(
+            edit(b"s\t\t", &[], &commands).1,
+            Outcome::List(vec!["save".to_string(), "step".to_string()])
+        );
+        // Pasted tabs don't complete
+        assert_eq!(edit(b"\x1b[200~st\tx\x1b[201~", &[], &commands).0, "st x");
+    }
+}
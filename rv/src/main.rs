@@ -1,6 +1,1825 @@
+mod debug;
+mod grade;
+mod interrupt;
+mod line_editor;
+mod report;
+mod session;
+mod terminal;
+
+use std::{
+    fs,
+    io::{self, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    thread,
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use easy_riscv::Program;
+use riscv_asm::{
+    AssembledProgram, AssemblerOptions, SkippedLine,
+    analysis::Warnings,
+    diagnostic::{Diagnostic, SourceFiles},
+    disassembler::Disassembler,
+    encoder::Xlen,
+    error::AssemblerError,
+    listing::Listing,
+    register::register_number,
+    section::Section,
+};
+use riscv_emu::{
+    bank::BankedMemory,
+    constant_time::audit_constant_time,
+    core_file::write_core_file,
+    cpu::{BaseIsa, Exception},
+    debugger::Debugger,
+    elf::{Elf, ElfSection, is_elf, write_executable},
+    image::{Image, ImageFormat},
+    layout::{Region, RegionKind},
+    line_map::LineMap,
+    linux::{LinuxSyscalls, start_process},
+    machine::{ExitReason, Machine, RunLimits, RunOptions},
+    shadow::{DataObject, ShadowMemory},
+    symbols::Symbols,
+    syscalls::{SyscallLog, SyscallMode},
+    trace::{InstructionClass, TraceEntry, TraceFilter, audit_determinism},
+    trace_file::{TraceReader, TraceWriter},
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    report::{MAX_REPORTED_LOOPS, RunReport},
+    terminal::{ExplanationWriter, Style, Terminal},
+};
+
 /// Memory of 64MiB
 const MEMORY_SIZE: u32 = 1024 * 1024 * 64;
 
-fn main() {
-    println!("Hello, world!");
+/// Banks are sized in multiples of 4 KiB
+const BANK_ALIGNMENT: usize = 4096;
+
+/// Console output kept for the JSON report
+const MAX_REPORTED_OUTPUT: usize = 1024 * 1024;
+
+/// Environment variable holding the log filter, e.g. `RV_LOG=riscv_asm::parser=trace,warn`
+const LOG_ENV: &str = "RV_LOG";
+
+#[derive(Parser)]
+#[command(version, about = "Assemble and run RISC-V programs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+/// How assembly sources are read, for every command assembling one
+#[derive(clap::Args)]
+struct SourceArgs {
+    /// Directory to look for `.include`d files in, after the including file's own
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR", global = true)]
+    include_dirs: Vec<PathBuf>,
+    /// Define a symbol as if with `.equ` before the first line, 1 without a value, for
+    /// `.if` and `.ifdef`
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]", value_parser = parse_define, global = true)]
+    defines: Vec<(String, u32)>,
+    /// Warn about a kind of finding (dead-label, unreachable-code, branch-to-next,
+    /// li-fits-addi, data-in-text), or not with no-KIND; -Werror and -Werror=KIND make
+    /// warnings errors, -Wall enables every kind (repeatable, applied in order)
+    #[arg(short = 'W', value_name = "WARNING", value_parser = parse_warning, global = true)]
+    warnings: Vec<String>,
+    /// Turn branches to labels out of their ±4 KiB reach into a branch over a jump instead
+    /// of failing
+    #[arg(long, global = true)]
+    relax_branches: bool,
+}
+
+impl SourceArgs {
+    fn apply(&self, options: AssemblerOptions) -> AssemblerOptions {
+        AssemblerOptions {
+            include_paths: self.include_dirs.clone(),
+            defines: self.defines.iter().cloned().collect(),
+            warnings: self.warnings(),
+            relax_branches: self.relax_branches,
+            ..options
+        }
+    }
+
+    fn warnings(&self) -> Warnings {
+        let mut warnings = Warnings::default();
+        for option in &self.warnings {
+            // SAFETY: parse_warning checked every option
+            warnings.apply(option).expect("valid warning option");
+        }
+        warnings
+    }
+}
+
+// Parsed once at startup, the size of the run options doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Run an assembly source (.s, .S, .asm), an Intel HEX or S-record image, or a raw binary
+    Run {
+        file: PathBuf,
+        /// Format of FILE, detected from its extension and contents by default
+        #[arg(long)]
+        format: Option<Format>,
+        /// Target ISA: rv32i, or rv32e to restrict programs to x0-x15
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Address a raw binary or assembled program is loaded at
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        load_addr: u32,
+        /// Address execution starts at, defaults to the image's entry point or lowest address
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        entry: Option<u32>,
+        /// Additional image to load, in any format FILE can have, e.g. --image data.bin@0x10000
+        /// (repeatable)
+        #[arg(long, value_name = "FILE@ADDR", value_parser = parse_image)]
+        image: Vec<(PathBuf, u32)>,
+        /// Image of a memory bank, the first --bank is bank 0 (repeatable)
+        #[arg(long, value_name = "FILE")]
+        bank: Vec<PathBuf>,
+        /// Address the selected bank is seen at, the bank select register is the word after
+        /// the bank
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0x20000000")]
+        bank_window: u32,
+        /// Stop after executing this many instructions
+        #[arg(long)]
+        max_instructions: Option<u64>,
+        /// Reserve a stack of this many bytes at the top of memory and point sp at its top
+        #[arg(long, value_name = "BYTES", value_parser = parse_address)]
+        stack_size: Option<u32>,
+        /// Reserve a heap of this many bytes right after the loaded images
+        #[arg(long, value_name = "BYTES", value_parser = parse_address)]
+        heap_size: Option<u32>,
+        /// Feed the contents of FILE to the program instead of reading stdin
+        #[arg(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+        /// Console backend: stdio, pty, or tcp:ADDRESS (e.g. tcp:127.0.0.1:4000)
+        #[arg(long, default_value = "stdio")]
+        console: ConsoleBackend,
+        /// Run the program twice and fail if the two executions differ in any way
+        #[arg(long)]
+        audit_determinism: bool,
+        /// Run the program on the contents of each file as its input and report branches,
+        /// memory addresses and durations that depend on it, failing if any do
+        #[arg(long, num_args = 2, value_names = ["INPUT1", "INPUT2"])]
+        audit_constant_time: Vec<PathBuf>,
+        /// Write a JSON run report to PATH, or to stdout when no path is given
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-")]
+        report_json: Option<PathBuf>,
+        /// nm-style symbol file used to symbolize addresses, e.g. for externally built binaries
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+        /// Write every executed instruction to FILE
+        #[arg(long, value_name = "FILE")]
+        trace: Option<PathBuf>,
+        /// Trace file format: text lines, or compact binary (zstd needs the zstd feature)
+        #[arg(long, default_value = "text", requires = "trace")]
+        trace_format: TraceFormat,
+        /// Only trace instructions inside this function (repeatable)
+        #[arg(long, value_name = "SYMBOL", requires = "trace")]
+        trace_function: Vec<String>,
+        /// Only trace instructions of this class (repeatable)
+        #[arg(long, value_name = "CLASS", requires = "trace")]
+        trace_class: Vec<TraceClass>,
+        /// Only trace while a register holds a value, e.g. --trace-when a0=5
+        #[arg(long, value_name = "REG=VALUE", value_parser = parse_register_value, requires = "trace")]
+        trace_when: Option<(u8, u32)>,
+        /// Write an ELF core file of the final state to FILE, for `gdb PROGRAM FILE`
+        #[arg(long, value_name = "FILE")]
+        core: Option<PathBuf>,
+        /// Report loads and stores past the end of the assembled program's .data objects
+        #[arg(long)]
+        check_bounds: bool,
+        /// Report the hottest loops with their trip counts and instructions per iteration
+        #[arg(long)]
+        profile_loops: bool,
+        /// Explain every executed instruction on stderr, with its operand values filled in
+        #[arg(long)]
+        explain: bool,
+        /// Map a CLINT (mtime, mtimecmp, msip) at 0x02000000 for timer and software interrupts
+        #[arg(long)]
+        clint: bool,
+        /// Map a PLIC at 0x0c000000 for external interrupts of devices
+        #[arg(long)]
+        plic: bool,
+        /// Map a 16550 UART at 0x10000000 on the console, interrupting on PLIC source 10
+        #[arg(long)]
+        uart: bool,
+        /// Keep a tag bit per memory word, checked on loads and stores as the mtagctl CSR
+        /// (0x7c8) selects
+        #[arg(long)]
+        tagged_memory: bool,
+        /// System calls carried out on ecall: none ends the run, bare has RARS-style console
+        /// I/O, sbrk and exit, linux runs Linux user-mode programs
+        #[arg(long, default_value = "none")]
+        syscalls: Syscalls,
+        /// Machine profile, setting the ISA, system calls and checks at once and overriding
+        /// --march and --syscalls
+        #[arg(long)]
+        profile: Option<Profile>,
+        /// Directory a Linux program can open files in, it can't open any without one
+        #[arg(long, value_name = "DIR")]
+        fs_root: Option<PathBuf>,
+        /// Environment variable of a Linux program (repeatable)
+        #[arg(long, value_name = "NAME=VALUE")]
+        env: Vec<String>,
+        /// Assemble lines with errors as EBREAK instead of failing, to run the rest
+        #[arg(long)]
+        permissive: bool,
+        /// Arguments of a Linux program, after its name
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Debug a program interactively, accepts the same files as `run`
+    Debug {
+        file: PathBuf,
+        #[arg(long)]
+        format: Option<Format>,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Address a raw binary or assembled program is loaded at
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        load_addr: u32,
+        /// Additional image to load, switch to its entry point with `entry` (repeatable)
+        #[arg(long, value_name = "FILE@ADDR", value_parser = parse_image)]
+        image: Vec<(PathBuf, u32)>,
+        /// Image of a memory bank, the first --bank is bank 0 (repeatable)
+        #[arg(long, value_name = "FILE")]
+        bank: Vec<PathBuf>,
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0x20000000")]
+        bank_window: u32,
+        /// nm-style symbol file used for breakpoints and locations
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+        /// Stop any single command after this many instructions
+        #[arg(long, default_value = "10000000")]
+        max_instructions: u64,
+        /// Session file restored at start and saved on exit, defaults to FILE.rvdbg
+        #[arg(long, value_name = "FILE")]
+        session: Option<PathBuf>,
+        /// Don't restore or save a session
+        #[arg(long, conflicts_with = "session")]
+        no_session: bool,
+        /// Map a CLINT (mtime, mtimecmp, msip) at 0x02000000 for timer and software interrupts
+        #[arg(long)]
+        clint: bool,
+        /// Map a PLIC at 0x0c000000 for external interrupts of devices
+        #[arg(long)]
+        plic: bool,
+        /// Map a 16550 UART at 0x10000000 on the console, interrupting on PLIC source 10
+        #[arg(long)]
+        uart: bool,
+        /// Keep a tag bit per memory word, checked on loads and stores as the mtagctl CSR
+        /// (0x7c8) selects
+        #[arg(long)]
+        tagged_memory: bool,
+        /// System calls carried out on ecall: none ends the run, bare has RARS-style console
+        /// I/O, sbrk and exit, linux runs Linux user-mode programs
+        #[arg(long, default_value = "none")]
+        syscalls: Syscalls,
+        /// Directory a Linux program can open files in, it can't open any without one
+        #[arg(long, value_name = "DIR")]
+        fs_root: Option<PathBuf>,
+    },
+    /// Run a program on every test case in a directory, several at once, and summarize
+    /// which passed as JSON or JUnit XML
+    Grade {
+        /// Program to grade, in any format `run` accepts
+        file: PathBuf,
+        /// Directory of test cases, NAME.json files with the input, expected_output and
+        /// optionally exit_code and max_instructions of a run
+        cases: PathBuf,
+        #[arg(long)]
+        format: Option<Format>,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Address a raw binary or assembled program is loaded at
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        load_addr: u32,
+        /// Instruction limit of the test cases that don't set their own
+        #[arg(long, default_value = "10000000")]
+        max_instructions: u64,
+        /// Reserve a stack of this many bytes at the top of memory and point sp at its top
+        #[arg(long, value_name = "BYTES", value_parser = parse_address)]
+        stack_size: Option<u32>,
+        /// Test cases run at once, defaults to the number of CPUs
+        #[arg(long, short)]
+        jobs: Option<usize>,
+        /// Write the summary as JSON to PATH, or to stdout when no path is given
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-")]
+        report_json: Option<PathBuf>,
+        /// Write the summary as JUnit XML to PATH
+        #[arg(long, value_name = "PATH")]
+        junit: Option<PathBuf>,
+        /// System calls carried out on ecall, bare gives programs console I/O and exit
+        #[arg(long, default_value = "bare")]
+        syscalls: Syscalls,
+        /// Machine profile the cases run on, overriding --march and --syscalls
+        #[arg(long)]
+        profile: Option<Profile>,
+    },
+    /// Print a binary trace file written by `run --trace-format binary|zstd` as text
+    Trace {
+        file: PathBuf,
+        /// nm-style symbol file used to symbolize addresses
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+    },
+    /// Print the machine code of an assembly source's `.text`, or of a program image, as
+    /// assembly, objdump-style
+    Disasm {
+        file: PathBuf,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Input format, detected from the extension and contents by default
+        #[arg(long)]
+        format: Option<Format>,
+        /// Address a flat binary input is placed at
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        base: u32,
+    },
+    /// Assemble a source file and write its symbols as an nm-style symbol file
+    Symbols {
+        file: PathBuf,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Output file, defaults to stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Assemble a source file and report dead labels, unreachable code and the other
+    /// findings -W enables
+    Check {
+        file: PathBuf,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Fail if anything was found, like -Werror
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Assemble a source file and list, for every label, the instructions referring to it
+    Xref {
+        file: PathBuf,
+        #[arg(long, default_value = "rv32i")]
+        march: March,
+        /// Output file, defaults to stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Convert a program image between ELF, flat binary, Intel HEX, S-records and
+    /// `$readmemh` text, optionally extracting sections or padding it
+    Objcopy {
+        /// Input image, assembly sources are assembled first
+        input: PathBuf,
+        output: PathBuf,
+        /// Input format, detected from the extension and contents by default
+        #[arg(long)]
+        input_format: Option<Format>,
+        /// Output format, guessed from the extension by default
+        #[arg(long, short = 'O')]
+        output_format: Option<Format>,
+        /// Only copy this section of an assembly source or ELF file, can be repeated
+        #[arg(long, short = 'j', value_name = "SECTION")]
+        only_section: Vec<String>,
+        /// Address a flat binary input is placed at
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        base: u32,
+        /// Pad the image up to this address
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        pad_to: Option<u32>,
+        /// Pad the end of the image to a multiple of this many bytes
+        #[arg(long, value_name = "BYTES", value_parser = parse_address)]
+        align: Option<u32>,
+        /// Byte used for padding and for gaps between segments
+        #[arg(long, value_name = "BYTE", value_parser = parse_byte, default_value = "0")]
+        gap_fill: u8,
+        /// Also write a listing of the assembly source INPUT, every line with its address,
+        /// machine code and the values of the symbols it uses
+        #[arg(long, value_name = "FILE")]
+        listing: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            let terminal = Terminal::stderr();
+            eprintln!("{} {:#}", terminal.paint(Style::Error, "error:"), error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    init_logging();
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Run {
+            file,
+            format,
+            march,
+            load_addr,
+            entry,
+            image,
+            bank,
+            bank_window,
+            max_instructions,
+            stack_size,
+            heap_size,
+            input,
+            console,
+            audit_determinism: audit,
+            audit_constant_time,
+            report_json,
+            symbols,
+            trace,
+            trace_format,
+            trace_function,
+            trace_class,
+            trace_when,
+            core,
+            check_bounds,
+            profile_loops,
+            explain,
+            clint,
+            plic,
+            uart,
+            tagged_memory,
+            syscalls,
+            profile,
+            fs_root,
+            env,
+            permissive,
+            args,
+        } => {
+            let march = profile.map_or(march, Profile::march);
+            let stack_size = stack_size.or(profile.map(Profile::stack_size));
+            let assembler_options = AssemblerOptions {
+                permissive,
+                ..march.assembler_options(&cli.source)
+            };
+            let LoadedProgram {
+                image: program,
+                symbols: mut program_symbols,
+                mut line_map,
+                data_objects,
+                skipped_lines,
+                auxv,
+                ..
+            } = load_image(&file, format, &assembler_options, load_addr)?;
+            if let Some(path) = symbols {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                program_symbols =
+                    Symbols::parse(&text).with_context(|| format!("parsing {}", path.display()))?;
+            }
+            let entry = entry
+                .or(program.entry)
+                .or(program.start())
+                .unwrap_or(load_addr);
+            let mut images: Vec<(Vec<u8>, u32)> = program
+                .segments()
+                .iter()
+                .map(|segment| (segment.data.clone(), segment.address))
+                .collect();
+            images.extend(load_extra_images(
+                &image,
+                &assembler_options,
+                &mut program_symbols,
+                &mut line_map,
+            )?);
+            let banks = load_banks(&bank, bank_window, &assembler_options)?;
+            let limits = RunLimits { max_instructions };
+            let input = input
+                .map(|path| fs::read(&path).with_context(|| format!("reading {}", path.display())))
+                .transpose()?;
+            let options = RunOptions {
+                base_isa: march.base_isa(),
+                input,
+                // The report carries the output, keep stdout clean JSON when it goes there
+                echo_output: report_json
+                    .as_ref()
+                    .is_none_or(|path| path.as_os_str() != "-"),
+                capture_output: report_json.is_some(),
+                max_captured_output: Some(MAX_REPORTED_OUTPUT),
+                clint,
+                plic,
+                uart,
+                syscalls: syscalls.into(),
+                tagged_memory,
+                ..RunOptions::default()
+            };
+            let options = match profile {
+                Some(profile) => profile.apply(options),
+                None => options,
+            };
+            let process = LinuxProcess {
+                args: [file.display().to_string()]
+                    .into_iter()
+                    .chain(args)
+                    .collect(),
+                env,
+                root: fs_root,
+                auxv,
+            };
+            let make_machine = |options: &RunOptions| {
+                let mut machine = build_machine(&images, entry, options)?;
+                attach_banks(&mut machine, banks.as_ref())?;
+                reserve_memory(&mut machine, stack_size, heap_size)?;
+                if options.syscalls == SyscallMode::Linux {
+                    start_linux_process(&mut machine, &process)?;
+                }
+                Ok(machine)
+            };
+            if audit {
+                return run_determinism_audit(make_machine, &options, &limits);
+            }
+            if let [first, second] = audit_constant_time.as_slice() {
+                let read = |path: &PathBuf| {
+                    fs::read(path).with_context(|| format!("reading {}", path.display()))
+                };
+                return run_constant_time_audit(
+                    make_machine,
+                    &options,
+                    [&read(first)?, &read(second)?],
+                    &limits,
+                    &program_symbols,
+                );
+            }
+            let mut machine = make_machine(&options)?;
+            machine.symbols = program_symbols;
+            if check_bounds {
+                machine.enable_bounds_checking(ShadowMemory::new(data_objects));
+            }
+            if profile_loops {
+                machine.enable_loop_profiling();
+            }
+            if explain {
+                let writer = ExplanationWriter::new(io::stderr(), Terminal::stderr());
+                machine.explain_to(Box::new(writer));
+            }
+            attach_console(&mut machine, console)?;
+            if let Some(path) = &trace {
+                let mut filter = TraceFilter {
+                    classes: trace_class
+                        .into_iter()
+                        .map(InstructionClass::from)
+                        .collect(),
+                    register: trace_when,
+                    ..TraceFilter::default()
+                };
+                for name in &trace_function {
+                    filter = filter
+                        .within_function(&machine.symbols, name)
+                        .with_context(|| format!("no function named '{}' to trace", name))?;
+                }
+                match trace_format {
+                    TraceFormat::Text => machine.enable_filtered_tracing(filter),
+                    TraceFormat::Binary | TraceFormat::Zstd => {
+                        let file = fs::File::create(path)
+                            .with_context(|| format!("creating {}", path.display()))?;
+                        let writer = trace_writer(Box::new(file), trace_format)?;
+                        machine.stream_trace(writer, filter);
+                    }
+                }
+            }
+            interrupt::install(machine.interrupt_handle());
+            let outcome = machine.run(&limits);
+            if let Some(path) = trace {
+                match trace_format {
+                    TraceFormat::Text => {
+                        let mut text = Vec::new();
+                        let trace = machine.take_trace();
+                        let disassembler =
+                            disassembler(&machine.symbols, xlen(machine.cpu.base_isa));
+                        for entry in &trace.entries {
+                            write_trace_line(&mut text, entry, &machine.symbols, &disassembler)?;
+                        }
+                        fs::write(&path, text)
+                    }
+                    TraceFormat::Binary | TraceFormat::Zstd => machine.finish_trace().map(drop),
+                }
+                .with_context(|| format!("writing trace to {}", path.display()))?;
+            }
+            if let Some(path) = core {
+                let exception = match outcome.exit_reason {
+                    ExitReason::Exception(exception) => Some(exception),
+                    _ => None,
+                };
+                write_core(&path, &machine, exception)?;
+            }
+
+            match report_json {
+                Some(path) => {
+                    let json = RunReport::new(&machine, &outcome, &limits)
+                        .with_line_map(&line_map)
+                        .to_json();
+                    if path.as_os_str() == "-" {
+                        println!("{}", json);
+                    } else {
+                        fs::write(&path, json)
+                            .with_context(|| format!("writing report to {}", path.display()))?;
+                    }
+                }
+                // Interactive runs continue in the debugger, right where they were stopped
+                None if outcome.exit_reason == ExitReason::Interrupted => {
+                    eprintln!(
+                        "interrupted after {} instructions, entering the debugger",
+                        outcome.instructions
+                    );
+                    let mut debugger = Debugger::new(machine);
+                    debugger.limits = limits;
+                    debugger.line_map = line_map;
+                    debug::run(debugger, None)?;
+                }
+                None => {
+                    eprintln!(
+                        "{:?} after {} instructions, exit code {:?}",
+                        outcome.exit_reason, outcome.instructions, outcome.exit_code
+                    );
+                    if let ExitReason::Exception(Exception::Breakpoint { pc }) = outcome.exit_reason
+                        && let Some(skipped) = skipped_lines.iter().find(|line| line.address == pc)
+                    {
+                        eprintln!(
+                            "{}: {} failed to assemble: {}",
+                            file.display(),
+                            skipped.location,
+                            skipped.message
+                        );
+                    }
+                    print_syscall_summary(machine.syscalls());
+                    let terminal = Terminal::stderr();
+                    for violation in machine.bounds_violations() {
+                        eprintln!(
+                            "{} {}",
+                            terminal.paint(Style::Warning, "bounds:"),
+                            violation
+                        );
+                    }
+                    for read in machine.uninitialized_reads() {
+                        let heading = terminal.paint(Style::Warning, "uninitialized:");
+                        eprintln!("{} {}", heading, read);
+                    }
+                    for violation in machine.abi_violations() {
+                        eprintln!("{} {}", terminal.paint(Style::Warning, "abi:"), violation);
+                    }
+                    print_hot_loops(&machine, &line_map);
+                }
+            }
+        }
+        Command::Debug {
+            file,
+            format,
+            march,
+            load_addr,
+            image,
+            bank,
+            bank_window,
+            symbols,
+            max_instructions,
+            session,
+            no_session,
+            clint,
+            plic,
+            uart,
+            tagged_memory,
+            syscalls,
+            fs_root,
+        } => {
+            let assembler_options = march.assembler_options(&cli.source);
+            let LoadedProgram {
+                image: program,
+                symbols: mut program_symbols,
+                mut line_map,
+                auxv,
+                ..
+            } = load_image(&file, format, &assembler_options, load_addr)?;
+            if let Some(path) = symbols {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                program_symbols =
+                    Symbols::parse(&text).with_context(|| format!("parsing {}", path.display()))?;
+            }
+            let entry = program.entry.or(program.start()).unwrap_or(load_addr);
+            let mut images: Vec<(Vec<u8>, u32)> = program
+                .segments()
+                .iter()
+                .map(|segment| (segment.data.clone(), segment.address))
+                .collect();
+            images.extend(load_extra_images(
+                &image,
+                &assembler_options,
+                &mut program_symbols,
+                &mut line_map,
+            )?);
+            let banks = load_banks(&bank, bank_window, &assembler_options)?;
+            let options = RunOptions {
+                base_isa: march.base_isa(),
+                clint,
+                plic,
+                uart,
+                syscalls: syscalls.into(),
+                tagged_memory,
+                ..RunOptions::default()
+            };
+            let mut machine = build_machine(&images, entry, &options)?;
+            attach_banks(&mut machine, banks.as_ref())?;
+            if options.syscalls == SyscallMode::Linux {
+                let process = LinuxProcess {
+                    args: vec![file.display().to_string()],
+                    env: Vec::new(),
+                    root: fs_root,
+                    auxv,
+                };
+                start_linux_process(&mut machine, &process)?;
+            }
+            machine.symbols = program_symbols;
+            let mut debugger = Debugger::new(machine);
+            debugger.limits.max_instructions = Some(max_instructions);
+            debugger.line_map = line_map;
+            interrupt::install(debugger.machine.interrupt_handle());
+            let session = if no_session {
+                None
+            } else {
+                Some(session.unwrap_or_else(|| debug::default_session_path(&file)))
+            };
+            debug::run(debugger, session)?;
+        }
+        Command::Grade {
+            file,
+            cases,
+            format,
+            march,
+            load_addr,
+            max_instructions,
+            stack_size,
+            jobs,
+            report_json,
+            junit,
+            syscalls,
+            profile,
+        } => {
+            let march = profile.map_or(march, Profile::march);
+            let stack_size = stack_size.or(profile.map(Profile::stack_size));
+            let test_cases = grade::load_cases(&cases)?;
+            if test_cases.is_empty() {
+                anyhow::bail!("no test cases (NAME.json files) in {}", cases.display());
+            }
+            let LoadedProgram {
+                image: program,
+                auxv,
+                ..
+            } = load_image(
+                &file,
+                format,
+                &march.assembler_options(&cli.source),
+                load_addr,
+            )?;
+            let entry = program.entry.or(program.start()).unwrap_or(load_addr);
+            let images: Vec<(Vec<u8>, u32)> = program
+                .segments()
+                .iter()
+                .map(|segment| (segment.data.clone(), segment.address))
+                .collect();
+            let options = RunOptions {
+                base_isa: march.base_isa(),
+                max_captured_output: Some(MAX_REPORTED_OUTPUT),
+                syscalls: syscalls.into(),
+                ..RunOptions::default()
+            };
+            let options = match profile {
+                Some(profile) => profile.apply(options),
+                None => options,
+            };
+            let process = LinuxProcess {
+                args: vec![file.display().to_string()],
+                env: Vec::new(),
+                root: None,
+                auxv,
+            };
+            let jobs =
+                jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+            let summary = grade::grade(
+                &file.display().to_string(),
+                &test_cases,
+                |options| {
+                    let mut machine = build_machine(&images, entry, options)?;
+                    reserve_memory(&mut machine, stack_size, None)?;
+                    if options.syscalls == SyscallMode::Linux {
+                        start_linux_process(&mut machine, &process)?;
+                    }
+                    Ok(machine)
+                },
+                &options,
+                max_instructions,
+                jobs,
+            );
+            eprint!("{}", summary.describe());
+            if let Some(path) = report_json {
+                let json = summary.to_json();
+                if path.as_os_str() == "-" {
+                    println!("{}", json);
+                } else {
+                    fs::write(&path, json)
+                        .with_context(|| format!("writing summary to {}", path.display()))?;
+                }
+            }
+            if let Some(path) = junit {
+                fs::write(&path, summary.to_junit(&test_cases))
+                    .with_context(|| format!("writing {}", path.display()))?;
+            }
+            if summary.failed > 0 {
+                anyhow::bail!(
+                    "{} of {} test cases failed",
+                    summary.failed,
+                    summary.cases.len()
+                );
+            }
+        }
+        Command::Trace { file, symbols } => {
+            let symbols = match symbols {
+                Some(path) => {
+                    let text = fs::read_to_string(&path)
+                        .with_context(|| format!("reading {}", path.display()))?;
+                    Symbols::parse(&text).with_context(|| format!("parsing {}", path.display()))?
+                }
+                None => Symbols::new(),
+            };
+            let input =
+                fs::File::open(&file).with_context(|| format!("opening {}", file.display()))?;
+            let reader =
+                TraceReader::new(input).with_context(|| format!("reading {}", file.display()))?;
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            // Traces don't record the target, their addresses are 32 bits either way
+            let disassembler = disassembler(&symbols, Xlen::Rv32);
+            for entry in reader {
+                let entry = entry.with_context(|| format!("reading {}", file.display()))?;
+                write_trace_line(&mut out, &entry, &symbols, &disassembler)?;
+            }
+            out.flush()?;
+        }
+        Command::Disasm {
+            file,
+            march,
+            format,
+            base,
+        } => {
+            let options = march.assembler_options(&cli.source);
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            if format.is_none() && is_source(&file) {
+                let program = assemble_file(&file, &options)?;
+                let disassembler =
+                    Disassembler::new(options.xlen).with_symbol_table(&program.symbols);
+                for extent in &program.sections {
+                    if extent.section != Section::Text {
+                        continue;
+                    }
+                    let start = (extent.address - program.origin) as usize;
+                    let code = &program.bytes[start..start + extent.size as usize];
+                    write!(out, "{}", disassembler.dump(code, extent.address))?;
+                }
+            } else {
+                let loaded = load_image(&file, format, &options, base)?;
+                let disassembler = disassembler(&loaded.symbols, options.xlen);
+                for segment in loaded.image.segments() {
+                    write!(out, "{}", disassembler.dump(&segment.data, segment.address))?;
+                }
+            }
+            out.flush()?;
+        }
+        Command::Symbols {
+            file,
+            march,
+            output,
+        } => {
+            let program = assemble_file(&file, &march.assembler_options(&cli.source))?;
+            let symbol_file = program.symbols.to_symbol_file();
+            match output {
+                Some(path) => fs::write(&path, symbol_file)
+                    .with_context(|| format!("writing {}", path.display()))?,
+                None => print!("{}", symbol_file),
+            }
+        }
+        Command::Check {
+            file,
+            march,
+            strict,
+        } => {
+            let mut options = march.assembler_options(&cli.source);
+            options.warnings.errors |= strict;
+            let program = assemble_file(&file, &options)?;
+            if program.findings.is_empty() {
+                eprintln!("{}: no problems found", file.display());
+            }
+        }
+        Command::Xref {
+            file,
+            march,
+            output,
+        } => {
+            let program = assemble_file(&file, &march.assembler_options(&cli.source))?;
+            let report = program.xrefs.report();
+            match output {
+                Some(path) => fs::write(&path, report)
+                    .with_context(|| format!("writing {}", path.display()))?,
+                None => print!("{}", report),
+            }
+        }
+        Command::Objcopy {
+            input,
+            output,
+            input_format,
+            output_format,
+            only_section,
+            base,
+            pad_to,
+            align,
+            gap_fill,
+            listing,
+        } => {
+            let options = cli.source.apply(AssemblerOptions::default());
+            let loaded = load_image(&input, input_format, &options, base)?;
+            if let Some(path) = listing {
+                let Some(program_listing) = &loaded.listing else {
+                    anyhow::bail!("{} is not an assembly source to list", input.display());
+                };
+                let source = fs::read_to_string(&input)
+                    .with_context(|| format!("reading {}", input.display()))?;
+                let text = program_listing.render(&source, &loaded.image.to_binary(0));
+                fs::write(&path, text).with_context(|| format!("writing {}", path.display()))?;
+            }
+            let (mut image, symbols, sections) = match only_section.is_empty() {
+                true => (loaded.image, loaded.symbols, loaded.sections),
+                false => extract_sections(&loaded, &only_section)
+                    .with_context(|| format!("extracting from {}", input.display()))?,
+            };
+            if let Some(end) = pad_to {
+                image.pad_to(end, gap_fill);
+            }
+            if let Some(alignment) = align {
+                image.align_end(alignment, gap_fill);
+            }
+            let elf = match output_format {
+                Some(format) => matches!(format, Format::Elf),
+                None => output
+                    .extension()
+                    .is_some_and(|extension| extension == "elf"),
+            };
+            let bytes = match (elf, output_format) {
+                (true, _) => write_executable(&image, &symbols, &sections),
+                (false, Some(format)) => image.write(format.try_into()?, gap_fill),
+                (false, None) => image.write(ImageFormat::from_path(&output), gap_fill),
+            };
+            fs::write(&output, bytes).with_context(|| format!("writing {}", output.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The sections of `loaded` called `names`, with the symbols in them and the absolute ones
+fn extract_sections(
+    loaded: &LoadedProgram,
+    names: &[String],
+) -> anyhow::Result<(Image, Symbols, Vec<ElfSection>)> {
+    let mut image = Image::new();
+    image.entry = loaded.image.entry;
+    let mut sections = Vec::new();
+    for name in names {
+        let Some(section) = loaded.sections.iter().find(|section| &section.name == name) else {
+            let names: Vec<&str> = loaded
+                .sections
+                .iter()
+                .map(|section| section.name.as_str())
+                .collect();
+            match names.is_empty() {
+                true => anyhow::bail!("no section '{}', the input has no sections", name),
+                false => anyhow::bail!("no section '{}' in {}", name, names.join(", ")),
+            }
+        };
+        let end = section.address as u64 + section.size as u64;
+        for segment in loaded.image.extract(section.address, end).segments() {
+            image.add_segment(segment.address, segment.data.clone())?;
+        }
+        sections.push(section.clone());
+    }
+    sections.sort_by_key(|section| section.address);
+    let mut symbols = Symbols::new();
+    for symbol in loaded.symbols.iter() {
+        let in_section = sections.iter().any(|section| {
+            (section.address as u64..section.address as u64 + section.size as u64)
+                .contains(&(symbol.address as u64))
+        });
+        if in_section || symbol.kind.eq_ignore_ascii_case(&'a') {
+            symbols.insert_with_kind(symbol.name, symbol.address, symbol.kind);
+        }
+    }
+    Ok((image, symbols, sections))
+}
+
+/// Writes an ELF core file of the machine's current state
+pub(crate) fn write_core(
+    path: &Path,
+    machine: &Machine,
+    exception: Option<Exception>,
+) -> anyhow::Result<()> {
+    let mut file = io::BufWriter::new(
+        fs::File::create(path).with_context(|| format!("creating {}", path.display()))?,
+    );
+    write_core_file(&mut file, machine, exception)
+        .with_context(|| format!("writing core file to {}", path.display()))
+}
+
+/// One line per system call number the program used
+fn print_syscall_summary(syscalls: &SyscallLog) {
+    for stats in syscalls.iter() {
+        let arguments: Vec<String> = stats
+            .last
+            .arguments
+            .iter()
+            .map(|argument| format!("{:#x}", argument))
+            .collect();
+        eprintln!(
+            "ecall {}{}: {} call{}, last with a0-a5 = {}{}",
+            stats.number,
+            stats
+                .name()
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default(),
+            stats.count,
+            if stats.count == 1 { "" } else { "s" },
+            arguments.join(", "),
+            match stats.last.result {
+                Some(result) => format!(", returned {:#x}", result),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+/// One line per loop, hottest first
+fn print_hot_loops(machine: &Machine, line_map: &LineMap) {
+    for stats in machine.hot_loops().iter().take(MAX_REPORTED_LOOPS) {
+        let location = report::source_range(line_map, stats.header, stats.latch)
+            .or_else(|| machine.symbols.symbolize(stats.header))
+            .unwrap_or_else(|| format!("{:#010x}", stats.header));
+        eprintln!(
+            "loop {} ({:#010x}-{:#010x}): {} iteration{} in {} entr{} \
+             (avg trip count {:.1}, max {}), {:.1} instructions per iteration, {} total",
+            location,
+            stats.header,
+            stats.latch,
+            stats.iterations(),
+            if stats.iterations() == 1 { "" } else { "s" },
+            stats.entries,
+            if stats.entries == 1 { "y" } else { "ies" },
+            stats.average_trip_count(),
+            stats.max_trip_count,
+            stats.instructions_per_iteration(),
+            stats.instructions
+        );
+    }
+}
+
+/// Creates a machine with every image loaded, the first image is the program
+fn build_machine(
+    images: &[(Vec<u8>, u32)],
+    entry: u32,
+    options: &RunOptions,
+) -> anyhow::Result<Machine> {
+    let mut machine = match images {
+        // Loaded at 0 the program can use the shared read-only image
+        [(program, 0), ..] => Machine::with_options(program.clone(), MEMORY_SIZE as usize, options),
+        _ => Machine::with_options(Vec::new(), MEMORY_SIZE as usize, options),
+    };
+    for (index, (bytes, address)) in images.iter().enumerate() {
+        if index == 0 && *address == 0 {
+            continue;
+        }
+        machine.load_binary(bytes, *address, None)?;
+    }
+    machine.set_entry(entry);
+    machine.layout().validate()?;
+    Ok(machine)
+}
+
+/// Loads the `--image` files, each at its address, adding their symbols and source lines to
+/// the program's. Returns the images' segments.
+fn load_extra_images(
+    images: &[(PathBuf, u32)],
+    options: &AssemblerOptions,
+    symbols: &mut Symbols,
+    line_map: &mut LineMap,
+) -> anyhow::Result<Vec<(Vec<u8>, u32)>> {
+    let mut segments = Vec::new();
+    for (path, address) in images {
+        let loaded = load_image(path, None, options, *address)?;
+        for symbol in loaded.symbols.iter() {
+            symbols.insert_with_kind(symbol.name, symbol.address, symbol.kind);
+        }
+        line_map.extend(loaded.line_map);
+        for segment in loaded.image.segments() {
+            segments.push((segment.data.clone(), segment.address));
+        }
+    }
+    Ok(segments)
+}
+
+/// Banked memory at `window` holding the `--bank` files, `None` without any. Every bank is
+/// as large as the largest file rounded up to 4 KiB.
+fn load_banks(
+    files: &[PathBuf],
+    window: u32,
+    options: &AssemblerOptions,
+) -> anyhow::Result<Option<BankedMemory>> {
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let mut banks = Vec::new();
+    for path in files {
+        let image = load_image(path, None, options, window)?.image;
+        let mut bank = Vec::new();
+        for segment in image.segments() {
+            let start = segment.address.checked_sub(window).with_context(|| {
+                format!(
+                    "{} has data at {:#010x}, below the bank window",
+                    path.display(),
+                    segment.address
+                )
+            })? as usize;
+            let end = start + segment.data.len();
+            if bank.len() < end {
+                bank.resize(end, 0);
+            }
+            bank[start..end].copy_from_slice(&segment.data);
+        }
+        banks.push(bank);
+    }
+    let largest = banks.iter().map(Vec::len).max().unwrap_or(0);
+    let bank_size = u32::try_from(largest.max(1).next_multiple_of(BANK_ALIGNMENT))
+        .context("banks don't fit in the address space")?;
+    Ok(Some(BankedMemory::new(window, bank_size, banks)?))
+}
+
+/// Maps `banks` into the machine, and checks they don't overlap anything
+fn attach_banks(machine: &mut Machine, banks: Option<&BankedMemory>) -> anyhow::Result<()> {
+    if let Some(banks) = banks {
+        machine.cpu.bus.attach(banks.clone());
+        machine.layout().validate()?;
+    }
+    Ok(())
+}
+
+/// What a Linux program starts with
+struct LinuxProcess {
+    /// The program's name and arguments
+    args: Vec<String>,
+    env: Vec<String>,
+    /// Directory the program can open files in
+    root: Option<PathBuf>,
+    /// Where an ELF program's headers and entry point are, for its startup code
+    auxv: Vec<(u64, u64)>,
+}
+
+/// Puts the arguments and environment of `process` on the stack and lets it open files
+/// below its root
+fn start_linux_process(machine: &mut Machine, process: &LinuxProcess) -> anyhow::Result<()> {
+    if let Some(root) = &process.root {
+        machine.set_syscall_handler(LinuxSyscalls::new().with_root(root));
+    }
+    start_process(machine, &process.args, &process.env, &process.auxv)?;
+    Ok(())
+}
+
+/// Adds a stack at the top of memory and a heap after the images, and checks they fit
+fn reserve_memory(
+    machine: &mut Machine,
+    stack_size: Option<u32>,
+    heap_size: Option<u32>,
+) -> anyhow::Result<()> {
+    let mut layout = machine.layout();
+    if let Some(size) = heap_size {
+        // 16 byte aligned, like the stack
+        let start = u32::try_from(layout.ram_end().next_multiple_of(16))
+            .context("no room for a heap after the loaded images")?;
+        layout.add(Region::new("heap", RegionKind::Heap, start, size));
+    }
+    if let Some(size) = stack_size {
+        let start = MEMORY_SIZE
+            .checked_sub(size)
+            .with_context(|| format!("{} byte stack is larger than memory", size))?;
+        layout.add(Region::new("stack", RegionKind::Stack, start, size));
+        machine.set_stack_pointer(MEMORY_SIZE);
+        machine.set_stack_limit(start);
+    }
+    layout.validate()?;
+    Ok(())
+}
+
+fn run_determinism_audit(
+    make_machine: impl Fn(&RunOptions) -> anyhow::Result<Machine>,
+    options: &RunOptions,
+    limits: &RunLimits,
+) -> anyhow::Result<()> {
+    // Surface load errors here, the audit itself needs an infallible constructor
+    make_machine(options)?;
+    let report = audit_determinism(
+        |options| make_machine(options).expect("machine built above"),
+        options,
+        limits,
+    );
+    if report.is_deterministic() {
+        eprintln!(
+            "deterministic: both runs executed {} instructions identically",
+            report.outcomes[0].instructions
+        );
+        return Ok(());
+    }
+    if let Some(divergence) = &report.divergence {
+        eprintln!(
+            "traces diverge at step {}: {:?} vs {:?}",
+            divergence.step, divergence.first, divergence.second
+        );
+    }
+    if report.output_differs {
+        eprintln!("console output differs between runs");
+    }
+    anyhow::bail!("nondeterministic execution detected")
+}
+
+fn run_constant_time_audit(
+    make_machine: impl Fn(&RunOptions) -> anyhow::Result<Machine>,
+    options: &RunOptions,
+    inputs: [&[u8]; 2],
+    limits: &RunLimits,
+    symbols: &Symbols,
+) -> anyhow::Result<()> {
+    make_machine(options)?;
+    let report = audit_constant_time(
+        |options| make_machine(options).expect("machine built above"),
+        options,
+        inputs,
+        limits,
+    );
+    let describe = |pc: u32| match symbols.symbolize(pc) {
+        Some(symbol) => format!("{:#010x} ({})", pc, symbol),
+        None => format!("{:#010x}", pc),
+    };
+    eprintln!(
+        "instructions: {} vs {}, cycles: {} vs {}",
+        report.instructions[0], report.instructions[1], report.cycles[0], report.cycles[1]
+    );
+    for leak in &report.address_leaks {
+        eprintln!(
+            "input-dependent address at {}: {:#010x} vs {:#010x} (step {})",
+            describe(leak.pc),
+            leak.addresses[0],
+            leak.addresses[1],
+            leak.step
+        );
+    }
+    if let Some(divergence) = &report.divergence {
+        let next = |pc: Option<u32>| pc.map_or("the end".to_string(), describe);
+        eprintln!(
+            "input-dependent branch at {}: continues at {} vs {} (step {})",
+            divergence.pc.map_or("the start".to_string(), describe),
+            next(divergence.next[0]),
+            next(divergence.next[1]),
+            divergence.step
+        );
+    }
+    if report.is_constant_time() {
+        eprintln!("constant time: both inputs took the same path through the same addresses");
+        return Ok(());
+    }
+    anyhow::bail!("execution depends on the input")
+}
+
+/// Parses a decimal or 0x prefixed hexadecimal address
+fn parse_address(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address '{}'", s))
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_address(s)?;
+    u8::try_from(value).map_err(|_| format!("'{}' does not fit in a byte", s))
+}
+
+/// Parses FILE@ADDR
+fn parse_image(s: &str) -> Result<(PathBuf, u32), String> {
+    let (path, address) = s
+        .rsplit_once('@')
+        .ok_or_else(|| format!("expected FILE@ADDR, found '{}'", s))?;
+    Ok((PathBuf::from(path), parse_address(address)?))
+}
+
+/// Parses NAME or NAME=VALUE, the value of a bare name is 1
+fn parse_define(s: &str) -> Result<(String, u32), String> {
+    let (name, value) = match s.split_once('=') {
+        Some((name, value)) => (name, parse_address(value)?),
+        None => (s, 1),
+    };
+    let is_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if !is_name {
+        return Err(format!("invalid symbol name '{}'", name));
+    }
+    Ok((name.to_string(), value))
+}
+
+/// Checks a -W option, see [`Warnings::apply`]
+fn parse_warning(s: &str) -> Result<String, String> {
+    Warnings::default().apply(s)?;
+    Ok(s.to_string())
+}
+
+/// Parses REG=VALUE
+fn parse_register_value(s: &str) -> Result<(u8, u32), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected REG=VALUE, found '{}'", s))?;
+    let register = register_number(name).ok_or_else(|| format!("unknown register '{}'", name))?;
+    Ok((register, parse_address(value)?))
+}
+
+/// One line per traced instruction: pc, symbol, encoding and register write
+fn write_trace_line(
+    out: &mut impl Write,
+    entry: &TraceEntry,
+    symbols: &Symbols,
+    disassembler: &Disassembler,
+) -> io::Result<()> {
+    let location = symbols.symbolize(entry.pc).unwrap_or_default();
+    let instruction = disassembler.instruction(entry.instruction, entry.pc);
+    write!(
+        out,
+        "{:08x} {:<24} {:08x}  ",
+        entry.pc, location, entry.instruction
+    )?;
+    match entry.write {
+        Some((register, value)) => write!(
+            out,
+            "{:<32}  x{}={:#010x}",
+            instruction.text, register, value
+        )?,
+        None => write!(out, "{}", instruction.text)?,
+    }
+    writeln!(out)
+}
+
+/// Width of the integer registers of `base_isa`, for the disassembler
+fn xlen(base_isa: BaseIsa) -> Xlen {
+    match base_isa {
+        BaseIsa::Rv64I => Xlen::Rv64,
+        BaseIsa::Rv32I | BaseIsa::Rv32E => Xlen::Rv32,
+    }
+}
+
+/// Disassembles instructions of an `xlen` bit target, naming branch targets after the
+/// symbols at them
+fn disassembler(symbols: &Symbols, xlen: Xlen) -> Disassembler<'_> {
+    Disassembler::new(xlen).with_symbols(|address| {
+        symbols
+            .lookup(address)
+            .filter(|(_, offset)| *offset == 0)
+            .map(|(name, _)| name.to_string())
+    })
+}
+
+fn trace_writer(
+    output: Box<dyn Write + Send>,
+    format: TraceFormat,
+) -> anyhow::Result<TraceWriter<Box<dyn Write + Send>>> {
+    match format {
+        TraceFormat::Zstd => {
+            #[cfg(feature = "zstd")]
+            {
+                Ok(TraceWriter::compressed(output, 0)?)
+            }
+            #[cfg(not(feature = "zstd"))]
+            {
+                anyhow::bail!("zstd traces need rv built with the zstd feature")
+            }
+        }
+        TraceFormat::Text | TraceFormat::Binary => Ok(TraceWriter::new(output)?),
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum March {
+    Rv32i,
+    Rv32e,
+    Rv64i,
+}
+
+impl March {
+    fn assembler_options(self, source: &SourceArgs) -> AssemblerOptions {
+        source.apply(match self {
+            March::Rv32i => AssemblerOptions::default(),
+            March::Rv32e => AssemblerOptions::rv32e(),
+            March::Rv64i => AssemblerOptions::rv64i(),
+        })
+    }
+
+    fn base_isa(self) -> BaseIsa {
+        match self {
+            March::Rv32i => BaseIsa::Rv32I,
+            March::Rv32e => BaseIsa::Rv32E,
+            March::Rv64i => BaseIsa::Rv64I,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Profile {
+    /// RV32I without extensions for courses: bare system calls only, and uninitialized
+    /// reads, calling convention violations and stack overflows reported or caught
+    StrictRv32i,
+}
+
+impl Profile {
+    fn march(self) -> March {
+        match self {
+            Profile::StrictRv32i => March::Rv32i,
+        }
+    }
+
+    /// Stack reserved when --stack-size isn't given
+    fn stack_size(self) -> u32 {
+        match self {
+            Profile::StrictRv32i => 64 * 1024,
+        }
+    }
+
+    /// `options` with the ISA, system calls and checks of the profile
+    fn apply(self, options: RunOptions) -> RunOptions {
+        let profile = match self {
+            Profile::StrictRv32i => RunOptions::strict_rv32i(),
+        };
+        RunOptions {
+            base_isa: profile.base_isa,
+            extensions: profile.extensions,
+            syscalls: profile.syscalls,
+            strict_syscalls: profile.strict_syscalls,
+            check_uninitialized: profile.check_uninitialized,
+            check_abi: profile.check_abi,
+            stack_guard: profile.stack_guard,
+            ..options
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Format {
+    Bin,
+    Ihex,
+    Srec,
+    /// Verilog `$readmemh` text of 32 bit words
+    Memh,
+    /// ELF executables
+    Elf,
+}
+
+impl TryFrom<Format> for ImageFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(format: Format) -> anyhow::Result<Self> {
+        match format {
+            Format::Bin => Ok(ImageFormat::Binary),
+            Format::Ihex => Ok(ImageFormat::IntelHex),
+            Format::Srec => Ok(ImageFormat::Srec),
+            Format::Memh => Ok(ImageFormat::Memh),
+            Format::Elf => anyhow::bail!("ELF files aren't plain images"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum TraceFormat {
+    Text,
+    Binary,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum TraceClass {
+    Load,
+    Store,
+    Branch,
+    Jump,
+    Alu,
+    System,
+}
+
+impl From<TraceClass> for InstructionClass {
+    fn from(class: TraceClass) -> Self {
+        match class {
+            TraceClass::Load => InstructionClass::Load,
+            TraceClass::Store => InstructionClass::Store,
+            TraceClass::Branch => InstructionClass::Branch,
+            TraceClass::Jump => InstructionClass::Jump,
+            TraceClass::Alu => InstructionClass::Alu,
+            TraceClass::System => InstructionClass::System,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Syscalls {
+    None,
+    Bare,
+    Linux,
+}
+
+impl From<Syscalls> for SyscallMode {
+    fn from(syscalls: Syscalls) -> Self {
+        match syscalls {
+            Syscalls::None => SyscallMode::None,
+            Syscalls::Bare => SyscallMode::Bare,
+            Syscalls::Linux => SyscallMode::Linux,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ConsoleBackend {
+    Stdio,
+    Pty,
+    Tcp(String),
+}
+
+impl FromStr for ConsoleBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdio" => Ok(Self::Stdio),
+            "pty" => Ok(Self::Pty),
+            _ => match s.strip_prefix("tcp:") {
+                Some(address) => Ok(Self::Tcp(address.to_string())),
+                None => Err(format!(
+                    "unknown console '{}', expected stdio, pty or tcp:ADDRESS",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+fn attach_console(machine: &mut Machine, backend: ConsoleBackend) -> anyhow::Result<()> {
+    match backend {
+        ConsoleBackend::Stdio => {}
+        ConsoleBackend::Pty => {
+            #[cfg(unix)]
+            {
+                let path = machine.console.attach_pty().context("opening pty")?;
+                eprintln!("console attached to {}", path.display());
+            }
+            #[cfg(not(unix))]
+            anyhow::bail!("pty consoles are only supported on unix hosts");
+        }
+        ConsoleBackend::Tcp(address) => {
+            let listener =
+                TcpListener::bind(&address).with_context(|| format!("binding {}", address))?;
+            eprintln!(
+                "waiting for a console connection on {}",
+                listener.local_addr()?
+            );
+            let peer = machine.console.attach_tcp(&listener)?;
+            eprintln!("console connected from {}", peer);
+        }
+    }
+    Ok(())
+}
+
+/// A program image and whatever debug information its file provided
+struct LoadedProgram {
+    image: Image,
+    symbols: Symbols,
+    line_map: LineMap,
+    /// Labelled data of assembled sources, for bounds checking
+    data_objects: Vec<DataObject>,
+    /// Lines of a permissively assembled source replaced by an EBREAK, at their load address
+    skipped_lines: Vec<SkippedLine>,
+    /// Auxiliary vector entries describing an ELF program to a Linux process
+    auxv: Vec<(u64, u64)>,
+    /// Listing of an assembled source, addresses are relative to its load address
+    listing: Option<Listing>,
+    /// Sections of an assembled source or ELF file, at their load addresses
+    sections: Vec<ElfSection>,
+}
+
+/// Assembles a source file, printing errors, and analysis findings and skipped lines as
+/// warnings, with the lines they are about
+fn assemble_file(file: &Path, options: &AssemblerOptions) -> anyhow::Result<AssembledProgram> {
+    let result = riscv_asm::assemble_program(file, options);
+    let name = file.display().to_string();
+    let text = || fs::read_to_string(file).unwrap_or_default();
+    let color = Terminal::stderr().color;
+    let program = match result {
+        Ok(program) => program,
+        Err(error) => {
+            let Some(assembler_error) = error.downcast_ref::<AssemblerError>() else {
+                return Err(error);
+            };
+            let text = text();
+            let sources = SourceFiles::new(&name, &text);
+            let diagnostics = Diagnostic::from_error(assembler_error, &sources);
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(&sources, color));
+            }
+            let errors = match diagnostics.len() {
+                1 => "error".to_string(),
+                count => format!("{} errors", count),
+            };
+            anyhow::bail!("could not assemble {} due to the {} above", name, errors);
+        }
+    };
+    if program.skipped_lines.is_empty() && program.findings.is_empty() {
+        return Ok(program);
+    }
+    let text = text();
+    let sources = SourceFiles::new(&name, &text);
+    let warnings =
+        program
+            .skipped_lines
+            .iter()
+            .map(|skipped| {
+                let message = format!("{}, assembled as ebreak", skipped.message);
+                Diagnostic::warning(message, skipped.location.clone())
+            })
+            .chain(program.findings.iter().map(|finding| {
+                Diagnostic::warning(finding.message.clone(), finding.location.clone())
+            }));
+    for warning in warnings {
+        eprintln!("{}", warning.render(&sources, color));
+    }
+    Ok(program)
+}
+
+/// Assembles source files and parses anything else as an image in `format`.
+///
+/// Without a format, the extension and then the contents decide between ELF, Intel HEX,
+/// S-records and raw binaries. Sources and raw binaries are placed at `load_addr`, ELF files
+/// at their own addresses with their symbol table.
+fn load_image(
+    file: &Path,
+    format: Option<Format>,
+    options: &AssemblerOptions,
+    load_addr: u32,
+) -> anyhow::Result<LoadedProgram> {
+    if format.is_none() && is_source(file) {
+        let program = assemble_file(file, options)?;
+        // The assembler places code at 0, debug information moves with the load address
+        let Program {
+            image,
+            symbols,
+            line_map,
+        } = Program::from_assembled(&program, &file.to_string_lossy(), load_addr)?;
+        let data_objects = program
+            .data_objects
+            .iter()
+            .map(|object| DataObject {
+                name: object.name.clone(),
+                address: object.address.wrapping_add(load_addr),
+                size: object.size,
+            })
+            .collect();
+        let skipped_lines = program
+            .skipped_lines
+            .iter()
+            .map(|skipped| SkippedLine {
+                address: skipped.address.wrapping_add(load_addr),
+                ..skipped.clone()
+            })
+            .collect();
+        let sections = program
+            .sections
+            .iter()
+            .map(|extent| ElfSection {
+                name: extent.section.name().to_string(),
+                address: extent.address.wrapping_add(load_addr),
+                size: extent.size,
+            })
+            .collect();
+        return Ok(LoadedProgram {
+            image,
+            symbols,
+            line_map,
+            data_objects,
+            skipped_lines,
+            auxv: Vec::new(),
+            listing: Some(program.listing),
+            sections,
+        });
+    }
+
+    let bytes = fs::read(file).with_context(|| format!("reading {}", file.display()))?;
+    if matches!(format, Some(Format::Elf)) || format.is_none() && is_elf(&bytes) {
+        let elf = Elf::parse(&bytes).with_context(|| format!("parsing {}", file.display()))?;
+        return Ok(LoadedProgram {
+            auxv: elf.auxv(),
+            image: elf.image,
+            symbols: elf.symbols,
+            line_map: LineMap::new(),
+            data_objects: Vec::new(),
+            skipped_lines: Vec::new(),
+            listing: None,
+            sections: elf.sections,
+        });
+    }
+    let format = match format {
+        Some(format) => format.try_into()?,
+        None => match ImageFormat::from_path(file) {
+            ImageFormat::Binary => ImageFormat::detect(&bytes),
+            format => format,
+        },
+    };
+    let image = Image::parse(&bytes, format, load_addr)
+        .with_context(|| format!("parsing {}", file.display()))?;
+    Ok(LoadedProgram {
+        image,
+        symbols: Symbols::new(),
+        line_map: LineMap::new(),
+        data_objects: Vec::new(),
+        skipped_lines: Vec::new(),
+        auxv: Vec::new(),
+        listing: None,
+        sections: Vec::new(),
+    })
+}
+
+fn is_source(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|extension| extension == "s" || extension == "S" || extension == "asm")
+}
+
+/// Logs go to stderr, filtered per subsystem through `RV_LOG` (warnings only by default)
+fn init_logging() {
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("warn"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        // Clashing option names across subcommands and global arguments only panic when
+        // the subcommand runs
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_extract_sections() {
+        let path = std::env::temp_dir().join(format!("rv-objcopy-{}.s", std::process::id()));
+        fs::write(
+            &path,
+            ".globl start\nstart: nop\n.data\nvalue: .word 7\n.equ N, 3",
+        )
+        .unwrap();
+        let loaded = load_image(&path, None, &AssemblerOptions::default(), 0x100).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (image, symbols, sections) = extract_sections(&loaded, &[".data".to_string()]).unwrap();
+        assert_eq!(image.start(), Some(0x104));
+        assert_eq!(image.to_binary(0), [7, 0, 0, 0]);
+        assert_eq!(sections.len(), 1);
+        assert_eq!(symbols.address("value"), Some(0x104));
+        assert_eq!(symbols.address("N"), Some(3));
+        assert_eq!(symbols.address("start"), None);
+        // ELF output keeps them
+        let elf = Elf::parse(&write_executable(&image, &symbols, &sections)).unwrap();
+        assert_eq!(elf.sections, sections);
+        assert_eq!(
+            extract_sections(&loaded, &[".bss".to_string()])
+                .unwrap_err()
+                .to_string(),
+            "no section '.bss' in .text, .data"
+        );
+    }
 }
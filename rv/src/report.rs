@@ -0,0 +1,464 @@
+use riscv_emu::{
+    abi::AbiViolation,
+    cpu::Exception,
+    digest::to_hex,
+    line_map::LineMap,
+    loops::LoopStats,
+    machine::{ExitReason, Machine, RunLimits, RunOutcome},
+    shadow::BoundsViolation,
+    symbols::Symbols,
+    syscalls::{ARGUMENT_COUNT, SyscallStats},
+    uninit::UninitializedRead,
+};
+use serde::Serialize;
+
+/// Loops listed in the report and on the terminal
+pub const MAX_REPORTED_LOOPS: usize = 10;
+
+/// Structured summary of a run, written by `--report-json` for autograders and CI
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub exit_reason: &'static str,
+    pub exit_code: Option<u32>,
+    pub instructions: u64,
+    pub cycles: u64,
+    /// SHA-256 of the loaded program, see [`Machine::image_digest`], to check it was built
+    /// from the submitted source
+    pub image_sha256: String,
+    pub console_output: String,
+    pub registers: RegisterReport,
+    pub traps: Vec<TrapReport>,
+    /// Environment calls made by the program, one entry per system call number
+    pub syscalls: Vec<SyscallReport>,
+    /// Accesses past the end of data objects, with `--check-bounds`
+    pub bounds_violations: Vec<BoundsReport>,
+    /// Loads of memory nothing was written to, with `--profile strict-rv32i`
+    pub uninitialized_reads: Vec<UninitializedReport>,
+    /// Functions returning with callee-saved registers changed, with `--profile strict-rv32i`
+    pub abi_violations: Vec<AbiReport>,
+    /// The hottest loops, with `--profile-loops`
+    pub loops: Vec<LoopReport>,
+    pub limits: LimitReport,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisterReport {
+    pub pc: u32,
+    pub x: [u64; 32],
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrapReport {
+    pub cause: String,
+    pub pc: u32,
+    /// `pc` relative to the nearest symbol, when symbols are loaded
+    pub symbol: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyscallReport {
+    /// System call number, from a7
+    pub number: u32,
+    /// Linux name of the number, when it's a well-known one
+    pub name: Option<&'static str>,
+    pub count: u64,
+    /// a0-a5 of the most recent call
+    pub last_arguments: [u64; ARGUMENT_COUNT],
+    /// a0 returned by the most recent call, `None` if it ended the run
+    pub last_result: Option<u64>,
+    pub last_pc: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BoundsReport {
+    pub pc: u32,
+    /// `pc` relative to the nearest symbol, when symbols are loaded
+    pub symbol: Option<String>,
+    pub address: u32,
+    pub len: u32,
+    pub write: bool,
+    /// Name of the overflowed object
+    pub object: String,
+    /// Bytes between the end of the object and the access
+    pub distance: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UninitializedReport {
+    pub pc: u32,
+    /// `pc` relative to the nearest symbol, when symbols are loaded
+    pub symbol: Option<String>,
+    pub address: u32,
+    pub len: u32,
+    /// The first byte of the access nothing was written to
+    pub uninitialized: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiReport {
+    /// The returning instruction
+    pub pc: u32,
+    /// The function returning, by name when symbols are loaded
+    pub function: u32,
+    pub symbol: Option<String>,
+    pub register: &'static str,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoopReport {
+    pub header: u32,
+    pub latch: u32,
+    /// `header` relative to the nearest symbol, when symbols are loaded
+    pub symbol: Option<String>,
+    /// Source lines of the loop, e.g. `main.s:4-9`, for assembled sources
+    pub source: Option<String>,
+    pub entries: u64,
+    pub iterations: u64,
+    pub max_trip_count: u64,
+    pub average_trip_count: f64,
+    pub instructions_per_iteration: f64,
+    /// Instructions executed in the loop after the first back-edge of each entry
+    pub instructions: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LimitReport {
+    pub max_instructions: Option<u64>,
+    /// True when the run was cut short by a limit
+    pub exceeded: bool,
+}
+
+impl RunReport {
+    pub fn new(machine: &Machine, outcome: &RunOutcome, limits: &RunLimits) -> Self {
+        Self {
+            exit_reason: exit_reason_name(outcome.exit_reason),
+            exit_code: outcome.exit_code,
+            instructions: outcome.instructions,
+            cycles: machine.cycles(),
+            image_sha256: to_hex(&machine.image_digest()),
+            console_output: machine.console.output_lossy(),
+            registers: RegisterReport {
+                pc: machine.cpu.pc,
+                x: machine.cpu.regs,
+            },
+            traps: match outcome.exit_reason {
+                ExitReason::Exception(exception) => {
+                    vec![TrapReport::new(exception, &machine.symbols)]
+                }
+                _ => Vec::new(),
+            },
+            syscalls: machine.syscalls().iter().map(SyscallReport::new).collect(),
+            bounds_violations: machine
+                .bounds_violations()
+                .iter()
+                .map(|violation| BoundsReport::new(violation, &machine.symbols))
+                .collect(),
+            uninitialized_reads: machine
+                .uninitialized_reads()
+                .iter()
+                .map(|read| UninitializedReport::new(read, &machine.symbols))
+                .collect(),
+            abi_violations: machine
+                .abi_violations()
+                .iter()
+                .map(|violation| AbiReport::new(violation, &machine.symbols))
+                .collect(),
+            loops: machine
+                .hot_loops()
+                .iter()
+                .take(MAX_REPORTED_LOOPS)
+                .map(|stats| LoopReport::new(stats, &machine.symbols))
+                .collect(),
+            limits: LimitReport {
+                max_instructions: limits.max_instructions,
+                exceeded: outcome.exit_reason == ExitReason::InstructionLimit,
+            },
+        }
+    }
+
+    /// Fills in the source lines of the loops
+    pub fn with_line_map(mut self, line_map: &LineMap) -> Self {
+        for report in &mut self.loops {
+            report.source = source_range(line_map, report.header, report.latch);
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        // SAFETY: the report only contains plain data, so serialization can't fail
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl LoopReport {
+    pub fn new(stats: &LoopStats, symbols: &Symbols) -> Self {
+        Self {
+            header: stats.header,
+            latch: stats.latch,
+            symbol: symbols.symbolize(stats.header),
+            source: None,
+            entries: stats.entries,
+            iterations: stats.iterations(),
+            max_trip_count: stats.max_trip_count,
+            average_trip_count: stats.average_trip_count(),
+            instructions_per_iteration: stats.instructions_per_iteration(),
+            instructions: stats.instructions,
+        }
+    }
+}
+
+/// `file:first-last` lines of the instructions from `start` to `end`, `None` unless both
+/// were assembled from the same file
+pub fn source_range(line_map: &LineMap, start: u32, end: u32) -> Option<String> {
+    let first = line_map.line_at(start)?;
+    let last = line_map.line_at(end)?;
+    if first.file != last.file {
+        return None;
+    }
+    Some(if first.line == last.line {
+        format!("{}:{}", first.file, first.line)
+    } else {
+        format!("{}:{}-{}", first.file, first.line, last.line)
+    })
+}
+
+impl BoundsReport {
+    pub fn new(violation: &BoundsViolation, symbols: &Symbols) -> Self {
+        Self {
+            pc: violation.pc,
+            symbol: symbols.symbolize(violation.pc),
+            address: violation.access.address,
+            len: violation.access.len,
+            write: violation.access.write,
+            object: violation.object.name.clone(),
+            distance: violation.distance,
+        }
+    }
+}
+
+impl UninitializedReport {
+    pub fn new(read: &UninitializedRead, symbols: &Symbols) -> Self {
+        Self {
+            pc: read.pc,
+            symbol: symbols.symbolize(read.pc),
+            address: read.access.address,
+            len: read.access.len,
+            uninitialized: read.address,
+        }
+    }
+}
+
+impl AbiReport {
+    pub fn new(violation: &AbiViolation, symbols: &Symbols) -> Self {
+        Self {
+            pc: violation.pc,
+            function: violation.function,
+            symbol: symbols.symbolize(violation.function),
+            register: violation.name,
+            expected: violation.expected,
+            actual: violation.actual,
+        }
+    }
+}
+
+impl TrapReport {
+    pub fn new(exception: Exception, symbols: &Symbols) -> Self {
+        match exception {
+            Exception::InstructionAddressMisaligned { pc, target } => Self {
+                cause: format!("misaligned instruction address {:#010x}", target),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::IllegalInstruction { pc, instruction } => Self {
+                cause: format!("illegal instruction {:#010x}", instruction),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::InstructionAccessFault { pc } => Self {
+                cause: "instruction access fault".to_string(),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::LoadAccessFault { pc, address } => Self {
+                cause: format!("load access fault at {:#010x}", address),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::StoreAccessFault { pc, address } => Self {
+                cause: format!("store access fault at {:#010x}", address),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::EnvironmentCall { pc } => Self {
+                cause: "environment call".to_string(),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+            Exception::Breakpoint { pc } => Self {
+                cause: "breakpoint".to_string(),
+                pc,
+                symbol: symbols.symbolize(pc),
+            },
+        }
+    }
+}
+
+impl SyscallReport {
+    pub fn new(stats: &SyscallStats) -> Self {
+        Self {
+            number: stats.number,
+            name: stats.name(),
+            count: stats.count,
+            last_arguments: stats.last.arguments,
+            last_result: stats.last.result,
+            last_pc: stats.last.pc,
+        }
+    }
+}
+
+pub(crate) fn exit_reason_name(reason: ExitReason) -> &'static str {
+    match reason {
+        ExitReason::EndOfProgram => "end_of_program",
+        ExitReason::EnvironmentCall => "ecall",
+        ExitReason::InstructionLimit => "instruction_limit",
+        ExitReason::Exception(_) => "exception",
+        ExitReason::Interrupted => "interrupted",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use riscv_asm::AssemblerOptions;
+    use riscv_emu::{
+        machine::RunOptions,
+        shadow::{DataObject, ShadowMemory},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_report_fields() {
+        let program = riscv_asm::assemble("li a0, 42\nnop").unwrap();
+        let mut hasher = riscv_emu::digest::Sha256::new();
+        hasher.update(&[0, 0, 0, 0, 8, 0, 0, 0]);
+        hasher.update(&program);
+        let digest = to_hex(&hasher.finish());
+        let mut machine = Machine::with_options(
+            program,
+            1024,
+            &RunOptions {
+                echo_output: false,
+                capture_output: true,
+                ..RunOptions::default()
+            },
+        );
+        let limits = RunLimits::default();
+        let outcome = machine.run(&limits);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&RunReport::new(&machine, &outcome, &limits).to_json()).unwrap();
+        assert_eq!(json["exit_reason"], "end_of_program");
+        assert_eq!(json["exit_code"], 42);
+        assert_eq!(json["instructions"], 2);
+        assert_eq!(json["registers"]["x"][10], 42);
+        assert_eq!(json["registers"]["pc"], 8);
+        assert_eq!(json["limits"]["exceeded"], false);
+        assert!(json["traps"].as_array().unwrap().is_empty());
+        assert!(json["syscalls"].as_array().unwrap().is_empty());
+        assert_eq!(json["image_sha256"], digest);
+    }
+
+    #[test]
+    fn test_syscall_summary() {
+        let program = riscv_asm::assemble("li a0, 1\nli a7, 64\necall").unwrap();
+        let mut machine = Machine::new(program, 1024);
+        let limits = RunLimits::default();
+        let outcome = machine.run(&limits);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&RunReport::new(&machine, &outcome, &limits).to_json()).unwrap();
+        let syscalls = json["syscalls"].as_array().unwrap();
+        assert_eq!(syscalls.len(), 1);
+        assert_eq!(syscalls[0]["number"], 64);
+        assert_eq!(syscalls[0]["name"], "write");
+        assert_eq!(syscalls[0]["count"], 1);
+        assert_eq!(syscalls[0]["last_arguments"][0], 1);
+        assert_eq!(syscalls[0]["last_result"], serde_json::Value::Null);
+        assert_eq!(syscalls[0]["last_pc"], 8);
+    }
+
+    #[test]
+    fn test_bounds_violations() {
+        let source = "
+            li t0, 0x100
+            lw a0, 16(t0)
+            jal zero, end
+            .org 0x100
+            .data
+            table:
+                .word 1, 2, 3, 4
+            .text
+            end:
+        ";
+        let program = riscv_asm::assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let mut machine = Machine::new(program.bytes, 1024);
+        machine.enable_bounds_checking(ShadowMemory::new(program.data_objects.iter().map(
+            |object| DataObject {
+                name: object.name.clone(),
+                address: object.address,
+                size: object.size,
+            },
+        )));
+        let limits = RunLimits::default();
+        let outcome = machine.run(&limits);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&RunReport::new(&machine, &outcome, &limits).to_json()).unwrap();
+        let violations = json["bounds_violations"].as_array().unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0]["pc"], 4);
+        assert_eq!(violations[0]["address"], 0x110);
+        assert_eq!(violations[0]["object"], "table");
+        assert_eq!(violations[0]["distance"], 0);
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let source = "li t0, 5\nloop:\naddi t0, t0, -1\nbne t0, zero, loop\n";
+        let program = riscv_asm::assemble_program(source, &AssemblerOptions::default()).unwrap();
+        let mut line_map = LineMap::new();
+        for (address, location) in &program.line_map {
+            line_map.insert(*address, "loop.s", location.line);
+        }
+        let mut machine = Machine::new(program.bytes, 1024);
+        machine.symbols.insert("loop", 4);
+        machine.enable_loop_profiling();
+        let limits = RunLimits::default();
+        let outcome = machine.run(&limits);
+
+        let report = RunReport::new(&machine, &outcome, &limits).with_line_map(&line_map);
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        let loops = json["loops"].as_array().unwrap();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0]["symbol"], "loop");
+        assert_eq!(loops[0]["source"], "loop.s:3-4");
+        assert_eq!(loops[0]["iterations"], 5);
+        assert_eq!(loops[0]["instructions_per_iteration"], 2.0);
+    }
+
+    #[test]
+    fn test_traps_are_symbolized() {
+        let mut symbols = Symbols::new();
+        symbols.insert("handler", 0x40);
+        let trap = TrapReport::new(
+            Exception::IllegalInstruction {
+                pc: 0x48,
+                instruction: 0,
+            },
+            &symbols,
+        );
+        assert_eq!(trap.symbol.as_deref(), Some("handler+0x8"));
+    }
+}
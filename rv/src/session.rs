@@ -0,0 +1,84 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Debugger state worth keeping between runs of the same program, saved as JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    /// Breakpoint locations as typed, so symbols are resolved again after a rebuild
+    pub breakpoints: Vec<String>,
+    /// Watched locations as typed
+    pub watchpoints: Vec<String>,
+    /// Registers printed whenever execution stops
+    pub displays: Vec<String>,
+    /// How register values are printed
+    pub radix: Radix,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Radix {
+    #[default]
+    Hex,
+    Dec,
+}
+
+impl Radix {
+    pub fn format(self, value: u32) -> String {
+        match self {
+            Radix::Hex => format!("{:#010x}", value),
+            Radix::Dec => format!("{}", value as i32),
+        }
+    }
+
+    /// Formats a register of an `xlen` bit CPU
+    pub fn format_register(self, value: u64, xlen: u32) -> String {
+        match (self, xlen) {
+            (_, 32) => self.format(value as u32),
+            (Radix::Hex, _) => format!("{:#018x}", value),
+            (Radix::Dec, _) => format!("{}", value as i64),
+        }
+    }
+}
+
+impl Session {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        // SAFETY: the session only contains plain data, so serialization can't fail
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, json).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let session = Session {
+            breakpoints: vec!["loop".to_string(), "0x10".to_string()],
+            watchpoints: vec!["counter".to_string()],
+            displays: vec!["a0".to_string()],
+            radix: Radix::Dec,
+        };
+        let path = std::env::temp_dir().join(format!("rv-session-{}.json", std::process::id()));
+        session.save(&path).unwrap();
+        assert_eq!(Session::load(&path).unwrap(), session);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_fields_use_defaults() {
+        let session: Session = serde_json::from_str(r#"{"breakpoints": ["main"]}"#).unwrap();
+        assert_eq!(session.breakpoints, ["main"]);
+        assert_eq!(session.radix, Radix::Hex);
+    }
+}
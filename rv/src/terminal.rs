@@ -0,0 +1,336 @@
+//! Terminal output: colors when writing to a terminal, tables fitting its width and
+//! highlighted disassembly.
+//!
+//! Colors are used on a terminal unless `NO_COLOR` is set (see <https://no-color.org>) or
+//! `TERM` is `dumb`, and everywhere when `CLICOLOR_FORCE` is set. The width comes from
+//! `COLUMNS`, then from the terminal itself, falling back to 80 columns.
+
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    sync::OnceLock,
+};
+
+use riscv_asm::register::{float_register_number, register_number};
+
+/// Width of output that isn't going to a terminal
+const DEFAULT_WIDTH: usize = 80;
+
+/// Columns between the cells of a table
+const CELL_GAP: usize = 3;
+
+/// What a piece of text is, deciding its color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Error,
+    Warning,
+    /// Addresses of instructions and data
+    Address,
+    Symbol,
+    Mnemonic,
+    Register,
+    Number,
+}
+
+impl Style {
+    /// The SGR parameters of the style
+    fn code(self) -> &'static str {
+        match self {
+            Style::Error => "1;31",
+            Style::Warning => "1;33",
+            Style::Address => "36",
+            Style::Symbol => "33",
+            Style::Mnemonic => "1;34",
+            Style::Register => "32",
+            Style::Number => "35",
+        }
+    }
+}
+
+/// How to render output for one stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Terminal {
+    pub color: bool,
+    pub width: usize,
+}
+
+impl Terminal {
+    /// How stdout is rendered, decided on the first call
+    pub fn stdout() -> Terminal {
+        static STDOUT: OnceLock<Terminal> = OnceLock::new();
+        *STDOUT.get_or_init(|| Terminal::detect(io::stdout().is_terminal(), Fd::Stdout))
+    }
+
+    /// How stderr is rendered, decided on the first call
+    pub fn stderr() -> Terminal {
+        static STDERR: OnceLock<Terminal> = OnceLock::new();
+        *STDERR.get_or_init(|| Terminal::detect(io::stderr().is_terminal(), Fd::Stderr))
+    }
+
+    fn detect(is_terminal: bool, fd: Fd) -> Terminal {
+        let var = |name| std::env::var(name).ok();
+        let color = wants_color(
+            var("NO_COLOR").as_deref(),
+            var("CLICOLOR_FORCE").as_deref(),
+            var("TERM").as_deref(),
+            is_terminal,
+        );
+        let width = var("COLUMNS")
+            .and_then(|columns| columns.parse().ok())
+            .filter(|&columns| columns > 0)
+            .or_else(|| is_terminal.then(|| fd.columns()).flatten())
+            .unwrap_or(DEFAULT_WIDTH);
+        Terminal { color, width }
+    }
+
+    /// `text` in the color of `style`, or as is without colors
+    pub fn paint(&self, style: Style, text: impl Display) -> String {
+        match self.color {
+            true => format!("\x1b[{}m{}\x1b[0m", style.code(), text),
+            false => text.to_string(),
+        }
+    }
+
+    /// Lays `cells` out in rows of as many columns as fit the width, left to right and then
+    /// top to bottom, each row ending in a newline
+    pub fn table(&self, cells: &[String]) -> String {
+        let cell_width = cells
+            .iter()
+            .map(|cell| visible_len(cell))
+            .max()
+            .unwrap_or(0);
+        let columns = ((self.width + CELL_GAP) / (cell_width + CELL_GAP)).max(1);
+        let mut table = String::new();
+        for row in cells.chunks(columns) {
+            for (index, cell) in row.iter().enumerate() {
+                table.push_str(cell);
+                if index + 1 < row.len() {
+                    let padding = cell_width - visible_len(cell) + CELL_GAP;
+                    table.extend(std::iter::repeat_n(' ', padding));
+                }
+            }
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Highlights an instruction as disassembled, like `addi x5, x0, 5` or
+    /// `jal ra, 0x00000010 <main>`: the mnemonic, registers, numbers and symbols
+    pub fn disassembly(&self, text: &str) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let (mnemonic, operands) = text.split_once(' ').unwrap_or((text, ""));
+        let mut highlighted = self.paint(Style::Mnemonic, mnemonic);
+        if !operands.is_empty() {
+            highlighted.push(' ');
+        }
+        let mut rest = operands;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '<' => rest.find('>').map_or(rest.len(), |end| end + 1),
+                c if is_word(c) => rest.find(|c| !is_word(c)).unwrap_or(rest.len()),
+                c => c.len_utf8(),
+            };
+            let (token, remainder) = rest.split_at(len);
+            let style = if token.starts_with('<') {
+                Some(Style::Symbol)
+            } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+                Some(Style::Number)
+            } else if register_number(token).is_some() || float_register_number(token).is_some() {
+                Some(Style::Register)
+            } else {
+                None
+            };
+            match style {
+                Some(style) => highlighted.push_str(&self.paint(style, token)),
+                None => highlighted.push_str(token),
+            }
+            rest = remainder;
+        }
+        highlighted
+    }
+
+    /// `0x00000010  addi x5, x0, 5 → x5 = 0 + 5 = 5`, the line of an explained instruction,
+    /// with its address and disassembly highlighted
+    pub fn explanation(&self, line: &str) -> String {
+        let Some((address, explanation)) = line.split_once("  ") else {
+            return line.to_string();
+        };
+        let (instruction, effect) = match explanation.split_once(" → ") {
+            Some((instruction, effect)) => (instruction, format!(" → {}", effect)),
+            None => (explanation, String::new()),
+        };
+        format!(
+            "{}  {}{}",
+            self.paint(Style::Address, address),
+            self.disassembly(instruction),
+            effect
+        )
+    }
+}
+
+/// Writes the explanations written to it on to `inner`, highlighted a line at a time
+pub struct ExplanationWriter<W> {
+    inner: W,
+    terminal: Terminal,
+    /// The start of a line not finished yet
+    pending: Vec<u8>,
+}
+
+impl<W: Write> ExplanationWriter<W> {
+    pub fn new(inner: W, terminal: Terminal) -> Self {
+        Self {
+            inner,
+            terminal,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ExplanationWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(bytes);
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            writeln!(self.inner, "{}", self.terminal.explanation(&line))?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether to color output: never with `NO_COLOR` set to anything but the empty string,
+/// always with `CLICOLOR_FORCE`, else on terminals that aren't `dumb`
+fn wants_color(
+    no_color: Option<&str>,
+    force: Option<&str>,
+    term: Option<&str>,
+    is_terminal: bool,
+) -> bool {
+    if no_color.is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    if force.is_some_and(|value| !value.is_empty() && value != "0") {
+        return true;
+    }
+    is_terminal && term != Some("dumb")
+}
+
+/// Characters of register names, numbers and mnemonics
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Characters `text` takes up on screen, leaving out the escape sequences of colors
+pub fn visible_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|&c| c == 'm');
+        } else {
+            len += 1;
+        }
+    }
+    len
+}
+
+/// The streams whose terminal width can be asked for
+#[derive(Debug, Clone, Copy)]
+enum Fd {
+    Stdout,
+    Stderr,
+}
+
+impl Fd {
+    #[cfg(unix)]
+    fn columns(self) -> Option<usize> {
+        let fd = match self {
+            Fd::Stdout => libc::STDOUT_FILENO,
+            Fd::Stderr => libc::STDERR_FILENO,
+        };
+        // SAFETY: winsize is plain data, which TIOCGWINSZ fills in
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: the pointer is to a live winsize
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } != 0 || size.ws_col == 0 {
+            return None;
+        }
+        Some(size.ws_col as usize)
+    }
+
+    #[cfg(not(unix))]
+    fn columns(self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_detection() {
+        assert!(wants_color(None, None, Some("xterm"), true));
+        assert!(!wants_color(None, None, Some("xterm"), false));
+        assert!(!wants_color(Some("1"), Some("1"), Some("xterm"), true));
+        assert!(wants_color(Some(""), None, None, true));
+        assert!(wants_color(None, Some("1"), None, false));
+        assert!(!wants_color(None, Some("0"), None, false));
+        assert!(!wants_color(None, None, Some("dumb"), true));
+    }
+
+    #[test]
+    fn test_table_layout() {
+        let cells: Vec<String> = ["x0 0", "x1 10", "x2 200", "x3 3"].map(String::from).into();
+        let narrow = Terminal {
+            color: false,
+            width: 20,
+        };
+        assert_eq!(narrow.table(&cells), "x0 0     x1 10\nx2 200   x3 3\n");
+        let colored = Terminal {
+            color: true,
+            width: 10,
+        };
+        // Colors don't count towards the width
+        let cells: Vec<String> = cells
+            .iter()
+            .map(|cell| colored.paint(Style::Register, cell))
+            .collect();
+        assert_eq!(colored.table(&cells).lines().count(), 4);
+        assert_eq!(visible_len(&cells[2]), 6);
+    }
+
+    #[test]
+    fn test_highlighting() {
+        let plain = Terminal {
+            color: false,
+            width: 80,
+        };
+        assert_eq!(plain.disassembly("addi x5, x0, 5"), "addi x5, x0, 5");
+        let colored = Terminal {
+            color: true,
+            ..plain
+        };
+        assert_eq!(
+            colored.disassembly("lw a0, -8(sp)"),
+            "\x1b[1;34mlw\x1b[0m \x1b[32ma0\x1b[0m, -\x1b[35m8\x1b[0m(\x1b[32msp\x1b[0m)"
+        );
+        assert_eq!(
+            colored.disassembly("jal ra, 0x10 <main>"),
+            "\x1b[1;34mjal\x1b[0m \x1b[32mra\x1b[0m, \x1b[35m0x10\x1b[0m \x1b[33m<main>\x1b[0m"
+        );
+        let mut output = Vec::new();
+        let mut writer = ExplanationWriter::new(&mut output, colored);
+        write!(writer, "0x00000000  ecall → call").unwrap();
+        writeln!(writer, " the environment").unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b[36m0x00000000\x1b[0m  \x1b[1;34mecall\x1b[0m → call the environment\n"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Assembles and runs the programs in `examples/`, checking what they print and leave behind
+
+use std::path::PathBuf;
+
+use riscv_asm::{AssemblerOptions, assemble_program};
+use riscv_emu::{
+    machine::{ExitReason, Machine, RunLimits, RunOptions, RunOutcome},
+    syscalls::SyscallMode,
+};
+
+const MEMORY_SIZE: u32 = 0x10000;
+
+/// Assembles `examples/<name>` and runs it with a stack at the top of memory, feeding it
+/// `input` and capturing its output
+fn run_example(name: &str, input: &[u8], options: RunOptions) -> (Machine, RunOutcome) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../examples")
+        .join(name);
+    let program = assemble_program(&path, &AssemblerOptions::default())
+        .unwrap_or_else(|error| panic!("assembling {}: {:#}", name, error));
+    let options = RunOptions {
+        input: Some(input.to_vec()),
+        echo_output: false,
+        capture_output: true,
+        syscalls: SyscallMode::Bare,
+        ..options
+    };
+    let mut machine = Machine::with_options(program.bytes, MEMORY_SIZE as usize, &options);
+    machine.set_stack_pointer(MEMORY_SIZE);
+    let outcome = machine.run(&RunLimits {
+        max_instructions: Some(100_000),
+    });
+    assert_eq!(outcome.exit_reason, ExitReason::EnvironmentCall, "{}", name);
+    (machine, outcome)
+}
+
+#[test]
+fn test_fibonacci() {
+    let (machine, outcome) = run_example("fibonacci.s", b"", RunOptions::default());
+    assert_eq!(machine.console.output(), b"0 1 1 2 3 5 8 13 21 34 \n");
+    assert_eq!(outcome.exit_code, Some(34));
+    // s1 and s2 hold fib(10) and fib(11)
+    assert_eq!((machine.cpu.regs[9], machine.cpu.regs[18]), (55, 89));
+}
+
+#[test]
+fn test_string_reverse() {
+    let (machine, outcome) = run_example("string_reverse.s", b"hello\n", RunOptions::default());
+    assert_eq!(machine.console.output(), b"olleh\n");
+    assert_eq!(outcome.exit_code, Some(5));
+    assert_eq!(machine.cpu.regs[2], MEMORY_SIZE as u64);
+
+    // The end of the input ends the line too
+    let (machine, outcome) = run_example("string_reverse.s", b"RISC-V", RunOptions::default());
+    assert_eq!(machine.console.output(), b"V-CSIR\n");
+    assert_eq!(outcome.exit_code, Some(6));
+}
+
+#[test]
+fn test_bubble_sort() {
+    let (machine, outcome) = run_example("bubble_sort.s", b"", RunOptions::default());
+    assert_eq!(
+        machine.console.output(),
+        b"5 -3 12 0 7 7 -20 1 \n-20 -3 0 1 5 7 7 12 \n"
+    );
+    assert_eq!(outcome.exit_code, Some(15));
+    // Sorted in place on the stack
+    let sorted = machine.read_memory(MEMORY_SIZE - 32, 32).unwrap();
+    let words: Vec<i32> = sorted
+        .chunks(4)
+        .map(|word| i32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    assert_eq!(words, [-20, -3, 0, 1, 5, 7, 7, 12]);
+}
+
+#[test]
+fn test_interrupt_echo() {
+    let options = RunOptions {
+        plic: true,
+        uart: true,
+        ..RunOptions::default()
+    };
+    let (machine, outcome) = run_example("interrupt_echo.s", b"Hello, world!\nnot read", options);
+    assert_eq!(machine.console.output(), b"HELLO, WORLD!\n");
+    assert_eq!(outcome.exit_code, Some(13));
+}
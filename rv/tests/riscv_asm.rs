@@ -0,0 +1,94 @@
+//! Runs the `riscv-asm` binary the way a Makefile would
+
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs `riscv-asm` with `args`, feeding it `input`
+fn riscv_asm(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_riscv-asm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_riscv_asm() {
+    // Standard input to standard output
+    let output = riscv_asm(&[], "li a0, 5\nret\n");
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        [0x13, 0x05, 0x50, 0x00, 0x67, 0x80, 0x00, 0x00]
+    );
+
+    // Several files as one program, the format from the output's extension
+    let directory = std::env::temp_dir().join(format!("riscv-asm-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = |name: &str| directory.join(name).display().to_string();
+    fs::write(path("main.s"), "addiw a0, a0, 1\nj end\n").unwrap();
+    fs::write(path("end.s"), "end: ret\n").unwrap();
+    let hex = path("out.hex");
+    let files = [path("main.s"), path("end.s")];
+    let output = riscv_asm(&["-march=rv64i", &files[0], &files[1], "-o", &hex], "");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(&hex).unwrap(),
+        ":0C0000001B0515006F0040006780000029\n:00000001FF\n"
+    );
+
+    // RV32 has no addiw, the diagnostic names the file
+    let output = riscv_asm(&[&files[0], "--format", "bin"], "");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("main.s:1:1"), "{}", stderr);
+
+    // ELF objects and listings
+    let object = PathBuf::from(path("end.o"));
+    let listing = path("end.lst");
+    let output = riscv_asm(&["-o", &path("end.o"), &files[1]], "");
+    assert!(output.status.success());
+    assert!(fs::read(&object).unwrap().starts_with(b"\x7fELF"));
+    let output = riscv_asm(&["--listing", &listing, &files[1]], "");
+    assert!(output.status.success());
+    assert!(fs::read_to_string(&listing).unwrap().contains("00008067"));
+
+    // Cached binaries are rebuilt when an included file changes
+    let cache = format!("--cache={}", path("cache"));
+    fs::write(
+        path("data.s"),
+        ".word 1
+",
+    )
+    .unwrap();
+    fs::write(path("table.s"), ".include \"data.s\"\n").unwrap();
+    let output = riscv_asm(&[&cache, &path("table.s")], "");
+    assert_eq!(output.stdout, [1, 0, 0, 0]);
+    fs::write(path("data.s"), ".word 2\n").unwrap();
+    let output = riscv_asm(&[&cache, &path("table.s")], "");
+    assert_eq!(output.stdout, [2, 0, 0, 0]);
+    assert_eq!(fs::read_dir(path("cache")).unwrap().count(), 2);
+    assert!(
+        !riscv_asm(&[&cache, "--format", "hex"], "nop")
+            .status
+            .success()
+    );
+    fs::remove_dir_all(&directory).unwrap();
+}